@@ -13,4 +13,9 @@ fn main() {
     } else {
         let _ = Command::new("cargo").arg("fmt").status();
     }
+
+    #[cfg(feature = "grpc")]
+    tonic_build::configure()
+        .compile_protos(&["src/server/grpc/node.proto"], &["src/server/grpc"])
+        .expect("failed compiling the gRPC protocol, it requires the protoc compiler");
 }
@@ -0,0 +1,234 @@
+//! Weighted fair queuing of client requests.
+//!
+//! Every request is assigned a virtual finish time which advances proportionally to its
+//! estimated cost, so that clients sending expensive requests get a proportionally smaller
+//! share of the node's throughput while backlogged, instead of each request counting the same.
+//!
+//! Requests can also be of a higher priority, e.g. those of clients holding a token of a higher
+//! service tier, which are always served ahead of those of a lower one and fairly among them.
+//!
+//! The queue holds up to a given capacity, past it those feeding it are to stop taking in more
+//! requests until some are served, leaving the rest waiting wherever they came from.
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+};
+
+use freenet_stdlib::client_api::{ClientRequest, ContractRequest};
+
+use super::{ClientId, OpenRequest};
+
+/// Estimates the relative cost of processing an item.
+pub(crate) trait CostEstimator<T> {
+    fn cost(&self, item: &T) -> u64;
+
+    fn priority(&self, _item: &T) -> u8 {
+        0
+    }
+}
+
+/// Cost based on the kind of operation plus the size of its payload.
+#[derive(Default, Clone, Copy)]
+pub(crate) struct PayloadCost;
+
+impl PayloadCost {
+    const BYTES_PER_UNIT: u64 = 1024;
+}
+
+impl CostEstimator<OpenRequest<'_>> for PayloadCost {
+    fn cost(&self, item: &OpenRequest<'_>) -> u64 {
+        let base = match &*item.request {
+            ClientRequest::ContractOp(ContractRequest::Put { .. }) => 4,
+            ClientRequest::ContractOp(ContractRequest::Update { .. }) => 2,
+            ClientRequest::DelegateOp(_) => 2,
+            _ => 1,
+        };
+        let payload = bincode::serialized_size(&*item.request).unwrap_or_default();
+        base + payload / Self::BYTES_PER_UNIT
+    }
+
+    fn priority(&self, item: &OpenRequest<'_>) -> u8 {
+        item.token.as_ref().map_or(0, |token| token.tier() as u8)
+    }
+}
+
+pub(crate) struct FairQueue<T, C = PayloadCost> {
+    estimator: C,
+    capacity: usize,
+    virtual_time: u64,
+    seq: u64,
+    last_finish: HashMap<ClientId, u64>,
+    queue: BTreeMap<(Reverse<u8>, u64, u64), (ClientId, T)>,
+}
+
+impl<T, C: CostEstimator<T>> FairQueue<T, C> {
+    pub fn new(estimator: C, capacity: usize) -> Self {
+        Self {
+            estimator,
+            capacity,
+            virtual_time: 0,
+            seq: 0,
+            last_finish: HashMap::new(),
+            queue: BTreeMap::new(),
+        }
+    }
+
+    /// Whether the queue holds its capacity, more can still be pushed but shouldn't be.
+    pub fn is_full(&self) -> bool {
+        self.queue.len() >= self.capacity
+    }
+
+    pub fn push(&mut self, client: ClientId, item: T) {
+        let cost = self.estimator.cost(&item).max(1);
+        let priority = self.estimator.priority(&item);
+        let last_finish = self.last_finish.entry(client).or_default();
+        let finish = (*last_finish).max(self.virtual_time) + cost;
+        *last_finish = finish;
+        self.seq += 1;
+        self.queue
+            .insert((Reverse(priority), finish, self.seq), (client, item));
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        let ((_, finish, _), (client, item)) = self.queue.pop_first()?;
+        // higher priority requests may have been served out of the order of their finish time
+        self.virtual_time = self.virtual_time.max(finish);
+        if self.last_finish.get(&client) == Some(&finish) {
+            // no other requests pending from this client
+            self.last_finish.remove(&client);
+        }
+        Some(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Weight;
+
+    impl CostEstimator<u64> for Weight {
+        fn cost(&self, item: &u64) -> u64 {
+            *item
+        }
+    }
+
+    #[test]
+    fn heavy_client_gets_proportionally_less_throughput() {
+        let heavy = ClientId::next();
+        let light = ClientId::next();
+        let mut queue = FairQueue::new(Weight, usize::MAX);
+        for _ in 0..100 {
+            queue.push(heavy, 10);
+            queue.push(light, 1);
+        }
+
+        let served: Vec<_> = std::iter::from_fn(|| queue.pop()).take(55).collect();
+        let heavy_served = served.iter().filter(|cost| **cost == 10).count();
+        let light_served = served.len() - heavy_served;
+        assert_eq!(heavy_served, 5);
+        assert_eq!(light_served, 50);
+        assert_eq!(queue.queue.len(), 145);
+    }
+
+    #[test]
+    fn idle_client_does_not_accumulate_credit() {
+        let busy = ClientId::next();
+        let idle = ClientId::next();
+        let mut queue = FairQueue::new(Weight, usize::MAX);
+        for _ in 0..10 {
+            queue.push(busy, 1);
+        }
+        for _ in 0..5 {
+            queue.pop();
+        }
+
+        // a client joining late competes from the current virtual time
+        queue.push(idle, 1);
+        queue.push(idle, 1);
+        for _ in 0..4 {
+            queue.pop();
+        }
+        assert!(!queue.last_finish.contains_key(&idle));
+        assert!(queue.last_finish.contains_key(&busy));
+    }
+
+    #[test]
+    fn full_at_capacity() {
+        let client = ClientId::next();
+        let mut queue = FairQueue::new(Weight, 2);
+        queue.push(client, 1);
+        assert!(!queue.is_full());
+        queue.push(client, 1);
+        assert!(queue.is_full());
+        queue.pop();
+        assert!(!queue.is_full());
+    }
+
+    #[test]
+    fn higher_tier_served_first() {
+        use freenet_stdlib::prelude::*;
+
+        use crate::{client_events::AuthToken, config::ServiceTier};
+
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let request = |client, tier| {
+            let get = ContractRequest::Get {
+                key,
+                return_contract_code: false,
+                subscribe: false,
+            };
+            OpenRequest::new(client, Box::new(get.into()))
+                .with_token(Some(AuthToken::generate_with_tier(tier)))
+        };
+        let (standard, premium) = (ClientId::next(), ClientId::next());
+        let mut queue = FairQueue::new(PayloadCost, usize::MAX);
+        // the standard client was backlogged first
+        for _ in 0..10 {
+            queue.push(standard, request(standard, ServiceTier::Standard));
+        }
+        for _ in 0..5 {
+            queue.push(premium, request(premium, ServiceTier::Premium));
+        }
+
+        let served: Vec<_> = std::iter::from_fn(|| queue.pop())
+            .map(|req| req.client_id)
+            .collect();
+        assert_eq!(served[..5], [premium; 5]);
+        assert_eq!(served[5..], [standard; 10]);
+    }
+
+    #[test]
+    fn payload_cost_grows_with_size() {
+        use freenet_stdlib::prelude::*;
+
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let request = |size: usize| {
+            OpenRequest::new(
+                ClientId::FIRST,
+                Box::new(
+                    ContractRequest::Update {
+                        key,
+                        data: UpdateData::State(State::from(vec![0; size])),
+                    }
+                    .into(),
+                ),
+            )
+        };
+        let get = OpenRequest::new(
+            ClientId::FIRST,
+            Box::new(
+                ContractRequest::Get {
+                    key,
+                    return_contract_code: false,
+                    subscribe: false,
+                }
+                .into(),
+            ),
+        );
+        assert_eq!(PayloadCost.cost(&get), 1);
+        assert_eq!(PayloadCost.cost(&request(16)), 2);
+        assert!(PayloadCost.cost(&request(64 * 1024)) > 64);
+    }
+}
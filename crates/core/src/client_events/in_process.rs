@@ -0,0 +1,336 @@
+//! Clients of the node living in the same process, for applications embedding it.
+//!
+//! The event loop serving the clients takes them through the [`InProcessProxy`] handed by
+//! [`InProcessClients`], which clients connect to. Once the event loop goes down, e.g. after a
+//! panic, their requests fail unless they reconnect automatically: then they wait for the
+//! event loop started in its place with a proxy of the same `InProcessClients`, and subscribe
+//! again to the contracts they were subscribed to before carrying on.
+//!
+//! A read in flight as the event loop goes down is sent again once reconnected, while a write
+//! fails as it may have been applied already.
+
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use freenet_stdlib::{
+    client_api::{ClientError, ClientRequest, ContractRequest, ContractResponse, ErrorKind},
+    prelude::ContractKey,
+};
+use futures::{future::BoxFuture, FutureExt};
+use tokio::sync::{mpsc, watch};
+
+use super::{ClientEventsProxy, ClientId, HostResult, OpenRequest};
+use crate::config::RequestTimeouts;
+
+struct Request {
+    client_id: ClientId,
+    request: Box<ClientRequest<'static>>,
+    responses: mpsc::UnboundedSender<HostResult>,
+    notifications: Option<mpsc::UnboundedSender<HostResult>>,
+}
+
+type ProxySender = mpsc::UnboundedSender<Request>;
+
+/// Where in-process clients connect to the event loop serving them.
+#[derive(Clone)]
+pub struct InProcessClients {
+    /// The proxy of the event loop last started.
+    proxy: Arc<watch::Sender<Option<ProxySender>>>,
+}
+
+impl Default for InProcessClients {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InProcessClients {
+    pub fn new() -> Self {
+        let (proxy, _) = watch::channel(None);
+        Self {
+            proxy: Arc::new(proxy),
+        }
+    }
+
+    /// The proxy for an event loop to serve the clients through, in place of the one of the
+    /// event loop before it.
+    pub fn proxy(&self) -> InProcessProxy {
+        let (requests, received) = mpsc::unbounded_channel();
+        self.proxy.send_replace(Some(requests));
+        InProcessProxy {
+            requests: received,
+            responses: Default::default(),
+        }
+    }
+
+    pub fn connect(&self) -> InProcessClient {
+        let (notify, notifications) = mpsc::unbounded_channel();
+        InProcessClient {
+            id: ClientId::next(),
+            proxies: self.proxy.subscribe(),
+            attached: None,
+            auto_reconnect: None,
+            subscriptions: HashSet::new(),
+            notify,
+            notifications,
+        }
+    }
+}
+
+pub struct InProcessProxy {
+    requests: mpsc::UnboundedReceiver<Request>,
+    /// Where the responses to each client go.
+    responses: std::collections::HashMap<ClientId, mpsc::UnboundedSender<HostResult>>,
+}
+
+impl ClientEventsProxy for InProcessProxy {
+    fn recv(&mut self) -> BoxFuture<'_, Result<OpenRequest<'static>, ClientError>> {
+        async move {
+            let Some(Request {
+                client_id,
+                request,
+                responses,
+                notifications,
+            }) = self.requests.recv().await
+            else {
+                return Err(ErrorKind::ChannelClosed.into());
+            };
+            self.responses.insert(client_id, responses);
+            let request = OpenRequest::new(client_id, request);
+            Ok(match notifications {
+                Some(notifications) => request.with_notification(notifications),
+                None => request,
+            })
+        }
+        .boxed()
+    }
+
+    fn send(&mut self, id: ClientId, response: HostResult) -> BoxFuture<Result<(), ClientError>> {
+        async move {
+            if let Some(responses) = self.responses.get(&id) {
+                if responses.send(response).is_err() {
+                    self.responses.remove(&id);
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+pub struct InProcessClient {
+    id: ClientId,
+    proxies: watch::Receiver<Option<ProxySender>>,
+    /// The proxy the client sends its requests through.
+    attached: Option<ProxySender>,
+    /// How long the client waits for the event loop to be back, if it reconnects.
+    auto_reconnect: Option<Duration>,
+    subscriptions: HashSet<ContractKey>,
+    notify: mpsc::UnboundedSender<HostResult>,
+    notifications: mpsc::UnboundedReceiver<HostResult>,
+}
+
+impl InProcessClient {
+    /// Reconnects to the event loop started in place of the one going down, waiting for it
+    /// as long as `wait`.
+    pub fn with_auto_reconnect(mut self, wait: Duration) -> Self {
+        self.auto_reconnect = Some(wait);
+        self
+    }
+
+    /// The next notification for the subscriptions of the client.
+    pub async fn recv_notification(&mut self) -> Option<HostResult> {
+        self.notifications.recv().await
+    }
+
+    pub async fn request(&mut self, request: ClientRequest<'static>) -> HostResult {
+        loop {
+            let proxy = self.attach().await?;
+            if let Some(result) = self.send(&proxy, request.clone()).await {
+                if let Ok(response) = &result {
+                    self.track(response);
+                }
+                return result;
+            }
+            tracing::debug!(client = %self.id, "event loop gone while serving a request");
+            let read =
+                matches!(&request, ClientRequest::ContractOp(op) if RequestTimeouts::is_read(op));
+            if self.auto_reconnect.is_none() || !read {
+                return Err(ErrorKind::NodeUnavailable.into());
+            }
+        }
+    }
+
+    /// The proxy of the event loop serving the client, subscribing again through it if it
+    /// replaced the one the client was attached to.
+    async fn attach(&mut self) -> Result<ProxySender, ClientError> {
+        if let Some(proxy) = self.attached.as_ref().filter(|proxy| !proxy.is_closed()) {
+            return Ok(proxy.clone());
+        }
+        let live = |proxy: &Option<ProxySender>| proxy.as_ref().is_some_and(|p| !p.is_closed());
+        let proxy = match self.auto_reconnect {
+            Some(wait) => tokio::time::timeout(wait, self.proxies.wait_for(live))
+                .await
+                .ok()
+                .and_then(Result::ok)
+                .and_then(|proxy| proxy.clone()),
+            None => self.proxies.borrow().clone().filter(|p| !p.is_closed()),
+        };
+        let Some(proxy) = proxy else {
+            return Err(ErrorKind::NodeUnavailable.into());
+        };
+        if self.attached.replace(proxy.clone()).is_some() {
+            self.subscribe_again(&proxy).await;
+        }
+        Ok(proxy)
+    }
+
+    async fn subscribe_again(&mut self, proxy: &ProxySender) {
+        tracing::debug!(client = %self.id, subscriptions = self.subscriptions.len(), "reconnected, subscribing again");
+        for key in std::mem::take(&mut self.subscriptions) {
+            let subscribe =
+                ClientRequest::ContractOp(ContractRequest::Subscribe { key, summary: None });
+            match self.send(proxy, subscribe).await {
+                Some(Ok(response)) => self.track(&response),
+                // told along with the notifications of the subscription
+                Some(Err(err)) => {
+                    let _ = self.notify.send(Err(err));
+                }
+                // gone again, the next request attaches to the next one
+                None => return,
+            }
+        }
+    }
+
+    /// The result of the request, none if the event loop went down before answering it.
+    async fn send(
+        &self,
+        proxy: &ProxySender,
+        request: ClientRequest<'static>,
+    ) -> Option<HostResult> {
+        let notifications = matches!(
+            request,
+            ClientRequest::ContractOp(ContractRequest::Subscribe { .. })
+        )
+        .then(|| self.notify.clone());
+        let (responses, mut response) = mpsc::unbounded_channel();
+        proxy
+            .send(Request {
+                client_id: self.id,
+                request: Box::new(request),
+                responses,
+                notifications,
+            })
+            .ok()?;
+        response.recv().await
+    }
+
+    fn track(&mut self, response: &freenet_stdlib::client_api::HostResponse) {
+        if let freenet_stdlib::client_api::HostResponse::ContractResponse(
+            ContractResponse::SubscribeResponse {
+                key,
+                subscribed: true,
+            },
+        ) = response
+        {
+            self.subscriptions.insert(*key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use freenet_stdlib::{
+        client_api::HostResponse,
+        prelude::{ContractInstanceId, StateDelta, UpdateData},
+    };
+
+    use super::*;
+
+    fn key(n: u8) -> ContractKey {
+        ContractKey::from(ContractInstanceId::new([n; 32]))
+    }
+
+    fn get(key: ContractKey) -> ClientRequest<'static> {
+        ClientRequest::ContractOp(ContractRequest::Get {
+            key,
+            return_contract_code: false,
+            subscribe: false,
+        })
+    }
+
+    /// Serves requests as the node would, notifying subscribers right away, unless it panics
+    /// on the first get.
+    async fn event_loop(mut proxy: InProcessProxy, panics: bool) {
+        while let Ok(req) = proxy.recv().await {
+            let response = match *req.request {
+                ClientRequest::ContractOp(ContractRequest::Subscribe { key, .. }) => {
+                    let update = UpdateData::Delta(StateDelta::from(vec![1]));
+                    let notification = ContractResponse::UpdateNotification { key, update };
+                    req.notification_channel
+                        .unwrap()
+                        .send(Ok(notification.into()))
+                        .unwrap();
+                    ContractResponse::SubscribeResponse {
+                        key,
+                        subscribed: true,
+                    }
+                    .into()
+                }
+                ClientRequest::ContractOp(ContractRequest::Get { .. }) if panics => {
+                    panic!("executor failure")
+                }
+                _ => HostResponse::Ok,
+            };
+            proxy.send(req.client_id, Ok(response)).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn client_recovers_once_event_loop_restarts() {
+        let clients = InProcessClients::new();
+        let failing = tokio::spawn(event_loop(clients.proxy(), true));
+        let mut client = clients
+            .connect()
+            .with_auto_reconnect(Duration::from_secs(5));
+        let subscribed = client
+            .request(ClientRequest::ContractOp(ContractRequest::Subscribe {
+                key: key(1),
+                summary: None,
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            subscribed,
+            HostResponse::ContractResponse(ContractResponse::SubscribeResponse { .. })
+        ));
+        client.recv_notification().await.unwrap().unwrap();
+
+        let restarted = {
+            let clients = clients.clone();
+            tokio::spawn(async move {
+                assert!(failing.await.unwrap_err().is_panic());
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                event_loop(clients.proxy(), false).await
+            })
+        };
+        // served by the event loop started in place of the one which panicked on it
+        assert!(matches!(
+            client.request(get(key(2))).await,
+            Ok(HostResponse::Ok)
+        ));
+        let notification = client.recv_notification().await.unwrap().unwrap();
+        assert!(matches!(
+            notification,
+            HostResponse::ContractResponse(ContractResponse::UpdateNotification { key: notified, .. })
+                if notified == key(1)
+        ));
+
+        // unless reconnecting, requests fail once the event loop is gone
+        let mut unattended = clients.connect();
+        assert!(unattended.request(get(key(2))).await.is_ok());
+        restarted.abort();
+        tokio::task::yield_now().await;
+        let err = unattended.request(get(key(2))).await.unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::NodeUnavailable));
+    }
+}
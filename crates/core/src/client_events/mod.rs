@@ -24,10 +24,16 @@ use crate::contract::{ClientResponsesReceiver, ContractHandlerEvent};
 use crate::message::{NodeEvent, QueryResult};
 use crate::node::OpManager;
 use crate::operations::{get, put, update, OpError};
-use crate::{config::GlobalExecutor, contract::StoreResponse};
+use crate::{
+    config::{GlobalExecutor, ServiceTier},
+    contract::StoreResponse,
+};
 
 pub(crate) mod combinator;
-#[cfg(feature = "websocket")]
+#[cfg(feature = "http-gateway")]
+pub(crate) mod fair_queue;
+pub(crate) mod in_process;
+#[cfg(feature = "http-gateway")]
 pub(crate) mod websocket;
 
 pub(crate) type BoxedClient = Box<dyn ClientEventsProxy + Send + 'static>;
@@ -70,15 +76,43 @@ impl AuthToken {
     }
 
     pub fn generate() -> AuthToken {
+        Self::generate_with_tier(ServiceTier::Standard)
+    }
+
+    /// Generates a token for clients of the tier, which the token carries along.
+    pub fn generate_with_tier(tier: ServiceTier) -> AuthToken {
         use rand::Rng;
         let mut rng = rand::thread_rng();
         let mut token = [0u8; 32];
         rng.fill(&mut token);
         let token_str = bs58::encode(token).into_string();
-        AuthToken::from(token_str)
+        match tier {
+            ServiceTier::Standard => AuthToken::from(token_str),
+            // the base58 alphabet has no dots, the prefix can't be mistaken for part of it
+            ServiceTier::Premium => AuthToken::from(format!("{PREMIUM_TOKEN_PREFIX}{token_str}")),
+        }
+    }
+
+    /// Whether the token has the shape of those generated by the node: the prefix of its tier,
+    /// if any, followed by 32 random bytes in base58.
+    pub fn is_well_formed(&self) -> bool {
+        let token = self.0.strip_prefix(PREMIUM_TOKEN_PREFIX).unwrap_or(&self.0);
+        bs58::decode(token)
+            .into_vec()
+            .is_ok_and(|bytes| bytes.len() == 32)
+    }
+
+    pub fn tier(&self) -> ServiceTier {
+        if self.0.starts_with(PREMIUM_TOKEN_PREFIX) {
+            ServiceTier::Premium
+        } else {
+            ServiceTier::Standard
+        }
     }
 }
 
+const PREMIUM_TOKEN_PREFIX: &str = "premium.";
+
 impl std::ops::Deref for AuthToken {
     type Target = str;
 
@@ -1,42 +1,314 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    sync::{Arc, OnceLock, RwLock},
-    time::Duration,
+    collections::{HashMap, HashSet, VecDeque},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock, RwLock,
+    },
+    time::{Duration, Instant},
 };
 
 use axum::{
     extract::{
         ws::{Message, WebSocket},
-        Query, WebSocketUpgrade,
+        FromRequestParts, Query, WebSocketUpgrade,
     },
-    http::StatusCode,
+    http::{request::Parts, HeaderMap, StatusCode},
     response::{IntoResponse, Response},
     routing::get,
-    Extension, Router,
+    Extension, Json, Router,
 };
+use bincode::Options;
 use freenet_stdlib::{
-    client_api::{ClientRequest, ContractRequest, ContractResponse, ErrorKind, HostResponse},
+    client_api::{
+        ClientRequest, ContractError, ContractRequest, ContractResponse, ErrorKind, HostResponse,
+        RequestError,
+    },
     prelude::*,
 };
-use futures::{future::BoxFuture, stream::SplitSink, FutureExt, SinkExt, StreamExt};
+use futures::{future::BoxFuture, FutureExt, StreamExt};
 use headers::Header;
 use serde::Deserialize;
 use tokio::sync::{mpsc, Mutex};
 
 use crate::{
     client_events::AuthToken,
-    server::{ClientConnection, HostCallbackResult},
+    config::{
+        ConnectionTimeouts, DuplicateSubscriptions, MalformedAuthTokens, NotificationAggregation,
+        OutboundPriority, QueueDiscipline, RequestTimeouts, SlowConsumers, UnknownFields,
+        WebsocketApiConfig,
+    },
+    contract::collection::RangeFrame,
+    server::{
+        admin::{require_admin, AdminAuth},
+        client_addr::ClientAddr,
+        http_gateway::{ExecutorCommand, ExecutorCommands},
+        metrics::{GatewayMetrics, LoadSummary},
+        tls::ClientIdentity,
+        work_queue::{self, WorkQueueError, WorkQueueMetrics, WorkQueueReceiver, WorkQueueSender},
+        ClientConnection, HostCallbackResult, IdentityTransformer, ResponseTransformer,
+        ServerHandle, ServerStateWatch,
+    },
     util::EncodingProtocol,
 };
 
-use super::{ClientError, ClientEventsProxy, ClientId, HostResult, OpenRequest};
+use super::{
+    fair_queue::{FairQueue, PayloadCost},
+    ClientError, ClientEventsProxy, ClientId, HostResult, OpenRequest,
+};
 use crate::server::http_gateway::AttestedContractMap;
+use crate::server::token_expiry::TokenExpiryCheck;
+
+use self::{
+    aggregation::Aggregation,
+    audit::{AuditIdentity, AuditSession, AuditTrail},
+    bandwidth::Bandwidth,
+    batch::{Batched, PendingBatches},
+    compression::{Deflate, Dictionaries},
+    connections::{ClientLabel, ConnectionDetails, Connections, CLIENT_LABEL_HEADER},
+    control::{ControlFrame, ControlResponse},
+    delivery::Deliveries,
+    encryption::{FrameCipher, SessionKeys},
+    idempotency::IdempotentWrites,
+    listener::{
+        Backlog, CoalescingWindows, NotificationMode, SubscriptionListener, TooSlow,
+        NOTIFICATION_VERSIONS_HEADER, SUBSCRIPTION_ACKS_HEADER,
+    },
+    maintenance::Maintenance,
+    memory::NotificationMemory,
+    multipart::{MultipartResponses, MULTIPART_RESPONSES_HEADER},
+    notification_format::NotificationFormat,
+    one_shot::{Answer, OneShotRequests, OneShotSubscriptions},
+    outbound::{negotiate_discipline, Notification, Outbound},
+    oversized::{ResponseLimit, CHUNKED_RESPONSES_HEADER},
+    pending::{PendingReceiver, PendingResponses},
+    registry::SubscriptionRegistry,
+    replay::UpdateLog,
+    sessions::Sessions,
+    snapshots::SnapshotEncodings,
+    tenant::{TenantConnection, TenantId, TenantRegistry},
+    timeouts::{negotiate_timeouts, timeout_headers},
+    timing::RequestTimings,
+    touched::TouchedContracts,
+};
+
+mod aggregation;
+mod audit;
+mod bandwidth;
+mod batch;
+mod compression;
+mod connections;
+mod control;
+mod delivery;
+mod encryption;
+#[cfg(feature = "fault-injection")]
+mod faults;
+mod idempotency;
+mod listener;
+mod maintenance;
+mod memory;
+mod multipart;
+mod notification_format;
+mod one_shot;
+mod outbound;
+mod oversized;
+mod pending;
+mod registry;
+mod replay;
+mod sessions;
+mod snapshots;
+mod tenant;
+mod timeouts;
+mod timing;
+mod touched;
+
+/// What is kept of, and applied to, the subscriptions of every connection.
+#[derive(Clone)]
+struct SubscriptionRecords {
+    update_log: Arc<UpdateLog>,
+    deliveries: Arc<Deliveries>,
+    coalescing: Arc<CoalescingWindows>,
+    slow_consumers: Arc<SlowConsumers>,
+    aggregation: Arc<NotificationAggregation>,
+    memory: Arc<NotificationMemory>,
+}
+
+/// How each websocket connection is served.
+#[derive(Clone, Copy)]
+struct ConnectionSettings {
+    outbound_priority: OutboundPriority,
+    notification_queue: QueueDiscipline,
+    timeouts: ConnectionTimeouts,
+    unknown_fields: UnknownFields,
+    response_limit: ResponseLimit,
+    max_contracts: Option<usize>,
+    /// Whether responses with several parts are sent one part at a time.
+    multipart: bool,
+    /// Whether notifications are preceded by the versions they bring the contract to and from.
+    notification_versions: bool,
+    subscription_acks: bool,
+    notification_format: NotificationFormat,
+    #[cfg(feature = "fault-injection")]
+    faults: Option<crate::config::FaultInjection>,
+}
+
+impl ConnectionSettings {
+    fn new(config: &WebsocketApiConfig) -> Self {
+        Self {
+            outbound_priority: config.outbound_priority,
+            notification_queue: config.notification_queue,
+            timeouts: config.connection_timeouts,
+            unknown_fields: config.unknown_request_fields,
+            response_limit: ResponseLimit::new(config),
+            max_contracts: config.max_contracts_per_connection,
+            multipart: false,
+            notification_versions: false,
+            subscription_acks: false,
+            notification_format: NotificationFormat::default(),
+            #[cfg(feature = "fault-injection")]
+            faults: config.fault_injection,
+        }
+    }
+}
+
+/// What the connections of the proxy share, handed to them as a single extension.
+struct GatewayState {
+    request_sender: WebSocketRequest,
+    token_check: TokenCheck,
+    tenants: Arc<TenantRegistry>,
+    connections: Arc<Connections>,
+    records: SubscriptionRecords,
+    snapshots: SnapshotEncodings,
+    pending_responses: Arc<PendingResponses>,
+    /// How connections are served, before negotiating with each client.
+    settings: ConnectionSettings,
+    request_timeouts: RequestTimeouts,
+    dictionaries: Dictionaries,
+    timings: Arc<RequestTimings>,
+    idempotent_writes: IdempotentWrites,
+    one_shot_requests: Arc<OneShotRequests>,
+    sessions: Sessions,
+    maintenance: Maintenance,
+    bandwidth: Arc<Bandwidth>,
+    audit: Arc<AuditTrail>,
+    metrics: GatewayMetrics,
+    /// Present when connections may encrypt their binary messages.
+    session_keys: Option<Arc<SessionKeys>>,
+}
+
+/// What a connection is served with, as negotiated during its handshake.
+struct ConnectionSetup {
+    auth_token: Option<(AuthToken, ContractInstanceId)>,
+    encoding_protoc: EncodingProtocol,
+    tenant: TenantConnection,
+    details: ConnectionDetails,
+    audit_identity: AuditIdentity,
+    commands: Option<ExecutorCommands>,
+    transformer: Arc<dyn ResponseTransformer>,
+    settings: ConnectionSettings,
+    deflate: Option<Deflate>,
+    cipher: Option<FrameCipher>,
+}
+
+/// What the middlewares in front of the proxy attach to the request of a connection.
+struct ConnectionRequest {
+    auth_token: Option<AuthToken>,
+    encoding_protoc: EncodingProtocol,
+    client_addr: Option<IpAddr>,
+    identity: Option<ClientIdentity>,
+    commands: Option<ExecutorCommands>,
+    transformer: Arc<dyn ResponseTransformer>,
+}
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ConnectionRequest {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let extensions = &parts.extensions;
+        Ok(Self {
+            auth_token: extensions.get::<Option<AuthToken>>().cloned().flatten(),
+            encoding_protoc: extensions
+                .get::<EncodingProtocol>()
+                .copied()
+                .unwrap_or(EncodingProtocol::Flatbuffers),
+            client_addr: extensions.get::<ClientAddr>().map(|ClientAddr(addr)| *addr),
+            identity: extensions.get::<ClientIdentity>().cloned(),
+            commands: extensions.get::<ExecutorCommands>().cloned(),
+            transformer: extensions
+                .get::<Arc<dyn ResponseTransformer>>()
+                .cloned()
+                .unwrap_or_else(|| Arc::new(IdentityTransformer)),
+        })
+    }
+}
+
+/// What the auth tokens presented by connections are checked against, when connecting and
+/// when authenticating afterwards.
+#[derive(Clone)]
+struct TokenCheck {
+    attested_contracts: AttestedContractMap,
+    expiry: TokenExpiryCheck,
+    malformed: MalformedAuthTokens,
+}
+
+impl TokenCheck {
+    /// Whether the connection is refused for presenting a malformed token, which is ignored
+    /// otherwise.
+    fn refuses_malformed(&self, token: &AuthToken, client_addr: Option<IpAddr>) -> bool {
+        match self.malformed {
+            MalformedAuthTokens::Reject => {
+                tracing::warn!(
+                    ?client_addr,
+                    len = token.len(),
+                    "rejected websocket connection with a malformed auth token"
+                );
+                true
+            }
+            MalformedAuthTokens::Ignore => {
+                tracing::warn!(
+                    ?client_addr,
+                    len = token.len(),
+                    "ignored malformed auth token of websocket connection"
+                );
+                false
+            }
+        }
+    }
+
+    /// The contract the token is attested for, unless it is unknown or expired.
+    fn attested(&self, token: &AuthToken) -> Option<ContractInstanceId> {
+        let attested_contracts = self.attested_contracts.read().unwrap();
+
+        // Only collect and log map contents when trace is enabled
+        if tracing::enabled!(tracing::Level::TRACE) {
+            let map_contents: Vec<_> = attested_contracts.keys().cloned().collect();
+            tracing::trace!(?token, "attested_contracts map keys: {:?}", map_contents);
+        }
+
+        let Some((cid, _, issued)) = attested_contracts.get(token) else {
+            tracing::warn!(?token, "Auth token not found in attested_contracts map");
+            return None;
+        };
+        tracing::trace!(?token, ?cid, "Found token in attested_contracts map");
+        if !self.expiry.accepts(*issued) {
+            tracing::warn!(?token, ?cid, "Auth token expired");
+            return None;
+        }
+        Some(*cid)
+    }
+}
+
+/// Headers of the handshake response telling clients how long the node gives requests reading
+/// contracts before failing them and writing them before reporting them as slow, in seconds.
+const READ_TIMEOUT_HEADER: &str = "x-read-timeout-secs";
+const WRITE_TIMEOUT_HEADER: &str = "x-write-timeout-secs";
 
 #[derive(Clone)]
-struct WebSocketRequest(mpsc::Sender<ClientConnection>);
+struct WebSocketRequest(WorkQueueSender);
 
 impl std::ops::Deref for WebSocketRequest {
-    type Target = mpsc::Sender<ClientConnection>;
+    type Target = WorkQueueSender;
 
     fn deref(&self) -> &Self::Target {
         &self.0
@@ -44,48 +316,271 @@ impl std::ops::Deref for WebSocketRequest {
 }
 
 pub(crate) struct WebSocketProxy {
-    proxy_server_request: mpsc::Receiver<ClientConnection>,
+    proxy_server_request: WorkQueueReceiver,
     response_channels: HashMap<ClientId, mpsc::UnboundedSender<HostCallbackResult>>,
+    /// Contracts each connected client subscribed to.
+    subscriptions: SubscriptionRegistry,
+    duplicate_subscriptions: DuplicateSubscriptions,
+    /// Requests received from clients and not yet handed to the node.
+    pending: FairQueue<OpenRequest<'static>>,
+    response_transformer: Arc<dyn ResponseTransformer>,
+    pending_responses: Arc<PendingResponses>,
+    /// The HTTP server the proxy is served through, shut down along with the proxy.
+    server: Option<ServerHandle>,
+    /// Sender to the queue of the proxy for the gRPC interface, until taken to serve it.
+    #[cfg(feature = "grpc")]
+    grpc_requests: Option<WorkQueueSender>,
+    #[cfg(feature = "grpc")]
+    grpc_server: Option<ServerHandle>,
+    connections: Arc<Connections>,
+    timings: Arc<RequestTimings>,
+    one_shot_requests: Arc<OneShotRequests>,
+    one_shot: OneShotSubscriptions,
+    metrics: GatewayMetrics,
+    /// When the node picked up the requests of each client it has yet to answer.
+    picked_up: HashMap<ClientId, VecDeque<Instant>>,
+}
+
+/// Connections and subscriptions of a [`WebSocketProxy`], so tests can set up a known state
+/// without going through the connection of every client.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct ProxyState {
+    pub connections: HashMap<ClientId, mpsc::UnboundedSender<HostCallbackResult>>,
+    pub subscriptions: HashMap<ClientId, HashSet<ContractKey>>,
 }
 
 const PARALLELISM: usize = 10; // TODO: get this from config, or whatever optimal way
+/// Requests scheduled ahead of the node picking them up, the rest wait in the work queue.
+const MAX_SCHEDULED: usize = 16 * PARALLELISM;
 
 impl WebSocketProxy {
     pub fn create_router(server_routing: Router) -> (Self, Router) {
         // Create a default empty attested contracts map
         let attested_contracts = Arc::new(RwLock::new(HashMap::<
             AuthToken,
-            (ContractInstanceId, ClientId),
+            (ContractInstanceId, ClientId, std::time::Instant),
         >::new()));
-        Self::create_router_with_attested_contracts(server_routing, attested_contracts)
+        Self::create_router_with_attested_contracts(
+            server_routing,
+            &WebsocketApiConfig::default(),
+            attested_contracts,
+            Arc::new(WorkQueueMetrics::default()),
+        )
     }
 
     pub fn create_router_with_attested_contracts(
         server_routing: Router,
+        config: &WebsocketApiConfig,
         attested_contracts: AttestedContractMap,
+        work_queue: Arc<WorkQueueMetrics>,
     ) -> (Self, Router) {
-        let (proxy_request_sender, proxy_server_request) = mpsc::channel(PARALLELISM);
+        let metrics = GatewayMetrics::new(work_queue.clone());
+        let (proxy_request_sender, proxy_server_request) =
+            work_queue::work_queue(PARALLELISM, work_queue);
+        let tenants = Arc::new(TenantRegistry::new(config.tenant_limits));
+        let deliveries = Arc::new(Deliveries::default());
+        let records = SubscriptionRecords {
+            update_log: Arc::new(UpdateLog::new(config.retained_updates)),
+            deliveries: deliveries.clone(),
+            coalescing: Arc::new(CoalescingWindows::new(&config.notification_coalescing)),
+            slow_consumers: Arc::new(config.slow_consumers.clone()),
+            aggregation: Arc::new(config.notification_aggregation.clone()),
+            memory: Arc::new(NotificationMemory::new(config.subscription_memory)),
+        };
+        let pending_responses = Arc::new(PendingResponses::new(config.max_pending_response_bytes));
+        let connections = Arc::new(Connections::new(metrics.clone()));
+        let timings = Arc::new(RequestTimings::default());
+        let one_shot_requests = Arc::new(OneShotRequests::default());
+        let idempotent_writes =
+            IdempotentWrites::new(Duration::from_secs(config.idempotency_ttl_secs));
+        let sessions = Sessions::new(
+            Duration::from_secs(config.session_ttl_secs),
+            attested_contracts.clone(),
+            TokenExpiryCheck::new(config.token_expiry),
+        );
+        #[cfg(feature = "grpc")]
+        let grpc_requests = config.grpc_port.map(|_| proxy_request_sender.clone());
+
+        let admin = Router::new()
+            .route("/v1/admin/tenants", get(tenant_metrics))
+            .route("/v1/admin/responses", get(pending_response_bytes))
+            .route("/v1/admin/connections", get(open_connections))
+            .route("/v1/admin/subscriptions", get(subscription_deliveries))
+            .route_layer(axum::middleware::from_fn_with_state(
+                AdminAuth::new(config.admin_token.clone()),
+                require_admin,
+            ));
 
         // Using Extension instead of with_state to avoid changing the Router's type parameter
         let router = server_routing
             .route("/v1/contract/command", get(websocket_commands))
+            .merge(admin)
+            .route("/v1/load", get(load_summary))
+            .route(
+                "/v1/contract/command/dictionaries",
+                get(compression_dictionaries),
+            )
+            .route(
+                "/v1/contract/command/dictionaries/:protocol",
+                get(compression_dictionary),
+            )
+            .layer(Extension(Arc::new(GatewayState {
+                request_sender: WebSocketRequest(proxy_request_sender),
+                token_check: TokenCheck {
+                    attested_contracts: attested_contracts.clone(),
+                    expiry: TokenExpiryCheck::new(config.token_expiry),
+                    malformed: config.malformed_auth_tokens,
+                },
+                tenants,
+                connections: connections.clone(),
+                records,
+                snapshots: SnapshotEncodings::default(),
+                pending_responses: pending_responses.clone(),
+                settings: ConnectionSettings::new(config),
+                request_timeouts: config.request_timeouts,
+                dictionaries: Dictionaries::load(&config.compression_dictionaries),
+                timings: timings.clone(),
+                idempotent_writes,
+                one_shot_requests: one_shot_requests.clone(),
+                sessions,
+                maintenance: Maintenance::schedule(config.maintenance_window),
+                bandwidth: Arc::new(Bandwidth::new(config.max_outbound_bytes_per_sec)),
+                audit: Arc::new(
+                    config
+                        .audit_log
+                        .as_deref()
+                        .map(|path| {
+                            AuditTrail::open(
+                                path,
+                                config.audit_log_key.as_deref(),
+                                config.audit_log_full,
+                                Duration::from_secs(config.audit_log_max_block_secs),
+                            )
+                        })
+                        .unwrap_or_default(),
+                ),
+                metrics: metrics.clone(),
+                session_keys: config
+                    .frame_encryption
+                    .then(|| Arc::new(SessionKeys::generate())),
+            })))
             .layer(Extension(attested_contracts))
-            .layer(Extension(WebSocketRequest(proxy_request_sender)))
+            .layer(Extension(TokenExpiryCheck::new(config.token_expiry)))
             .layer(axum::middleware::from_fn(connection_info));
 
         (
             WebSocketProxy {
                 proxy_server_request,
                 response_channels: HashMap::new(),
+                subscriptions: SubscriptionRegistry::new(config.max_node_subscriptions),
+                duplicate_subscriptions: config.duplicate_subscriptions,
+                pending: FairQueue::new(PayloadCost, MAX_SCHEDULED),
+                response_transformer: Arc::new(IdentityTransformer),
+                pending_responses,
+                server: None,
+                #[cfg(feature = "grpc")]
+                grpc_requests,
+                #[cfg(feature = "grpc")]
+                grpc_server: None,
+                connections,
+                timings,
+                one_shot_requests,
+                one_shot: OneShotSubscriptions::default(),
+                metrics,
+                picked_up: HashMap::new(),
             },
             router,
         )
     }
 
+    pub fn with_response_transformer(mut self, transformer: Arc<dyn ResponseTransformer>) -> Self {
+        self.response_transformer = transformer;
+        self
+    }
+
+    pub fn with_server(mut self, server: ServerHandle) -> Self {
+        self.server = Some(server);
+        self
+    }
+
+    /// Requests of the gRPC interface go through the same queue as those of the websocket
+    /// connections; the sender to it is there when the interface is configured.
+    #[cfg(feature = "grpc")]
+    pub fn take_grpc_requests(&mut self) -> Option<WorkQueueSender> {
+        self.grpc_requests.take()
+    }
+
+    #[cfg(feature = "grpc")]
+    pub fn with_grpc_server(mut self, server: ServerHandle) -> Self {
+        self.grpc_server = Some(server);
+        self
+    }
+
+    /// Has the server stop accepting connections, if the proxy is served through one.
+    pub fn stop_accepting(&mut self) {
+        if let Some(server) = &mut self.server {
+            server.stop_accepting();
+        }
+        #[cfg(feature = "grpc")]
+        if let Some(server) = &mut self.grpc_server {
+            server.stop_accepting();
+        }
+    }
+
+    /// Handle to the metrics of the gateway the proxy is part of.
+    pub fn metrics(&self) -> GatewayMetrics {
+        self.metrics.clone()
+    }
+
+    /// The state of the server the proxy is served through, if any.
+    pub fn server_state(&self) -> Option<ServerStateWatch> {
+        self.server.as_ref().map(ServerHandle::state)
+    }
+
+    /// Closes the connection of every client, dropping their subscriptions, and waits until
+    /// all of them are closed.
+    pub async fn close_subscriptions(&mut self) {
+        tracing::info!(
+            connections = self.response_channels.len(),
+            subscriptions = self.subscriptions.active(),
+            "closing websocket connections"
+        );
+        // every connection closes once its channel for responses is
+        self.response_channels.clear();
+        self.subscriptions.clear();
+        self.one_shot = OneShotSubscriptions::default();
+        self.picked_up.clear();
+        self.connections.all_closed().await;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn snapshot(&self) -> ProxyState {
+        ProxyState {
+            connections: self.response_channels.clone(),
+            subscriptions: self.subscriptions.clients().clone(),
+        }
+    }
+
+    /// Replaces the connections and subscriptions known to the proxy.
+    #[cfg(test)]
+    pub(crate) fn restore(&mut self, state: ProxyState) {
+        self.response_channels = state.connections;
+        self.subscriptions.replace(state.subscriptions);
+    }
+
+    fn drop_client(&mut self, id: &ClientId) {
+        self.response_channels.remove(id);
+        let subscriptions = self.subscriptions.remove_client(id);
+        self.one_shot.remove_client(id);
+        self.picked_up.remove(id);
+        tracing::info!(subscriptions, "dropped connection to client #{id}");
+    }
+
     async fn internal_proxy_recv(
         &mut self,
         msg: ClientConnection,
-    ) -> Result<Option<OpenRequest>, ClientError> {
+    ) -> Result<Option<OpenRequest<'static>>, ClientError> {
         match msg {
             ClientConnection::NewConnection { callbacks, .. } => {
                 // is a new client, assign an id and open a channel to communicate responses from the node
@@ -96,13 +591,76 @@ impl WebSocketProxy {
                 self.response_channels.insert(cli_id, callbacks);
                 Ok(None)
             }
+            ClientConnection::Unsubscribed { client_id, key } => {
+                self.subscriptions.remove(&client_id, &key);
+                Ok(None)
+            }
             ClientConnection::Request {
                 client_id,
                 req,
                 auth_token,
                 attested_contract,
             } => {
+                if let ClientRequest::Disconnect { .. } = &*req {
+                    // nothing is sent to the client anymore, the node is still told of it
+                    self.drop_client(&client_id);
+                }
                 let open_req = match &*req {
+                    ClientRequest::ContractOp(ContractRequest::Subscribe { key, .. })
+                        if self.one_shot_requests.take(client_id, key) =>
+                    {
+                        tracing::debug!(%client_id, contract = %key, "subscribing for the state only");
+                        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+                        self.one_shot.open(
+                            client_id,
+                            *key,
+                            rx,
+                            auth_token.clone(),
+                            attested_contract,
+                        );
+                        OpenRequest::new(client_id, req)
+                            .with_notification(tx)
+                            .with_token(auth_token)
+                            .with_attested_contract(attested_contract)
+                    }
+                    ClientRequest::ContractOp(ContractRequest::Subscribe { key, .. })
+                        if self.is_duplicate_subscription(client_id, key) =>
+                    {
+                        tracing::debug!(
+                            %client_id,
+                            contract = %key,
+                            subscriptions = ?self.subscriptions.of(&client_id),
+                            "already subscribed to contract"
+                        );
+                        let subscribed = ContractResponse::SubscribeResponse {
+                            key: *key,
+                            subscribed: true,
+                        };
+                        self.send(client_id, Ok(subscribed.into())).await?;
+                        return Ok(None);
+                    }
+                    ClientRequest::ContractOp(ContractRequest::Subscribe { key, .. })
+                        if self.subscriptions.full().is_some()
+                            && !self.subscriptions.holds(&client_id, key) =>
+                    {
+                        let max = self.subscriptions.full().unwrap_or_default();
+                        tracing::debug!(
+                            %client_id,
+                            contract = %key,
+                            max,
+                            "node-wide subscription limit reached"
+                        );
+                        let err = ContractError::Subscribe {
+                            key: *key,
+                            cause: format!(
+                                "the node already holds its maximum of {max} subscriptions"
+                            )
+                            .into(),
+                        };
+                        self.send(client_id, Err(ErrorKind::RequestError(err.into()).into()))
+                            .await?;
+                        return Ok(None);
+                    }
                     ClientRequest::ContractOp(ContractRequest::Subscribe { key, .. }) => {
                         tracing::debug!(%client_id, contract = %key, "subscribing to contract");
                         // intercept subscription messages because they require a callback subscription channel
@@ -114,6 +672,7 @@ impl WebSocketProxy {
                                 callback: rx,
                             })
                             .map_err(|_| ErrorKind::ChannelClosed)?;
+                            self.subscriptions.request(client_id, *key);
                             OpenRequest::new(client_id, req)
                                 .with_notification(tx)
                                 .with_token(auth_token)
@@ -134,6 +693,50 @@ impl WebSocketProxy {
             }
         }
     }
+
+    /// Whether the subscription would be the second one of the client to the contract, which
+    /// is reconciled with the one it has instead of subscribing again.
+    fn is_duplicate_subscription(&self, client_id: ClientId, key: &ContractKey) -> bool {
+        self.duplicate_subscriptions == DuplicateSubscriptions::Reconcile
+            && self.subscriptions.contains(&client_id, key)
+    }
+
+    /// Queues a request until the node is ready to process it, heavier requests delay
+    /// the following ones from the same client.
+    fn schedule(&mut self, req: OpenRequest<'static>) {
+        self.proxy_server_request.metrics().hold();
+        self.pending.push(req.client_id, req);
+    }
+
+    /// Holds the subscription of the client once the node answers it took it.
+    fn acknowledge_subscription(
+        &mut self,
+        id: ClientId,
+        result: &Result<HostResponse, ClientError>,
+    ) {
+        match result {
+            Ok(HostResponse::ContractResponse(ContractResponse::SubscribeResponse {
+                key,
+                subscribed,
+            })) => self.subscriptions.acknowledge(&id, key, *subscribed),
+            Err(err) => {
+                if let ErrorKind::RequestError(RequestError::ContractError(
+                    ContractError::Subscribe { key, .. },
+                )) = err.kind()
+                {
+                    // never subscribed, the client may try again
+                    self.subscriptions.acknowledge(&id, key, false);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn next_scheduled(&mut self) -> Option<OpenRequest<'static>> {
+        let req = self.pending.pop()?;
+        self.proxy_server_request.metrics().release();
+        Some(req)
+    }
 }
 
 struct EncodingProtocolExt(EncodingProtocol);
@@ -234,80 +837,394 @@ async fn connection_info(
     next.run(req).await
 }
 
-async fn websocket_commands(
-    ws: WebSocketUpgrade,
-    Extension(auth_token): Extension<Option<AuthToken>>,
-    Extension(encoding_protoc): Extension<EncodingProtocol>,
-    Extension(rs): Extension<WebSocketRequest>,
-    Extension(attested_contracts): Extension<AttestedContractMap>,
+async fn tenant_metrics(
+    Extension(state): Extension<Arc<GatewayState>>,
+) -> Json<Vec<tenant::TenantMetrics>> {
+    Json(state.tenants.metrics())
+}
+
+async fn pending_response_bytes(
+    Extension(state): Extension<Arc<GatewayState>>,
+) -> Json<pending::PendingResponsesSnapshot> {
+    Json(state.pending_responses.snapshot())
+}
+
+async fn open_connections(
+    Extension(state): Extension<Arc<GatewayState>>,
+) -> Json<Vec<connections::ConnectionListing>> {
+    Json(state.connections.list())
+}
+
+async fn load_summary(Extension(state): Extension<Arc<GatewayState>>) -> Json<LoadSummary> {
+    Json(state.metrics.load())
+}
+
+async fn subscription_deliveries(
+    Extension(state): Extension<Arc<GatewayState>>,
+) -> Json<Vec<delivery::DeliveryStats>> {
+    Json(state.records.deliveries.list())
+}
+
+async fn compression_dictionaries(
+    Extension(state): Extension<Arc<GatewayState>>,
+) -> Json<Vec<compression::AdvertisedDictionary>> {
+    Json(state.dictionaries.advertised())
+}
+
+async fn compression_dictionary(
+    axum::extract::Path(protocol): axum::extract::Path<String>,
+    Extension(state): Extension<Arc<GatewayState>>,
 ) -> Response {
-    let on_upgrade = move |ws: WebSocket| async move {
-        // Get the data we need and immediately drop the lock
-        let auth_and_instance = if let Some(token) = auth_token.as_ref() {
-            let attested_contracts_read = attested_contracts.read().unwrap();
+    match state.dictionaries.get(&protocol) {
+        Some(dictionary) => dictionary.bytes().to_vec().into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            format!("no compression dictionary for subprotocol `{protocol}`"),
+        )
+            .into_response(),
+    }
+}
 
-            // Only collect and log map contents when trace is enabled
-            if tracing::enabled!(tracing::Level::TRACE) {
-                let map_contents: Vec<_> = attested_contracts_read.keys().cloned().collect();
-                tracing::trace!(?token, "attested_contracts map keys: {:?}", map_contents);
-            }
+/// A range of a collection the executor started streaming for the contract, or why not.
+type StartedRange = (String, Result<mpsc::Receiver<RangeFrame>, String>);
+
+type RangeFrames = futures::stream::BoxStream<'static, (String, RangeFrame)>;
 
-            if let Some((cid, _)) = attested_contracts_read.get(token) {
-                tracing::trace!(?token, ?cid, "Found token in attested_contracts map");
-                Some((token.clone(), *cid))
-            } else {
-                tracing::warn!(?token, "Auth token not found in attested_contracts map");
-                None
+async fn websocket_commands(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<GatewayState>>,
+    ConnectionRequest {
+        auth_token,
+        encoding_protoc,
+        client_addr,
+        identity,
+        commands,
+        transformer,
+    }: ConnectionRequest,
+) -> Response {
+    if let Some(end) = state.maintenance.until() {
+        tracing::debug!(
+            ?client_addr,
+            "rejected websocket connection under maintenance"
+        );
+        let retry_after = (end - chrono::Utc::now()).num_seconds().max(1);
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(axum::http::header::RETRY_AFTER, retry_after.to_string())],
+            format!("the node is under maintenance until {}", end.to_rfc3339()),
+        )
+            .into_response();
+    }
+    let auth_token = match auth_token {
+        Some(token) if !token.is_well_formed() => {
+            if state.token_check.refuses_malformed(&token, client_addr) {
+                return (StatusCode::UNAUTHORIZED, "malformed auth token").into_response();
             }
-        } else {
-            tracing::trace!("No auth token provided in WebSocket request");
+            // as if the connection came without one
             None
-        }; // RwLockReadGuard is dropped here
+        }
+        token => token,
+    };
+    if auth_token.is_none() {
+        tracing::trace!("No auth token provided in WebSocket request");
+    }
+    let auth_and_instance = auth_token
+        .and_then(|token| {
+            let contract = state.token_check.attested(&token)?;
+            Some((token, contract))
+        })
+        // a token of its own for the contract of its certificate, issued as it connects
+        .or_else(|| {
+            let contract = identity.as_ref()?.contract?;
+            Some((AuthToken::generate(), contract))
+        });
+
+    let tenant = TenantId::resolve(
+        identity.as_ref().map(|identity| &*identity.name),
+        auth_and_instance.as_ref().map(|(_, cid)| cid),
+    );
+    let tenant = match state.tenants.connect(tenant.clone()) {
+        Ok(tenant) => tenant,
+        Err(err) => {
+            tracing::debug!(?client_addr, %tenant, %err, "rejected websocket connection");
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                format!("tenant `{tenant}`: {err}"),
+            )
+                .into_response();
+        }
+    };
 
+    let audit_identity = AuditIdentity {
+        tenant: tenant.tenant().clone(),
+        address: client_addr,
+        certificate: identity.map(|identity| identity.name),
+        attested: auth_and_instance
+            .as_ref()
+            .map(|(_, contract)| contract.to_string()),
+    };
+    let details = ConnectionDetails {
+        tenant: tenant.tenant().clone(),
+        label: headers
+            .get(CLIENT_LABEL_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(ClientLabel::sanitize),
+        address: client_addr,
+    };
+
+    let notification_format = match NotificationFormat::negotiate(&headers) {
+        Ok(format) => format,
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+    let settings = state.settings;
+    let notification_queue = match negotiate_discipline(&headers, settings.notification_queue) {
+        Ok(discipline) => discipline,
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+    let timeouts = match negotiate_timeouts(&headers, settings.timeouts) {
+        Ok(timeouts) => timeouts,
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+    let settings = ConnectionSettings {
+        response_limit: settings
+            .response_limit
+            .for_client(headers.contains_key(CHUNKED_RESPONSES_HEADER)),
+        multipart: matches!(encoding_protoc, EncodingProtocol::Native)
+            && headers.contains_key(MULTIPART_RESPONSES_HEADER),
+        notification_versions: headers.contains_key(NOTIFICATION_VERSIONS_HEADER),
+        subscription_acks: headers.contains_key(SUBSCRIPTION_ACKS_HEADER),
+        notification_format,
+        notification_queue,
+        timeouts,
+        ..settings
+    };
+    let negotiated = state.dictionaries.negotiate(&headers);
+    let ws = match negotiated.protocol {
+        Some(protocol) => ws.protocols([protocol]),
+        None => ws,
+    };
+    let deflate = negotiated.deflate;
+    let handshake_deflate = deflate.clone();
+    let request_timeouts = state.request_timeouts;
+    let on_upgrade = move |ws: WebSocket| async move {
         // Only evaluate auth_and_instance for trace when trace is enabled
         if tracing::enabled!(tracing::Level::TRACE) {
-            tracing::trace!(protoc = ?ws.protocol(), ?auth_and_instance, "websocket connection established");
+            tracing::trace!(protoc = ?ws.protocol(), ?client_addr, label = ?details.label, ?auth_and_instance, "websocket connection established");
         } else {
-            tracing::trace!(protoc = ?ws.protocol(), "websocket connection established");
+            tracing::trace!(protoc = ?ws.protocol(), ?client_addr, label = ?details.label, "websocket connection established");
         }
-        if let Err(error) =
-            websocket_interface(rs.clone(), auth_and_instance, encoding_protoc, ws).await
-        {
+        let connection = ConnectionSetup {
+            auth_token: auth_and_instance,
+            encoding_protoc,
+            tenant,
+            details,
+            audit_identity,
+            commands,
+            transformer,
+            settings,
+            deflate,
+            cipher: state.session_keys.clone().map(FrameCipher::new),
+        };
+        if let Err(error) = websocket_interface(state, connection, ws).await {
             tracing::error!("{error}");
         }
     };
 
-    ws.on_upgrade(on_upgrade)
+    let mut response = ws.on_upgrade(on_upgrade);
+    for (header, value) in [
+        (READ_TIMEOUT_HEADER, request_timeouts.read_secs),
+        (WRITE_TIMEOUT_HEADER, request_timeouts.write_secs),
+    ]
+    .into_iter()
+    .chain(timeout_headers(&timeouts))
+    {
+        response
+            .headers_mut()
+            .insert(header, axum::http::HeaderValue::from(value));
+    }
+    for (header, value) in handshake_deflate.iter().flat_map(Deflate::response_headers) {
+        if let Ok(value) = axum::http::HeaderValue::from_str(value) {
+            response.headers_mut().insert(header, value);
+        }
+    }
+    response
+}
+
+/// Closes the subscriptions set up by a transactional batch which failed, dropping their
+/// listeners closes the channels the node notifies them through.
+async fn roll_back_batch(
+    client_id: ClientId,
+    keys: Vec<ContractKey>,
+    listeners: &Mutex<VecDeque<SubscriptionListener>>,
+    request_sender: &WebSocketRequest,
+    session: Option<(&Sessions, String)>,
+) -> anyhow::Result<()> {
+    tracing::debug!(%client_id, ?keys, "rolling back subscriptions of a transactional batch");
+    listeners
+        .lock()
+        .await
+        .retain(|listener| !keys.contains(&listener.key));
+    for key in keys {
+        if let Some((sessions, token)) = &session {
+            sessions.unsubscribed(token, &key);
+        }
+        request_sender
+            .send(ClientConnection::Unsubscribed { client_id, key })
+            .await?;
+    }
+    Ok(())
 }
 
 async fn websocket_interface(
-    request_sender: WebSocketRequest,
-    mut auth_token: Option<(AuthToken, ContractInstanceId)>,
-    encoding_protoc: EncodingProtocol,
+    state: Arc<GatewayState>,
+    connection: ConnectionSetup,
     ws: WebSocket,
 ) -> anyhow::Result<()> {
-    let (mut response_rx, client_id) =
-        new_client_connection(&request_sender, auth_token.clone()).await?;
-    let (mut server_sink, mut client_stream) = ws.split();
-    let contract_updates: Arc<Mutex<VecDeque<(_, mpsc::UnboundedReceiver<HostResult>)>>> =
+    let GatewayState {
+        request_sender,
+        connections,
+        records:
+            SubscriptionRecords {
+                update_log,
+                deliveries,
+                coalescing,
+                slow_consumers,
+                aggregation,
+                memory,
+            },
+        snapshots,
+        pending_responses,
+        timings,
+        idempotent_writes,
+        one_shot_requests,
+        sessions,
+        bandwidth,
+        audit,
+        ..
+    } = &*state;
+    let ConnectionSetup {
+        mut auth_token,
+        encoding_protoc,
+        mut tenant,
+        details,
+        audit_identity,
+        commands,
+        transformer,
+        settings,
+        deflate,
+        cipher,
+    } = connection;
+    let ConnectionSettings {
+        outbound_priority,
+        notification_queue,
+        timeouts,
+        unknown_fields,
+        response_limit,
+        max_contracts,
+        multipart,
+        notification_versions,
+        subscription_acks,
+        notification_format,
+        #[cfg(feature = "fault-injection")]
+        faults,
+    } = settings;
+    #[cfg(feature = "fault-injection")]
+    let mut faults = faults.map(faults::Faults::new);
+    let mut contracts = TouchedContracts::new(max_contracts);
+    // contracts subscribed to only for a snapshot of their state, accounted to the tenant
+    // until it is in
+    let mut snapshotting = HashSet::new();
+    let mut multipart = multipart.then(MultipartResponses::default);
+    // numbers the subscriptions in their acknowledgments
+    let mut subscriptions_set_up: u64 = 0;
+    let (response_rx, client_id) =
+        new_client_connection(request_sender, auth_token.clone()).await?;
+    let _listed = connections.open(client_id, details);
+    let mut audit = audit.connect(client_id, audit_identity);
+    let mut response_rx = PendingReceiver::new(response_rx, pending_responses.clone());
+    let (server_sink, mut client_stream) = ws.split();
+    let sealing = cipher.clone();
+    // compressed before being sealed, sealed messages don't compress
+    let server_sink = futures::SinkExt::with(server_sink, move |msg| {
+        let msg = match &deflate {
+            Some(deflate) => deflate.compress(msg),
+            None => Ok(msg),
+        };
+        futures::future::ready(match &sealing {
+            Some(cipher) => msg.and_then(|msg| cipher.seal(msg)),
+            None => msg,
+        })
+    });
+    let (outbound, mut writer) = Outbound::start(
+        server_sink,
+        outbound_priority,
+        notification_queue,
+        timeouts.send(),
+        bandwidth.clone(),
+    );
+    let contract_updates: Arc<Mutex<VecDeque<SubscriptionListener>>> =
         Arc::new(Mutex::new(VecDeque::new()));
+    let batches = parking_lot::Mutex::new(PendingBatches::default());
+    // slow consumer policies picked for subscriptions yet to be set up
+    let picked_policies = parking_lot::Mutex::new(HashMap::<ContractInstanceId, _>::new());
+    // and aggregations
+    let picked_aggregations = parking_lot::Mutex::new(HashMap::<ContractInstanceId, _>::new());
+    let time_next_request = AtomicBool::new(false);
+    let next_idempotency_key = parking_lot::Mutex::new(None);
+    // resumption token of the session kept for the connection, if any
+    let session = parking_lot::Mutex::new(None::<String>);
+    // ranges of collections requested from the executor, and those it streams
+    let (ranges_started, mut ranges_starting) = mpsc::unbounded_channel::<StartedRange>();
+    let mut ranges = futures::stream::SelectAll::<RangeFrames>::new();
+    // the pings keeping the connection alive, and their answers, aren't traffic
+    let mut keepalive = timeouts
+        .keepalive()
+        .map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
+    let keepalive_turn = AtomicBool::new(false);
+    let mut last_traffic = Instant::now();
     loop {
+        if !keepalive_turn.swap(false, Ordering::Relaxed) {
+            last_traffic = Instant::now();
+        }
         let contract_updates_cp = contract_updates.clone();
+        let outbound_cp = &outbound;
         let listeners_task = async move {
             loop {
                 let mut lock = contract_updates_cp.lock().await;
                 let active_listeners = &mut *lock;
+                if !outbound_cp.has_room() {
+                    // the client is behind, what comes meanwhile waits in the subscriptions
+                    for listener in active_listeners.iter_mut() {
+                        listener.absorb()?;
+                    }
+                } else {
+                    // those paused hold back as much at the memory cap
+                    for listener in active_listeners.iter_mut() {
+                        listener.bound_waiting()?;
+                    }
+                }
                 for _ in 0..active_listeners.len() {
-                    if let Some((key, mut listener)) = active_listeners.pop_front() {
-                        match listener.try_recv() {
-                            Ok(r) => {
-                                active_listeners.push_back((key, listener));
-                                return Ok(r);
+                    if !outbound_cp.has_room() {
+                        break;
+                    }
+                    if let Some(mut listener) = active_listeners.pop_front() {
+                        match listener.try_next() {
+                            Ok(Some(r)) => {
+                                let causality = listener.causality().map(|c| (listener.key, c));
+                                let delivery = listener.pending_delivery();
+                                let priority = listener.priority();
+                                // a shed subscription ends with the error telling its client
+                                let shed = listener.is_shed().then_some(listener.key);
+                                if shed.is_none() {
+                                    active_listeners.push_back(listener);
+                                }
+                                return Ok((r, causality, delivery, priority, shed));
                             }
-                            Err(mpsc::error::TryRecvError::Empty) => {
-                                active_listeners.push_back((key, listener));
+                            Ok(None) => {
+                                active_listeners.push_back(listener);
                             }
-                            Err(err @ mpsc::error::TryRecvError::Disconnected) => {
+                            Err(err) => {
                                 tracing::debug!(err = ?err, "listener channel disconnected");
                                 return Err(anyhow::anyhow!(err));
                             }
@@ -331,37 +1248,536 @@ async fn websocket_interface(
                 }
                 Ok(v) => v,
             };
-            process_client_request(
+            if let Ok(Message::Pong(_)) = &next_msg {
+                keepalive_turn.store(true, Ordering::Relaxed);
+                return Ok(None);
+            }
+            let next_msg = match (next_msg, &cipher) {
+                (Ok(Message::Binary(data)), Some(cipher)) => match cipher.open(&data) {
+                    Ok(opened) => Ok(Message::Binary(opened)),
+                    Err(cause) => {
+                        return Ok(Some(ControlResponse::Error { cause }.into_message()));
+                    }
+                },
+                (msg, _) => msg,
+            };
+            if let Ok(Message::Text(text)) = &next_msg {
+                if let Some(frame) = ControlFrame::parse(text) {
+                    match frame {
+                        ControlFrame::Grant { credits } => {
+                            // not acknowledged, it would spend one of the credits
+                            outbound.grant(credits);
+                            return Ok(None);
+                        }
+                        ControlFrame::Timing {} => {
+                            time_next_request.store(true, Ordering::Relaxed);
+                            return Ok(None);
+                        }
+                        ControlFrame::IdempotencyKey { key } => {
+                            *next_idempotency_key.lock() = Some(key);
+                            return Ok(None);
+                        }
+                        ControlFrame::Snapshot { key } => {
+                            let key = match ContractKey::from_id(key.as_str()) {
+                                Ok(parsed) => parsed,
+                                Err(err) => {
+                                    let response = ControlResponse::Error {
+                                        cause: format!("invalid contract key `{key}`: {err}"),
+                                    };
+                                    return Ok(Some(response.into_message()));
+                                }
+                            };
+                            if let Err(err) = contracts.touch(&key) {
+                                let response = ControlResponse::Error {
+                                    cause: err.to_string(),
+                                };
+                                return Ok(Some(response.into_message()));
+                            }
+                            if let Err(cause) = account_subscription(&mut tenant, &key) {
+                                return Ok(Some(ControlResponse::Error { cause }.into_message()));
+                            }
+                            snapshotting.insert(*key.id());
+                            one_shot_requests.request(client_id, key);
+                            let req = ClientRequest::ContractOp(ContractRequest::Subscribe {
+                                key,
+                                summary: None,
+                            });
+                            request_sender
+                                .send(ClientConnection::Request {
+                                    client_id,
+                                    req: Box::new(req),
+                                    auth_token: auth_token.as_ref().map(|t| t.0.clone()),
+                                    attested_contract: auth_token.as_ref().map(|t| t.1),
+                                })
+                                .await
+                                .map_err(|err| Some(err.into()))?;
+                            return Ok(None);
+                        }
+                        ControlFrame::Subscribe { keys, mode } => {
+                            let held: Vec<_> = contract_updates
+                                .lock()
+                                .await
+                                .iter()
+                                .map(|listener| listener.key)
+                                .collect();
+                            let subscribe = match batches.lock().start(keys, mode, held) {
+                                Ok(subscribe) => subscribe,
+                                Err(response) => return Ok(Some(response.into_message())),
+                            };
+                            for key in subscribe {
+                                let accounted = contracts
+                                    .touch(&key)
+                                    .map_err(|err| err.to_string())
+                                    .and_then(|_| account_subscription(&mut tenant, &key));
+                                if let Err(cause) = accounted {
+                                    let err = ErrorKind::RequestError(RequestError::ContractError(
+                                        ContractError::Subscribe {
+                                            key,
+                                            cause: cause.into(),
+                                        },
+                                    ))
+                                    .into();
+                                    let batched = batches.lock().record(&Err(err));
+                                    match batched {
+                                        Batched::Complete(response) => {
+                                            return Ok(Some(response.into_message()));
+                                        }
+                                        Batched::RolledBack(response, keys) => {
+                                            let token = session.lock().clone();
+                                            roll_back_batch(
+                                                client_id,
+                                                keys,
+                                                &contract_updates,
+                                                request_sender,
+                                                token.map(|token| (&*sessions, token)),
+                                            )
+                                            .await
+                                            .map_err(Some)?;
+                                            return Ok(Some(response.into_message()));
+                                        }
+                                        Batched::No | Batched::Pending => continue,
+                                    }
+                                }
+                                let req = ClientRequest::ContractOp(ContractRequest::Subscribe {
+                                    key,
+                                    summary: None,
+                                });
+                                request_sender
+                                    .send(ClientConnection::Request {
+                                        client_id,
+                                        req: Box::new(req),
+                                        auth_token: auth_token.as_ref().map(|t| t.0.clone()),
+                                        attested_contract: auth_token.as_ref().map(|t| t.1),
+                                    })
+                                    .await
+                                    .map_err(|err| Some(err.into()))?;
+                            }
+                            return Ok(None);
+                        }
+                        ControlFrame::Session {} => {
+                            let subscribed: Vec<_> = contract_updates
+                                .lock()
+                                .await
+                                .iter()
+                                .map(|listener| listener.key)
+                                .collect();
+                            let token = sessions.open(auth_token.clone(), subscribed);
+                            *session.lock() = Some(token.clone());
+                            return Ok(Some(ControlResponse::Session { token }.into_message()));
+                        }
+                        ControlFrame::Migrate { token } => {
+                            let Some(migrated) = sessions.migrate(&token) else {
+                                tracing::debug!(%client_id, "migrating from an unknown session");
+                                let token = sessions.open(auth_token.clone(), []);
+                                *session.lock() = Some(token.clone());
+                                let response = ControlResponse::Migrated {
+                                    token,
+                                    resumed: false,
+                                    subscriptions: vec![],
+                                };
+                                return Ok(Some(response.into_message()));
+                            };
+                            tracing::debug!(%client_id, subscriptions = migrated.subscriptions.len(), "migrating session");
+                            if auth_token.is_none() && migrated.auth.is_some() {
+                                auth_token = migrated.auth;
+                                audit.authenticated(auth_token.as_ref().map(|t| t.1));
+                            }
+                            let token = migrated.token;
+                            *session.lock() = Some(token.clone());
+                            let mut subscriptions =
+                                Vec::with_capacity(migrated.subscriptions.len());
+                            for key in migrated.subscriptions {
+                                let accounted = contracts
+                                    .touch(&key)
+                                    .map_err(|err| err.to_string())
+                                    .and_then(|_| account_subscription(&mut tenant, &key));
+                                if let Err(cause) = accounted {
+                                    // left out of those resumed, the client subscribes anew
+                                    tracing::debug!(%client_id, contract = %key, %cause, "subscription not migrated");
+                                    continue;
+                                }
+                                let req = ClientRequest::ContractOp(ContractRequest::Subscribe {
+                                    key,
+                                    summary: None,
+                                });
+                                request_sender
+                                    .send(ClientConnection::Request {
+                                        client_id,
+                                        req: Box::new(req),
+                                        auth_token: auth_token.as_ref().map(|t| t.0.clone()),
+                                        attested_contract: auth_token.as_ref().map(|t| t.1),
+                                    })
+                                    .await
+                                    .map_err(|err| Some(err.into()))?;
+                                subscriptions.push(key.to_string());
+                            }
+                            let response = ControlResponse::Migrated {
+                                token,
+                                resumed: true,
+                                subscriptions,
+                            };
+                            return Ok(Some(response.into_message()));
+                        }
+                        ControlFrame::SlowConsumer { key, policy } => {
+                            let active_listeners = &mut *contract_updates.lock().await;
+                            let contract = match ContractKey::from_id(key.as_str()) {
+                                Ok(parsed) => parsed,
+                                Err(err) => {
+                                    let response = ControlResponse::Error {
+                                        cause: format!("invalid contract key `{key}`: {err}"),
+                                    };
+                                    return Ok(Some(response.into_message()));
+                                }
+                            };
+                            if !slow_consumers.allowed_policies.contains(&policy) {
+                                let response = ControlResponse::Error {
+                                    cause: format!("slow consumer policy `{policy:?}` not allowed"),
+                                };
+                                return Ok(Some(response.into_message()));
+                            }
+                            if let Err(err) = contracts.touch(&contract) {
+                                let response = ControlResponse::Error {
+                                    cause: err.to_string(),
+                                };
+                                return Ok(Some(response.into_message()));
+                            }
+                            let mut subscribed = false;
+                            for listener in active_listeners
+                                .iter_mut()
+                                .filter(|listener| listener.key.id() == contract.id())
+                            {
+                                listener.set_backlog(Backlog::new(slow_consumers, Some(policy)));
+                                subscribed = true;
+                            }
+                            if !subscribed {
+                                picked_policies.lock().insert(*contract.id(), policy);
+                            }
+                            let response = ControlResponse::SlowConsumer { key, policy };
+                            return Ok(Some(response.into_message()));
+                        }
+                        ControlFrame::Aggregate {
+                            key,
+                            function,
+                            window_ms,
+                        } => {
+                            let active_listeners = &mut *contract_updates.lock().await;
+                            let contract = match ContractKey::from_id(key.as_str()) {
+                                Ok(parsed) => parsed,
+                                Err(err) => {
+                                    let response = ControlResponse::Error {
+                                        cause: format!("invalid contract key `{key}`: {err}"),
+                                    };
+                                    return Ok(Some(response.into_message()));
+                                }
+                            };
+                            let picked = match Aggregation::new(aggregation, function, window_ms) {
+                                Ok(picked) => picked,
+                                Err(cause) => {
+                                    let response = ControlResponse::Error { cause };
+                                    return Ok(Some(response.into_message()));
+                                }
+                            };
+                            if let Err(err) = contracts.touch(&contract) {
+                                let response = ControlResponse::Error {
+                                    cause: err.to_string(),
+                                };
+                                return Ok(Some(response.into_message()));
+                            }
+                            let mut subscribed = false;
+                            for listener in active_listeners
+                                .iter_mut()
+                                .filter(|listener| listener.key.id() == contract.id())
+                            {
+                                listener.set_aggregation(picked);
+                                subscribed = true;
+                            }
+                            if !subscribed {
+                                picked_aggregations.lock().insert(*contract.id(), picked);
+                            }
+                            let response = ControlResponse::Aggregated {
+                                key,
+                                function,
+                                window_ms,
+                            };
+                            return Ok(Some(response.into_message()));
+                        }
+                        ControlFrame::Range { key, offset, limit } => {
+                            let parsed = match ContractKey::from_id(key.as_str()) {
+                                Ok(parsed) => parsed,
+                                Err(err) => {
+                                    let response = ControlResponse::Error {
+                                        cause: format!("invalid contract key `{key}`: {err}"),
+                                    };
+                                    return Ok(Some(response.into_message()));
+                                }
+                            };
+                            if let Err(err) = contracts.touch(&parsed) {
+                                let response = ControlResponse::Error {
+                                    cause: err.to_string(),
+                                };
+                                return Ok(Some(response.into_message()));
+                            }
+                            let Some(commands) = commands.clone() else {
+                                let response = ControlResponse::Error {
+                                    cause: "ranges of contracts not served".into(),
+                                };
+                                return Ok(Some(response.into_message()));
+                            };
+                            // started apart, this turn of the loop may be cancelled meanwhile
+                            let ranges_started = ranges_started.clone();
+                            tokio::spawn(async move {
+                                let started = commands
+                                    .request(parsed, |respond| ExecutorCommand::Range {
+                                        key: parsed,
+                                        offset,
+                                        limit,
+                                        respond,
+                                    })
+                                    .await
+                                    .map_err(|err| err.to_string());
+                                let _ = ranges_started.send((key, started));
+                            });
+                            return Ok(None);
+                        }
+                        ControlFrame::EncryptionKey {} => {
+                            let response = match &cipher {
+                                Some(cipher) => ControlResponse::EncryptionKey {
+                                    public_key: cipher.public_key().to_owned(),
+                                },
+                                None => ControlResponse::Error {
+                                    cause: "the node doesn't encrypt messages".into(),
+                                },
+                            };
+                            return Ok(Some(response.into_message()));
+                        }
+                        ControlFrame::SessionKey { key } => {
+                            let Some(cipher) = &cipher else {
+                                let cause = "the node doesn't encrypt messages".into();
+                                return Ok(Some(ControlResponse::Error { cause }.into_message()));
+                            };
+                            return Ok(Some(cipher.rotate(&key)));
+                        }
+                        ControlFrame::Health {} => {
+                            let active_listeners = &mut *contract_updates.lock().await;
+                            let health = ControlResponse::health(active_listeners.iter());
+                            return Ok(Some(health.into_message()));
+                        }
+                        ControlFrame::State {} => {
+                            let active_listeners = &mut *contract_updates.lock().await;
+                            let state = ControlResponse::connection_state(
+                                client_id,
+                                auth_token.as_ref().map(|t| &t.1),
+                                active_listeners.iter(),
+                            );
+                            return Ok(Some(state.into_message()));
+                        }
+                        ControlFrame::Subscription(frame) => {
+                            let active_listeners = &mut *contract_updates.lock().await;
+                            let response = frame.apply(active_listeners.iter_mut(), update_log);
+                            if let ControlResponse::Replayed {
+                                key,
+                                snapshot: true,
+                                ..
+                            } = &response
+                            {
+                                // the missed updates are gone, the client has to start over from the current state
+                                let key = ContractKey::from_id(key.as_str())
+                                    .map_err(|err| Some(err.into()))?;
+                                let req = ClientRequest::ContractOp(ContractRequest::Get {
+                                    key,
+                                    return_contract_code: false,
+                                    subscribe: false,
+                                });
+                                request_sender
+                                    .send(ClientConnection::Request {
+                                        client_id,
+                                        req: Box::new(req),
+                                        auth_token: auth_token.as_ref().map(|t| t.0.clone()),
+                                        attested_contract: auth_token.as_ref().map(|t| t.1),
+                                    })
+                                    .await
+                                    .map_err(|err| Some(err.into()))?;
+                            }
+                            return Ok(Some(response.into_message()));
+                        }
+                    }
+                }
+            }
+            let timed = time_next_request.swap(false, Ordering::Relaxed);
+            if timed {
+                timings.start(client_id);
+            }
+            let idempotency_key = next_idempotency_key.lock().take();
+            let requests = ConnectionRequests {
+                state: &state,
+                auth_token: &mut auth_token,
+                tenant: &mut tenant,
+                contracts: &mut contracts,
+                audit: &mut audit,
+            };
+            let processed = process_client_request(
                 client_id,
                 next_msg,
-                &request_sender,
-                &mut auth_token.as_mut().map(|t| t.0.clone()),
-                auth_token.as_mut().map(|t| t.1),
+                requests,
                 encoding_protoc,
+                unknown_fields,
+                idempotency_key,
             )
-            .await
+            .await;
+            if timed && !matches!(processed, Ok(None)) {
+                // rejected without reaching the node
+                timings.finish(client_id);
+            }
+            processed
         };
 
         tokio::select! { biased;
-            msg = async { process_host_response(response_rx.recv().await, client_id, encoding_protoc, &mut server_sink).await } => {
+            msg = response_rx.recv() => {
+                #[cfg(feature = "fault-injection")]
+                let msg = match (msg, faults.as_mut()) {
+                    (Some(HostCallbackResult::Result { id, result }), Some(faults)) => {
+                        match faults.inject(result).await {
+                            Some(result) => Some(HostCallbackResult::Result { id, result }),
+                            None => return Ok(()),
+                        }
+                    }
+                    (msg, _) => msg,
+                };
+                if let Some(HostCallbackResult::Result { result, .. }) = &msg {
+                    match result.as_ref().map_err(|err| err.kind()) {
+                        Err(ErrorKind::RequestError(RequestError::ContractError(
+                            ContractError::Subscribe { key, .. },
+                        ))) => {
+                            snapshotting.remove(key.id());
+                            tenant.subscription_failed(key.id());
+                        }
+                        Err(ErrorKind::RequestError(RequestError::ContractError(
+                            ContractError::MissingContract { key },
+                        ))) if snapshotting.remove(key) => tenant.subscription_failed(key),
+                        Ok(HostResponse::ContractResponse(ContractResponse::GetResponse {
+                            key,
+                            ..
+                        })) if snapshotting.remove(key.id()) => {
+                            // the snapshot is in, closing the subscription it was taken through
+                            drop(tenant.subscribed(key.id()));
+                        }
+                        _ => {}
+                    }
+                    // responses to a batch are answered together once all are in
+                    let batched = batches.lock().record(result);
+                    match batched {
+                        Batched::No => {}
+                        Batched::Pending => continue,
+                        Batched::Complete(response) => {
+                            outbound.respond(response.into_message()).await?;
+                            continue;
+                        }
+                        Batched::RolledBack(response, keys) => {
+                            let token = session.lock().clone();
+                            roll_back_batch(
+                                client_id,
+                                keys,
+                                &contract_updates,
+                                request_sender,
+                                token.map(|token| (&*sessions, token)),
+                            )
+                            .await?;
+                            outbound.respond(response.into_message()).await?;
+                            continue;
+                        }
+                    }
+                }
+                let phases = match &msg {
+                    Some(HostCallbackResult::Result { result, .. }) => {
+                        idempotent_writes.record(client_id, result);
+                        timings.finish(client_id)
+                    }
+                    _ => None,
+                };
+                let encoding_started = Instant::now();
                 let active_listeners = contract_updates.clone();
+                let msg = process_host_response(
+                    msg,
+                    client_id,
+                    encoding_protoc,
+                    response_limit,
+                    multipart.as_mut(),
+                    snapshots,
+                    &outbound,
+                )
+                .await;
+                if let Some(phases) = phases {
+                    let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+                    let timing = ControlResponse::Timing {
+                        queue_ms: millis(phases.queue),
+                        execution_ms: millis(phases.execution),
+                        serialization_ms: millis(encoding_started.elapsed()),
+                    };
+                    outbound.respond(timing.into_message()).await?;
+                }
                 if let Some(NewSubscription { key, callback }) = msg? {
                     tracing::debug!(cli_id = %client_id, contract = %key, "added new notification listener");
+                    let token = session.lock().clone();
+                    if let Some(token) = token {
+                        sessions.subscribed(&token, key);
+                    }
+                    subscriptions_set_up += 1;
+                    if subscription_acks {
+                        let ack = ControlResponse::Acknowledged {
+                            key: key.to_string(),
+                            subscription: subscriptions_set_up,
+                            version: update_log.version(&key),
+                            session: update_log.session(),
+                            mode: NotificationMode::of(notification_versions),
+                            format: notification_format,
+                        };
+                        outbound.respond(ack.into_message()).await?;
+                    }
                     let active_listeners = &mut *active_listeners.lock().await;
-                    active_listeners.push_back((key, callback));
+                    active_listeners.push_back(
+                        SubscriptionListener::new(key, callback)
+                            .with_coalescing(coalescing.of(&key))
+                            .with_aggregation(picked_aggregations.lock().remove(key.id()))
+                            .with_backlog(Backlog::new(
+                                slow_consumers,
+                                picked_policies.lock().remove(key.id()),
+                            ))
+                            .with_update_log(update_log.clone())
+                            .with_delivery(deliveries.track(client_id, &key))
+                            .with_memory(memory.register())
+                            .with_tenant(tenant.subscribed(key.id())),
+                    );
                 }
             }
             process_client_request = client_req_task => {
                 match process_client_request {
-                    Ok(Some(error)) => {
-                        server_sink.send(error).await.inspect_err(|err| {
-                            tracing::debug!(err = %err, "error sending message to client");
-                        })?;
-                    }
+                    Ok(Some(error)) => outbound.respond(error).await?,
                     Ok(None) => continue,
                     Err(None) => {
                         tracing::debug!("client channel closed on request");
-                        let _ = server_sink.send(Message::Close(None)).await;
+                        let _ = outbound.respond(Message::Close(None)).await;
+                        drop(outbound);
+                        let _ = writer.await;
                         return Ok(())
                     },
                     Err(Some(err)) => {
@@ -371,29 +1787,104 @@ async fn websocket_interface(
                 }
             }
             response = listeners_task => {
-                let response = response?;
+                let (response, causality, delivery, priority, shed) = match response {
+                    Ok(response) => response,
+                    Err(err) if err.is::<TooSlow>() => {
+                        tracing::debug!(cli_id = %client_id, %err, "disconnecting slow client");
+                        return Ok(());
+                    }
+                    Err(err) => return Err(err),
+                };
+                let response = transformer.transform(client_id, response);
+                let notified = causality
+                    .filter(|_| notification_versions)
+                    .map(|(key, causality)| {
+                        ControlResponse::Notified {
+                            key: key.to_string(),
+                            version: causality.version,
+                            session: update_log.session(),
+                            base: causality.base,
+                        }
+                        .into_message()
+                    });
                 match &response {
                     Ok(res) => tracing::debug!(response = %res, cli_id = %client_id, "sending notification"),
                     Err(err) => tracing::debug!(response = %err, cli_id = %client_id, "sending notification error"),
                 }
-                let serialized_res = match encoding_protoc {
-                    EncodingProtocol::Flatbuffers => match response {
-                        Ok(res) => res.into_fbs_bytes()?,
-                        Err(err) => err.into_fbs_bytes()?,
-                    },
-                    EncodingProtocol::Native => bincode::serialize(&response)?,
-                };
-                server_sink.send(Message::Binary(serialized_res)).await.inspect_err(|err| {
-                    tracing::debug!(err = %err, "error sending message to client");
-                })?;
+                let notification = Notification::new(
+                    notification_format.encode(response, encoding_protoc)?,
+                )
+                .preceded_by(notified)
+                .with_priority(priority);
+                outbound.notify(notification).await?;
+                if let Some(delivery) = delivery {
+                    delivery.sent();
+                }
+                if let Some(key) = shed {
+                    if let Some(token) = &*session.lock() {
+                        sessions.unsubscribed(token, &key);
+                    }
+                    request_sender
+                        .send(ClientConnection::Unsubscribed { client_id, key })
+                        .await?;
+                }
+            }
+            Some((key, started)) = ranges_starting.recv() => {
+                match started {
+                    Ok(frames) => {
+                        let frames = futures::stream::unfold(frames, |mut frames| async move {
+                            let frame = frames.recv().await?;
+                            Some((frame, frames))
+                        });
+                        ranges.push(frames.map(move |frame| (key.clone(), frame)).boxed());
+                    }
+                    Err(cause) => {
+                        let response = ControlResponse::Error { cause };
+                        outbound.respond(response.into_message()).await?;
+                    }
+                }
+            }
+            Some((key, frame)) = ranges.next() => {
+                outbound.respond(ControlResponse::Range { key, frame }.into_message()).await?;
+            }
+            _ = &mut writer => {
+                anyhow::bail!("stopped writing to client #{client_id}");
+            }
+            _ = ping(keepalive.as_mut()) => {
+                outbound.respond(Message::Ping(Vec::new())).await?;
+                keepalive_turn.store(true, Ordering::Relaxed);
+            }
+            // every turn of the loop but for keepalives is for something sent either way
+            _ = idle(timeouts.idle().map(|idle| idle.saturating_sub(last_traffic.elapsed()))) => {
+                tracing::debug!(cli_id = %client_id, "closing idle connection");
+                let _ = outbound.respond(Message::Close(None)).await;
+                drop(outbound);
+                let _ = writer.await;
+                return Ok(());
             }
         }
     }
 }
 
-async fn new_client_connection(
-    request_sender: &WebSocketRequest,
-    assigned_token: Option<(AuthToken, ContractInstanceId)>,
+async fn idle(timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => tokio::time::sleep(timeout).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn ping(keepalive: Option<&mut tokio::time::Interval>) {
+    match keepalive {
+        Some(keepalive) => {
+            keepalive.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+async fn new_client_connection(
+    request_sender: &WebSocketRequest,
+    assigned_token: Option<(AuthToken, ContractInstanceId)>,
 ) -> Result<(mpsc::UnboundedReceiver<HostCallbackResult>, ClientId), ClientError> {
     let (response_sender, mut response_recv) = mpsc::unbounded_channel();
     tracing::debug!(?assigned_token, "sending new client connection request");
@@ -416,14 +1907,62 @@ struct NewSubscription {
     callback: mpsc::UnboundedReceiver<HostResult>,
 }
 
+/// Decodes a request in the native encoding. Fields a newer client appends to a request
+/// come after the ones known to this node, so they are what is left after decoding it.
+///
+/// Bincode doesn't describe the values it encodes, so only fields appended at the top level
+/// of the request can be told apart: any added within a nested value or an enum variant
+/// misaligns the decoding of what follows, the request failing to decode or, at worst,
+/// decoding as a different one. [`UnknownFields`] only applies to the former.
+///
+/// Flatbuffers requests always skip the fields they don't know about.
+fn decode_native(msg: &[u8], unknown_fields: UnknownFields) -> bincode::Result<ClientRequest<'_>> {
+    // same options as `bincode::deserialize`, which allows trailing bytes
+    let options = bincode::DefaultOptions::new().with_fixint_encoding();
+    match unknown_fields {
+        UnknownFields::Lenient => options.allow_trailing_bytes().deserialize(msg),
+        UnknownFields::Strict => options.reject_trailing_bytes().deserialize(msg),
+    }
+}
+
+/// Accounts a subscription to the contract to the tenant of the connection, whether the client
+/// subscribes on its own, in a batch, for a snapshot or by migrating its session.
+fn account_subscription(tenant: &mut TenantConnection, key: &ContractKey) -> Result<(), String> {
+    tenant
+        .subscribe(*key.id())
+        .map_err(|err| format!("tenant `{}`: {err}", tenant.tenant()))
+}
+
+/// What the requests of a connection are checked and accounted against.
+struct ConnectionRequests<'a> {
+    state: &'a GatewayState,
+    auth_token: &'a mut Option<(AuthToken, ContractInstanceId)>,
+    tenant: &'a mut TenantConnection,
+    contracts: &'a mut TouchedContracts,
+    audit: &'a mut AuditSession,
+}
+
 async fn process_client_request(
     client_id: ClientId,
     msg: Result<Message, axum::Error>,
-    request_sender: &mpsc::Sender<ClientConnection>,
-    auth_token: &mut Option<AuthToken>,
-    attested_contract: Option<ContractInstanceId>,
+    requests: ConnectionRequests<'_>,
     encoding_protoc: EncodingProtocol,
+    unknown_fields: UnknownFields,
+    idempotency_key: Option<String>,
 ) -> Result<Option<Message>, Option<anyhow::Error>> {
+    let ConnectionRequests {
+        state:
+            GatewayState {
+                request_sender,
+                token_check,
+                idempotent_writes,
+                ..
+            },
+        auth_token,
+        tenant,
+        contracts,
+        audit,
+    } = requests;
     let msg = match msg {
         Ok(Message::Binary(data)) => data,
         Ok(Message::Text(data)) => data.into_bytes(),
@@ -443,7 +1982,7 @@ async fn process_client_request(
                 Ok(decoded) => decoded.into_owned(),
                 Err(err) => return Ok(Some(Message::Binary(err.into_fbs_bytes()))),
             },
-            EncodingProtocol::Native => match bincode::deserialize::<ClientRequest>(&msg) {
+            EncodingProtocol::Native => match decode_native(&msg, unknown_fields) {
                 Ok(decoded) => decoded.into_owned(),
                 Err(err) => {
                     let result_error = bincode::serialize(&Err::<HostResponse, ClientError>(
@@ -466,28 +2005,122 @@ async fn process_client_request(
         return Err(None); // Signal graceful closure to websocket_interface
     }
 
+    let accounted = tenant
+        .request()
+        .map_err(|err| format!("tenant `{}`: {err}", tenant.tenant()))
+        .and_then(|_| match &req {
+            ClientRequest::ContractOp(ContractRequest::Subscribe { key, .. }) => {
+                account_subscription(tenant, key)
+            }
+            _ => Ok(()),
+        })
+        .and_then(|_| contracts.request(&req).map_err(|err| err.to_string()));
+    if let Err(cause) = accounted {
+        tracing::debug!(tenant = %tenant.tenant(), %cause, "rejected client request");
+        let error = ClientError::from(ErrorKind::OperationError {
+            cause: cause.into(),
+        });
+        let error = match encoding_protoc {
+            EncodingProtocol::Flatbuffers => {
+                error.into_fbs_bytes().map_err(|err| Some(err.into()))?
+            }
+            EncodingProtocol::Native => bincode::serialize(&Err::<HostResponse, _>(error))
+                .map_err(|err| Some(err.into()))?,
+        };
+        return Ok(Some(Message::Binary(error)));
+    }
+
     if let ClientRequest::Authenticate { token } = &req {
-        *auth_token = Some(AuthToken::from(token.clone()));
+        let token = AuthToken::from(token.clone());
+        if !token.is_well_formed() {
+            if token_check.refuses_malformed(&token, None) {
+                let error = ClientError::from(ErrorKind::OperationError {
+                    cause: "malformed auth token".into(),
+                });
+                let error = match encoding_protoc {
+                    EncodingProtocol::Flatbuffers => {
+                        error.into_fbs_bytes().map_err(|err| Some(err.into()))?
+                    }
+                    EncodingProtocol::Native => bincode::serialize(&Err::<HostResponse, _>(error))
+                        .map_err(|err| Some(err.into()))?,
+                };
+                return Ok(Some(Message::Binary(error)));
+            }
+            // as if it wasn't sent
+            return Ok(None);
+        }
+        *auth_token = token_check
+            .attested(&token)
+            .map(|contract| (token, contract));
+        audit.authenticated(auth_token.as_ref().map(|(_, contract)| *contract));
+    }
+
+    if let Some(contract) = idempotency::written_contract(&req) {
+        let written = idempotency_key
+            .as_deref()
+            .and_then(|key| idempotent_writes.result(contract, key));
+        if let Some(result) = written {
+            tracing::debug!(%contract, "write retried with the same idempotency key");
+            let result = match encoding_protoc {
+                EncodingProtocol::Flatbuffers => {
+                    result.into_fbs_bytes().map_err(|err| Some(err.into()))?
+                }
+                EncodingProtocol::Native => bincode::serialize(&Ok::<_, ClientError>(result))
+                    .map_err(|err| Some(err.into()))?,
+            };
+            return Ok(Some(Message::Binary(result)));
+        }
+        idempotent_writes.write(client_id, contract, idempotency_key);
     }
 
     tracing::debug!(req = %req, "received client request");
-    request_sender
+    if let Err(err) = audit.request(&req).await {
+        let error = ClientError::from(ErrorKind::OperationError {
+            cause: format!("node degraded, the audit trail can't be written: {err}").into(),
+        });
+        let error = match encoding_protoc {
+            EncodingProtocol::Flatbuffers => {
+                error.into_fbs_bytes().map_err(|err| Some(err.into()))?
+            }
+            EncodingProtocol::Native => bincode::serialize(&Err::<HostResponse, _>(error))
+                .map_err(|err| Some(err.into()))?,
+        };
+        return Ok(Some(Message::Binary(error)));
+    }
+    let sent = request_sender
         .send(ClientConnection::Request {
             client_id,
             req: Box::new(req),
-            auth_token: auth_token.clone(),
-            attested_contract,
+            auth_token: auth_token.as_ref().map(|(token, _)| token.clone()),
+            attested_contract: auth_token.as_ref().map(|(_, contract)| *contract),
         })
-        .await
-        .map_err(|err| Some(err.into()))?;
-    Ok(None)
+        .await;
+    match sent {
+        Ok(()) => Ok(None),
+        Err(WorkQueueError::Cancelled) => {
+            // clients are expected to retry once the node is available again
+            let error = ClientError::from(ErrorKind::NodeUnavailable);
+            let error = match encoding_protoc {
+                EncodingProtocol::Flatbuffers => {
+                    error.into_fbs_bytes().map_err(|err| Some(err.into()))?
+                }
+                EncodingProtocol::Native => bincode::serialize(&Err::<HostResponse, _>(error))
+                    .map_err(|err| Some(err.into()))?,
+            };
+            Ok(Some(Message::Binary(error)))
+        }
+        Err(err) => Err(Some(err.into())),
+    }
 }
 
 async fn process_host_response(
     msg: Option<HostCallbackResult>,
     client_id: ClientId,
     encoding_protoc: EncodingProtocol,
-    tx: &mut SplitSink<WebSocket, Message>,
+    response_limit: ResponseLimit,
+    multipart: Option<&mut MultipartResponses>,
+    snapshots: &SnapshotEncodings,
+    outbound: &Outbound,
 ) -> anyhow::Result<Option<NewSubscription>> {
     match msg {
         Some(HostCallbackResult::Result { id, result }) => {
@@ -521,14 +2154,40 @@ async fn process_host_response(
                     Err(err)
                 }
             };
-            let serialized_res = match encoding_protoc {
-                EncodingProtocol::Flatbuffers => match result {
-                    Ok(res) => res.into_fbs_bytes()?,
-                    Err(err) => err.into_fbs_bytes()?,
+            let result = match (result, multipart) {
+                (Ok(res), Some(multipart)) => match multipart.split(res)? {
+                    Ok(parts) => {
+                        for (part, data) in parts {
+                            outbound.respond(part.into_message()).await?;
+                            for msg in response_limit.messages(data, encoding_protoc)? {
+                                outbound.respond(msg).await?;
+                            }
+                        }
+                        return Ok(None);
+                    }
+                    Err(res) => Ok(res),
                 },
-                EncodingProtocol::Native => bincode::serialize(&result)?,
+                (result, _) => result,
+            };
+            let snapshot = match &result {
+                Ok(HostResponse::ContractResponse(ContractResponse::GetResponse {
+                    key,
+                    contract: None,
+                    state,
+                })) => Some((*key.id(), state.clone())),
+                _ => None,
+            };
+            let serialized_res = match snapshot {
+                Some((contract, state)) => snapshots
+                    .encoded(contract, &state, encoding_protoc, || {
+                        serialize_result(result, encoding_protoc)
+                    })?
+                    .to_vec(),
+                None => serialize_result(result, encoding_protoc)?,
             };
-            tx.send(Message::Binary(serialized_res)).await?;
+            for msg in response_limit.messages(serialized_res, encoding_protoc)? {
+                outbound.respond(msg).await?;
+            }
             Ok(None)
         }
         Some(HostCallbackResult::SubscriptionChannel { key, id, callback }) => {
@@ -543,8 +2202,8 @@ async fn process_host_response(
             let result_error = bincode::serialize(&Err::<HostResponse, ClientError>(
                 ErrorKind::NodeUnavailable.into(),
             ))?;
-            tx.send(Message::Binary(result_error)).await?;
-            tx.send(Message::Close(None)).await?;
+            outbound.respond(Message::Binary(result_error)).await?;
+            outbound.respond(Message::Close(None)).await?;
             tracing::warn!("node shut down while handling responses for {client_id}");
             Err(anyhow::anyhow!(
                 "node shut down while handling responses for {client_id}"
@@ -553,14 +2212,45 @@ async fn process_host_response(
     }
 }
 
+fn serialize_result(
+    result: Result<HostResponse, ClientError>,
+    encoding_protoc: EncodingProtocol,
+) -> anyhow::Result<Vec<u8>> {
+    Ok(match encoding_protoc {
+        EncodingProtocol::Flatbuffers => match result {
+            Ok(res) => res.into_fbs_bytes()?,
+            Err(err) => err.into_fbs_bytes()?,
+        },
+        EncodingProtocol::Native => bincode::serialize(&result)?,
+    })
+}
+
 impl ClientEventsProxy for WebSocketProxy {
     fn recv(&mut self) -> BoxFuture<Result<OpenRequest<'static>, ClientError>> {
         async move {
             loop {
+                // pick up what is already waiting, as much as can be scheduled, so it is
+                // scheduled fairly
+                while !self.pending.is_full() {
+                    let Some(msg) = self.proxy_server_request.try_recv() else {
+                        break;
+                    };
+                    if let Some(req) = self.internal_proxy_recv(msg).await? {
+                        self.schedule(req);
+                    }
+                }
+                if let Some(req) = self.next_scheduled() {
+                    self.timings.picked_up(req.client_id);
+                    self.picked_up
+                        .entry(req.client_id)
+                        .or_default()
+                        .push_back(Instant::now());
+                    break Ok(req);
+                }
                 let msg = self.proxy_server_request.recv().await;
                 if let Some(msg) = msg {
-                    if let Some(reply) = self.internal_proxy_recv(msg).await? {
-                        break Ok(reply.into_owned());
+                    if let Some(req) = self.internal_proxy_recv(msg).await? {
+                        self.schedule(req);
                     }
                 } else {
                     break Err(ClientError::from(ErrorKind::ChannelClosed));
@@ -576,23 +2266,1723 @@ impl ClientEventsProxy for WebSocketProxy {
         result: Result<HostResponse, ClientError>,
     ) -> BoxFuture<Result<(), ClientError>> {
         async move {
+            if !self.response_channels.contains_key(&id) {
+                // the client disconnected while its request was being served, nothing is done
+                // on its behalf anymore, e.g. following up on a one shot subscription
+                tracing::debug!("dropped result for disconnected client #{id}");
+                self.proxy_server_request.metrics().orphaned();
+                return Ok(());
+            }
+            // answers are assumed to come in the order the requests were picked up
+            if let Some(picked_up) = self.picked_up.get_mut(&id).and_then(VecDeque::pop_front) {
+                self.metrics.answered(picked_up.elapsed());
+            }
+            self.acknowledge_subscription(id, &result);
+            let result = self.response_transformer.transform(id, result);
+            let result = match self.one_shot.answer(id, result) {
+                Answer::Forward(result) => result,
+                Answer::Fetch {
+                    key,
+                    auth_token,
+                    attested_contract,
+                } => {
+                    let get = ClientRequest::ContractOp(ContractRequest::Get {
+                        key,
+                        return_contract_code: false,
+                        subscribe: false,
+                    });
+                    self.schedule(
+                        OpenRequest::new(id, Box::new(get))
+                            .with_token(auth_token)
+                            .with_attested_contract(attested_contract),
+                    );
+                    return Ok(());
+                }
+            };
             if let Some(ch) = self.response_channels.remove(&id) {
                 let should_rm = result
                     .as_ref()
                     .map_err(|err| matches!(err.kind(), ErrorKind::Disconnect))
                     .err()
                     .unwrap_or(false);
-                if ch.send(HostCallbackResult::Result { id, result }).is_ok() && !should_rm {
+                let sent = self
+                    .pending_responses
+                    .send(&ch, HostCallbackResult::Result { id, result })
+                    .await
+                    .is_ok();
+                if sent && !should_rm && !ch.is_closed() {
                     // still alive connection, keep it
                     self.response_channels.insert(id, ch);
                 } else {
-                    tracing::info!("dropped connection to client #{id}");
+                    self.drop_client(&id);
                 }
-            } else {
-                tracing::warn!("client: {id} not found");
             }
             Ok(())
         }
         .boxed()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn key(n: u8) -> ContractKey {
+        ContractKey::from(ContractInstanceId::new([n; 32]))
+    }
+
+    fn subscribed(key: ContractKey) -> HostResponse {
+        ContractResponse::SubscribeResponse {
+            key,
+            subscribed: true,
+        }
+        .into()
+    }
+
+    fn subscribe(client_id: ClientId, key: ContractKey) -> ClientConnection {
+        ClientConnection::Request {
+            client_id,
+            req: Box::new(ClientRequest::ContractOp(ContractRequest::Subscribe {
+                key,
+                summary: None,
+            })),
+            auth_token: None,
+            attested_contract: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshot_and_restore_subscriptions() {
+        let (mut proxy, _) = WebSocketProxy::create_router(Router::new());
+        let (first, second) = (ClientId::next(), ClientId::next());
+        let (first_tx, mut first_rx) = mpsc::unbounded_channel();
+        let (second_tx, second_rx) = mpsc::unbounded_channel();
+        proxy.restore(ProxyState {
+            connections: HashMap::from([(first, first_tx), (second, second_tx)]),
+            subscriptions: HashMap::from([(second, HashSet::from([key(1)]))]),
+        });
+
+        // the restored connection takes new subscriptions as if it had gone through the handshake
+        let req = proxy
+            .internal_proxy_recv(subscribe(first, key(2)))
+            .await
+            .unwrap();
+        assert!(req.is_some());
+        assert!(matches!(
+            first_rx.recv().await,
+            Some(HostCallbackResult::SubscriptionChannel { id, key: subscribed, .. })
+                if id == first && subscribed == key(2)
+        ));
+        // held once the node takes it
+        assert!(!proxy.snapshot().subscriptions.contains_key(&first));
+        proxy.send(first, Ok(subscribed(key(2)))).await.unwrap();
+
+        let snapshot = proxy.snapshot();
+        assert_eq!(
+            snapshot.subscriptions,
+            HashMap::from([
+                (first, HashSet::from([key(2)])),
+                (second, HashSet::from([key(1)])),
+            ])
+        );
+
+        // the second client goes away along with its subscription
+        drop(second_rx);
+        proxy.send(second, Ok(HostResponse::Ok)).await.unwrap();
+        assert!(!proxy.snapshot().subscriptions.contains_key(&second));
+
+        let (mut restored, _) = WebSocketProxy::create_router(Router::new());
+        restored.restore(snapshot.clone());
+        let state = restored.snapshot();
+        assert_eq!(state.subscriptions, snapshot.subscriptions);
+        let mut connections: Vec<_> = state.connections.into_keys().collect();
+        connections.sort();
+        assert_eq!(connections, [first, second]);
+    }
+
+    #[tokio::test]
+    async fn result_for_disconnected_client_dropped() {
+        let (mut proxy, _) = WebSocketProxy::create_router(Router::new());
+        let (gone, remaining) = (ClientId::next(), ClientId::next());
+        let (gone_tx, gone_rx) = mpsc::unbounded_channel();
+        let (remaining_tx, mut remaining_rx) = mpsc::unbounded_channel();
+        proxy.restore(ProxyState {
+            connections: HashMap::from([(gone, gone_tx), (remaining, remaining_tx)]),
+            subscriptions: HashMap::new(),
+        });
+        drop(gone_rx);
+        proxy.send(gone, Ok(HostResponse::Ok)).await.unwrap();
+        assert_eq!(proxy.metrics().snapshot().orphaned, 0);
+
+        // answers to requests made before it went away
+        for _ in 0..2 {
+            proxy.send(gone, Ok(HostResponse::Ok)).await.unwrap();
+        }
+        assert_eq!(proxy.metrics().snapshot().orphaned, 2);
+        assert!(remaining_rx.try_recv().is_err());
+        assert!(proxy.snapshot().connections.contains_key(&remaining));
+    }
+
+    #[tokio::test]
+    async fn scheduled_requests_count_towards_depth() {
+        let (mut proxy, _) = WebSocketProxy::create_router(Router::new());
+        let client = ClientId::next();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        proxy.restore(ProxyState {
+            connections: HashMap::from([(client, tx)]),
+            subscriptions: HashMap::new(),
+        });
+        for n in 0..3 {
+            let get = ClientConnection::Request {
+                client_id: client,
+                req: Box::new(ClientRequest::ContractOp(ContractRequest::Get {
+                    key: key(n),
+                    return_contract_code: false,
+                    subscribe: false,
+                })),
+                auth_token: None,
+                attested_contract: None,
+            };
+            let req = proxy.internal_proxy_recv(get).await.unwrap().unwrap();
+            proxy.schedule(req);
+        }
+        // the node sheds load by this depth, so the backlog waiting in the fair queue is in it
+        let depth = |proxy: &WebSocketProxy| proxy.proxy_server_request.metrics().snapshot().depth;
+        assert_eq!(depth(&proxy), 3);
+        proxy.recv().await.unwrap();
+        assert_eq!(depth(&proxy), 2);
+    }
+
+    #[tokio::test]
+    async fn resubscribe_after_resume_reconciled() {
+        let restored_state = |client, tx| ProxyState {
+            connections: HashMap::from([(client, tx)]),
+            subscriptions: HashMap::from([(client, HashSet::from([key(1), key(2)]))]),
+        };
+        let (mut proxy, _) = WebSocketProxy::create_router(Router::new());
+        let client = ClientId::next();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        proxy.restore(restored_state(client, tx));
+
+        // the resumed client asks again for everything it wants, some of it already held
+        let held = proxy
+            .internal_proxy_recv(subscribe(client, key(2)))
+            .await
+            .unwrap();
+        assert!(held.is_none(), "subscribed again to a held contract");
+        assert!(matches!(
+            rx.recv().await,
+            Some(HostCallbackResult::Result {
+                result: Ok(HostResponse::ContractResponse(ContractResponse::SubscribeResponse {
+                    key: subscribed,
+                    subscribed: true,
+                })),
+                ..
+            }) if subscribed == key(2)
+        ));
+        let new = proxy
+            .internal_proxy_recv(subscribe(client, key(3)))
+            .await
+            .unwrap();
+        assert!(new.is_some());
+        assert!(matches!(
+            rx.recv().await,
+            Some(HostCallbackResult::SubscriptionChannel { key: subscribed, .. })
+                if subscribed == key(3)
+        ));
+
+        // a failed subscription isn't held
+        let err = ContractError::Subscribe {
+            key: key(3),
+            cause: "unreachable".into(),
+        };
+        proxy
+            .send(client, Err(ErrorKind::RequestError(err.into()).into()))
+            .await
+            .unwrap();
+        assert_eq!(
+            proxy.snapshot().subscriptions[&client],
+            HashSet::from([key(1), key(2)])
+        );
+        let again = proxy
+            .internal_proxy_recv(subscribe(client, key(3)))
+            .await
+            .unwrap();
+        assert!(again.is_some(), "the client may try again");
+        proxy.send(client, Ok(subscribed(key(3)))).await.unwrap();
+        assert_eq!(
+            proxy.snapshot().subscriptions[&client],
+            HashSet::from([key(1), key(2), key(3)])
+        );
+
+        // nor is one the client unsubscribed from
+        let unsubscribed = ClientConnection::Unsubscribed {
+            client_id: client,
+            key: key(1),
+        };
+        assert!(proxy
+            .internal_proxy_recv(unsubscribed)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            proxy.snapshot().subscriptions[&client],
+            HashSet::from([key(2), key(3)])
+        );
+
+        let config = WebsocketApiConfig {
+            duplicate_subscriptions: DuplicateSubscriptions::Resubscribe,
+            ..Default::default()
+        };
+        let (mut proxy, _) = WebSocketProxy::create_router_with_attested_contracts(
+            Router::new(),
+            &config,
+            Default::default(),
+            Default::default(),
+        );
+        let (tx, _rx) = mpsc::unbounded_channel();
+        proxy.restore(restored_state(client, tx));
+        let again = proxy
+            .internal_proxy_recv(subscribe(client, key(2)))
+            .await
+            .unwrap();
+        assert!(again.is_some());
+    }
+
+    #[tokio::test]
+    async fn node_wide_subscription_limit() {
+        let config = WebsocketApiConfig {
+            max_node_subscriptions: Some(2),
+            ..Default::default()
+        };
+        let (mut proxy, _) = WebSocketProxy::create_router_with_attested_contracts(
+            Router::new(),
+            &config,
+            Default::default(),
+            Default::default(),
+        );
+        let (first, second) = (ClientId::next(), ClientId::next());
+        let (first_tx, _first_rx) = mpsc::unbounded_channel();
+        let (second_tx, mut second_rx) = mpsc::unbounded_channel();
+        proxy.restore(ProxyState {
+            connections: HashMap::from([(first, first_tx), (second, second_tx)]),
+            subscriptions: HashMap::new(),
+        });
+
+        for (client, contract) in [(first, key(1)), (second, key(1))] {
+            let subscribed = proxy
+                .internal_proxy_recv(subscribe(client, contract))
+                .await
+                .unwrap();
+            assert!(subscribed.is_some());
+        }
+        second_rx.recv().await.unwrap();
+
+        // over the limit, however many subscriptions the client itself has
+        let rejected = proxy
+            .internal_proxy_recv(subscribe(second, key(2)))
+            .await
+            .unwrap();
+        assert!(rejected.is_none());
+        let Some(HostCallbackResult::Result {
+            result: Err(err), ..
+        }) = second_rx.recv().await
+        else {
+            panic!("expected the subscription to be rejected");
+        };
+        assert!(
+            err.to_string().contains("maximum of 2 subscriptions"),
+            "{err}"
+        );
+        proxy.send(second, Ok(subscribed(key(1)))).await.unwrap();
+        assert_eq!(
+            proxy.snapshot().subscriptions[&second],
+            HashSet::from([key(1)])
+        );
+
+        // room is made once a client goes away
+        proxy
+            .send(first, Err(ErrorKind::Disconnect.into()))
+            .await
+            .unwrap();
+        let subscribed = proxy
+            .internal_proxy_recv(subscribe(second, key(2)))
+            .await
+            .unwrap();
+        assert!(subscribed.is_some());
+    }
+
+    #[tokio::test]
+    async fn connection_state_matches_connection() -> anyhow::Result<()> {
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message as WsMessage};
+
+        let (mut proxy, router) = WebSocketProxy::create_router(Router::new());
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        // stands in for the node, answering subscriptions
+        let (connected, mut clients) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut notifications = Vec::new();
+            while let Ok(req) = proxy.recv().await {
+                let ClientRequest::ContractOp(ContractRequest::Subscribe { key, .. }) =
+                    *req.request
+                else {
+                    continue;
+                };
+                connected.send(req.client_id).unwrap();
+                notifications.extend(req.notification_channel);
+                let response = ContractResponse::SubscribeResponse {
+                    key,
+                    subscribed: true,
+                };
+                proxy
+                    .send(req.client_id, Ok(response.into()))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let mut request = format!("ws://{addr}/v1/contract/command").into_client_request()?;
+        request
+            .headers_mut()
+            .insert(EncodingProtocolExt::name(), "native".parse()?);
+        let (mut client, _) = tokio_tungstenite::connect_async(request).await?;
+        let subscribe = ClientRequest::ContractOp(ContractRequest::Subscribe {
+            key: key(1),
+            summary: None,
+        });
+        client
+            .send(WsMessage::Binary(bincode::serialize(&subscribe)?.into()))
+            .await?;
+        let client_id = clients.recv().await.unwrap();
+        let Some(WsMessage::Binary(response)) = client.next().await.transpose()? else {
+            anyhow::bail!("expected the subscription response");
+        };
+        let response: Result<HostResponse, ClientError> = bincode::deserialize(&response)?;
+        assert!(response.is_ok());
+
+        client
+            .send(WsMessage::Text(r#"{"state":{}}"#.into()))
+            .await?;
+        let Some(WsMessage::Text(state)) = client.next().await.transpose()? else {
+            anyhow::bail!("expected the connection state");
+        };
+        let state: serde_json::Value = serde_json::from_str(&state)?;
+        assert_eq!(
+            state,
+            serde_json::json!({
+                "state": {
+                    "client": client_id.to_string(),
+                    "attestedContract": null,
+                    "subscriptions": [{ "key": key(1).to_string(), "paused": false }],
+                }
+            })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batched_subscriptions_answered_once() -> anyhow::Result<()> {
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let (mut proxy, router) = WebSocketProxy::create_router(Router::new());
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        // stands in for the node, which doesn't have the third contract
+        tokio::spawn(async move {
+            let mut notifications = Vec::new();
+            while let Ok(req) = proxy.recv().await {
+                let ClientRequest::ContractOp(ContractRequest::Subscribe { key: contract, .. }) =
+                    *req.request
+                else {
+                    continue;
+                };
+                notifications.extend(req.notification_channel);
+                let response = if contract == key(3) {
+                    Err(ErrorKind::RequestError(
+                        freenet_stdlib::client_api::RequestError::ContractError(
+                            freenet_stdlib::client_api::ContractError::MissingContract {
+                                key: *contract.id(),
+                            },
+                        ),
+                    )
+                    .into())
+                } else {
+                    Ok(ContractResponse::SubscribeResponse {
+                        key: contract,
+                        subscribed: true,
+                    }
+                    .into())
+                };
+                proxy.send(req.client_id, response).await.unwrap();
+            }
+        });
+
+        let (mut client, _) =
+            tokio_tungstenite::connect_async(format!("ws://{addr}/v1/contract/command")).await?;
+        let frame = serde_json::json!({
+            "subscribe": { "keys": [key(1).to_string(), key(2).to_string(), key(3).to_string(), "not a key"] }
+        });
+        client
+            .send(WsMessage::Text(frame.to_string().into()))
+            .await?;
+        let Some(WsMessage::Text(response)) = client.next().await.transpose()? else {
+            anyhow::bail!("expected the batch response");
+        };
+        let response: serde_json::Value = serde_json::from_str(&response)?;
+        let subscriptions = response["subscribed"]["subscriptions"].as_array().unwrap();
+        let outcomes: Vec<_> = subscriptions
+            .iter()
+            .map(|outcome| {
+                (
+                    outcome["key"].as_str().unwrap(),
+                    outcome["subscribed"].as_bool().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                (key(1).to_string().as_str(), true),
+                (key(2).to_string().as_str(), true),
+                (key(3).to_string().as_str(), false),
+                ("not a key", false),
+            ]
+        );
+        assert!(subscriptions[2]["error"].is_string());
+        assert!(subscriptions[1].get("error").is_none());
+        assert_eq!(response["subscribed"]["failed"], serde_json::json!([2, 3]));
+
+        // no further responses for the individual subscriptions
+        client
+            .send(WsMessage::Text(r#"{"state":{}}"#.into()))
+            .await?;
+        let Some(WsMessage::Text(state)) = client.next().await.transpose()? else {
+            anyhow::bail!("expected the connection state");
+        };
+        assert!(state.contains("\"state\""));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batched_subscriptions_over_tenant_rate() -> anyhow::Result<()> {
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let config = WebsocketApiConfig {
+            tenant_limits: crate::config::TenantLimits {
+                max_subscriptions_per_sec: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (mut proxy, router) = WebSocketProxy::create_router_with_attested_contracts(
+            Router::new(),
+            &config,
+            Default::default(),
+            Default::default(),
+        );
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        // stands in for the node, which only gets the subscriptions within the rate
+        let (requested, mut requests) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut notifications = Vec::new();
+            while let Ok(req) = proxy.recv().await {
+                let ClientRequest::ContractOp(ContractRequest::Subscribe { key: contract, .. }) =
+                    *req.request
+                else {
+                    continue;
+                };
+                requested.send(contract).unwrap();
+                notifications.extend(req.notification_channel);
+                let response = ContractResponse::SubscribeResponse {
+                    key: contract,
+                    subscribed: true,
+                };
+                proxy
+                    .send(req.client_id, Ok(response.into()))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let (mut client, _) =
+            tokio_tungstenite::connect_async(format!("ws://{addr}/v1/contract/command")).await?;
+        let frame = serde_json::json!({
+            "subscribe": { "keys": [key(1).to_string(), key(2).to_string()] }
+        });
+        client
+            .send(WsMessage::Text(frame.to_string().into()))
+            .await?;
+        let Some(WsMessage::Text(response)) = client.next().await.transpose()? else {
+            anyhow::bail!("expected the batch response");
+        };
+        let response: serde_json::Value = serde_json::from_str(&response)?;
+        let subscriptions = response["subscribed"]["subscriptions"].as_array().unwrap();
+        assert_eq!(subscriptions[0]["subscribed"], true);
+        assert_eq!(subscriptions[1]["subscribed"], false);
+        assert!(subscriptions[1]["error"]
+            .as_str()
+            .unwrap()
+            .contains("subscription rate limit exceeded"));
+        assert_eq!(response["subscribed"]["failed"], serde_json::json!([1]));
+        assert_eq!(requests.recv().await, Some(key(1)));
+        assert!(requests.try_recv().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn notifications_in_format_of_each_client() -> anyhow::Result<()> {
+        use freenet_stdlib::prelude::{State, UpdateData};
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message as WsMessage};
+
+        use super::notification_format::NOTIFICATION_FORMAT_HEADER;
+
+        let (mut proxy, router) = WebSocketProxy::create_router(Router::new());
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        // stands in for the node, notifying both subscribers of the same update
+        tokio::spawn(async move {
+            let mut subscribers = Vec::new();
+            while let Ok(req) = proxy.recv().await {
+                let ClientRequest::ContractOp(ContractRequest::Subscribe { key, .. }) =
+                    *req.request
+                else {
+                    continue;
+                };
+                subscribers.extend(req.notification_channel);
+                let response = ContractResponse::SubscribeResponse {
+                    key,
+                    subscribed: true,
+                };
+                proxy
+                    .send(req.client_id, Ok(response.into()))
+                    .await
+                    .unwrap();
+                if subscribers.len() == 2 {
+                    for subscriber in &subscribers {
+                        let update = ContractResponse::UpdateNotification {
+                            key,
+                            update: UpdateData::State(State::from(vec![1, 2, 3])),
+                        };
+                        subscriber.send(Ok(update.into())).unwrap();
+                    }
+                }
+            }
+        });
+
+        let mut clients = Vec::new();
+        for format in ["json", "binary"] {
+            let mut request = format!("ws://{addr}/v1/contract/command").into_client_request()?;
+            let headers = request.headers_mut();
+            headers.insert(EncodingProtocolExt::name(), "native".parse()?);
+            headers.insert(NOTIFICATION_FORMAT_HEADER, format.parse()?);
+            let (mut client, _) = tokio_tungstenite::connect_async(request).await?;
+            let subscribe = ClientRequest::ContractOp(ContractRequest::Subscribe {
+                key: key(1),
+                summary: None,
+            });
+            client
+                .send(WsMessage::Binary(bincode::serialize(&subscribe)?.into()))
+                .await?;
+            let Some(WsMessage::Binary(_)) = client.next().await.transpose()? else {
+                anyhow::bail!("expected the subscription response");
+            };
+            clients.push(client);
+        }
+
+        let Some(WsMessage::Text(json)) = clients[0].next().await.transpose()? else {
+            anyhow::bail!("expected a json notification");
+        };
+        let json: serde_json::Value = serde_json::from_str(&json)?;
+        assert!(json["Ok"]["ContractResponse"]["UpdateNotification"].is_object());
+        let Some(WsMessage::Binary(binary)) = clients[1].next().await.transpose()? else {
+            anyhow::bail!("expected a binary notification");
+        };
+        let notification: Result<HostResponse, ClientError> = bincode::deserialize(&binary)?;
+        assert!(matches!(
+            notification,
+            Ok(HostResponse::ContractResponse(ContractResponse::UpdateNotification { key: notified, .. }))
+                if notified == key(1)
+        ));
+
+        let mut request = format!("ws://{addr}/v1/contract/command").into_client_request()?;
+        request
+            .headers_mut()
+            .insert(NOTIFICATION_FORMAT_HEADER, "xml".parse()?);
+        assert!(tokio_tungstenite::connect_async(request).await.is_err());
+        Ok(())
+    }
+
+    /// Blanks the states clients are notified of.
+    struct BlankStates;
+
+    impl ResponseTransformer for BlankStates {
+        fn transform(
+            &self,
+            _client: ClientId,
+            response: Result<HostResponse, ClientError>,
+        ) -> Result<HostResponse, ClientError> {
+            use freenet_stdlib::prelude::{State, UpdateData};
+
+            match response {
+                Ok(HostResponse::ContractResponse(ContractResponse::UpdateNotification {
+                    key,
+                    update: UpdateData::State(_),
+                })) => Ok(ContractResponse::UpdateNotification {
+                    key,
+                    update: UpdateData::State(State::from(vec![])),
+                }
+                .into()),
+                other => other,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn notifications_go_through_transformer() -> anyhow::Result<()> {
+        use freenet_stdlib::prelude::{State, UpdateData};
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message as WsMessage};
+
+        let (mut proxy, router) = WebSocketProxy::create_router(Router::new());
+        let transformer: Arc<dyn ResponseTransformer> = Arc::new(BlankStates);
+        let router = router.layer(Extension(transformer));
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        // stands in for the node, notifying the subscriber of an update
+        tokio::spawn(async move {
+            while let Ok(req) = proxy.recv().await {
+                let ClientRequest::ContractOp(ContractRequest::Subscribe { key, .. }) =
+                    *req.request
+                else {
+                    continue;
+                };
+                let response = ContractResponse::SubscribeResponse {
+                    key,
+                    subscribed: true,
+                };
+                proxy
+                    .send(req.client_id, Ok(response.into()))
+                    .await
+                    .unwrap();
+                let update = ContractResponse::UpdateNotification {
+                    key,
+                    update: UpdateData::State(State::from(vec![1, 2, 3])),
+                };
+                req.notification_channel
+                    .unwrap()
+                    .send(Ok(update.into()))
+                    .unwrap();
+            }
+        });
+
+        let mut request = format!("ws://{addr}/v1/contract/command").into_client_request()?;
+        request
+            .headers_mut()
+            .insert(EncodingProtocolExt::name(), "native".parse()?);
+        let (mut client, _) = tokio_tungstenite::connect_async(request).await?;
+        let subscribe = ClientRequest::ContractOp(ContractRequest::Subscribe {
+            key: key(1),
+            summary: None,
+        });
+        client
+            .send(WsMessage::Binary(bincode::serialize(&subscribe)?.into()))
+            .await?;
+        let Some(WsMessage::Binary(_)) = client.next().await.transpose()? else {
+            anyhow::bail!("expected the subscription response");
+        };
+        let Some(WsMessage::Binary(binary)) = client.next().await.transpose()? else {
+            anyhow::bail!("expected a notification");
+        };
+        let notification: Result<HostResponse, ClientError> = bincode::deserialize(&binary)?;
+        let Ok(HostResponse::ContractResponse(ContractResponse::UpdateNotification {
+            update: UpdateData::State(state),
+            ..
+        })) = notification
+        else {
+            anyhow::bail!("expected an update notification");
+        };
+        assert!(state.as_ref().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscriptions_acknowledged() -> anyhow::Result<()> {
+        use freenet_stdlib::prelude::{State, UpdateData};
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message as WsMessage};
+
+        use super::notification_format::NOTIFICATION_FORMAT_HEADER;
+
+        let (mut proxy, router) = WebSocketProxy::create_router(Router::new());
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        // stands in for the node, notifying the first subscriber of an update
+        tokio::spawn(async move {
+            let mut subscribers = Vec::new();
+            while let Ok(req) = proxy.recv().await {
+                let ClientRequest::ContractOp(ContractRequest::Subscribe { key, .. }) =
+                    *req.request
+                else {
+                    continue;
+                };
+                subscribers.extend(req.notification_channel);
+                let response = ContractResponse::SubscribeResponse {
+                    key,
+                    subscribed: true,
+                };
+                proxy
+                    .send(req.client_id, Ok(response.into()))
+                    .await
+                    .unwrap();
+                if subscribers.len() == 1 {
+                    let update = ContractResponse::UpdateNotification {
+                        key,
+                        update: UpdateData::State(State::from(vec![1, 2, 3])),
+                    };
+                    subscribers[0].send(Ok(update.into())).unwrap();
+                }
+            }
+        });
+
+        let connect = |headers: &'static [(&'static str, &'static str)]| async move {
+            let mut request = format!("ws://{addr}/v1/contract/command").into_client_request()?;
+            request
+                .headers_mut()
+                .insert(EncodingProtocolExt::name(), "native".parse()?);
+            for (name, value) in headers {
+                request.headers_mut().insert(*name, value.parse()?);
+            }
+            let (client, _) = tokio_tungstenite::connect_async(request).await?;
+            anyhow::Ok(client)
+        };
+        let subscribe = ClientRequest::ContractOp(ContractRequest::Subscribe {
+            key: key(1),
+            summary: None,
+        });
+        let subscribe = WsMessage::Binary(bincode::serialize(&subscribe)?.into());
+        async fn next_json(
+            client: &mut tokio_tungstenite::WebSocketStream<
+                tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+            >,
+        ) -> anyhow::Result<serde_json::Value> {
+            loop {
+                match client.next().await.transpose()? {
+                    Some(WsMessage::Text(text)) => return Ok(serde_json::from_str(&text)?),
+                    Some(WsMessage::Binary(_)) => continue,
+                    other => anyhow::bail!("unexpected message: {other:?}"),
+                }
+            }
+        }
+
+        let mut versioned = connect(&[
+            (SUBSCRIPTION_ACKS_HEADER, ""),
+            (NOTIFICATION_VERSIONS_HEADER, ""),
+            (NOTIFICATION_FORMAT_HEADER, "json"),
+        ])
+        .await?;
+        versioned.send(subscribe.clone()).await?;
+        let ack = next_json(&mut versioned).await?;
+        assert_eq!(
+            ack,
+            serde_json::json!({ "acknowledged": {
+                "key": key(1).to_string(),
+                "subscription": 1,
+                "version": 0,
+                "mode": "delta",
+                "format": "json",
+            }})
+        );
+        // the update brings the contract to version 1
+        let notified = next_json(&mut versioned).await?;
+        assert_eq!(notified["notified"]["version"], 1);
+        let notification = next_json(&mut versioned).await?;
+        assert!(notification["Ok"]["ContractResponse"]["UpdateNotification"].is_object());
+
+        let mut plain = connect(&[(SUBSCRIPTION_ACKS_HEADER, "")]).await?;
+        plain.send(subscribe).await?;
+        let ack = next_json(&mut plain).await?;
+        assert_eq!(ack["acknowledged"]["subscription"], 1);
+        assert_eq!(ack["acknowledged"]["version"], 1);
+        assert_eq!(ack["acknowledged"]["mode"], "full");
+        assert_eq!(ack["acknowledged"]["format"], "binary");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn timing_follows_requested_response() -> anyhow::Result<()> {
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message as WsMessage};
+
+        let (mut proxy, router) = WebSocketProxy::create_router(Router::new());
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        // stands in for a node taking its time to serve every subscription
+        tokio::spawn(async move {
+            let mut notifications = Vec::new();
+            while let Ok(req) = proxy.recv().await {
+                let ClientRequest::ContractOp(ContractRequest::Subscribe { key, .. }) =
+                    *req.request
+                else {
+                    continue;
+                };
+                notifications.extend(req.notification_channel);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let response = ContractResponse::SubscribeResponse {
+                    key,
+                    subscribed: true,
+                };
+                proxy
+                    .send(req.client_id, Ok(response.into()))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let mut request = format!("ws://{addr}/v1/contract/command").into_client_request()?;
+        request
+            .headers_mut()
+            .insert(EncodingProtocolExt::name(), "native".parse()?);
+        let (mut client, _) = tokio_tungstenite::connect_async(request).await?;
+        let subscribe = |n| {
+            let request = ClientRequest::ContractOp(ContractRequest::Subscribe {
+                key: key(n),
+                summary: None,
+            });
+            bincode::serialize(&request).map(|bytes| WsMessage::Binary(bytes.into()))
+        };
+
+        client
+            .send(WsMessage::Text(r#"{"timing":{}}"#.into()))
+            .await?;
+        client.send(subscribe(1)?).await?;
+        let Some(WsMessage::Binary(_)) = client.next().await.transpose()? else {
+            anyhow::bail!("expected the subscription response");
+        };
+        let Some(WsMessage::Text(timing)) = client.next().await.transpose()? else {
+            anyhow::bail!("expected the timing of the response");
+        };
+        let timing: serde_json::Value = serde_json::from_str(&timing)?;
+        let phase = |name: &str| timing["timing"][name].as_f64().unwrap();
+        for name in ["queueMs", "executionMs", "serializationMs"] {
+            assert!(phase(name).is_finite() && phase(name) >= 0.0, "{timing}");
+        }
+        assert!(phase("executionMs") >= 50.0, "{timing}");
+
+        // only the request asked for is timed
+        client.send(subscribe(2)?).await?;
+        let Some(WsMessage::Binary(_)) = client.next().await.transpose()? else {
+            anyhow::bail!("expected the subscription response");
+        };
+        client
+            .send(WsMessage::Text(r#"{"state":{}}"#.into()))
+            .await?;
+        let Some(WsMessage::Text(state)) = client.next().await.transpose()? else {
+            anyhow::bail!("expected the connection state");
+        };
+        assert!(state.contains("\"state\""), "{state}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn metrics_snapshot_reflects_activity() -> anyhow::Result<()> {
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message as WsMessage};
+
+        let (mut proxy, router) = WebSocketProxy::create_router(Router::new());
+        let metrics = proxy.metrics();
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        tokio::spawn(async move {
+            let mut notifications = Vec::new();
+            while let Ok(req) = proxy.recv().await {
+                let ClientRequest::ContractOp(ContractRequest::Subscribe { key, .. }) =
+                    *req.request
+                else {
+                    continue;
+                };
+                notifications.extend(req.notification_channel);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                let response = ContractResponse::SubscribeResponse {
+                    key,
+                    subscribed: true,
+                };
+                proxy
+                    .send(req.client_id, Ok(response.into()))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let connect = || async {
+            let mut request = format!("ws://{addr}/v1/contract/command").into_client_request()?;
+            request
+                .headers_mut()
+                .insert(EncodingProtocolExt::name(), "native".parse()?);
+            anyhow::Ok(tokio_tungstenite::connect_async(request).await?.0)
+        };
+        let mut client = connect().await?;
+        let idle = connect().await?;
+        for n in 1..=3 {
+            let request = ClientRequest::ContractOp(ContractRequest::Subscribe {
+                key: key(n),
+                summary: None,
+            });
+            client
+                .send(WsMessage::Binary(bincode::serialize(&request)?.into()))
+                .await?;
+            let Some(WsMessage::Binary(_)) = client.next().await.transpose()? else {
+                anyhow::bail!("expected the subscription response");
+            };
+        }
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.connections, 2, "{snapshot:?}");
+        // the connections of both clients along with the subscriptions
+        assert_eq!(snapshot.requests, 5, "{snapshot:?}");
+        assert_eq!(snapshot.queued, 0, "{snapshot:?}");
+        assert_eq!(snapshot.answered, 3, "{snapshot:?}");
+        assert!(
+            snapshot.mean_latency >= Duration::from_millis(20),
+            "{snapshot:?}"
+        );
+        assert!(
+            snapshot.max_latency >= snapshot.mean_latency,
+            "{snapshot:?}"
+        );
+
+        drop(idle);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while metrics.snapshot().connections > 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retried_write_answered_once_per_contract() -> anyhow::Result<()> {
+        use freenet_stdlib::prelude::{State, StateSummary, UpdateData};
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message as WsMessage};
+
+        let (mut proxy, router) = WebSocketProxy::create_router(Router::new());
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        // stands in for the node, answering every update with how many it applied
+        let (applied, mut updates) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut count = 0u8;
+            while let Ok(req) = proxy.recv().await {
+                let ClientRequest::ContractOp(ContractRequest::Update { key, .. }) = *req.request
+                else {
+                    continue;
+                };
+                count += 1;
+                applied.send(key).unwrap();
+                let response = ContractResponse::UpdateResponse {
+                    key,
+                    summary: StateSummary::from(vec![count]),
+                };
+                proxy
+                    .send(req.client_id, Ok(response.into()))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let mut request = format!("ws://{addr}/v1/contract/command").into_client_request()?;
+        request
+            .headers_mut()
+            .insert(EncodingProtocolExt::name(), "native".parse()?);
+        let (mut client, _) = tokio_tungstenite::connect_async(request).await?;
+        let mut update = async |contract| -> anyhow::Result<(ContractKey, Vec<u8>)> {
+            client
+                .send(WsMessage::Text(
+                    r#"{"idempotencyKey":{"key":"retry-me"}}"#.into(),
+                ))
+                .await?;
+            let request = ClientRequest::ContractOp(ContractRequest::Update {
+                key: contract,
+                data: UpdateData::State(State::from(vec![1])),
+            });
+            client
+                .send(WsMessage::Binary(bincode::serialize(&request)?.into()))
+                .await?;
+            let Some(WsMessage::Binary(response)) = client.next().await.transpose()? else {
+                anyhow::bail!("expected the update response");
+            };
+            match bincode::deserialize::<Result<HostResponse, ClientError>>(&response)? {
+                Ok(HostResponse::ContractResponse(ContractResponse::UpdateResponse {
+                    key,
+                    summary,
+                })) => Ok((key, summary.into_bytes())),
+                other => anyhow::bail!("unexpected response {other:?}"),
+            }
+        };
+
+        assert_eq!(update(key(1)).await?, (key(1), vec![1]));
+        // retried, answered with the result of the first
+        assert_eq!(update(key(1)).await?, (key(1), vec![1]));
+        // the same key with another contract is another write
+        assert_eq!(update(key(2)).await?, (key(2), vec![2]));
+        assert_eq!(update(key(2)).await?, (key(2), vec![2]));
+
+        assert_eq!(updates.recv().await, Some(key(1)));
+        assert_eq!(updates.recv().await, Some(key(2)));
+        assert!(updates.try_recv().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn snapshot_delivered_once_then_closed() -> anyhow::Result<()> {
+        use freenet_stdlib::prelude::WrappedState;
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message as WsMessage};
+
+        let (mut proxy, router) = WebSocketProxy::create_router(Router::new());
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        // stands in for the node, handing over the channel of the subscription
+        let (subscribed, mut subscriptions) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok(req) = proxy.recv().await {
+                let response = match *req.request {
+                    ClientRequest::ContractOp(ContractRequest::Subscribe { key, .. }) => {
+                        subscribed.send(req.notification_channel).unwrap();
+                        ContractResponse::SubscribeResponse {
+                            key,
+                            subscribed: true,
+                        }
+                    }
+                    ClientRequest::ContractOp(ContractRequest::Get { key, .. }) => {
+                        ContractResponse::GetResponse {
+                            key,
+                            contract: None,
+                            state: WrappedState::new(vec![7]),
+                        }
+                    }
+                    _ => continue,
+                };
+                proxy
+                    .send(req.client_id, Ok(response.into()))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let mut request = format!("ws://{addr}/v1/contract/command").into_client_request()?;
+        request
+            .headers_mut()
+            .insert(EncodingProtocolExt::name(), "native".parse()?);
+        let (mut client, _) = tokio_tungstenite::connect_async(request).await?;
+        let frame = serde_json::json!({ "snapshot": { "key": key(1).to_string() } });
+        client
+            .send(WsMessage::Text(frame.to_string().into()))
+            .await?;
+        let Some(WsMessage::Binary(response)) = client.next().await.transpose()? else {
+            anyhow::bail!("expected the state");
+        };
+        let response: Result<HostResponse, ClientError> = bincode::deserialize(&response)?;
+        assert!(matches!(
+            response,
+            Ok(HostResponse::ContractResponse(ContractResponse::GetResponse { key: got, state, .. }))
+                if got == key(1) && state.as_ref() == [7]
+        ));
+
+        // the subscription was closed once the state was in
+        let updates = subscriptions.recv().await.flatten().unwrap();
+        assert!(updates.is_closed());
+        client
+            .send(WsMessage::Text(r#"{"state":{}}"#.into()))
+            .await?;
+        let Some(WsMessage::Text(state)) = client.next().await.transpose()? else {
+            anyhow::bail!("expected the connection state");
+        };
+        let state: serde_json::Value = serde_json::from_str(&state)?;
+        assert_eq!(state["state"]["subscriptions"], serde_json::json!([]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn range_streamed_over_the_connection() -> anyhow::Result<()> {
+        use std::net::SocketAddr;
+
+        use base64::Engine;
+        use freenet_stdlib::prelude::WrappedState;
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        use crate::{
+            contract::{
+                collection::{collection, stream_range},
+                storages::Storage,
+            },
+            server::http_gateway::HttpGateway,
+            wasm_runtime::StateReader,
+        };
+
+        let (mut gw, router) = HttpGateway::as_router(&SocketAddr::from(([127, 0, 0, 1], 0)));
+        let (_proxy, router) = WebSocketProxy::create_router(router);
+        let router = router.layer(Extension(gw.executor_commands()));
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        // stands in for the executor
+        let entries: Vec<_> = (0..10_000u32).map(|i| i.to_be_bytes()).collect();
+        let state = WrappedState::new(collection(&entries));
+        let mut commands = gw.take_executor_commands();
+        tokio::spawn(async move {
+            while let Some(command) = commands.recv().await {
+                let ExecutorCommand::Range {
+                    offset,
+                    limit,
+                    respond,
+                    ..
+                } = command
+                else {
+                    panic!("unexpected command");
+                };
+                let reader = StateReader::<Storage>::from(state.clone());
+                let _ = respond.send(Ok(stream_range(reader, offset, limit)));
+            }
+        });
+
+        let (mut client, _) =
+            tokio_tungstenite::connect_async(format!("ws://{addr}/v1/contract/command")).await?;
+        let frame = serde_json::json!({
+            "range": { "key": key(1).to_string(), "offset": 4990, "limit": 20 }
+        });
+        client
+            .send(WsMessage::Text(frame.to_string().into()))
+            .await?;
+        let mut next_frame = async || -> anyhow::Result<serde_json::Value> {
+            let Some(WsMessage::Text(frame)) = client.next().await.transpose()? else {
+                anyhow::bail!("expected a range frame");
+            };
+            let frame: serde_json::Value = serde_json::from_str(&frame)?;
+            assert_eq!(frame["range"]["key"], key(1).to_string());
+            Ok(frame["range"].clone())
+        };
+        for index in 4990u32..5010 {
+            let frame = next_frame().await?;
+            assert_eq!(frame["entry"]["index"], index);
+            assert_eq!(
+                frame["entry"]["data"],
+                base64::engine::general_purpose::STANDARD.encode(index.to_be_bytes())
+            );
+        }
+        assert_eq!(
+            next_frame().await?["complete"],
+            serde_json::json!({ "entries": 20 })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn session_migrated_between_addresses() -> anyhow::Result<()> {
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message as WsMessage};
+
+        // both addresses serve the same websocket API, sharing its sessions
+        let (mut proxy, router) = WebSocketProxy::create_router(Router::new());
+        let mut addrs = vec![];
+        for _ in 0..2 {
+            let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+            addrs.push(listener.local_addr()?);
+            let router = router.clone();
+            tokio::spawn(async move { axum::serve(listener, router).await });
+        }
+
+        let (subscribed, mut subscriptions) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok(req) = proxy.recv().await {
+                let ClientRequest::ContractOp(ContractRequest::Subscribe { key, .. }) =
+                    *req.request
+                else {
+                    continue;
+                };
+                subscribed.send((req.client_id, key)).unwrap();
+                let response = ContractResponse::SubscribeResponse {
+                    key,
+                    subscribed: true,
+                };
+                proxy
+                    .send(req.client_id, Ok(response.into()))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let connect = |addr: std::net::SocketAddr| async move {
+            let mut request = format!("ws://{addr}/v1/contract/command").into_client_request()?;
+            request
+                .headers_mut()
+                .insert(EncodingProtocolExt::name(), "native".parse()?);
+            anyhow::Ok(tokio_tungstenite::connect_async(request).await?.0)
+        };
+        let mut client = connect(addrs[0]).await?;
+        client
+            .send(WsMessage::Text(r#"{"session":{}}"#.into()))
+            .await?;
+        let Some(WsMessage::Text(response)) = client.next().await.transpose()? else {
+            anyhow::bail!("expected the session");
+        };
+        let response: serde_json::Value = serde_json::from_str(&response)?;
+        let token = response["session"]["token"].as_str().unwrap().to_owned();
+        let req = ClientRequest::ContractOp(ContractRequest::Subscribe {
+            key: key(1),
+            summary: None,
+        });
+        client
+            .send(WsMessage::Binary(bincode::serialize(&req)?.into()))
+            .await?;
+        let (first, _) = subscriptions.recv().await.unwrap();
+        client.next().await.transpose()?;
+        client.close(None).await?;
+
+        let mut client = connect(addrs[1]).await?;
+        let frame = serde_json::json!({ "migrate": { "token": token } });
+        client
+            .send(WsMessage::Text(frame.to_string().into()))
+            .await?;
+        let Some(WsMessage::Text(response)) = client.next().await.transpose()? else {
+            anyhow::bail!("expected the migrated session");
+        };
+        let response: serde_json::Value = serde_json::from_str(&response)?;
+        assert_eq!(response["migrated"]["resumed"], true);
+        assert_ne!(response["migrated"]["token"], token.as_str());
+        assert_eq!(
+            response["migrated"]["subscriptions"],
+            serde_json::json!([key(1).to_string()])
+        );
+        let (second, resubscribed) = subscriptions.recv().await.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(resubscribed, key(1));
+        let Some(WsMessage::Binary(response)) = client.next().await.transpose()? else {
+            anyhow::bail!("expected the subscription");
+        };
+        let response: Result<HostResponse, ClientError> = bincode::deserialize(&response)?;
+        assert!(matches!(
+            response,
+            Ok(HostResponse::ContractResponse(ContractResponse::SubscribeResponse { key: got, .. }))
+                if got == key(1)
+        ));
+
+        // a token the node does not know of starts over
+        let mut client = connect(addrs[1]).await?;
+        let frame = serde_json::json!({ "migrate": { "token": "unknown" } });
+        client
+            .send(WsMessage::Text(frame.to_string().into()))
+            .await?;
+        let Some(WsMessage::Text(response)) = client.next().await.transpose()? else {
+            anyhow::bail!("expected the new session");
+        };
+        let response: serde_json::Value = serde_json::from_str(&response)?;
+        assert_eq!(response["migrated"]["resumed"], false);
+        assert_ne!(response["migrated"]["token"], "unknown");
+        assert_eq!(response["migrated"]["subscriptions"], serde_json::json!([]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handshake_advertises_timeouts() -> anyhow::Result<()> {
+        let config = WebsocketApiConfig {
+            request_timeouts: RequestTimeouts {
+                read_secs: 7,
+                write_secs: 45,
+            },
+            ..Default::default()
+        };
+        let (_proxy, router) = WebSocketProxy::create_router_with_attested_contracts(
+            Router::new(),
+            &config,
+            Arc::new(RwLock::new(HashMap::new())),
+            Arc::new(WorkQueueMetrics::default()),
+        );
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let (_client, response) =
+            tokio_tungstenite::connect_async(format!("ws://{addr}/v1/contract/command")).await?;
+        let header = |name| response.headers().get(name).and_then(|v| v.to_str().ok());
+        assert_eq!(header(READ_TIMEOUT_HEADER), Some("7"));
+        assert_eq!(header(WRITE_TIMEOUT_HEADER), Some("45"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn malformed_auth_token_rejected() -> anyhow::Result<()> {
+        use tokio_tungstenite::tungstenite::Error as WsError;
+
+        let (mut proxy, router) = WebSocketProxy::create_router_with_attested_contracts(
+            Router::new(),
+            &WebsocketApiConfig::default(),
+            Arc::new(RwLock::new(HashMap::new())),
+            Arc::new(WorkQueueMetrics::default()),
+        );
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        tokio::spawn(async move { while proxy.recv().await.is_ok() {} });
+
+        let connect = |token: String| {
+            tokio_tungstenite::connect_async(format!(
+                "ws://{addr}/v1/contract/command?authToken={token}"
+            ))
+        };
+        match connect("not-a-token".to_owned()).await {
+            Err(WsError::Http(response)) => {
+                assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+                assert_eq!(
+                    response.body().as_deref(),
+                    Some(b"malformed auth token".as_slice())
+                );
+            }
+            other => panic!("{other:?}"),
+        }
+        // well formed yet unknown to the node, served as unauthenticated
+        connect(AuthToken::generate().as_str().to_owned()).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn in_band_auth_token_checked_and_kept() -> anyhow::Result<()> {
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message as WsMessage};
+
+        let token = AuthToken::generate();
+        let contract = ContractInstanceId::new([1; 32]);
+        let attested_contracts: AttestedContractMap = Arc::new(RwLock::new(HashMap::from([(
+            token.clone(),
+            (contract, ClientId::FIRST, Instant::now()),
+        )])));
+        let (mut proxy, router) = WebSocketProxy::create_router_with_attested_contracts(
+            Router::new(),
+            &WebsocketApiConfig::default(),
+            attested_contracts,
+            Arc::new(WorkQueueMetrics::default()),
+        );
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        let (received, mut gets) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok(req) = proxy.recv().await {
+                if let ClientRequest::ContractOp(ContractRequest::Get { .. }) = *req.request {
+                    received.send((req.token, req.attested_contract)).unwrap();
+                }
+            }
+        });
+
+        let mut request = format!("ws://{addr}/v1/contract/command").into_client_request()?;
+        request
+            .headers_mut()
+            .insert(EncodingProtocolExt::name(), "native".parse()?);
+        let (mut client, _) = tokio_tungstenite::connect_async(request).await?;
+        let authenticate = |token: &str| ClientRequest::Authenticate {
+            token: token.to_owned(),
+        };
+        client
+            .send(WsMessage::Binary(
+                bincode::serialize(&authenticate("not-a-token"))?.into(),
+            ))
+            .await?;
+        let Some(WsMessage::Binary(response)) = client.next().await.transpose()? else {
+            anyhow::bail!("expected the malformed token refused");
+        };
+        let response: Result<HostResponse, ClientError> = bincode::deserialize(&response)?;
+        assert!(response.is_err());
+
+        let get = ClientRequest::ContractOp(ContractRequest::Get {
+            key: key(1),
+            return_contract_code: false,
+            subscribe: false,
+        });
+        for req in [authenticate(token.as_str()), get] {
+            client
+                .send(WsMessage::Binary(bincode::serialize(&req)?.into()))
+                .await?;
+        }
+        let (presented, attested) = tokio::time::timeout(Duration::from_secs(5), gets.recv())
+            .await?
+            .unwrap();
+        assert_eq!(presented, Some(token));
+        assert_eq!(attested, Some(contract));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn proposed_timeouts_clamped() -> anyhow::Result<()> {
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message as WsMessage};
+
+        use super::timeouts::{IDLE_TIMEOUT_HEADER, KEEPALIVE_HEADER};
+        use crate::config::TimeoutBounds;
+
+        let config = WebsocketApiConfig {
+            connection_timeouts: ConnectionTimeouts {
+                idle_ms: Some(60_000),
+                idle_bounds: TimeoutBounds {
+                    min_ms: Some(100),
+                    max_ms: Some(600),
+                },
+                keepalive_bounds: TimeoutBounds {
+                    min_ms: Some(100),
+                    max_ms: None,
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (mut proxy, router) = WebSocketProxy::create_router_with_attested_contracts(
+            Router::new(),
+            &config,
+            Arc::new(RwLock::new(HashMap::new())),
+            Arc::new(WorkQueueMetrics::default()),
+        );
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        tokio::spawn(async move { while proxy.recv().await.is_ok() {} });
+
+        // both out of bounds, on either side
+        let mut request = format!("ws://{addr}/v1/contract/command").into_client_request()?;
+        request
+            .headers_mut()
+            .insert(IDLE_TIMEOUT_HEADER, "3600000".parse()?);
+        request.headers_mut().insert(KEEPALIVE_HEADER, "1".parse()?);
+        let started = Instant::now();
+        let (mut client, response) = tokio_tungstenite::connect_async(request).await?;
+        let header = |name| response.headers().get(name).and_then(|v| v.to_str().ok());
+        assert_eq!(header(IDLE_TIMEOUT_HEADER), Some("600"));
+        assert_eq!(header(KEEPALIVE_HEADER), Some("100"));
+
+        // pinged meanwhile, yet closed for being idle
+        let mut pings = 0;
+        let closed = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                match client.next().await {
+                    Some(Ok(WsMessage::Ping(_))) => pings += 1,
+                    other => break other,
+                }
+            }
+        })
+        .await?;
+        assert!(
+            matches!(closed, Some(Ok(WsMessage::Close(_))) | None),
+            "{closed:?}"
+        );
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(550), "{elapsed:?}");
+        assert!((3..=6).contains(&pings), "{pings} pings");
+
+        let mut request = format!("ws://{addr}/v1/contract/command").into_client_request()?;
+        request
+            .headers_mut()
+            .insert(KEEPALIVE_HEADER, "a while".parse()?);
+        assert!(tokio_tungstenite::connect_async(request).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn idle_connection_closed_after_idle_timeout() -> anyhow::Result<()> {
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let config = WebsocketApiConfig {
+            connection_timeouts: ConnectionTimeouts {
+                idle_ms: Some(300),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (mut proxy, router) = WebSocketProxy::create_router_with_attested_contracts(
+            Router::new(),
+            &config,
+            Arc::new(RwLock::new(HashMap::new())),
+            Arc::new(WorkQueueMetrics::default()),
+        );
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        // registers the connection, the client never makes any request
+        tokio::spawn(async move { while proxy.recv().await.is_ok() {} });
+        let (mut client, _) =
+            tokio_tungstenite::connect_async(format!("ws://{addr}/v1/contract/command")).await?;
+
+        // kept open past the idle timeout as long as there is traffic
+        for _ in 0..6 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            client.send(WsMessage::Ping(vec![1].into())).await?;
+            let pong = tokio::time::timeout(Duration::from_millis(200), client.next()).await?;
+            assert!(matches!(pong, Some(Ok(WsMessage::Pong(_)))), "{pong:?}");
+        }
+
+        let started = Instant::now();
+        let closed = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                match client.next().await {
+                    // also answered by the websocket library itself
+                    Some(Ok(WsMessage::Pong(_))) => continue,
+                    other => break other,
+                }
+            }
+        })
+        .await?;
+        assert!(
+            matches!(closed, Some(Ok(WsMessage::Close(_))) | None),
+            "{closed:?}"
+        );
+        assert!(started.elapsed() >= Duration::from_millis(250));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn audit_trail_records_privileged_operations() -> anyhow::Result<()> {
+        use freenet_stdlib::prelude::{StateDelta, UpdateData};
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message as WsMessage};
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("audit.log");
+        let audit_key = dir.path().join("audit.key");
+        let config = WebsocketApiConfig {
+            audit_log: Some(path.clone()),
+            audit_log_key: Some(audit_key.clone()),
+            ..Default::default()
+        };
+        let (mut proxy, router) = WebSocketProxy::create_router_with_attested_contracts(
+            Router::new(),
+            &config,
+            Arc::new(RwLock::new(HashMap::new())),
+            Arc::new(WorkQueueMetrics::default()),
+        );
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        tokio::spawn(async move { while proxy.recv().await.is_ok() {} });
+
+        let mut request = format!("ws://{addr}/v1/contract/command").into_client_request()?;
+        request
+            .headers_mut()
+            .insert(EncodingProtocolExt::name(), "native".parse()?);
+        let (mut client, _) = tokio_tungstenite::connect_async(request).await?;
+        let update = |n: u8| {
+            ClientRequest::ContractOp(ContractRequest::Update {
+                key: key(n),
+                data: UpdateData::Delta(StateDelta::from(vec![n])),
+            })
+        };
+        for req in [
+            update(1),
+            // not privileged
+            ClientRequest::ContractOp(ContractRequest::Get {
+                key: key(2),
+                return_contract_code: false,
+                subscribe: false,
+            }),
+            ClientRequest::Authenticate {
+                token: "token".into(),
+            },
+            update(3),
+        ] {
+            client
+                .send(WsMessage::Binary(bincode::serialize(&req)?.into()))
+                .await?;
+        }
+        client.close(None).await?;
+
+        let records = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let records = audit::verify(&path, &audit_key)?;
+                if records.len() == 5 {
+                    break anyhow::Ok(records);
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await??;
+        let events: Vec<_> = records
+            .iter()
+            .map(|record| {
+                let event = record["event"].as_str().unwrap_or_default();
+                match record["target"].as_str() {
+                    Some(target) => format!("{event} {target}"),
+                    None => event.to_owned(),
+                }
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "connected".to_owned(),
+                format!("operation {}", key(1)),
+                "authenticated".to_owned(),
+                format!("operation {}", key(3)),
+                "disconnected".to_owned(),
+            ]
+        );
+        assert!(records
+            .iter()
+            .all(|record| record["client"] == records[0]["client"]
+                && record["tenant"] == records[0]["tenant"]));
+
+        // altering any line breaks the chain
+        let trail = std::fs::read_to_string(&path)?;
+        std::fs::write(&path, trail.replacen("\"update\"", "\"put\"", 1))?;
+        assert!(audit::verify(&path, &audit_key).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_request_fields() {
+        let req = ClientRequest::ContractOp(ContractRequest::Get {
+            key: key(1),
+            return_contract_code: false,
+            subscribe: false,
+        });
+        let mut msg = bincode::serialize(&req).unwrap();
+        // a field added by a newer version of the request
+        msg.extend(bincode::serialize(&true).unwrap());
+
+        let decoded = decode_native(&msg, UnknownFields::Lenient).unwrap();
+        assert!(matches!(
+            decoded,
+            ClientRequest::ContractOp(ContractRequest::Get { key: decoded, .. }) if decoded == key(1)
+        ));
+        assert!(decode_native(&msg, UnknownFields::Strict).is_err());
+        // requests without them are fine regardless
+        let msg = bincode::serialize(&req).unwrap();
+        assert!(decode_native(&msg, UnknownFields::Strict).is_ok());
+    }
+}
@@ -0,0 +1,230 @@
+//! Aggregation of the numeric updates notified for a subscription.
+//!
+//! Clients subscribed to contracts updated often with numeric values may ask, with the
+//! `aggregate` control frame, for those updates to be reduced server-side with one of the
+//! [`AggregateFunction`]s over a window starting with the first update received. Once the window
+//! ends the client gets a single notification of the whole state holding the aggregated value,
+//! as a JSON number, instead of every update. Updates which are not a JSON number, and errors,
+//! are sent as they come.
+//!
+//! A state which is a JSON number is the value of the contract, a delta which is one is added
+//! to the last value. Deltas are sent as they come until a state gives the value they apply to.
+
+use std::time::{Duration, Instant};
+
+use freenet_stdlib::{
+    client_api::{ContractResponse, HostResponse},
+    prelude::{ContractKey, State, UpdateData},
+};
+
+use super::listener::{Causality, Pending};
+use crate::{
+    client_events::HostResult,
+    config::{AggregateFunction, NotificationAggregation},
+};
+
+/// Aggregation picked by a client for one of its subscriptions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct Aggregation {
+    pub function: AggregateFunction,
+    pub window: Duration,
+}
+
+impl Aggregation {
+    /// The aggregation asked for by a client, unless not allowed by `config`.
+    pub fn new(
+        config: &NotificationAggregation,
+        function: AggregateFunction,
+        window_ms: u64,
+    ) -> Result<Self, String> {
+        if !config.allowed_functions.contains(&function) {
+            return Err(format!("aggregate function `{function:?}` not allowed"));
+        }
+        if !(config.min_window_ms..=config.max_window_ms).contains(&window_ms) {
+            return Err(format!(
+                "aggregation window of {window_ms}ms not within {}ms and {}ms",
+                config.min_window_ms, config.max_window_ms
+            ));
+        }
+        Ok(Self {
+            function,
+            window: Duration::from_millis(window_ms),
+        })
+    }
+}
+
+/// The values received over the current window of an aggregated subscription.
+pub(super) struct Aggregator {
+    aggregation: Aggregation,
+    window: Option<Window>,
+    /// Value of the contract as of the last update, if known.
+    last: Option<f64>,
+}
+
+struct Window {
+    started: Instant,
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+    /// Of the last update aggregated.
+    causality: Option<Causality>,
+}
+
+impl Aggregator {
+    pub fn new(aggregation: Aggregation) -> Self {
+        Self {
+            aggregation,
+            window: None,
+            last: None,
+        }
+    }
+
+    /// The value the contract is left at by a notification which can be aggregated, that of
+    /// an update which is a finite JSON number; any other update leaves the value unknown.
+    pub fn value(&mut self, notification: &HostResult) -> Option<f64> {
+        let Ok(HostResponse::ContractResponse(ContractResponse::UpdateNotification {
+            update, ..
+        })) = notification
+        else {
+            return None;
+        };
+        let number = |bytes: &[u8]| {
+            serde_json::from_slice::<f64>(bytes)
+                .ok()
+                .filter(|value| value.is_finite())
+        };
+        self.last = match update {
+            UpdateData::State(state) => number(state.as_ref()),
+            UpdateData::Delta(delta) => self
+                .last
+                .zip(number(delta.as_ref()))
+                .map(|(last, delta)| last + delta)
+                .filter(|value| value.is_finite()),
+            _ => None,
+        };
+        self.last
+    }
+
+    pub fn add(&mut self, value: f64, causality: Option<Causality>, received: Instant) {
+        let window = self.window.get_or_insert(Window {
+            started: received,
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            causality: None,
+        });
+        window.count += 1;
+        window.sum += value;
+        window.min = window.min.min(value);
+        window.max = window.max.max(value);
+        window.causality = causality;
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.window.is_some()
+    }
+
+    pub fn window_ended(&self) -> bool {
+        self.window
+            .as_ref()
+            .is_some_and(|window| window.started.elapsed() >= self.aggregation.window)
+    }
+
+    /// Closes the current window, returning the notification of its aggregated value if any
+    /// value was received in it.
+    pub fn take(&mut self, key: ContractKey) -> Option<Pending> {
+        let window = self.window.take()?;
+        let value = match self.aggregation.function {
+            AggregateFunction::Avg => serde_json::json!(window.sum / window.count as f64),
+            AggregateFunction::Min => serde_json::json!(window.min),
+            AggregateFunction::Max => serde_json::json!(window.max),
+            AggregateFunction::Sum => serde_json::json!(window.sum),
+            AggregateFunction::Count => serde_json::json!(window.count),
+        };
+        let update = UpdateData::State(State::from(value.to_string().into_bytes()));
+        let notification = ContractResponse::UpdateNotification { key, update };
+        // the aggregated value replaces whatever the client held
+        let causality = window.causality.map(|causality| Causality {
+            version: causality.version,
+            base: None,
+        });
+        Some((Ok(notification.into()), causality, window.started))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use freenet_stdlib::prelude::{ContractInstanceId, StateDelta};
+
+    use super::*;
+
+    fn state(key: ContractKey, value: &str) -> HostResult {
+        Ok(ContractResponse::UpdateNotification {
+            key,
+            update: UpdateData::State(State::from(value.as_bytes().to_vec())),
+        }
+        .into())
+    }
+
+    fn delta(key: ContractKey, value: &str) -> HostResult {
+        Ok(ContractResponse::UpdateNotification {
+            key,
+            update: UpdateData::Delta(StateDelta::from(value.as_bytes().to_vec())),
+        }
+        .into())
+    }
+
+    #[test]
+    fn aggregates_allowed_by_config() {
+        let config = NotificationAggregation {
+            allowed_functions: vec![AggregateFunction::Avg],
+            ..Default::default()
+        };
+        assert!(Aggregation::new(&config, AggregateFunction::Avg, 1_000).is_ok());
+        assert!(Aggregation::new(&config, AggregateFunction::Sum, 1_000).is_err());
+        assert!(Aggregation::new(&config, AggregateFunction::Avg, 1).is_err());
+        assert!(Aggregation::new(&config, AggregateFunction::Avg, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn values_of_numeric_updates() {
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let mut aggregator = Aggregator::new(Aggregation {
+            function: AggregateFunction::Avg,
+            window: Duration::from_secs(1),
+        });
+        // nothing to apply it to yet
+        assert_eq!(aggregator.value(&delta(key, "2")), None);
+        assert_eq!(aggregator.value(&state(key, "21.5")), Some(21.5));
+        assert_eq!(aggregator.value(&delta(key, "-3")), Some(18.5));
+        assert_eq!(aggregator.value(&delta(key, "1")), Some(19.5));
+        assert_eq!(aggregator.value(&state(key, "\"21.5\"")), None);
+        assert_eq!(aggregator.value(&delta(key, "1")), None);
+        assert_eq!(aggregator.value(&state(key, "-3")), Some(-3.0));
+        assert_eq!(aggregator.value(&delta(key, "{}")), None);
+    }
+
+    #[test]
+    fn functions_over_window() {
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let aggregated = |function| {
+            let mut aggregator = Aggregator::new(Aggregation {
+                function,
+                window: Duration::from_secs(1),
+            });
+            for value in [4.0, 1.0, 7.0] {
+                aggregator.add(value, None, Instant::now());
+            }
+            let (notification, _, _) = aggregator.take(key).unwrap();
+            assert!(!aggregator.is_open());
+            aggregator.value(&notification).unwrap()
+        };
+        assert_eq!(aggregated(AggregateFunction::Avg), 4.0);
+        assert_eq!(aggregated(AggregateFunction::Min), 1.0);
+        assert_eq!(aggregated(AggregateFunction::Max), 7.0);
+        assert_eq!(aggregated(AggregateFunction::Sum), 12.0);
+        assert_eq!(aggregated(AggregateFunction::Count), 3.0);
+    }
+}
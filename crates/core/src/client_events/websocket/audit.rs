@@ -0,0 +1,673 @@
+//! Append-only audit trail of the websocket connections.
+//!
+//! Each connection records when it was opened, every time it authenticates, every privileged
+//! operation it requests and when it was closed, along with who it was at the time, as one
+//! JSON line per event. Every line carries the hash of the line before it and its own hash
+//! over both, keyed with the secret key of the trail, so editing, dropping or reordering lines
+//! breaks the chain from there on, even for whoever can write the trail but not read the key.
+//! A trail already in the file is continued instead of starting a new one: a line left half
+//! written at its end, e.g. by a crash, is cut off, while a last line which isn't a record
+//! chained under the key leaves the trail unwritable, it is not started over.
+//!
+//! The lines are written by a thread of their own, connections only queue them. Lines which
+//! can't be written, e.g. as the disk of the trail is full, are handled by the configured
+//! [`LogFullPolicy`]; with the block policy the writer keeps trying the line of a privileged
+//! request for as long as the request may wait, the request is refused past that. Whatever
+//! the policy, the lines about connections coming and going are dropped then. A line written
+//! only in part is cut off, the chain goes on from the last line written whole.
+//!
+//! The node keeps no access log of its own, the requests to the gateway are only traced, so
+//! the audit trail is the only log the policy applies to.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    net::IpAddr,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use freenet_stdlib::{
+    client_api::{ClientRequest, ContractRequest, DelegateRequest},
+    prelude::ContractInstanceId,
+};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::Sha256;
+use tokio::sync::{mpsc, oneshot};
+
+use super::{tenant::TenantId, ClientId};
+use crate::config::LogFullPolicy;
+
+/// Hash preceding the first line of a trail.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// How often the line of a blocked request is tried again.
+const BLOCKED_RETRY: Duration = Duration::from_millis(100);
+
+/// Size of the keys generated for the trails without one.
+const KEY_LEN: usize = 32;
+
+/// How much of the end of the trail is read at a time looking for its last line.
+const TAIL_CHUNK: u64 = 4096;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Where the lines of a trail are written.
+trait Sink: Send {
+    fn append(&mut self, line: &[u8]) -> std::io::Result<()>;
+    /// Cuts off whatever follows the first `len` bytes, e.g. a line written in part.
+    fn truncate(&mut self, len: u64) -> std::io::Result<()>;
+}
+
+impl Sink for File {
+    fn append(&mut self, line: &[u8]) -> std::io::Result<()> {
+        self.write_all(line)?;
+        self.flush()
+    }
+
+    fn truncate(&mut self, len: u64) -> std::io::Result<()> {
+        self.set_len(len)
+    }
+}
+
+struct Chain {
+    sink: Box<dyn Sink>,
+    key: Vec<u8>,
+    seq: u64,
+    last: String,
+    /// Bytes of the lines written whole.
+    len: u64,
+    /// Whether a line written in part is yet to be cut off.
+    torn: bool,
+}
+
+/// A line queued for the writer.
+struct Entry {
+    at: chrono::DateTime<Utc>,
+    client: ClientId,
+    identity: AuditIdentity,
+    event: AuditEvent,
+    /// Until when the line is tried again if it can't be written.
+    blocked_until: Option<Instant>,
+    written: oneshot::Sender<std::io::Result<()>>,
+}
+
+#[derive(Default)]
+pub(super) struct AuditTrail {
+    writer: Option<mpsc::UnboundedSender<Entry>>,
+    full: LogFullPolicy,
+    /// How long a privileged request waits for its line with the block policy.
+    max_blocked: Duration,
+}
+
+/// Who a connection is, as far as the node knows.
+#[derive(Debug, Clone, Serialize)]
+pub(super) struct AuditIdentity {
+    pub tenant: TenantId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<IpAddr>,
+    /// Name in the client certificate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub certificate: Option<Arc<str>>,
+    /// Contract the token of the connection was attested for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attested: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub(super) enum AuditEvent {
+    Connected,
+    Authenticated,
+    Operation {
+        operation: &'static str,
+        target: String,
+    },
+    Disconnected,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    seq: u64,
+    at: chrono::DateTime<Utc>,
+    client: ClientId,
+    #[serde(flatten)]
+    identity: &'a AuditIdentity,
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+    prev: &'a str,
+}
+
+impl AuditTrail {
+    /// Appends to the trail at `path`, chained with the key at `key`, or next to the trail
+    /// if not set, which is generated if there is none yet.
+    pub fn open(
+        path: &Path,
+        key: Option<&Path>,
+        full: LogFullPolicy,
+        max_blocked: Duration,
+    ) -> Self {
+        let path = path.to_owned();
+        let key = key.map_or_else(|| path.with_extension("key"), Path::to_owned);
+        Self::spawn(move || Chain::continued(&path, &key), full, max_blocked)
+    }
+
+    /// Starts the writer, which first sets up the chain it writes to; while it can't, no line
+    /// is written and the requests to record are handled by the policy.
+    fn spawn(
+        chain: impl FnOnce() -> std::io::Result<Chain> + Send + 'static,
+        full: LogFullPolicy,
+        max_blocked: Duration,
+    ) -> Self {
+        let (writer, entries) = mpsc::unbounded_channel();
+        let spawned = std::thread::Builder::new()
+            .name("audit-trail".into())
+            .spawn(move || write(chain(), entries));
+        if let Err(err) = spawned {
+            tracing::error!(%err, "failed starting the writer of the audit trail");
+            return Self::default();
+        }
+        Self {
+            writer: Some(writer),
+            full,
+            max_blocked,
+        }
+    }
+
+    /// Records the connection of `client`, and its disconnection once the session is dropped.
+    pub fn connect(self: &Arc<Self>, client: ClientId, identity: AuditIdentity) -> AuditSession {
+        let session = AuditSession {
+            trail: self.clone(),
+            client,
+            identity,
+        };
+        session.record(AuditEvent::Connected);
+        session
+    }
+
+    /// Queues a line, none is returned if there is no trail to write it to. The line of a
+    /// request which may block is tried again as the policy says.
+    fn append(
+        &self,
+        client: ClientId,
+        identity: &AuditIdentity,
+        event: AuditEvent,
+        may_block: bool,
+    ) -> Option<oneshot::Receiver<std::io::Result<()>>> {
+        let writer = self.writer.as_ref()?;
+        let (written, result) = oneshot::channel();
+        let entry = Entry {
+            at: Utc::now(),
+            client,
+            identity: identity.clone(),
+            event,
+            blocked_until: (may_block && self.full == LogFullPolicy::Block)
+                .then(|| Instant::now() + self.max_blocked),
+            written,
+        };
+        writer.send(entry).ok()?;
+        Some(result)
+    }
+}
+
+impl Chain {
+    /// The chain of the trail at `path`, continued from its last line.
+    fn continued(path: &Path, key: &Path) -> std::io::Result<Self> {
+        let key = load_key(key)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let (last, written) = tail(&mut file)?;
+        let len = file.metadata()?.len();
+        if written < len {
+            tracing::warn!(
+                path = %path.display(),
+                bytes = len - written,
+                "cutting off a line left half written at the end of the audit trail"
+            );
+            file.set_len(written)?;
+        }
+        let (seq, last) = match last {
+            Some(line) => {
+                let Some(record) = verified(&line, &key) else {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "the last line of the audit trail is not a record chained under its key",
+                    ));
+                };
+                let seq = record["seq"].as_u64().unwrap_or_default() + 1;
+                let hash = record["hash"].as_str().unwrap_or_default().to_owned();
+                (seq, hash)
+            }
+            None => (0, GENESIS.to_owned()),
+        };
+        Ok(Self {
+            sink: Box::new(file),
+            key,
+            seq,
+            last,
+            len: written,
+            torn: false,
+        })
+    }
+
+    fn append(&mut self, entry: &Entry) -> std::io::Result<()> {
+        let record = Record {
+            seq: self.seq,
+            at: entry.at,
+            client: entry.client,
+            identity: &entry.identity,
+            event: &entry.event,
+            prev: &self.last,
+        };
+        let Ok(Value::Object(mut record)) = serde_json::to_value(record) else {
+            return Ok(());
+        };
+        let hash = hash(&self.key, &record);
+        record.insert("hash".into(), Value::String(hash.clone()));
+        let mut line = Value::Object(record).to_string().into_bytes();
+        line.push(b'\n');
+        if self.torn {
+            self.sink.truncate(self.len)?;
+            self.torn = false;
+        }
+        // in a single write, so the line is never seen half written
+        if let Err(err) = self.sink.append(&line) {
+            // the lines after one written in part wouldn't be read back
+            if let Err(err) = self.sink.truncate(self.len) {
+                tracing::error!(%err, "failed cutting off a line of the audit trail written in part");
+                self.torn = true;
+            }
+            return Err(err);
+        }
+        self.len += line.len() as u64;
+        self.seq += 1;
+        self.last = hash;
+        Ok(())
+    }
+}
+
+/// Writes the lines queued until the trail is dropped.
+fn write(mut chain: std::io::Result<Chain>, mut entries: mpsc::UnboundedReceiver<Entry>) {
+    if let Err(err) = &chain {
+        tracing::error!(%err, "can't continue the audit trail, no line is written to it");
+    }
+    let mut append = |entry: &Entry| match &mut chain {
+        Ok(chain) => chain.append(entry),
+        Err(err) => Err(std::io::Error::new(err.kind(), err.to_string())),
+    };
+    let mut failing = false;
+    while let Some(entry) = entries.blocking_recv() {
+        let mut written = append(&entry);
+        if let Some(until) = entry.blocked_until {
+            while written.is_err() && Instant::now() < until && !entry.written.is_closed() {
+                std::thread::sleep(BLOCKED_RETRY);
+                written = append(&entry);
+            }
+        }
+        match &written {
+            Err(err) if !failing => {
+                tracing::warn!(client = %entry.client, event = ?entry.event, %err, "failed writing to the audit trail");
+            }
+            Ok(()) if failing => tracing::info!("audit trail written again"),
+            _ => {}
+        }
+        failing = written.is_err();
+        let _ = entry.written.send(written);
+    }
+}
+
+/// The key at `path`, generated if there is none yet.
+fn load_key(path: &Path) -> std::io::Result<Vec<u8>> {
+    match std::fs::read(path) {
+        Ok(key) if key.is_empty() => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("the key of the audit trail at {} is empty", path.display()),
+        )),
+        Ok(key) => Ok(key),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let key: [u8; KEY_LEN] = rand::random();
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options.open(path)?.write_all(&key)?;
+            tracing::info!(path = %path.display(), "generated the key of the audit trail");
+            Ok(key.to_vec())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// The hash of a record is taken over all its fields but the hash itself, the previous one
+/// among them, keyed with the key of the trail.
+fn hash(key: &[u8], record: &serde_json::Map<String, Value>) -> String {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(Value::Object(record.clone()).to_string().as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// The record on `line`, if its hash is the one of the rest of its fields under the key.
+fn verified(line: &[u8], key: &[u8]) -> Option<serde_json::Map<String, Value>> {
+    let Ok(Value::Object(mut record)) = serde_json::from_slice(line) else {
+        return None;
+    };
+    let Some(Value::String(claimed)) = record.remove("hash") else {
+        return None;
+    };
+    if hash(key, &record) != claimed {
+        return None;
+    }
+    record.insert("hash".into(), Value::String(claimed));
+    Some(record)
+}
+
+/// The last line of the trail, read back from its end, along with where the lines written
+/// whole end; anything past them is a line left half written.
+fn tail(file: &mut File) -> std::io::Result<(Option<Vec<u8>>, u64)> {
+    let mut start = file.seek(SeekFrom::End(0))?;
+    let mut read = Vec::new();
+    while start > 0 && read.iter().filter(|b| **b == b'\n').count() < 2 {
+        let chunk = TAIL_CHUNK.min(start);
+        start -= chunk;
+        file.seek(SeekFrom::Start(start))?;
+        let mut buf = vec![0; chunk as usize];
+        file.read_exact(&mut buf)?;
+        buf.extend(read);
+        read = buf;
+    }
+    let Some(end) = read.iter().rposition(|b| *b == b'\n') else {
+        return Ok((None, start));
+    };
+    let line_start = read[..end]
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |newline| newline + 1);
+    Ok((Some(read[line_start..end].to_vec()), start + end as u64 + 1))
+}
+
+/// What is recorded of a connection.
+pub(super) struct AuditSession {
+    trail: Arc<AuditTrail>,
+    client: ClientId,
+    identity: AuditIdentity,
+}
+
+impl AuditSession {
+    pub fn record(&self, event: AuditEvent) {
+        let _ = self.trail.append(self.client, &self.identity, event, false);
+    }
+
+    pub fn authenticated(&mut self, attested: Option<ContractInstanceId>) {
+        if let Some(contract) = attested {
+            self.identity.attested = Some(contract.to_string());
+        }
+        self.record(AuditEvent::Authenticated);
+    }
+
+    /// Records `req` if it is a privileged operation, failing if it can't be recorded and the
+    /// request is to be refused then.
+    pub async fn request(&self, req: &ClientRequest<'_>) -> std::io::Result<()> {
+        let Some(event) = privileged(req) else {
+            return Ok(());
+        };
+        let Some(written) = self.trail.append(self.client, &self.identity, event, true) else {
+            return Ok(());
+        };
+        match written.await {
+            Ok(Ok(())) | Err(_) => Ok(()),
+            Ok(Err(_)) if self.trail.full == LogFullPolicy::Drop => Ok(()),
+            // with the block policy, once it waited for as long as it may
+            Ok(Err(err)) => Err(err),
+        }
+    }
+}
+
+impl Drop for AuditSession {
+    fn drop(&mut self) {
+        self.record(AuditEvent::Disconnected);
+    }
+}
+
+/// Requests changing what is stored in the node: contract writes and delegate registrations.
+fn privileged(req: &ClientRequest) -> Option<AuditEvent> {
+    let (operation, target) = match req {
+        ClientRequest::ContractOp(ContractRequest::Put { contract, .. }) => {
+            ("put", contract.key().to_string())
+        }
+        ClientRequest::ContractOp(ContractRequest::Update { key, .. }) => {
+            ("update", key.to_string())
+        }
+        ClientRequest::DelegateOp(DelegateRequest::RegisterDelegate { delegate, .. }) => {
+            ("register-delegate", delegate.key().to_string())
+        }
+        ClientRequest::DelegateOp(DelegateRequest::UnregisterDelegate(key)) => {
+            ("unregister-delegate", key.to_string())
+        }
+        _ => return None,
+    };
+    Some(AuditEvent::Operation { operation, target })
+}
+
+/// Checks the chain of the trail at `path` under its key at `key`, returning its records.
+#[cfg(test)]
+pub(super) fn verify(path: &Path, key: &Path) -> anyhow::Result<Vec<Value>> {
+    verify_lines(&std::fs::read_to_string(path)?, &std::fs::read(key)?)
+}
+
+#[cfg(test)]
+fn verify_lines(trail: &str, key: &[u8]) -> anyhow::Result<Vec<Value>> {
+    anyhow::ensure!(
+        trail.is_empty() || trail.ends_with('\n'),
+        "last line written in part"
+    );
+    let mut prev = GENESIS.to_owned();
+    let mut records = Vec::new();
+    for (seq, line) in trail.lines().enumerate() {
+        let Some(mut record) = verified(line.as_bytes(), key) else {
+            anyhow::bail!("line {seq} is not a record or was altered");
+        };
+        let Some(Value::String(hash)) = record.remove("hash") else {
+            anyhow::bail!("line {seq} has no hash");
+        };
+        anyhow::ensure!(record["seq"] == seq, "line {seq} out of sequence");
+        anyhow::ensure!(
+            record["prev"] == *prev,
+            "line {seq} doesn't follow the one before"
+        );
+        prev = hash;
+        records.push(Value::Object(record));
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use parking_lot::Mutex;
+
+    use super::*;
+
+    /// A sink failing its writes as a full disk does while `full` is set, the first line
+    /// failing written in part.
+    #[derive(Clone, Default)]
+    struct Disk {
+        full: Arc<AtomicBool>,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Sink for Disk {
+        fn append(&mut self, line: &[u8]) -> std::io::Result<()> {
+            let mut written = self.written.lock();
+            if self.full.load(Ordering::Relaxed) {
+                if written.last().map_or(true, |b| *b == b'\n') {
+                    written.extend_from_slice(&line[..line.len() / 2]);
+                }
+                return Err(std::io::ErrorKind::StorageFull.into());
+            }
+            written.extend_from_slice(line);
+            Ok(())
+        }
+
+        fn truncate(&mut self, len: u64) -> std::io::Result<()> {
+            self.written.lock().truncate(len as usize);
+            Ok(())
+        }
+    }
+
+    impl Disk {
+        fn records(&self) -> Vec<Value> {
+            let written = String::from_utf8(self.written.lock().clone()).unwrap();
+            verify_lines(&written, KEY).unwrap()
+        }
+    }
+
+    const KEY: &[u8] = b"key";
+
+    fn trail(full: LogFullPolicy, max_blocked: Duration) -> (AuditSession, Disk) {
+        let disk = Disk::default();
+        let chain = Chain {
+            sink: Box::new(disk.clone()),
+            key: KEY.to_vec(),
+            seq: 0,
+            last: GENESIS.to_owned(),
+            len: 0,
+            torn: false,
+        };
+        let trail = Arc::new(AuditTrail::spawn(move || Ok(chain), full, max_blocked));
+        (trail.connect(ClientId::next(), identity()), disk)
+    }
+
+    fn identity() -> AuditIdentity {
+        AuditIdentity {
+            tenant: TenantId::resolve(None, None),
+            address: None,
+            certificate: None,
+            attested: None,
+        }
+    }
+
+    fn update() -> ClientRequest<'static> {
+        ClientRequest::ContractOp(ContractRequest::Update {
+            key: ContractInstanceId::new([1; 32]).into(),
+            data: freenet_stdlib::prelude::UpdateData::Delta(vec![1].into()),
+        })
+    }
+
+    #[tokio::test]
+    async fn failing_sink_by_policy() {
+        let (session, disk) = trail(LogFullPolicy::Drop, Duration::ZERO);
+        disk.full.store(true, Ordering::Relaxed);
+        assert!(session.request(&update()).await.is_ok());
+        disk.full.store(false, Ordering::Relaxed);
+        session.request(&update()).await.unwrap();
+        // the connection and the last request, the one in between was dropped and the part
+        // of it written cut off
+        let records = disk.records();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1]["seq"], 1);
+
+        let (session, disk) = trail(LogFullPolicy::Degraded, Duration::ZERO);
+        disk.full.store(true, Ordering::Relaxed);
+        let err = session.request(&update()).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+        // what isn't recorded is served all the same
+        let get = ClientRequest::ContractOp(ContractRequest::Get {
+            key: ContractInstanceId::new([3; 32]).into(),
+            return_contract_code: false,
+            subscribe: false,
+        });
+        assert!(session.request(&get).await.is_ok());
+        disk.full.store(false, Ordering::Relaxed);
+        session.request(&update()).await.unwrap();
+        assert_eq!(disk.records().len(), 2);
+
+        let (session, disk) = trail(LogFullPolicy::Block, Duration::from_secs(60));
+        disk.full.store(true, Ordering::Relaxed);
+        let update = update();
+        let blocked = session.request(&update);
+        tokio::pin!(blocked);
+        assert!(
+            tokio::time::timeout(BLOCKED_RETRY * 3, &mut blocked)
+                .await
+                .is_err(),
+            "the request waits while the trail can't be written"
+        );
+        disk.full.store(false, Ordering::Relaxed);
+        tokio::time::timeout(BLOCKED_RETRY * 3, blocked)
+            .await
+            .expect("written once there is room")
+            .unwrap();
+        assert_eq!(disk.records().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn blocked_for_at_most_max_blocked() {
+        let max_blocked = BLOCKED_RETRY * 3;
+        let (session, disk) = trail(LogFullPolicy::Block, max_blocked);
+        disk.full.store(true, Ordering::Relaxed);
+        let started = Instant::now();
+        let err = tokio::time::timeout(max_blocked * 3, session.request(&update()))
+            .await
+            .expect("refused once it waited for long enough")
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+        assert!(started.elapsed() >= max_blocked);
+    }
+
+    /// The records of the trail once it holds `count`.
+    async fn written(path: &Path, key: &Path, count: usize) -> anyhow::Result<Vec<Value>> {
+        let records = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(records) = verify(path, key) {
+                    if records.len() == count {
+                        break records;
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await?;
+        Ok(records)
+    }
+
+    #[tokio::test]
+    async fn trail_continued_after_reopening() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (path, key) = (dir.path().join("audit.log"), dir.path().join("audit.key"));
+        let session = |full| {
+            let trail = Arc::new(AuditTrail::open(&path, None, full, Duration::ZERO));
+            trail.connect(ClientId::next(), identity())
+        };
+
+        let first = session(LogFullPolicy::Degraded);
+        first.request(&update()).await?;
+        drop(first);
+        written(&path, &key, 3).await?;
+        // a line left half written by a crash is cut off
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        file.write_all(b"{\"seq\":3,\"at\"")?;
+        let second = session(LogFullPolicy::Degraded);
+        second.request(&update()).await?;
+        drop(second);
+        let records = written(&path, &key, 6).await?;
+        assert_eq!(records[5]["seq"], 5);
+
+        // a last line not chained under the key leaves the trail unwritable
+        std::fs::write(&key, b"another key")?;
+        let third = session(LogFullPolicy::Degraded);
+        assert!(third.request(&update()).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+//! Cap on the bytes written to all the websocket connections of the node together.
+//!
+//! Every connection takes the bytes it writes out of a single bucket, refilled at the
+//! configured rate and holding at most a second worth of them. Once it runs dry, writers wait
+//! for it to refill in the order they started waiting, so while the cap is reached the
+//! connections take turns instead of the busiest one getting most of it.
+
+use std::{num::NonZeroU64, time::Duration};
+
+use axum::extract::ws::Message;
+use tokio::{sync::Mutex, time::Instant};
+
+struct Bucket {
+    available: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.available = (self.available + elapsed * rate).min(rate);
+        self.refilled = now;
+    }
+}
+
+#[derive(Default)]
+pub(super) struct Bandwidth {
+    /// Bytes per second along with the bucket, unlimited if not set.
+    limit: Option<(f64, Mutex<Bucket>)>,
+}
+
+impl Bandwidth {
+    pub fn new(bytes_per_sec: Option<NonZeroU64>) -> Self {
+        let limit = bytes_per_sec.map(|rate| {
+            let rate = rate.get() as f64;
+            let bucket = Bucket {
+                available: rate,
+                refilled: Instant::now(),
+            };
+            (rate, Mutex::new(bucket))
+        });
+        Self { limit }
+    }
+
+    /// Waits until `msg` can be written without going over the cap.
+    pub async fn take(&self, msg: &Message) {
+        let Some((rate, bucket)) = &self.limit else {
+            return;
+        };
+        let bytes = len(msg) as f64;
+        // the lock is handed out in the order it is waited on, which is what keeps it fair
+        let mut bucket = bucket.lock().await;
+        bucket.refill(*rate);
+        // a message larger than the bucket goes once it is full, leaving it in debt
+        let needed = bytes.min(*rate);
+        if bucket.available < needed {
+            let deficit = needed - bucket.available;
+            tokio::time::sleep(Duration::from_secs_f64(deficit / rate)).await;
+            bucket.refill(*rate);
+        }
+        bucket.available -= bytes;
+    }
+}
+
+fn len(msg: &Message) -> usize {
+    match msg {
+        Message::Text(text) => text.len(),
+        Message::Binary(bytes) | Message::Ping(bytes) | Message::Pong(bytes) => bytes.len(),
+        Message::Close(frame) => frame.as_ref().map_or(0, |frame| 2 + frame.reason.len()),
+    }
+}
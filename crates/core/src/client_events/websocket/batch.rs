@@ -0,0 +1,400 @@
+//! Subscriptions to several contracts set up with a single control frame.
+//!
+//! The proxy sends a regular subscribe request to the node for every key of the batch and
+//! holds back their responses, once all of them are in the client gets a single
+//! [`ControlResponse::Subscribed`] with the outcome for each key. Notifications for every
+//! subscription of the batch arrive over the connection as for any other subscription.
+//!
+//! Outcomes carry the index of their key in the request, and the response lists apart the
+//! indices which succeeded and failed, so a client retries the failed keys only. A key
+//! requested more than once is subscribed to once, reported at its first index.
+//!
+//! A batch is best effort unless requested as [`BatchMode::Transactional`], then either every
+//! key is subscribed to or none is: once any of them fails, the subscriptions the batch set up
+//! are closed again and reported as rolled back. Subscriptions the connection held before the
+//! batch are kept as they were.
+
+use std::collections::{HashMap, HashSet};
+
+use freenet_stdlib::{
+    client_api::{ContractError, ContractResponse, ErrorKind, HostResponse, RequestError},
+    prelude::{ContractInstanceId, ContractKey},
+};
+use serde::{Deserialize, Serialize};
+
+use super::control::ControlResponse;
+use crate::client_events::HostResult;
+
+/// Whether the keys of a batch are subscribed to independently of each other.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) enum BatchMode {
+    /// Keep the subscriptions set up whatever happens to the rest.
+    #[default]
+    BestEffort,
+    /// Roll back the subscriptions set up if any other fails.
+    Transactional,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(super) struct SubscribeOutcome {
+    index: usize,
+    key: String,
+    subscribed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Set up, then closed along with the rest of a transactional batch.
+    #[serde(rename = "rolledBack", skip_serializing_if = "std::ops::Not::not")]
+    rolled_back: bool,
+}
+
+/// A batch waiting for the responses of some of its subscriptions.
+struct Batch {
+    mode: BatchMode,
+    /// In the order the keys were requested, with their index in the request, `None` until
+    /// the response is in.
+    outcomes: Vec<(usize, String, Option<SubscribeOutcome>)>,
+    waiting: HashMap<ContractInstanceId, usize>,
+    /// Contracts the connection was subscribed to before the batch.
+    held: HashSet<ContractInstanceId>,
+    /// The subscriptions the batch set up so far, with their position in `outcomes`.
+    established: Vec<(usize, ContractKey)>,
+}
+
+#[derive(Default)]
+pub(super) struct PendingBatches(Vec<Batch>);
+
+#[derive(Debug)]
+pub(super) enum Batched {
+    /// The response is not for a subscription of any batch.
+    No,
+    Pending,
+    Complete(ControlResponse),
+    /// A transactional batch failed, the subscriptions to the keys are to be closed.
+    RolledBack(ControlResponse, Vec<ContractKey>),
+}
+
+impl PendingBatches {
+    /// Starts tracking a batch for the requested `keys` of a connection already subscribed to
+    /// the `held` contracts, returns those to subscribe to.
+    ///
+    /// Keys which couldn't be parsed already have their outcome, if none is left the batch
+    /// is complete at once.
+    pub fn start(
+        &mut self,
+        keys: Vec<String>,
+        mode: BatchMode,
+        held: impl IntoIterator<Item = ContractKey>,
+    ) -> Result<Vec<ContractKey>, ControlResponse> {
+        let mut batch = Batch {
+            mode,
+            outcomes: Vec::with_capacity(keys.len()),
+            waiting: HashMap::new(),
+            held: held.into_iter().map(|key| *key.id()).collect(),
+            established: Vec::new(),
+        };
+        let mut subscribe = Vec::new();
+        for (index, requested) in keys.into_iter().enumerate() {
+            let outcome = match ContractKey::from_id(requested.as_str()) {
+                Ok(key) if batch.waiting.contains_key(key.id()) => continue,
+                Ok(key) => {
+                    batch.waiting.insert(*key.id(), batch.outcomes.len());
+                    subscribe.push(key);
+                    None
+                }
+                Err(err) => Some(SubscribeOutcome {
+                    index,
+                    key: requested.clone(),
+                    subscribed: false,
+                    error: Some(format!("invalid contract key: {err}")),
+                    rolled_back: false,
+                }),
+            };
+            batch.outcomes.push((index, requested, outcome));
+        }
+        if batch.waiting.is_empty() {
+            return Err(batch.finish().0);
+        }
+        self.0.push(batch);
+        Ok(subscribe)
+    }
+
+    /// Records `result` if it answers a subscription of a pending batch.
+    pub fn record(&mut self, result: &HostResult) -> Batched {
+        let (id, subscribed, error) = match result {
+            Ok(HostResponse::ContractResponse(ContractResponse::SubscribeResponse {
+                key,
+                subscribed,
+            })) => (*key.id(), subscribed.then_some(*key), None),
+            Err(err) => match subscription_failure(err.kind()) {
+                Some(id) => (id, None, Some(err.to_string())),
+                None => return Batched::No,
+            },
+            Ok(_) => return Batched::No,
+        };
+        let Some(pos) = self
+            .0
+            .iter()
+            .position(|batch| batch.waiting.contains_key(&id))
+        else {
+            return Batched::No;
+        };
+        let batch = &mut self.0[pos];
+        let position = batch.waiting.remove(&id).expect("waiting for key");
+        let (index, key, outcome) = &mut batch.outcomes[position];
+        *outcome = Some(SubscribeOutcome {
+            index: *index,
+            key: key.clone(),
+            subscribed: subscribed.is_some(),
+            error,
+            rolled_back: false,
+        });
+        if let Some(key) = subscribed.filter(|_| !batch.held.contains(&id)) {
+            batch.established.push((position, key));
+        }
+        if !batch.waiting.is_empty() {
+            return Batched::Pending;
+        }
+        match self.0.remove(pos).finish() {
+            (response, rolled_back) if rolled_back.is_empty() => Batched::Complete(response),
+            (response, rolled_back) => Batched::RolledBack(response, rolled_back),
+        }
+    }
+}
+
+impl Batch {
+    /// The response to the batch, and the subscriptions rolled back if any.
+    fn finish(self) -> (ControlResponse, Vec<ContractKey>) {
+        let mut subscriptions: Vec<_> = self
+            .outcomes
+            .into_iter()
+            .map(|(_, _, outcome)| outcome.expect("every key answered"))
+            .collect();
+        let mut rolled_back = Vec::new();
+        if self.mode == BatchMode::Transactional
+            && subscriptions.iter().any(|outcome| !outcome.subscribed)
+        {
+            for (position, key) in self.established {
+                let outcome = &mut subscriptions[position];
+                outcome.subscribed = false;
+                outcome.rolled_back = true;
+                rolled_back.push(key);
+            }
+        }
+        let (succeeded, failed) = subscriptions
+            .iter()
+            .partition::<Vec<_>, _>(|outcome| outcome.subscribed);
+        let response = ControlResponse::Subscribed {
+            succeeded: succeeded.iter().map(|outcome| outcome.index).collect(),
+            failed: failed.iter().map(|outcome| outcome.index).collect(),
+            subscriptions,
+        };
+        (response, rolled_back)
+    }
+}
+
+/// The contract a failed request was about, if it could have been a subscription.
+fn subscription_failure(kind: &ErrorKind) -> Option<ContractInstanceId> {
+    let ErrorKind::RequestError(RequestError::ContractError(err)) = kind else {
+        return None;
+    };
+    match err {
+        ContractError::Subscribe { key, .. } | ContractError::Get { key, .. } => Some(*key.id()),
+        ContractError::MissingContract { key } => Some(*key),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use freenet_stdlib::client_api::ClientError;
+
+    use super::*;
+
+    fn key(n: u8) -> ContractKey {
+        ContractKey::from(ContractInstanceId::new([n; 32]))
+    }
+
+    fn subscribed(key: ContractKey) -> HostResult {
+        Ok(ContractResponse::SubscribeResponse {
+            key,
+            subscribed: true,
+        }
+        .into())
+    }
+
+    #[test]
+    fn completes_once_every_key_is_answered() {
+        let mut batches = PendingBatches::default();
+        let keys = [key(1), key(2), key(1)].map(|key| key.to_string()).to_vec();
+        let subscribe = batches.start(keys, BatchMode::BestEffort, []).unwrap();
+        assert_eq!(subscribe, [key(1), key(2)]);
+
+        assert!(matches!(batches.record(&subscribed(key(3))), Batched::No));
+        assert!(matches!(batches.record(&Ok(HostResponse::Ok)), Batched::No));
+        assert!(matches!(
+            batches.record(&subscribed(key(2))),
+            Batched::Pending
+        ));
+        let missing: ClientError = ErrorKind::RequestError(RequestError::ContractError(
+            ContractError::MissingContract { key: *key(1).id() },
+        ))
+        .into();
+        let Batched::Complete(ControlResponse::Subscribed { subscriptions, .. }) =
+            batches.record(&Err(missing))
+        else {
+            panic!("expected the batch to complete");
+        };
+        assert_eq!(subscriptions.len(), 2);
+        assert_eq!(subscriptions[0].key, key(1).to_string());
+        assert!(!subscriptions[0].subscribed);
+        assert!(subscriptions[0].error.is_some());
+        assert_eq!(
+            subscriptions[1],
+            SubscribeOutcome {
+                index: 1,
+                key: key(2).to_string(),
+                subscribed: true,
+                error: None,
+                rolled_back: false,
+            }
+        );
+        // nothing left waiting
+        assert!(matches!(batches.record(&subscribed(key(2))), Batched::No));
+    }
+
+    #[test]
+    fn invalid_keys_only() {
+        let mut batches = PendingBatches::default();
+        let Err(ControlResponse::Subscribed { subscriptions, .. }) =
+            batches.start(vec!["not a key".into()], BatchMode::Transactional, [])
+        else {
+            panic!("expected the batch to complete at once");
+        };
+        assert!(!subscriptions[0].subscribed);
+    }
+
+    #[test]
+    fn mixed_outcomes_reported_by_index() {
+        let mut batches = PendingBatches::default();
+        let keys = vec![
+            key(1).to_string(),
+            "not a key".into(),
+            key(2).to_string(),
+            key(1).to_string(),
+            key(3).to_string(),
+        ];
+        batches.start(keys, BatchMode::BestEffort, []).unwrap();
+        let denied: ClientError =
+            ErrorKind::RequestError(RequestError::ContractError(ContractError::Subscribe {
+                key: key(2),
+                cause: "unauthorized".into(),
+            }))
+            .into();
+        batches.record(&subscribed(key(3)));
+        batches.record(&Err(denied));
+        let Batched::Complete(ControlResponse::Subscribed {
+            subscriptions,
+            succeeded,
+            failed,
+        }) = batches.record(&subscribed(key(1)))
+        else {
+            panic!("expected the batch to complete");
+        };
+
+        assert_eq!(succeeded, [0, 4]);
+        assert_eq!(failed, [1, 2]);
+        let reported: Vec<_> = subscriptions
+            .iter()
+            .map(|outcome| (outcome.index, outcome.subscribed, outcome.error.is_some()))
+            .collect();
+        assert_eq!(
+            reported,
+            [
+                (0, true, false),
+                (1, false, true),
+                (2, false, true),
+                (4, true, false)
+            ]
+        );
+        assert_eq!(subscriptions[2].key, key(2).to_string());
+    }
+
+    #[test]
+    fn transactional_batch_rolled_back_on_failure() {
+        let denied = |key| -> HostResult {
+            Err(
+                ErrorKind::RequestError(RequestError::ContractError(ContractError::Subscribe {
+                    key,
+                    cause: "unauthorized".into(),
+                }))
+                .into(),
+            )
+        };
+        let keys = [key(1), key(2), key(3)].map(|key| key.to_string()).to_vec();
+
+        let mut batches = PendingBatches::default();
+        batches
+            .start(keys.clone(), BatchMode::Transactional, [])
+            .unwrap();
+        batches.record(&subscribed(key(1)));
+        batches.record(&denied(key(2)));
+        let Batched::RolledBack(
+            ControlResponse::Subscribed {
+                subscriptions,
+                succeeded,
+                failed,
+            },
+            rolled_back,
+        ) = batches.record(&subscribed(key(3)))
+        else {
+            panic!("expected the batch to be rolled back");
+        };
+        assert_eq!(rolled_back, [key(1), key(3)]);
+        assert!(succeeded.is_empty());
+        assert_eq!(failed, [0, 1, 2]);
+        let reported: Vec<_> = subscriptions
+            .iter()
+            .map(|outcome| (outcome.subscribed, outcome.rolled_back))
+            .collect();
+        assert_eq!(reported, [(false, true), (false, false), (false, true)]);
+
+        // kept as they are in a best effort batch
+        batches
+            .start(keys.clone(), BatchMode::BestEffort, [])
+            .unwrap();
+        batches.record(&subscribed(key(1)));
+        batches.record(&denied(key(2)));
+        let Batched::Complete(ControlResponse::Subscribed { succeeded, .. }) =
+            batches.record(&subscribed(key(3)))
+        else {
+            panic!("expected the batch to complete");
+        };
+        assert_eq!(succeeded, [0, 2]);
+
+        // nothing to roll back once all succeed
+        batches
+            .start(keys.clone(), BatchMode::Transactional, [])
+            .unwrap();
+        batches.record(&subscribed(key(1)));
+        batches.record(&subscribed(key(2)));
+        assert!(matches!(
+            batches.record(&subscribed(key(3))),
+            Batched::Complete(_)
+        ));
+
+        // subscriptions held before the batch are kept
+        batches
+            .start(keys, BatchMode::Transactional, [key(1)])
+            .unwrap();
+        batches.record(&subscribed(key(1)));
+        batches.record(&denied(key(2)));
+        let Batched::RolledBack(ControlResponse::Subscribed { succeeded, .. }, rolled_back) =
+            batches.record(&subscribed(key(3)))
+        else {
+            panic!("expected the batch to be rolled back");
+        };
+        assert_eq!(rolled_back, [key(3)]);
+        assert_eq!(succeeded, [0]);
+    }
+}
@@ -0,0 +1,284 @@
+//! Compression of the binary messages sent to websocket clients.
+//!
+//! The websocket implementation doesn't support the `permessage-deflate` extension, so the
+//! proxy compresses the messages itself in the same way: every binary message is a raw
+//! deflate stream ended with a sync flush, without the trailing `00 00 ff ff`, and without
+//! context takeover between messages. A client asks for it sending `x-compression: deflate`
+//! in the handshake.
+//!
+//! The node may be configured with a preset dictionary for each subprotocol, payloads of
+//! apps speaking the same subprotocol tend to repeat the same structure which the dictionary
+//! captures. When the client connects with one of those subprotocols the handshake response
+//! carries the hash of the dictionary used, which clients fetch from
+//! `/v1/contract/command/dictionaries/{protocol}` and set on their inflater.
+
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc};
+
+use axum::{
+    extract::ws::Message,
+    http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderMap},
+};
+use flate2::{Compress, CompressError, Compression, FlushCompress};
+use serde::Serialize;
+
+pub(super) const COMPRESSION_HEADER: &str = "x-compression";
+/// Hash of the preset dictionary messages are compressed with, if any.
+pub(super) const DICTIONARY_HEADER: &str = "x-compression-dictionary";
+const DEFLATE: &str = "deflate";
+
+/// Only this much of a dictionary, its end, is within reach of the compressor.
+const WINDOW_SIZE: usize = 32 * 1024;
+/// Ends the output of a sync flush.
+const SYNC_TAIL: [u8; 4] = [0, 0, 0xff, 0xff];
+
+pub(super) struct Dictionary {
+    bytes: Vec<u8>,
+    hash: String,
+}
+
+impl Dictionary {
+    fn new(mut bytes: Vec<u8>) -> Self {
+        if bytes.len() > WINDOW_SIZE {
+            bytes.drain(..bytes.len() - WINDOW_SIZE);
+        }
+        let hash = blake3::hash(&bytes).to_hex().to_string();
+        Self { bytes, hash }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+#[derive(Default)]
+pub(super) struct Dictionaries(BTreeMap<String, Arc<Dictionary>>);
+
+#[derive(Debug, Serialize)]
+pub(super) struct AdvertisedDictionary {
+    protocol: String,
+    hash: String,
+    size: usize,
+}
+
+/// What was agreed on with a client when it connected.
+pub(super) struct Negotiated {
+    pub protocol: Option<String>,
+    pub deflate: Option<Deflate>,
+}
+
+impl Dictionaries {
+    /// Reads the dictionaries at `paths`, leaving out the ones which can't be read.
+    pub fn load(paths: &BTreeMap<String, PathBuf>) -> Self {
+        let mut dictionaries = BTreeMap::new();
+        for (protocol, path) in paths {
+            match std::fs::read(path) {
+                Ok(bytes) => {
+                    dictionaries.insert(protocol.clone(), Arc::new(Dictionary::new(bytes)));
+                }
+                Err(err) => tracing::warn!(
+                    %protocol, path = %path.display(), %err,
+                    "failed reading compression dictionary"
+                ),
+            }
+        }
+        Self(dictionaries)
+    }
+
+    pub fn get(&self, protocol: &str) -> Option<&Dictionary> {
+        self.0.get(protocol).map(AsRef::as_ref)
+    }
+
+    pub fn advertised(&self) -> Vec<AdvertisedDictionary> {
+        self.0
+            .iter()
+            .map(|(protocol, dictionary)| AdvertisedDictionary {
+                protocol: protocol.clone(),
+                hash: dictionary.hash.clone(),
+                size: dictionary.bytes.len(),
+            })
+            .collect()
+    }
+
+    pub fn negotiate(&self, headers: &HeaderMap) -> Negotiated {
+        let offered = |name: &str, value: &str| {
+            headers
+                .get_all(name)
+                .iter()
+                .filter_map(|header| header.to_str().ok())
+                .flat_map(|header| header.split(','))
+                .any(|offered| offered.trim() == value)
+        };
+        let protocol = self
+            .0
+            .keys()
+            .find(|protocol| offered(SEC_WEBSOCKET_PROTOCOL.as_str(), protocol))
+            .cloned();
+        let deflate = offered(COMPRESSION_HEADER, DEFLATE).then(|| Deflate {
+            dictionary: protocol
+                .as_ref()
+                .and_then(|protocol| self.0.get(protocol).cloned()),
+        });
+        Negotiated { protocol, deflate }
+    }
+}
+
+#[derive(Clone, Default)]
+pub(super) struct Deflate {
+    dictionary: Option<Arc<Dictionary>>,
+}
+
+impl Deflate {
+    /// The headers of the handshake response telling the client how messages are compressed.
+    pub fn response_headers(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [(COMPRESSION_HEADER, DEFLATE)].into_iter().chain(
+            self.dictionary
+                .as_ref()
+                .map(|dictionary| (DICTIONARY_HEADER, dictionary.hash.as_str())),
+        )
+    }
+
+    /// Compresses binary messages, the rest are sent as they are.
+    pub fn compress(&self, msg: Message) -> Result<Message, axum::Error> {
+        match msg {
+            Message::Binary(data) => self
+                .deflate(&data)
+                .map(Message::Binary)
+                .map_err(axum::Error::new),
+            other => Ok(other),
+        }
+    }
+
+    fn deflate(&self, data: &[u8]) -> Result<Vec<u8>, CompressError> {
+        let mut compress = Compress::new(Compression::default(), false);
+        let mut out = Vec::new();
+        if let Some(dictionary) = &self.dictionary {
+            // fills the window, the client already has the dictionary so its output is not sent
+            sync_flush(&mut compress, &dictionary.bytes, &mut out)?;
+            out.clear();
+        }
+        sync_flush(&mut compress, data, &mut out)?;
+        if out.ends_with(&SYNC_TAIL) {
+            out.truncate(out.len() - SYNC_TAIL.len());
+        }
+        Ok(out)
+    }
+}
+
+fn sync_flush(
+    compress: &mut Compress,
+    mut input: &[u8],
+    out: &mut Vec<u8>,
+) -> Result<(), CompressError> {
+    loop {
+        out.reserve(input.len() + 64);
+        let read = compress.total_in();
+        compress.compress_vec(input, out, FlushCompress::Sync)?;
+        input = &input[(compress.total_in() - read) as usize..];
+        // done once there was room left after flushing everything
+        if input.is_empty() && out.len() < out.capacity() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{Decompress, FlushDecompress};
+
+    use super::*;
+
+    /// What a client does with the compressed messages.
+    fn inflate(dictionary: Option<&[u8]>, data: &[u8]) -> Vec<u8> {
+        let mut decompress = Decompress::new(false);
+        let mut out = Vec::new();
+        let mut run = |input: &[u8], out: &mut Vec<u8>| {
+            let input = [input, &SYNC_TAIL].concat();
+            let mut input = &input[..];
+            loop {
+                out.reserve(4 * input.len() + 1024);
+                let read = decompress.total_in();
+                decompress
+                    .decompress_vec(input, out, FlushDecompress::Sync)
+                    .unwrap();
+                input = &input[(decompress.total_in() - read) as usize..];
+                if input.is_empty() && out.len() < out.capacity() {
+                    break;
+                }
+            }
+        };
+        if let Some(dictionary) = dictionary {
+            // miniz can't preset a dictionary, though inflating it first leaves the same window
+            let primed = Deflate::default().deflate(dictionary).unwrap();
+            run(&primed, &mut out);
+            out.clear();
+        }
+        run(data, &mut out);
+        out
+    }
+
+    fn payload(n: usize) -> Vec<u8> {
+        format!(
+            r#"{{"type":"document-updated","document":{{"id":{n},"title":"note {n}","owner":"user-{}","tags":["shared","draft"],"revision":{}}}}}"#,
+            n % 7,
+            n * 3
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn dictionary_improves_ratio() {
+        let dictionary = Arc::new(Dictionary::new(
+            (1000..1010).flat_map(payload).collect::<Vec<_>>(),
+        ));
+        let plain = Deflate::default();
+        let preset = Deflate {
+            dictionary: Some(dictionary.clone()),
+        };
+
+        let (mut original, mut without, mut with) = (0, 0, 0);
+        for n in 0..50 {
+            let payload = payload(n);
+            let compressed = plain.deflate(&payload).unwrap();
+            assert_eq!(inflate(None, &compressed), payload);
+            let compressed_preset = preset.deflate(&payload).unwrap();
+            assert_eq!(
+                inflate(Some(dictionary.bytes()), &compressed_preset),
+                payload
+            );
+            original += payload.len();
+            without += compressed.len();
+            with += compressed_preset.len();
+        }
+        let ratio = |compressed| original as f64 / compressed as f64;
+        assert!(
+            ratio(with) > 2.0 * ratio(without),
+            "with dictionary {:.2}, without {:.2}",
+            ratio(with),
+            ratio(without)
+        );
+    }
+
+    #[test]
+    fn negotiate_dictionary_by_protocol() {
+        let dictionaries = Dictionaries(BTreeMap::from([(
+            "chat.v1".to_owned(),
+            Arc::new(Dictionary::new(b"{\"message\":".to_vec())),
+        )]));
+        let mut headers = HeaderMap::new();
+        headers.insert(SEC_WEBSOCKET_PROTOCOL, "other, chat.v1".parse().unwrap());
+        let negotiated = dictionaries.negotiate(&headers);
+        assert_eq!(negotiated.protocol.as_deref(), Some("chat.v1"));
+        // the client didn't ask for compression
+        assert!(negotiated.deflate.is_none());
+
+        headers.insert(COMPRESSION_HEADER, DEFLATE.parse().unwrap());
+        let deflate = dictionaries.negotiate(&headers).deflate.unwrap();
+        let response: Vec<_> = deflate.response_headers().map(|(name, _)| name).collect();
+        assert_eq!(response, [COMPRESSION_HEADER, DICTIONARY_HEADER]);
+
+        headers.remove(SEC_WEBSOCKET_PROTOCOL);
+        let negotiated = dictionaries.negotiate(&headers);
+        assert!(negotiated.protocol.is_none());
+        assert!(negotiated.deflate.unwrap().dictionary.is_none());
+    }
+}
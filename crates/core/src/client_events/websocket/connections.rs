@@ -0,0 +1,198 @@
+//! The websocket connections currently open, listed for operators.
+//!
+//! Clients may tag their connection with a label through the [`CLIENT_LABEL_HEADER`] when
+//! connecting, e.g. the name and instance of their app, so the connection can be told apart
+//! in the logs and in the listing when correlating it with other systems. Labels are cut to
+//! [`MAX_LABEL_LEN`] characters, anything but printable ASCII replaced by `_`.
+
+use std::{collections::BTreeMap, fmt::Display, net::IpAddr, sync::Arc, time::Instant};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::server::metrics::GatewayMetrics;
+
+use super::{tenant::TenantId, ClientId};
+
+pub(super) const CLIENT_LABEL_HEADER: &str = "x-client-label";
+
+const MAX_LABEL_LEN: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub(super) struct ClientLabel(Arc<str>);
+
+impl ClientLabel {
+    /// The label sent by a client, if it isn't blank.
+    pub fn sanitize(label: &str) -> Option<Self> {
+        let label: String = label
+            .trim()
+            .chars()
+            .take(MAX_LABEL_LEN)
+            .map(|c| {
+                if c.is_ascii_graphic() || c == ' ' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        (!label.is_empty()).then(|| Self(label.into()))
+    }
+}
+
+impl Display for ClientLabel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// What is known of a client when it connects.
+#[derive(Debug, Clone)]
+pub(super) struct ConnectionDetails {
+    pub tenant: TenantId,
+    pub label: Option<ClientLabel>,
+    pub address: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct ConnectionListing {
+    pub client: ClientId,
+    pub tenant: TenantId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<ClientLabel>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<IpAddr>,
+    /// Seconds since the client connected.
+    pub open_secs: u64,
+}
+
+#[derive(Default)]
+pub(super) struct Connections {
+    open: Mutex<BTreeMap<ClientId, (ConnectionDetails, Instant)>>,
+    closed: Notify,
+    metrics: GatewayMetrics,
+}
+
+impl Connections {
+    pub fn new(metrics: GatewayMetrics) -> Self {
+        Self {
+            metrics,
+            ..Default::default()
+        }
+    }
+
+    /// Lists the connection of `client` until the returned guard is dropped.
+    pub fn open(self: &Arc<Self>, client: ClientId, details: ConnectionDetails) -> OpenConnection {
+        tracing::debug!(
+            %client,
+            tenant = %details.tenant,
+            label = details.label.as_ref().map(tracing::field::display),
+            "websocket connection open"
+        );
+        self.open.lock().insert(client, (details, Instant::now()));
+        self.metrics.connection_opened();
+        OpenConnection {
+            connections: self.clone(),
+            client,
+        }
+    }
+
+    pub fn list(&self) -> Vec<ConnectionListing> {
+        self.open
+            .lock()
+            .iter()
+            .map(|(client, (details, since))| ConnectionListing {
+                client: *client,
+                tenant: details.tenant.clone(),
+                label: details.label.clone(),
+                address: details.address,
+                open_secs: since.elapsed().as_secs(),
+            })
+            .collect()
+    }
+
+    /// Waits until no connection is open.
+    pub async fn all_closed(&self) {
+        loop {
+            let closed = self.closed.notified();
+            if self.open.lock().is_empty() {
+                return;
+            }
+            closed.await;
+        }
+    }
+}
+
+pub(super) struct OpenConnection {
+    connections: Arc<Connections>,
+    client: ClientId,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        if let Some((details, since)) = self.connections.open.lock().remove(&self.client) {
+            tracing::debug!(
+                client = %self.client,
+                label = details.label.as_ref().map(tracing::field::display),
+                open_secs = since.elapsed().as_secs(),
+                "websocket connection closed"
+            );
+            self.connections.metrics.connection_closed();
+        }
+        self.connections.closed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+
+    #[test]
+    fn label_in_connection_listing() {
+        let connections = Arc::new(Connections::default());
+        let client = ClientId::next();
+        let open = connections.open(
+            client,
+            ConnectionDetails {
+                tenant: TenantId::resolve(None, None),
+                label: ClientLabel::sanitize(" mail-app/instance-2\n"),
+                address: Some([192, 0, 2, 1].into()),
+            },
+        );
+        let anonymous = connections.open(
+            ClientId::next(),
+            ConnectionDetails {
+                tenant: TenantId::resolve(None, None),
+                label: ClientLabel::sanitize("  "),
+                address: None,
+            },
+        );
+
+        let listing = serde_json::to_value(connections.list()).unwrap();
+        assert_eq!(listing[0]["client"], serde_json::json!(client));
+        assert_eq!(listing[0]["label"], "mail-app/instance-2");
+        assert_eq!(listing[0]["address"], "192.0.2.1");
+        assert!(listing[1].get("label").is_none());
+
+        drop(open);
+        let listing = connections.list();
+        assert_eq!(listing.len(), 1);
+        drop(anonymous);
+        assert!(connections.list().is_empty());
+        connections.all_closed().now_or_never().unwrap();
+    }
+
+    #[test]
+    fn labels_sanitized() {
+        let label = ClientLabel::sanitize("app\u{7}\u{e9}").unwrap();
+        assert_eq!(label.to_string(), "app__");
+        let long = "x".repeat(MAX_LABEL_LEN * 2);
+        let label = ClientLabel::sanitize(&long).unwrap();
+        assert_eq!(label.to_string().len(), MAX_LABEL_LEN);
+    }
+}
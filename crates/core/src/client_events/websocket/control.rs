@@ -0,0 +1,590 @@
+//! Control frames handled by the websocket proxy itself.
+//!
+//! Control frames are JSON encoded text messages, they are intercepted before
+//! decoding any [`ClientRequest`](freenet_stdlib::client_api::ClientRequest) and
+//! never reach the node. Any text message which is not a valid control frame is
+//! processed as a regular request.
+
+use axum::extract::ws::Message;
+use freenet_stdlib::prelude::{ContractInstanceId, ContractKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client_events::ClientId,
+    config::{AggregateFunction, SlowConsumerPolicy},
+    contract::collection::RangeFrame,
+};
+
+use super::{
+    batch::{BatchMode, SubscribeOutcome},
+    listener::{NotificationMode, PausePolicy, SubscriptionListener},
+    multipart::PartKind,
+    notification_format::NotificationFormat,
+    replay::{Replay, UpdateLog},
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) enum ControlFrame {
+    /// Allow the server to send this many more messages on the connection, from then on it
+    /// only sends the messages it has credits for.
+    Grant { credits: u64 },
+    /// Describe the connection as the server sees it, so a reconnected client can check it
+    /// is in sync.
+    State {},
+    /// Subscribe to every contract in `keys`, answered once all the subscriptions are set up
+    /// or failed.
+    Subscribe {
+        keys: Vec<String>,
+        #[serde(default)]
+        mode: BatchMode,
+    },
+    /// Follow the response to the next request with where the time answering it went.
+    Timing {},
+    /// Answer the next request, if a write, with the result of the write made with the same
+    /// key to the same contract instead of applying it once more.
+    IdempotencyKey { key: String },
+    /// Get the state of the contract through a subscription closed as soon as the state is in.
+    Snapshot { key: String },
+    /// Keep the session of the connection, answered with the token to carry it over to
+    /// another connection with.
+    Session {},
+    /// Continue the session of the token on this connection.
+    Migrate { token: String },
+    /// Sum up how the subscriptions of the connection are doing.
+    Health {},
+    /// Apply `policy` to the subscription to the given contract once its client falls behind,
+    /// either the one already set up or the next one.
+    SlowConsumer {
+        key: String,
+        policy: SlowConsumerPolicy,
+    },
+    /// Reduce the numeric updates of the subscription to the given contract, either the one
+    /// already set up or the next one, to a single notification every `window_ms` with their
+    /// `function`.
+    Aggregate {
+        key: String,
+        function: AggregateFunction,
+        #[serde(rename = "windowMs")]
+        window_ms: u64,
+    },
+    /// Stream the entries from `offset` of the contract modeling a collection, at most `limit`
+    /// of them, each in a [`ControlResponse::Range`] frame.
+    Range {
+        key: String,
+        #[serde(default)]
+        offset: usize,
+        limit: usize,
+    },
+    /// Get the public key to send the session key of the connection with, if the node
+    /// encrypts messages.
+    EncryptionKey {},
+    /// Seal the binary messages of the connection with the session key given, in base64 and
+    /// encrypted with the public key of the node, from its acknowledgement on.
+    SessionKey { key: String },
+    /// About the subscriptions of the connection to a contract.
+    #[serde(untagged)]
+    Subscription(SubscriptionFrame),
+}
+
+/// Control frames applied to the subscriptions of the connection to the given contract.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) enum SubscriptionFrame {
+    /// Stop sending notifications for the subscription to the given contract.
+    Pause {
+        key: String,
+        #[serde(default)]
+        policy: PausePolicy,
+    },
+    /// Restart sending notifications for a previously paused subscription.
+    Resume { key: String },
+    /// Send the updates applied to a subscribed contract after the given version, handed out
+    /// in the given session of the node, the client gets the full state instead if those are
+    /// not retained anymore.
+    Replay {
+        key: String,
+        since: u64,
+        #[serde(default)]
+        session: Option<u64>,
+    },
+    /// Set the priority of the notifications for the subscription to the given contract, those
+    /// of a higher priority go out first on connections with the priority queue discipline.
+    Prioritize { key: String, priority: u8 },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) enum ControlResponse {
+    Paused {
+        key: String,
+    },
+    Prioritized {
+        key: String,
+        priority: u8,
+    },
+    Resumed {
+        key: String,
+        buffered: usize,
+    },
+    Replayed {
+        key: String,
+        version: u64,
+        session: u64,
+        updates: usize,
+        snapshot: bool,
+    },
+    /// A message is waiting to be sent, along with `queued - 1` others, but there are no
+    /// credits left.
+    CreditsExhausted {
+        queued: usize,
+    },
+    State {
+        client: String,
+        #[serde(rename = "attestedContract")]
+        attested_contract: Option<String>,
+        subscriptions: Vec<SubscriptionState>,
+    },
+    /// The outcome of each subscription requested in a batch, in the order requested, and
+    /// the indices of the keys in the request which were subscribed to and which failed.
+    Subscribed {
+        subscriptions: Vec<SubscribeOutcome>,
+        succeeded: Vec<usize>,
+        failed: Vec<usize>,
+    },
+    /// The next response is over the maximum message size, it follows as `chunks` binary
+    /// messages adding up to `size` bytes.
+    Chunked {
+        size: usize,
+        chunks: usize,
+    },
+    /// Part `index` of the `count` ones of a response, it follows as a binary message.
+    Part {
+        response: u64,
+        index: usize,
+        count: usize,
+        kind: PartKind,
+        key: String,
+    },
+    /// The notification following it as a binary message brings the contract to `version`,
+    /// applying an update made against `base`, both numbered by the node in `session`.
+    Notified {
+        key: String,
+        version: u64,
+        session: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        base: Option<u64>,
+    },
+    /// Milliseconds the request answered by the previous message waited to be picked up by
+    /// the node, the node took to serve it, and its response took to be encoded.
+    Timing {
+        #[serde(rename = "queueMs")]
+        queue_ms: f64,
+        #[serde(rename = "executionMs")]
+        execution_ms: f64,
+        #[serde(rename = "serializationMs")]
+        serialization_ms: f64,
+    },
+    Session {
+        token: String,
+    },
+    SlowConsumer {
+        key: String,
+        policy: SlowConsumerPolicy,
+    },
+    Aggregated {
+        key: String,
+        function: AggregateFunction,
+        #[serde(rename = "windowMs")]
+        window_ms: u64,
+    },
+    /// The subscription to `key` is set up, as the `subscription`-th of the connection, from
+    /// the contract at `version` of the `session`; its notifications are sent in the `mode` and
+    /// `format` given.
+    Acknowledged {
+        key: String,
+        subscription: u64,
+        version: u64,
+        session: u64,
+        mode: NotificationMode,
+        format: NotificationFormat,
+    },
+    /// Of the `active` subscriptions of the connection, those `errored` got a notification of
+    /// an error, while those `backpressured` have notifications waiting for the client or
+    /// dropped; `healthy` are the ones neither.
+    Health {
+        active: usize,
+        paused: usize,
+        errored: usize,
+        backpressured: usize,
+        healthy: usize,
+    },
+    /// The connection continues the session migrated from if `resumed`, subscribed again to the
+    /// contracts listed, otherwise it was unknown and a new session started; either way `token`
+    /// is the one to migrate with next, the one presented is no longer valid.
+    Migrated {
+        token: String,
+        resumed: bool,
+        subscriptions: Vec<String>,
+    },
+    /// The public key to encrypt the session key of the connection with, in PEM.
+    EncryptionKey {
+        #[serde(rename = "publicKey")]
+        public_key: String,
+    },
+    /// The binary messages following it are sealed with the session key sent in the
+    /// `rotation`-th `sessionKey` frame of the connection.
+    SessionKey {
+        rotation: u64,
+    },
+    /// A frame of the range of entries streamed for the contract.
+    Range {
+        key: String,
+        #[serde(flatten)]
+        frame: RangeFrame,
+    },
+    Error {
+        cause: String,
+    },
+}
+
+impl ControlResponse {
+    /// The rotation the text written acknowledges, if it is the acknowledgement of a
+    /// `sessionKey` frame.
+    pub fn acknowledged_rotation(text: &str) -> Option<u64> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Acknowledgement {
+            session_key: Rotation,
+        }
+        #[derive(Deserialize)]
+        struct Rotation {
+            rotation: u64,
+        }
+        let ack: Acknowledgement = serde_json::from_str(text).ok()?;
+        Some(ack.session_key.rotation)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct SubscriptionState {
+    key: String,
+    paused: bool,
+}
+
+impl ControlFrame {
+    pub fn parse(text: &str) -> Option<Self> {
+        serde_json::from_str(text).ok()
+    }
+}
+
+impl SubscriptionFrame {
+    pub fn apply<'a>(
+        self,
+        listeners: impl IntoIterator<Item = &'a mut SubscriptionListener>,
+        update_log: &UpdateLog,
+    ) -> ControlResponse {
+        match self {
+            SubscriptionFrame::Pause { key, policy } => match subscriptions(&key, listeners) {
+                Ok(subs) => {
+                    for sub in subs {
+                        sub.pause(policy);
+                    }
+                    tracing::debug!(contract = %key, ?policy, "paused subscription");
+                    ControlResponse::Paused { key }
+                }
+                Err(err) => err,
+            },
+            SubscriptionFrame::Prioritize { key, priority } => match subscriptions(&key, listeners)
+            {
+                Ok(subs) => {
+                    for sub in subs {
+                        sub.set_priority(priority);
+                    }
+                    ControlResponse::Prioritized { key, priority }
+                }
+                Err(err) => err,
+            },
+            SubscriptionFrame::Resume { key } => match subscriptions(&key, listeners) {
+                Ok(subs) => {
+                    let buffered = subs.into_iter().map(|sub| sub.resume()).sum();
+                    tracing::debug!(contract = %key, buffered, "resumed subscription");
+                    ControlResponse::Resumed { key, buffered }
+                }
+                Err(err) => err,
+            },
+            SubscriptionFrame::Replay {
+                key,
+                since,
+                session,
+            } => match subscriptions(&key, listeners) {
+                Ok(mut subs) => {
+                    let sub = subs.swap_remove(0);
+                    match update_log.replay(&sub.key, session, since) {
+                        Replay::Updates { version, updates } => {
+                            tracing::debug!(contract = %key, since, version, "replaying updates");
+                            let count = updates.len();
+                            sub.replay(updates);
+                            ControlResponse::Replayed {
+                                key,
+                                version,
+                                session: update_log.session(),
+                                updates: count,
+                                snapshot: false,
+                            }
+                        }
+                        Replay::Snapshot { version } => ControlResponse::Replayed {
+                            key,
+                            version,
+                            session: update_log.session(),
+                            updates: 0,
+                            snapshot: true,
+                        },
+                    }
+                }
+                Err(err) => err,
+            },
+        }
+    }
+}
+
+impl ControlResponse {
+    pub fn connection_state<'a>(
+        client: ClientId,
+        attested_contract: Option<&ContractInstanceId>,
+        listeners: impl IntoIterator<Item = &'a SubscriptionListener>,
+    ) -> Self {
+        ControlResponse::State {
+            client: client.to_string(),
+            attested_contract: attested_contract.map(ToString::to_string),
+            subscriptions: listeners
+                .into_iter()
+                .map(|sub| SubscriptionState {
+                    key: sub.key.to_string(),
+                    paused: sub.is_paused(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn health<'a>(listeners: impl IntoIterator<Item = &'a SubscriptionListener>) -> Self {
+        let (mut active, mut paused, mut errored, mut backpressured, mut healthy) = (0, 0, 0, 0, 0);
+        for sub in listeners {
+            active += 1;
+            paused += usize::from(sub.is_paused());
+            let has_errored = sub.delivery().is_some_and(|d| d.has_errored());
+            let is_backpressured = sub.delivery().is_some_and(|d| d.backpressured());
+            errored += usize::from(has_errored);
+            backpressured += usize::from(is_backpressured);
+            healthy += usize::from(!has_errored && !is_backpressured);
+        }
+        ControlResponse::Health {
+            active,
+            paused,
+            errored,
+            backpressured,
+            healthy,
+        }
+    }
+
+    pub fn into_message(self) -> Message {
+        Message::Text(serde_json::to_string(&self).expect("infallible serialization"))
+    }
+}
+
+fn subscriptions<'a>(
+    key: &str,
+    listeners: impl IntoIterator<Item = &'a mut SubscriptionListener>,
+) -> Result<Vec<&'a mut SubscriptionListener>, ControlResponse> {
+    let key = ContractKey::from_id(key).map_err(|err| ControlResponse::Error {
+        cause: format!("invalid contract key `{key}`: {err}"),
+    })?;
+    let subs: Vec<_> = listeners
+        .into_iter()
+        .filter(|sub| sub.key.id() == key.id())
+        .collect();
+    if subs.is_empty() {
+        return Err(ControlResponse::Error {
+            cause: format!("not subscribed to contract `{key}`"),
+        });
+    }
+    Ok(subs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use freenet_stdlib::{
+        client_api::{ContractResponse, HostResponse},
+        prelude::{ContractInstanceId, StateDelta, UpdateData},
+    };
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn parse_control_frames() {
+        assert!(matches!(
+            ControlFrame::parse(r#"{"pause":{"key":"abc"}}"#),
+            Some(ControlFrame::Subscription(SubscriptionFrame::Pause {
+                policy: PausePolicy::Buffer,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            ControlFrame::parse(r#"{"pause":{"key":"abc","policy":"drop"}}"#),
+            Some(ControlFrame::Subscription(SubscriptionFrame::Pause {
+                policy: PausePolicy::Drop,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            ControlFrame::parse(r#"{"resume":{"key":"abc"}}"#),
+            Some(ControlFrame::Subscription(SubscriptionFrame::Resume { .. }))
+        ));
+        assert!(matches!(
+            ControlFrame::parse(r#"{"replay":{"key":"abc","since":3,"session":7}}"#),
+            Some(ControlFrame::Subscription(SubscriptionFrame::Replay {
+                since: 3,
+                session: Some(7),
+                ..
+            }))
+        ));
+        assert!(matches!(
+            ControlFrame::parse(r#"{"grant":{"credits":10}}"#),
+            Some(ControlFrame::Grant { credits: 10 })
+        ));
+        assert!(matches!(
+            ControlFrame::parse(r#"{"state":{}}"#),
+            Some(ControlFrame::State {})
+        ));
+        assert!(matches!(
+            ControlFrame::parse(r#"{"subscribe":{"keys":["abc","def"]}}"#),
+            Some(ControlFrame::Subscribe { keys, mode: BatchMode::BestEffort }) if keys.len() == 2
+        ));
+        assert!(matches!(
+            ControlFrame::parse(r#"{"subscribe":{"keys":["abc"],"mode":"transactional"}}"#),
+            Some(ControlFrame::Subscribe {
+                mode: BatchMode::Transactional,
+                ..
+            })
+        ));
+        assert!(matches!(
+            ControlFrame::parse(r#"{"encryptionKey":{}}"#),
+            Some(ControlFrame::EncryptionKey {})
+        ));
+        assert!(matches!(
+            ControlFrame::parse(r#"{"sessionKey":{"key":"abc"}}"#),
+            Some(ControlFrame::SessionKey { key }) if key == "abc"
+        ));
+        assert!(matches!(
+            ControlFrame::parse(r#"{"prioritize":{"key":"abc","priority":3}}"#),
+            Some(ControlFrame::Subscription(SubscriptionFrame::Prioritize {
+                priority: 3,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            ControlFrame::parse(r#"{"aggregate":{"key":"abc","function":"avg","windowMs":1000}}"#),
+            Some(ControlFrame::Aggregate {
+                function: AggregateFunction::Avg,
+                window_ms: 1000,
+                ..
+            })
+        ));
+        assert!(ControlFrame::parse("not a control frame").is_none());
+    }
+
+    #[test]
+    fn health_of_mixed_subscriptions() {
+        use freenet_stdlib::client_api::{ClientError, ErrorKind};
+
+        use super::super::delivery::Deliveries;
+
+        let deliveries = Arc::new(Deliveries::default());
+        let client = ClientId::next();
+        let mut senders = vec![];
+        let mut listeners: Vec<_> = (1..=4u8)
+            .map(|n| {
+                let key = ContractKey::from(ContractInstanceId::new([n; 32]));
+                let (tx, rx) = mpsc::unbounded_channel();
+                senders.push((key, tx));
+                SubscriptionListener::new(key, rx).with_delivery(deliveries.track(client, &key))
+            })
+            .collect();
+        for (key, tx) in &senders {
+            let update = UpdateData::Delta(StateDelta::from(vec![1]));
+            tx.send(Ok(ContractResponse::UpdateNotification {
+                key: *key,
+                update,
+            }
+            .into()))
+                .unwrap();
+        }
+        // the second one fails, the third one is paused with its notification buffered
+        let failed: ClientError = ErrorKind::NodeUnavailable.into();
+        senders[1].1.send(Err(failed)).unwrap();
+        listeners[2].pause(PausePolicy::Buffer);
+        for listener in &mut listeners {
+            while listener.try_next().unwrap().is_some() {}
+        }
+
+        let health = ControlResponse::health(listeners.iter());
+        assert!(matches!(
+            health,
+            ControlResponse::Health {
+                active: 4,
+                paused: 1,
+                errored: 1,
+                backpressured: 1,
+                healthy: 2,
+            }
+        ));
+        assert!(ControlFrame::parse(r#"{"health":{}}"#).is_some());
+    }
+
+    #[test]
+    fn replay_from_recent_version() {
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let log = Arc::new(UpdateLog::default());
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut listeners = [SubscriptionListener::new(key, rx).with_update_log(log.clone())];
+        for n in 1..=4u8 {
+            let update = UpdateData::Delta(StateDelta::from(vec![n]));
+            tx.send(Ok(
+                ContractResponse::UpdateNotification { key, update }.into()
+            ))
+            .unwrap();
+        }
+        while listeners[0].try_next().unwrap().is_some() {}
+
+        // a reconnecting client which holds the state at version 2
+        let response = SubscriptionFrame::Replay {
+            key: key.to_string(),
+            since: 2,
+            session: Some(log.session()),
+        }
+        .apply(listeners.iter_mut(), &log);
+        assert!(matches!(
+            response,
+            ControlResponse::Replayed {
+                version: 4,
+                updates: 2,
+                snapshot: false,
+                ..
+            }
+        ));
+        let replayed: Vec<_> = std::iter::from_fn(|| listeners[0].try_next().unwrap()).collect();
+        assert_eq!(replayed.len(), 2);
+        for (n, notification) in (3..=4u8).zip(replayed) {
+            assert!(matches!(
+                notification,
+                Ok(HostResponse::ContractResponse(ContractResponse::UpdateNotification {
+                    update: UpdateData::Delta(delta),
+                    ..
+                })) if delta.as_ref() == [n]
+            ));
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! How the notifications of every subscription are being delivered, listed for operators
+//! debugging slow clients.
+//!
+//! A notification counts as sent once it is handed to the writer of the connection, its
+//! latency taken from when it was received from the node, so a client reading slowly shows
+//! up as increasing latencies and, while paused, as queued or dropped notifications.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use freenet_stdlib::prelude::ContractKey;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use super::ClientId;
+
+#[derive(Default)]
+pub(super) struct DeliveryCounters {
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    /// Notifications of the node failing to keep the subscription going.
+    errors: AtomicU64,
+    queued: AtomicUsize,
+    latency_micros: AtomicU64,
+}
+
+impl DeliveryCounters {
+    pub fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn errored(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_queued(&self, queued: usize) {
+        self.queued.store(queued, Ordering::Relaxed);
+    }
+
+    pub fn has_errored(&self) -> bool {
+        self.errors.load(Ordering::Relaxed) > 0
+    }
+
+    /// Whether notifications are waiting for or were dropped on the client.
+    pub fn backpressured(&self) -> bool {
+        self.queued.load(Ordering::Relaxed) > 0 || self.dropped.load(Ordering::Relaxed) > 0
+    }
+}
+
+/// A notification on its way to the client.
+pub(super) struct PendingDelivery {
+    counters: Arc<DeliveryCounters>,
+    received: Instant,
+}
+
+impl PendingDelivery {
+    pub fn new(counters: Arc<DeliveryCounters>, received: Instant) -> Self {
+        Self { counters, received }
+    }
+
+    pub fn sent(self) {
+        let latency = u64::try_from(self.received.elapsed().as_micros()).unwrap_or(u64::MAX);
+        self.counters.sent.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .latency_micros
+            .fetch_add(latency, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct DeliveryStats {
+    pub client: ClientId,
+    pub key: String,
+    pub sent: u64,
+    pub dropped: u64,
+    pub errors: u64,
+    pub queued: usize,
+    /// Average of the notifications sent so far, in milliseconds.
+    pub average_latency_ms: f64,
+}
+
+#[derive(Default)]
+pub(super) struct Deliveries {
+    subscriptions: Mutex<BTreeMap<(ClientId, String), Arc<DeliveryCounters>>>,
+}
+
+impl Deliveries {
+    /// Lists the deliveries for the subscription of `client` to `key` until the returned
+    /// guard is dropped.
+    pub fn track(self: &Arc<Self>, client: ClientId, key: &ContractKey) -> TrackedDelivery {
+        let key = key.to_string();
+        let counters = Arc::new(DeliveryCounters::default());
+        self.subscriptions
+            .lock()
+            .insert((client, key.clone()), counters.clone());
+        TrackedDelivery {
+            deliveries: self.clone(),
+            subscription: (client, key),
+            counters,
+        }
+    }
+
+    pub fn list(&self) -> Vec<DeliveryStats> {
+        self.subscriptions
+            .lock()
+            .iter()
+            .map(|((client, key), counters)| {
+                let sent = counters.sent.load(Ordering::Relaxed);
+                let latency =
+                    Duration::from_micros(counters.latency_micros.load(Ordering::Relaxed));
+                DeliveryStats {
+                    client: *client,
+                    key: key.clone(),
+                    sent,
+                    dropped: counters.dropped.load(Ordering::Relaxed),
+                    errors: counters.errors.load(Ordering::Relaxed),
+                    queued: counters.queued.load(Ordering::Relaxed),
+                    average_latency_ms: if sent == 0 {
+                        0.0
+                    } else {
+                        latency.as_secs_f64() * 1000.0 / sent as f64
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+pub(super) struct TrackedDelivery {
+    deliveries: Arc<Deliveries>,
+    subscription: (ClientId, String),
+    counters: Arc<DeliveryCounters>,
+}
+
+impl TrackedDelivery {
+    pub fn counters(&self) -> &Arc<DeliveryCounters> {
+        &self.counters
+    }
+}
+
+impl Drop for TrackedDelivery {
+    fn drop(&mut self) {
+        let mut subscriptions = self.deliveries.subscriptions.lock();
+        // a later subscription of the client to the same contract replaces this one
+        if subscriptions
+            .get(&self.subscription)
+            .is_some_and(|counters| Arc::ptr_eq(counters, &self.counters))
+        {
+            subscriptions.remove(&self.subscription);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use freenet_stdlib::{
+        client_api::{ContractResponse, HostResponse},
+        prelude::{ContractInstanceId, State, UpdateData},
+    };
+    use tokio::sync::mpsc;
+
+    use super::super::listener::{PausePolicy, SubscriptionListener};
+    use super::*;
+    use crate::client_events::HostResult;
+
+    fn notification(key: ContractKey) -> HostResult {
+        Ok(HostResponse::ContractResponse(
+            ContractResponse::UpdateNotification {
+                key,
+                update: UpdateData::State(State::from(vec![1])),
+            },
+        ))
+    }
+
+    #[test]
+    fn stats_follow_deliveries() {
+        let deliveries = Arc::new(Deliveries::default());
+        let client = ClientId::next();
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut listener =
+            SubscriptionListener::new(key, rx).with_delivery(deliveries.track(client, &key));
+
+        for _ in 0..2 {
+            tx.send(notification(key)).unwrap();
+        }
+        for _ in 0..2 {
+            listener.try_next().unwrap().unwrap().unwrap();
+            let delivery = listener.pending_delivery().unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+            delivery.sent();
+        }
+        listener.pause(PausePolicy::Drop);
+        tx.send(notification(key)).unwrap();
+        assert!(listener.try_next().unwrap().is_none());
+        listener.pause(PausePolicy::Buffer);
+        for _ in 0..2 {
+            tx.send(notification(key)).unwrap();
+        }
+        assert!(listener.try_next().unwrap().is_none());
+
+        let stats = deliveries.list();
+        assert_eq!(stats.len(), 1);
+        let stats = &stats[0];
+        assert_eq!(
+            (stats.client, stats.key.as_str()),
+            (client, &*key.to_string())
+        );
+        assert_eq!((stats.sent, stats.dropped, stats.queued), (2, 1, 2));
+        assert!(stats.average_latency_ms >= 10.0, "{stats:?}");
+
+        drop(listener);
+        assert!(deliveries.list().is_empty());
+    }
+}
@@ -0,0 +1,275 @@
+//! Encryption of the binary messages of websocket connections with a session key of their own,
+//! for the clients which opt in, on top of the TLS the gateway may be served with.
+//!
+//! When enabled, the proxy holds an RSA key pair generated as it starts. A client gets its
+//! public key with the `encryptionKey` control frame and sends the session key it picked,
+//! encrypted with it, in a `sessionKey` frame. Once the frame is acknowledged every binary
+//! message, in either direction, is a nonce followed by the message sealed with
+//! XChaCha20-Poly1305 under the session key; text messages, control frames among them, go in
+//! the clear.
+//!
+//! Sending another `sessionKey` frame rotates the key. The rotation takes effect with its
+//! acknowledgement: the messages written before it are sealed with the previous key and those
+//! after with the new one. The client keeps sealing with the previous key until it gets the
+//! acknowledgement, which is taken until the first message sealed with the new one comes in.
+
+use std::sync::Arc;
+
+use axum::extract::ws::Message;
+use base64::Engine;
+use chacha20poly1305::{aead::Aead, Key, KeyInit, XChaCha20Poly1305, XNonce};
+use parking_lot::Mutex;
+use rand::RngCore;
+use rsa::{
+    pkcs8::{EncodePublicKey, LineEnding},
+    Oaep, RsaPrivateKey, RsaPublicKey,
+};
+use sha2::Sha256;
+
+use super::control::ControlResponse;
+
+const RSA_BITS: usize = 2048;
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 24;
+
+/// The key pair clients encrypt their session keys with.
+pub(super) struct SessionKeys {
+    private: RsaPrivateKey,
+    /// The public key in PEM, as handed to clients.
+    public: String,
+}
+
+impl SessionKeys {
+    pub fn generate() -> Self {
+        Self::with_bits(RSA_BITS)
+    }
+
+    fn with_bits(bits: usize) -> Self {
+        let private =
+            RsaPrivateKey::new(&mut rand::thread_rng(), bits).expect("failed to generate a key");
+        let public = RsaPublicKey::from(&private)
+            .to_public_key_pem(LineEnding::LF)
+            .expect("infallible encoding");
+        Self { private, public }
+    }
+
+    /// The session key sent by a client, in base64 and encrypted with the public key.
+    fn open(&self, sealed: &str) -> Result<XChaCha20Poly1305, String> {
+        let sealed = base64::engine::general_purpose::STANDARD
+            .decode(sealed)
+            .map_err(|err| format!("session key not in base64: {err}"))?;
+        let key = self
+            .private
+            .decrypt(Oaep::new::<Sha256>(), &sealed)
+            .map_err(|err| format!("failed to decrypt the session key: {err}"))?;
+        if key.len() != KEY_SIZE {
+            return Err(format!("session keys are {KEY_SIZE} bytes long"));
+        }
+        Ok(XChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+}
+
+/// What the messages of a connection are sealed with.
+#[derive(Clone, Default)]
+enum Sealing {
+    #[default]
+    Clear,
+    Key(XChaCha20Poly1305),
+}
+
+impl Sealing {
+    fn seal(&self, data: Vec<u8>) -> Result<Vec<u8>, axum::Error> {
+        let Sealing::Key(key) = self else {
+            return Ok(data);
+        };
+        let mut nonce = [0; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let sealed = key
+            .encrypt(XNonce::from_slice(&nonce), data.as_slice())
+            .map_err(|err| axum::Error::new(anyhow::anyhow!("failed to seal message: {err}")))?;
+        Ok([nonce.as_slice(), &sealed].concat())
+    }
+
+    fn open(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let Sealing::Key(key) = self else {
+            return Some(data.to_vec());
+        };
+        if data.len() < NONCE_SIZE {
+            return None;
+        }
+        let (nonce, sealed) = data.split_at(NONCE_SIZE);
+        key.decrypt(XNonce::from_slice(nonce), sealed).ok()
+    }
+}
+
+#[derive(Default)]
+struct Keys {
+    current: Sealing,
+    /// What the client may still seal with, until it seals with the current key.
+    previous: Option<Sealing>,
+    /// The key taking effect once the acknowledgement of its rotation is written.
+    rotation: Option<(u64, XChaCha20Poly1305)>,
+    rotations: u64,
+}
+
+/// The session key of a connection, shared by the messages read and those written.
+#[derive(Clone)]
+pub(super) struct FrameCipher {
+    session_keys: Arc<SessionKeys>,
+    keys: Arc<Mutex<Keys>>,
+}
+
+impl FrameCipher {
+    pub fn new(session_keys: Arc<SessionKeys>) -> Self {
+        Self {
+            session_keys,
+            keys: Arc::default(),
+        }
+    }
+
+    pub fn public_key(&self) -> &str {
+        &self.session_keys.public
+    }
+
+    /// Rotates to the session key sent by the client, answered with the acknowledgement the
+    /// key takes effect with.
+    pub fn rotate(&self, sealed: &str) -> Message {
+        let mut keys = self.keys.lock();
+        if keys.rotation.is_some() {
+            let cause = "the previous session key is not acknowledged yet".into();
+            return ControlResponse::Error { cause }.into_message();
+        }
+        let key = match self.session_keys.open(sealed) {
+            Ok(key) => key,
+            Err(cause) => return ControlResponse::Error { cause }.into_message(),
+        };
+        keys.rotations += 1;
+        keys.rotation = Some((keys.rotations, key));
+        ControlResponse::SessionKey {
+            rotation: keys.rotations,
+        }
+        .into_message()
+    }
+
+    /// Seals binary messages as they are written, switching to the rotated key once its
+    /// acknowledgement goes out.
+    pub fn seal(&self, msg: Message) -> Result<Message, axum::Error> {
+        let mut keys = self.keys.lock();
+        match msg {
+            Message::Binary(data) => keys.current.seal(data).map(Message::Binary),
+            Message::Text(text) => {
+                let pending = keys.rotation.as_ref().map(|(rotation, _)| *rotation);
+                if pending.is_some() && ControlResponse::acknowledged_rotation(&text) == pending {
+                    let (_, key) = keys.rotation.take().expect("rotation");
+                    let previous = std::mem::replace(&mut keys.current, Sealing::Key(key));
+                    keys.previous = Some(previous);
+                }
+                Ok(Message::Text(text))
+            }
+            other => Ok(other),
+        }
+    }
+
+    /// Opens a binary message read from the client.
+    pub fn open(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut keys = self.keys.lock();
+        if let Some(opened) = keys.current.open(data) {
+            // the client took the rotation
+            keys.previous = None;
+            return Ok(opened);
+        }
+        keys.previous
+            .as_ref()
+            .and_then(|previous| previous.open(data))
+            .ok_or_else(|| "message not sealed with the session key".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rsa::pkcs8::DecodePublicKey;
+
+    use super::*;
+
+    /// The client side of the connection.
+    struct Client {
+        public_key: RsaPublicKey,
+    }
+
+    impl Client {
+        fn session_key(&self, key: &[u8; KEY_SIZE]) -> String {
+            let sealed = self
+                .public_key
+                .encrypt(&mut rand::thread_rng(), Oaep::new::<Sha256>(), key)
+                .unwrap();
+            base64::engine::general_purpose::STANDARD.encode(sealed)
+        }
+    }
+
+    fn sealed_with(key: &[u8; KEY_SIZE], data: &[u8]) -> Vec<u8> {
+        let key = Sealing::Key(XChaCha20Poly1305::new(Key::from_slice(key)));
+        key.seal(data.to_vec()).unwrap()
+    }
+
+    fn opened_with(key: &[u8; KEY_SIZE], msg: Message) -> Option<Vec<u8>> {
+        let Message::Binary(data) = msg else {
+            panic!("expected a binary message");
+        };
+        Sealing::Key(XChaCha20Poly1305::new(Key::from_slice(key))).open(&data)
+    }
+
+    #[test]
+    fn rotated_key_used_once_acknowledged() {
+        let cipher = FrameCipher::new(Arc::new(SessionKeys::with_bits(1024)));
+        let client = Client {
+            public_key: RsaPublicKey::from_public_key_pem(cipher.public_key()).unwrap(),
+        };
+        let written = |data: &[u8]| cipher.seal(Message::Binary(data.to_vec())).unwrap();
+        let (first, second) = ([1; KEY_SIZE], [2; KEY_SIZE]);
+
+        // in the clear until the first key is acknowledged
+        let ack = cipher.rotate(&client.session_key(&first));
+        assert!(matches!(&ack, Message::Text(text) if text.contains(r#""rotation":1"#)));
+        assert_eq!(written(b"before"), Message::Binary(b"before".to_vec()));
+        cipher.seal(ack).unwrap();
+        assert_eq!(opened_with(&first, written(b"after")).unwrap(), b"after");
+        assert_eq!(cipher.open(b"in flight").unwrap(), b"in flight");
+        assert_eq!(
+            cipher.open(&sealed_with(&first, b"sealed")).unwrap(),
+            b"sealed"
+        );
+        assert!(cipher.open(b"in the clear").is_err());
+
+        let ack = cipher.rotate(&client.session_key(&second));
+        // a single rotation waits for its acknowledgement at a time
+        assert!(matches!(
+            cipher.rotate(&client.session_key(&second)),
+            Message::Text(text) if text.contains("error")
+        ));
+        assert_eq!(opened_with(&first, written(b"before")).unwrap(), b"before");
+        // only the acknowledgement of the pending rotation switches keys
+        let stale = ControlResponse::SessionKey { rotation: 1 }.into_message();
+        cipher.seal(stale).unwrap();
+        assert_eq!(opened_with(&first, written(b"before")).unwrap(), b"before");
+        cipher.seal(ack).unwrap();
+        let after = written(b"after");
+        assert!(opened_with(&first, after.clone()).is_none());
+        assert_eq!(opened_with(&second, after).unwrap(), b"after");
+        // the client sealed with the first key until it got the acknowledgement
+        assert_eq!(cipher.open(&sealed_with(&first, b"old")).unwrap(), b"old");
+        assert_eq!(cipher.open(&sealed_with(&second, b"new")).unwrap(), b"new");
+        assert!(cipher.open(&sealed_with(&first, b"old")).is_err());
+    }
+
+    #[test]
+    fn invalid_session_key_rejected() {
+        let cipher = FrameCipher::new(Arc::new(SessionKeys::with_bits(1024)));
+        let Message::Text(response) = cipher.rotate("not a key") else {
+            panic!("expected a text message");
+        };
+        assert!(response.contains("base64"), "{response}");
+        // nothing changes
+        let msg = cipher.seal(Message::Binary(vec![1])).unwrap();
+        assert_eq!(msg, Message::Binary(vec![1]));
+    }
+}
@@ -0,0 +1,107 @@
+//! Faults injected into the responses to websocket clients, so client libraries can be tested
+//! against connections dropping, slow responses and failing requests.
+//!
+//! Only built with the `fault-injection` feature, the faults are drawn from a generator seeded
+//! as configured so a failing test run can be reproduced.
+
+use std::time::Duration;
+
+use freenet_stdlib::client_api::ErrorKind;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{client_events::HostResult, config::FaultInjection};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Fault {
+    Disconnect,
+    Delay(Duration),
+    Error,
+}
+
+pub(super) struct Faults {
+    config: FaultInjection,
+    rng: StdRng,
+}
+
+impl Faults {
+    pub fn new(config: FaultInjection) -> Self {
+        Self {
+            config,
+            rng: StdRng::seed_from_u64(config.seed),
+        }
+    }
+
+    /// The fault to inject in place of the next response, if any.
+    pub fn next(&mut self) -> Option<Fault> {
+        let FaultInjection {
+            disconnect_rate,
+            delay_rate,
+            error_rate,
+            delay_ms,
+            ..
+        } = self.config;
+        let roll: f64 = self.rng.gen();
+        if roll < disconnect_rate {
+            Some(Fault::Disconnect)
+        } else if roll < disconnect_rate + delay_rate {
+            Some(Fault::Delay(Duration::from_millis(delay_ms)))
+        } else if roll < disconnect_rate + delay_rate + error_rate {
+            Some(Fault::Error)
+        } else {
+            None
+        }
+    }
+
+    /// Passes on the response to be sent to the client unless the connection is to be dropped.
+    pub async fn inject(&mut self, result: HostResult) -> Option<HostResult> {
+        match self.next() {
+            None => Some(result),
+            Some(Fault::Disconnect) => {
+                tracing::debug!("injected fault: dropping connection");
+                None
+            }
+            Some(Fault::Delay(delay)) => {
+                tokio::time::sleep(delay).await;
+                Some(result)
+            }
+            Some(Fault::Error) => Some(Err(ErrorKind::OperationError {
+                cause: "injected fault".into(),
+            }
+            .into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn faults_injected_at_configured_rates() {
+        const DRAWS: usize = 10_000;
+        let config = FaultInjection {
+            seed: 7,
+            disconnect_rate: 0.05,
+            delay_rate: 0.1,
+            delay_ms: 20,
+            error_rate: 0.2,
+        };
+        let faults: Vec<_> = {
+            let mut faults = Faults::new(config);
+            (0..DRAWS).map(|_| faults.next()).collect()
+        };
+        let rate = |fault: Fault| {
+            faults.iter().filter(|f| **f == Some(fault)).count() as f64 / DRAWS as f64
+        };
+        assert!((rate(Fault::Disconnect) - 0.05).abs() < 0.01);
+        assert!((rate(Fault::Delay(Duration::from_millis(20))) - 0.1).abs() < 0.01);
+        assert!((rate(Fault::Error) - 0.2).abs() < 0.01);
+
+        // the same seed goes through the same faults
+        let mut again = Faults::new(config);
+        assert!(faults.iter().all(|fault| *fault == again.next()));
+
+        let mut none = Faults::new(FaultInjection::default());
+        assert!((0..DRAWS).all(|_| none.next().is_none()));
+    }
+}
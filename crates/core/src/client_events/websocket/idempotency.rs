@@ -0,0 +1,188 @@
+//! Results of the writes clients made with an idempotency key, so a client retrying a write,
+//! e.g. after losing its connection before getting the response, is answered with the result
+//! of the write which went through instead of the node applying it again.
+//!
+//! A client sends a [`ControlFrame::IdempotencyKey`](super::control::ControlFrame::IdempotencyKey)
+//! frame before a put or an update. Keys are scoped to the contract written to, the same key
+//! used with writes to different contracts stands for unrelated writes, each with its own
+//! result. Only the writes which succeeded are kept, so one which failed can be retried as is.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use freenet_stdlib::{
+    client_api::{ClientError, ClientRequest, ContractRequest, ContractResponse, HostResponse},
+    prelude::ContractInstanceId,
+};
+use parking_lot::Mutex;
+
+use super::ClientId;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Scope {
+    contract: ContractInstanceId,
+    key: String,
+}
+
+#[derive(Default)]
+struct Writes {
+    /// Writes made with a key waiting for their result, by client and contract written to.
+    pending: HashMap<(ClientId, ContractInstanceId), (Instant, String)>,
+    results: HashMap<Scope, (Instant, ContractResponse)>,
+}
+
+pub(super) struct IdempotentWrites {
+    /// How long results are kept for, also how long a write is waited on for its result.
+    ttl: Duration,
+    writes: Mutex<Writes>,
+}
+
+/// The contract the request writes to, if it is a write.
+pub(super) fn written_contract(req: &ClientRequest) -> Option<ContractInstanceId> {
+    match req {
+        ClientRequest::ContractOp(ContractRequest::Put { contract, .. }) => {
+            Some(*contract.key().id())
+        }
+        ClientRequest::ContractOp(ContractRequest::Update { key, .. }) => Some(*key.id()),
+        _ => None,
+    }
+}
+
+impl IdempotentWrites {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            writes: Mutex::new(Writes::default()),
+        }
+    }
+
+    /// The result of the write made to the contract with the key, if it went through.
+    pub fn result(&self, contract: ContractInstanceId, key: &str) -> Option<HostResponse> {
+        let scope = Scope {
+            contract,
+            key: key.to_owned(),
+        };
+        let writes = &mut *self.writes.lock();
+        let (at, result) = writes.results.get(&scope)?;
+        if at.elapsed() < self.ttl {
+            return Some(result.clone().into());
+        }
+        writes.results.remove(&scope);
+        None
+    }
+
+    /// The client is writing to the contract, the result is kept for the key if there is one.
+    pub fn write(&self, client: ClientId, contract: ContractInstanceId, key: Option<String>) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let writes = &mut *self.writes.lock();
+        match key {
+            Some(key) => {
+                let now = Instant::now();
+                writes
+                    .pending
+                    .retain(|_, (at, _)| now.duration_since(*at) < self.ttl);
+                writes.pending.insert((client, contract), (now, key));
+            }
+            // a later write without a key, the result which comes back may be its own
+            None => {
+                writes.pending.remove(&(client, contract));
+            }
+        }
+    }
+
+    /// Keeps the result of the write made by the client it is for, if made with a key.
+    pub fn record(&self, client: ClientId, result: &Result<HostResponse, ClientError>) {
+        let Ok(HostResponse::ContractResponse(
+            response @ (ContractResponse::PutResponse { key }
+            | ContractResponse::UpdateResponse { key, .. }),
+        )) = result
+        else {
+            return;
+        };
+        let writes = &mut *self.writes.lock();
+        let Some((_, idempotency_key)) = writes.pending.remove(&(client, *key.id())) else {
+            return;
+        };
+        let now = Instant::now();
+        writes
+            .results
+            .retain(|_, (at, _)| now.duration_since(*at) < self.ttl);
+        let scope = Scope {
+            contract: *key.id(),
+            key: idempotency_key,
+        };
+        writes.results.insert(scope, (now, response.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use freenet_stdlib::{
+        client_api::ErrorKind,
+        prelude::{ContractKey, StateSummary},
+    };
+
+    use super::*;
+
+    fn updated(key: ContractKey, summary: u8) -> Result<HostResponse, ClientError> {
+        Ok(ContractResponse::UpdateResponse {
+            key,
+            summary: StateSummary::from(vec![summary]),
+        }
+        .into())
+    }
+
+    fn summary(result: Option<HostResponse>) -> Option<Vec<u8>> {
+        match result? {
+            HostResponse::ContractResponse(ContractResponse::UpdateResponse {
+                summary, ..
+            }) => Some(summary.into_bytes()),
+            other => panic!("unexpected result {other}"),
+        }
+    }
+
+    #[test]
+    fn same_key_on_two_contracts() {
+        let writes = IdempotentWrites::new(Duration::from_secs(60));
+        let client = ClientId::next();
+        let (first, second) = (
+            ContractKey::from(ContractInstanceId::new([1; 32])),
+            ContractKey::from(ContractInstanceId::new([2; 32])),
+        );
+
+        writes.write(client, *first.id(), Some("retry-me".into()));
+        writes.record(client, &updated(first, 1));
+        assert_eq!(
+            summary(writes.result(*first.id(), "retry-me")),
+            Some(vec![1])
+        );
+        // the key was never used with the second contract
+        assert!(writes.result(*second.id(), "retry-me").is_none());
+
+        writes.write(client, *second.id(), Some("retry-me".into()));
+        writes.record(client, &updated(second, 2));
+        assert_eq!(
+            summary(writes.result(*first.id(), "retry-me")),
+            Some(vec![1])
+        );
+        assert_eq!(
+            summary(writes.result(*second.id(), "retry-me")),
+            Some(vec![2])
+        );
+
+        // writes without a key, or which failed, are not kept
+        writes.write(client, *first.id(), Some("failed".into()));
+        writes.record(client, &Err(ErrorKind::NodeUnavailable.into()));
+        writes.write(client, *first.id(), None);
+        writes.record(client, &updated(first, 3));
+        assert!(writes.result(*first.id(), "failed").is_none());
+        assert_eq!(
+            summary(writes.result(*first.id(), "retry-me")),
+            Some(vec![1])
+        );
+    }
+}
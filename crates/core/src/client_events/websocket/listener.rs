@@ -0,0 +1,1148 @@
+//! Delivery of contract update notifications to a single websocket client.
+//!
+//! Clients which send the [`NOTIFICATION_VERSIONS_HEADER`] when connecting get every
+//! notification preceded by a [`ControlResponse::Notified`](super::control::ControlResponse)
+//! text message with the version of the contract the update brings it to and the version a
+//! delta was made against, so a client applying deltas can tell when it missed any and ask
+//! for a replay.
+//!
+//! Those which send the [`SUBSCRIPTION_ACKS_HEADER`] get every subscription they set up
+//! followed by a [`ControlResponse::Acknowledged`](super::control::ControlResponse) text
+//! message, with the id of the subscription on the connection, the version of the contract it
+//! starts from and how its notifications are sent.
+//!
+//! The notifications of subscriptions to contracts with a coalescing window, see
+//! [`NotificationCoalescing`], are held from the first one received until the window ends,
+//! trading latency for fewer messages to the client. A whole state received meanwhile
+//! supersedes everything held before it, the rest is sent in order once the window ends.
+//! Subscriptions the client asked an [`Aggregation`] for aren't coalesced, their numeric updates
+//! are reduced to a single notification per window instead.
+//!
+//! While the client doesn't read its notifications as fast as they come, they pile up in the
+//! subscriptions they are for, up to the backlog of each; past it the [`SlowConsumerPolicy`]
+//! of the subscription tells which are discarded, or whether the client is disconnected. Those
+//! held by all the subscriptions of the node together are accounted in its
+//! [`NotificationMemory`](super::memory::NotificationMemory), which may stop subscriptions from
+//! taking in more or shed them.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use freenet_stdlib::{
+    client_api::{ContractResponse, ErrorKind, HostResponse},
+    prelude::{ContractInstanceId, ContractKey, UpdateData},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use super::{
+    aggregation::{Aggregation, Aggregator},
+    delivery::{DeliveryCounters, PendingDelivery, TrackedDelivery},
+    memory::AccountedBuffer,
+    pending::response_size,
+    replay::UpdateLog,
+    tenant::TenantSubscription,
+};
+use crate::{
+    client_events::HostResult,
+    config::{NotificationCoalescing, SlowConsumerPolicy, SlowConsumers},
+};
+
+/// Maximum number of notifications retained for a subscription paused with
+/// [`PausePolicy::Buffer`]; once reached the oldest notification is discarded.
+pub(super) const MAX_PAUSED_NOTIFICATIONS: usize = 256;
+
+pub(super) const NOTIFICATION_VERSIONS_HEADER: &str = "x-notification-versions";
+
+pub(super) const SUBSCRIPTION_ACKS_HEADER: &str = "x-subscription-acks";
+
+/// How the updates notified for a subscription are to be applied by the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum NotificationMode {
+    /// Every notification comes with the versions it goes between, deltas are applied in order.
+    Delta,
+    /// Notifications come without versions, each is to be applied as a whole.
+    Full,
+}
+
+impl NotificationMode {
+    pub fn of(notification_versions: bool) -> Self {
+        if notification_versions {
+            Self::Delta
+        } else {
+            Self::Full
+        }
+    }
+}
+
+/// What happens to the notifications received while a subscription is paused.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum PausePolicy {
+    /// Keep the most recent notifications and deliver them on resume.
+    #[default]
+    Buffer,
+    /// Discard any notification received until resumed.
+    Drop,
+}
+
+/// Coalescing windows of the contracts, as configured.
+#[derive(Default)]
+pub(super) struct CoalescingWindows {
+    default: Duration,
+    contracts: HashMap<ContractInstanceId, Duration>,
+}
+
+impl CoalescingWindows {
+    pub fn new(config: &NotificationCoalescing) -> Self {
+        let contracts = config
+            .contracts
+            .iter()
+            .filter_map(|(contract, window)| match contract.parse::<ContractInstanceId>() {
+                Ok(contract) => Some((contract, Duration::from_millis(*window))),
+                Err(err) => {
+                    tracing::warn!(%contract, %err, "ignoring the coalescing window of an invalid contract id");
+                    None
+                }
+            })
+            .collect();
+        Self {
+            default: Duration::from_millis(config.default_window_ms),
+            contracts,
+        }
+    }
+
+    pub fn of(&self, key: &ContractKey) -> Duration {
+        self.contracts
+            .get(key.id())
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// How many notifications a subscription keeps while the client falls behind, and what is done
+/// with those past them.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Backlog {
+    policy: SlowConsumerPolicy,
+    limit: usize,
+    block_timeout: Duration,
+}
+
+impl Backlog {
+    /// The backlog of a subscription the client picked `policy` for, if any.
+    pub fn new(config: &SlowConsumers, policy: Option<SlowConsumerPolicy>) -> Self {
+        Self {
+            policy: policy.unwrap_or(config.default_policy),
+            limit: config.max_backlog,
+            block_timeout: Duration::from_millis(config.block_timeout_ms),
+        }
+    }
+}
+
+impl Default for Backlog {
+    fn default() -> Self {
+        Self::new(&SlowConsumers::default(), None)
+    }
+}
+
+/// The client fell further behind a subscription than its backlog allows.
+#[derive(Debug, thiserror::Error)]
+#[error("client too slow reading the notifications for {key} ({policy:?})")]
+pub(super) struct TooSlow {
+    key: ContractKey,
+    policy: SlowConsumerPolicy,
+}
+
+/// Where a notification leaves the contract, as numbered by the [`UpdateLog`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Causality {
+    /// Version of the contract once the update is applied.
+    pub version: u64,
+    /// Version the update builds on, none for whole states.
+    pub base: Option<u64>,
+}
+
+/// A notification pending delivery along with when it was received.
+pub(super) type Pending = (HostResult, Option<Causality>, Instant);
+
+/// A subscription to a contract held by a websocket connection.
+pub(super) struct SubscriptionListener {
+    pub key: ContractKey,
+    callback: mpsc::UnboundedReceiver<HostResult>,
+    paused: Option<PausePolicy>,
+    priority: u8,
+    buffered: VecDeque<Pending>,
+    coalescing: Duration,
+    /// Notifications held until the coalescing window ends, along with when it started.
+    held: Option<(Instant, Vec<Pending>)>,
+    aggregator: Option<Aggregator>,
+    backlog: Backlog,
+    /// Since the backlog was exceeded, for the [`SlowConsumerPolicy::Block`] policy.
+    over_backlog: Option<Instant>,
+    update_log: Option<Arc<UpdateLog>>,
+    /// Version of the last update received, as numbered by the `update_log`.
+    seen: u64,
+    delivery: Option<TrackedDelivery>,
+    /// Share of the notifications held, buffered or coalesced, in the memory of the node.
+    memory: Option<AccountedBuffer>,
+    /// Held against the subscriptions of the tenant of the client while the listener lives.
+    _tenant: Option<TenantSubscription>,
+    /// Of the notification last returned.
+    causality: Option<Causality>,
+    received: Option<Instant>,
+}
+
+impl SubscriptionListener {
+    pub fn new(key: ContractKey, callback: mpsc::UnboundedReceiver<HostResult>) -> Self {
+        Self {
+            key,
+            callback,
+            paused: None,
+            priority: 0,
+            buffered: VecDeque::new(),
+            coalescing: Duration::ZERO,
+            held: None,
+            aggregator: None,
+            backlog: Backlog::default(),
+            over_backlog: None,
+            update_log: None,
+            seen: 0,
+            delivery: None,
+            memory: None,
+            _tenant: None,
+            causality: None,
+            received: None,
+        }
+    }
+
+    /// Records every update received by this subscription in `log`.
+    pub fn with_update_log(mut self, log: Arc<UpdateLog>) -> Self {
+        self.seen = log.version(&self.key);
+        self.update_log = Some(log);
+        self
+    }
+
+    /// Holds the notifications received for `window` after the first of them to coalesce them.
+    pub fn with_coalescing(mut self, window: Duration) -> Self {
+        self.coalescing = window;
+        self
+    }
+
+    pub fn with_aggregation(mut self, aggregation: Option<Aggregation>) -> Self {
+        self.aggregator = aggregation.map(Aggregator::new);
+        self
+    }
+
+    /// Aggregates the numeric updates received from now on, those of the window in progress
+    /// are sent as aggregated until now.
+    pub fn set_aggregation(&mut self, aggregation: Aggregation) {
+        self.release_aggregated();
+        self.aggregator = Some(Aggregator::new(aggregation));
+    }
+
+    pub fn with_backlog(mut self, backlog: Backlog) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    pub fn set_backlog(&mut self, backlog: Backlog) {
+        self.backlog = backlog;
+        self.over_backlog = None;
+    }
+
+    /// Counts the notifications delivered by this subscription in `delivery`.
+    pub fn with_delivery(mut self, delivery: TrackedDelivery) -> Self {
+        self.delivery = Some(delivery);
+        self
+    }
+
+    /// Accounts the notifications held by this subscription in `memory`.
+    pub fn with_memory(mut self, memory: AccountedBuffer) -> Self {
+        memory.set_priority(self.priority);
+        self.memory = Some(memory);
+        self
+    }
+
+    pub fn with_tenant(mut self, tenant: Option<TenantSubscription>) -> Self {
+        self._tenant = tenant;
+        self
+    }
+
+    /// Whether the subscription was shed to bring the memory held by all of them under the
+    /// cap, once it has told the client so it is to be dropped.
+    pub fn is_shed(&self) -> bool {
+        self.memory.as_ref().is_some_and(AccountedBuffer::is_shed)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.is_some()
+    }
+
+    pub fn pause(&mut self, policy: PausePolicy) {
+        self.release_held();
+        self.release_aggregated();
+        self.paused = Some(policy);
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    pub fn set_priority(&mut self, priority: u8) {
+        self.priority = priority;
+        if let Some(memory) = &self.memory {
+            memory.set_priority(priority);
+        }
+    }
+
+    /// Resumes delivery, returns the number of buffered notifications pending delivery.
+    pub fn resume(&mut self) -> usize {
+        self.paused = None;
+        self.buffered.len()
+    }
+
+    /// Queues previously missed updates, along with where each leaves the contract, for
+    /// delivery ahead of any live notification.
+    ///
+    /// Notifications buffered while paused are already part of the replayed updates, so they
+    /// are superseded.
+    pub fn replay(&mut self, updates: Vec<(Causality, UpdateData<'static>)>) {
+        let key = self.key;
+        let received = Instant::now();
+        for superseded in std::mem::take(&mut self.buffered) {
+            self.released(&superseded);
+        }
+        self.buffered = updates
+            .into_iter()
+            .map(|(causality, update)| {
+                let notification = ContractResponse::UpdateNotification { key, update };
+                (Ok(notification.into()), Some(causality), received)
+            })
+            .collect();
+        for replayed in &self.buffered {
+            self.held_in_memory(replayed);
+        }
+    }
+
+    /// Where the notification last returned by [`Self::try_next`] leaves the contract, if
+    /// the updates it gets are recorded.
+    pub fn causality(&self) -> Option<Causality> {
+        self.causality
+    }
+
+    pub fn delivery(&self) -> Option<&DeliveryCounters> {
+        self.delivery
+            .as_ref()
+            .map(|delivery| &**delivery.counters())
+    }
+
+    /// The notification last returned by [`Self::try_next`], to be accounted once sent.
+    pub fn pending_delivery(&self) -> Option<PendingDelivery> {
+        let delivery = self.delivery.as_ref()?;
+        Some(PendingDelivery::new(
+            delivery.counters().clone(),
+            self.received?,
+        ))
+    }
+
+    /// Returns the next notification to be sent to the client, if any.
+    ///
+    /// While paused the underlying channel is still drained so the node side never
+    /// piles up notifications for this subscription, unless the memory of the node held by
+    /// subscriptions is at its cap.
+    ///
+    /// Once shed, whatever it holds is discarded and the client is sent an error instead.
+    pub fn try_next(&mut self) -> Result<Option<HostResult>, mpsc::error::TryRecvError> {
+        if self.is_shed() {
+            self.discard_held();
+            self.causality = None;
+            self.received = Some(Instant::now());
+            let cause = format!(
+                "subscription to {} ended, the node holds too many notifications",
+                self.key
+            );
+            return Ok(Some(Err(ErrorKind::OperationError {
+                cause: cause.into(),
+            }
+            .into())));
+        }
+        let next = self.next_notification();
+        if self.queued() <= self.backlog.limit {
+            self.over_backlog = None;
+        }
+        self.set_queued();
+        next
+    }
+
+    /// Keeps the notifications waiting with the node while the memory cap is reached within
+    /// the backlog of the subscription, along with those it buffers: past it they are dropped
+    /// or the client is disconnected, as for those buffered.
+    pub fn bound_waiting(&mut self) -> Result<(), TooSlow> {
+        let Backlog {
+            policy,
+            limit,
+            block_timeout,
+        } = self.backlog;
+        if self.has_room() || self.is_shed() {
+            return Ok(());
+        }
+        while self.queued() > limit {
+            match policy {
+                SlowConsumerPolicy::DropOldest if !self.buffered.is_empty() => {
+                    if let Some(oldest) = self.buffered.pop_front() {
+                        self.released(&oldest);
+                    }
+                    self.dropped();
+                }
+                SlowConsumerPolicy::DropOldest | SlowConsumerPolicy::DropNewest => {
+                    if self.callback.try_recv().is_err() {
+                        break;
+                    }
+                    self.dropped();
+                }
+                SlowConsumerPolicy::Disconnect => {
+                    return Err(TooSlow {
+                        key: self.key,
+                        policy,
+                    })
+                }
+                SlowConsumerPolicy::Block => {
+                    let since = *self.over_backlog.get_or_insert_with(Instant::now);
+                    if since.elapsed() >= block_timeout {
+                        return Err(TooSlow {
+                            key: self.key,
+                            policy,
+                        });
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes in the notifications received while the client can't be sent any more, keeping
+    /// those the backlog of the subscription allows; fails once the client is to be
+    /// disconnected.
+    pub fn absorb(&mut self) -> Result<(), TooSlow> {
+        let Backlog {
+            policy,
+            limit,
+            block_timeout,
+        } = self.backlog;
+        if self.is_shed() {
+            self.discard_held();
+            return Ok(());
+        }
+        // past the memory cap what comes waits with the node
+        while self.has_room() {
+            let Ok(notification) = self.callback.try_recv() else {
+                break;
+            };
+            let Some(pending) = self.receive(notification) else {
+                continue;
+            };
+            if self.buffered.len() >= limit {
+                match policy {
+                    SlowConsumerPolicy::DropOldest => {
+                        if let Some(oldest) = self.buffered.pop_front() {
+                            self.released(&oldest);
+                        }
+                        self.dropped();
+                    }
+                    SlowConsumerPolicy::DropNewest => {
+                        self.dropped();
+                        continue;
+                    }
+                    SlowConsumerPolicy::Disconnect => {
+                        return Err(TooSlow {
+                            key: self.key,
+                            policy,
+                        })
+                    }
+                    SlowConsumerPolicy::Block => {}
+                }
+            }
+            self.held_in_memory(&pending);
+            self.buffered.push_back(pending);
+        }
+        self.bound_waiting()?;
+        self.set_queued();
+        if self.queued() <= limit {
+            self.over_backlog = None;
+            return Ok(());
+        }
+        let since = *self.over_backlog.get_or_insert_with(Instant::now);
+        if since.elapsed() >= block_timeout {
+            return Err(TooSlow {
+                key: self.key,
+                policy,
+            });
+        }
+        Ok(())
+    }
+
+    /// Notifications counted against the backlog, those waiting with the node too while the
+    /// memory cap is reached.
+    fn queued(&self) -> usize {
+        let waiting = if self.has_room() {
+            0
+        } else {
+            self.callback.len()
+        };
+        self.buffered.len() + waiting
+    }
+
+    fn set_queued(&self) {
+        if let Some(delivery) = &self.delivery {
+            delivery
+                .counters()
+                .set_queued(self.buffered.len() + self.held_len() + self.callback.len());
+        }
+    }
+
+    fn next_notification(&mut self) -> Result<Option<HostResult>, mpsc::error::TryRecvError> {
+        if self.paused.is_none() {
+            if let Some(pending) = self.buffered.pop_front() {
+                self.released(&pending);
+                let (notification, causality, received) = pending;
+                self.causality = causality;
+                self.received = Some(received);
+                return Ok(Some(notification));
+            }
+        }
+        loop {
+            // those paused or coalesced would be held, past the memory cap they wait with the node
+            let holds = self.paused.is_some() || !self.coalescing.is_zero();
+            if holds && !self.has_room() {
+                return Ok(None);
+            }
+            match self.callback.try_recv() {
+                Ok(notification) => {
+                    if let Some((notification, causality, received)) = self.receive(notification) {
+                        self.causality = causality;
+                        self.received = Some(received);
+                        return Ok(Some(notification));
+                    }
+                }
+                Err(err) => {
+                    let window_ended = matches!(
+                        self.held,
+                        Some((since, _)) if since.elapsed() >= self.coalescing
+                    );
+                    // whatever is held still goes out before the subscription ends
+                    let disconnected = err == mpsc::error::TryRecvError::Disconnected;
+                    let aggregation_ended = self
+                        .aggregator
+                        .as_ref()
+                        .is_some_and(Aggregator::window_ended);
+                    if self.paused.is_none()
+                        && (aggregation_ended || disconnected)
+                        && self.release_aggregated()
+                    {
+                        return self.next_notification();
+                    }
+                    if self.paused.is_none()
+                        && (window_ended || disconnected)
+                        && self.release_held()
+                    {
+                        return self.next_notification();
+                    }
+                    return match err {
+                        mpsc::error::TryRecvError::Empty => Ok(None),
+                        err => Err(err),
+                    };
+                }
+            }
+        }
+    }
+
+    /// Accounts for a notification just received, returning it unless held or buffered
+    /// while paused.
+    fn receive(&mut self, notification: HostResult) -> Option<Pending> {
+        let received = Instant::now();
+        if let (Err(_), Some(delivery)) = (&notification, &self.delivery) {
+            delivery.counters().errored();
+        }
+        let causality = match (&self.update_log, &notification) {
+            (
+                Some(log),
+                Ok(HostResponse::ContractResponse(ContractResponse::UpdateNotification {
+                    key,
+                    update,
+                })),
+            ) => Some(log.record(key, &mut self.seen, update)),
+            _ => None,
+        };
+        // tracked while paused too, the updates buffered meanwhile change the value
+        if let Some(aggregator) = &mut self.aggregator {
+            if let (None, Some(value)) = (self.paused, aggregator.value(&notification)) {
+                aggregator.add(value, causality, received);
+                return None;
+            }
+        }
+        match self.paused {
+            None if !self.coalescing.is_zero() => {
+                self.hold((notification, causality, received));
+            }
+            None => return Some((notification, causality, received)),
+            Some(PausePolicy::Buffer) => {
+                if self.buffered.len() == MAX_PAUSED_NOTIFICATIONS {
+                    if let Some(oldest) = self.buffered.pop_front() {
+                        self.released(&oldest);
+                    }
+                    self.dropped();
+                }
+                let pending = (notification, causality, received);
+                self.held_in_memory(&pending);
+                self.buffered.push_back(pending);
+            }
+            Some(PausePolicy::Drop) => self.dropped(),
+        }
+        None
+    }
+
+    fn hold(&mut self, notification: Pending) {
+        self.held_in_memory(&notification);
+        let whole_state = matches!(
+            &notification.0,
+            Ok(HostResponse::ContractResponse(
+                ContractResponse::UpdateNotification {
+                    update: UpdateData::State(_),
+                    ..
+                }
+            ))
+        );
+        if whole_state {
+            let superseded = self.held.take().map(|(_, held)| held).unwrap_or_default();
+            for superseded in &superseded {
+                self.released(superseded);
+            }
+        }
+        let (_, held) = self
+            .held
+            .get_or_insert_with(|| (notification.2, Vec::new()));
+        held.push(notification);
+    }
+
+    fn held_len(&self) -> usize {
+        let aggregated = self.aggregator.as_ref().is_some_and(Aggregator::is_open);
+        self.held.as_ref().map_or(0, |(_, held)| held.len()) + usize::from(aggregated)
+    }
+
+    /// Queues the held notifications for delivery, false if there were none.
+    fn release_held(&mut self) -> bool {
+        match self.held.take() {
+            Some((_, held)) if !held.is_empty() => {
+                self.buffered.extend(held);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Queues the notification of the aggregation window in progress for delivery, false if
+    /// there was none.
+    fn release_aggregated(&mut self) -> bool {
+        let key = self.key;
+        match self
+            .aggregator
+            .as_mut()
+            .and_then(|aggregator| aggregator.take(key))
+        {
+            Some(aggregated) => {
+                self.held_in_memory(&aggregated);
+                self.buffered.push_back(aggregated);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops everything the subscription holds, once shed, and stops the node from notifying
+    /// it any further.
+    fn discard_held(&mut self) {
+        self.callback.close();
+        while self.callback.try_recv().is_ok() {
+            self.dropped();
+        }
+        let held = self.held.take().map_or(0, |(_, held)| held.len());
+        let key = self.key;
+        let aggregated = self
+            .aggregator
+            .as_mut()
+            .and_then(|aggregator| aggregator.take(key));
+        for _ in 0..self.buffered.len() + held + usize::from(aggregated.is_some()) {
+            self.dropped();
+        }
+        self.buffered.clear();
+        if let Some(memory) = &self.memory {
+            memory.clear();
+        }
+    }
+
+    fn has_room(&self) -> bool {
+        match &self.memory {
+            Some(memory) => memory.has_room(),
+            None => true,
+        }
+    }
+
+    fn held_in_memory(&self, pending: &Pending) {
+        if let Some(memory) = &self.memory {
+            memory.hold(response_size(&pending.0));
+        }
+    }
+
+    fn released(&self, pending: &Pending) {
+        if let Some(memory) = &self.memory {
+            memory.release(response_size(&pending.0));
+        }
+    }
+
+    fn dropped(&self) {
+        if let Some(delivery) = &self.delivery {
+            delivery.counters().dropped();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use freenet_stdlib::prelude::*;
+
+    use super::*;
+
+    fn notification(key: ContractKey, version: u8) -> HostResult {
+        Ok(HostResponse::ContractResponse(
+            ContractResponse::UpdateNotification {
+                key,
+                update: UpdateData::State(State::from(vec![version])),
+            },
+        ))
+    }
+
+    fn version(result: HostResult) -> u8 {
+        match result {
+            Ok(HostResponse::ContractResponse(ContractResponse::UpdateNotification {
+                update: UpdateData::State(state),
+                ..
+            })) => state.as_ref()[0],
+            other => panic!("unexpected notification: {other:?}"),
+        }
+    }
+
+    fn drain(listener: &mut SubscriptionListener) -> Vec<u8> {
+        std::iter::from_fn(|| listener.try_next().unwrap())
+            .map(version)
+            .collect()
+    }
+
+    #[test]
+    fn pause_buffers_until_resumed() {
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut listener = SubscriptionListener::new(key, rx);
+
+        tx.send(notification(key, 0)).unwrap();
+        assert_eq!(drain(&mut listener), vec![0]);
+
+        listener.pause(PausePolicy::Buffer);
+        for v in 1..=3 {
+            tx.send(notification(key, v)).unwrap();
+        }
+        assert!(listener.try_next().unwrap().is_none());
+
+        assert_eq!(listener.resume(), 3);
+        tx.send(notification(key, 4)).unwrap();
+        assert_eq!(drain(&mut listener), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn pause_drops_until_resumed() {
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut listener = SubscriptionListener::new(key, rx);
+
+        listener.pause(PausePolicy::Drop);
+        for v in 0..3 {
+            tx.send(notification(key, v)).unwrap();
+        }
+        assert!(listener.try_next().unwrap().is_none());
+
+        assert_eq!(listener.resume(), 0);
+        tx.send(notification(key, 3)).unwrap();
+        assert_eq!(drain(&mut listener), vec![3]);
+    }
+
+    #[test]
+    fn paused_buffer_is_bounded() {
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut listener = SubscriptionListener::new(key, rx);
+
+        listener.pause(PausePolicy::Buffer);
+        for v in 0..MAX_PAUSED_NOTIFICATIONS + 4 {
+            tx.send(notification(key, v as u8)).unwrap();
+        }
+        assert!(listener.try_next().unwrap().is_none());
+
+        assert_eq!(listener.resume(), MAX_PAUSED_NOTIFICATIONS);
+        let delivered = drain(&mut listener);
+        assert_eq!(delivered.len(), MAX_PAUSED_NOTIFICATIONS);
+        assert_eq!(delivered[0], 4);
+    }
+
+    #[test]
+    fn deltas_carry_causality() {
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let (tx, rx) = mpsc::unbounded_channel();
+        let log = Arc::new(UpdateLog::default());
+        let mut listener = SubscriptionListener::new(key, rx).with_update_log(log.clone());
+        let delta = |n: u8| {
+            Ok(ContractResponse::UpdateNotification {
+                key,
+                update: UpdateData::Delta(StateDelta::from(vec![n])),
+            }
+            .into())
+        };
+
+        tx.send(notification(key, 0)).unwrap();
+        for n in 1..=3 {
+            tx.send(delta(n)).unwrap();
+        }
+        let mut causality = vec![];
+        while listener.try_next().unwrap().is_some() {
+            causality.push(listener.causality().unwrap());
+        }
+        assert_eq!(
+            causality,
+            vec![
+                Causality {
+                    version: 1,
+                    base: None
+                },
+                Causality {
+                    version: 2,
+                    base: Some(1)
+                },
+                Causality {
+                    version: 3,
+                    base: Some(2)
+                },
+                Causality {
+                    version: 4,
+                    base: Some(3)
+                },
+            ]
+        );
+
+        // dropping notifications while paused leaves a gap the client can see
+        listener.pause(PausePolicy::Drop);
+        tx.send(delta(4)).unwrap();
+        assert!(listener.try_next().unwrap().is_none());
+        assert_eq!(listener.resume(), 0);
+        tx.send(delta(5)).unwrap();
+        assert!(listener.try_next().unwrap().unwrap().is_ok());
+        assert_eq!(
+            listener.causality(),
+            Some(Causality {
+                version: 6,
+                base: Some(5)
+            })
+        );
+
+        // replayed deltas are numbered as when they were first received
+        let super::super::replay::Replay::Updates { updates, .. } =
+            log.replay(&key, Some(log.session()), 4)
+        else {
+            panic!("updates no longer retained");
+        };
+        listener.replay(updates);
+        let mut replayed = vec![];
+        while listener.try_next().unwrap().is_some() {
+            replayed.push(listener.causality().unwrap().base);
+        }
+        assert_eq!(replayed, vec![Some(4), Some(5)]);
+    }
+
+    #[test]
+    fn contracts_coalesced_over_own_windows() {
+        let (fast, slow) = (
+            ContractKey::from(ContractInstanceId::new([1; 32])),
+            ContractKey::from(ContractInstanceId::new([2; 32])),
+        );
+        let windows = CoalescingWindows::new(&NotificationCoalescing {
+            default_window_ms: 0,
+            contracts: [(fast.id().to_string(), 50), (slow.id().to_string(), 300)].into(),
+        });
+        let mut listeners = [fast, slow].map(|key| {
+            let (tx, rx) = mpsc::unbounded_channel();
+            let listener = SubscriptionListener::new(key, rx).with_coalescing(windows.of(&key));
+            (tx, listener)
+        });
+        let delta = |key, n: u8| {
+            Ok(ContractResponse::UpdateNotification {
+                key,
+                update: UpdateData::Delta(StateDelta::from(vec![n])),
+            }
+            .into())
+        };
+        for (tx, listener) in &mut listeners {
+            let key = listener.key;
+            tx.send(notification(key, 1)).unwrap();
+            tx.send(delta(key, 2)).unwrap();
+            // supersedes both
+            tx.send(notification(key, 3)).unwrap();
+            tx.send(delta(key, 4)).unwrap();
+            assert!(listener.try_next().unwrap().is_none());
+        }
+
+        std::thread::sleep(Duration::from_millis(100));
+        let [(_, fast), (_, slow)] = &mut listeners;
+        assert!(slow.try_next().unwrap().is_none(), "held past its window");
+        assert_eq!(version(fast.try_next().unwrap().unwrap()), 3);
+        assert!(matches!(
+            fast.try_next().unwrap(),
+            Some(Ok(HostResponse::ContractResponse(
+                ContractResponse::UpdateNotification {
+                    update: UpdateData::Delta(_),
+                    ..
+                }
+            )))
+        ));
+        assert!(fast.try_next().unwrap().is_none());
+
+        std::thread::sleep(Duration::from_millis(250));
+        assert_eq!(version(slow.try_next().unwrap().unwrap()), 3);
+        assert!(slow.try_next().unwrap().is_some());
+        assert!(slow.try_next().unwrap().is_none());
+
+        // a contract without a window of its own isn't held
+        let (tx, rx) = mpsc::unbounded_channel();
+        let other = ContractKey::from(ContractInstanceId::new([3; 32]));
+        let mut listener = SubscriptionListener::new(other, rx).with_coalescing(windows.of(&other));
+        tx.send(notification(other, 1)).unwrap();
+        assert_eq!(drain(&mut listener), vec![1]);
+    }
+
+    #[test]
+    fn numeric_updates_aggregated_over_window() {
+        use crate::config::AggregateFunction;
+
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let (tx, rx) = mpsc::unbounded_channel();
+        let log = Arc::new(UpdateLog::default());
+        let mut listener = SubscriptionListener::new(key, rx)
+            .with_update_log(log)
+            .with_aggregation(Some(Aggregation {
+                function: AggregateFunction::Avg,
+                window: Duration::from_millis(100),
+            }));
+        let update = |value: &str| {
+            Ok(ContractResponse::UpdateNotification {
+                key,
+                update: UpdateData::State(State::from(value.as_bytes().to_vec())),
+            }
+            .into())
+        };
+        let delta = |value: &str| {
+            Ok(ContractResponse::UpdateNotification {
+                key,
+                update: UpdateData::Delta(StateDelta::from(value.as_bytes().to_vec())),
+            }
+            .into())
+        };
+        let aggregated = |notification: HostResult| match notification {
+            Ok(HostResponse::ContractResponse(ContractResponse::UpdateNotification {
+                update: UpdateData::State(state),
+                ..
+            })) => serde_json::from_slice::<f64>(state.as_ref()).unwrap(),
+            other => panic!("unexpected notification: {other:?}"),
+        };
+
+        for value in ["1", "2", "6"] {
+            tx.send(update(value)).unwrap();
+        }
+        // not a number, sent as it comes
+        tx.send(delta("\"high\"")).unwrap();
+        assert!(matches!(
+            listener.try_next().unwrap(),
+            Some(Ok(HostResponse::ContractResponse(
+                ContractResponse::UpdateNotification {
+                    update: UpdateData::Delta(_),
+                    ..
+                }
+            )))
+        ));
+        assert!(
+            listener.try_next().unwrap().is_none(),
+            "sent within the window"
+        );
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(aggregated(listener.try_next().unwrap().unwrap()), 3.0);
+        assert_eq!(
+            listener.causality(),
+            Some(Causality {
+                version: 3,
+                base: None
+            })
+        );
+        assert!(listener.try_next().unwrap().is_none());
+
+        // each window aggregated on its own, the deltas applied to the last value
+        tx.send(update("10")).unwrap();
+        tx.send(delta("5")).unwrap();
+        assert!(listener.try_next().unwrap().is_none());
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(aggregated(listener.try_next().unwrap().unwrap()), 12.5);
+
+        // the window in progress goes out before the subscription ends
+        tx.send(delta("-11")).unwrap();
+        drop(tx);
+        assert_eq!(aggregated(listener.try_next().unwrap().unwrap()), 4.0);
+    }
+
+    #[test]
+    fn memory_cap_over_filled_buffers() {
+        use super::super::memory::NotificationMemory;
+        use crate::config::{SubscriptionMemory, SubscriptionMemoryPolicy};
+
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let size = response_size(&notification(key, 0));
+        let subscribe = |memory: &Arc<NotificationMemory>| {
+            let (tx, rx) = mpsc::unbounded_channel();
+            let mut listener = SubscriptionListener::new(key, rx).with_memory(memory.register());
+            listener.pause(PausePolicy::Buffer);
+            (tx, listener)
+        };
+
+        // buffers stop filling at the cap, what comes meanwhile waits until some are sent
+        let memory = Arc::new(NotificationMemory::new(SubscriptionMemory {
+            max_bytes: Some(4 * size),
+            policy: SubscriptionMemoryPolicy::Backpressure,
+        }));
+        let [(tx, mut first), (other_tx, mut second)] = [(); 2].map(|_| subscribe(&memory));
+        for v in 0..3 {
+            tx.send(notification(key, v)).unwrap();
+            other_tx.send(notification(key, v)).unwrap();
+        }
+        assert!(first.try_next().unwrap().is_none());
+        assert!(second.try_next().unwrap().is_none());
+        assert_eq!(memory.held(), 4 * size);
+        assert_eq!(first.resume() + second.resume(), 4);
+        assert_eq!(drain(&mut first), vec![0, 1, 2]);
+        assert_eq!(drain(&mut second), vec![0, 1, 2]);
+        assert_eq!(memory.held(), 0);
+
+        // the least important subscription is shed once over the cap
+        let memory = Arc::new(NotificationMemory::new(SubscriptionMemory {
+            max_bytes: Some(4 * size),
+            policy: SubscriptionMemoryPolicy::Shed,
+        }));
+        let [(tx, mut important), (other_tx, mut shed)] = [(); 2].map(|_| subscribe(&memory));
+        important.set_priority(1);
+        for v in 0..3 {
+            tx.send(notification(key, v)).unwrap();
+            other_tx.send(notification(key, v)).unwrap();
+        }
+        assert!(important.try_next().unwrap().is_none());
+        assert!(shed.try_next().unwrap().is_none());
+        assert!(shed.is_shed() && !important.is_shed());
+        shed.resume();
+        assert!(matches!(shed.try_next().unwrap(), Some(Err(_))));
+        // the node no longer notifies it
+        assert!(other_tx.is_closed());
+        assert_eq!(memory.held(), 3 * size);
+        important.resume();
+        assert_eq!(drain(&mut important), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn waiting_at_memory_cap_within_backlog() {
+        use super::super::memory::NotificationMemory;
+        use crate::config::{SubscriptionMemory, SubscriptionMemoryPolicy};
+
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let size = response_size(&notification(key, 0));
+        let memory = Arc::new(NotificationMemory::new(SubscriptionMemory {
+            max_bytes: Some(size),
+            policy: SubscriptionMemoryPolicy::Backpressure,
+        }));
+        let config = SlowConsumers {
+            max_backlog: 3,
+            ..Default::default()
+        };
+        let subscribe = |policy| {
+            let (tx, rx) = mpsc::unbounded_channel();
+            let listener = SubscriptionListener::new(key, rx)
+                .with_memory(memory.register())
+                .with_backlog(Backlog::new(&config, Some(policy)));
+            for v in 0..6 {
+                tx.send(notification(key, v)).unwrap();
+            }
+            (tx, listener)
+        };
+
+        let (_tx, mut dropping) = subscribe(SlowConsumerPolicy::DropNewest);
+        dropping.absorb().unwrap();
+        // one buffered up to the cap, two more wait with the node
+        assert_eq!(dropping.queued(), 3);
+        assert_eq!(memory.held(), size);
+
+        let (_tx, mut disconnecting) = subscribe(SlowConsumerPolicy::Disconnect);
+        assert!(disconnecting.absorb().is_err());
+    }
+
+    #[test]
+    fn slow_consumer_policies() {
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let config = SlowConsumers {
+            max_backlog: 3,
+            block_timeout_ms: 100,
+            ..Default::default()
+        };
+        // five notifications come while the client can't be sent any
+        let fall_behind = |policy| {
+            let (tx, rx) = mpsc::unbounded_channel();
+            let mut listener = SubscriptionListener::new(key, rx)
+                .with_backlog(Backlog::new(&config, Some(policy)));
+            for v in 1..=5 {
+                tx.send(notification(key, v)).unwrap();
+            }
+            let absorbed = listener.absorb();
+            (tx, listener, absorbed)
+        };
+
+        let (_tx, mut listener, absorbed) = fall_behind(SlowConsumerPolicy::DropOldest);
+        assert!(absorbed.is_ok());
+        assert_eq!(drain(&mut listener), vec![3, 4, 5]);
+
+        let (_tx, mut listener, absorbed) = fall_behind(SlowConsumerPolicy::DropNewest);
+        assert!(absorbed.is_ok());
+        assert_eq!(drain(&mut listener), vec![1, 2, 3]);
+
+        let (_tx, _, absorbed) = fall_behind(SlowConsumerPolicy::Disconnect);
+        assert!(absorbed.is_err());
+
+        // tolerated for the block timeout, keeping everything
+        let (_tx, mut listener, absorbed) = fall_behind(SlowConsumerPolicy::Block);
+        assert!(absorbed.is_ok());
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(listener.absorb().is_err());
+        assert_eq!(drain(&mut listener), vec![1, 2, 3, 4, 5]);
+        // unless it caught up meanwhile
+        let (_caught_up_tx, mut caught_up, _) = fall_behind(SlowConsumerPolicy::Block);
+        std::thread::sleep(Duration::from_millis(150));
+        caught_up.try_next().unwrap();
+        caught_up.try_next().unwrap();
+        assert!(caught_up.absorb().is_ok());
+        assert_eq!(drain(&mut caught_up), vec![3, 4, 5]);
+    }
+}
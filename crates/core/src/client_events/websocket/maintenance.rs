@@ -0,0 +1,78 @@
+//! Maintenance mode the gateway enters over the window scheduled for it.
+//!
+//! The mode is switched on at the start of the [`MaintenanceWindow`] and off at its end by a
+//! task sleeping until then, and while on, clients trying to connect are told the gateway is
+//! under maintenance and when to try again. A window which already ended when the gateway
+//! starts is never entered.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::config::MaintenanceWindow;
+
+pub(super) struct Maintenance {
+    window: Option<MaintenanceWindow>,
+    active: Arc<AtomicBool>,
+}
+
+impl Maintenance {
+    pub fn schedule(window: Option<MaintenanceWindow>) -> Self {
+        let active = Arc::new(AtomicBool::new(false));
+        if let Some(window) = window {
+            tokio::spawn(switch(window, active.clone()));
+        }
+        Self { window, active }
+    }
+
+    /// When the maintenance ends, if the gateway is under maintenance.
+    pub fn until(&self) -> Option<DateTime<Utc>> {
+        let window = self.window?;
+        self.active.load(Ordering::Acquire).then_some(window.end)
+    }
+}
+
+async fn switch(window: MaintenanceWindow, active: Arc<AtomicBool>) {
+    let until = |at: DateTime<Utc>| (at - Utc::now()).to_std().unwrap_or_default();
+    tokio::time::sleep(until(window.start)).await;
+    if Utc::now() >= window.end {
+        return;
+    }
+    tracing::warn!(end = %window.end, "entering maintenance mode");
+    active.store(true, Ordering::Release);
+    tokio::time::sleep(until(window.end)).await;
+    active.store(false, Ordering::Release);
+    tracing::info!("leaving maintenance mode");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn toggled_over_the_window() {
+        let start = Utc::now() + Duration::from_millis(100);
+        let end = start + Duration::from_millis(200);
+        let maintenance = Maintenance::schedule(Some(MaintenanceWindow { start, end }));
+        assert_eq!(maintenance.until(), None);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(maintenance.until(), Some(end));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(maintenance.until(), None);
+
+        // already over
+        let past = Maintenance::schedule(Some(MaintenanceWindow {
+            start: start - Duration::from_secs(60),
+            end: end - Duration::from_secs(60),
+        }));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(past.until(), None);
+    }
+}
@@ -0,0 +1,233 @@
+//! Accounting of the memory held in the subscriptions of every websocket connection.
+//!
+//! Notifications pile up in the subscriptions of clients reading them slower than they come,
+//! or which paused them, up to the backlog of each; across many subscriptions that can still
+//! add up to a lot of memory. With a cap configured the bytes held across all of them are
+//! accounted, and once reached the [`SubscriptionMemoryPolicy`] applies: either subscriptions
+//! stop taking in notifications, which wait with the node until those held are sent, counted
+//! against the backlog of their subscription like those it holds, or the least important
+//! subscriptions are shed until back under the cap. Those are the ones of the lowest priority
+//! and, among them, holding the most; the node stops notifying them right away.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use parking_lot::Mutex;
+
+use crate::config::{SubscriptionMemory, SubscriptionMemoryPolicy};
+
+pub(super) struct NotificationMemory {
+    max_bytes: Option<usize>,
+    policy: SubscriptionMemoryPolicy,
+    accounted: Mutex<Accounted>,
+}
+
+#[derive(Default)]
+struct Accounted {
+    held: usize,
+    next_id: u64,
+    subscriptions: HashMap<u64, Subscription>,
+}
+
+impl Accounted {
+    /// Sheds the least important subscriptions until those shed free enough to get back under
+    /// the cap, counting the ones shed before which are yet to free theirs.
+    fn shed(&self, max_bytes: usize) {
+        let freeing: usize = self
+            .subscriptions
+            .values()
+            .filter(|sub| sub.shed.load(Ordering::Relaxed))
+            .map(|sub| sub.bytes)
+            .sum();
+        let mut over = self.held.saturating_sub(freeing).saturating_sub(max_bytes);
+        while over > 0 {
+            let Some(victim) = self
+                .subscriptions
+                .values()
+                .filter(|sub| sub.bytes > 0 && !sub.shed.load(Ordering::Relaxed))
+                .min_by_key(|sub| (sub.priority, std::cmp::Reverse(sub.bytes)))
+            else {
+                break;
+            };
+            tracing::debug!(
+                bytes = victim.bytes,
+                priority = victim.priority,
+                "shedding subscription over the subscription memory cap"
+            );
+            victim.shed.store(true, Ordering::Relaxed);
+            over = over.saturating_sub(victim.bytes);
+        }
+    }
+}
+
+struct Subscription {
+    priority: u8,
+    bytes: usize,
+    shed: Arc<AtomicBool>,
+}
+
+impl NotificationMemory {
+    pub fn new(config: SubscriptionMemory) -> Self {
+        Self {
+            max_bytes: config.max_bytes,
+            policy: config.policy,
+            accounted: Mutex::new(Accounted::default()),
+        }
+    }
+
+    /// Bytes held across all the subscriptions.
+    pub fn held(&self) -> usize {
+        self.accounted.lock().held
+    }
+
+    /// Starts accounting the notifications held by a new subscription.
+    pub fn register(self: &Arc<Self>) -> AccountedBuffer {
+        let shed = Arc::new(AtomicBool::new(false));
+        let mut accounted = self.accounted.lock();
+        let id = accounted.next_id;
+        accounted.next_id += 1;
+        accounted.subscriptions.insert(
+            id,
+            Subscription {
+                priority: 0,
+                bytes: 0,
+                shed: shed.clone(),
+            },
+        );
+        AccountedBuffer {
+            id,
+            memory: self.clone(),
+            shed,
+        }
+    }
+}
+
+/// The share of a single subscription in the [`NotificationMemory`], released once dropped.
+pub(super) struct AccountedBuffer {
+    id: u64,
+    memory: Arc<NotificationMemory>,
+    shed: Arc<AtomicBool>,
+}
+
+impl AccountedBuffer {
+    /// Whether the subscription may take in more notifications, false while the cap is reached
+    /// with the [`SubscriptionMemoryPolicy::Backpressure`] policy.
+    pub fn has_room(&self) -> bool {
+        match (self.memory.policy, self.memory.max_bytes) {
+            (SubscriptionMemoryPolicy::Backpressure, Some(max_bytes)) => {
+                self.memory.held() < max_bytes
+            }
+            _ => true,
+        }
+    }
+
+    /// Whether the subscription was shed, it is to be ended.
+    pub fn is_shed(&self) -> bool {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    pub fn set_priority(&self, priority: u8) {
+        if let Some(sub) = self.memory.accounted.lock().subscriptions.get_mut(&self.id) {
+            sub.priority = priority;
+        }
+    }
+
+    pub fn hold(&self, size: usize) {
+        let mut accounted = self.memory.accounted.lock();
+        accounted.held += size;
+        if let Some(sub) = accounted.subscriptions.get_mut(&self.id) {
+            sub.bytes += size;
+        }
+        match (self.memory.policy, self.memory.max_bytes) {
+            (SubscriptionMemoryPolicy::Shed, Some(max_bytes)) if accounted.held > max_bytes => {
+                accounted.shed(max_bytes);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn release(&self, size: usize) {
+        let mut accounted = self.memory.accounted.lock();
+        let Some(sub) = accounted.subscriptions.get_mut(&self.id) else {
+            return;
+        };
+        let size = size.min(sub.bytes);
+        sub.bytes -= size;
+        accounted.held -= size;
+    }
+
+    /// Releases everything the subscription holds.
+    pub fn clear(&self) {
+        let mut accounted = self.memory.accounted.lock();
+        let Some(sub) = accounted.subscriptions.get_mut(&self.id) else {
+            return;
+        };
+        let size = std::mem::take(&mut sub.bytes);
+        accounted.held -= size;
+    }
+}
+
+impl Drop for AccountedBuffer {
+    fn drop(&mut self) {
+        let mut accounted = self.memory.accounted.lock();
+        if let Some(sub) = accounted.subscriptions.remove(&self.id) {
+            accounted.held -= sub.bytes;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory(max_bytes: usize, policy: SubscriptionMemoryPolicy) -> Arc<NotificationMemory> {
+        Arc::new(NotificationMemory::new(SubscriptionMemory {
+            max_bytes: Some(max_bytes),
+            policy,
+        }))
+    }
+
+    #[test]
+    fn backpressure_while_over_cap() {
+        let memory = memory(100, SubscriptionMemoryPolicy::Backpressure);
+        let (first, second) = (memory.register(), memory.register());
+        first.hold(60);
+        assert!(second.has_room());
+        second.hold(60);
+        assert!(!first.has_room() && !second.has_room());
+        assert!(!first.is_shed() && !second.is_shed());
+
+        first.release(30);
+        assert!(second.has_room());
+        drop(second);
+        assert_eq!(memory.held(), 30);
+    }
+
+    #[test]
+    fn least_important_shed_over_cap() {
+        let memory = memory(100, SubscriptionMemoryPolicy::Shed);
+        let [important, small, large] = [(); 3].map(|_| memory.register());
+        important.set_priority(1);
+        important.hold(50);
+        small.hold(10);
+        large.hold(30);
+        assert!(![&important, &small, &large].iter().any(|sub| sub.is_shed()));
+
+        // the largest of the least important frees enough
+        important.hold(20);
+        assert!(large.is_shed());
+        assert!(!important.is_shed() && !small.is_shed());
+        // shed already, yet to free its share
+        small.hold(5);
+        assert!(!small.is_shed());
+
+        large.clear();
+        assert_eq!(memory.held(), 85);
+        assert!(important.has_room());
+    }
+}
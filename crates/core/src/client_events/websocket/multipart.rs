@@ -0,0 +1,157 @@
+//! Responses made of several parts delivered one part at a time.
+//!
+//! Clients using the native encoding which send the [`MULTIPART_RESPONSES_HEADER`] when
+//! connecting get the responses which carry several independent pieces split into them: a
+//! get into the state and then the contract code, a delegate response into each of its
+//! messages. Every part is a [`ControlResponse::Part`] text message, identifying the response
+//! it belongs to and its position, followed by a binary message with the part itself, so a
+//! client can start on the state while the contract code is still on its way.
+//!
+//! The state is sent as is, contracts and delegate messages bincode serialized. Responses
+//! with a single part are sent as usual.
+
+use freenet_stdlib::client_api::{ContractResponse, HostResponse};
+use serde::Serialize;
+
+use super::control::ControlResponse;
+
+pub(super) const MULTIPART_RESPONSES_HEADER: &str = "x-multipart-responses";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) enum PartKind {
+    State,
+    Contract,
+    DelegateMessage,
+}
+
+/// Each part along with the message announcing it.
+type Parts = Vec<(ControlResponse, Vec<u8>)>;
+
+/// Numbers the multi-part responses of a connection, for their parts to be correlated.
+#[derive(Default)]
+pub(super) struct MultipartResponses {
+    next_response: u64,
+}
+
+impl MultipartResponses {
+    /// The parts of `response`, or the response back if it has a single one.
+    pub fn split(
+        &mut self,
+        response: HostResponse,
+    ) -> bincode::Result<Result<Parts, HostResponse>> {
+        let (key, parts) = match response {
+            HostResponse::ContractResponse(ContractResponse::GetResponse {
+                key,
+                contract: Some(contract),
+                state,
+            }) => (
+                key.to_string(),
+                vec![
+                    (PartKind::State, state.as_ref().to_vec()),
+                    (PartKind::Contract, bincode::serialize(&contract)?),
+                ],
+            ),
+            HostResponse::DelegateResponse { key, values } if values.len() > 1 => {
+                let parts = values
+                    .iter()
+                    .map(|msg| Ok((PartKind::DelegateMessage, bincode::serialize(msg)?)))
+                    .collect::<bincode::Result<_>>()?;
+                (key.encode(), parts)
+            }
+            other => return Ok(Err(other)),
+        };
+        let response = self.next_response;
+        self.next_response += 1;
+        let count = parts.len();
+        Ok(Ok(parts
+            .into_iter()
+            .enumerate()
+            .map(|(index, (kind, data))| {
+                let part = ControlResponse::Part {
+                    response,
+                    index,
+                    count,
+                    kind,
+                    key: key.clone(),
+                };
+                (part, data)
+            })
+            .collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use freenet_stdlib::prelude::{
+        ApplicationMessage, CodeHash, ContractCode, ContractContainer, ContractInstanceId,
+        ContractKey, ContractWasmAPIVersion, DelegateKey, OutboundDelegateMsg, Parameters,
+        WrappedContract, WrappedState,
+    };
+
+    use super::*;
+
+    fn announced(part: &ControlResponse) -> serde_json::Value {
+        serde_json::to_value(part).unwrap()["part"].clone()
+    }
+
+    #[test]
+    fn parts_correlated_in_order() {
+        let contract = ContractContainer::Wasm(ContractWasmAPIVersion::V1(WrappedContract::new(
+            std::sync::Arc::new(ContractCode::from(vec![0, 1, 2])),
+            Parameters::from(vec![]),
+        )));
+        let key = contract.key();
+        let get = ContractResponse::GetResponse {
+            key,
+            contract: Some(contract.clone()),
+            state: WrappedState::new(vec![7; 8]),
+        };
+        let mut responses = MultipartResponses::default();
+        let parts = responses.split(get.into()).unwrap().unwrap();
+        assert_eq!(parts.len(), 2);
+        let (state, contract_part) = (&parts[0], &parts[1]);
+        assert_eq!(
+            announced(&state.0),
+            serde_json::json!({
+                "response": 0, "index": 0, "count": 2, "kind": "state", "key": key.to_string()
+            })
+        );
+        assert_eq!(state.1, vec![7; 8]);
+        assert_eq!(announced(&contract_part.0)["index"], 1);
+        assert_eq!(announced(&contract_part.0)["kind"], "contract");
+        let decoded: ContractContainer = bincode::deserialize(&contract_part.1).unwrap();
+        assert_eq!(decoded.key(), key);
+
+        let app = ContractInstanceId::new([1; 32]);
+        let delegate = HostResponse::DelegateResponse {
+            key: DelegateKey::new([2; 32], CodeHash::new([3; 32])),
+            values: (0..3)
+                .map(|n| {
+                    OutboundDelegateMsg::ApplicationMessage(ApplicationMessage::new(app, vec![n]))
+                })
+                .collect(),
+        };
+        let parts = responses.split(delegate).unwrap().unwrap();
+        for (index, (part, data)) in parts.iter().enumerate() {
+            let part = announced(part);
+            // the next response being split gets its own number
+            assert_eq!(part["response"], 1);
+            assert_eq!(part["index"], index);
+            assert_eq!(part["count"], 3);
+            let OutboundDelegateMsg::ApplicationMessage(msg) = bincode::deserialize(data).unwrap()
+            else {
+                panic!("expected an application message");
+            };
+            assert_eq!(msg.payload, vec![index as u8]);
+        }
+
+        // a get without the contract is a single part
+        let get = ContractResponse::GetResponse {
+            key: ContractKey::from(app),
+            contract: None,
+            state: WrappedState::new(vec![]),
+        };
+        assert!(responses.split(get.into()).unwrap().is_err());
+    }
+}
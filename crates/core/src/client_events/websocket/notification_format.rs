@@ -0,0 +1,56 @@
+//! Format of the subscription notifications sent to each websocket client.
+//!
+//! Clients pick it with the [`NOTIFICATION_FORMAT_HEADER`] when connecting. Those asking for
+//! `json` get every notification as a text message with the result serialized as JSON, i.e.
+//! `{"Ok": ...}` or `{"Err": ...}`, which browser clients can read without a decoder for the
+//! encoding protocol. The others get them as binary messages in the encoding protocol of the
+//! connection, as they do responses. Every subscriber to a contract gets its notifications in
+//! the format of its own connection.
+
+use axum::{extract::ws::Message, http::HeaderMap};
+use serde::Serialize;
+
+use crate::{client_events::HostResult, util::EncodingProtocol};
+
+pub(super) const NOTIFICATION_FORMAT_HEADER: &str = "x-notification-format";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum NotificationFormat {
+    #[default]
+    Binary,
+    Json,
+}
+
+impl NotificationFormat {
+    /// The format asked for in the headers of the handshake, binary unless any.
+    pub fn negotiate(headers: &HeaderMap) -> Result<Self, String> {
+        let Some(format) = headers.get(NOTIFICATION_FORMAT_HEADER) else {
+            return Ok(Self::Binary);
+        };
+        match format.to_str() {
+            Ok("binary") => Ok(Self::Binary),
+            Ok("json") => Ok(Self::Json),
+            _ => Err(format!(
+                "`{NOTIFICATION_FORMAT_HEADER}` must be either `binary` or `json`"
+            )),
+        }
+    }
+
+    pub fn encode(
+        self,
+        notification: HostResult,
+        encoding: EncodingProtocol,
+    ) -> anyhow::Result<Message> {
+        Ok(match (self, encoding) {
+            (Self::Json, _) => Message::Text(serde_json::to_string(&notification)?),
+            (Self::Binary, EncodingProtocol::Flatbuffers) => Message::Binary(match notification {
+                Ok(res) => res.into_fbs_bytes()?,
+                Err(err) => err.into_fbs_bytes()?,
+            }),
+            (Self::Binary, EncodingProtocol::Native) => {
+                Message::Binary(bincode::serialize(&notification)?)
+            }
+        })
+    }
+}
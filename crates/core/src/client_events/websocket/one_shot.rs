@@ -0,0 +1,137 @@
+//! Subscriptions made only to get the current state of a contract once.
+//!
+//! A client sends a [`ControlFrame::Snapshot`](super::control::ControlFrame::Snapshot) frame
+//! instead of subscribing. The proxy subscribes on its behalf, and once the subscription is
+//! set up gets the state of the contract, which is then as recent as the point the
+//! subscription started from. The client is answered with that state alone, the channel the
+//! node notifies updates through is closed right after and the connection never gets any.
+
+use std::collections::{HashMap, HashSet};
+
+use freenet_stdlib::{
+    client_api::{
+        ClientError, ContractError, ContractResponse, ErrorKind, HostResponse, RequestError,
+    },
+    prelude::{ContractInstanceId, ContractKey},
+};
+use parking_lot::Mutex;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::client_events::{AuthToken, ClientId, HostResult};
+
+/// Subscriptions clients asked for only the state of, shared by the connections asking and the
+/// proxy making them.
+#[derive(Default)]
+pub(super) struct OneShotRequests(Mutex<HashSet<(ClientId, ContractKey)>>);
+
+impl OneShotRequests {
+    /// The next subscription of the client to the contract is only for its state.
+    pub fn request(&self, client: ClientId, key: ContractKey) {
+        self.0.lock().insert((client, key));
+    }
+
+    pub fn take(&self, client: ClientId, key: &ContractKey) -> bool {
+        self.0.lock().remove(&(client, *key))
+    }
+}
+
+struct OneShot {
+    key: ContractKey,
+    /// Dropped once the state is in, closing the subscription.
+    _updates: UnboundedReceiver<HostResult>,
+    auth_token: Option<AuthToken>,
+    attested_contract: Option<ContractInstanceId>,
+    fetching: bool,
+}
+
+/// What is done with a response to a client.
+pub(super) enum Answer {
+    Forward(Result<HostResponse, ClientError>),
+    /// The subscription is set up, the state is to be got for the client.
+    Fetch {
+        key: ContractKey,
+        auth_token: Option<AuthToken>,
+        attested_contract: Option<ContractInstanceId>,
+    },
+}
+
+#[derive(Default)]
+pub(super) struct OneShotSubscriptions {
+    open: HashMap<(ClientId, ContractInstanceId), OneShot>,
+}
+
+impl OneShotSubscriptions {
+    pub fn open(
+        &mut self,
+        client: ClientId,
+        key: ContractKey,
+        updates: UnboundedReceiver<HostResult>,
+        auth_token: Option<AuthToken>,
+        attested_contract: Option<ContractInstanceId>,
+    ) {
+        let one_shot = OneShot {
+            key,
+            _updates: updates,
+            auth_token,
+            attested_contract,
+            fetching: false,
+        };
+        self.open.insert((client, *key.id()), one_shot);
+    }
+
+    /// Goes on with the one shot subscription the response is for, if any.
+    pub fn answer(
+        &mut self,
+        client: ClientId,
+        result: Result<HostResponse, ClientError>,
+    ) -> Answer {
+        if self.open.is_empty() {
+            return Answer::Forward(result);
+        }
+        let contract = match &result {
+            Ok(HostResponse::ContractResponse(
+                ContractResponse::SubscribeResponse { key, .. }
+                | ContractResponse::GetResponse { key, .. },
+            )) => *key.id(),
+            Err(err) => match err.kind() {
+                ErrorKind::RequestError(RequestError::ContractError(
+                    ContractError::Subscribe { key, .. },
+                )) => *key.id(),
+                ErrorKind::RequestError(RequestError::ContractError(
+                    ContractError::MissingContract { key },
+                )) => *key,
+                _ => return Answer::Forward(result),
+            },
+            _ => return Answer::Forward(result),
+        };
+        let Some(one_shot) = self.open.get_mut(&(client, contract)) else {
+            return Answer::Forward(result);
+        };
+        match &result {
+            Ok(HostResponse::ContractResponse(ContractResponse::SubscribeResponse { .. }))
+                if !one_shot.fetching =>
+            {
+                one_shot.fetching = true;
+                Answer::Fetch {
+                    key: one_shot.key,
+                    auth_token: one_shot.auth_token.clone(),
+                    attested_contract: one_shot.attested_contract,
+                }
+            }
+            Ok(HostResponse::ContractResponse(ContractResponse::GetResponse { .. }))
+                if !one_shot.fetching =>
+            {
+                Answer::Forward(result)
+            }
+            // the state, or the reason there is none
+            _ => {
+                self.open.remove(&(client, contract));
+                Answer::Forward(result)
+            }
+        }
+    }
+
+    pub fn remove_client(&mut self, client: &ClientId) {
+        self.open.retain(|(open, _), _| open != client);
+    }
+}
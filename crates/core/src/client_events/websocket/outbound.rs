@@ -0,0 +1,575 @@
+//! Writing of the messages sent to a websocket client.
+//!
+//! Responses to the requests of the client and notifications for its subscriptions are
+//! queued separately, so a flood of notifications doesn't hold back the responses. Which
+//! one is written next while both are waiting is decided by the [`OutboundPriority`], and
+//! which of the waiting notifications by the [`QueueDiscipline`] of the connection.
+//!
+//! Clients may also limit how many messages they get: once a client grants some credits,
+//! every message written spends one, and when they run out nothing else is written until
+//! more are granted. The client is told when a message is waiting for credits.
+//!
+//! A client not reading what is written to it eventually stalls the writes; once one takes
+//! longer than the send timeout nothing more is written and the connection is to be closed.
+//! Writes also wait for the [`Bandwidth`] shared by all the connections to allow them.
+
+use std::{cmp::Reverse, collections::VecDeque, fmt::Display, sync::Arc, time::Duration};
+
+use axum::{extract::ws::Message, http::HeaderMap};
+use futures::{Sink, SinkExt};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use super::{bandwidth::Bandwidth, control::ControlResponse};
+use crate::config::{OutboundPriority, QueueDiscipline};
+
+/// Header of the handshake a client picks the discipline of its notifications with, instead
+/// of the one the node is configured with.
+pub(super) const NOTIFICATION_QUEUE_HEADER: &str = "x-notification-queue";
+
+/// Messages of each kind queued for writing, further ones wait until there is room.
+const QUEUED_MESSAGES: usize = 16;
+
+/// A notification for a subscription, along with the message which has to go right before it,
+/// if any.
+pub(super) struct Notification {
+    preceded_by: Option<Message>,
+    message: Message,
+    priority: u8,
+}
+
+impl Notification {
+    pub fn new(message: Message) -> Self {
+        Self {
+            preceded_by: None,
+            message,
+            priority: 0,
+        }
+    }
+
+    pub fn preceded_by(mut self, message: Option<Message>) -> Self {
+        self.preceded_by = message;
+        self
+    }
+
+    /// Priority of the subscription the notification is for, see [`QueueDiscipline::Priority`].
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+impl From<Message> for Notification {
+    fn from(message: Message) -> Self {
+        Self::new(message)
+    }
+}
+
+/// The discipline asked for in the headers of the handshake, the configured one unless any.
+pub(super) fn negotiate_discipline(
+    headers: &HeaderMap,
+    configured: QueueDiscipline,
+) -> Result<QueueDiscipline, String> {
+    let Some(discipline) = headers.get(NOTIFICATION_QUEUE_HEADER) else {
+        return Ok(configured);
+    };
+    match discipline.to_str() {
+        Ok("fifo") => Ok(QueueDiscipline::Fifo),
+        Ok("lifo") => Ok(QueueDiscipline::Lifo),
+        Ok("priority") => Ok(QueueDiscipline::Priority),
+        _ => Err(format!(
+            "`{NOTIFICATION_QUEUE_HEADER}` must be one of `fifo`, `lifo` or `priority`"
+        )),
+    }
+}
+
+pub(super) struct Outbound {
+    responses: mpsc::Sender<Message>,
+    notifications: mpsc::Sender<Notification>,
+    grants: mpsc::UnboundedSender<u64>,
+}
+
+impl Outbound {
+    /// Starts writing to `sink` the queued messages, until `Outbound` is dropped and
+    /// everything queued has been written, or a write takes longer than `send_timeout`.
+    pub fn start<S>(
+        sink: S,
+        priority: OutboundPriority,
+        discipline: QueueDiscipline,
+        send_timeout: Duration,
+        bandwidth: Arc<Bandwidth>,
+    ) -> (Self, JoinHandle<()>)
+    where
+        S: Sink<Message> + Unpin + Send + 'static,
+        S::Error: Display,
+    {
+        let (responses, responses_rx) = mpsc::channel(QUEUED_MESSAGES);
+        let (notifications, notifications_rx) = mpsc::channel(QUEUED_MESSAGES);
+        let (grants, grants_rx) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(write(
+            sink,
+            Queues {
+                responses: responses_rx,
+                notifications: Notifications {
+                    channel: notifications_rx,
+                    discipline,
+                    waiting: VecDeque::new(),
+                },
+                grants: grants_rx,
+            },
+            priority,
+            Sending {
+                timeout: send_timeout,
+                bandwidth,
+            },
+        ));
+        (
+            Self {
+                responses,
+                notifications,
+                grants,
+            },
+            writer,
+        )
+    }
+
+    pub async fn respond(&self, msg: Message) -> anyhow::Result<()> {
+        self.responses
+            .send(msg)
+            .await
+            .map_err(|_| anyhow::anyhow!("connection to client closed"))
+    }
+
+    pub async fn notify(&self, notification: impl Into<Notification>) -> anyhow::Result<()> {
+        self.notifications
+            .send(notification.into())
+            .await
+            .map_err(|_| anyhow::anyhow!("connection to client closed"))
+    }
+
+    /// Whether a notification can be queued right away.
+    pub fn has_room(&self) -> bool {
+        self.notifications.capacity() > 0
+    }
+
+    /// Allows writing `credits` more messages, the first grant enables flow control.
+    pub fn grant(&self, credits: u64) {
+        let _ = self.grants.send(credits);
+    }
+}
+
+/// The notifications queued, taken in the order of the discipline.
+struct Notifications {
+    channel: mpsc::Receiver<Notification>,
+    discipline: QueueDiscipline,
+    /// Taken from the channel to pick from, in the order they were queued.
+    waiting: VecDeque<Notification>,
+}
+
+impl Notifications {
+    fn len(&self) -> usize {
+        self.waiting.len() + self.channel.len()
+    }
+
+    async fn next(&mut self) -> Option<Notification> {
+        while self.waiting.len() < QUEUED_MESSAGES {
+            let Ok(notification) = self.channel.try_recv() else {
+                break;
+            };
+            self.waiting.push_back(notification);
+        }
+        let next = match self.discipline {
+            QueueDiscipline::Fifo => self.waiting.pop_front(),
+            QueueDiscipline::Lifo => self.waiting.pop_back(),
+            QueueDiscipline::Priority => self
+                .waiting
+                .iter()
+                .enumerate()
+                .max_by_key(|(i, notification)| (notification.priority, Reverse(*i)))
+                .map(|(i, _)| i)
+                .and_then(|i| self.waiting.remove(i)),
+        };
+        match next {
+            Some(notification) => Some(notification),
+            None => self.channel.recv().await,
+        }
+    }
+}
+
+struct Queues {
+    responses: mpsc::Receiver<Message>,
+    notifications: Notifications,
+    grants: mpsc::UnboundedReceiver<u64>,
+}
+
+enum Queue<'a> {
+    Responses(&'a mut mpsc::Receiver<Message>),
+    Notifications(&'a mut Notifications),
+}
+
+impl Queue<'_> {
+    /// The message to write next from the queue, along with the one to write before it.
+    async fn next(&mut self) -> Option<(Option<Message>, Message)> {
+        match self {
+            Queue::Responses(responses) => responses.recv().await.map(|msg| (None, msg)),
+            Queue::Notifications(notifications) => {
+                let notification = notifications.next().await?;
+                Some((notification.preceded_by, notification.message))
+            }
+        }
+    }
+}
+
+struct Sending {
+    timeout: Duration,
+    bandwidth: Arc<Bandwidth>,
+}
+
+/// Sends `msg` to the client once the bandwidth allows, false if the connection is gone or
+/// stalled.
+async fn send<S>(sink: &mut S, msg: Message, sending: &Sending) -> bool
+where
+    S: Sink<Message> + Unpin,
+    S::Error: Display,
+{
+    sending.bandwidth.take(&msg).await;
+    let timeout = sending.timeout;
+    match tokio::time::timeout(timeout, sink.send(msg)).await {
+        Ok(Ok(())) => true,
+        Ok(Err(err)) => {
+            tracing::debug!(err = %err, "error sending message to client");
+            false
+        }
+        Err(_) => {
+            tracing::debug!(?timeout, "sending to client stalled");
+            false
+        }
+    }
+}
+
+async fn write<S>(mut sink: S, queues: Queues, priority: OutboundPriority, sending: Sending)
+where
+    S: Sink<Message> + Unpin,
+    S::Error: Display,
+{
+    let Queues {
+        mut responses,
+        mut notifications,
+        mut grants,
+    } = queues;
+    // unlimited until the client grants some
+    let mut credits: Option<u64> = None;
+    let mut notifications_turn = false;
+    loop {
+        let (mut first, mut second) = if notifications_turn {
+            (
+                Queue::Notifications(&mut notifications),
+                Queue::Responses(&mut responses),
+            )
+        } else {
+            (
+                Queue::Responses(&mut responses),
+                Queue::Notifications(&mut notifications),
+            )
+        };
+        let ((before, msg), took_first) = tokio::select! { biased;
+            Some(granted) = grants.recv() => {
+                let credits = credits.get_or_insert(0);
+                *credits = credits.saturating_add(granted);
+                continue;
+            }
+            Some(msgs) = first.next() => (msgs, true),
+            Some(msgs) = second.next() => (msgs, false),
+            else => break,
+        };
+        if priority == OutboundPriority::RoundRobin {
+            let was_response = took_first != notifications_turn;
+            notifications_turn = was_response;
+        }
+        for msg in before.into_iter().chain([msg]) {
+            if credits == Some(0) {
+                let queued = 1 + responses.len() + notifications.len();
+                tracing::debug!(queued, "client ran out of credits");
+                let request = ControlResponse::CreditsExhausted { queued }.into_message();
+                if !send(&mut sink, request, &sending).await {
+                    return;
+                }
+                while credits == Some(0) {
+                    let Some(granted) = grants.recv().await else {
+                        return;
+                    };
+                    credits = Some(granted);
+                }
+            }
+            if let Some(credits) = &mut credits {
+                *credits -= 1;
+            }
+            if !send(&mut sink, msg, &sending).await {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{channel::mpsc as sink_channel, StreamExt};
+
+    use super::*;
+
+    const RESPONSE: &str = "response";
+
+    const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Floods the connection with notifications, then sends a response once the client
+    /// has read a few of them; returns the messages read until the response.
+    async fn respond_under_flood(priority: OutboundPriority) -> Vec<Message> {
+        // the client reads one message at a time
+        let (sink, mut client) = sink_channel::channel(0);
+        let (outbound, _writer) = Outbound::start(
+            sink,
+            priority,
+            QueueDiscipline::Fifo,
+            SEND_TIMEOUT,
+            Arc::default(),
+        );
+        let outbound = std::sync::Arc::new(outbound);
+
+        let flood = {
+            let outbound = outbound.clone();
+            tokio::spawn(async move {
+                for i in 0.. {
+                    if outbound.notify(Message::Text(i.to_string())).await.is_err() {
+                        break;
+                    }
+                }
+            })
+        };
+        let mut read = Vec::new();
+        for _ in 0..2 * QUEUED_MESSAGES {
+            read.push(client.next().await.unwrap());
+        }
+        outbound
+            .respond(Message::Text(RESPONSE.into()))
+            .await
+            .unwrap();
+        loop {
+            let msg = client.next().await.unwrap();
+            let done = msg == Message::Text(RESPONSE.into());
+            read.push(msg);
+            if done {
+                break;
+            }
+        }
+        flood.abort();
+        read
+    }
+
+    #[tokio::test]
+    async fn response_is_not_starved_by_notifications() {
+        for priority in [
+            OutboundPriority::ResponsesFirst,
+            OutboundPriority::RoundRobin,
+        ] {
+            let read = respond_under_flood(priority).await;
+            // at most the notification buffered for the client and the one being written
+            let behind = read.len() - 2 * QUEUED_MESSAGES - 1;
+            assert!(
+                behind <= 2,
+                "{priority:?}: {behind} notifications went first"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn round_robin_alternates() {
+        let (sink, client) = sink_channel::channel(4 * QUEUED_MESSAGES);
+        let (outbound, writer) = Outbound::start(
+            sink,
+            OutboundPriority::RoundRobin,
+            QueueDiscipline::Fifo,
+            SEND_TIMEOUT,
+            Arc::default(),
+        );
+        // everything queued before the writer gets a chance to run
+        for i in 0..4 {
+            outbound
+                .notify(Message::Text(format!("n{i}")))
+                .await
+                .unwrap();
+            outbound
+                .respond(Message::Text(format!("r{i}")))
+                .await
+                .unwrap();
+        }
+        drop(outbound);
+        writer.await.unwrap();
+
+        let sent: Vec<_> = client
+            .map(|msg| match msg {
+                Message::Text(text) => text,
+                other => panic!("unexpected message: {other:?}"),
+            })
+            .collect()
+            .await;
+        assert_eq!(sent, ["r0", "n0", "r1", "n1", "r2", "n2", "r3", "n3"]);
+    }
+
+    /// Next text message written to the client, if any is written shortly.
+    async fn read(client: &mut sink_channel::Receiver<Message>) -> Option<String> {
+        match tokio::time::timeout(Duration::from_millis(50), client.next()).await {
+            Ok(Some(Message::Text(text))) => Some(text),
+            Ok(other) => panic!("unexpected message: {other:?}"),
+            Err(_) => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_only_granted_messages() {
+        let (sink, mut client) = sink_channel::channel(4 * QUEUED_MESSAGES);
+        let (outbound, _writer) = Outbound::start(
+            sink,
+            OutboundPriority::ResponsesFirst,
+            QueueDiscipline::Fifo,
+            SEND_TIMEOUT,
+            Arc::default(),
+        );
+        outbound.grant(2);
+        for i in 0..5 {
+            outbound.notify(Message::Text(i.to_string())).await.unwrap();
+        }
+
+        assert_eq!(read(&mut client).await.as_deref(), Some("0"));
+        assert_eq!(read(&mut client).await.as_deref(), Some("1"));
+        // the third one waits for more credits
+        assert_eq!(
+            read(&mut client).await.as_deref(),
+            Some(r#"{"creditsExhausted":{"queued":3}}"#)
+        );
+        assert_eq!(read(&mut client).await, None);
+
+        outbound.grant(2);
+        assert_eq!(read(&mut client).await.as_deref(), Some("2"));
+        assert_eq!(read(&mut client).await.as_deref(), Some("3"));
+        assert!(read(&mut client)
+            .await
+            .unwrap()
+            .contains("creditsExhausted"));
+        assert_eq!(read(&mut client).await, None);
+
+        // credits saturate instead of overflowing
+        outbound.grant(u64::MAX);
+        outbound.grant(u64::MAX);
+        assert_eq!(read(&mut client).await.as_deref(), Some("4"));
+        outbound.notify(Message::Text("5".into())).await.unwrap();
+        assert_eq!(read(&mut client).await.as_deref(), Some("5"));
+    }
+
+    #[tokio::test]
+    async fn stalled_send_stops_writing() {
+        // room for a single message the client never reads
+        let (sink, _client) = sink_channel::channel(0);
+        let (outbound, writer) = Outbound::start(
+            sink,
+            OutboundPriority::ResponsesFirst,
+            QueueDiscipline::Fifo,
+            Duration::from_millis(50),
+            Arc::default(),
+        );
+        for i in 0..2 {
+            outbound.notify(Message::Text(i.to_string())).await.unwrap();
+        }
+
+        tokio::time::timeout(Duration::from_secs(1), writer)
+            .await
+            .expect("writer still blocked on the stalled send")
+            .unwrap();
+        assert!(outbound
+            .respond(Message::Text(RESPONSE.into()))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn disciplines_order_notifications() {
+        let text = |text: &str| Message::Text(text.into());
+        for (discipline, expected) in [
+            (QueueDiscipline::Fifo, ["v0", "n0", "n1", "n2", "n3"]),
+            (QueueDiscipline::Lifo, ["n3", "n2", "n1", "v0", "n0"]),
+            (QueueDiscipline::Priority, ["n1", "n3", "n2", "v0", "n0"]),
+        ] {
+            let (sink, client) = sink_channel::channel(4 * QUEUED_MESSAGES);
+            let (outbound, writer) = Outbound::start(
+                sink,
+                OutboundPriority::ResponsesFirst,
+                discipline,
+                SEND_TIMEOUT,
+                Arc::default(),
+            );
+            // everything queued before the writer gets a chance to run
+            for (i, priority) in [0, 2, 1, 2].into_iter().enumerate() {
+                let preceded_by = (i == 0).then(|| text("v0"));
+                let notification = Notification::new(text(&format!("n{i}")))
+                    .preceded_by(preceded_by)
+                    .with_priority(priority);
+                outbound.notify(notification).await.unwrap();
+            }
+            drop(outbound);
+            writer.await.unwrap();
+
+            let sent: Vec<_> = client
+                .map(|msg| match msg {
+                    Message::Text(text) => text,
+                    other => panic!("unexpected message: {other:?}"),
+                })
+                .collect()
+                .await;
+            assert_eq!(sent, expected, "{discipline:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn bandwidth_shared_by_connections() {
+        const RATE: usize = 3000;
+        const MESSAGE: usize = 100;
+        let bandwidth = Arc::new(Bandwidth::new(std::num::NonZeroU64::new(RATE as u64)));
+        // saturated from the start
+        bandwidth.take(&Message::Binary(vec![0; RATE])).await;
+        let start = tokio::time::Instant::now();
+        let mut connections = Vec::new();
+        for _ in 0..3 {
+            let (sink, client) = sink_channel::channel(4 * QUEUED_MESSAGES);
+            let (outbound, writer) = Outbound::start(
+                sink,
+                OutboundPriority::ResponsesFirst,
+                QueueDiscipline::Fifo,
+                SEND_TIMEOUT,
+                bandwidth.clone(),
+            );
+            for _ in 0..10 {
+                outbound
+                    .notify(Message::Text("x".repeat(MESSAGE)))
+                    .await
+                    .unwrap();
+            }
+            drop(outbound);
+            connections.push(tokio::spawn(async move {
+                writer.await.unwrap();
+                let written = client.count().await * MESSAGE;
+                (written, start.elapsed())
+            }));
+        }
+
+        let mut total = 0;
+        for connection in connections {
+            let (written, done) = connection.await.unwrap();
+            total += written;
+            // the connections took turns, none was done well before the others
+            assert!(done >= Duration::from_millis(800), "done after {done:?}");
+        }
+        let elapsed = start.elapsed();
+        assert_eq!(total, RATE);
+        assert!(elapsed >= Duration::from_secs(1), "took {elapsed:?}");
+    }
+}
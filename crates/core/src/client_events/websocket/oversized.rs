@@ -0,0 +1,171 @@
+//! Responses too large to be sent to the client as a single websocket message.
+//!
+//! Clients which send the [`CHUNKED_RESPONSES_HEADER`] when connecting get an oversized
+//! response as a [`ControlResponse::Chunked`] text message followed by that many binary
+//! messages, which concatenated are the serialized response. Any other client, or every one
+//! when chunking is disabled, gets an error asking to request the data in pages instead.
+
+use axum::extract::ws::Message;
+use freenet_stdlib::client_api::{ClientError, ErrorKind, HostResponse};
+
+use crate::{
+    config::{OversizedResponses, WebsocketApiConfig},
+    util::EncodingProtocol,
+};
+
+use super::control::ControlResponse;
+
+/// Header a client sends in the handshake when it can put together chunked responses.
+pub(super) const CHUNKED_RESPONSES_HEADER: &str = "x-chunked-responses";
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct ResponseLimit {
+    max_size: usize,
+    chunk: bool,
+}
+
+impl ResponseLimit {
+    pub fn new(config: &WebsocketApiConfig) -> Self {
+        Self {
+            // there has to be room for data in each chunk
+            max_size: config.max_response_size.max(1),
+            chunk: config.oversized_responses == OversizedResponses::Chunk,
+        }
+    }
+
+    /// The limit for a connection, oversized responses are only chunked if the client
+    /// accepts them.
+    pub fn for_client(self, accepts_chunks: bool) -> Self {
+        Self {
+            chunk: self.chunk && accepts_chunks,
+            ..self
+        }
+    }
+
+    /// The messages to send for the serialized response.
+    pub fn messages(
+        &self,
+        serialized: Vec<u8>,
+        encoding_protoc: EncodingProtocol,
+    ) -> anyhow::Result<Vec<Message>> {
+        let size = serialized.len();
+        if size <= self.max_size {
+            return Ok(vec![Message::Binary(serialized)]);
+        }
+        if self.chunk {
+            let chunks = serialized.chunks(self.max_size);
+            let mut messages = Vec::with_capacity(chunks.len() + 1);
+            messages.push(
+                ControlResponse::Chunked {
+                    size,
+                    chunks: chunks.len(),
+                }
+                .into_message(),
+            );
+            messages.extend(chunks.map(|chunk| Message::Binary(chunk.to_vec())));
+            return Ok(messages);
+        }
+        tracing::warn!(
+            size,
+            max_size = self.max_size,
+            "rejecting oversized response"
+        );
+        let err: ClientError = ErrorKind::Unhandled {
+            cause: format!(
+                "response of {size} bytes exceeds the maximum message size of {} bytes, \
+                 request the data in pages instead (e.g. with a range query)",
+                self.max_size
+            )
+            .into(),
+        }
+        .into();
+        let serialized = match encoding_protoc {
+            EncodingProtocol::Flatbuffers => err.into_fbs_bytes()?,
+            EncodingProtocol::Native => bincode::serialize(&Err::<HostResponse, _>(err))?,
+        };
+        Ok(vec![Message::Binary(serialized)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use freenet_stdlib::{
+        client_api::ContractResponse,
+        prelude::{ContractInstanceId, ContractKey, WrappedState},
+    };
+
+    use super::*;
+
+    fn oversized_response() -> Vec<u8> {
+        let response: Result<HostResponse, ClientError> = Ok(ContractResponse::GetResponse {
+            key: ContractKey::from(ContractInstanceId::new([1; 32])),
+            contract: None,
+            state: WrappedState::new(vec![7; 4096]),
+        }
+        .into());
+        bincode::serialize(&response).unwrap()
+    }
+
+    fn limit(oversized_responses: OversizedResponses) -> ResponseLimit {
+        ResponseLimit::new(&WebsocketApiConfig {
+            max_response_size: 1024,
+            oversized_responses,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn oversized_response_rejected() {
+        let serialized = oversized_response();
+        // chunking is only done for clients which accept it
+        for limit in [
+            limit(OversizedResponses::Chunk).for_client(false),
+            limit(OversizedResponses::Reject).for_client(true),
+        ] {
+            let messages = limit
+                .messages(serialized.clone(), EncodingProtocol::Native)
+                .unwrap();
+            let [Message::Binary(msg)] = &messages[..] else {
+                panic!("expected a single error message");
+            };
+            let response: Result<HostResponse, ClientError> = bincode::deserialize(msg).unwrap();
+            let err = response.unwrap_err().to_string();
+            assert!(err.contains("exceeds the maximum message size"), "{err}");
+            assert!(err.contains("pages"), "{err}");
+        }
+    }
+
+    #[test]
+    fn oversized_response_chunked() {
+        let serialized = oversized_response();
+        let limit = limit(OversizedResponses::Chunk).for_client(true);
+        let messages = limit
+            .messages(serialized.clone(), EncodingProtocol::Native)
+            .unwrap();
+        let Message::Text(header) = &messages[0] else {
+            panic!("expected the chunked response header");
+        };
+        let header: serde_json::Value = serde_json::from_str(header).unwrap();
+        let chunks = serialized.len().div_ceil(1024);
+        assert_eq!(
+            header,
+            serde_json::json!({ "chunked": { "size": serialized.len(), "chunks": chunks } })
+        );
+        assert_eq!(messages.len(), chunks + 1);
+        let mut reassembled = Vec::new();
+        for msg in &messages[1..] {
+            let Message::Binary(chunk) = msg else {
+                panic!("expected a binary chunk");
+            };
+            assert!(chunk.len() <= 1024);
+            reassembled.extend_from_slice(chunk);
+        }
+        assert_eq!(reassembled, serialized);
+
+        // responses within the limit are sent as they are
+        let small = limit
+            .messages(vec![1; 1024], EncodingProtocol::Native)
+            .unwrap();
+        assert!(matches!(&small[..], [Message::Binary(msg)] if msg.len() == 1024));
+    }
+}
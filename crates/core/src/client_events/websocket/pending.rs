@@ -0,0 +1,197 @@
+//! Accounting of the responses handed to websocket connections but not yet written to them.
+//!
+//! Responses are buffered per connection until its socket is ready, a slow client could
+//! make the node hold an unbounded amount of memory. Once the bytes held across all the
+//! connections reach the configured cap, sending further responses waits until some are
+//! drained, which stops the node from pulling new results from the executor meanwhile.
+
+use std::sync::Arc;
+
+use freenet_stdlib::client_api::HostResponse;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::sync::{mpsc, Notify};
+
+use crate::{client_events::HostResult, server::HostCallbackResult};
+
+pub(crate) struct PendingResponses {
+    max_bytes: usize,
+    held: Mutex<usize>,
+    drained: Notify,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PendingResponsesSnapshot {
+    pub bytes: usize,
+    pub max_bytes: usize,
+}
+
+impl PendingResponses {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            held: Mutex::new(0),
+            drained: Notify::new(),
+        }
+    }
+
+    pub fn snapshot(&self) -> PendingResponsesSnapshot {
+        PendingResponsesSnapshot {
+            bytes: *self.held.lock(),
+            max_bytes: self.max_bytes,
+        }
+    }
+
+    /// Sends a response to a connection, waiting first for room if the cap is reached.
+    pub async fn send(
+        &self,
+        ch: &mpsc::UnboundedSender<HostCallbackResult>,
+        msg: HostCallbackResult,
+    ) -> Result<(), mpsc::error::SendError<HostCallbackResult>> {
+        let size = message_size(&msg);
+        self.reserve(size).await;
+        ch.send(msg).inspect_err(|_| self.release(size))
+    }
+
+    async fn reserve(&self, size: usize) {
+        loop {
+            let drained = self.drained.notified();
+            {
+                let mut held = self.held.lock();
+                // a single response larger than the cap still goes through once nothing else is held
+                if *held == 0 || *held + size <= self.max_bytes {
+                    *held += size;
+                    return;
+                }
+            }
+            tracing::debug!(size, "pending responses over capacity, waiting to drain");
+            drained.await;
+        }
+    }
+
+    fn release(&self, size: usize) {
+        if size == 0 {
+            return;
+        }
+        let mut held = self.held.lock();
+        *held = held.saturating_sub(size);
+        self.drained.notify_waiters();
+    }
+}
+
+/// Receiving end of a connection, releases the accounted responses as they are picked up.
+pub(super) struct PendingReceiver {
+    inner: mpsc::UnboundedReceiver<HostCallbackResult>,
+    pending: Arc<PendingResponses>,
+}
+
+impl PendingReceiver {
+    pub fn new(
+        inner: mpsc::UnboundedReceiver<HostCallbackResult>,
+        pending: Arc<PendingResponses>,
+    ) -> Self {
+        Self { inner, pending }
+    }
+
+    pub async fn recv(&mut self) -> Option<HostCallbackResult> {
+        let msg = self.inner.recv().await?;
+        self.pending.release(message_size(&msg));
+        Some(msg)
+    }
+}
+
+impl Drop for PendingReceiver {
+    fn drop(&mut self) {
+        // the connection is gone, whatever is left won't ever be sent
+        self.inner.close();
+        while let Ok(msg) = self.inner.try_recv() {
+            self.pending.release(message_size(&msg));
+        }
+    }
+}
+
+fn message_size(msg: &HostCallbackResult) -> usize {
+    match msg {
+        HostCallbackResult::Result { result, .. } => response_size(result),
+        _ => 0,
+    }
+}
+
+pub(super) fn response_size(result: &HostResult) -> usize {
+    let size = match result {
+        Ok(HostResponse::Ok) => Ok(0),
+        Ok(res) => bincode::serialized_size(res),
+        Err(err) => bincode::serialized_size(err),
+    };
+    size.unwrap_or_default() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use freenet_stdlib::{
+        client_api::ContractResponse,
+        prelude::{ContractInstanceId, ContractKey, StateSummary},
+    };
+
+    use super::*;
+    use crate::client_events::ClientId;
+
+    const RESPONSE_SIZE: usize = 64 * 1024;
+
+    fn large_response() -> HostCallbackResult {
+        HostCallbackResult::Result {
+            id: ClientId::FIRST,
+            result: Ok(ContractResponse::UpdateResponse {
+                key: ContractKey::from(ContractInstanceId::new([1; 32])),
+                summary: StateSummary::from(vec![0; RESPONSE_SIZE]),
+            }
+            .into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_reader_is_bounded_by_cap() {
+        let max_bytes = 4 * RESPONSE_SIZE;
+        let pending = Arc::new(PendingResponses::new(max_bytes));
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut rx = PendingReceiver::new(rx, pending.clone());
+
+        let sender = {
+            let pending = pending.clone();
+            tokio::spawn(async move {
+                for _ in 0..20 {
+                    pending.send(&tx, large_response()).await.unwrap();
+                }
+            })
+        };
+
+        let mut received = 0;
+        while received < 20 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            assert!(pending.snapshot().bytes <= max_bytes);
+            rx.recv().await.unwrap();
+            received += 1;
+        }
+        sender.await.unwrap();
+        assert_eq!(pending.snapshot().bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn dropped_connection_releases_its_responses() {
+        let pending = Arc::new(PendingResponses::new(usize::MAX));
+        let (tx, rx) = mpsc::unbounded_channel();
+        let rx = PendingReceiver::new(rx, pending.clone());
+        for _ in 0..3 {
+            pending.send(&tx, large_response()).await.unwrap();
+        }
+        assert!(pending.snapshot().bytes > 3 * RESPONSE_SIZE);
+
+        drop(rx);
+        assert_eq!(pending.snapshot().bytes, 0);
+        assert!(pending.send(&tx, large_response()).await.is_err());
+        assert_eq!(pending.snapshot().bytes, 0);
+    }
+}
@@ -0,0 +1,133 @@
+//! The contracts each connected client is subscribed to.
+//!
+//! A subscription is only held once the node acknowledges it, until the client unsubscribes
+//! or goes away. Those waiting for the acknowledgement are counted along with the held ones
+//! across all clients, so the node can turn down new ones once the configured maximum for the
+//! whole node is reached, however they are spread over clients, bounding the notifications it
+//! has to fan out.
+
+use std::collections::{HashMap, HashSet};
+
+use freenet_stdlib::prelude::ContractKey;
+
+use super::ClientId;
+
+#[derive(Default)]
+pub(super) struct SubscriptionRegistry {
+    clients: HashMap<ClientId, HashSet<ContractKey>>,
+    /// Subscriptions asked of the node and not acknowledged yet.
+    requested: HashMap<ClientId, HashSet<ContractKey>>,
+    active: usize,
+    max_active: Option<usize>,
+}
+
+impl SubscriptionRegistry {
+    pub fn new(max_active: Option<usize>) -> Self {
+        Self {
+            max_active,
+            ..Default::default()
+        }
+    }
+
+    pub fn of(&self, client: &ClientId) -> Option<&HashSet<ContractKey>> {
+        self.clients.get(client)
+    }
+
+    pub fn contains(&self, client: &ClientId, key: &ContractKey) -> bool {
+        self.clients
+            .get(client)
+            .is_some_and(|keys| keys.contains(key))
+    }
+
+    /// Whether the client holds the subscription or is waiting for it to be acknowledged.
+    pub fn holds(&self, client: &ClientId, key: &ContractKey) -> bool {
+        self.contains(client, key)
+            || self
+                .requested
+                .get(client)
+                .is_some_and(|keys| keys.contains(key))
+    }
+
+    /// Subscriptions of all clients, including those not acknowledged yet.
+    pub fn active(&self) -> usize {
+        self.active
+    }
+
+    /// The maximum of subscriptions reached, if so, no more are taken other than those
+    /// clients already hold.
+    pub fn full(&self) -> Option<usize> {
+        self.max_active.filter(|max| self.active >= *max)
+    }
+
+    /// Accounts a subscription asked of the node, held once acknowledged.
+    pub fn request(&mut self, client: ClientId, key: ContractKey) {
+        if !self.holds(&client, &key) {
+            self.requested.entry(client).or_default().insert(key);
+            self.active += 1;
+        }
+    }
+
+    /// Holds the subscription requested if the node took it, drops it otherwise. Those the
+    /// client unsubscribed from meanwhile stay dropped.
+    pub fn acknowledge(&mut self, client: &ClientId, key: &ContractKey, subscribed: bool) {
+        if !remove_key(&mut self.requested, client, key) {
+            return;
+        }
+        if subscribed {
+            self.clients.entry(*client).or_default().insert(*key);
+        } else {
+            self.active -= 1;
+        }
+    }
+
+    pub fn remove(&mut self, client: &ClientId, key: &ContractKey) {
+        if remove_key(&mut self.clients, client, key)
+            || remove_key(&mut self.requested, client, key)
+        {
+            self.active -= 1;
+        }
+    }
+
+    /// Drops all the subscriptions of the client, returning how many it had.
+    pub fn remove_client(&mut self, client: &ClientId) -> usize {
+        let removed = [&mut self.clients, &mut self.requested]
+            .into_iter()
+            .map(|keys| keys.remove(client).map_or(0, |keys| keys.len()))
+            .sum();
+        self.active -= removed;
+        removed
+    }
+
+    pub fn clear(&mut self) {
+        self.clients.clear();
+        self.requested.clear();
+        self.active = 0;
+    }
+
+    #[cfg(test)]
+    pub fn clients(&self) -> &HashMap<ClientId, HashSet<ContractKey>> {
+        &self.clients
+    }
+
+    #[cfg(test)]
+    pub fn replace(&mut self, clients: HashMap<ClientId, HashSet<ContractKey>>) {
+        self.active = clients.values().map(HashSet::len).sum();
+        self.clients = clients;
+        self.requested.clear();
+    }
+}
+
+fn remove_key(
+    clients: &mut HashMap<ClientId, HashSet<ContractKey>>,
+    client: &ClientId,
+    key: &ContractKey,
+) -> bool {
+    let Some(keys) = clients.get_mut(client) else {
+        return false;
+    };
+    let removed = keys.remove(key);
+    if keys.is_empty() {
+        clients.remove(client);
+    }
+    removed
+}
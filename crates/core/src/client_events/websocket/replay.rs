@@ -0,0 +1,363 @@
+//! Recent contract updates retained so reconnecting clients can catch up from a known version.
+//!
+//! The node numbers the updates it notifies subscribers of from a single sequence, every
+//! update to any contract taking the next version, so a version is never reused for another
+//! update even once the ones of a contract are no longer retained. A client which already
+//! holds the state at some version can ask for the updates since then instead of fetching
+//! the whole state again, as long as those are still retained.
+//!
+//! The node sends the same update to every connection subscribed to the contract, the first
+//! one receiving it records it and the others find it among those retained after the last
+//! one they received. Versions only hold for as long as the node runs, they are handed out
+//! along with the session of the node which numbered them, and one from another session is
+//! answered with a [`Replay::Snapshot`].
+//!
+//! Each contract retains its latest updates up to the configured [`RetainedUpdates`], those
+//! past the maximum count or age are evicted and a client asking for them is answered with a
+//! [`Replay::Snapshot`] instead, i.e. it has to resync from the current state. Past the
+//! maximum number of contracts, the contract updated least recently is forgotten.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Instant,
+};
+
+use freenet_stdlib::prelude::{ContractInstanceId, ContractKey, UpdateData};
+use parking_lot::Mutex;
+
+use super::listener::Causality;
+use crate::{
+    config::RetainedUpdates,
+    util::time_source::{InstantTimeSrc, TimeSource},
+};
+
+pub(super) struct UpdateLog<T: TimeSource = InstantTimeSrc> {
+    limits: RetainedUpdates,
+    /// Identifies the versions numbered by this node while it runs.
+    session: u64,
+    contracts: Mutex<Contracts>,
+    time_source: T,
+}
+
+#[derive(Default)]
+struct Contracts {
+    /// Version of the last update recorded for any contract.
+    sequence: u64,
+    logs: HashMap<ContractInstanceId, ContractLog>,
+}
+
+#[derive(Default)]
+struct ContractLog {
+    version: u64,
+    retained: VecDeque<Retained>,
+}
+
+struct Retained {
+    /// Version of the contract the update was applied to.
+    previous: u64,
+    causality: Causality,
+    applied: Instant,
+    update: UpdateData<'static>,
+}
+
+impl ContractLog {
+    fn evict(&mut self, limits: &RetainedUpdates, now: Instant) {
+        while self.retained.len() > limits.max_count
+            || matches!(
+                self.retained.front(),
+                Some(retained) if now.duration_since(retained.applied) >= limits.max_age()
+            )
+        {
+            self.retained.pop_front();
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Replay {
+    /// The updates applied after the requested version, oldest first.
+    Updates {
+        version: u64,
+        updates: Vec<(Causality, UpdateData<'static>)>,
+    },
+    /// The requested version is unknown or no longer retained.
+    Snapshot { version: u64 },
+}
+
+impl Default for UpdateLog {
+    fn default() -> Self {
+        Self::new(RetainedUpdates::default())
+    }
+}
+
+impl UpdateLog {
+    pub fn new(limits: RetainedUpdates) -> Self {
+        Self::with_time_source(limits, InstantTimeSrc::new())
+    }
+}
+
+impl<T: TimeSource> UpdateLog<T> {
+    fn with_time_source(limits: RetainedUpdates, time_source: T) -> Self {
+        Self {
+            limits,
+            // within the integers javascript clients represent exactly
+            session: rand::random::<u64>() >> 11,
+            contracts: Mutex::default(),
+            time_source,
+        }
+    }
+
+    pub fn session(&self) -> u64 {
+        self.session
+    }
+
+    /// Records an update notification for the contract received by a subscription which
+    /// last received the one at version `seen`, returning where it leaves the contract.
+    pub fn record(&self, key: &ContractKey, seen: &mut u64, update: &UpdateData<'_>) -> Causality {
+        let contracts = &mut *self.contracts.lock();
+        let log = contracts.logs.entry(*key.id()).or_default();
+        // recorded already by another subscription it was sent to
+        if let Some(retained) = log
+            .retained
+            .iter()
+            .find(|retained| retained.causality.version > *seen && retained.update == *update)
+        {
+            *seen = retained.causality.version;
+            return retained.causality;
+        }
+        contracts.sequence += 1;
+        let causality = Causality {
+            version: contracts.sequence,
+            base: match update {
+                UpdateData::State(_) => None,
+                _ => Some(log.version),
+            },
+        };
+        let now = self.time_source.now();
+        log.retained.push_back(Retained {
+            previous: log.version,
+            causality,
+            applied: now,
+            update: update.clone().into_owned(),
+        });
+        log.version = causality.version;
+        log.evict(&self.limits, now);
+        *seen = causality.version;
+        if contracts.logs.len() > self.limits.max_contracts {
+            let forgotten = contracts
+                .logs
+                .iter()
+                .min_by_key(|(_, log)| log.version)
+                .map(|(id, _)| *id);
+            if let Some(forgotten) = forgotten {
+                contracts.logs.remove(&forgotten);
+            }
+        }
+        causality
+    }
+
+    /// Version the contract is at, as numbered by the updates recorded for it.
+    pub fn version(&self, key: &ContractKey) -> u64 {
+        let contracts = self.contracts.lock();
+        contracts.logs.get(key.id()).map_or(0, |log| log.version)
+    }
+
+    /// The updates since version `since`, numbered by the node in `session`.
+    pub fn replay(&self, key: &ContractKey, session: Option<u64>, since: u64) -> Replay {
+        let contracts = &mut *self.contracts.lock();
+        let Some(log) = contracts.logs.get_mut(key.id()) else {
+            return Replay::Snapshot { version: 0 };
+        };
+        let version = log.version;
+        if session != Some(self.session) {
+            return Replay::Snapshot { version };
+        }
+        if since == version {
+            return Replay::Updates {
+                version,
+                updates: vec![],
+            };
+        }
+        log.evict(&self.limits, self.time_source.now());
+        let Some(first) = log
+            .retained
+            .iter()
+            .position(|retained| retained.previous == since)
+        else {
+            return Replay::Snapshot { version };
+        };
+        Replay::Updates {
+            version,
+            updates: log
+                .retained
+                .range(first..)
+                .map(|retained| (retained.causality, retained.update.clone()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use freenet_stdlib::prelude::StateDelta;
+
+    use crate::util::time_source::MockTimeSource;
+
+    use super::*;
+
+    fn delta(n: usize) -> UpdateData<'static> {
+        UpdateData::Delta(StateDelta::from(n.to_le_bytes().to_vec()))
+    }
+
+    fn updates(replay: Replay) -> Vec<UpdateData<'static>> {
+        let Replay::Updates { updates, .. } = replay else {
+            panic!("expected the retained updates");
+        };
+        updates.into_iter().map(|(_, update)| update).collect()
+    }
+
+    #[test]
+    fn replay_recent_version_with_deltas_only() {
+        let log = UpdateLog::default();
+        let session = Some(log.session());
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let (mut first, mut second) = (0, 0);
+        for n in 1..=5 {
+            let causality = log.record(&key, &mut first, &delta(n));
+            assert_eq!(causality.version, n as u64);
+            assert_eq!(causality.base, Some(n as u64 - 1));
+            // the copy sent to another subscription
+            assert_eq!(log.record(&key, &mut second, &delta(n)), causality);
+        }
+
+        assert_eq!(updates(log.replay(&key, session, 3)), [delta(4), delta(5)]);
+        assert_eq!(
+            log.replay(&key, session, 5),
+            Replay::Updates {
+                version: 5,
+                updates: vec![]
+            }
+        );
+        assert_eq!(
+            log.replay(&key, session, 6),
+            Replay::Snapshot { version: 5 }
+        );
+        // numbered by a node in another session
+        assert_eq!(log.replay(&key, None, 3), Replay::Snapshot { version: 5 });
+        assert_eq!(
+            log.replay(&key, Some(log.session() + 1), 3),
+            Replay::Snapshot { version: 5 }
+        );
+    }
+
+    #[test]
+    fn same_update_recorded_once_whatever_the_order_received() {
+        let log = UpdateLog::default();
+        let key = ContractKey::from(ContractInstanceId::new([4; 32]));
+        let (mut first, mut second) = (0, 0);
+        log.record(&key, &mut first, &delta(1));
+        log.record(&key, &mut first, &delta(2));
+        // the same update twice in a row is two updates
+        log.record(&key, &mut first, &delta(2));
+        assert_eq!(log.record(&key, &mut second, &delta(1)).version, 1);
+        assert_eq!(log.record(&key, &mut second, &delta(2)).version, 2);
+        assert_eq!(log.record(&key, &mut second, &delta(2)).version, 3);
+        assert_eq!(log.version(&key), 3);
+    }
+
+    #[test]
+    fn versions_not_reused_across_contracts() {
+        let limits = RetainedUpdates {
+            max_contracts: 1,
+            ..Default::default()
+        };
+        let log = UpdateLog::new(limits);
+        let session = Some(log.session());
+        let [first, second] = [1, 2].map(|n| ContractKey::from(ContractInstanceId::new([n; 32])));
+        let mut seen = 0;
+        log.record(&first, &mut seen, &delta(1));
+        let mut seen = 0;
+        assert_eq!(log.record(&second, &mut seen, &delta(1)).version, 2);
+        // forgotten once over the maximum number of contracts
+        assert_eq!(log.version(&first), 0);
+        let mut seen = 0;
+        let causality = log.record(&first, &mut seen, &delta(2));
+        assert_eq!(causality.version, 3);
+        assert_eq!(
+            log.replay(&first, session, 1),
+            Replay::Snapshot { version: 3 }
+        );
+    }
+
+    #[test]
+    fn fall_back_to_snapshot_once_evicted() {
+        let log = UpdateLog::default();
+        let session = Some(log.session());
+        let key = ContractKey::from(ContractInstanceId::new([2; 32]));
+        assert_eq!(
+            log.replay(&key, session, 0),
+            Replay::Snapshot { version: 0 }
+        );
+
+        let max_count = RetainedUpdates::default().max_count;
+        let total = max_count + 10;
+        let mut seen = 0;
+        for n in 1..=total {
+            log.record(&key, &mut seen, &delta(n));
+        }
+        assert_eq!(
+            log.replay(&key, session, 1),
+            Replay::Snapshot {
+                version: total as u64
+            }
+        );
+        assert_eq!(updates(log.replay(&key, session, 10)).len(), max_count);
+    }
+
+    #[test]
+    fn resync_once_too_old() {
+        let limits = RetainedUpdates {
+            max_count: 3,
+            max_age_secs: 60,
+            ..Default::default()
+        };
+        let mut log = UpdateLog::with_time_source(limits, MockTimeSource::new(Instant::now()));
+        let session = Some(log.session());
+        let key = ContractKey::from(ContractInstanceId::new([3; 32]));
+        let mut seen = 0;
+        log.record(&key, &mut seen, &delta(1));
+        log.time_source.advance_time(Duration::from_secs(30));
+        for n in 2..=3 {
+            log.record(&key, &mut seen, &delta(n));
+        }
+        assert_eq!(updates(log.replay(&key, session, 0)).len(), 3);
+
+        // the first update is over the maximum age, the others still retained
+        log.time_source.advance_time(Duration::from_secs(30));
+        assert_eq!(
+            log.replay(&key, session, 0),
+            Replay::Snapshot { version: 3 }
+        );
+        assert_eq!(updates(log.replay(&key, session, 1)), [delta(2), delta(3)]);
+
+        // the oldest retained is evicted past the maximum count
+        log.record(&key, &mut seen, &delta(4));
+        log.record(&key, &mut seen, &delta(5));
+        assert_eq!(
+            log.replay(&key, session, 1),
+            Replay::Snapshot { version: 5 }
+        );
+        assert_eq!(
+            updates(log.replay(&key, session, 2)),
+            [delta(3), delta(4), delta(5)]
+        );
+
+        log.time_source.advance_time(Duration::from_secs(60));
+        assert_eq!(
+            log.replay(&key, session, 4),
+            Replay::Snapshot { version: 5 }
+        );
+    }
+}
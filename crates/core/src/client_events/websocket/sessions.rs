@@ -0,0 +1,186 @@
+//! Sessions websocket clients carry over to a new connection, e.g. once their network changes
+//! and they have to connect again, possibly through another address of the node.
+//!
+//! A client sends a [`ControlFrame::Session`](super::control::ControlFrame::Session) frame to
+//! get a resumption token for its connection, and from then on the contracts it subscribes to
+//! are kept along with the contract it is attested for. Connecting again, it presents the token
+//! in a [`ControlFrame::Migrate`](super::control::ControlFrame::Migrate) frame and the new
+//! connection continues the session: it is attested as the old one was and subscribed again to
+//! the same contracts. The token is good for a single migration, the new connection gets another
+//! one in its place, and the attestation is only carried over while its auth token is still
+//! attested for the same contract and not expired.
+//!
+//! Sessions are held in memory by the node serving the websocket API, known to all its
+//! addresses but neither shared with other nodes nor kept across restarts; those answer the
+//! token as unknown and start a new session, the client then carries on as it would after
+//! reconnecting.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use freenet_stdlib::prelude::{ContractInstanceId, ContractKey};
+use parking_lot::Mutex;
+
+use crate::{
+    client_events::AuthToken,
+    server::{http_gateway::AttestedContractMap, token_expiry::TokenExpiryCheck},
+};
+
+struct Session {
+    auth: Option<(AuthToken, ContractInstanceId)>,
+    subscriptions: HashSet<ContractKey>,
+    used: Instant,
+}
+
+pub(super) struct Migrated {
+    /// The resumption token replacing the one migrated from.
+    pub token: String,
+    pub auth: Option<(AuthToken, ContractInstanceId)>,
+    pub subscriptions: Vec<ContractKey>,
+}
+
+pub(super) struct Sessions {
+    /// How long a session is kept after it was last used.
+    ttl: Duration,
+    attested_contracts: AttestedContractMap,
+    token_expiry: TokenExpiryCheck,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl Sessions {
+    pub fn new(
+        ttl: Duration,
+        attested_contracts: AttestedContractMap,
+        token_expiry: TokenExpiryCheck,
+    ) -> Self {
+        Self {
+            ttl,
+            attested_contracts,
+            token_expiry,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts a session for a connection already subscribed to the given contracts, returning
+    /// its resumption token.
+    pub fn open(
+        &self,
+        auth: Option<(AuthToken, ContractInstanceId)>,
+        subscriptions: impl IntoIterator<Item = ContractKey>,
+    ) -> String {
+        let token = AuthToken::generate().as_str().to_owned();
+        let now = Instant::now();
+        let sessions = &mut *self.sessions.lock();
+        sessions.retain(|_, session| now.duration_since(session.used) < self.ttl);
+        sessions.insert(
+            token.clone(),
+            Session {
+                auth,
+                subscriptions: subscriptions.into_iter().collect(),
+                used: now,
+            },
+        );
+        token
+    }
+
+    pub fn subscribed(&self, token: &str, key: ContractKey) {
+        if let Some(session) = self.sessions.lock().get_mut(token) {
+            session.subscriptions.insert(key);
+            session.used = Instant::now();
+        }
+    }
+
+    pub fn unsubscribed(&self, token: &str, key: &ContractKey) {
+        if let Some(session) = self.sessions.lock().get_mut(token) {
+            session.subscriptions.remove(key);
+        }
+    }
+
+    /// The session of the token, if known and not expired, continued from then on by the
+    /// connection migrating to it under the new token returned; the one presented is no longer
+    /// known after this.
+    pub fn migrate(&self, token: &str) -> Option<Migrated> {
+        let sessions = &mut *self.sessions.lock();
+        let mut session = sessions.remove(token)?;
+        if session.used.elapsed() >= self.ttl {
+            return None;
+        }
+        session.auth = session.auth.filter(|auth| self.still_attested(auth));
+        session.used = Instant::now();
+        let token = AuthToken::generate().as_str().to_owned();
+        let migrated = Migrated {
+            token: token.clone(),
+            auth: session.auth.clone(),
+            subscriptions: session.subscriptions.iter().copied().collect(),
+        };
+        sessions.insert(token, session);
+        Some(migrated)
+    }
+
+    fn still_attested(&self, (token, contract): &(AuthToken, ContractInstanceId)) -> bool {
+        self.attested_contracts
+            .read()
+            .unwrap()
+            .get(token)
+            .is_some_and(|(attested, _, issued)| {
+                attested == contract && self.token_expiry.accepts(*issued)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{client_events::ClientId, config::TokenExpiry};
+
+    use super::*;
+
+    fn sessions(ttl: Duration) -> (Sessions, AttestedContractMap) {
+        let attested = AttestedContractMap::default();
+        let check = TokenExpiryCheck::new(TokenExpiry::default());
+        (Sessions::new(ttl, attested.clone(), check), attested)
+    }
+
+    #[test]
+    fn expired_sessions_unknown() {
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let (sessions, _) = sessions(Duration::from_millis(50));
+        let token = sessions.open(None, []);
+        sessions.subscribed(&token, key);
+        let migrated = sessions.migrate(&token).unwrap();
+        assert_eq!(migrated.subscriptions, [key]);
+        assert!(sessions.migrate("unknown").is_none());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(sessions.migrate(&migrated.token).is_none());
+    }
+
+    #[test]
+    fn tokens_migrated_once() {
+        let (sessions, _) = sessions(Duration::from_secs(60));
+        let token = sessions.open(None, []);
+        let migrated = sessions.migrate(&token).unwrap();
+        assert_ne!(migrated.token, token);
+        assert!(sessions.migrate(&token).is_none());
+        assert!(sessions.migrate(&migrated.token).is_some());
+    }
+
+    #[test]
+    fn attestation_kept_while_still_attested() {
+        let contract = ContractInstanceId::new([1; 32]);
+        let auth_token = AuthToken::generate();
+        let (sessions, attested) = sessions(Duration::from_secs(60));
+        attested.write().unwrap().insert(
+            auth_token.clone(),
+            (contract, ClientId::FIRST, Instant::now()),
+        );
+        let token = sessions.open(Some((auth_token.clone(), contract)), []);
+        let migrated = sessions.migrate(&token).unwrap();
+        assert_eq!(migrated.auth.map(|(_, contract)| contract), Some(contract));
+
+        attested.write().unwrap().remove(&auth_token);
+        let migrated = sessions.migrate(&migrated.token).unwrap();
+        assert!(migrated.auth.is_none());
+    }
+}
@@ -0,0 +1,122 @@
+//! Encoded get responses with the state of a contract, shared by every connection sending them.
+//!
+//! When many clients fetch the same large contract at once, e.g. subscribers catching up
+//! through a snapshot, each connection would serialize the same state on its own. The latest
+//! encoding of the state of each contract is kept instead, per encoding protocol, and the
+//! connections sending that same state reuse it. A connection encoding a state waits for
+//! any other one already encoding it.
+
+use std::{collections::HashMap, sync::Arc};
+
+use freenet_stdlib::prelude::{ContractInstanceId, WrappedState};
+use parking_lot::Mutex;
+
+use crate::util::EncodingProtocol;
+
+struct Encoded {
+    state: WrappedState,
+    bytes: Arc<[u8]>,
+}
+
+impl Encoded {
+    fn is_of(&self, state: &WrappedState) -> bool {
+        // the states handed out by the store share their buffer, no need to compare those
+        std::ptr::eq(self.state.as_ref(), state.as_ref()) || self.state.as_ref() == state.as_ref()
+    }
+}
+
+type Latest = Arc<Mutex<Option<Encoded>>>;
+
+#[derive(Default)]
+pub(super) struct SnapshotEncodings {
+    latest: Mutex<HashMap<(ContractInstanceId, EncodingProtocol), Latest>>,
+}
+
+impl SnapshotEncodings {
+    /// The encoding of a response with `state` for the contract, made with `encode` unless
+    /// there is one already.
+    pub fn encoded(
+        &self,
+        contract: ContractInstanceId,
+        state: &WrappedState,
+        encoding: EncodingProtocol,
+        encode: impl FnOnce() -> anyhow::Result<Vec<u8>>,
+    ) -> anyhow::Result<Arc<[u8]>> {
+        let latest = self
+            .latest
+            .lock()
+            .entry((contract, encoding))
+            .or_default()
+            .clone();
+        let mut latest = latest.lock();
+        if let Some(encoded) = latest.as_ref().filter(|encoded| encoded.is_of(state)) {
+            return Ok(encoded.bytes.clone());
+        }
+        let bytes: Arc<[u8]> = encode()?.into();
+        tracing::trace!(%contract, %encoding, size = bytes.len(), "encoded state snapshot");
+        *latest = Some(Encoded {
+            state: state.clone(),
+            bytes: bytes.clone(),
+        });
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn simultaneous_subscribers_share_encoding() {
+        let snapshots = SnapshotEncodings::default();
+        let contract = ContractInstanceId::new([1; 32]);
+        let state = WrappedState::new(vec![7; 1024 * 1024]);
+        let encodings = AtomicUsize::new(0);
+        let encode = |state: &WrappedState| {
+            encodings.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            Ok(bincode::serialize(state)?)
+        };
+
+        let sent: Vec<_> = std::thread::scope(|scope| {
+            let subscribers: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        snapshots.encoded(contract, &state, EncodingProtocol::Native, || {
+                            encode(&state)
+                        })
+                    })
+                })
+                .collect();
+            subscribers
+                .into_iter()
+                .map(|subscriber| subscriber.join().unwrap().unwrap())
+                .collect()
+        });
+        assert_eq!(encodings.load(Ordering::SeqCst), 1);
+        assert!(sent.iter().all(|bytes| Arc::ptr_eq(bytes, &sent[0])));
+
+        // a copy of the same state is still the same snapshot
+        let copy = WrappedState::new(state.as_ref().to_vec());
+        snapshots
+            .encoded(contract, &copy, EncodingProtocol::Native, || encode(&copy))
+            .unwrap();
+        assert_eq!(encodings.load(Ordering::SeqCst), 1);
+
+        let updated = WrappedState::new(vec![8; 16]);
+        snapshots
+            .encoded(contract, &updated, EncodingProtocol::Native, || {
+                encode(&updated)
+            })
+            .unwrap();
+        assert_eq!(encodings.load(Ordering::SeqCst), 2);
+        snapshots
+            .encoded(contract, &updated, EncodingProtocol::Flatbuffers, || {
+                encode(&updated)
+            })
+            .unwrap();
+        assert_eq!(encodings.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,490 @@
+//! Per-tenant accounting of the resources consumed by websocket clients.
+//!
+//! Every connection is associated with a tenant, which is either the identity of the client
+//! certificate it was verified with or derived from the contract attested by the connection's
+//! auth token; nothing a client merely claims decides it. Connections which can't be
+//! associated to any tenant share the default tenant. Limits are enforced for each tenant
+//! independently, and a tenant left without connections nor subscriptions is forgotten.
+//!
+//! A subscription counts against its tenant from the moment it is requested until it ends,
+//! either failing to be set up, closed while the connection goes on or along with it.
+//!
+//! Requests over the rate of their tenant are rejected with the time left until the rate
+//! window is renewed, so well-behaved clients can wait that long before retrying. The same
+//! goes for the subscriptions a connection starts, which are limited on their own for each
+//! connection so a client subscribing and unsubscribing over and over doesn't churn through
+//! the node's resources.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use freenet_stdlib::prelude::ContractInstanceId;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::{
+    config::TenantLimits,
+    util::time_source::{InstantTimeSrc, TimeSource},
+};
+
+const DEFAULT_TENANT: &str = "default";
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(transparent)]
+pub(crate) struct TenantId(Arc<str>);
+
+impl TenantId {
+    pub fn resolve(
+        certificate: Option<&str>,
+        attested_contract: Option<&ContractInstanceId>,
+    ) -> Self {
+        match (certificate.map(str::trim), attested_contract) {
+            (Some(tenant), _) if !tenant.is_empty() => Self(tenant.into()),
+            (_, Some(contract)) => Self(contract.to_string().into()),
+            _ => Self(DEFAULT_TENANT.into()),
+        }
+    }
+}
+
+impl Display for TenantId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub(crate) enum TenantLimitExceeded {
+    #[error("maximum number of connections reached")]
+    Connections,
+    #[error("maximum number of subscriptions reached")]
+    Subscriptions,
+    /// Requests are accepted again once the current rate window is over, `retry_after` from
+    /// the rejected one.
+    #[error(
+        "request rate limit exceeded, retry after {}ms",
+        retry_after.as_micros().div_ceil(1000)
+    )]
+    RequestRate { retry_after: Duration },
+    #[error(
+        "subscription rate limit exceeded, retry after {}ms",
+        retry_after.as_micros().div_ceil(1000)
+    )]
+    SubscriptionRate { retry_after: Duration },
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TenantMetrics {
+    pub tenant: TenantId,
+    pub connections: usize,
+    pub subscriptions: usize,
+    pub requests: u64,
+    pub rejected: u64,
+}
+
+#[derive(Default)]
+struct TenantUsage {
+    connections: usize,
+    subscriptions: usize,
+    window: Option<(Instant, u32)>,
+    requests: u64,
+    rejected: u64,
+}
+
+impl TenantUsage {
+    fn is_idle(&self) -> bool {
+        self.connections == 0 && self.subscriptions == 0
+    }
+}
+
+pub(crate) struct TenantRegistry<T: TimeSource = InstantTimeSrc> {
+    limits: TenantLimits,
+    tenants: Mutex<HashMap<TenantId, TenantUsage>>,
+    time_source: T,
+}
+
+impl TenantRegistry<InstantTimeSrc> {
+    pub fn new(limits: TenantLimits) -> Self {
+        Self::with_time_source(limits, InstantTimeSrc::new())
+    }
+}
+
+impl<T: TimeSource> TenantRegistry<T> {
+    fn with_time_source(limits: TenantLimits, time_source: T) -> Self {
+        Self {
+            limits,
+            tenants: Mutex::new(HashMap::new()),
+            time_source,
+        }
+    }
+
+    /// Registers a new connection for the tenant, the connection is released once the returned
+    /// guard is dropped.
+    pub fn connect(
+        self: &Arc<Self>,
+        tenant: TenantId,
+    ) -> Result<TenantConnection<T>, TenantLimitExceeded> {
+        let mut tenants = self.tenants.lock();
+        let usage = tenants.entry(tenant.clone()).or_default();
+        if self
+            .limits
+            .max_connections
+            .is_some_and(|max| usage.connections >= max)
+        {
+            usage.rejected += 1;
+            tracing::debug!(%tenant, "tenant connection limit reached");
+            return Err(TenantLimitExceeded::Connections);
+        }
+        usage.connections += 1;
+        Ok(TenantConnection {
+            registry: self.clone(),
+            tenant,
+            subscribing: None,
+            requested: HashMap::new(),
+        })
+    }
+
+    /// Applies `release` to the usage of the tenant, forgetting it once idle.
+    fn release(&self, tenant: &TenantId, release: impl FnOnce(&mut TenantUsage)) {
+        let mut tenants = self.tenants.lock();
+        if let Some(usage) = tenants.get_mut(tenant) {
+            release(usage);
+            if usage.is_idle() {
+                tenants.remove(tenant);
+            }
+        }
+    }
+
+    pub fn metrics(&self) -> Vec<TenantMetrics> {
+        let mut metrics: Vec<_> = self
+            .tenants
+            .lock()
+            .iter()
+            .map(|(tenant, usage)| TenantMetrics {
+                tenant: tenant.clone(),
+                connections: usage.connections,
+                subscriptions: usage.subscriptions,
+                requests: usage.requests,
+                rejected: usage.rejected,
+            })
+            .collect();
+        metrics.sort_unstable_by(|a, b| a.tenant.0.cmp(&b.tenant.0));
+        metrics
+    }
+}
+
+/// A connection accounted to a tenant.
+pub(crate) struct TenantConnection<T: TimeSource = InstantTimeSrc> {
+    registry: Arc<TenantRegistry<T>>,
+    tenant: TenantId,
+    subscribing: Option<(Instant, u32)>,
+    /// Subscriptions requested which are yet to be set up or fail.
+    requested: HashMap<ContractInstanceId, Vec<TenantSubscription<T>>>,
+}
+
+/// A subscription accounted to a tenant, released once dropped.
+pub(crate) struct TenantSubscription<T: TimeSource = InstantTimeSrc> {
+    registry: Arc<TenantRegistry<T>>,
+    tenant: TenantId,
+}
+
+impl<T: TimeSource> Drop for TenantSubscription<T> {
+    fn drop(&mut self) {
+        self.registry.release(&self.tenant, |usage| {
+            usage.subscriptions = usage.subscriptions.saturating_sub(1);
+        });
+    }
+}
+
+/// Counts one more event in the current rate window, renewing it if over, or returns the time
+/// left until it is if the maximum was already reached.
+fn count_in_window(
+    window: &mut Option<(Instant, u32)>,
+    max: u32,
+    now: Instant,
+) -> Result<(), Duration> {
+    let (start, count) = match *window {
+        Some((start, count)) if now.duration_since(start) < RATE_WINDOW => (start, count),
+        _ => (now, 0),
+    };
+    if count >= max {
+        return Err(RATE_WINDOW.saturating_sub(now.duration_since(start)));
+    }
+    *window = Some((start, count + 1));
+    Ok(())
+}
+
+impl<T: TimeSource> TenantConnection<T> {
+    pub fn tenant(&self) -> &TenantId {
+        &self.tenant
+    }
+
+    /// Accounts a new request against the tenant's request rate.
+    pub fn request(&self) -> Result<(), TenantLimitExceeded> {
+        let now = self.registry.time_source.now();
+        let mut tenants = self.registry.tenants.lock();
+        let usage = tenants.entry(self.tenant.clone()).or_default();
+        if let Some(max) = self.registry.limits.max_requests_per_sec {
+            if let Err(retry_after) = count_in_window(&mut usage.window, max, now) {
+                usage.rejected += 1;
+                return Err(TenantLimitExceeded::RequestRate { retry_after });
+            }
+        }
+        usage.requests += 1;
+        Ok(())
+    }
+
+    /// Accounts a new subscription to the contract requested by this connection, held until
+    /// it is either [set up](Self::subscribed) or [fails](Self::subscription_failed).
+    pub fn subscribe(&mut self, contract: ContractInstanceId) -> Result<(), TenantLimitExceeded> {
+        let now = self.registry.time_source.now();
+        let mut tenants = self.registry.tenants.lock();
+        let usage = tenants.entry(self.tenant.clone()).or_default();
+        if let Some(max) = self.registry.limits.max_subscriptions_per_sec {
+            if let Err(retry_after) = count_in_window(&mut self.subscribing, max, now) {
+                usage.rejected += 1;
+                return Err(TenantLimitExceeded::SubscriptionRate { retry_after });
+            }
+        }
+        if self
+            .registry
+            .limits
+            .max_subscriptions
+            .is_some_and(|max| usage.subscriptions >= max)
+        {
+            usage.rejected += 1;
+            return Err(TenantLimitExceeded::Subscriptions);
+        }
+        usage.subscriptions += 1;
+        self.requested
+            .entry(contract)
+            .or_default()
+            .push(TenantSubscription {
+                registry: self.registry.clone(),
+                tenant: self.tenant.clone(),
+            });
+        Ok(())
+    }
+
+    /// The subscription requested to the contract was set up, it counts against the tenant
+    /// until the returned one is dropped. None if it wasn't requested through this connection.
+    pub fn subscribed(&mut self, contract: &ContractInstanceId) -> Option<TenantSubscription<T>> {
+        let requested = self.requested.get_mut(contract)?;
+        let subscription = requested.pop();
+        if requested.is_empty() {
+            self.requested.remove(contract);
+        }
+        subscription
+    }
+
+    pub fn subscription_failed(&mut self, contract: &ContractInstanceId) {
+        drop(self.subscribed(contract));
+    }
+}
+
+impl<T: TimeSource> Drop for TenantConnection<T> {
+    fn drop(&mut self) {
+        // before the connection, so the tenant is forgotten once both are gone
+        self.requested.clear();
+        self.registry.release(&self.tenant, |usage| {
+            usage.connections = usage.connections.saturating_sub(1);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contract() -> ContractInstanceId {
+        ContractInstanceId::new([1; 32])
+    }
+
+    struct MockTimeSrc(Mutex<Instant>);
+
+    impl TimeSource for MockTimeSrc {
+        fn now(&self) -> Instant {
+            *self.0.lock()
+        }
+    }
+
+    impl MockTimeSrc {
+        fn advance(&self, duration: Duration) {
+            *self.0.lock() += duration;
+        }
+    }
+
+    fn registry() -> Arc<TenantRegistry<MockTimeSrc>> {
+        let limits = TenantLimits {
+            max_connections: Some(2),
+            max_subscriptions: Some(1),
+            max_requests_per_sec: Some(3),
+            max_subscriptions_per_sec: None,
+        };
+        Arc::new(TenantRegistry::with_time_source(
+            limits,
+            MockTimeSrc(Mutex::new(Instant::now())),
+        ))
+    }
+
+    #[test]
+    fn resolve_tenant() {
+        let contract = ContractInstanceId::new([1; 32]);
+        assert_eq!(
+            TenantId::resolve(Some("acme"), Some(&contract)).to_string(),
+            "acme"
+        );
+        assert_eq!(
+            TenantId::resolve(Some(" "), Some(&contract)).to_string(),
+            contract.to_string()
+        );
+        assert_eq!(TenantId::resolve(None, None).to_string(), DEFAULT_TENANT);
+    }
+
+    #[test]
+    fn tenants_hit_limits_independently() {
+        let registry = registry();
+        let tenant_a = TenantId::resolve(Some("a"), None);
+        let tenant_b = TenantId::resolve(Some("b"), None);
+
+        let mut a1 = registry.connect(tenant_a.clone()).unwrap();
+        let _a2 = registry.connect(tenant_a.clone()).unwrap();
+        assert_eq!(
+            registry.connect(tenant_a.clone()).err(),
+            Some(TenantLimitExceeded::Connections)
+        );
+        let mut b1 = registry.connect(tenant_b.clone()).unwrap();
+
+        a1.subscribe(contract()).unwrap();
+        assert_eq!(
+            a1.subscribe(contract()),
+            Err(TenantLimitExceeded::Subscriptions)
+        );
+        b1.subscribe(contract()).unwrap();
+
+        for _ in 0..3 {
+            a1.request().unwrap();
+        }
+        assert!(matches!(
+            a1.request(),
+            Err(TenantLimitExceeded::RequestRate { .. })
+        ));
+        b1.request().unwrap();
+        registry.time_source.advance(RATE_WINDOW);
+        a1.request().unwrap();
+
+        let metrics = registry.metrics();
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics[0].tenant, tenant_a);
+        assert_eq!(metrics[0].connections, 2);
+        assert_eq!(metrics[0].subscriptions, 1);
+        assert_eq!(metrics[0].requests, 4);
+        assert_eq!(metrics[0].rejected, 3);
+        assert_eq!(metrics[1].tenant, tenant_b);
+        assert_eq!(metrics[1].requests, 1);
+        assert_eq!(metrics[1].rejected, 0);
+
+        // releasing a connection frees its subscriptions and connection slot
+        drop(a1);
+        let mut a3 = registry.connect(tenant_a).unwrap();
+        a3.subscribe(contract()).unwrap();
+    }
+
+    #[test]
+    fn subscriptions_released_once_ended() {
+        let registry = registry();
+        let tenant = TenantId::resolve(Some("a"), None);
+        let mut connection = registry.connect(tenant.clone()).unwrap();
+
+        connection.subscribe(contract()).unwrap();
+        connection.subscription_failed(&contract());
+        connection.subscribe(contract()).unwrap();
+        let subscription = connection.subscribed(&contract()).unwrap();
+        assert_eq!(
+            connection.subscribe(contract()),
+            Err(TenantLimitExceeded::Subscriptions)
+        );
+        drop(subscription);
+        assert_eq!(registry.metrics()[0].subscriptions, 0);
+        connection.subscribe(contract()).unwrap();
+
+        // forgotten once idle
+        let subscription = connection.subscribed(&contract()).unwrap();
+        drop(connection);
+        assert_eq!(registry.metrics().len(), 1);
+        drop(subscription);
+        assert!(registry.metrics().is_empty());
+    }
+
+    #[test]
+    fn retry_after_rate_window() {
+        let registry = registry();
+        let connection = registry
+            .connect(TenantId::resolve(Some("a"), None))
+            .unwrap();
+        for _ in 0..3 {
+            connection.request().unwrap();
+        }
+        registry.time_source.advance(Duration::from_millis(400));
+        let err = connection.request().unwrap_err();
+        assert_eq!(
+            err,
+            TenantLimitExceeded::RequestRate {
+                retry_after: Duration::from_millis(600)
+            }
+        );
+        assert!(err.to_string().ends_with("retry after 600ms"), "{err}");
+
+        // still rejected later in the window, with less to wait
+        registry.time_source.advance(Duration::from_millis(350));
+        assert_eq!(
+            connection.request(),
+            Err(TenantLimitExceeded::RequestRate {
+                retry_after: Duration::from_millis(250)
+            })
+        );
+        registry.time_source.advance(Duration::from_millis(250));
+        connection.request().unwrap();
+    }
+
+    #[test]
+    fn subscription_rate_per_connection() {
+        let limits = TenantLimits {
+            max_subscriptions_per_sec: Some(2),
+            ..Default::default()
+        };
+        let registry = Arc::new(TenantRegistry::with_time_source(
+            limits,
+            MockTimeSrc(Mutex::new(Instant::now())),
+        ));
+        let tenant = TenantId::resolve(Some("a"), None);
+        let mut churning = registry.connect(tenant.clone()).unwrap();
+        let mut other = registry.connect(tenant).unwrap();
+
+        churning.subscribe(contract()).unwrap();
+        churning.subscribe(contract()).unwrap();
+        registry.time_source.advance(Duration::from_millis(100));
+        let err = churning.subscribe(contract()).unwrap_err();
+        assert_eq!(
+            err,
+            TenantLimitExceeded::SubscriptionRate {
+                retry_after: Duration::from_millis(900)
+            }
+        );
+        assert!(err.to_string().ends_with("retry after 900ms"), "{err}");
+        // other requests and connections of the tenant are not limited by it
+        churning.request().unwrap();
+        other.subscribe(contract()).unwrap();
+
+        registry.time_source.advance(Duration::from_millis(900));
+        churning.subscribe(contract()).unwrap();
+        assert_eq!(registry.metrics()[0].subscriptions, 4);
+        assert_eq!(registry.metrics()[0].rejected, 1);
+    }
+}
@@ -0,0 +1,53 @@
+//! Connection timeouts proposed by the clients.
+//!
+//! A client may send the [`IDLE_TIMEOUT_HEADER`] and the [`KEEPALIVE_HEADER`] with the
+//! handshake, in milliseconds, to have its connection served with those instead of the
+//! configured ones. They are clamped to the bounds the node is configured with, and the ones
+//! applied are sent back in the same headers of the handshake response.
+
+use axum::http::HeaderMap;
+
+use crate::config::ConnectionTimeouts;
+
+pub(super) const IDLE_TIMEOUT_HEADER: &str = "x-idle-timeout-ms";
+pub(super) const KEEPALIVE_HEADER: &str = "x-keepalive-ms";
+
+/// The timeouts proposed in the headers of the handshake, clamped, the configured ones
+/// otherwise.
+pub(super) fn negotiate_timeouts(
+    headers: &HeaderMap,
+    configured: ConnectionTimeouts,
+) -> Result<ConnectionTimeouts, String> {
+    let proposed = |name: &str| {
+        headers
+            .get(name)
+            .map(|value| {
+                value
+                    .to_str()
+                    .ok()
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .ok_or_else(|| format!("`{name}` must be a number of milliseconds"))
+            })
+            .transpose()
+    };
+    let mut timeouts = configured;
+    if let Some(idle) = proposed(IDLE_TIMEOUT_HEADER)? {
+        timeouts.idle_ms = Some(configured.idle_bounds.clamp(idle));
+    }
+    if let Some(keepalive) = proposed(KEEPALIVE_HEADER)? {
+        timeouts.keepalive_ms = Some(configured.keepalive_bounds.clamp(keepalive));
+    }
+    Ok(timeouts)
+}
+
+/// Headers of the handshake response with the timeouts applied to the connection, if any.
+pub(super) fn timeout_headers(
+    timeouts: &ConnectionTimeouts,
+) -> impl Iterator<Item = (&'static str, u64)> {
+    [
+        (IDLE_TIMEOUT_HEADER, timeouts.idle_ms),
+        (KEEPALIVE_HEADER, timeouts.keepalive_ms),
+    ]
+    .into_iter()
+    .filter_map(|(header, ms)| Some((header, ms?)))
+}
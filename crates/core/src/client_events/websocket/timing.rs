@@ -0,0 +1,103 @@
+//! Breakdown of the time taken to answer a request, for clients debugging their latency.
+//!
+//! A client sends a [`ControlFrame::Timing`](super::control::ControlFrame::Timing) frame
+//! before a request, and the response to that request is followed by a
+//! [`ControlResponse::Timing`](super::control::ControlResponse::Timing) frame splitting the
+//! time between waiting in the queue until the node picked the request up, the node serving
+//! it, and encoding the response for the connection. Only the requests asked for are timed,
+//! the others don't go through any of it.
+
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+use super::ClientId;
+
+struct Timed {
+    sent: Instant,
+    picked_up: Option<Instant>,
+}
+
+/// Requests being timed of every connection, at most one at a time for each.
+#[derive(Default)]
+pub(super) struct RequestTimings {
+    timed: Mutex<HashMap<ClientId, Timed>>,
+    /// Requests being timed, checked before locking so the others pay for nothing more.
+    pending: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct Phases {
+    pub queue: Duration,
+    pub execution: Duration,
+}
+
+impl RequestTimings {
+    /// Times the request the client is about to send.
+    pub fn start(&self, client: ClientId) {
+        let previous = self.timed.lock().insert(
+            client,
+            Timed {
+                sent: Instant::now(),
+                picked_up: None,
+            },
+        );
+        if previous.is_none() {
+            self.pending.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The request of the client being timed is handed to the node.
+    pub fn picked_up(&self, client: ClientId) {
+        if self.pending.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        if let Some(timed) = self.timed.lock().get_mut(&client) {
+            timed.picked_up.get_or_insert_with(Instant::now);
+        }
+    }
+
+    /// Stops timing the request of the client, if it was, as it is answered.
+    pub fn finish(&self, client: ClientId) -> Option<Phases> {
+        if self.pending.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        let timed = self.timed.lock().remove(&client)?;
+        self.pending.fetch_sub(1, Ordering::Relaxed);
+        let received = Instant::now();
+        // answered without going to the node, it was never waiting for it
+        let picked_up = timed.picked_up.unwrap_or(received);
+        Some(Phases {
+            queue: picked_up.duration_since(timed.sent),
+            execution: received.duration_since(picked_up),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phases_split_at_pick_up() {
+        let timings = RequestTimings::default();
+        let (client, other) = (ClientId::next(), ClientId::next());
+        timings.picked_up(client);
+        assert!(timings.finish(client).is_none());
+
+        timings.start(client);
+        std::thread::sleep(Duration::from_millis(10));
+        timings.picked_up(client);
+        timings.picked_up(other);
+        std::thread::sleep(Duration::from_millis(20));
+        let phases = timings.finish(client).unwrap();
+        assert!(phases.queue >= Duration::from_millis(10), "{phases:?}");
+        assert!(phases.execution >= Duration::from_millis(20), "{phases:?}");
+        assert!(timings.finish(client).is_none());
+        assert!(timings.finish(other).is_none());
+    }
+}
@@ -0,0 +1,90 @@
+//! The distinct contracts a connection has operated on.
+//!
+//! When capped, requests for contracts other than the ones already touched are rejected once
+//! the connection reaches the cap, so a single client can't walk every contract on the node.
+//! The contracts are only remembered while the connection is open.
+
+use std::collections::HashSet;
+
+use freenet_stdlib::{
+    client_api::{ClientRequest, ContractRequest},
+    prelude::{ContractInstanceId, ContractKey},
+};
+
+#[derive(Debug, thiserror::Error)]
+#[error("connection already operated on the maximum of {max} distinct contracts")]
+pub(super) struct TooManyContracts {
+    max: usize,
+}
+
+pub(super) struct TouchedContracts {
+    max: Option<usize>,
+    touched: HashSet<ContractInstanceId>,
+}
+
+impl TouchedContracts {
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            touched: HashSet::new(),
+        }
+    }
+
+    /// Accounts for the contract `req` operates on, if any.
+    pub fn request(&mut self, req: &ClientRequest) -> Result<(), TooManyContracts> {
+        let ClientRequest::ContractOp(op) = req else {
+            return Ok(());
+        };
+        let key = match op {
+            ContractRequest::Put { contract, .. } => contract.key(),
+            ContractRequest::Update { key, .. }
+            | ContractRequest::Get { key, .. }
+            | ContractRequest::Subscribe { key, .. } => *key,
+            _ => return Ok(()),
+        };
+        self.touch(&key)
+    }
+
+    pub fn touch(&mut self, key: &ContractKey) -> Result<(), TooManyContracts> {
+        match self.max {
+            Some(max) if self.touched.len() >= max && !self.touched.contains(key.id()) => {
+                Err(TooManyContracts { max })
+            }
+            _ => {
+                self.touched.insert(*key.id());
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(n: u8) -> ClientRequest<'static> {
+        ClientRequest::ContractOp(ContractRequest::Get {
+            key: ContractKey::from(ContractInstanceId::new([n; 32])),
+            return_contract_code: false,
+            subscribe: false,
+        })
+    }
+
+    #[test]
+    fn touching_more_than_the_cap() {
+        let mut touched = TouchedContracts::new(Some(2));
+        assert!(touched.request(&get(1)).is_ok());
+        assert!(touched.request(&get(2)).is_ok());
+        let err = touched.request(&get(3)).unwrap_err();
+        assert_eq!(err.max, 2);
+        // the ones already touched are still fine
+        assert!(touched.request(&get(1)).is_ok());
+        assert!(touched.request(&get(2)).is_ok());
+        assert!(touched
+            .request(&ClientRequest::Disconnect { cause: None })
+            .is_ok());
+
+        let mut uncapped = TouchedContracts::new(None);
+        assert!((0..=255).all(|n| uncapped.request(&get(n)).is_ok()));
+    }
+}
@@ -1,9 +1,10 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashSet},
     fs::{self, File},
     future::Future,
     io::{Read, Write},
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    num::NonZeroU64,
     path::{Path, PathBuf},
     sync::{atomic::AtomicBool, Arc},
     time::Duration,
@@ -12,6 +13,7 @@ use std::{
 use anyhow::Context;
 use directories::ProjectDirs;
 use either::Either;
+use freenet_stdlib::client_api::ContractRequest;
 use itertools::Itertools;
 use once_cell::sync::Lazy;
 use pkcs8::DecodePublicKey;
@@ -226,11 +228,15 @@ impl ConfigArgs {
         let should_persist = cfg.is_none();
 
         // merge the configuration from the file with the command line arguments
+        let mut ws_api_file = WebsocketApiConfig::default();
+        let mut executor_file = ExecutorConfig::default();
         if let Some(cfg) = cfg {
             self.secrets.merge(cfg.secrets);
             self.mode.get_or_insert(cfg.mode);
             self.ws_api.address.get_or_insert(cfg.ws_api.address);
             self.ws_api.ws_api_port.get_or_insert(cfg.ws_api.port);
+            ws_api_file = cfg.ws_api;
+            executor_file = cfg.executor;
             self.log_level.get_or_insert(cfg.log_level);
             self.config_paths.merge(cfg.config_paths.as_ref().clone());
         }
@@ -333,8 +339,11 @@ impl ConfigArgs {
                     .ws_api
                     .ws_api_port
                     .unwrap_or(default_http_gateway_port()),
+                // settings only available through the configuration file
+                ..ws_api_file
             },
             secrets,
+            executor: executor_file,
             log_level: self.log_level.unwrap_or(tracing::log::LevelFilter::Info),
             config_paths: Arc::new(config_paths),
             gateways: gateways.gateways.clone(),
@@ -413,6 +422,8 @@ pub struct Config {
     pub ws_api: WebsocketApiConfig,
     #[serde(flatten)]
     pub secrets: Secrets,
+    #[serde(default)]
+    pub executor: ExecutorConfig,
     #[serde(with = "serde_log_level_filter")]
     pub log_level: tracing::log::LevelFilter,
     #[serde(flatten)]
@@ -584,7 +595,7 @@ pub struct WebsocketApiArgs {
     pub ws_api_port: Option<u16>,
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WebsocketApiConfig {
     /// Address to bind to
     #[serde(default = "default_listening_address", rename = "ws-api-address")]
@@ -593,6 +604,334 @@ pub struct WebsocketApiConfig {
     /// Port to expose api on
     #[serde(default = "default_http_gateway_port", rename = "ws-api-port")]
     pub port: u16,
+
+    /// Resource limits enforced for each tenant connecting to the api
+    #[serde(default, rename = "tenant-limits")]
+    pub tenant_limits: TenantLimits,
+
+    /// Maximum length in bytes of the path (including the query) of asset requests
+    #[serde(default = "default_max_path_length", rename = "max-path-length")]
+    pub max_path_length: usize,
+
+    /// Location of an external content-addressed store to resolve web app assets not
+    /// bundled in the contract state, either an http(s) url or a local directory
+    #[serde(
+        default,
+        rename = "asset-store",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub asset_store: Option<String>,
+
+    /// Time given to the node to answer client requests, by kind of operation
+    #[serde(default, rename = "request-timeouts")]
+    pub request_timeouts: RequestTimeouts,
+
+    /// Time given to the HTTP gateway to answer a request, by group of routes
+    #[serde(default, rename = "route-timeouts")]
+    pub route_timeouts: RouteTimeouts,
+
+    /// Load over which the local node turns away new requests
+    #[serde(default, rename = "load-shedding")]
+    pub load_shedding: LoadShedding,
+
+    /// Worker tasks delivering contract update notifications to subscribed clients, each
+    /// serving a share of them; with one they are delivered by the executor itself
+    #[serde(
+        default = "default_notification_shards",
+        rename = "notification-shards"
+    )]
+    pub notification_shards: usize,
+
+    /// OTLP/gRPC collector the spans of client requests are exported to, e.g.
+    /// `http://localhost:4317`, it requires the `trace-ot` feature; nothing is exported when
+    /// unset
+    #[serde(rename = "otlp-endpoint", skip_serializing_if = "Option::is_none")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Maximum number of bytes held in responses waiting to be written to websocket clients,
+    /// over it the node stops processing further requests until they are drained
+    #[serde(
+        default = "default_max_pending_response_bytes",
+        rename = "max-pending-response-bytes"
+    )]
+    pub max_pending_response_bytes: usize,
+
+    /// Milliseconds during which a contract found missing is answered as such without looking
+    /// it up again, zero disables it
+    #[serde(
+        default = "default_not_found_cache_ttl",
+        rename = "not-found-cache-ttl-ms"
+    )]
+    pub not_found_cache_ttl_ms: u64,
+
+    /// Milliseconds during which a get repeated by the same client is answered with the
+    /// result of the first one, zero disables it
+    #[serde(default = "default_get_dedup_window", rename = "get-dedup-window-ms")]
+    pub get_dedup_window_ms: u64,
+
+    /// Seconds the result of a write made with an idempotency key is kept to answer the
+    /// same write retried with that key, zero disables it
+    #[serde(default = "default_idempotency_ttl", rename = "idempotency-ttl-secs")]
+    pub idempotency_ttl_secs: u64,
+
+    /// Seconds the session of a websocket client is kept after it was last used, for the
+    /// client to carry it over to a new connection
+    #[serde(default = "default_session_ttl", rename = "session-ttl-secs")]
+    pub session_ttl_secs: u64,
+
+    /// Bytes of an upload through the HTTP gateway kept in memory, uploads past it are
+    /// received into a temporary file instead
+    #[serde(
+        default = "default_upload_spill_threshold",
+        rename = "upload-spill-threshold-bytes"
+    )]
+    pub upload_spill_threshold_bytes: usize,
+
+    /// Seconds an HTTP connection is kept open while idle waiting for the next request,
+    /// websocket connections are not affected
+    #[serde(
+        default = "default_http_keep_alive_timeout",
+        rename = "http-keep-alive-timeout-secs"
+    )]
+    pub http_keep_alive_timeout_secs: u64,
+
+    /// Maximum size in bytes of the head of an HTTP request, requests with a larger head are
+    /// answered with 431 before reading them any further; at least 8 KiB
+    #[serde(default = "default_max_header_size", rename = "max-header-size")]
+    pub max_header_size: usize,
+
+    /// Seconds the HTTP server waits for in-flight requests to complete when shutting down,
+    /// connections still open by then are closed abruptly
+    #[serde(
+        default = "default_shutdown_deadline",
+        rename = "shutdown-deadline-secs"
+    )]
+    pub shutdown_deadline_secs: u64,
+
+    /// Time given to the other stages of shutting down the gateway, see [`ShutdownTimeouts`]
+    #[serde(default, rename = "shutdown-timeouts")]
+    pub shutdown_timeouts: ShutdownTimeouts,
+
+    /// Updates kept for every contract so reconnecting clients can replay those they missed,
+    /// see [`RetainedUpdates`]
+    #[serde(default, rename = "retained-updates")]
+    pub retained_updates: RetainedUpdates,
+
+    /// Order in which responses and subscription notifications waiting to be written to a
+    /// websocket connection are sent
+    #[serde(default, rename = "outbound-priority")]
+    pub outbound_priority: OutboundPriority,
+
+    /// Order in which the notifications waiting to be written to a websocket connection are
+    /// sent, unless the client picks another one
+    #[serde(default, rename = "notification-queue")]
+    pub notification_queue: QueueDiscipline,
+
+    /// How long the notifications for a subscription are held to coalesce them, by contract,
+    /// see [`NotificationCoalescing`]
+    #[serde(default, rename = "notification-coalescing")]
+    pub notification_coalescing: NotificationCoalescing,
+
+    /// [`SlowConsumers`]
+    #[serde(default, rename = "slow-consumers")]
+    pub slow_consumers: SlowConsumers,
+
+    /// [`NotificationAggregation`]
+    #[serde(default, rename = "notification-aggregation")]
+    pub notification_aggregation: NotificationAggregation,
+
+    /// [`SubscriptionMemory`]
+    #[serde(default, rename = "subscription-memory")]
+    pub subscription_memory: SubscriptionMemory,
+
+    /// When a websocket connection is closed for being idle or for a send to it stalling, see
+    /// [`ConnectionTimeouts`]
+    #[serde(default, rename = "connection-timeouts")]
+    pub connection_timeouts: ConnectionTimeouts,
+
+    /// Maximum bytes per second written to all the websocket connections together, while
+    /// reached the connections take turns writing; unlimited if not set
+    #[serde(
+        default,
+        rename = "max-outbound-bytes-per-sec",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_outbound_bytes_per_sec: Option<NonZeroU64>,
+
+    /// Whether websocket requests with fields unknown to this node are accepted, ignoring
+    /// those fields, or rejected
+    #[serde(default, rename = "unknown-request-fields")]
+    pub unknown_request_fields: UnknownFields,
+
+    /// Port of the gRPC interface, on the same address and with the same TLS as the websocket
+    /// API; it is only served when set and the node is built with the `grpc` feature
+    #[serde(rename = "grpc-port", skip_serializing_if = "Option::is_none")]
+    pub grpc_port: Option<u16>,
+
+    /// Faults injected into websocket connections to test how clients cope with them; only
+    /// applied when the node is built with the `fault-injection` feature
+    #[serde(
+        default,
+        rename = "fault-injection",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub fault_injection: Option<FaultInjection>,
+
+    /// How long the tokens handed to contract web apps are accepted for
+    #[serde(default, rename = "token-expiry")]
+    pub token_expiry: TokenExpiry,
+
+    /// Maximum size in bytes of a response sent to a websocket client as a single message
+    #[serde(default = "default_max_response_size", rename = "max-response-size")]
+    pub max_response_size: usize,
+
+    /// What is sent instead of a response over the maximum size
+    #[serde(default, rename = "oversized-responses")]
+    pub oversized_responses: OversizedResponses,
+
+    /// Preset dictionaries for compressing the messages sent to websocket clients, by the
+    /// subprotocol the client connects with
+    #[serde(default, rename = "compression-dictionaries")]
+    pub compression_dictionaries: BTreeMap<String, PathBuf>,
+
+    /// Maximum number of distinct contracts a single websocket connection may operate on
+    #[serde(
+        rename = "max-contracts-per-connection",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_contracts_per_connection: Option<usize>,
+
+    /// Maximum number of active subscriptions across all the websocket clients of the node,
+    /// further subscriptions are rejected
+    #[serde(
+        default,
+        rename = "max-node-subscriptions",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_node_subscriptions: Option<usize>,
+
+    /// Scheduled window the gateway is under maintenance in, see [`MaintenanceWindow`]
+    #[serde(
+        default,
+        rename = "maintenance-window",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub maintenance_window: Option<MaintenanceWindow>,
+
+    /// Websocket API of another node to act as a read replica of, e.g. `ws://primary:50509`;
+    /// contracts are read from replicated copies and writes forwarded to it
+    #[serde(rename = "replica-of", skip_serializing_if = "Option::is_none")]
+    pub replica_of: Option<String>,
+
+    /// How the connections of the replica to the primary are kept, see [`ReplicaConnection`]
+    #[serde(default, rename = "replica-connection")]
+    pub replica_connection: ReplicaConnection,
+
+    /// Reverse proxies in front of the gateway; the address of the client is taken from the
+    /// forwarding headers of requests coming through them
+    #[serde(
+        rename = "trusted-proxies",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub trusted_proxies: Vec<IpAddr>,
+
+    /// Fields redacted from the logs, either by name or by a name starting or ending with `*`
+    /// matching the names ending or starting with the rest
+    #[serde(
+        rename = "redacted-log-fields",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub redacted_log_fields: Vec<String>,
+
+    /// File the audit trail of the websocket connections is appended to: their connections,
+    /// authentications, privileged operations and disconnections, each line chained to the
+    /// one before by its hash; no trail is kept if not set
+    #[serde(rename = "audit-log", skip_serializing_if = "Option::is_none")]
+    pub audit_log: Option<PathBuf>,
+
+    /// File holding the secret key the lines of the audit trail are chained with, generated
+    /// if missing; the file of the trail with the `key` extension if not set
+    #[serde(rename = "audit-log-key", skip_serializing_if = "Option::is_none")]
+    pub audit_log_key: Option<PathBuf>,
+
+    /// What is done once the audit trail can't be written, e.g. as its disk is full
+    #[serde(rename = "audit-log-full", default)]
+    pub audit_log_full: LogFullPolicy,
+
+    /// How long privileged requests wait for their line in the audit trail with the `block`
+    /// policy before they are refused
+    #[serde(
+        rename = "audit-log-max-block-secs",
+        default = "default_audit_log_max_block_secs"
+    )]
+    pub audit_log_max_block_secs: u64,
+
+    /// What is done with websocket connections presenting an auth token which is not one the
+    /// node could have issued, as opposed to one it doesn't know of
+    #[serde(default, rename = "malformed-auth-tokens")]
+    pub malformed_auth_tokens: MalformedAuthTokens,
+
+    /// Service tier of the tokens issued to the web apps of the contracts, by contract id;
+    /// the tokens of any other are of the standard tier
+    #[serde(
+        rename = "service-tiers",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub service_tiers: BTreeMap<String, ServiceTier>,
+
+    /// Seconds the token of a client is kept once it disconnects, so it can reconnect with it,
+    /// by the tier of the token; those of tiers missing are kept until they expire
+    #[serde(
+        rename = "reconnection-grace-secs",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub reconnection_grace_secs: BTreeMap<ServiceTier, u64>,
+
+    /// Contracts only the clients of their own web app may subscribe to, those connecting
+    /// with a token handed to it
+    #[serde(
+        rename = "private-contracts",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub private_contracts: Vec<String>,
+
+    /// Operations only clients which connected with a token may request, anyone may request
+    /// the rest
+    #[serde(
+        rename = "authenticated-operations",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub authenticated_operations: Vec<Operation>,
+
+    /// How much of the errors the node fails with while serving a request is told to the
+    /// client
+    #[serde(default, rename = "error-details")]
+    pub error_details: ErrorDetails,
+
+    /// What is done when a client subscribes to a contract it is already subscribed to, e.g.
+    /// subscribing again to everything after resuming its connection
+    #[serde(default, rename = "duplicate-subscriptions")]
+    pub duplicate_subscriptions: DuplicateSubscriptions,
+
+    /// Whether websocket clients may have their binary messages encrypted with a session key
+    /// of their own, which they can rotate without reconnecting
+    #[serde(default, rename = "frame-encryption")]
+    pub frame_encryption: bool,
+
+    /// Certificate the HTTP gateway is served over TLS with, plain HTTP when unset
+    #[serde(rename = "tls", skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+
+    /// Bearer token the admin routes require; when unset they are only served to clients on
+    /// the loopback interface
+    #[serde(rename = "admin-token", skip_serializing_if = "Option::is_none")]
+    pub admin_token: Option<String>,
 }
 
 impl From<SocketAddr> for WebsocketApiConfig {
@@ -600,6 +939,7 @@ impl From<SocketAddr> for WebsocketApiConfig {
         Self {
             address: addr.ip(),
             port: addr.port(),
+            ..Default::default()
         }
     }
 }
@@ -610,10 +950,806 @@ impl Default for WebsocketApiConfig {
         Self {
             address: default_listening_address(),
             port: default_http_gateway_port(),
+            tenant_limits: TenantLimits::default(),
+            max_path_length: default_max_path_length(),
+            asset_store: None,
+            request_timeouts: RequestTimeouts::default(),
+            route_timeouts: RouteTimeouts::default(),
+            load_shedding: LoadShedding::default(),
+            notification_shards: default_notification_shards(),
+            otlp_endpoint: None,
+            max_pending_response_bytes: default_max_pending_response_bytes(),
+            not_found_cache_ttl_ms: default_not_found_cache_ttl(),
+            get_dedup_window_ms: default_get_dedup_window(),
+            idempotency_ttl_secs: default_idempotency_ttl(),
+            session_ttl_secs: default_session_ttl(),
+            upload_spill_threshold_bytes: default_upload_spill_threshold(),
+            http_keep_alive_timeout_secs: default_http_keep_alive_timeout(),
+            max_header_size: default_max_header_size(),
+            shutdown_deadline_secs: default_shutdown_deadline(),
+            shutdown_timeouts: ShutdownTimeouts::default(),
+            retained_updates: RetainedUpdates::default(),
+            outbound_priority: OutboundPriority::default(),
+            notification_queue: QueueDiscipline::default(),
+            notification_coalescing: NotificationCoalescing::default(),
+            slow_consumers: SlowConsumers::default(),
+            notification_aggregation: NotificationAggregation::default(),
+            subscription_memory: SubscriptionMemory::default(),
+            connection_timeouts: ConnectionTimeouts::default(),
+            max_outbound_bytes_per_sec: None,
+            unknown_request_fields: UnknownFields::default(),
+            grpc_port: None,
+            fault_injection: None,
+            token_expiry: TokenExpiry::default(),
+            max_response_size: default_max_response_size(),
+            oversized_responses: OversizedResponses::default(),
+            compression_dictionaries: BTreeMap::new(),
+            max_contracts_per_connection: None,
+            max_node_subscriptions: None,
+            maintenance_window: None,
+            replica_of: None,
+            replica_connection: ReplicaConnection::default(),
+            tls: None,
+            trusted_proxies: Vec::new(),
+            redacted_log_fields: Vec::new(),
+            audit_log: None,
+            audit_log_key: None,
+            audit_log_full: LogFullPolicy::default(),
+            audit_log_max_block_secs: default_audit_log_max_block_secs(),
+            malformed_auth_tokens: MalformedAuthTokens::default(),
+            service_tiers: BTreeMap::new(),
+            reconnection_grace_secs: BTreeMap::new(),
+            private_contracts: Vec::new(),
+            authenticated_operations: Vec::new(),
+            error_details: ErrorDetails::default(),
+            duplicate_subscriptions: DuplicateSubscriptions::default(),
+            frame_encryption: false,
+            admin_token: None,
         }
     }
 }
 
+/// Limits applied to each tenant independently, unset limits are not enforced.
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
+pub struct TenantLimits {
+    /// Maximum number of simultaneous connections
+    #[serde(
+        default,
+        rename = "max-connections",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_connections: Option<usize>,
+
+    /// Maximum number of active subscriptions across all the tenant connections
+    #[serde(
+        default,
+        rename = "max-subscriptions",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_subscriptions: Option<usize>,
+
+    /// Maximum number of requests per second across all the tenant connections
+    #[serde(
+        default,
+        rename = "max-requests-per-sec",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_requests_per_sec: Option<u32>,
+
+    /// Maximum number of subscriptions each connection starts per second, on top of the
+    /// requests limit
+    #[serde(
+        default,
+        rename = "max-subscriptions-per-sec",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_subscriptions_per_sec: Option<u32>,
+}
+
+/// Time allowed for a client request to complete, so slow writes are not held to the limit
+/// suitable for reads. Reads are failed once over theirs, writes past theirs are reported but
+/// never cancelled midway.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestTimeouts {
+    /// Seconds allowed for requests reading contracts (get and subscribe)
+    #[serde(default = "default_read_timeout", rename = "read-secs")]
+    pub read_secs: u64,
+
+    /// Seconds after which requests writing contracts (put and update) are reported as slow
+    #[serde(default = "default_write_timeout", rename = "write-secs")]
+    pub write_secs: u64,
+}
+
+impl Default for RequestTimeouts {
+    fn default() -> Self {
+        Self {
+            read_secs: default_read_timeout(),
+            write_secs: default_write_timeout(),
+        }
+    }
+}
+
+impl RequestTimeouts {
+    pub fn for_request(&self, request: &ContractRequest<'_>) -> Duration {
+        let secs = if Self::is_read(request) {
+            self.read_secs
+        } else {
+            self.write_secs
+        };
+        Duration::from_secs(secs)
+    }
+
+    /// Whether the request only reads contracts, so it can be cancelled once over its timeout.
+    pub fn is_read(request: &ContractRequest<'_>) -> bool {
+        matches!(
+            request,
+            ContractRequest::Get { .. } | ContractRequest::Subscribe { .. }
+        )
+    }
+}
+
+/// Time allowed for the HTTP gateway to answer a request, so fetching the assets of a web app
+/// from an external store can take longer than the api is allowed to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteTimeouts {
+    /// Seconds allowed for serving contract web apps and their assets
+    #[serde(default = "default_assets_timeout", rename = "assets-secs")]
+    pub assets_secs: u64,
+
+    /// Seconds allowed for the contract and delegate api routes
+    #[serde(default = "default_api_timeout", rename = "api-secs")]
+    pub api_secs: u64,
+}
+
+impl Default for RouteTimeouts {
+    fn default() -> Self {
+        Self {
+            assets_secs: default_assets_timeout(),
+            api_secs: default_api_timeout(),
+        }
+    }
+}
+
+impl RouteTimeouts {
+    pub fn assets(&self) -> Duration {
+        Duration::from_secs(self.assets_secs)
+    }
+
+    pub fn api(&self) -> Duration {
+        Duration::from_secs(self.api_secs)
+    }
+}
+
+/// Seconds every stage of shutting down the gateway is given before moving on to the next
+/// one, stopping accepting connections, draining the in-flight requests, up to
+/// `shutdown-deadline-secs`, closing the websocket connections and their subscriptions and
+/// stopping the executor, in that order.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShutdownTimeouts {
+    #[serde(
+        default = "default_stop_accepting_timeout",
+        rename = "stop-accepting-secs"
+    )]
+    pub stop_accepting_secs: u64,
+
+    #[serde(
+        default = "default_subscriptions_shutdown_timeout",
+        rename = "subscriptions-secs"
+    )]
+    pub subscriptions_secs: u64,
+
+    #[serde(
+        default = "default_executor_shutdown_timeout",
+        rename = "executor-secs"
+    )]
+    pub executor_secs: u64,
+}
+
+impl Default for ShutdownTimeouts {
+    fn default() -> Self {
+        Self {
+            stop_accepting_secs: default_stop_accepting_timeout(),
+            subscriptions_secs: default_subscriptions_shutdown_timeout(),
+            executor_secs: default_executor_shutdown_timeout(),
+        }
+    }
+}
+
+/// Window, between two RFC 3339 times, the gateway is under maintenance in: new websocket
+/// connections are rejected from `start` until `end`, those already open are kept.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    pub start: chrono::DateTime<chrono::Utc>,
+    pub end: chrono::DateTime<chrono::Utc>,
+}
+
+/// Milliseconds a websocket connection is kept open with nothing sent either way, never
+/// closed for being idle unless `idle-ms` is set, and a message being sent to the client may
+/// take before the connection is considered stalled and closed. Connections are pinged every
+/// `keepalive-ms` if set, the pings and their answers don't keep them from being idle.
+///
+/// Clients may propose their own idle and keepalive timeouts when connecting, which are
+/// clamped to the `idle-bounds` and `keepalive-bounds`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionTimeouts {
+    #[serde(default, rename = "idle-ms", skip_serializing_if = "Option::is_none")]
+    pub idle_ms: Option<u64>,
+
+    #[serde(default = "default_send_timeout", rename = "send-ms")]
+    pub send_ms: u64,
+
+    #[serde(
+        default,
+        rename = "keepalive-ms",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub keepalive_ms: Option<u64>,
+
+    #[serde(default, rename = "idle-bounds")]
+    pub idle_bounds: TimeoutBounds,
+
+    #[serde(default, rename = "keepalive-bounds")]
+    pub keepalive_bounds: TimeoutBounds,
+}
+
+impl Default for ConnectionTimeouts {
+    fn default() -> Self {
+        Self {
+            idle_ms: None,
+            send_ms: default_send_timeout(),
+            keepalive_ms: None,
+            idle_bounds: TimeoutBounds::default(),
+            keepalive_bounds: TimeoutBounds::default(),
+        }
+    }
+}
+
+impl ConnectionTimeouts {
+    pub fn idle(&self) -> Option<Duration> {
+        self.idle_ms.map(Duration::from_millis)
+    }
+
+    pub fn send(&self) -> Duration {
+        Duration::from_millis(self.send_ms)
+    }
+
+    pub fn keepalive(&self) -> Option<Duration> {
+        self.keepalive_ms
+            .filter(|ms| *ms > 0)
+            .map(Duration::from_millis)
+    }
+}
+
+/// Milliseconds a timeout proposed by a client is raised to, or lowered to, unbounded on the
+/// sides not set.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeoutBounds {
+    #[serde(default, rename = "min-ms", skip_serializing_if = "Option::is_none")]
+    pub min_ms: Option<u64>,
+
+    #[serde(default, rename = "max-ms", skip_serializing_if = "Option::is_none")]
+    pub max_ms: Option<u64>,
+}
+
+impl TimeoutBounds {
+    pub fn clamp(&self, ms: u64) -> u64 {
+        let ms = self.min_ms.map_or(ms, |min| ms.max(min));
+        self.max_ms.map_or(ms, |max| ms.min(max))
+    }
+}
+
+/// Milliseconds the notifications for a subscription are held after the first of them, in
+/// the window of the contract if listed in `contracts` by id, in `default-window-ms` otherwise.
+/// Once the window ends they are sent all together, but for those superseded by a whole state
+/// notified after them; contracts with a window of zero are never held.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotificationCoalescing {
+    #[serde(default, rename = "default-window-ms")]
+    pub default_window_ms: u64,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub contracts: BTreeMap<String, u64>,
+}
+
+/// What is done with the notifications of a subscription piling up while its client doesn't
+/// read them as fast as they come, once over `max-backlog`. Clients may pick for each of their
+/// subscriptions any of the `allowed-policies`, the `default-policy` applies to the others.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlowConsumers {
+    #[serde(default, rename = "default-policy")]
+    pub default_policy: SlowConsumerPolicy,
+
+    #[serde(default = "all_slow_consumer_policies", rename = "allowed-policies")]
+    pub allowed_policies: Vec<SlowConsumerPolicy>,
+
+    #[serde(default = "default_max_backlog", rename = "max-backlog")]
+    pub max_backlog: usize,
+
+    /// How long subscriptions with the [`SlowConsumerPolicy::Block`] policy stay over the
+    /// backlog before disconnecting the client
+    #[serde(default = "default_block_timeout", rename = "block-timeout-ms")]
+    pub block_timeout_ms: u64,
+}
+
+impl Default for SlowConsumers {
+    fn default() -> Self {
+        Self {
+            default_policy: SlowConsumerPolicy::default(),
+            allowed_policies: all_slow_consumer_policies(),
+            max_backlog: default_max_backlog(),
+            block_timeout_ms: default_block_timeout(),
+        }
+    }
+}
+
+fn all_slow_consumer_policies() -> Vec<SlowConsumerPolicy> {
+    vec![
+        SlowConsumerPolicy::DropOldest,
+        SlowConsumerPolicy::DropNewest,
+        SlowConsumerPolicy::Disconnect,
+        SlowConsumerPolicy::Block,
+    ]
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowConsumerPolicy {
+    /// Discard the oldest notification to make room for the new one.
+    #[default]
+    DropOldest,
+    /// Discard the new notification.
+    DropNewest,
+    /// Disconnect the client.
+    Disconnect,
+    /// Keep every notification, disconnecting the client if still over the backlog after the
+    /// block timeout.
+    Block,
+}
+
+/// Cap on the bytes of the notifications held in the subscriptions of all the websocket clients
+/// together, waiting for clients behind or paused; once reached the `policy` applies. Unlimited
+/// if `max-bytes` is not set, only the backlog of each subscription bounds them then.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubscriptionMemory {
+    #[serde(default, rename = "max-bytes", skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<usize>,
+
+    #[serde(default)]
+    pub policy: SubscriptionMemoryPolicy,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SubscriptionMemoryPolicy {
+    /// Subscriptions stop taking in further notifications, which wait with the node, until
+    /// enough of those held are sent.
+    #[default]
+    Backpressure,
+    /// The subscriptions of the lowest priority holding the most are ended, their clients told
+    /// so, until back under the cap.
+    Shed,
+}
+
+/// Which aggregations clients may ask for their subscriptions, so numeric updates are sent to
+/// them once per window as a single value computed from all those received in it. Clients may
+/// pick any of the `allowed-functions`, over a window between `min-window-ms` and
+/// `max-window-ms`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotificationAggregation {
+    #[serde(default = "all_aggregate_functions", rename = "allowed-functions")]
+    pub allowed_functions: Vec<AggregateFunction>,
+
+    #[serde(default = "default_min_aggregation_window", rename = "min-window-ms")]
+    pub min_window_ms: u64,
+
+    #[serde(default = "default_max_aggregation_window", rename = "max-window-ms")]
+    pub max_window_ms: u64,
+}
+
+impl Default for NotificationAggregation {
+    fn default() -> Self {
+        Self {
+            allowed_functions: all_aggregate_functions(),
+            min_window_ms: default_min_aggregation_window(),
+            max_window_ms: default_max_aggregation_window(),
+        }
+    }
+}
+
+fn all_aggregate_functions() -> Vec<AggregateFunction> {
+    vec![
+        AggregateFunction::Avg,
+        AggregateFunction::Min,
+        AggregateFunction::Max,
+        AggregateFunction::Sum,
+        AggregateFunction::Count,
+    ]
+}
+
+/// How the numeric updates received over an aggregation window are reduced to one value.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AggregateFunction {
+    Avg,
+    Min,
+    Max,
+    Sum,
+    /// The number of updates, whatever their values.
+    Count,
+}
+
+/// How many of the latest updates of each contract are kept, and for how long, for clients
+/// to replay; a client asking for updates past those is told to fetch the whole state again.
+/// Updates are kept for up to `max-contracts` contracts, those updated last.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetainedUpdates {
+    #[serde(default = "default_retained_updates", rename = "max-count")]
+    pub max_count: usize,
+
+    #[serde(default = "default_retained_updates_age", rename = "max-age-secs")]
+    pub max_age_secs: u64,
+
+    #[serde(
+        default = "default_retained_updates_contracts",
+        rename = "max-contracts"
+    )]
+    pub max_contracts: usize,
+}
+
+impl Default for RetainedUpdates {
+    fn default() -> Self {
+        Self {
+            max_count: default_retained_updates(),
+            max_age_secs: default_retained_updates_age(),
+            max_contracts: default_retained_updates_contracts(),
+        }
+    }
+}
+
+impl RetainedUpdates {
+    pub fn max_age(&self) -> Duration {
+        Duration::from_secs(self.max_age_secs)
+    }
+}
+
+/// Connections a read replica keeps to its primary. The primary is pinged through each one every
+/// `health-check-secs`, and the connection considered lost once nothing was heard from it for
+/// twice as long; a lost connection is made again after `initial-backoff-ms`, doubling the wait
+/// after every failed attempt up to `max-backoff-ms`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicaConnection {
+    /// Connections the requests to the primary are spread over
+    #[serde(default = "default_replica_pool_size", rename = "pool-size")]
+    pub pool_size: usize,
+
+    #[serde(default = "default_replica_health_check", rename = "health-check-secs")]
+    pub health_check_secs: u64,
+
+    #[serde(
+        default = "default_replica_initial_backoff",
+        rename = "initial-backoff-ms"
+    )]
+    pub initial_backoff_ms: u64,
+
+    #[serde(default = "default_replica_max_backoff", rename = "max-backoff-ms")]
+    pub max_backoff_ms: u64,
+}
+
+impl ReplicaConnection {
+    pub fn health_check(&self) -> Duration {
+        Duration::from_secs(self.health_check_secs)
+    }
+
+    pub fn initial_backoff(&self) -> Duration {
+        Duration::from_millis(self.initial_backoff_ms)
+    }
+
+    pub fn max_backoff(&self) -> Duration {
+        Duration::from_millis(self.max_backoff_ms)
+    }
+}
+
+impl Default for ReplicaConnection {
+    fn default() -> Self {
+        Self {
+            pool_size: default_replica_pool_size(),
+            health_check_secs: default_replica_health_check(),
+            initial_backoff_ms: default_replica_initial_backoff(),
+            max_backoff_ms: default_replica_max_backoff(),
+        }
+    }
+}
+
+/// Rates, between 0 and 1, at which the responses to a websocket client are replaced by a
+/// fault. Every connection goes through the same sequence of faults for a given seed.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FaultInjection {
+    #[serde(default)]
+    pub seed: u64,
+
+    /// Rate at which the connection is dropped instead of responding
+    #[serde(default, rename = "disconnect-rate")]
+    pub disconnect_rate: f64,
+
+    /// Rate at which responses are held back for `delay-ms` before being sent
+    #[serde(default, rename = "delay-rate")]
+    pub delay_rate: f64,
+
+    #[serde(default, rename = "delay-ms")]
+    pub delay_ms: u64,
+
+    /// Rate at which responses are replaced by an error
+    #[serde(default, rename = "error-rate")]
+    pub error_rate: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain, the certificate of the gateway first
+    #[serde(rename = "cert-file")]
+    pub cert_file: PathBuf,
+
+    /// PEM file with the private key of the certificate
+    #[serde(rename = "key-file")]
+    pub key_file: PathBuf,
+
+    /// PEM file with the certificates of the authorities client certificates must be issued
+    /// by; when set, clients without such a certificate are rejected
+    #[serde(
+        default,
+        rename = "client-ca-file",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub client_ca_file: Option<PathBuf>,
+
+    /// Names a client certificate must be valid for one of, the one it is valid for
+    /// identifying the client; any certificate issued by the authorities is accepted if empty,
+    /// identified by the first name it is valid for
+    #[serde(
+        default,
+        rename = "allowed-clients",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub allowed_clients: Vec<String>,
+
+    /// Contract each client is attested for by the identity of its certificate, unless it
+    /// connects with a token
+    #[serde(
+        default,
+        rename = "client-contracts",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub client_contracts: BTreeMap<String, String>,
+}
+
+/// Lifetime of the tokens handed to contract web apps, measured by the node since it issued
+/// them; clients keep them in a cookie expiring after the same time by their own clock.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenExpiry {
+    /// Seconds a token is valid for
+    #[serde(default = "default_token_ttl", rename = "ttl-secs")]
+    pub ttl_secs: u64,
+
+    /// Seconds a token is still accepted after expiring, covering clients whose clock runs
+    /// behind and hold on to the cookie for longer
+    #[serde(default = "default_token_skew_margin", rename = "skew-margin-secs")]
+    pub skew_margin_secs: u64,
+}
+
+impl Default for TokenExpiry {
+    fn default() -> Self {
+        Self {
+            ttl_secs: default_token_ttl(),
+            skew_margin_secs: default_token_skew_margin(),
+        }
+    }
+}
+
+/// Thresholds over which new requests are rejected until the node catches up, unset
+/// thresholds are not enforced.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoadShedding {
+    /// Maximum number of requests waiting to be processed
+    #[serde(
+        default,
+        rename = "max-queue-depth",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_queue_depth: Option<usize>,
+
+    /// Maximum milliseconds taken on average by the recently served requests
+    #[serde(
+        default,
+        rename = "max-latency-ms",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_latency_ms: Option<u64>,
+
+    /// Number of requests waiting over which those waiting the longest are cancelled, reads
+    /// before writes, to make room for new ones
+    #[serde(
+        default,
+        rename = "cancel-queue-depth",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub cancel_queue_depth: Option<usize>,
+}
+
+/// Where and how the executor stores the contracts, delegates, secrets and states, and how
+/// it runs the contracts.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExecutorConfig {
+    /// Directory holding every store of the executor, instead of the directories of the
+    /// node under its data directory
+    #[serde(
+        default,
+        rename = "storage-dir",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub storage_dir: Option<PathBuf>,
+
+    /// Database holding the contract states, it has to be compiled in
+    #[serde(default, rename = "storage-backend")]
+    pub storage_backend: StorageBackend,
+
+    /// Number of past states of every contract kept in memory to be queried, none by
+    /// default; they are lost when the node restarts
+    #[serde(default, rename = "state-history-depth")]
+    pub state_history_depth: usize,
+
+    /// Whether contracts are run twice when validating states to catch nondeterministic ones,
+    /// and what to do about them; it doubles the cost of validation
+    #[serde(default, rename = "determinism-check")]
+    pub determinism_check: DeterminismCheck,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StorageBackend {
+    #[cfg_attr(feature = "redb", default)]
+    Redb,
+    #[cfg_attr(not(feature = "redb"), default)]
+    Sqlite,
+}
+
+/// Handling of contracts validating the same state differently when run twice.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeterminismCheck {
+    /// Contracts are run once.
+    #[default]
+    Off,
+    /// Divergences are logged, the outcome of the first run is kept.
+    Warn,
+    /// Divergences are logged and the operation is rejected.
+    Reject,
+}
+
+/// Which messages go out first on a websocket connection when both responses to its
+/// requests and notifications for its subscriptions are waiting.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutboundPriority {
+    /// Notifications are only sent while no response is waiting.
+    #[default]
+    ResponsesFirst,
+    /// Responses and notifications take turns.
+    RoundRobin,
+}
+
+/// Which of the notifications waiting to be written to a websocket connection goes out next.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueueDiscipline {
+    /// The one received first.
+    #[default]
+    Fifo,
+    /// The one received last, for clients only after the freshest data.
+    Lifo,
+    /// One of the subscription the client gave the highest priority, the one received first
+    /// among those.
+    Priority,
+}
+
+/// Service tier of the clients holding a token, the requests of those of a higher tier are
+/// scheduled ahead of the others while the node is loaded.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ServiceTier {
+    #[default]
+    Standard,
+    Premium,
+}
+
+/// Handling of the fields of a client request which this node doesn't know about, e.g.
+/// those added by a newer version of the protocol.
+///
+/// Requests in the native encoding aren't self-describing, so only fields appended at the end
+/// of the request are told apart; a field added within a nested value or an enum variant
+/// can't be skipped and leaves the request malformed either way.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnknownFields {
+    /// The request is served as if they weren't there.
+    #[default]
+    Lenient,
+    /// The request is rejected as malformed.
+    Strict,
+}
+
+/// Kinds of requests clients make to the node.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Operation {
+    Get,
+    Put,
+    Update,
+    /// Subscribing as well as getting a contract while subscribing to it.
+    Subscribe,
+    /// Any request to delegates.
+    Delegate,
+}
+
+/// Handling of subscriptions to contracts the client is already subscribed to.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicateSubscriptions {
+    /// Answered as subscribed right away, the client keeps the subscription it has.
+    #[default]
+    Reconcile,
+    /// Subscribed again, the client gets the notifications once per subscription.
+    Resubscribe,
+}
+
+/// Detail of the unexpected errors reported to clients.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorDetails {
+    /// Only the error itself.
+    #[default]
+    Message,
+    /// The error along with the chain of errors which caused it, meant for developing apps
+    /// as it exposes the internals of the node.
+    Chain,
+}
+
+/// Handling of the writes to a log failing, e.g. as its disk is full; the node keeps no access
+/// log of its own, the audit trail is the only log it applies to.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFullPolicy {
+    /// The lines are dropped with a warning, and the node goes on serving as usual.
+    #[default]
+    Drop,
+    /// The requests to log wait until their line is written, for at most as long as
+    /// configured; they are refused past that.
+    Block,
+    /// The requests to log are refused until their line can be written, the rest are served.
+    Degraded,
+}
+
+/// Handling of websocket connections presenting a malformed auth token.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MalformedAuthTokens {
+    /// The connection is refused as unauthorized.
+    #[default]
+    Reject,
+    /// The token is ignored, the connection goes on as if it came without one.
+    Ignore,
+}
+
+/// Handling of websocket responses over the maximum message size.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OversizedResponses {
+    /// Sent in chunks to clients which accept them, the rest get an error instead.
+    #[default]
+    Chunk,
+    /// Replaced by an error telling the client to page through the data.
+    Reject,
+}
+
 #[inline]
 const fn default_listening_address() -> IpAddr {
     IpAddr::V4(Ipv4Addr::UNSPECIFIED)
@@ -629,6 +1765,176 @@ const fn default_http_gateway_port() -> u16 {
     50509
 }
 
+#[inline]
+const fn default_max_path_length() -> usize {
+    4096
+}
+
+#[inline]
+const fn default_max_response_size() -> usize {
+    64 * 1024 * 1024
+}
+
+#[inline]
+const fn default_max_pending_response_bytes() -> usize {
+    256 * 1024 * 1024
+}
+
+#[inline]
+const fn default_audit_log_max_block_secs() -> u64 {
+    30
+}
+
+#[inline]
+const fn default_not_found_cache_ttl() -> u64 {
+    1000
+}
+
+#[inline]
+const fn default_get_dedup_window() -> u64 {
+    100
+}
+
+#[inline]
+const fn default_idempotency_ttl() -> u64 {
+    300
+}
+
+#[inline]
+const fn default_session_ttl() -> u64 {
+    600
+}
+
+#[inline]
+const fn default_upload_spill_threshold() -> usize {
+    8 * 1024 * 1024
+}
+
+#[inline]
+const fn default_http_keep_alive_timeout() -> u64 {
+    30
+}
+
+#[inline]
+const fn default_max_header_size() -> usize {
+    64 * 1024
+}
+
+#[inline]
+const fn default_shutdown_deadline() -> u64 {
+    10
+}
+
+#[inline]
+const fn default_stop_accepting_timeout() -> u64 {
+    1
+}
+
+#[inline]
+const fn default_subscriptions_shutdown_timeout() -> u64 {
+    5
+}
+
+#[inline]
+const fn default_executor_shutdown_timeout() -> u64 {
+    5
+}
+
+#[inline]
+const fn default_retained_updates() -> usize {
+    64
+}
+
+#[inline]
+const fn default_retained_updates_age() -> u64 {
+    600
+}
+
+#[inline]
+const fn default_retained_updates_contracts() -> usize {
+    1024
+}
+
+#[inline]
+const fn default_max_backlog() -> usize {
+    256
+}
+
+#[inline]
+const fn default_block_timeout() -> u64 {
+    5_000
+}
+
+#[inline]
+const fn default_min_aggregation_window() -> u64 {
+    100
+}
+
+#[inline]
+const fn default_max_aggregation_window() -> u64 {
+    60_000
+}
+
+#[inline]
+const fn default_send_timeout() -> u64 {
+    30_000
+}
+
+#[inline]
+const fn default_replica_pool_size() -> usize {
+    2
+}
+
+#[inline]
+const fn default_replica_health_check() -> u64 {
+    15
+}
+
+#[inline]
+const fn default_replica_initial_backoff() -> u64 {
+    100
+}
+
+#[inline]
+const fn default_replica_max_backoff() -> u64 {
+    30_000
+}
+
+#[inline]
+const fn default_notification_shards() -> usize {
+    1
+}
+
+#[inline]
+const fn default_token_ttl() -> u64 {
+    24 * 60 * 60
+}
+
+#[inline]
+const fn default_token_skew_margin() -> u64 {
+    5 * 60
+}
+
+#[inline]
+const fn default_read_timeout() -> u64 {
+    10
+}
+
+#[inline]
+const fn default_write_timeout() -> u64 {
+    OPERATION_TTL.as_secs()
+}
+
+#[inline]
+const fn default_assets_timeout() -> u64 {
+    120
+}
+
+#[inline]
+const fn default_api_timeout() -> u64 {
+    60
+}
+
 #[derive(clap::Parser, Default, Debug, Clone, Serialize, Deserialize)]
 pub struct ConfigPathsArgs {
     /// The configuration directory.
@@ -1045,6 +2351,7 @@ mod tests {
     use rsa::RsaPublicKey;
 
     use crate::node::NodeConfig;
+    use freenet_stdlib::prelude::{ContractInstanceId, ContractKey, StateDelta, UpdateData};
 
     use super::*;
 
@@ -1102,6 +2409,30 @@ mod tests {
         assert!(pub_keys_dir.path().join("public_key.pem").exists());
     }
 
+    #[test]
+    fn request_timeouts_by_operation() {
+        let timeouts: RequestTimeouts = toml::from_str("write-secs = 120").unwrap();
+        assert_eq!(timeouts.read_secs, default_read_timeout());
+
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let get = ContractRequest::Get {
+            key,
+            return_contract_code: false,
+            subscribe: false,
+        };
+        let subscribe = ContractRequest::Subscribe { key, summary: None };
+        let update = ContractRequest::Update {
+            key,
+            data: UpdateData::Delta(StateDelta::from(vec![1])),
+        };
+        let read = Duration::from_secs(default_read_timeout());
+        assert_eq!(timeouts.for_request(&get), read);
+        assert_eq!(timeouts.for_request(&subscribe), read);
+        assert_eq!(timeouts.for_request(&update), Duration::from_secs(120));
+        assert!(RequestTimeouts::is_read(&subscribe));
+        assert!(!RequestTimeouts::is_read(&update));
+    }
+
     #[test]
     fn test_gateways() {
         let gateways = Gateways {
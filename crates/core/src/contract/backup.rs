@@ -0,0 +1,113 @@
+//! Export of the contracts stored by a node and their import into another one, to back a node
+//! up or move it elsewhere.
+//!
+//! An export is a sequence of frames, one per contract, each the bincode encoding of an
+//! [`ExportedContract`] prefixed by its length as a big-endian `u32`. The contracts are
+//! exported one at a time, each with its state as of when it is read, and those whose stored
+//! state is corrupt are left out. Importing a contract goes the same way as a put: its state
+//! is validated and merged into the one held, if any, notifying the subscribers of the
+//! contract; one already holding that same state is left untouched, so importing the same
+//! export again changes nothing.
+
+use freenet_stdlib::prelude::{ContractContainer, WrappedState};
+use serde::{Deserialize, Serialize};
+
+const LEN_PREFIX: usize = std::mem::size_of::<u32>();
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ExportedContract {
+    pub contract: ContractContainer,
+    pub state: WrappedState,
+}
+
+impl ExportedContract {
+    /// The frame of the contract in an export.
+    pub fn encode(&self) -> Vec<u8> {
+        let encoded = bincode::serialize(self).expect("infallible serialization");
+        let mut frame = Vec::with_capacity(LEN_PREFIX + encoded.len());
+        frame.extend((encoded.len() as u32).to_be_bytes());
+        frame.extend(encoded);
+        frame
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum MalformedExport {
+    #[error("contract {index} of the export can't be decoded: {cause}")]
+    Undecodable { index: usize, cause: bincode::Error },
+    #[error("the export ends in the middle of contract {index}")]
+    Truncated { index: usize },
+    #[error("contract {index} of the export is {len} bytes, over the maximum of {max}")]
+    Oversized {
+        index: usize,
+        len: usize,
+        max: usize,
+    },
+}
+
+/// Decodes the contracts of an export as its bytes come in.
+pub(crate) struct ExportReader {
+    buffered: Vec<u8>,
+    read: usize,
+    max_contract_size: usize,
+}
+
+impl ExportReader {
+    /// Rejects the contracts whose frame is over `max_contract_size` bytes, so no more than
+    /// that is ever buffered besides the chunk last extended with.
+    pub fn new(max_contract_size: usize) -> Self {
+        Self {
+            buffered: Vec::new(),
+            read: 0,
+            max_contract_size,
+        }
+    }
+
+    pub fn extend(&mut self, chunk: &[u8]) {
+        self.buffered.extend_from_slice(chunk);
+    }
+
+    /// The next contract whose frame is complete.
+    pub fn next_contract(&mut self) -> Result<Option<ExportedContract>, MalformedExport> {
+        let Some(len) = self.buffered.get(..LEN_PREFIX) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(len.try_into().expect("prefix length")) as usize;
+        if len > self.max_contract_size {
+            return Err(MalformedExport::Oversized {
+                index: self.read,
+                len,
+                max: self.max_contract_size,
+            });
+        }
+        let Some(frame) = self.buffered.get(LEN_PREFIX..LEN_PREFIX + len) else {
+            return Ok(None);
+        };
+        let contract =
+            bincode::deserialize(frame).map_err(|cause| MalformedExport::Undecodable {
+                index: self.read,
+                cause,
+            })?;
+        self.buffered.drain(..LEN_PREFIX + len);
+        self.read += 1;
+        Ok(Some(contract))
+    }
+
+    /// Checks nothing is left over once the export is over, returning how many contracts
+    /// were read.
+    pub fn finish(self) -> Result<usize, MalformedExport> {
+        if !self.buffered.is_empty() {
+            return Err(MalformedExport::Truncated { index: self.read });
+        }
+        Ok(self.read)
+    }
+}
+
+/// Contracts imported from an export.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ImportOutcome {
+    /// Contracts stored, either new to the node or with a different state than the one held.
+    pub imported: usize,
+    /// Contracts the node already held with the same state.
+    pub unchanged: usize,
+}
@@ -0,0 +1,296 @@
+//! Range queries over the state of contracts modeling collections.
+//!
+//! The state of such a contract is a sequence of entries, each one prefixed by its length as
+//! a big-endian `u32`. A query streams the entries within the requested range as
+//! [`RangeFrame::Entry`] frames followed by a [`RangeFrame::Complete`] one, so clients get
+//! a slice of the collection without transferring the whole state. The state is read a chunk
+//! at a time as the entries are streamed, those before the range only as far as their lengths.
+//!
+//! Clients get the frames over the websocket API, as JSON text messages with the data of the
+//! entries in base64.
+
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::wasm_runtime::{StateReader, StateStorage, StateStoreError};
+
+/// Frames buffered for the client, the query stops walking the collection while it is full.
+const BUFFERED_FRAMES: usize = 64;
+
+/// Bytes of the state read at once while walking the collection.
+const CHUNK_LEN: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum RangeFrame {
+    Entry {
+        index: usize,
+        #[serde(serialize_with = "base64")]
+        data: Vec<u8>,
+    },
+    /// Every entry in the range has been sent, fewer than requested if the collection ended.
+    Complete { entries: usize },
+    /// The state is not a well formed collection, or could not be read, no frames follow.
+    Error { cause: String },
+}
+
+fn base64<S: serde::Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    use base64::Engine;
+    serializer.serialize_str(&base64::engine::general_purpose::STANDARD.encode(data))
+}
+
+#[derive(Debug, thiserror::Error)]
+enum CollectionError {
+    #[error("malformed collection, entry {index} is truncated")]
+    Malformed { index: usize },
+    #[error(transparent)]
+    Read(#[from] StateStoreError),
+}
+
+/// What walking the collection one entry further came across.
+enum Walked {
+    Entry(Vec<u8>),
+    Skipped,
+    End,
+}
+
+/// Walks a collection forward, reading its state a chunk at a time.
+struct Cursor<S> {
+    reader: StateReader<S>,
+    chunk: Vec<u8>,
+    /// Where the chunk starts in the state.
+    chunk_at: usize,
+    position: usize,
+}
+
+impl<S> Cursor<S>
+where
+    S: StateStorage,
+    <S as StateStorage>::Error: Into<anyhow::Error>,
+{
+    fn new(reader: StateReader<S>) -> Self {
+        Self {
+            reader,
+            chunk: Vec::new(),
+            chunk_at: 0,
+            position: 0,
+        }
+    }
+
+    /// The entry at the position, moving past it, only read unless `skipped`.
+    async fn entry(&mut self, index: usize, skipped: bool) -> Result<Walked, CollectionError> {
+        let len = self.take(4).await?;
+        if len.is_empty() {
+            return Ok(Walked::End);
+        }
+        let len = <[u8; 4]>::try_from(len).map_err(|_| CollectionError::Malformed { index })?;
+        let len = u32::from_be_bytes(len) as usize;
+        if skipped {
+            self.position += len;
+            // past the end of a state of an unknown size, found once reading on
+            if self.reader.size().is_some_and(|size| self.position > size) {
+                return Err(CollectionError::Malformed { index });
+            }
+            return Ok(Walked::Skipped);
+        }
+        let data = self.take(len).await?;
+        if data.len() < len {
+            return Err(CollectionError::Malformed { index });
+        }
+        Ok(Walked::Entry(data))
+    }
+
+    /// Up to `len` bytes from the position on, fewer only at the end of the state.
+    async fn take(&mut self, len: usize) -> Result<Vec<u8>, StateStoreError> {
+        let mut taken = Vec::with_capacity(len.min(CHUNK_LEN));
+        while taken.len() < len {
+            let wanted = len - taken.len();
+            let in_chunk = self
+                .position
+                .checked_sub(self.chunk_at)
+                .filter(|at| *at < self.chunk.len());
+            let read = match in_chunk {
+                Some(at) => {
+                    let end = self.chunk.len().min(at + wanted);
+                    taken.extend_from_slice(&self.chunk[at..end]);
+                    end - at
+                }
+                // as large as a chunk, read on its own
+                None if wanted >= CHUNK_LEN => {
+                    let read = self.reader.read_at(self.position, wanted).await?;
+                    taken.extend_from_slice(&read);
+                    read.len()
+                }
+                None => {
+                    self.chunk = self.reader.read_at(self.position, CHUNK_LEN).await?;
+                    self.chunk_at = self.position;
+                    if self.chunk.is_empty() {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            if read == 0 {
+                break;
+            }
+            self.position += read;
+        }
+        Ok(taken)
+    }
+}
+
+/// Streams the entries of the state read by `reader` from `offset`, at most `limit` of them.
+pub(crate) fn stream_range<S>(
+    reader: StateReader<S>,
+    offset: usize,
+    limit: usize,
+) -> mpsc::Receiver<RangeFrame>
+where
+    S: StateStorage + Send + Sync + 'static,
+    <S as StateStorage>::Error: Into<anyhow::Error>,
+{
+    let (frames, streamed) = mpsc::channel(BUFFERED_FRAMES);
+    tokio::spawn(async move {
+        let mut cursor = Cursor::new(reader);
+        let mut sent = 0;
+        let mut index = 0;
+        while sent < limit {
+            let frame = match cursor.entry(index, index < offset).await {
+                Ok(Walked::Entry(data)) => RangeFrame::Entry { index, data },
+                Ok(Walked::Skipped) => {
+                    index += 1;
+                    continue;
+                }
+                Ok(Walked::End) => break,
+                Err(err) => {
+                    let _ = frames
+                        .send(RangeFrame::Error {
+                            cause: err.to_string(),
+                        })
+                        .await;
+                    return;
+                }
+            };
+            if frames.send(frame).await.is_err() {
+                // the client went away
+                return;
+            }
+            sent += 1;
+            index += 1;
+        }
+        let _ = frames.send(RangeFrame::Complete { entries: sent }).await;
+    });
+    streamed
+}
+
+#[cfg(test)]
+pub(crate) fn collection<T: AsRef<[u8]>>(entries: impl IntoIterator<Item = T>) -> Vec<u8> {
+    let mut state = Vec::new();
+    for entry in entries {
+        let entry = entry.as_ref();
+        state.extend((entry.len() as u32).to_be_bytes());
+        state.extend(entry);
+    }
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use freenet_stdlib::prelude::WrappedState;
+
+    use crate::contract::storages::Storage;
+
+    use super::*;
+
+    async fn streamed(state: Vec<u8>, offset: usize, limit: usize) -> Vec<RangeFrame> {
+        let reader = StateReader::<Storage>::from(WrappedState::new(state));
+        let mut frames = stream_range(reader, offset, limit);
+        let mut streamed = Vec::new();
+        while let Some(frame) = frames.recv().await {
+            streamed.push(frame);
+        }
+        streamed
+    }
+
+    #[tokio::test]
+    async fn streams_requested_range() {
+        let state = collection([&b"a"[..], b"", b"ccc", b"dd"]);
+        assert_eq!(
+            streamed(state.clone(), 1, 2).await,
+            [
+                RangeFrame::Entry {
+                    index: 1,
+                    data: vec![]
+                },
+                RangeFrame::Entry {
+                    index: 2,
+                    data: b"ccc".to_vec()
+                },
+                RangeFrame::Complete { entries: 2 },
+            ]
+        );
+        assert_eq!(
+            streamed(state, 3, 10).await,
+            [
+                RangeFrame::Entry {
+                    index: 3,
+                    data: b"dd".to_vec()
+                },
+                RangeFrame::Complete { entries: 1 },
+            ]
+        );
+        assert_eq!(
+            streamed(vec![], 0, 10).await,
+            [RangeFrame::Complete { entries: 0 }]
+        );
+    }
+
+    #[tokio::test]
+    async fn entries_across_chunks() {
+        // larger than a chunk, and straddling the end of one
+        let large = vec![1; CHUNK_LEN + 10];
+        let straddling = vec![2; CHUNK_LEN - 10];
+        let state = collection([&large, &straddling, &large]);
+        let frames = streamed(state, 1, 2).await;
+        assert_eq!(
+            frames,
+            [
+                RangeFrame::Entry {
+                    index: 1,
+                    data: straddling
+                },
+                RangeFrame::Entry {
+                    index: 2,
+                    data: large
+                },
+                RangeFrame::Complete { entries: 2 },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_collection_ends_stream() {
+        let mut state = collection([b"abc", b"def"]);
+        state.truncate(state.len() - 1);
+        let frames = streamed(state.clone(), 0, 10).await;
+        assert!(matches!(frames[0], RangeFrame::Entry { index: 0, .. }));
+        assert!(matches!(&frames[1..], [RangeFrame::Error { .. }]));
+        // even if the truncated entry is skipped
+        assert!(matches!(
+            &streamed(state, 2, 10).await[..],
+            [RangeFrame::Error { .. }]
+        ));
+    }
+
+    #[test]
+    fn entries_base64_encoded() {
+        let frame = RangeFrame::Entry {
+            index: 3,
+            data: b"entry".to_vec(),
+        };
+        assert_eq!(
+            serde_json::to_value(&frame).unwrap(),
+            serde_json::json!({ "entry": { "index": 3, "data": "ZW50cnk=" } })
+        );
+    }
+}
@@ -22,23 +22,32 @@ use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::{self};
 
 use super::storages::Storage;
-use crate::config::Config;
+use crate::config::{Config, DeterminismCheck};
+#[cfg(feature = "http-gateway")]
+use crate::contract::{backup::ExportedContract, collection::RangeFrame};
 use crate::message::Transaction;
 use crate::node::OpManager;
 use crate::operations::get::GetResult;
 use crate::operations::{OpEnum, OpError};
 use crate::wasm_runtime::{
-    ContractExecError, ContractRuntimeInterface, ContractStore, DelegateRuntimeInterface,
-    DelegateStore, Runtime, SecretsStore, StateStore, StateStoreError,
+    state_version, ContractExecError, ContractRuntimeInterface, ContractStore,
+    DelegateRuntimeInterface, DelegateStore, Runtime, SecretsStore, StateStore, StateStoreError,
 };
+#[cfg(feature = "http-gateway")]
+use crate::wasm_runtime::{HistoricalState, StateVersion};
 use crate::{
     client_events::{ClientId, HostResult},
     operations::{self, Operation},
 };
 
 pub(super) mod mock_runtime;
+mod notification_shards;
 pub(super) mod runtime;
 
+use notification_shards::{Notification, Payload};
+
+const MISSING_STATE_CAUSE: &str = "contract state not found";
+
 #[derive(Debug)]
 pub struct ExecutorError {
     inner: Either<Box<RequestError>, anyhow::Error>,
@@ -50,6 +59,199 @@ enum InnerOpError {
     Delegate(DelegateKey),
 }
 
+#[derive(Clone, Copy)]
+enum ValidatedOp {
+    Put,
+    Update,
+}
+
+/// A state or delta rejected by a contract during validation.
+///
+/// Contracts can attach a machine readable code to the errors they return by reporting them
+/// as a JSON object with the code and the message, e.g.
+/// `ContractError::Other(r#"{"code":"insufficient-funds","message":"balance is negative"}"#)`;
+/// any other error is a rejection without a code. The error reaches clients as the cause of
+/// the `ContractError` for the operation, formatted as `validation failed [<code>]: <message>`,
+/// which can be parsed back with [`ValidationError::from_cause`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ValidationError {
+    pub code: Option<String>,
+    pub message: String,
+}
+
+impl ValidationError {
+    const PREFIX: &'static str = "validation failed";
+
+    /// The rejection reported by a contract as a JSON object with a code, if it was.
+    pub(crate) fn coded(err: &ContractError) -> Option<Self> {
+        let (ContractError::Other(msg) | ContractError::InvalidUpdateWithInfo { reason: msg }) =
+            err
+        else {
+            return None;
+        };
+        serde_json::from_str::<Self>(msg)
+            .ok()
+            .filter(|rejected| rejected.code.as_deref().is_some_and(Self::is_code))
+    }
+
+    /// The rejection a contract reported while running, if the error is one.
+    fn from_runtime(err: &crate::wasm_runtime::RuntimeInnerError) -> Option<Self> {
+        match err {
+            crate::wasm_runtime::RuntimeInnerError::ContractExecError(err) => match err {
+                ContractExecError::Rejected(rejected) => Some(rejected.clone()),
+                ContractExecError::ContractError(err) => Some(Self::from(err)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn is_code(code: &str) -> bool {
+        !code.is_empty()
+            && code
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    }
+
+    /// Parses the cause of a `ContractError` returned to a client, if it was produced by
+    /// a validation failure.
+    pub fn from_cause(cause: &str) -> Option<Self> {
+        let rest = cause.strip_prefix(Self::PREFIX)?;
+        if let Some(message) = rest.strip_prefix(": ") {
+            return Some(Self {
+                code: None,
+                message: message.to_owned(),
+            });
+        }
+        let (code, message) = rest.strip_prefix(" [")?.split_once("]: ")?;
+        Self::is_code(code).then(|| Self {
+            code: Some(code.to_owned()),
+            message: message.to_owned(),
+        })
+    }
+}
+
+impl From<&ContractError> for ValidationError {
+    fn from(err: &ContractError) -> Self {
+        let message = match err {
+            ContractError::Other(msg) | ContractError::InvalidUpdateWithInfo { reason: msg } => {
+                msg.clone()
+            }
+            other => other.to_string(),
+        };
+        Self {
+            code: None,
+            message,
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.code {
+            Some(code) => write!(f, "{} [{code}]: {}", Self::PREFIX, self.message),
+            None => write!(f, "{}: {}", Self::PREFIX, self.message),
+        }
+    }
+}
+
+#[cfg(feature = "http-gateway")]
+/// Outcome of validating the state stored for a contract again, see
+/// [`Executor::revalidate_contract`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "result", rename_all = "camelCase")]
+pub enum Revalidation {
+    Valid,
+    Invalid,
+    /// The contract needs the state of these other contracts to decide.
+    RequestRelated {
+        contracts: Vec<String>,
+    },
+    /// The contract failed while validating, e.g. it couldn't make sense of the state at all.
+    Failed {
+        cause: String,
+    },
+}
+
+/// Describes the state stored for a contract, see [`Executor::contract_metadata`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContractMetadata {
+    pub key: String,
+    pub code_hash: Option<String>,
+    /// Size in bytes of the current state.
+    pub state_size: usize,
+    /// Hash of the current state, changes whenever the state does.
+    pub version: String,
+}
+
+#[cfg(feature = "http-gateway")]
+/// Outcome of updating several contracts as a whole, see [`Executor::commit_transaction`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "camelCase")]
+pub enum TransactionOutcome {
+    /// Every update was applied, leaving each contract at the version listed.
+    Committed { contracts: Vec<CommittedContract> },
+    /// None of the updates was applied, the one at `index` failed.
+    Aborted { index: usize, cause: String },
+}
+
+#[cfg(feature = "http-gateway")]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommittedContract {
+    pub key: String,
+    pub version: String,
+}
+
+#[cfg(feature = "http-gateway")]
+/// What brings a client from a past version of a contract state to a newer one, see
+/// [`Executor::state_diff`].
+#[derive(Debug, Clone)]
+pub enum StateDiff {
+    /// Delta computed by the contract from the past version.
+    Delta {
+        version: String,
+        delta: StateDelta<'static>,
+    },
+    /// The whole newer state, the past version being no longer retained.
+    State {
+        version: String,
+        state: WrappedState,
+    },
+}
+
+#[cfg(feature = "http-gateway")]
+/// A contract updated within a transaction, not stored until every update is staged.
+struct StagedState {
+    key: ContractKey,
+    params: Parameters<'static>,
+    original: WrappedState,
+    updated: WrappedState,
+}
+
+#[cfg(feature = "http-gateway")]
+/// Estimated cost of applying an update to a contract, see [`Executor::estimate_update`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CostEstimate {
+    /// Time the contract took to merge the update and validate the result.
+    pub cpu_micros: u64,
+    /// Size in bytes of the state stored now.
+    pub state_size: usize,
+    /// Size in bytes the state would have after the update.
+    pub updated_size: usize,
+    /// Bytes the update would add to the storage, negative if it shrinks the state.
+    pub storage_delta: i64,
+    /// Whether the resulting state would pass validation.
+    pub valid: bool,
+    /// Contracts whose state is needed to apply the update, which were not accounted for.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub related: Vec<String>,
+}
+
 impl std::error::Error for ExecutorError {}
 
 impl ExecutorError {
@@ -68,7 +270,7 @@ impl ExecutorError {
         }
     }
 
-    fn request(error: impl Into<RequestError>) -> Self {
+    pub fn request(error: impl Into<RequestError>) -> Self {
         Self {
             inner: Either::Left(Box::new(error.into())),
             fatal: false,
@@ -140,6 +342,26 @@ impl ExecutorError {
         err
     }
 
+    /// Maps errors raised while validating a contract state, surfacing a rejection reported
+    /// by the contract itself as a request error carrying a [`ValidationError`].
+    fn validation(
+        outer_error: crate::wasm_runtime::ContractError,
+        key: ContractKey,
+        op: ValidatedOp,
+    ) -> Self {
+        if let Some(rejected) = ValidationError::from_runtime(outer_error.deref()) {
+            let cause = rejected.to_string().into();
+            tracing::debug!(contract = %key, %cause, "contract rejected state");
+            return match op {
+                ValidatedOp::Put => ExecutorError::request(StdContractError::Put { key, cause }),
+                ValidatedOp::Update => {
+                    ExecutorError::request(StdContractError::Update { key, cause })
+                }
+            };
+        }
+        ExecutorError::execution(outer_error, None)
+    }
+
     pub fn is_request(&self) -> bool {
         matches!(self.inner, Either::Left(_))
     }
@@ -148,6 +370,56 @@ impl ExecutorError {
         self.fatal
     }
 
+    pub(crate) fn missing_contract(key: ContractKey) -> Self {
+        ExecutorError::request(StdContractError::Get {
+            key,
+            cause: MISSING_STATE_CAUSE.into(),
+        })
+    }
+
+    /// The error reported to the client for a state which can't be read from the store,
+    /// corrupt states included.
+    pub(crate) fn stored_state(key: ContractKey, err: StateStoreError) -> Self {
+        match err {
+            StateStoreError::MissingContract(_) => Self::missing_contract(key),
+            err @ (StateStoreError::DataCorruption(_) | StateStoreError::UnknownFormat(..)) => {
+                Self::request(StdContractError::Get {
+                    key,
+                    cause: err.to_string().into(),
+                })
+            }
+            err => Self::other(err),
+        }
+    }
+
+    /// Whether the error reports that no state is stored for the requested contract.
+    pub fn is_missing_contract(&self) -> bool {
+        matches!(
+            &self.inner,
+            Either::Left(err) if matches!(
+                &**err,
+                RequestError::ContractError(StdContractError::Get { cause, .. })
+                    if cause == MISSING_STATE_CAUSE
+            )
+        )
+    }
+
+    /// The messages of the error and of each error which caused it, outermost first.
+    pub fn chain(&self) -> Vec<String> {
+        match &self.inner {
+            Either::Left(err) => {
+                let mut chain = vec![err.to_string()];
+                let mut source = std::error::Error::source(&**err);
+                while let Some(err) = source {
+                    chain.push(err.to_string());
+                    source = err.source();
+                }
+                chain
+            }
+            Either::Right(err) => err.chain().map(ToString::to_string).collect(),
+        }
+    }
+
     pub fn unwrap_request(self) -> RequestError {
         match self.inner {
             Either::Left(err) => *err,
@@ -498,6 +770,8 @@ pub struct Executor<R = Runtime> {
     delegate_attested_ids: HashMap<DelegateKey, Vec<ContractInstanceId>>,
 
     event_loop_channel: Option<ExecutorToEventLoopChannel<ExecutorHalve>>,
+    determinism_check: DeterminismCheck,
+    notification_shards: notification_shards::NotificationShards,
 }
 
 impl<R> Executor<R> {
@@ -518,9 +792,22 @@ impl<R> Executor<R> {
             subscriber_summaries: HashMap::default(),
             delegate_attested_ids: HashMap::default(),
             event_loop_channel,
+            determinism_check: DeterminismCheck::default(),
+            notification_shards: Default::default(),
         })
     }
 
+    pub fn with_determinism_check(mut self, check: DeterminismCheck) -> Self {
+        self.determinism_check = check;
+        self
+    }
+
+    /// Delivers update notifications from `shards` worker tasks instead of the executor's.
+    pub fn with_notification_shards(mut self, shards: usize) -> Self {
+        self.notification_shards = notification_shards::NotificationShards::new(shards);
+        self
+    }
+
     pub fn test_data_dir(identifier: &str) -> PathBuf {
         std::env::temp_dir().join(format!("freenet-executor-{identifier}"))
     }
@@ -539,13 +826,34 @@ impl<R> Executor<R> {
         const MAX_SIZE: i64 = 10 * 1024 * 1024;
         const MAX_MEM_CACHE: u32 = 10_000_000;
 
-        let state_store =
-            StateStore::new(Storage::new(&config.db_dir()).await?, MAX_MEM_CACHE).unwrap();
-        let contract_store = ContractStore::new(config.contracts_dir(), MAX_SIZE)?;
+        let [db_dir, contracts_dir, delegates_dir, secrets_dir] = match &config.executor.storage_dir
+        {
+            Some(dir) => {
+                let dirs = ["db", "contracts", "delegates", "secrets"].map(|store| dir.join(store));
+                for dir in &dirs {
+                    tokio::fs::create_dir_all(dir).await?;
+                }
+                dirs
+            }
+            None => [
+                config.db_dir(),
+                config.contracts_dir(),
+                config.delegates_dir(),
+                config.secrets_dir(),
+            ],
+        };
+
+        let state_store = StateStore::new(
+            Storage::open(config.executor.storage_backend, &db_dir).await?,
+            MAX_MEM_CACHE,
+        )
+        .unwrap()
+        .with_history(config.executor.state_history_depth);
+        let contract_store = ContractStore::new(contracts_dir, MAX_SIZE)?;
 
-        let delegate_store = DelegateStore::new(config.delegates_dir(), MAX_SIZE)?;
+        let delegate_store = DelegateStore::new(delegates_dir, MAX_SIZE)?;
 
-        let secret_store = SecretsStore::new(config.secrets_dir(), config.secrets.clone())?;
+        let secret_store = SecretsStore::new(secrets_dir, config.secrets.clone())?;
 
         Ok((contract_store, delegate_store, secret_store, state_store))
     }
@@ -590,3 +898,1194 @@ impl<R> Executor<R> {
         Ok(result)
     }
 }
+
+impl<R> Executor<R> {
+    #[cfg(feature = "http-gateway")]
+    /// Runs the contract's state validation again over the state currently stored for it.
+    ///
+    /// Meant to diagnose state suspected to be corrupted, nothing is written back whatever
+    /// the outcome.
+    pub(crate) async fn revalidate_contract(
+        &mut self,
+        key: &ContractKey,
+    ) -> Result<Revalidation, ExecutorError>
+    where
+        R: ContractRuntimeInterface,
+    {
+        let state = self.stored_state(key).await?;
+        let params = self
+            .state_store
+            .get_params(key)
+            .await
+            .map_err(ExecutorError::other)?
+            .ok_or_else(|| ExecutorError::missing_contract(*key))?;
+        let result =
+            match self
+                .runtime
+                .validate_state(key, &params, &state, &RelatedContracts::default())
+            {
+                Ok(ValidateResult::Valid) => Revalidation::Valid,
+                Ok(ValidateResult::Invalid) => Revalidation::Invalid,
+                Ok(ValidateResult::RequestRelated(related)) => Revalidation::RequestRelated {
+                    contracts: related.iter().map(ToString::to_string).collect(),
+                },
+                Err(err) => Revalidation::Failed {
+                    cause: ValidationError::from_runtime(err.deref())
+                        .map_or_else(|| err.deref().to_string(), |rejected| rejected.to_string()),
+                },
+            };
+        tracing::info!(contract = %key, ?result, "re-validated stored state");
+        Ok(result)
+    }
+
+    #[cfg(feature = "http-gateway")]
+    /// Runs an update to a contract without committing it, measuring what applying it would
+    /// cost. Nothing is stored nor are subscribers notified.
+    pub(crate) async fn estimate_update(
+        &mut self,
+        key: &ContractKey,
+        update: UpdateData<'static>,
+    ) -> Result<CostEstimate, ExecutorError>
+    where
+        R: ContractRuntimeInterface,
+    {
+        let state = self.stored_state(key).await?;
+        let params = self
+            .state_store
+            .get_params(key)
+            .await
+            .map_err(ExecutorError::other)?
+            .ok_or_else(|| ExecutorError::missing_contract(*key))?;
+        let started = Instant::now();
+        let modification = self
+            .runtime
+            .update_state(key, &params, &state, &[update])
+            .map_err(|err| ExecutorError::execution(err, Some(InnerOpError::Upsert(*key))))?;
+        let updated = modification
+            .new_state
+            .map(|state| WrappedState::new(state.into_bytes()))
+            .unwrap_or_else(|| state.clone());
+        let valid =
+            match self
+                .runtime
+                .validate_state(key, &params, &updated, &RelatedContracts::default())
+            {
+                Ok(result) => result == ValidateResult::Valid,
+                Err(err) => return Err(ExecutorError::execution(err, None)),
+            };
+        let estimate = CostEstimate {
+            cpu_micros: started.elapsed().as_micros().try_into().unwrap_or(u64::MAX),
+            state_size: state.size(),
+            updated_size: updated.size(),
+            storage_delta: updated.size() as i64 - state.size() as i64,
+            valid,
+            related: modification
+                .related
+                .iter()
+                .map(|related| related.contract_instance_id.to_string())
+                .collect(),
+        };
+        tracing::debug!(contract = %key, ?estimate, "estimated update cost");
+        Ok(estimate)
+    }
+
+    #[cfg(feature = "http-gateway")]
+    /// Stores updates to several contracts all together or not at all, returning the outcome
+    /// along with the contracts changed. Every resulting state is computed and validated before
+    /// any is stored, an update building on the ones before it to the same contract, and all of
+    /// them are stored within a single transaction of the storage.
+    ///
+    /// Updates needing the state of other contracts are not supported within transactions.
+    async fn commit_transaction(
+        &mut self,
+        updates: Vec<(ContractKey, UpdateData<'static>)>,
+    ) -> Result<(TransactionOutcome, Vec<StagedState>), ExecutorError>
+    where
+        R: ContractRuntimeInterface,
+    {
+        let mut staged: Vec<StagedState> = Vec::new();
+        for (index, (key, update)) in updates.into_iter().enumerate() {
+            if let Err(err) = self.stage_update(&mut staged, key, update).await {
+                tracing::debug!(contract = %key, index, %err, "transaction aborted");
+                let aborted = TransactionOutcome::Aborted {
+                    index,
+                    cause: err.to_string(),
+                };
+                return Ok((aborted, vec![]));
+            }
+        }
+
+        let committed = TransactionOutcome::Committed {
+            contracts: staged
+                .iter()
+                .map(|state| CommittedContract {
+                    key: state.key.to_string(),
+                    version: state_version(&state.updated),
+                })
+                .collect(),
+        };
+        staged.retain(|state| state.updated.as_ref() != state.original.as_ref());
+        let states = staged
+            .iter()
+            .map(|state| (state.key, state.updated.clone()))
+            .collect();
+        self.state_store
+            .update_all(states)
+            .await
+            .map_err(ExecutorError::other)?;
+        Ok((committed, staged))
+    }
+
+    #[cfg(feature = "http-gateway")]
+    async fn stage_update(
+        &mut self,
+        staged: &mut Vec<StagedState>,
+        key: ContractKey,
+        update: UpdateData<'static>,
+    ) -> Result<(), ExecutorError>
+    where
+        R: ContractRuntimeInterface,
+    {
+        let state = match staged.iter().position(|state| state.key == key) {
+            Some(position) => &mut staged[position],
+            None => {
+                let original = self.stored_state(&key).await?;
+                let params = self
+                    .state_store
+                    .get_params(&key)
+                    .await
+                    .map_err(ExecutorError::other)?
+                    .ok_or_else(|| ExecutorError::missing_contract(key))?;
+                staged.push(StagedState {
+                    key,
+                    params,
+                    updated: original.clone(),
+                    original,
+                });
+                staged.last_mut().expect("just staged")
+            }
+        };
+        let modification = self
+            .runtime
+            .update_state(&key, &state.params, &state.updated, &[update])
+            .map_err(|err| ExecutorError::execution(err, Some(InnerOpError::Upsert(key))))?;
+        if !modification.related.is_empty() {
+            return Err(ExecutorError::request(StdContractError::Update {
+                key,
+                cause: "updates needing related contracts are not supported in transactions".into(),
+            }));
+        }
+        let Some(updated) = modification.new_state else {
+            return Ok(());
+        };
+        let updated = WrappedState::new(updated.into_bytes());
+        match self.checked_validate_state(
+            &key,
+            &state.params,
+            &updated,
+            &RelatedContracts::default(),
+        ) {
+            Ok(ValidateResult::Valid) => {}
+            Ok(_) => {
+                return Err(ExecutorError::request(StdContractError::Update {
+                    key,
+                    cause: "not valid".into(),
+                }))
+            }
+            Err(err) => return Err(ExecutorError::validation(err, key, ValidatedOp::Update)),
+        }
+        state.updated = updated;
+        Ok(())
+    }
+
+    /// Tells the subscribers of the contract its code was upgraded, sending each the whole
+    /// state under the key of the new code whatever the summary it subscribed with, so it can
+    /// reload anything built upon the previous code.
+    fn notify_code_upgrade(&mut self, key: &ContractKey, state: &WrappedState) {
+        let Some(notifiers) = self.update_notifications.get_mut(key) else {
+            return;
+        };
+        notifiers.retain(|(_, notifier)| !notifier.is_closed());
+        tracing::info!(contract = %key, subscribers = notifiers.len(), "notify of contract code upgrade");
+        let notifications = notifiers
+            .iter()
+            .map(|(client, channel)| Notification {
+                key: *key,
+                client: *client,
+                channel: channel.clone(),
+                payload: Payload::State(state.clone()),
+            })
+            .collect();
+        self.notification_shards.deliver(notifications);
+    }
+
+    /// Validates a state with the contract, running it a second time to compare the outcomes
+    /// when checking for nondeterministic contracts.
+    fn checked_validate_state(
+        &mut self,
+        key: &ContractKey,
+        params: &Parameters<'_>,
+        state: &WrappedState,
+        related: &RelatedContracts<'_>,
+    ) -> crate::wasm_runtime::RuntimeResult<ValidateResult>
+    where
+        R: ContractRuntimeInterface,
+    {
+        let first = self.runtime.validate_state(key, params, state, related);
+        if self.determinism_check == DeterminismCheck::Off {
+            return first;
+        }
+        let second = self.runtime.validate_state(key, params, state, related);
+        let diverged = match (&first, &second) {
+            (Ok(first), Ok(second)) => first != second,
+            (Err(first), Err(second)) => first.to_string() != second.to_string(),
+            _ => true,
+        };
+        if !diverged {
+            return first;
+        }
+        tracing::warn!(contract = %key, ?first, ?second, "nondeterministic contract validation");
+        match self.determinism_check {
+            DeterminismCheck::Reject => Err(anyhow::anyhow!(
+                "contract {key} is nondeterministic, validating the same state twice gave different outcomes"
+            )
+            .into()),
+            _ => first,
+        }
+    }
+
+    /// Metadata of the state stored for a contract, as kept by the state store when writing
+    /// the state, which is neither read nor hashed again.
+    pub(crate) async fn state_metadata(
+        &self,
+        key: &ContractKey,
+    ) -> Result<ContractMetadata, ExecutorError> {
+        let metadata = self
+            .state_store
+            .metadata(key)
+            .await
+            .map_err(|err| ExecutorError::stored_state(*key, err))?;
+        Ok(ContractMetadata {
+            key: key.encoded_contract_id(),
+            code_hash: key.encoded_code_hash(),
+            state_size: metadata.size,
+            version: metadata.version,
+        })
+    }
+
+    #[cfg(feature = "http-gateway")]
+    /// A past state of a contract, none if it is no longer retained.
+    pub(crate) async fn historical_state(
+        &self,
+        key: &ContractKey,
+        version: &StateVersion,
+    ) -> Result<Option<HistoricalState>, ExecutorError> {
+        if let Some(state) = self.state_store.version(key, version) {
+            return Ok(Some(state));
+        }
+        // nothing retained at all for contracts the node doesn't have
+        self.stored_state(key).await?;
+        Ok(None)
+    }
+
+    #[cfg(feature = "http-gateway")]
+    /// The difference between the `base` version of the state of a contract and the `target`
+    /// one, the current state if none. The contract computes the delta from a summary of the
+    /// base version, unless that version is no longer retained and the whole target state is
+    /// returned instead. None if the target version is no longer retained.
+    pub(crate) async fn state_diff(
+        &mut self,
+        key: &ContractKey,
+        base: &StateVersion,
+        target: Option<&StateVersion>,
+    ) -> Result<Option<StateDiff>, ExecutorError>
+    where
+        R: ContractRuntimeInterface,
+    {
+        let current = self.stored_state(key).await?;
+        let target = match target {
+            None => current,
+            Some(version) => match self.state_store.version(key, version) {
+                Some(retained) => retained.state,
+                None => return Ok(None),
+            },
+        };
+        let version = state_version(&target);
+        let Some(base) = self.state_store.version(key, base) else {
+            return Ok(Some(StateDiff::State {
+                version,
+                state: target,
+            }));
+        };
+        let params = self
+            .state_store
+            .get_params(key)
+            .await
+            .map_err(ExecutorError::other)?
+            .ok_or_else(|| ExecutorError::missing_contract(*key))?;
+        let summary = self
+            .runtime
+            .summarize_state(key, &params, &base.state)
+            .map_err(|err| ExecutorError::execution(err, None))?;
+        let delta = self
+            .runtime
+            .get_state_delta(key, &params, &target, &summary)
+            .map_err(|err| ExecutorError::execution(err, None))?;
+        Ok(Some(StateDiff::Delta { version, delta }))
+    }
+
+    #[cfg(feature = "http-gateway")]
+    /// Streams the entries from `offset` of the collection stored for a contract, at most
+    /// `limit` of them.
+    pub(crate) async fn range_query(
+        &self,
+        key: &ContractKey,
+        offset: usize,
+        limit: usize,
+    ) -> Result<mpsc::Receiver<RangeFrame>, ExecutorError> {
+        let reader = self
+            .state_store
+            .reader(key)
+            .await
+            .map_err(|err| ExecutorError::stored_state(*key, err))?;
+        Ok(super::collection::stream_range(reader, offset, limit))
+    }
+
+    async fn stored_state(&self, key: &ContractKey) -> Result<WrappedState, ExecutorError> {
+        self.state_store
+            .get(key)
+            .await
+            .map_err(|err| ExecutorError::stored_state(*key, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contract_validation_code_reaches_client() {
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let rejected = crate::wasm_runtime::ContractError::from(ContractExecError::from_contract(
+            ContractError::Other(
+                r#"{"code":"insufficient-funds","message":"balance cannot be negative"}"#.into(),
+            ),
+        ));
+
+        let err = ExecutorError::validation(rejected, key, ValidatedOp::Put);
+        assert!(err.is_request());
+        let RequestError::ContractError(StdContractError::Put { cause, .. }) = err.unwrap_request()
+        else {
+            panic!("expected a put error");
+        };
+        assert_eq!(
+            ValidationError::from_cause(&cause),
+            Some(ValidationError {
+                code: Some("insufficient-funds".into()),
+                message: "balance cannot be negative".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn validation_error_without_code() {
+        let err = ValidationError::from(&ContractError::InvalidState);
+        assert_eq!(err.code, None);
+        assert_eq!(ValidationError::from_cause(&err.to_string()), Some(err));
+        assert_eq!(ValidationError::from_cause("missing contract"), None);
+
+        // a message which merely looks like it starts with a code has none
+        let err = ContractError::Other("insufficient-funds: balance cannot be negative".into());
+        assert_eq!(ValidationError::coded(&err), None);
+        assert_eq!(ValidationError::from(&err).code, None);
+        let err = ContractError::Other(r#"{"code":"","message":"no code"}"#.into());
+        assert_eq!(ValidationError::coded(&err), None);
+    }
+
+    /// Accepts states whose last byte is the checksum of the preceding ones.
+    struct ChecksumRuntime;
+
+    impl ContractRuntimeInterface for ChecksumRuntime {
+        fn validate_state(
+            &mut self,
+            _key: &ContractKey,
+            _parameters: &Parameters<'_>,
+            state: &WrappedState,
+            _related: &RelatedContracts<'_>,
+        ) -> crate::wasm_runtime::RuntimeResult<ValidateResult> {
+            let Some((checksum, data)) = state.as_ref().split_last() else {
+                return Err(ContractExecError::ContractError(ContractError::Deser(
+                    "empty state".into(),
+                ))
+                .into());
+            };
+            let sum = data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+            Ok(if sum == *checksum {
+                ValidateResult::Valid
+            } else {
+                ValidateResult::Invalid
+            })
+        }
+
+        /// Appends deltas to the data, keeping the checksum current; states replace it as sent.
+        fn update_state(
+            &mut self,
+            _key: &ContractKey,
+            _parameters: &Parameters<'_>,
+            state: &WrappedState,
+            update_data: &[UpdateData<'_>],
+        ) -> crate::wasm_runtime::RuntimeResult<UpdateModification<'static>> {
+            let mut new_state = state.as_ref().to_vec();
+            for update in update_data {
+                match update {
+                    UpdateData::Delta(delta) => {
+                        new_state.pop();
+                        new_state.extend_from_slice(delta.as_ref());
+                        let sum = new_state.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+                        new_state.push(sum);
+                    }
+                    UpdateData::State(state) => new_state = state.as_ref().to_vec(),
+                    _ => {
+                        return Err(
+                            ContractExecError::ContractError(ContractError::InvalidUpdate).into(),
+                        )
+                    }
+                }
+            }
+            Ok(UpdateModification::valid(State::from(new_state)))
+        }
+
+        /// Summarized by the length of the data, deltas being appended to it.
+        fn summarize_state(
+            &mut self,
+            _key: &ContractKey,
+            _parameters: &Parameters<'_>,
+            state: &WrappedState,
+        ) -> crate::wasm_runtime::RuntimeResult<StateSummary<'static>> {
+            let len = state.as_ref().len().saturating_sub(1) as u64;
+            Ok(StateSummary::from(len.to_le_bytes().to_vec()))
+        }
+
+        fn get_state_delta(
+            &mut self,
+            _key: &ContractKey,
+            _parameters: &Parameters<'_>,
+            state: &WrappedState,
+            delta_to: &StateSummary<'_>,
+        ) -> crate::wasm_runtime::RuntimeResult<StateDelta<'static>> {
+            let len = u64::from_le_bytes(delta_to.as_ref().try_into().expect("summary")) as usize;
+            let data = &state.as_ref()[..state.as_ref().len() - 1];
+            Ok(StateDelta::from(data[len..].to_vec()))
+        }
+    }
+
+    /// Alternately accepts and rejects every state.
+    #[derive(Default)]
+    struct FlakyRuntime {
+        runs: usize,
+    }
+
+    impl ContractRuntimeInterface for FlakyRuntime {
+        fn validate_state(
+            &mut self,
+            _key: &ContractKey,
+            _parameters: &Parameters<'_>,
+            _state: &WrappedState,
+            _related: &RelatedContracts<'_>,
+        ) -> crate::wasm_runtime::RuntimeResult<ValidateResult> {
+            self.runs += 1;
+            Ok(if self.runs % 2 == 1 {
+                ValidateResult::Valid
+            } else {
+                ValidateResult::Invalid
+            })
+        }
+
+        /// Leaves the state as it was.
+        fn update_state(
+            &mut self,
+            _key: &ContractKey,
+            _parameters: &Parameters<'_>,
+            state: &WrappedState,
+            _update_data: &[UpdateData<'_>],
+        ) -> crate::wasm_runtime::RuntimeResult<UpdateModification<'static>> {
+            Ok(UpdateModification::valid(State::from(
+                state.as_ref().to_vec(),
+            )))
+        }
+
+        fn summarize_state(
+            &mut self,
+            _key: &ContractKey,
+            _parameters: &Parameters<'_>,
+            _state: &WrappedState,
+        ) -> crate::wasm_runtime::RuntimeResult<StateSummary<'static>> {
+            Ok(StateSummary::from(vec![]))
+        }
+
+        fn get_state_delta(
+            &mut self,
+            _key: &ContractKey,
+            _parameters: &Parameters<'_>,
+            state: &WrappedState,
+            _delta_to: &StateSummary<'_>,
+        ) -> crate::wasm_runtime::RuntimeResult<StateDelta<'static>> {
+            Ok(StateDelta::from(state.as_ref().to_vec()))
+        }
+    }
+
+    #[tokio::test]
+    async fn nondeterministic_validation() -> anyhow::Result<()> {
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let params = Parameters::from(vec![]);
+        let state = WrappedState::new(vec![1, 2, 3, 6]);
+        let related = RelatedContracts::default();
+
+        for check in [
+            DeterminismCheck::Off,
+            DeterminismCheck::Warn,
+            DeterminismCheck::Reject,
+        ] {
+            let tmp_dir = tempfile::tempdir()?;
+            let state_store = StateStore::new(Storage::new(tmp_dir.path()).await?, 10_000_000)?;
+            let mut executor = Executor::new(
+                state_store,
+                || Ok(()),
+                OperationMode::Local,
+                FlakyRuntime::default(),
+                None,
+            )
+            .await?
+            .with_determinism_check(check);
+            let result = executor.checked_validate_state(&key, &params, &state, &related);
+            match check {
+                DeterminismCheck::Off | DeterminismCheck::Warn => {
+                    assert_eq!(result?, ValidateResult::Valid, "{check:?}")
+                }
+                DeterminismCheck::Reject => assert!(result.is_err()),
+            }
+            let runs = if check == DeterminismCheck::Off { 1 } else { 2 };
+            assert_eq!(executor.runtime.runs, runs, "{check:?}");
+        }
+
+        // deterministic contracts are unaffected
+        let tmp_dir = tempfile::tempdir()?;
+        let state_store = StateStore::new(Storage::new(tmp_dir.path()).await?, 10_000_000)?;
+        let mut executor = Executor::new(
+            state_store,
+            || Ok(()),
+            OperationMode::Local,
+            ChecksumRuntime,
+            None,
+        )
+        .await?
+        .with_determinism_check(DeterminismCheck::Reject);
+        assert_eq!(
+            executor.checked_validate_state(&key, &params, &state, &related)?,
+            ValidateResult::Valid
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn executors_with_separate_storage() -> anyhow::Result<()> {
+        let node_dir = tempfile::tempdir()?;
+        let config = crate::config::ConfigArgs {
+            mode: Some(OperationMode::Local),
+            config_paths: crate::config::ConfigPathsArgs {
+                config_dir: Some(node_dir.path().to_path_buf()),
+                data_dir: Some(node_dir.path().to_path_buf()),
+            },
+            ..Default::default()
+        }
+        .build()
+        .await?;
+
+        let mut executors = Vec::new();
+        let mut storage_dirs = Vec::new();
+        for _ in 0..2 {
+            let storage_dir = tempfile::tempdir()?;
+            let mut config = config.clone();
+            config.executor.storage_dir = Some(storage_dir.path().to_path_buf());
+            executors.push(Executor::from_config(Arc::new(config), None).await?);
+            storage_dirs.push(storage_dir);
+        }
+
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        executors[0]
+            .state_store
+            .store(
+                key,
+                WrappedState::new(vec![1, 2, 3]),
+                Parameters::from(vec![]),
+            )
+            .await?;
+        assert_eq!(
+            executors[0].state_store.get(&key).await?.as_ref(),
+            &[1, 2, 3]
+        );
+        assert!(executors[1].state_store.get(&key).await.is_err());
+        for storage_dir in &storage_dirs {
+            assert!(storage_dir.path().join("db").join("db").exists());
+        }
+        assert!(!config.db_dir().join("db").exists());
+
+        // the backend configured, if compiled in
+        let storage_dir = tempfile::tempdir()?;
+        let mut config = config.clone();
+        config.executor.storage_dir = Some(storage_dir.path().to_path_buf());
+        config.executor.storage_backend = crate::config::StorageBackend::Sqlite;
+        let executor = Executor::from_config(Arc::new(config), None).await;
+        if cfg!(feature = "sqlite") {
+            executor?;
+            assert!(storage_dir.path().join("db").join("freenet.db").exists());
+        } else {
+            assert!(executor.is_err());
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "http-gateway")]
+    #[tokio::test]
+    async fn contracts_exported_into_another_node() -> anyhow::Result<()> {
+        use crate::contract::backup::ExportReader;
+
+        async fn node(dir: &std::path::Path) -> anyhow::Result<Executor> {
+            let config = crate::config::ConfigArgs {
+                mode: Some(OperationMode::Local),
+                config_paths: crate::config::ConfigPathsArgs {
+                    config_dir: Some(dir.to_path_buf()),
+                    data_dir: Some(dir.to_path_buf()),
+                },
+                ..Default::default()
+            }
+            .build()
+            .await?;
+            Executor::from_config(Arc::new(config), None).await
+        }
+        async fn export(
+            executor: &Executor,
+            included: Option<ContractInstanceId>,
+        ) -> anyhow::Result<Vec<u8>> {
+            let mut export = Vec::new();
+            for key in executor
+                .exported_contracts(|id| included.map_or(true, |included| *id == included))
+                .await?
+            {
+                if let Some(contract) = executor.export_contract(&key).await? {
+                    export.extend(contract.encode());
+                }
+            }
+            Ok(export)
+        }
+        async fn import(executor: &mut Executor, export: Vec<u8>) -> anyhow::Result<Vec<bool>> {
+            let mut reader = ExportReader::new(1024 * 1024);
+            let mut changed = Vec::new();
+            // as if coming in over the network
+            for chunk in export.chunks(7) {
+                reader.extend(chunk);
+                while let Some(contract) = reader.next_contract()? {
+                    changed.push(executor.import_contract(contract).await?);
+                }
+            }
+            reader.finish()?;
+            Ok(changed)
+        }
+
+        let (source_dir, target_dir) = (tempfile::tempdir()?, tempfile::tempdir()?);
+        let (mut source, mut target) = (
+            node(source_dir.path()).await?,
+            node(target_dir.path()).await?,
+        );
+
+        let code = Arc::new(ContractCode::from(
+            crate::wasm_runtime::tests::get_test_module("test_contract_1")
+                .map_err(|err| anyhow::anyhow!("{err}"))?,
+        ));
+        let contract = |n: u8| {
+            ContractContainer::Wasm(ContractWasmAPIVersion::V1(WrappedContract::new(
+                code.clone(),
+                Parameters::from(vec![n]),
+            )))
+        };
+        let mut keys = Vec::new();
+        for n in 0..3u8 {
+            let contract = contract(n);
+            let key = contract.key();
+            source
+                .runtime
+                .contract_store
+                .store_contract(contract.clone())?;
+            source
+                .state_store
+                .store(
+                    key,
+                    WrappedState::new(vec![1, 2, 3, 4]),
+                    contract.params().into_owned(),
+                )
+                .await?;
+            keys.push(key);
+        }
+
+        let filtered = export(&source, Some(*keys[1].id())).await?;
+        assert_eq!(import(&mut target, filtered).await?, vec![true]);
+        let everything = export(&source, None).await?;
+        let imported = import(&mut target, everything.clone()).await?;
+        assert_eq!(imported.iter().filter(|changed| **changed).count(), 2);
+        for key in &keys {
+            assert_eq!(
+                target.state_store.get(key).await?.as_ref(),
+                source.state_store.get(key).await?.as_ref()
+            );
+            assert_eq!(
+                target.contract_code(key).await?.data(),
+                source.contract_code(key).await?.data()
+            );
+        }
+        // importing again changes nothing
+        assert_eq!(import(&mut target, everything).await?, vec![false; 3]);
+
+        // states are validated as those put
+        let invalid = ExportedContract {
+            contract: contract(3),
+            state: WrappedState::new(vec![1, 2, 3]),
+        };
+        assert!(target.import_contract(invalid).await.is_err());
+        assert!(target.state_store.get(&contract(3).key()).await.is_err());
+
+        let mut truncated = ExportReader::new(1024 * 1024);
+        truncated.extend(&export(&source, None).await?[..10]);
+        assert!(truncated.next_contract()?.is_none());
+        assert!(truncated.finish().is_err());
+        let mut oversized = ExportReader::new(8);
+        oversized.extend(&export(&source, None).await?);
+        assert!(oversized.next_contract().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn metadata_leaves_out_state() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let state_store = StateStore::new(Storage::new(tmp_dir.path()).await?, 10_000_000)?;
+        let mut executor = Executor::new(
+            state_store,
+            || Ok(()),
+            OperationMode::Local,
+            ChecksumRuntime,
+            None,
+        )
+        .await?;
+
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let state = vec![7; 1024 * 1024];
+        executor
+            .state_store
+            .store(
+                key,
+                WrappedState::new(state.clone()),
+                Parameters::from(vec![]),
+            )
+            .await?;
+
+        let metadata = executor.state_metadata(&key).await?;
+        assert_eq!(metadata.state_size, state.len());
+        let serialized = serde_json::to_vec(&metadata)?;
+        assert!(serialized.len() < 1024, "{} bytes", serialized.len());
+
+        // a different state is identified by a different version
+        executor
+            .state_store
+            .update(&key, WrappedState::new(vec![8; 16]))
+            .await?;
+        let updated = executor.state_metadata(&key).await?;
+        assert_eq!(updated.state_size, 16);
+        assert_ne!(updated.version, metadata.version);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrupt_state_reported_to_client() -> anyhow::Result<()> {
+        use crate::wasm_runtime::StateStorage;
+
+        let tmp_dir = tempfile::tempdir()?;
+        let mut storage = Storage::new(tmp_dir.path()).await?;
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let mut damaged = crate::wasm_runtime::seal(&WrappedState::new(vec![7; 64]))
+            .as_ref()
+            .to_vec();
+        damaged.truncate(damaged.len() - 1);
+        storage.store(key, WrappedState::new(damaged)).await?;
+        storage.store_params(key, Parameters::from(vec![])).await?;
+
+        let state_store = StateStore::new(storage, 10_000_000)?;
+        let executor = Executor::new(
+            state_store,
+            || Ok(()),
+            OperationMode::Local,
+            ChecksumRuntime,
+            None,
+        )
+        .await?;
+        let err = executor.state_metadata(&key).await.unwrap_err();
+        assert!(err.is_request() && !err.is_missing_contract());
+        let RequestError::ContractError(StdContractError::Get { cause, .. }) = err.unwrap_request()
+        else {
+            panic!("expected a get error");
+        };
+        assert!(cause.contains("corrupt"), "{cause}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscribers_notified_of_code_upgrade() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let state_store = StateStore::new(Storage::new(tmp_dir.path()).await?, 10_000_000)?;
+        let mut executor = Executor::new(
+            state_store,
+            || Ok(()),
+            OperationMode::Local,
+            ChecksumRuntime,
+            None,
+        )
+        .await?;
+
+        let params = Parameters::from(vec![]);
+        let upgraded = ContractCode::from(vec![2]);
+        let key = ContractKey::from_params_and_code(&params, &upgraded);
+        let (subscriber, gone) = (ClientId::next(), ClientId::next());
+        let (tx, mut notifications) = mpsc::unbounded_channel();
+        let (gone_tx, _) = mpsc::unbounded_channel();
+        // subscribed by the instance alone, before the upgrade and with a summary
+        let subscribed = ContractKey::from(*key.id());
+        executor
+            .update_notifications
+            .insert(subscribed, vec![(subscriber, tx), (gone, gone_tx)]);
+        executor.subscriber_summaries.insert(
+            subscribed,
+            HashMap::from([(subscriber, Some(StateSummary::from(vec![0; 8])))]),
+        );
+
+        let state = WrappedState::new(vec![1, 2, 3]);
+        executor.notify_code_upgrade(&key, &state);
+        let Ok(HostResponse::ContractResponse(ContractResponse::UpdateNotification {
+            key: notified,
+            update,
+        })) = notifications.try_recv()?
+        else {
+            panic!("not an update notification");
+        };
+        assert_eq!(notified.code_hash(), Some(upgraded.hash()));
+        // the whole state, it is not to be patched onto what came from the previous code
+        assert_eq!(update, UpdateData::State(State::from(vec![1, 2, 3])));
+        assert_eq!(executor.update_notifications[&key].len(), 1);
+        Ok(())
+    }
+
+    #[cfg(feature = "http-gateway")]
+    #[tokio::test]
+    async fn retrieve_older_version() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let state_store =
+            StateStore::new(Storage::new(tmp_dir.path()).await?, 10_000_000)?.with_history(3);
+        let mut executor = Executor::new(
+            state_store,
+            || Ok(()),
+            OperationMode::Local,
+            ChecksumRuntime,
+            None,
+        )
+        .await?;
+
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        executor
+            .state_store
+            .store(key, WrappedState::new(vec![1]), Parameters::from(vec![]))
+            .await?;
+        for n in 2..=4 {
+            executor
+                .state_store
+                .update(&key, WrappedState::new(vec![n]))
+                .await?;
+        }
+
+        let second = executor
+            .historical_state(&key, &StateVersion::Number(2))
+            .await?
+            .expect("retained");
+        assert_eq!(second.state.as_ref(), &[2]);
+        // the version shown in the metadata of the state at the time
+        let current = executor.state_metadata(&key).await?;
+        let by_hash = executor
+            .historical_state(&key, &StateVersion::Hash(current.version))
+            .await?
+            .expect("retained");
+        assert_eq!(by_hash.number, 4);
+
+        // only the last three are retained
+        assert!(executor
+            .historical_state(&key, &StateVersion::Number(1))
+            .await?
+            .is_none());
+        let missing = ContractKey::from(ContractInstanceId::new([2; 32]));
+        let err = executor
+            .historical_state(&missing, &StateVersion::Number(1))
+            .await
+            .unwrap_err();
+        assert!(err.is_missing_contract());
+        Ok(())
+    }
+
+    #[cfg(feature = "http-gateway")]
+    #[tokio::test]
+    async fn revalidate_stored_state() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let state_store = StateStore::new(Storage::new(tmp_dir.path()).await?, 10_000_000)?;
+        let mut executor = Executor::new(
+            state_store,
+            || Ok(()),
+            OperationMode::Local,
+            ChecksumRuntime,
+            None,
+        )
+        .await?;
+
+        let valid = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let corrupted = ContractKey::from(ContractInstanceId::new([2; 32]));
+        let missing = ContractKey::from(ContractInstanceId::new([3; 32]));
+        for (key, state) in [(valid, vec![1, 2, 3, 6]), (corrupted, vec![1, 0, 3, 6])] {
+            executor
+                .state_store
+                .store(key, WrappedState::new(state), Parameters::from(vec![]))
+                .await?;
+        }
+
+        assert_eq!(
+            executor.revalidate_contract(&valid).await?,
+            Revalidation::Valid
+        );
+        assert_eq!(
+            executor.revalidate_contract(&corrupted).await?,
+            Revalidation::Invalid
+        );
+        assert!(executor
+            .revalidate_contract(&missing)
+            .await
+            .unwrap_err()
+            .is_missing_contract());
+
+        // the corrupted state is left as it was
+        assert_eq!(
+            executor.state_store.get(&corrupted).await?.as_ref(),
+            &[1, 0, 3, 6]
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "http-gateway")]
+    #[tokio::test]
+    async fn estimate_update_without_committing() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let state_store = StateStore::new(Storage::new(tmp_dir.path()).await?, 10_000_000)?;
+        let mut executor = Executor::new(
+            state_store,
+            || Ok(()),
+            OperationMode::Local,
+            ChecksumRuntime,
+            None,
+        )
+        .await?;
+
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        executor
+            .state_store
+            .store(
+                key,
+                WrappedState::new(vec![1, 2, 3, 6]),
+                Parameters::from(vec![]),
+            )
+            .await?;
+
+        let delta = UpdateData::Delta(StateDelta::from(vec![4, 5]));
+        let estimate = executor.estimate_update(&key, delta).await?;
+        assert_eq!(estimate.state_size, 4);
+        assert_eq!(estimate.updated_size, 6);
+        assert_eq!(estimate.storage_delta, 2);
+        assert!(estimate.valid);
+        assert!(estimate.related.is_empty());
+
+        let corrupted = UpdateData::State(State::from(vec![1, 0]));
+        let estimate = executor.estimate_update(&key, corrupted).await?;
+        assert_eq!(estimate.storage_delta, -2);
+        assert!(!estimate.valid);
+
+        // neither update was applied
+        assert_eq!(
+            executor.state_store.get(&key).await?.as_ref(),
+            &[1, 2, 3, 6]
+        );
+        let missing = ContractKey::from(ContractInstanceId::new([2; 32]));
+        let delta = UpdateData::Delta(StateDelta::from(vec![4]));
+        assert!(executor
+            .estimate_update(&missing, delta)
+            .await
+            .unwrap_err()
+            .is_missing_contract());
+        Ok(())
+    }
+
+    #[cfg(feature = "http-gateway")]
+    #[tokio::test]
+    async fn diff_between_versions() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let state_store =
+            StateStore::new(Storage::new(tmp_dir.path()).await?, 10_000_000)?.with_history(3);
+        let mut executor = Executor::new(
+            state_store,
+            || Ok(()),
+            OperationMode::Local,
+            ChecksumRuntime,
+            None,
+        )
+        .await?;
+
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        executor
+            .state_store
+            .store(key, WrappedState::new(vec![1, 1]), Parameters::from(vec![]))
+            .await?;
+        for delta in [vec![2], vec![3, 4], vec![5]] {
+            let updated = executor
+                .runtime
+                .update_state(
+                    &key,
+                    &Parameters::from(vec![]),
+                    &executor.state_store.get(&key).await?,
+                    &[UpdateData::Delta(StateDelta::from(delta))],
+                )?
+                .new_state
+                .unwrap();
+            executor
+                .state_store
+                .update(&key, WrappedState::new(updated.into_bytes()))
+                .await?;
+        }
+
+        let diff = executor
+            .state_diff(
+                &key,
+                &StateVersion::Number(2),
+                Some(&StateVersion::Number(3)),
+            )
+            .await?;
+        let Some(StateDiff::Delta { version, delta }) = diff else {
+            panic!("no delta: {diff:?}");
+        };
+        assert_eq!(delta.as_ref(), &[3, 4]);
+        let third = executor
+            .historical_state(&key, &StateVersion::Number(3))
+            .await?
+            .unwrap();
+        assert_eq!(version, third.hash);
+        // to the current state by default
+        let diff = executor
+            .state_diff(&key, &StateVersion::Number(2), None)
+            .await?;
+        assert!(
+            matches!(diff, Some(StateDiff::Delta { delta, .. }) if delta.as_ref() == [3, 4, 5])
+        );
+
+        // the first version is no longer retained, nor is any further one
+        let diff = executor
+            .state_diff(&key, &StateVersion::Number(1), None)
+            .await?;
+        let Some(StateDiff::State { state, .. }) = diff else {
+            panic!("no state: {diff:?}");
+        };
+        assert_eq!(state.as_ref(), &[1, 2, 3, 4, 5, 15]);
+        assert!(executor
+            .state_diff(
+                &key,
+                &StateVersion::Number(2),
+                Some(&StateVersion::Number(5))
+            )
+            .await?
+            .is_none());
+        Ok(())
+    }
+
+    #[cfg(feature = "http-gateway")]
+    #[tokio::test]
+    async fn failed_transaction_applies_nothing() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let state_store = StateStore::new(Storage::new(tmp_dir.path()).await?, 10_000_000)?;
+        let mut executor = Executor::new(
+            state_store,
+            || Ok(()),
+            OperationMode::Local,
+            ChecksumRuntime,
+            None,
+        )
+        .await?;
+
+        let first = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let second = ContractKey::from(ContractInstanceId::new([2; 32]));
+        for key in [first, second] {
+            executor
+                .state_store
+                .store(key, WrappedState::new(vec![1, 1]), Parameters::from(vec![]))
+                .await?;
+        }
+        let delta = |bytes: Vec<u8>| UpdateData::Delta(StateDelta::from(bytes));
+
+        let (outcome, _) = executor
+            .commit_transaction(vec![
+                (first, delta(vec![2])),
+                (second, delta(vec![3])),
+                // fails the checksum
+                (second, UpdateData::State(State::from(vec![1, 2]))),
+            ])
+            .await?;
+        assert!(matches!(
+            outcome,
+            TransactionOutcome::Aborted { index: 2, .. }
+        ));
+        for key in [first, second] {
+            assert_eq!(executor.state_store.get(&key).await?.as_ref(), &[1, 1]);
+        }
+        let missing = ContractKey::from(ContractInstanceId::new([3; 32]));
+        let (outcome, _) = executor
+            .commit_transaction(vec![(first, delta(vec![2])), (missing, delta(vec![3]))])
+            .await?;
+        assert!(matches!(
+            outcome,
+            TransactionOutcome::Aborted { index: 1, .. }
+        ));
+        assert_eq!(executor.state_store.get(&first).await?.as_ref(), &[1, 1]);
+
+        // later updates build on the earlier ones to the same contract
+        let (outcome, _) = executor
+            .commit_transaction(vec![
+                (first, delta(vec![2])),
+                (second, delta(vec![3])),
+                (first, delta(vec![4])),
+            ])
+            .await?;
+        let TransactionOutcome::Committed { contracts } = outcome else {
+            panic!("transaction aborted: {outcome:?}");
+        };
+        assert_eq!(contracts.len(), 2);
+        assert_eq!(
+            executor.state_store.get(&first).await?.as_ref(),
+            &[1, 2, 4, 7]
+        );
+        assert_eq!(
+            executor.state_store.get(&second).await?.as_ref(),
+            &[1, 3, 4]
+        );
+        assert_eq!(
+            contracts[0].version,
+            executor.state_metadata(&first).await?.version
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+//! Delivery of update notifications to subscribed clients spread over worker tasks.
+//!
+//! Sending a notification with the whole state copies it once per subscriber, with many
+//! subscribers to a large contract that dominates the executor. With several shards the
+//! executor only hands each one the notifications for its share of the clients, the copies
+//! and sends happening on the workers. A client always lands in the same shard, so the
+//! notifications it gets keep the order they were produced in.
+
+use std::hash::{BuildHasher, RandomState};
+
+use freenet_stdlib::{
+    client_api::ContractResponse,
+    prelude::{ContractKey, State, UpdateData, WrappedState},
+};
+use tokio::sync::mpsc;
+
+use crate::client_events::{ClientId, HostResult};
+
+pub(crate) enum Payload {
+    /// The state is copied into the notification when delivering it.
+    State(WrappedState),
+    Update(UpdateData<'static>),
+}
+
+pub(crate) struct Notification {
+    pub key: ContractKey,
+    pub client: ClientId,
+    pub channel: mpsc::UnboundedSender<HostResult>,
+    pub payload: Payload,
+}
+
+impl Notification {
+    fn deliver(self) {
+        let update = match self.payload {
+            Payload::State(state) => UpdateData::State(State::from(state.as_ref()).into_owned()),
+            Payload::Update(update) => update,
+        };
+        let notification = ContractResponse::UpdateNotification {
+            key: self.key,
+            update,
+        };
+        if self.channel.send(Ok(notification.into())).is_err() {
+            // found closed and dropped by the executor the next time it notifies
+            tracing::debug!(cli_id = %self.client, contract = %self.key, "subscriber gone");
+        } else {
+            tracing::debug!(cli_id = %self.client, contract = %self.key, "notified of update");
+        }
+    }
+}
+
+pub(crate) struct NotificationShards {
+    /// Empty when delivering on the executor itself.
+    workers: Vec<mpsc::UnboundedSender<Vec<Notification>>>,
+    hasher: RandomState,
+}
+
+impl Default for NotificationShards {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+impl NotificationShards {
+    /// Spreads the delivery over `shards` worker tasks, a single shard delivers inline.
+    pub fn new(shards: usize) -> Self {
+        let workers = if shards > 1 {
+            (0..shards)
+                .map(|_| {
+                    let (worker, mut batches) = mpsc::unbounded_channel::<Vec<Notification>>();
+                    tokio::spawn(async move {
+                        while let Some(batch) = batches.recv().await {
+                            batch.into_iter().for_each(Notification::deliver);
+                        }
+                    });
+                    worker
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Self {
+            workers,
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, client: &ClientId) -> usize {
+        (self.hasher.hash_one(client) % self.workers.len() as u64) as usize
+    }
+
+    pub fn deliver(&self, notifications: Vec<Notification>) {
+        if self.workers.is_empty() {
+            notifications.into_iter().for_each(Notification::deliver);
+            return;
+        }
+        let mut batches: Vec<Vec<Notification>> = self.workers.iter().map(|_| vec![]).collect();
+        for notification in notifications {
+            batches[self.shard(&notification.client)].push(notification);
+        }
+        for (worker, batch) in self.workers.iter().zip(batches) {
+            if !batch.is_empty() && worker.send(batch).is_err() {
+                tracing::error!("notification worker stopped");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        time::{Duration, Instant},
+    };
+
+    use freenet_stdlib::{client_api::HostResponse, prelude::ContractInstanceId};
+
+    use super::*;
+
+    const CLIENTS: usize = 64;
+    const UPDATES: u8 = 10;
+
+    fn subscribers() -> Vec<(ClientId, mpsc::UnboundedReceiver<HostResult>, Notification)> {
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        (0..CLIENTS)
+            .map(|_| {
+                let (channel, received) = mpsc::unbounded_channel();
+                let client = ClientId::next();
+                let template = Notification {
+                    key,
+                    client,
+                    channel,
+                    payload: Payload::State(WrappedState::new(vec![])),
+                };
+                (client, received, template)
+            })
+            .collect()
+    }
+
+    fn update(
+        subscribers: &[(ClientId, mpsc::UnboundedReceiver<HostResult>, Notification)],
+        state: WrappedState,
+    ) -> Vec<Notification> {
+        subscribers
+            .iter()
+            .map(|(_, _, template)| Notification {
+                key: template.key,
+                client: template.client,
+                channel: template.channel.clone(),
+                payload: Payload::State(state.clone()),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn delivered_in_order_across_shards() {
+        let shards = NotificationShards::new(4);
+        let mut subscribers = subscribers();
+        let used: HashSet<_> = subscribers
+            .iter()
+            .map(|(client, _, _)| shards.shard(client))
+            .collect();
+        assert!(used.len() > 1, "every client in the same shard");
+
+        for n in 0..UPDATES {
+            shards.deliver(update(&subscribers, WrappedState::new(vec![n])));
+        }
+        for (client, received, _) in &mut subscribers {
+            for n in 0..UPDATES {
+                let notification = tokio::time::timeout(Duration::from_secs(5), received.recv())
+                    .await
+                    .expect("notification delivered")
+                    .unwrap();
+                let Ok(HostResponse::ContractResponse(ContractResponse::UpdateNotification {
+                    update: UpdateData::State(state),
+                    ..
+                })) = notification
+                else {
+                    panic!("unexpected notification for {client}");
+                };
+                assert_eq!(state.as_ref(), &[n]);
+            }
+        }
+    }
+
+    /// Compares delivering notifications with a large state inline and sharded.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore = "benchmark"]
+    async fn sharded_fanout_throughput() {
+        const STATE_SIZE: usize = 512 * 1024;
+        for shards in [1, 4] {
+            let notifier = NotificationShards::new(shards);
+            let mut subscribers = subscribers();
+            let started = Instant::now();
+            for n in 0..UPDATES {
+                notifier.deliver(update(&subscribers, WrappedState::new(vec![n; STATE_SIZE])));
+            }
+            // only the executor is held up inline, sharded it hands the work over right away
+            let handed_over = started.elapsed();
+            for (_, received, _) in &mut subscribers {
+                for _ in 0..UPDATES {
+                    received.recv().await.unwrap().unwrap();
+                }
+            }
+            let delivered = started.elapsed();
+            let throughput = (CLIENTS * UPDATES as usize) as f64 / delivered.as_secs_f64();
+            tracing::info!(
+                shards,
+                executor_busy = ?handed_over,
+                ?delivered,
+                "{throughput:.0} notifications/s"
+            );
+        }
+    }
+}
@@ -1,7 +1,8 @@
+use super::notification_shards::{Notification, Payload};
 use super::*;
 use super::{
-    ContractExecutor, ContractRequest, ContractResponse, ExecutorError, ExecutorHalve,
-    ExecutorToEventLoopChannel, RequestError, Response, StateStoreError,
+    ContractExecutor, ContractMetadata, ContractRequest, ContractResponse, ExecutorError,
+    ExecutorHalve, ExecutorToEventLoopChannel, RequestError, Response, StateStoreError,
 };
 
 impl ContractExecutor for Executor<Runtime> {
@@ -86,13 +87,12 @@ impl ContractExecutor for Executor<Runtime> {
         let mut updates = match update {
             Either::Left(incoming_state) => {
                 let result = self
-                    .runtime
-                    .validate_state(&key, &params, &incoming_state, &related_contracts)
+                    .checked_validate_state(&key, &params, &incoming_state, &related_contracts)
                     .map_err(|err| {
                         if remove_if_fail {
                             let _ = self.runtime.contract_store.remove_contract(&key);
                         }
-                        ExecutorError::execution(err, None)
+                        ExecutorError::validation(err, key, ValidatedOp::Put)
                     })?;
                 match result {
                     ValidateResult::Valid => {
@@ -139,7 +139,15 @@ impl ContractExecutor for Executor<Runtime> {
                     key: key.into(),
                 }));
             }
-            Err(StateStoreError::Any(err)) => return Err(ExecutorError::other(err)),
+            Err(
+                err @ (StateStoreError::DataCorruption(_) | StateStoreError::UnknownFormat(..)),
+            ) => {
+                return Err(ExecutorError::request(StdContractError::Update {
+                    key,
+                    cause: err.to_string().into(),
+                }));
+            }
+            Err(err) => return Err(ExecutorError::other(err)),
         };
 
         for (id, state) in related_contracts
@@ -168,9 +176,8 @@ impl ContractExecutor for Executor<Runtime> {
             }
         };
         match self
-            .runtime
-            .validate_state(&key, &params, &updated_state, &related_contracts)
-            .map_err(|e| ExecutorError::execution(e, None))?
+            .checked_validate_state(&key, &params, &updated_state, &related_contracts)
+            .map_err(|e| ExecutorError::validation(e, key, ValidatedOp::Update))?
         {
             ValidateResult::Valid => {
                 if updated_state.as_ref() == current_state.as_ref() {
@@ -345,6 +352,8 @@ impl Executor<Runtime> {
         let (contract_store, delegate_store, secret_store, state_store) =
             Self::get_stores(&config).await?;
         let rt = Runtime::build(contract_store, delegate_store, secret_store, false).unwrap();
+        let determinism_check = config.executor.determinism_check;
+        let notification_shards = config.ws_api.notification_shards;
         Executor::new(
             state_store,
             move || {
@@ -359,6 +368,11 @@ impl Executor<Runtime> {
             event_loop_channel,
         )
         .await
+        .map(|executor| {
+            executor
+                .with_determinism_check(determinism_check)
+                .with_notification_shards(notification_shards)
+        })
     }
 
     pub async fn preload(
@@ -456,10 +470,7 @@ impl Executor<Runtime> {
                             contract = %key,
                             "Contract state not found during get request."
                         );
-                        ExecutorError::request(StdContractError::Get {
-                            key,
-                            cause: "contract state not found".into(),
-                        })
+                        ExecutorError::missing_contract(key)
                     })?,
                     contract,
                 }
@@ -624,6 +635,7 @@ impl Executor<Runtime> {
         let params = contract.params();
 
         if self.get_local_contract(key.id()).await.is_ok() {
+            self.upgrade_contract_code(&contract).await?;
             // already existing contract, just try to merge states
             return self
                 .perform_contract_update(key, UpdateData::State(state.into()))
@@ -644,6 +656,51 @@ impl Executor<Runtime> {
         Ok(ContractResponse::PutResponse { key }.into())
     }
 
+    /// Moves an existing contract to the code of `contract` when it differs from the stored
+    /// one, as long as the current state is still valid under the new code.
+    async fn upgrade_contract_code(
+        &mut self,
+        contract: &ContractContainer,
+    ) -> Result<(), ExecutorError> {
+        let key = contract.key();
+        let store = &mut self.runtime.contract_store;
+        let (Some(code), Some(current)) = (key.code_hash(), store.code_hash_from_key(&key)) else {
+            return Ok(());
+        };
+        if *code == current {
+            return Ok(());
+        }
+        let params = contract.params();
+        let previous = store.fetch_contract(&ContractKey::from(*key.id()), &params);
+        store
+            .store_contract(contract.clone())
+            .map_err(ExecutorError::other)?;
+        let state = self
+            .state_store
+            .get(&key)
+            .await
+            .map_err(ExecutorError::other)?;
+        // compiled anew, the module of the previous code is dropped
+        let valid =
+            self.runtime
+                .validate_state(&key, &params, &state, &RelatedContracts::default());
+        if !matches!(valid, Ok(ValidateResult::Valid)) {
+            tracing::warn!(contract = %key, previous = %current, ?valid, "rejected contract code upgrade");
+            if let Some(previous) = previous {
+                self.runtime
+                    .contract_store
+                    .store_contract(previous)
+                    .map_err(ExecutorError::other)?;
+            }
+            return Err(ExecutorError::request(StdContractError::Put {
+                key,
+                cause: "current state is not valid under the upgraded code".into(),
+            }));
+        }
+        self.notify_code_upgrade(&key, &state);
+        Ok(())
+    }
+
     async fn perform_contract_update(
         &mut self,
         key: ContractKey,
@@ -962,7 +1019,7 @@ impl Executor<Runtime> {
                 )
                 .map_err(|err| {
                     let _ = self.runtime.contract_store.remove_contract(&trying_key);
-                    ExecutorError::execution(err, None)
+                    ExecutorError::validation(err, trying_key, ValidatedOp::Put)
                 })?;
 
             let is_valid = match result {
@@ -1048,38 +1105,177 @@ impl Executor<Runtime> {
         let key = *key;
         if let Some(notifiers) = self.update_notifications.get_mut(&key) {
             let summaries = self.subscriber_summaries.get_mut(&key).unwrap();
-            // in general there should be less than 32 failures
-            let mut failures = Vec::with_capacity(32);
+            // clients which went away since the last update
+            notifiers.retain(|(peer_key, notifier)| {
+                let closed = notifier.is_closed();
+                if closed {
+                    tracing::debug!(cli_id = %peer_key, contract = %key, "dropping closed subscriber");
+                }
+                !closed
+            });
+            let mut notifications = Vec::with_capacity(notifiers.len());
             for (peer_key, notifier) in notifiers.iter() {
                 let peer_summary = summaries.get_mut(peer_key).unwrap();
-                let update = match peer_summary {
-                    Some(summary) => self
-                        .runtime
-                        .get_state_delta(&key, params, new_state, &*summary)
-                        .map_err(|err| {
-                            tracing::error!("{err}");
-                            ExecutorError::execution(err, Some(InnerOpError::Upsert(key)))
-                        })?
-                        .to_owned()
-                        .into(),
-                    None => UpdateData::State(State::from(new_state.as_ref()).into_owned()),
+                let payload = match peer_summary {
+                    Some(summary) => Payload::Update(
+                        self.runtime
+                            .get_state_delta(&key, params, new_state, &*summary)
+                            .map_err(|err| {
+                                tracing::error!("{err}");
+                                ExecutorError::execution(err, Some(InnerOpError::Upsert(key)))
+                            })?
+                            .to_owned()
+                            .into(),
+                    ),
+                    None => Payload::State(new_state.clone()),
                 };
-                if let Err(err) =
-                    notifier.send(Ok(
-                        ContractResponse::UpdateNotification { key, update }.into()
-                    ))
-                {
-                    failures.push(*peer_key);
-                    tracing::error!(cli_id = %peer_key, "{err}");
-                } else {
-                    tracing::debug!(cli_id = %peer_key, contract = %key, "notified of update");
+                notifications.push(Notification {
+                    key,
+                    client: *peer_key,
+                    channel: notifier.clone(),
+                    payload,
+                });
+            }
+            self.notification_shards.deliver(notifications);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "http-gateway")]
+    /// Applies updates to several contracts all together or not at all, see
+    /// [`Executor::commit_transaction`]; subscribers are only notified once all are stored.
+    pub(crate) async fn apply_transaction(
+        &mut self,
+        updates: Vec<(ContractKey, UpdateData<'static>)>,
+    ) -> Result<TransactionOutcome, ExecutorError> {
+        let (outcome, changed) = self.commit_transaction(updates).await?;
+        for state in &changed {
+            if let Err(err) = self
+                .send_update_notification(&state.key, &state.params, &state.updated)
+                .await
+            {
+                tracing::error!(contract = %state.key, %err, "failed sending notifications");
+            }
+        }
+        if self.mode != OperationMode::Local {
+            for state in &changed {
+                let request = UpdateContract {
+                    key: state.key,
+                    new_state: state.updated.clone(),
+                };
+                let sent: Result<operations::update::UpdateResult, _> =
+                    self.op_request(request).await;
+                if let Err(err) = sent {
+                    tracing::warn!(contract = %state.key, %err, "failed propagating transaction update");
                 }
             }
-            if !failures.is_empty() {
-                notifiers.retain(|(c, _)| !failures.contains(c));
+        }
+        Ok(outcome)
+    }
+
+    /// Metadata of the state stored for a contract, see [`Executor::state_metadata`]. Keys
+    /// given only by instance id get the code hash of the contract stored for them.
+    pub async fn contract_metadata(
+        &self,
+        key: &ContractKey,
+    ) -> Result<ContractMetadata, ExecutorError> {
+        let mut metadata = self.state_metadata(key).await?;
+        if metadata.code_hash.is_none() {
+            metadata.code_hash = self
+                .runtime
+                .contract_store
+                .code_hash_from_key(key)
+                .map(|hash| hash.encode());
+        }
+        Ok(metadata)
+    }
+
+    /// Topics the contract stored for `key` publishes its updates under.
+    pub async fn contract_topics(&self, key: &ContractKey) -> Result<Vec<String>, ExecutorError> {
+        let Some(contract) = self.get_contract_locally(key).await? else {
+            return Err(ExecutorError::missing_contract(*key));
+        };
+        crate::wasm_runtime::declared_topics(contract.data()).map_err(ExecutorError::other)
+    }
+
+    /// Contracts the contract stored for `key` declares it depends on.
+    pub async fn contract_dependencies(
+        &self,
+        key: &ContractKey,
+    ) -> Result<Vec<ContractKey>, ExecutorError> {
+        let Some(contract) = self.get_contract_locally(key).await? else {
+            return Err(ExecutorError::missing_contract(*key));
+        };
+        crate::wasm_runtime::declared_dependencies(contract.data()).map_err(ExecutorError::other)
+    }
+
+    /// The contract stored for `key`, along with its code.
+    pub async fn contract_code(
+        &self,
+        key: &ContractKey,
+    ) -> Result<ContractContainer, ExecutorError> {
+        self.get_contract_locally(key)
+            .await?
+            .ok_or_else(|| ExecutorError::missing_contract(*key))
+    }
+
+    /// The stored contracts which are `included`, to be exported one by one with
+    /// [`Self::export_contract`]; see [`crate::contract::backup`].
+    #[cfg(feature = "http-gateway")]
+    pub(crate) async fn exported_contracts(
+        &self,
+        included: impl Fn(&ContractInstanceId) -> bool,
+    ) -> Result<Vec<ContractKey>, ExecutorError> {
+        self.state_store
+            .keys(included)
+            .await
+            .map_err(ExecutorError::other)
+    }
+
+    /// A stored contract along with its current state, none if it can't be exported as its
+    /// state is corrupt, it lacks its code or it is no longer stored.
+    #[cfg(feature = "http-gateway")]
+    pub(crate) async fn export_contract(
+        &self,
+        key: &ContractKey,
+    ) -> Result<Option<ExportedContract>, ExecutorError> {
+        let state = match self.state_store.get(key).await {
+            Ok(state) => state,
+            Err(StateStoreError::MissingContract(_)) => return Ok(None),
+            Err(StateStoreError::DataCorruption(_) | StateStoreError::UnknownFormat(..)) => {
+                tracing::warn!(contract = %key, "leaving out of the export a corrupt state");
+                return Ok(None);
             }
+            Err(err) => return Err(ExecutorError::other(err)),
+        };
+        let Some(contract) = self.get_contract_locally(key).await? else {
+            tracing::warn!(contract = %key, "leaving out of the export a state without code");
+            return Ok(None);
+        };
+        Ok(Some(ExportedContract { contract, state }))
+    }
+
+    /// Puts a contract exported from another node, telling whether it changed anything.
+    #[cfg(feature = "http-gateway")]
+    pub(crate) async fn import_contract(
+        &mut self,
+        exported: ExportedContract,
+    ) -> Result<bool, ExecutorError> {
+        let ExportedContract { contract, state } = exported;
+        let key = contract.key();
+        if matches!(self.state_store.get(&key).await, Ok(current) if current.as_ref() == state.as_ref())
+        {
+            return Ok(false);
         }
-        Ok(())
+        let outcome = self
+            .upsert_contract_state(
+                key,
+                Either::Left(state),
+                RelatedContracts::default(),
+                Some(contract),
+            )
+            .await?;
+        Ok(matches!(outcome, UpsertResult::Updated(_)))
     }
 
     async fn get_contract_locally(
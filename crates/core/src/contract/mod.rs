@@ -5,6 +5,10 @@
 use either::Either;
 use freenet_stdlib::prelude::*;
 
+#[cfg(feature = "http-gateway")]
+pub(crate) mod backup;
+#[cfg(feature = "http-gateway")]
+pub(crate) mod collection;
 mod executor;
 mod handler;
 pub mod storages;
@@ -20,7 +24,9 @@ pub(crate) use handler::{
     WaitingTransaction,
 };
 
-pub use executor::{Executor, ExecutorError, OperationMode};
+#[cfg(feature = "http-gateway")]
+pub use executor::{ContractMetadata, CostEstimate, Revalidation, StateDiff, TransactionOutcome};
+pub use executor::{Executor, ExecutorError, OperationMode, ValidationError};
 
 use executor::ContractExecutor;
 use tracing::Instrument;
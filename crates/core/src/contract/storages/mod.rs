@@ -1,17 +1,126 @@
+use std::path::Path;
+
+use freenet_stdlib::prelude::*;
+
+use crate::{config::StorageBackend, wasm_runtime::StateStorage};
+
 /// State storage implementation based on the `sqlite`
 #[cfg(feature = "sqlite")]
 pub mod sqlite;
-#[cfg(all(feature = "sqlite", not(feature = "redb")))]
+#[cfg(feature = "sqlite")]
 pub use sqlite::Pool as SqlitePool;
 
-#[cfg(all(feature = "sqlite", not(feature = "redb")))]
-pub type Storage = SqlitePool;
-
 /// State storage implementation based on the [`redb`]
 #[cfg(feature = "redb")]
 pub mod redb;
 #[cfg(feature = "redb")]
 use self::redb::ReDb;
 
-#[cfg(feature = "redb")]
-pub type Storage = ReDb;
+/// The database the states are stored in, any of the backends compiled in.
+#[derive(Clone)]
+pub enum Storage {
+    #[cfg(feature = "redb")]
+    Redb(ReDb),
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqlitePool),
+}
+
+impl Storage {
+    /// Opens the database of the default backend in `data_dir`.
+    pub async fn new(data_dir: &Path) -> anyhow::Result<Self> {
+        Self::open(StorageBackend::default(), data_dir).await
+    }
+
+    /// Opens the database of `backend` in `data_dir`, failing if it is not compiled in.
+    pub async fn open(backend: StorageBackend, data_dir: &Path) -> anyhow::Result<Self> {
+        match backend {
+            #[cfg(feature = "redb")]
+            StorageBackend::Redb => Ok(Self::Redb(ReDb::new(data_dir).await?)),
+            #[cfg(feature = "sqlite")]
+            StorageBackend::Sqlite => Ok(Self::Sqlite(SqlitePool::new(Some(data_dir)).await?)),
+            #[allow(unreachable_patterns)]
+            backend => {
+                anyhow::bail!("the {backend:?} storage backend is not available in this build")
+            }
+        }
+    }
+}
+
+impl StateStorage for Storage {
+    type Error = anyhow::Error;
+
+    async fn store(&mut self, key: ContractKey, state: WrappedState) -> anyhow::Result<()> {
+        match self {
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => Ok(db.store(key, state).await?),
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(pool) => Ok(pool.store(key, state).await?),
+        }
+    }
+
+    async fn store_all(&mut self, states: Vec<(ContractKey, WrappedState)>) -> anyhow::Result<()> {
+        match self {
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => Ok(db.store_all(states).await?),
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(pool) => Ok(pool.store_all(states).await?),
+        }
+    }
+
+    async fn store_params(
+        &mut self,
+        key: ContractKey,
+        params: Parameters<'static>,
+    ) -> anyhow::Result<()> {
+        match self {
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => Ok(db.store_params(key, params).await?),
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(pool) => Ok(pool.store_params(key, params).await?),
+        }
+    }
+
+    async fn get(&self, key: &ContractKey) -> anyhow::Result<Option<WrappedState>> {
+        match self {
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => Ok(db.get(key).await?),
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(pool) => Ok(pool.get(key).await?),
+        }
+    }
+
+    async fn get_params<'a>(
+        &'a self,
+        key: &'a ContractKey,
+    ) -> anyhow::Result<Option<Parameters<'static>>> {
+        match self {
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => Ok(db.get_params(key).await?),
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(pool) => Ok(pool.get_params(key).await?),
+        }
+    }
+
+    async fn keys(&self) -> anyhow::Result<Vec<ContractKey>> {
+        match self {
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => Ok(db.keys().await?),
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(pool) => Ok(pool.keys().await?),
+        }
+    }
+
+    async fn read_at(
+        &self,
+        key: &ContractKey,
+        offset: usize,
+        len: usize,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        match self {
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => Ok(db.read_at(key, offset, len).await?),
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(pool) => Ok(pool.read_at(key, offset, len).await?),
+        }
+    }
+}
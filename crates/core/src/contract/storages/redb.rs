@@ -1,7 +1,7 @@
-use std::path::Path;
+use std::{path::Path, sync::Arc};
 
 use freenet_stdlib::prelude::*;
-use redb::{Database, TableDefinition};
+use redb::{Database, ReadableTable, TableDefinition};
 
 use crate::wasm_runtime::StateStorage;
 
@@ -9,13 +9,14 @@ const CONTRACT_PARAMS_TABLE: TableDefinition<&[u8], &[u8]> =
     TableDefinition::new("contract_params");
 const STATE_TABLE: TableDefinition<&[u8], &[u8]> = TableDefinition::new("state");
 
-pub struct ReDb(Database);
+#[derive(Clone)]
+pub struct ReDb(Arc<Database>);
 
 impl ReDb {
     pub async fn new(data_dir: &Path) -> Result<Self, redb::Error> {
         let db_path = data_dir.join("db");
         tracing::info!("loading contract store from {db_path:?}");
-        match Database::create(db_path).map(Self) {
+        match Database::create(db_path).map(|db| Self(Arc::new(db))) {
             Ok(db) => {
                 let txn = db.0.begin_write()?;
                 {
@@ -54,6 +55,21 @@ impl StateStorage for ReDb {
         txn.commit().map_err(Into::into)
     }
 
+    async fn store_all(
+        &mut self,
+        states: Vec<(ContractKey, WrappedState)>,
+    ) -> Result<(), Self::Error> {
+        let txn = self.0.begin_write()?;
+
+        {
+            let mut tbl = txn.open_table(STATE_TABLE)?;
+            for (key, state) in &states {
+                tbl.insert(key.as_bytes(), state.as_ref())?;
+            }
+        }
+        txn.commit().map_err(Into::into)
+    }
+
     async fn get(&self, key: &ContractKey) -> Result<Option<WrappedState>, Self::Error> {
         let txn = self.0.begin_read()?;
 
@@ -98,4 +114,34 @@ impl StateStorage for ReDb {
             None => Ok(None),
         }
     }
+
+    async fn read_at(
+        &self,
+        key: &ContractKey,
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Self::Error> {
+        let txn = self.0.begin_read()?;
+        let tbl = txn.open_table(STATE_TABLE)?;
+        let Some(val) = tbl.get(key.as_bytes())? else {
+            return Ok(None);
+        };
+        let rest = val.value().get(offset..).unwrap_or_default();
+        Ok(Some(rest[..len.min(rest.len())].to_vec()))
+    }
+
+    async fn keys(&self) -> Result<Vec<ContractKey>, Self::Error> {
+        let txn = self.0.begin_read()?;
+        let tbl = txn.open_table(STATE_TABLE)?;
+        let mut keys = Vec::new();
+        for entry in tbl.iter()? {
+            let (key, _) = entry?;
+            let Ok(instance) = <[u8; 32]>::try_from(key.value()) else {
+                tracing::warn!("skipping the state stored under a malformed contract key");
+                continue;
+            };
+            keys.push(ContractKey::from(ContractInstanceId::new(instance)));
+        }
+        Ok(keys)
+    }
 }
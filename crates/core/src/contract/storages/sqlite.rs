@@ -25,7 +25,6 @@ async fn create_contracts_table(pool: &SqlitePool) -> Result<(), SqlDbError> {
 pub struct Pool(SqlitePool);
 
 impl Pool {
-    #[cfg_attr(feature = "redb", allow(unused))]
     pub async fn new(db_dir: Option<&Path>) -> Result<Self, SqlDbError> {
         let opts = if let Some(db_dir) = db_dir {
             let file = db_dir.join("freenet.db");
@@ -64,6 +63,27 @@ impl StateStorage for Pool {
         Ok(())
     }
 
+    async fn store_all(
+        &mut self,
+        states: Vec<(ContractKey, WrappedState)>,
+    ) -> Result<(), Self::Error> {
+        let mut txn = self.0.begin().await?;
+        for (key, state) in &states {
+            sqlx::query(
+                "INSERT INTO states (contract, state) 
+                     VALUES ($1, $2) 
+                     ON CONFLICT(contract) DO UPDATE SET state = excluded.state
+                     ",
+            )
+            .bind(key.as_bytes())
+            .bind(state.as_ref())
+            .execute(&mut *txn)
+            .await?;
+        }
+        txn.commit().await?;
+        Ok(())
+    }
+
     async fn get(&self, key: &ContractKey) -> Result<Option<WrappedState>, Self::Error> {
         match sqlx::query("SELECT state FROM states WHERE contract = ?")
             .bind(key.as_bytes())
@@ -110,6 +130,38 @@ impl StateStorage for Pool {
             Err(_) => Err(SqlDbError::ContractNotFound),
         }
     }
+
+    async fn read_at(
+        &self,
+        key: &ContractKey,
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Self::Error> {
+        // positions of `substr` start at 1
+        let row = sqlx::query(
+            "SELECT substr(state, ?, ?) AS chunk FROM states
+                     WHERE contract = ? AND state IS NOT NULL",
+        )
+        .bind(i64::try_from(offset).unwrap_or(i64::MAX).saturating_add(1))
+        .bind(i64::try_from(len).unwrap_or(i64::MAX))
+        .bind(key.as_bytes())
+        .fetch_optional(&self.0)
+        .await?;
+        Ok(row.map(|row: SqliteRow| row.get("chunk")))
+    }
+
+    async fn keys(&self) -> Result<Vec<ContractKey>, Self::Error> {
+        let rows = sqlx::query("SELECT contract FROM states WHERE state IS NOT NULL")
+            .fetch_all(&self.0)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row: SqliteRow| {
+                let instance = <[u8; 32]>::try_from(row.get::<Vec<u8>, _>("contract")).ok()?;
+                Some(ContractKey::from(ContractInstanceId::new(instance)))
+            })
+            .collect())
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -15,7 +15,9 @@ mod message;
 
 /// Node configuration, implementations and execution (entry points for the binaries).
 mod node;
-pub use node::{run_local_node, run_network_node};
+#[cfg(feature = "http-gateway")]
+pub use node::run_local_node;
+pub use node::run_network_node;
 
 /// Network operation/transaction state machines.
 mod operations;
@@ -27,7 +29,7 @@ mod ring;
 mod router;
 
 /// Local server used to communicate with the peer core.
-#[cfg(feature = "websocket")]
+#[cfg(feature = "http-gateway")]
 pub mod server;
 
 /// Local network topology management.
@@ -48,6 +50,7 @@ pub mod local_node {
     use super::*;
     pub use contract::Executor;
     pub use contract::OperationMode;
+    pub use contract::ValidationError;
     pub use node::NodeConfig;
 }
 
@@ -56,8 +59,10 @@ pub mod dev_tool {
     use super::*;
     pub use crate::config::Config;
     pub use client_events::{
-        test::MemoryEventsGen, test::NetworkEventGenerator, ClientEventsProxy, ClientId,
-        OpenRequest,
+        in_process::{InProcessClient, InProcessClients, InProcessProxy},
+        test::MemoryEventsGen,
+        test::NetworkEventGenerator,
+        ClientEventsProxy, ClientId, OpenRequest,
     };
     pub use contract::{storages::Storage, Executor, OperationMode};
     pub use flatbuffers;
@@ -0,0 +1,165 @@
+//! Admission of client requests by the local node depending on how loaded it is.
+//!
+//! While too many requests are waiting or the recent ones took too long to be served, new
+//! requests are turned away at once instead of making the backlog worse. Only requests
+//! served within the last [`LATENCY_WINDOW`] count for the latency, so once the node stops
+//! serving requests for being overloaded it starts admitting them again after a while.
+
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::LoadShedding,
+    util::time_source::{InstantTimeSrc, TimeSource},
+};
+
+const LATENCY_WINDOW: Duration = Duration::from_secs(10);
+
+/// Most recent requests averaged for the latency.
+const LATENCY_SAMPLES: usize = 32;
+
+pub(crate) struct Admission<T: TimeSource = InstantTimeSrc> {
+    limits: LoadShedding,
+    /// When the recent requests finished and how long they took.
+    served: VecDeque<(Instant, Duration)>,
+    time_source: T,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Overload {
+    QueueDepth(usize),
+    Latency(Duration),
+}
+
+impl Display for Overload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::QueueDepth(depth) => write!(f, "{depth} requests waiting"),
+            Self::Latency(latency) => write!(f, "recent requests took {latency:?} on average"),
+        }
+    }
+}
+
+impl Admission {
+    pub fn new(limits: LoadShedding) -> Self {
+        Self::with_time_source(limits, InstantTimeSrc::new())
+    }
+}
+
+impl<T: TimeSource> Admission<T> {
+    fn with_time_source(limits: LoadShedding, time_source: T) -> Self {
+        Self {
+            limits,
+            served: VecDeque::new(),
+            time_source,
+        }
+    }
+
+    /// Whether a new request can be served while `queue_depth` others are waiting.
+    pub fn admit(&mut self, queue_depth: usize) -> Result<(), Overload> {
+        if let Some(max) = self.limits.max_queue_depth {
+            if queue_depth > max {
+                return Err(Overload::QueueDepth(queue_depth));
+            }
+        }
+        if let Some(max) = self.limits.max_latency_ms {
+            let latency = self.latency();
+            if latency > Duration::from_millis(max) {
+                return Err(Overload::Latency(latency));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn started(&self) -> Instant {
+        self.time_source.now()
+    }
+
+    /// Accounts a request which started being served at `started`.
+    pub fn finished(&mut self, started: Instant) {
+        let now = self.time_source.now();
+        if self.served.len() == LATENCY_SAMPLES {
+            self.served.pop_front();
+        }
+        self.served.push_back((now, now.duration_since(started)));
+    }
+
+    fn latency(&mut self) -> Duration {
+        let now = self.time_source.now();
+        while let Some((finished, _)) = self.served.front() {
+            if now.duration_since(*finished) < LATENCY_WINDOW {
+                break;
+            }
+            self.served.pop_front();
+        }
+        if self.served.is_empty() {
+            return Duration::ZERO;
+        }
+        let total: Duration = self.served.iter().map(|(_, took)| *took).sum();
+        total / self.served.len() as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::time_source::MockTimeSource;
+
+    use super::*;
+
+    fn admission(limits: LoadShedding) -> Admission<MockTimeSource> {
+        Admission::with_time_source(limits, MockTimeSource::new(Instant::now()))
+    }
+
+    /// Serves a request which takes `took` once admitted.
+    fn serve(admission: &mut Admission<MockTimeSource>, took: Duration) -> Result<(), Overload> {
+        admission.admit(0)?;
+        let started = admission.started();
+        admission.time_source.advance_time(took);
+        admission.finished(started);
+        Ok(())
+    }
+
+    #[test]
+    fn sheds_load_on_high_latency() {
+        let mut admission = admission(LoadShedding {
+            max_latency_ms: Some(100),
+            ..Default::default()
+        });
+        for _ in 0..4 {
+            serve(&mut admission, Duration::from_millis(50)).unwrap();
+        }
+        // the node slows down until the average goes over the threshold
+        let mut admitted = 0;
+        while serve(&mut admission, Duration::from_millis(500)).is_ok() {
+            admitted += 1;
+        }
+        assert_eq!(admitted, 1);
+        assert!(
+            matches!(admission.admit(0), Err(Overload::Latency(latency)) if latency > Duration::from_millis(100))
+        );
+
+        // nothing served lately, the node gets another chance
+        admission.time_source.advance_time(LATENCY_WINDOW);
+        assert_eq!(admission.admit(0), Ok(()));
+    }
+
+    #[test]
+    fn sheds_load_on_deep_queue() {
+        let mut admission = admission(LoadShedding {
+            max_queue_depth: Some(8),
+            ..Default::default()
+        });
+        assert_eq!(admission.admit(8), Ok(()));
+        assert_eq!(admission.admit(9), Err(Overload::QueueDepth(9)));
+    }
+
+    #[test]
+    fn unset_thresholds_admit_everything() {
+        let mut admission = admission(LoadShedding::default());
+        serve(&mut admission, Duration::from_secs(60)).unwrap();
+        assert_eq!(admission.admit(usize::MAX), Ok(()));
+    }
+}
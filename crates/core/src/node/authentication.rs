@@ -0,0 +1,157 @@
+//! Operations only authenticated clients may request, so a node can serve anyone reading its
+//! contracts while keeping writes to those it handed out a token to.
+//!
+//! A client is authenticated when it connects with a token the node still knows of, the one
+//! handed to the web app of a contract. Getting a contract while subscribing to it requires
+//! whatever subscribing does.
+
+use std::collections::HashSet;
+
+use freenet_stdlib::client_api::{
+    ClientRequest, ContractError, ContractRequest, DelegateError, RequestError,
+};
+
+use crate::config::Operation;
+
+#[derive(Default)]
+pub(crate) struct AuthenticatedOperations(HashSet<Operation>);
+
+impl AuthenticatedOperations {
+    pub fn new(operations: &[Operation]) -> Self {
+        Self(operations.iter().copied().collect())
+    }
+
+    /// Checks whether `request` may be made by the client, whether `authenticated` or not.
+    pub fn admit(
+        &self,
+        request: &ClientRequest<'_>,
+        authenticated: bool,
+    ) -> Result<(), RequestError> {
+        if authenticated {
+            return Ok(());
+        }
+        let Some(operation) = operation(request).filter(|op| self.0.contains(op)) else {
+            return Ok(());
+        };
+        let cause = format!(
+            "unauthenticated, {} requires authentication",
+            name(operation)
+        );
+        let err = match request {
+            ClientRequest::ContractOp(ContractRequest::Put { contract, .. }) => {
+                ContractError::Put {
+                    key: contract.key(),
+                    cause: cause.into(),
+                }
+            }
+            ClientRequest::ContractOp(ContractRequest::Update { key, .. }) => {
+                ContractError::Update {
+                    key: *key,
+                    cause: cause.into(),
+                }
+            }
+            ClientRequest::ContractOp(ContractRequest::Get {
+                key,
+                subscribe: false,
+                ..
+            }) => ContractError::Get {
+                key: *key,
+                cause: cause.into(),
+            },
+            ClientRequest::ContractOp(
+                ContractRequest::Subscribe { key, .. } | ContractRequest::Get { key, .. },
+            ) => ContractError::Subscribe {
+                key: *key,
+                cause: cause.into(),
+            },
+            _ => return Err(DelegateError::ExecutionError(cause.into()).into()),
+        };
+        Err(err.into())
+    }
+}
+
+fn name(operation: Operation) -> &'static str {
+    match operation {
+        Operation::Get => "getting",
+        Operation::Put => "putting",
+        Operation::Update => "updating",
+        Operation::Subscribe => "subscribing",
+        Operation::Delegate => "requesting delegates",
+    }
+}
+
+fn operation(request: &ClientRequest<'_>) -> Option<Operation> {
+    let operation = match request {
+        ClientRequest::ContractOp(ContractRequest::Put { .. }) => Operation::Put,
+        ClientRequest::ContractOp(ContractRequest::Update { .. }) => Operation::Update,
+        ClientRequest::ContractOp(
+            ContractRequest::Subscribe { .. }
+            | ContractRequest::Get {
+                subscribe: true, ..
+            },
+        ) => Operation::Subscribe,
+        ClientRequest::ContractOp(ContractRequest::Get { .. }) => Operation::Get,
+        ClientRequest::DelegateOp(_) => Operation::Delegate,
+        _ => return None,
+    };
+    Some(operation)
+}
+
+#[cfg(test)]
+mod tests {
+    use freenet_stdlib::prelude::{ContractInstanceId, ContractKey, State, StateDelta, UpdateData};
+
+    use super::*;
+
+    #[test]
+    fn anonymous_reads_only() {
+        let required = AuthenticatedOperations::new(&[
+            Operation::Put,
+            Operation::Update,
+            Operation::Subscribe,
+        ]);
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let get = |subscribe| {
+            ClientRequest::ContractOp(ContractRequest::Get {
+                key,
+                return_contract_code: false,
+                subscribe,
+            })
+        };
+        let update = ClientRequest::ContractOp(ContractRequest::Update {
+            key,
+            data: UpdateData::Delta(StateDelta::from(vec![1])),
+        });
+        let subscribe =
+            ClientRequest::ContractOp(ContractRequest::Subscribe { key, summary: None });
+
+        assert!(required.admit(&get(false), false).is_ok());
+        let err = required.admit(&update, false).unwrap_err();
+        assert!(
+            matches!(&err, RequestError::ContractError(ContractError::Update { key: rejected, cause })
+                if *rejected == key && cause.starts_with("unauthenticated")),
+            "{err}"
+        );
+        assert!(matches!(
+            required.admit(&subscribe, false),
+            Err(RequestError::ContractError(ContractError::Subscribe { .. }))
+        ));
+        // subscribing while getting counts as subscribing
+        assert!(matches!(
+            required.admit(&get(true), false),
+            Err(RequestError::ContractError(ContractError::Subscribe { .. }))
+        ));
+
+        // authenticated clients request anything
+        for request in [&get(false), &get(true), &update, &subscribe] {
+            assert!(required.admit(request, true).is_ok(), "{request}");
+        }
+        // and without requirements so does anyone
+        let open = AuthenticatedOperations::default();
+        let replace = ClientRequest::ContractOp(ContractRequest::Update {
+            key,
+            data: UpdateData::State(State::from(vec![1])),
+        });
+        assert!(open.admit(&replace, false).is_ok());
+    }
+}
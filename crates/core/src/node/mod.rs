@@ -17,10 +17,7 @@
 
 use anyhow::Context;
 use either::Either;
-use freenet_stdlib::{
-    client_api::{ClientRequest, ErrorKind},
-    prelude::ContractKey,
-};
+use freenet_stdlib::prelude::ContractKey;
 use std::{
     borrow::Cow,
     fmt::Display,
@@ -35,13 +32,12 @@ use std::{collections::HashSet, convert::Infallible};
 
 use self::p2p_impl::NodeP2P;
 use crate::{
-    client_events::{BoxedClient, ClientEventsProxy, ClientId, OpenRequest},
-    config::{Address, GatewayConfig, WebsocketApiConfig},
+    client_events::{BoxedClient, ClientId},
+    config::{Address, GatewayConfig},
     contract::{
-        Callback, ClientResponsesSender, ContractError, ExecutorError, ExecutorToEventLoopChannel,
+        Callback, ClientResponsesSender, ContractError, ExecutorToEventLoopChannel,
         NetworkContractHandler, WaitingTransaction,
     },
-    local_node::Executor,
     message::{InnerMessage, NetMessage, Transaction, TransactionType},
     operations::{
         connect::{self, ConnectOp},
@@ -51,13 +47,34 @@ use crate::{
     router::{RouteEvent, RouteOutcome},
     tracing::{EventRegister, NetEventLog, NetEventRegister},
 };
+#[cfg(feature = "http-gateway")]
+use crate::{
+    client_events::{ClientEventsProxy, OpenRequest},
+    config::{ErrorDetails, RequestTimeouts, WebsocketApiConfig},
+    contract::{ExecutorError, TransactionOutcome},
+    local_node::Executor,
+    server::{
+        http_gateway::ExecutorCommand,
+        shutdown::{ShutdownSequence, ShutdownStage},
+        ServerState, ServerStateWatch,
+    },
+    tracing::otlp::RequestSpan,
+};
 use crate::{
     config::Config,
     message::{MessageStats, NetMessageV1},
 };
-use freenet_stdlib::client_api::DelegateRequest;
+#[cfg(feature = "http-gateway")]
+use freenet_stdlib::client_api::{
+    ClientRequest, ContractRequest, ContractResponse, DelegateRequest, ErrorKind, HostResponse,
+    RequestError,
+};
+#[cfg(feature = "http-gateway")]
+use freenet_stdlib::prelude::{ContractInstanceId, UpdateData};
 use rsa::pkcs8::DecodePublicKey;
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "http-gateway")]
+use std::time::SystemTime;
 use tracing::Instrument;
 
 use crate::operations::handle_op_request;
@@ -67,9 +84,23 @@ use crate::topology::rate::Rate;
 use crate::transport::{TransportKeypair, TransportPublicKey};
 pub(crate) use op_state_manager::{OpManager, OpNotAvailable};
 
+#[cfg(feature = "http-gateway")]
+mod admission;
+#[cfg(feature = "http-gateway")]
+mod authentication;
 mod network_bridge;
+#[cfg(feature = "http-gateway")]
+mod not_found_cache;
 mod op_state_manager;
 mod p2p_impl;
+#[cfg(feature = "http-gateway")]
+mod private_contracts;
+#[cfg(feature = "http-gateway")]
+mod recent_gets;
+#[cfg(feature = "http-gateway")]
+mod reconnection_grace;
+#[cfg(feature = "http-gateway")]
+mod replica;
 pub(crate) mod testing_impl;
 
 pub struct Node(NodeP2P);
@@ -913,6 +944,42 @@ impl Display for PeerId {
     }
 }
 
+#[cfg(feature = "http-gateway")]
+async fn next_primary_update(
+    replica: &mut Option<replica::Replica>,
+) -> Option<replica::PrimaryUpdate> {
+    match replica {
+        Some(replica) => replica.next_update().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Runs a contract request within its timeout. Reads are failed once over it, writes are
+/// finished regardless since cancelling one midway could leave the contract half updated.
+#[cfg(feature = "http-gateway")]
+async fn within_timeout(
+    client: ClientId,
+    timeout: Duration,
+    read: bool,
+    request: impl std::future::Future<Output = Result<HostResponse, ExecutorError>>,
+) -> Result<HostResponse, ExecutorError> {
+    tokio::pin!(request);
+    match tokio::time::timeout(timeout, &mut request).await {
+        Ok(res) => res,
+        Err(_) if read => {
+            tracing::warn!(client_id = %client, ?timeout, "contract request timed out");
+            Err(ExecutorError::other(anyhow::anyhow!(
+                "request timed out after {timeout:?}"
+            )))
+        }
+        Err(_) => {
+            tracing::warn!(client_id = %client, ?timeout, "contract write over its timeout, finishing it");
+            request.await
+        }
+    }
+}
+
+#[cfg(feature = "http-gateway")]
 pub async fn run_local_node(
     mut executor: Executor,
     socket: WebsocketApiConfig,
@@ -927,7 +994,34 @@ pub async fn run_local_node(
         _ => {}
     }
 
-    let (mut gw, mut ws_proxy) = crate::server::serve_gateway_in(socket).await;
+    let request_timeouts = socket.request_timeouts;
+    let error_details = socket.error_details;
+    let shutdown_timeouts = socket.shutdown_timeouts;
+    let drain_deadline = Duration::from_secs(socket.shutdown_deadline_secs);
+    let mut admission = admission::Admission::new(socket.load_shedding);
+    let span_exporter = socket
+        .otlp_endpoint
+        .as_deref()
+        .map(crate::tracing::otlp::SpanExporter::start)
+        .transpose()?;
+    let mut not_found =
+        not_found_cache::NotFoundCache::new(Duration::from_millis(socket.not_found_cache_ttl_ms));
+    let mut recent_gets =
+        recent_gets::RecentGets::new(Duration::from_millis(socket.get_dedup_window_ms));
+    let private_contracts = private_contracts::PrivateContracts::new(&socket.private_contracts)?;
+    let reconnection_grace_secs = socket.reconnection_grace_secs.clone();
+    let authentication =
+        authentication::AuthenticatedOperations::new(&socket.authenticated_operations);
+    let mut replica = match &socket.replica_of {
+        Some(primary) => Some(replica::Replica::connect(primary, socket.replica_connection).await?),
+        None => None,
+    };
+    let (mut gw, mut ws_proxy) = crate::server::serve_gateway_in(socket, None).await?;
+    let mut reconnection_grace = reconnection_grace::ReconnectionGrace::new(
+        &reconnection_grace_secs,
+        &gw.attested_contracts,
+    );
+    let mut executor_commands = gw.take_executor_commands();
 
     // TODO: use combinator instead
     // let mut all_clients =
@@ -937,8 +1031,39 @@ pub async fn run_local_node(
         Gw,
     }
     let mut receiver;
+    // requests keep being served while the gateway drains them
+    let mut shutdown: Option<ShutdownSequence> = None;
+    let mut server_state = ws_proxy.server_state();
     loop {
         let req = tokio::select! {
+            _ = tokio::signal::ctrl_c(), if shutdown.is_none() => {
+                shutdown = Some(ShutdownSequence::start(shutdown_timeouts, drain_deadline));
+                ws_proxy.stop_accepting();
+                continue;
+            }
+            state = server_state_changed(&mut server_state), if shutdown.is_some() => {
+                let Some(sequence) = &mut shutdown else { continue };
+                match state {
+                    ServerState::Serving => {}
+                    ServerState::Draining => {
+                        sequence.complete(ShutdownStage::StopAccepting);
+                    }
+                    ServerState::Stopped => {
+                        sequence.complete(ShutdownStage::StopAccepting);
+                        sequence.complete(ShutdownStage::DrainRequests);
+                        break;
+                    }
+                }
+                continue;
+            }
+            _ = shutdown_deadline(&shutdown) => {
+                let Some(sequence) = &mut shutdown else { continue };
+                sequence.timed_out();
+                if sequence.stage() > Some(ShutdownStage::DrainRequests) {
+                    break;
+                }
+                continue;
+            }
             req = ws_proxy.recv() => {
                 receiver = Receiver::Ws;
                 req?
@@ -947,6 +1072,92 @@ pub async fn run_local_node(
                 receiver = Receiver::Gw;
                 req?
             }
+            Some(command) = executor_commands.recv() => {
+                match command {
+                    ExecutorCommand::Revalidate { key, respond } => {
+                        let _ = respond.send(executor.revalidate_contract(&key).await);
+                    }
+                    ExecutorCommand::Metadata { key, respond } => {
+                        let _ = respond.send(executor.contract_metadata(&key).await);
+                    }
+                    ExecutorCommand::Topics { key, respond } => {
+                        let _ = respond.send(executor.contract_topics(&key).await);
+                    }
+                    ExecutorCommand::Dependencies { key, respond } => {
+                        let _ = respond.send(executor.contract_dependencies(&key).await);
+                    }
+                    ExecutorCommand::Range { key, offset, limit, respond } => {
+                        let _ = respond.send(executor.range_query(&key, offset, limit).await);
+                    }
+                    ExecutorCommand::Estimate { key, update, respond } => {
+                        let _ = respond.send(executor.estimate_update(&key, update).await);
+                    }
+                    ExecutorCommand::History { key, version, respond } => {
+                        let _ = respond.send(executor.historical_state(&key, &version).await);
+                    }
+                    ExecutorCommand::Diff { key, base, target, respond } => {
+                        let _ = respond.send(executor.state_diff(&key, &base, target.as_ref()).await);
+                    }
+                    ExecutorCommand::Transaction { updates, auth_token, respond } => {
+                        let attested_contract = auth_token.and_then(|token| gw.attested_contract(&token));
+                        let refused = transaction_refusal(&authentication, &private_contracts, &updates, attested_contract.as_ref());
+                        let keys: Vec<_> = updates.iter().map(|(key, _)| *key).collect();
+                        let started_at = SystemTime::now();
+                        let res = match (refused, &replica) {
+                            (Some(err), _) => {
+                                tracing::info!(?attested_contract, "unauthorized transaction");
+                                Err(ExecutorError::request(err))
+                            }
+                            (None, Some(replica)) => replica
+                                .transaction(updates)
+                                .await
+                                .map_err(ExecutorError::other),
+                            (None, None) => {
+                                let started = admission.started();
+                                let res = executor.apply_transaction(updates).await;
+                                admission.finished(started);
+                                res
+                            }
+                        };
+                        if let Ok(TransactionOutcome::Committed { .. }) = &res {
+                            for key in &keys {
+                                not_found.invalidate(key.id());
+                                recent_gets.invalidate(key.id());
+                            }
+                        }
+                        if let Some(exporter) = &span_exporter {
+                            exporter.record(RequestSpan {
+                                name: "contract.transaction",
+                                start: started_at,
+                                end: SystemTime::now(),
+                                attributes: vec![("contract.count", keys.len().to_string())],
+                                error: res.as_ref().err().map(|err| err.to_string()),
+                            });
+                        }
+                        let _ = respond.send(res);
+                    }
+                    ExecutorCommand::Code { key, respond } => {
+                        let _ = respond.send(executor.contract_code(&key).await);
+                    }
+                    ExecutorCommand::ExportedContracts { contracts, respond } => {
+                        let included = |id: &freenet_stdlib::prelude::ContractInstanceId| contracts.is_empty() || contracts.contains(id);
+                        let _ = respond.send(executor.exported_contracts(included).await);
+                    }
+                    ExecutorCommand::ExportContract { key, respond } => {
+                        let _ = respond.send(executor.export_contract(&key).await);
+                    }
+                    ExecutorCommand::Import { contract, respond } => {
+                        let _ = respond.send(executor.import_contract(*contract).await);
+                    }
+                }
+                continue;
+            }
+            Some(update) = next_primary_update(&mut replica) => {
+                if let Some(replica) = &mut replica {
+                    replica.apply(update);
+                }
+                continue;
+            }
         };
         let OpenRequest {
             client_id: id,
@@ -957,19 +1168,112 @@ pub async fn run_local_node(
         } = req;
         tracing::debug!(client_id = %id, ?token, "Received OpenRequest -> {request}");
 
+        if matches!(
+            *request,
+            ClientRequest::ContractOp(_) | ClientRequest::DelegateOp(_)
+        ) {
+            // both proxies hold the requests they scheduled in the work queue until the node
+            // picks them up, so its depth is the whole backlog
+            if let Err(overload) = admission.admit(gw.work_queue().snapshot().depth) {
+                tracing::debug!(client_id = %id, %overload, "node overloaded, rejecting request");
+                // clients are expected to retry once the node is available again
+                let err = Err(ErrorKind::NodeUnavailable.into());
+                match receiver {
+                    Receiver::Ws => ws_proxy.send(id, err).await?,
+                    Receiver::Gw => gw.send(id, err).await?,
+                };
+                continue;
+            }
+        }
+
+        if let Some(token) = &token {
+            reconnection_grace.reconnected(token);
+        }
+        let attested_contract = token.and_then(|token| gw.attested_contract(&token));
+        let mut unauthorized = refusal(
+            &authentication,
+            &private_contracts,
+            &request,
+            attested_contract.as_ref(),
+        );
+
+        let started_at = SystemTime::now();
+        let span_name = request_span_name(&request);
+        let recent_get = match &*request {
+            ClientRequest::ContractOp(op) => recent_gets::RecentGet::of(id, op),
+            _ => None,
+        };
+        let mut recent_result = recent_get.as_ref().and_then(|get| recent_gets.result(get));
         let res = match *request {
-            ClientRequest::ContractOp(op) => {
-                executor
-                    .contract_requests(op, id, notification_channel)
+            ClientRequest::ContractOp(_) | ClientRequest::DelegateOp(_)
+                if unauthorized.is_some() =>
+            {
+                tracing::info!(client_id = %id, ?attested_contract, "unauthorized request");
+                Err(ExecutorError::request(
+                    unauthorized.take().expect("unauthorized"),
+                ))
+            }
+            ClientRequest::ContractOp(ContractRequest::Get { key, .. })
+                if recent_result.is_some() =>
+            {
+                tracing::debug!(client_id = %id, contract = %key, "get repeated within the dedup window");
+                Ok(recent_result.take().expect("recent result"))
+            }
+            ClientRequest::ContractOp(op) if replica.is_some() => {
+                let replica = replica.as_mut().expect("replica");
+                replica
+                    .request(op, notification_channel)
                     .await
+                    .map_err(|err| match err.kind() {
+                        ErrorKind::RequestError(err) => ExecutorError::request(err.clone()),
+                        _ => ExecutorError::other(anyhow::anyhow!("{err}")),
+                    })
+            }
+            ClientRequest::ContractOp(ContractRequest::Get { key, .. })
+                if not_found.is_missing(key.id()) =>
+            {
+                tracing::debug!(client_id = %id, contract = %key, "contract recently found missing");
+                Err(ExecutorError::missing_contract(key))
+            }
+            ClientRequest::ContractOp(op) => {
+                let get_key = match &op {
+                    ContractRequest::Get { key, .. } => Some(*key.id()),
+                    _ => None,
+                };
+                let timeout = request_timeouts.for_request(&op);
+                let read = RequestTimeouts::is_read(&op);
+                let started = admission.started();
+                let request = executor.contract_requests(op, id, notification_channel);
+                let res = within_timeout(id, timeout, read, request).await;
+                admission.finished(started);
+                match (&res, get_key) {
+                    (Err(err), Some(key)) if err.is_missing_contract() => {
+                        not_found.record_miss(key)
+                    }
+                    (
+                        Ok(HostResponse::ContractResponse(ContractResponse::PutResponse { key })),
+                        _,
+                    ) => {
+                        not_found.invalidate(key.id());
+                        recent_gets.invalidate(key.id());
+                    }
+                    (
+                        Ok(HostResponse::ContractResponse(ContractResponse::UpdateResponse {
+                            key,
+                            ..
+                        })),
+                        _,
+                    ) => recent_gets.invalidate(key.id()),
+                    (Ok(res), _) => {
+                        if let Some(get) = recent_get {
+                            recent_gets.record(get, res);
+                        }
+                    }
+                    _ => {}
+                }
+                res
             }
             ClientRequest::DelegateOp(op) => {
-                let attested_contract = token.and_then(|token| {
-                    gw.attested_contracts
-                        .read()
-                        .ok()
-                        .and_then(|guard| guard.get(&token).map(|(t, _)| *t))
-                });
                 let op_name = match op {
                     DelegateRequest::RegisterDelegate { .. } => "RegisterDelegate",
                     DelegateRequest::ApplicationMessages { .. } => "ApplicationMessages",
@@ -988,22 +1292,24 @@ pub async fn run_local_node(
                 if let Some(cause) = cause {
                     tracing::info!("disconnecting cause: {cause}");
                 }
-                // FIXME: We're not removing tokens on disconnect to allow WebSocket connections
-                // to use them for authentication. We should implement a proper token expiration
-                // mechanism instead of keeping them forever or removing them immediately.
-                // if let Ok(mut guard) = gw.attested_contracts.write() {
-                //     if let Some(rm_token) = guard
-                //         .iter()
-                //         .find_map(|(k, (_, eid))| (eid == &id).then(|| k.clone()))
-                //     {
-                //         guard.remove(&rm_token);
-                //     }
-                // }
+                // the token outlives the client so WebSocket connections can keep using it for
+                // authentication, for as long as the grace window of its tier
+                reconnection_grace.defer_removal(&gw.attested_contracts, id);
                 continue;
             }
             _ => Err(ExecutorError::other(anyhow::anyhow!("not supported"))),
         };
 
+        if let Some(exporter) = &span_exporter {
+            exporter.record(RequestSpan {
+                name: span_name,
+                start: started_at,
+                end: SystemTime::now(),
+                attributes: vec![("client.id", id.to_string())],
+                error: res.as_ref().err().map(|err| err.to_string()),
+            });
+        }
+
         match res {
             Ok(res) => {
                 match receiver {
@@ -1025,7 +1331,7 @@ pub async fn run_local_node(
             Err(err) => {
                 tracing::error!("{err}");
                 let err = Err(ErrorKind::Unhandled {
-                    cause: format!("{err}").into(),
+                    cause: unhandled_cause(&err, error_details).into(),
                 }
                 .into());
                 match receiver {
@@ -1039,6 +1345,108 @@ pub async fn run_local_node(
             }
         }
     }
+
+    let Some(mut sequence) = shutdown else {
+        unreachable!("the node only stops serving requests once shutting down")
+    };
+    sequence
+        .run(
+            ShutdownStage::CloseSubscriptions,
+            ws_proxy.close_subscriptions(),
+        )
+        .await;
+    // tearing down the runtime and the stores of the executor may block
+    sequence
+        .run(ShutdownStage::StopExecutor, async move {
+            let _ = tokio::task::spawn_blocking(move || drop(executor)).await;
+        })
+        .await;
+    sequence.finish();
+    Ok(())
+}
+
+#[cfg(feature = "http-gateway")]
+async fn server_state_changed(state: &mut Option<ServerStateWatch>) -> ServerState {
+    match state {
+        Some(state) => state.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// When the stage of the shutdown in progress is given up on, never when not shutting down.
+#[cfg(feature = "http-gateway")]
+async fn shutdown_deadline(shutdown: &Option<ShutdownSequence>) {
+    match shutdown.as_ref().and_then(ShutdownSequence::deadline) {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// What the client is told of an error the node failed with serving its request.
+#[cfg(feature = "http-gateway")]
+fn unhandled_cause(err: &ExecutorError, details: ErrorDetails) -> String {
+    match details {
+        ErrorDetails::Message => err.to_string(),
+        ErrorDetails::Chain => err.chain().join("\ncaused by: "),
+    }
+}
+
+/// Name of the span exported for a client request.
+#[cfg(feature = "http-gateway")]
+#[cfg(feature = "http-gateway")]
+/// Why `request` is refused to the client, if it is: either the operation requires
+/// authentication, or the contract is private to another one than `attested_contract`.
+fn refusal(
+    authentication: &authentication::AuthenticatedOperations,
+    private_contracts: &private_contracts::PrivateContracts,
+    request: &ClientRequest<'_>,
+    attested_contract: Option<&ContractInstanceId>,
+) -> Option<RequestError> {
+    authentication
+        .admit(request, attested_contract.is_some())
+        .err()
+        .or_else(|| match request {
+            ClientRequest::ContractOp(op) => private_contracts
+                .authorize(op, attested_contract)
+                .err()
+                .map(RequestError::from),
+            _ => None,
+        })
+}
+
+#[cfg(feature = "http-gateway")]
+/// Why a transaction is refused to the client, that of the first of its updates refused
+/// when requested on its own.
+fn transaction_refusal(
+    authentication: &authentication::AuthenticatedOperations,
+    private_contracts: &private_contracts::PrivateContracts,
+    updates: &[(ContractKey, UpdateData<'static>)],
+    attested_contract: Option<&ContractInstanceId>,
+) -> Option<RequestError> {
+    updates.iter().find_map(|(key, data)| {
+        let update = ClientRequest::ContractOp(ContractRequest::Update {
+            key: *key,
+            data: data.clone(),
+        });
+        refusal(
+            authentication,
+            private_contracts,
+            &update,
+            attested_contract,
+        )
+    })
+}
+
+fn request_span_name(request: &ClientRequest<'_>) -> &'static str {
+    match request {
+        ClientRequest::ContractOp(ContractRequest::Get { .. }) => "contract.get",
+        ClientRequest::ContractOp(ContractRequest::Put { .. }) => "contract.put",
+        ClientRequest::ContractOp(ContractRequest::Update { .. }) => "contract.update",
+        ClientRequest::ContractOp(ContractRequest::Subscribe { .. }) => "contract.subscribe",
+        ClientRequest::ContractOp(_) => "contract",
+        ClientRequest::DelegateOp(_) => "delegate",
+        _ => "request",
+    }
 }
 
 pub async fn run_network_node(mut node: Node) -> anyhow::Result<()> {
@@ -1133,4 +1541,87 @@ mod tests {
         let socket_addr = NodeConfig::parse_socket_addr(&addr).await.unwrap();
         assert_eq!(socket_addr.port(), 8080);
     }
+    #[cfg(feature = "http-gateway")]
+    #[test]
+    fn error_chain_in_debug_mode() {
+        let err = ExecutorError::other(
+            anyhow::anyhow!("disk full")
+                .context("failed writing state")
+                .context("put failed"),
+        );
+        assert_eq!(
+            unhandled_cause(&err, ErrorDetails::Chain),
+            "put failed\ncaused by: failed writing state\ncaused by: disk full"
+        );
+        assert_eq!(unhandled_cause(&err, ErrorDetails::Message), "put failed");
+    }
+
+    #[cfg(feature = "http-gateway")]
+    #[tokio::test]
+    async fn writes_finished_past_their_timeout() -> anyhow::Result<()> {
+        use freenet_stdlib::{
+            client_api::ContractResponse,
+            prelude::{ContractInstanceId, Parameters, WrappedState},
+        };
+
+        use crate::{contract::storages::Storage, wasm_runtime::StateStore};
+
+        let dir = tempfile::tempdir()?;
+        let mut store = StateStore::new(Storage::new(dir.path()).await?, 10_000_000)?;
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let client = ClientId::next();
+        let timeout = Duration::from_millis(10);
+
+        let write = async {
+            tokio::time::sleep(timeout * 5).await;
+            store
+                .store(key, WrappedState::new(vec![1]), Parameters::from(vec![]))
+                .await
+                .map_err(ExecutorError::other)?;
+            Ok(HostResponse::ContractResponse(
+                ContractResponse::PutResponse { key },
+            ))
+        };
+        assert!(within_timeout(client, timeout, false, write).await.is_ok());
+        assert_eq!(store.get(&key).await?.as_ref(), &[1]);
+
+        let read = async {
+            tokio::time::sleep(timeout * 5).await;
+            Ok(HostResponse::Ok)
+        };
+        assert!(within_timeout(client, timeout, true, read).await.is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "http-gateway")]
+    #[test]
+    fn anonymous_transaction_refused() {
+        use freenet_stdlib::{
+            client_api::ContractError,
+            prelude::{ContractInstanceId, StateDelta},
+        };
+
+        use crate::config::Operation;
+
+        let authentication = authentication::AuthenticatedOperations::new(&[Operation::Update]);
+        let private_contracts = private_contracts::PrivateContracts::default();
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let updates = vec![(key, UpdateData::Delta(StateDelta::from(vec![1])))];
+
+        let err = transaction_refusal(&authentication, &private_contracts, &updates, None)
+            .expect("refused");
+        assert!(
+            matches!(&err, RequestError::ContractError(ContractError::Update { key: refused, cause })
+                if *refused == key && cause.starts_with("unauthenticated")),
+            "{err}"
+        );
+        let attested = ContractInstanceId::new([2; 32]);
+        assert!(transaction_refusal(
+            &authentication,
+            &private_contracts,
+            &updates,
+            Some(&attested)
+        )
+        .is_none());
+    }
 }
@@ -0,0 +1,115 @@
+//! Short lived memory of contracts recently found missing, so bursts of requests for a
+//! contract which doesn't exist don't each go through the executor lookup.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use freenet_stdlib::prelude::ContractInstanceId;
+
+use crate::util::time_source::{InstantTimeSrc, TimeSource};
+
+pub(crate) struct NotFoundCache<T: TimeSource = InstantTimeSrc> {
+    ttl: Duration,
+    misses: HashMap<ContractInstanceId, Instant>,
+    time_source: T,
+}
+
+impl NotFoundCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self::with_time_source(ttl, InstantTimeSrc::new())
+    }
+}
+
+impl<T: TimeSource> NotFoundCache<T> {
+    fn with_time_source(ttl: Duration, time_source: T) -> Self {
+        Self {
+            ttl,
+            misses: HashMap::new(),
+            time_source,
+        }
+    }
+
+    /// Whether a recent lookup didn't find the contract.
+    pub fn is_missing(&mut self, key: &ContractInstanceId) -> bool {
+        let Some(at) = self.misses.get(key) else {
+            return false;
+        };
+        if self.time_source.now().duration_since(*at) < self.ttl {
+            return true;
+        }
+        self.misses.remove(key);
+        false
+    }
+
+    pub fn record_miss(&mut self, key: ContractInstanceId) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let now = self.time_source.now();
+        self.misses
+            .retain(|_, at| now.duration_since(*at) < self.ttl);
+        self.misses.insert(key, now);
+    }
+
+    /// Forgets a previous miss, i.e. the contract has been stored since.
+    pub fn invalidate(&mut self, key: &ContractInstanceId) {
+        self.misses.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct MockTimeSrc(Arc<Mutex<Instant>>);
+
+    impl TimeSource for MockTimeSrc {
+        fn now(&self) -> Instant {
+            *self.0.lock()
+        }
+    }
+
+    const TTL: Duration = Duration::from_secs(1);
+
+    /// Serves a get through the cache, returns whether the executor was reached.
+    fn get(cache: &mut NotFoundCache<MockTimeSrc>, key: &ContractInstanceId) -> bool {
+        if cache.is_missing(key) {
+            return false;
+        }
+        cache.record_miss(*key);
+        true
+    }
+
+    #[test]
+    fn repeated_misses_reach_executor_once_within_ttl() {
+        let time = MockTimeSrc(Arc::new(Mutex::new(Instant::now())));
+        let mut cache = NotFoundCache::with_time_source(TTL, time.clone());
+        let key = ContractInstanceId::new([1; 32]);
+
+        let lookups = (0..10).filter(|_| get(&mut cache, &key)).count();
+        assert_eq!(lookups, 1);
+
+        *time.0.lock() += TTL;
+        assert!(get(&mut cache, &key));
+        assert!(!get(&mut cache, &key));
+
+        // the contract got stored meanwhile
+        cache.invalidate(&key);
+        assert!(!cache.is_missing(&key));
+    }
+
+    #[test]
+    fn zero_ttl_disables_cache() {
+        let mut cache = NotFoundCache::new(Duration::ZERO);
+        let key = ContractInstanceId::new([1; 32]);
+        cache.record_miss(key);
+        assert!(!cache.is_missing(&key));
+    }
+}
@@ -0,0 +1,92 @@
+//! Subscriptions to private contracts, which only the clients of their own web app may make.
+//!
+//! Subscribed clients get every update to the state of a contract, so for a private one the
+//! client must be attested for it: it has to connect with a token handed to the web app of
+//! that same contract. Subscribing while getting a contract counts the same as subscribing to
+//! it on its own.
+
+use std::collections::HashSet;
+
+use freenet_stdlib::{
+    client_api::{ContractError, ContractRequest},
+    prelude::ContractInstanceId,
+};
+
+#[derive(Default)]
+pub(crate) struct PrivateContracts(HashSet<ContractInstanceId>);
+
+impl PrivateContracts {
+    pub fn new(contracts: &[String]) -> anyhow::Result<Self> {
+        let contracts = contracts
+            .iter()
+            .map(|contract| {
+                contract
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("invalid private contract id: {contract}"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self(contracts))
+    }
+
+    /// Checks the client making `op`, attested for `attested`, may subscribe as it asks.
+    pub fn authorize(
+        &self,
+        op: &ContractRequest<'_>,
+        attested: Option<&ContractInstanceId>,
+    ) -> Result<(), ContractError> {
+        let key = match op {
+            ContractRequest::Subscribe { key, .. }
+            | ContractRequest::Get {
+                key,
+                subscribe: true,
+                ..
+            } => key,
+            _ => return Ok(()),
+        };
+        if !self.0.contains(key.id()) || attested == Some(key.id()) {
+            return Ok(());
+        }
+        Err(ContractError::Subscribe {
+            key: *key,
+            cause: "unauthorized, the contract is private".into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use freenet_stdlib::prelude::ContractKey;
+
+    use super::*;
+
+    #[test]
+    fn subscribe_to_private_contract() {
+        let private = ContractInstanceId::new([1; 32]);
+        let public = ContractInstanceId::new([2; 32]);
+        let contracts = PrivateContracts::new(&[private.to_string()]).unwrap();
+        let subscribe = |contract| ContractRequest::Subscribe {
+            key: ContractKey::from(contract),
+            summary: None,
+        };
+
+        // only clients attested for it subscribe to the private contract
+        assert!(contracts
+            .authorize(&subscribe(private), Some(&private))
+            .is_ok());
+        let err = contracts
+            .authorize(&subscribe(private), Some(&public))
+            .unwrap_err();
+        assert!(matches!(err, ContractError::Subscribe { key, .. } if key.id() == &private));
+        assert!(contracts.authorize(&subscribe(private), None).is_err());
+        let get = |subscribe| ContractRequest::Get {
+            key: ContractKey::from(private),
+            return_contract_code: false,
+            subscribe,
+        };
+        assert!(contracts.authorize(&get(true), None).is_err());
+        assert!(contracts.authorize(&get(false), None).is_ok());
+
+        assert!(contracts.authorize(&subscribe(public), None).is_ok());
+        assert!(PrivateContracts::new(&["not a contract".into()]).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+//! Results of the gets a client made within the last moments, so a client repeating the
+//! same get in a quick succession is answered with the first result instead of each one
+//! going through the executor.
+//!
+//! Only plain gets are kept, those subscribing have to register the subscription every time.
+//! A result is kept for the window from when it was produced, and dropped earlier if the
+//! contract is written to.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use freenet_stdlib::{
+    client_api::{ContractRequest, ContractResponse, HostResponse},
+    prelude::ContractInstanceId,
+};
+
+use crate::{
+    client_events::ClientId,
+    util::time_source::{InstantTimeSrc, TimeSource},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct RecentGet {
+    client: ClientId,
+    contract: ContractInstanceId,
+    return_contract_code: bool,
+}
+
+impl RecentGet {
+    pub fn of(client: ClientId, op: &ContractRequest) -> Option<Self> {
+        match op {
+            ContractRequest::Get {
+                key,
+                return_contract_code,
+                subscribe: false,
+            } => Some(Self {
+                client,
+                contract: *key.id(),
+                return_contract_code: *return_contract_code,
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) struct RecentGets<T: TimeSource = InstantTimeSrc> {
+    window: Duration,
+    results: HashMap<RecentGet, (Instant, ContractResponse)>,
+    time_source: T,
+}
+
+impl RecentGets {
+    pub fn new(window: Duration) -> Self {
+        Self::with_time_source(window, InstantTimeSrc::new())
+    }
+}
+
+impl<T: TimeSource> RecentGets<T> {
+    fn with_time_source(window: Duration, time_source: T) -> Self {
+        Self {
+            window,
+            results: HashMap::new(),
+            time_source,
+        }
+    }
+
+    /// The result of the same get made within the window, if any.
+    pub fn result(&mut self, get: &RecentGet) -> Option<HostResponse> {
+        let (at, result) = self.results.get(get)?;
+        if self.time_source.now().duration_since(*at) < self.window {
+            return Some(result.clone().into());
+        }
+        self.results.remove(get);
+        None
+    }
+
+    pub fn record(&mut self, get: RecentGet, result: &HostResponse) {
+        let HostResponse::ContractResponse(result @ ContractResponse::GetResponse { .. }) = result
+        else {
+            return;
+        };
+        if self.window.is_zero() {
+            return;
+        }
+        let now = self.time_source.now();
+        self.results
+            .retain(|_, (at, _)| now.duration_since(*at) < self.window);
+        self.results.insert(get, (now, result.clone()));
+    }
+
+    /// Forgets the results for a contract which changed since.
+    pub fn invalidate(&mut self, contract: &ContractInstanceId) {
+        self.results.retain(|get, _| &get.contract != contract);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use freenet_stdlib::prelude::{ContractKey, WrappedState};
+
+    use crate::util::time_source::MockTimeSource;
+
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_millis(100);
+
+    fn get(key: ContractKey) -> ContractRequest<'static> {
+        ContractRequest::Get {
+            key,
+            return_contract_code: false,
+            subscribe: false,
+        }
+    }
+
+    /// Serves a get through the recent results, counting the executor calls.
+    fn serve(
+        recent: &mut RecentGets<MockTimeSource>,
+        client: ClientId,
+        op: &ContractRequest,
+        executor_calls: &mut usize,
+    ) -> WrappedState {
+        let get = RecentGet::of(client, op);
+        if let Some(result) = get.as_ref().and_then(|get| recent.result(get)) {
+            return result.unwrap_get().0;
+        }
+        *executor_calls += 1;
+        let ContractRequest::Get { key, .. } = op else {
+            unreachable!()
+        };
+        let result: HostResponse = ContractResponse::GetResponse {
+            key: *key,
+            contract: None,
+            state: WrappedState::new(vec![*executor_calls as u8]),
+        }
+        .into();
+        if let Some(get) = get {
+            recent.record(get, &result);
+        }
+        result.unwrap_get().0
+    }
+
+    #[test]
+    fn identical_gets_reach_executor_once() {
+        let mut recent = RecentGets::with_time_source(WINDOW, MockTimeSource::new(Instant::now()));
+        let client = ClientId::next();
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let mut calls = 0;
+
+        let first = serve(&mut recent, client, &get(key), &mut calls);
+        for _ in 0..10 {
+            let repeated = serve(&mut recent, client, &get(key), &mut calls);
+            assert_eq!(repeated, first);
+        }
+        assert_eq!(calls, 1);
+
+        // other clients and gets subscribing are served on their own
+        serve(&mut recent, ClientId::next(), &get(key), &mut calls);
+        assert_eq!(calls, 2);
+        let subscribing = ContractRequest::Get {
+            key,
+            return_contract_code: false,
+            subscribe: true,
+        };
+        serve(&mut recent, client, &subscribing, &mut calls);
+        assert_eq!(calls, 3);
+
+        recent.time_source.advance_time(WINDOW);
+        serve(&mut recent, client, &get(key), &mut calls);
+        assert_eq!(calls, 4);
+        serve(&mut recent, client, &get(key), &mut calls);
+        assert_eq!(calls, 4);
+
+        // the contract got updated meanwhile
+        recent.invalidate(key.id());
+        serve(&mut recent, client, &get(key), &mut calls);
+        assert_eq!(calls, 5);
+    }
+}
@@ -0,0 +1,243 @@
+//! How long the token of a client outlives the client, by the service tier of the token.
+//!
+//! Once the client a token was handed to disconnects, its web app can still connect with the
+//! token for the grace window of the tier; past it the token is dropped, even if it has not
+//! expired yet. A request made with the token meanwhile, from any connection, keeps it. Tokens
+//! of a tier without a window are only dropped once they expire.
+//!
+//! The removals pending are kept by token along with their generation, a single task drops
+//! the tokens as their windows pass unless their removal was cancelled or deferred again since.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+
+use crate::{
+    client_events::{AuthToken, ClientId},
+    config::ServiceTier,
+    server::http_gateway::AttestedContractMap,
+};
+
+/// Generation of the removal pending for each token.
+type Pending = Arc<Mutex<HashMap<AuthToken, u64>>>;
+
+struct Removal {
+    token: AuthToken,
+    generation: u64,
+    /// When the token removed was issued.
+    issued: Instant,
+    at: tokio::time::Instant,
+    client: ClientId,
+}
+
+#[derive(Default)]
+pub(crate) struct ReconnectionGrace {
+    windows: BTreeMap<ServiceTier, Duration>,
+    pending: Pending,
+    generation: u64,
+    removals: Option<mpsc::UnboundedSender<Removal>>,
+}
+
+impl ReconnectionGrace {
+    pub fn new(
+        windows_secs: &BTreeMap<ServiceTier, u64>,
+        attested_contracts: &AttestedContractMap,
+    ) -> Self {
+        Self::with_windows(
+            windows_secs
+                .iter()
+                .map(|(tier, secs)| (*tier, Duration::from_secs(*secs)))
+                .collect(),
+            attested_contracts,
+        )
+    }
+
+    fn with_windows(
+        windows: BTreeMap<ServiceTier, Duration>,
+        attested_contracts: &AttestedContractMap,
+    ) -> Self {
+        let pending = Pending::default();
+        let removals = (!windows.is_empty()).then(|| {
+            let (removals, scheduled) = mpsc::unbounded_channel();
+            tokio::spawn(remove_tokens(
+                scheduled,
+                pending.clone(),
+                attested_contracts.clone(),
+            ));
+            removals
+        });
+        Self {
+            windows,
+            pending,
+            generation: 0,
+            removals,
+        }
+    }
+
+    pub fn window(&self, tier: ServiceTier) -> Option<Duration> {
+        self.windows.get(&tier).copied()
+    }
+
+    /// Drops the tokens handed to `client` once the grace window of their tier passes.
+    pub fn defer_removal(&mut self, attested_contracts: &AttestedContractMap, client: ClientId) {
+        let Some(removals) = &self.removals else {
+            return;
+        };
+        let Ok(attested) = attested_contracts.read() else {
+            return;
+        };
+        let mut pending = self.pending.lock();
+        for (token, (_, _, issued)) in attested.iter().filter(|(_, (_, id, _))| *id == client) {
+            let Some(window) = self.window(token.tier()) else {
+                continue;
+            };
+            self.generation += 1;
+            pending.insert(token.clone(), self.generation);
+            let _ = removals.send(Removal {
+                token: token.clone(),
+                generation: self.generation,
+                issued: *issued,
+                at: tokio::time::Instant::now() + window,
+                client,
+            });
+        }
+    }
+
+    /// Keeps the token used again by a client, cancelling its removal.
+    pub fn reconnected(&self, token: &AuthToken) {
+        if self.removals.is_some() && self.pending.lock().remove(token).is_some() {
+            tracing::debug!("token used again within its reconnection grace window");
+        }
+    }
+}
+
+/// Drops the tokens as their removals come due, until the grace is dropped.
+async fn remove_tokens(
+    mut scheduled: mpsc::UnboundedReceiver<Removal>,
+    pending: Pending,
+    attested_contracts: AttestedContractMap,
+) {
+    let mut due: BTreeMap<(tokio::time::Instant, u64), Removal> = BTreeMap::new();
+    loop {
+        let next = due.keys().next().map(|(at, _)| *at);
+        let next_due = tokio::time::sleep_until(next.unwrap_or_else(tokio::time::Instant::now));
+        tokio::select! {
+            removal = scheduled.recv() => {
+                let Some(removal) = removal else {
+                    return;
+                };
+                due.insert((removal.at, removal.generation), removal);
+            }
+            _ = next_due, if next.is_some() => {
+                if let Some((_, removal)) = due.pop_first() {
+                    remove(removal, &pending, &attested_contracts);
+                }
+            }
+        }
+    }
+}
+
+fn remove(removal: Removal, pending: &Pending, attested_contracts: &AttestedContractMap) {
+    {
+        let mut pending = pending.lock();
+        // cancelled or deferred again meanwhile
+        if pending.get(&removal.token) != Some(&removal.generation) {
+            return;
+        }
+        pending.remove(&removal.token);
+    }
+    let Ok(mut attested) = attested_contracts.write() else {
+        return;
+    };
+    // unless issued anew meanwhile
+    if attested
+        .get(&removal.token)
+        .is_some_and(|(_, _, issued)| *issued == removal.issued)
+    {
+        tracing::debug!(client = %removal.client, "reconnection grace window over, dropping token");
+        attested.remove(&removal.token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::RwLock};
+
+    use freenet_stdlib::prelude::ContractInstanceId;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn grace_window_by_tier() {
+        let attested_contracts: AttestedContractMap = Arc::new(RwLock::new(HashMap::new()));
+        let mut grace = ReconnectionGrace::with_windows(
+            BTreeMap::from([
+                (ServiceTier::Standard, Duration::from_millis(50)),
+                (ServiceTier::Premium, Duration::from_millis(500)),
+            ]),
+            &attested_contracts,
+        );
+        assert!(grace.window(ServiceTier::Premium) > grace.window(ServiceTier::Standard));
+        assert_eq!(
+            ReconnectionGrace::new(
+                &BTreeMap::from([(ServiceTier::Premium, 60)]),
+                &attested_contracts
+            )
+            .window(ServiceTier::Standard),
+            None
+        );
+
+        let contract = ContractInstanceId::new([1; 32]);
+        let (client, other) = (ClientId::next(), ClientId::next());
+        let standard = AuthToken::generate_with_tier(ServiceTier::Standard);
+        let premium = AuthToken::generate_with_tier(ServiceTier::Premium);
+        let unrelated = AuthToken::generate();
+        {
+            let mut attested = attested_contracts.write().unwrap();
+            attested.insert(standard.clone(), (contract, client, Instant::now()));
+            attested.insert(premium.clone(), (contract, client, Instant::now()));
+            attested.insert(unrelated.clone(), (contract, other, Instant::now()));
+        }
+        grace.defer_removal(&attested_contracts, client);
+        let held = |token: &AuthToken| attested_contracts.read().unwrap().contains_key(token);
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(!held(&standard));
+        assert!(held(&premium));
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!held(&premium));
+        assert!(held(&unrelated));
+    }
+
+    #[tokio::test]
+    async fn removal_cancelled_once_reconnected() {
+        let attested_contracts: AttestedContractMap = Arc::new(RwLock::new(HashMap::new()));
+        let mut grace = ReconnectionGrace::with_windows(
+            BTreeMap::from([(ServiceTier::Standard, Duration::from_millis(100))]),
+            &attested_contracts,
+        );
+        let contract = ContractInstanceId::new([1; 32]);
+        let client = ClientId::next();
+        let token = AuthToken::generate_with_tier(ServiceTier::Standard);
+        attested_contracts
+            .write()
+            .unwrap()
+            .insert(token.clone(), (contract, client, Instant::now()));
+        let held = || attested_contracts.read().unwrap().contains_key(&token);
+
+        grace.defer_removal(&attested_contracts, client);
+        grace.reconnected(&token);
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(held());
+
+        // disconnecting again starts another window
+        grace.defer_removal(&attested_contracts, client);
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(!held());
+    }
+}
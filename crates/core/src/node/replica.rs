@@ -0,0 +1,749 @@
+//! Read replica of the contracts of another node, the primary.
+//!
+//! The replica connects to the websocket API of the primary as any other client would. The
+//! first time a local client reads a contract, the replica subscribes to it on the primary and
+//! fetches its current state and code; from then on gets and subscriptions for it are served
+//! from the replicated copy, which the primary keeps current through the update notifications
+//! of the subscription. Writes are forwarded to the primary, and reach the replica back as
+//! updates like those made by any other client of the primary.
+//!
+//! Requests to the primary are spread over a pool of connections, see [`ReplicaConnection`].
+//! A connection which is lost is made again with an exponential backoff, meanwhile the requests
+//! go through the others. Once connected anew, the contracts subscribed to through it are
+//! subscribed to again and their state fetched, so the updates made while disconnected reach
+//! the replicated copies too.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use anyhow::Context;
+use freenet_stdlib::{
+    client_api::{
+        ClientError, ClientRequest, ContractRequest, ContractResponse, ErrorKind, HostResponse,
+    },
+    prelude::*,
+};
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+    time::MissedTickBehavior,
+};
+use tokio_tungstenite::{
+    tungstenite::{client::IntoClientRequest, http::HeaderValue, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+use crate::{client_events::HostResult, config::ReplicaConnection, contract::TransactionOutcome};
+
+/// Requests to the primary waiting to be written, further ones wait for room.
+const QUEUED_REQUESTS: usize = 64;
+
+type PrimaryRequest = (ClientRequest<'static>, oneshot::Sender<HostResult>);
+
+type PrimaryStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+struct Replicated {
+    contract: ContractContainer,
+    state: WrappedState,
+}
+
+/// A contract updated on the primary.
+pub(crate) struct PrimaryUpdate {
+    key: ContractKey,
+    update: UpdateData<'static>,
+}
+
+struct Pooled {
+    requests: mpsc::Sender<PrimaryRequest>,
+    healthy: Arc<AtomicBool>,
+}
+
+pub(crate) struct Replica {
+    primary: String,
+    pool: Vec<Pooled>,
+    /// The connection of the pool the next request goes through, if healthy.
+    next: AtomicUsize,
+    updates: mpsc::UnboundedReceiver<PrimaryUpdate>,
+    replicated: HashMap<ContractInstanceId, Replicated>,
+    subscribers: HashMap<ContractInstanceId, Vec<mpsc::UnboundedSender<HostResult>>>,
+}
+
+impl Replica {
+    /// Connects to the websocket API of the primary at `primary`, e.g. `ws://primary:50509`.
+    pub async fn connect(primary: &str, settings: ReplicaConnection) -> anyhow::Result<Self> {
+        let (updated, updates) = mpsc::unbounded_channel();
+        let mut pool = Vec::with_capacity(settings.pool_size);
+        for _ in 0..settings.pool_size.max(1) {
+            let conn = connect(primary)
+                .await
+                .with_context(|| format!("failed connecting to primary at {primary}"))?;
+            let (requests, queued) = mpsc::channel(QUEUED_REQUESTS);
+            let healthy = Arc::new(AtomicBool::new(true));
+            let connection = PrimaryConnection {
+                primary: primary.to_owned(),
+                settings,
+                requests: queued,
+                updated: updated.clone(),
+                healthy: healthy.clone(),
+                subscribed: HashSet::new(),
+            };
+            tokio::spawn(connection.run(conn));
+            pool.push(Pooled { requests, healthy });
+        }
+        tracing::info!(%primary, connections = pool.len(), "replicating contracts of primary");
+        Ok(Self {
+            primary: primary.to_owned(),
+            pool,
+            next: AtomicUsize::new(0),
+            updates,
+            replicated: HashMap::new(),
+            subscribers: HashMap::new(),
+        })
+    }
+
+    /// Serves a contract request of a local client.
+    pub async fn request(
+        &mut self,
+        op: ContractRequest<'static>,
+        notifications: Option<mpsc::UnboundedSender<HostResult>>,
+    ) -> HostResult {
+        match op {
+            ContractRequest::Get {
+                key,
+                return_contract_code,
+                subscribe,
+            } => {
+                let replicated = self.replicate(key).await?;
+                let response = ContractResponse::GetResponse {
+                    key: replicated.contract.key(),
+                    contract: return_contract_code.then(|| replicated.contract.clone()),
+                    state: replicated.state.clone(),
+                };
+                if subscribe {
+                    self.subscribe(&key, notifications)?;
+                }
+                Ok(response.into())
+            }
+            ContractRequest::Subscribe { key, .. } => {
+                let key = self.replicate(key).await?.contract.key();
+                self.subscribe(&key, notifications)?;
+                Ok(ContractResponse::SubscribeResponse {
+                    key,
+                    subscribed: true,
+                }
+                .into())
+            }
+            other => self.forward(ClientRequest::ContractOp(other)).await,
+        }
+    }
+
+    /// Forwards a transaction of a local client to the primary, through its HTTP API since
+    /// the websocket one has no transactions.
+    pub async fn transaction(
+        &self,
+        updates: Vec<(ContractKey, UpdateData<'static>)>,
+    ) -> anyhow::Result<TransactionOutcome> {
+        let requests: Vec<_> = updates
+            .into_iter()
+            .map(|(key, data)| ContractRequest::Update { key, data })
+            .collect();
+        let primary = self.primary.trim_end_matches('/');
+        let base = match primary.split_once("://") {
+            Some(("wss", host)) => format!("https://{host}"),
+            Some((_, host)) => format!("http://{host}"),
+            None => format!("http://{primary}"),
+        };
+        let outcome = reqwest::Client::new()
+            .post(format!("{base}/v1/contract/transaction"))
+            .body(bincode::serialize(&requests)?)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("failed forwarding transaction to primary at {primary}"))?
+            .json()
+            .await?;
+        Ok(outcome)
+    }
+
+    pub async fn next_update(&mut self) -> Option<PrimaryUpdate> {
+        self.updates.recv().await
+    }
+
+    /// Applies an update from the primary to the replicated copy and notifies the local
+    /// subscribers of the contract.
+    pub fn apply(&mut self, PrimaryUpdate { key, update }: PrimaryUpdate) {
+        let Some(replicated) = self.replicated.get_mut(key.id()) else {
+            return;
+        };
+        let UpdateData::State(state) = &update else {
+            // the replica subscribes without a summary, so the primary always sends the state
+            tracing::warn!(contract = %key, "unexpected partial update from primary");
+            return;
+        };
+        if replicated.state.as_ref() == state.as_ref() {
+            // e.g. fetched again after reconnecting, with no update made meanwhile
+            return;
+        }
+        replicated.state = WrappedState::new(state.as_ref().to_vec());
+        tracing::debug!(contract = %key, "replicated update from primary");
+        if let Some(subscribers) = self.subscribers.get_mut(key.id()) {
+            subscribers.retain(|subscriber| {
+                let notification = ContractResponse::UpdateNotification {
+                    key,
+                    update: update.clone(),
+                };
+                subscriber.send(Ok(notification.into())).is_ok()
+            });
+        }
+    }
+
+    /// The replicated copy of the contract, fetched from the primary if there is none yet.
+    async fn replicate(&mut self, key: ContractKey) -> Result<&Replicated, ClientError> {
+        if !self.replicated.contains_key(key.id()) {
+            // subscribing first, so no update made after fetching the state is missed
+            self.forward(ClientRequest::ContractOp(ContractRequest::Subscribe {
+                key,
+                summary: None,
+            }))
+            .await?;
+            let fetched = self
+                .forward(ClientRequest::ContractOp(ContractRequest::Get {
+                    key,
+                    return_contract_code: true,
+                    subscribe: false,
+                }))
+                .await?;
+            let HostResponse::ContractResponse(ContractResponse::GetResponse {
+                contract: Some(contract),
+                state,
+                ..
+            }) = fetched
+            else {
+                return Err(ErrorKind::Unhandled {
+                    cause: format!("primary didn't return contract {key}").into(),
+                }
+                .into());
+            };
+            tracing::info!(contract = %key, "replicating contract");
+            self.replicated
+                .insert(*key.id(), Replicated { contract, state });
+        }
+        Ok(&self.replicated[key.id()])
+    }
+
+    fn subscribe(
+        &mut self,
+        key: &ContractKey,
+        notifications: Option<mpsc::UnboundedSender<HostResult>>,
+    ) -> Result<(), ClientError> {
+        let notifications = notifications.ok_or_else(|| ErrorKind::Unhandled {
+            cause: "missing update channel".into(),
+        })?;
+        self.subscribers
+            .entry(*key.id())
+            .or_default()
+            .push(notifications);
+        Ok(())
+    }
+
+    /// Forwards the request through the next healthy connection of the pool.
+    async fn forward(&self, req: ClientRequest<'static>) -> HostResult {
+        let pooled = (0..self.pool.len())
+            .map(|_| &self.pool[self.next.fetch_add(1, Ordering::Relaxed) % self.pool.len()])
+            .find(|pooled| pooled.healthy.load(Ordering::Acquire))
+            .ok_or(ErrorKind::NodeUnavailable)?;
+        let (respond, response) = oneshot::channel();
+        pooled
+            .requests
+            .send((req, respond))
+            .await
+            .map_err(|_| ErrorKind::NodeUnavailable)?;
+        response.await.map_err(|_| ErrorKind::NodeUnavailable)?
+    }
+}
+
+async fn connect(primary: &str) -> anyhow::Result<PrimaryStream> {
+    let mut request =
+        format!("{}/v1/contract/command", primary.trim_end_matches('/')).into_client_request()?;
+    request
+        .headers_mut()
+        .insert("encoding-protocol", HeaderValue::from_static("native"));
+    let (conn, _) = tokio_tungstenite::connect_async(request).await?;
+    Ok(conn)
+}
+
+fn encode(req: &ClientRequest<'static>) -> Result<Message, ClientError> {
+    let msg = bincode::serialize(req).map_err(|err| ErrorKind::Unhandled {
+        cause: err.to_string().into(),
+    })?;
+    Ok(Message::Binary(msg.into()))
+}
+
+/// What a response of the primary, which come in the order of the requests, is for.
+enum Waiting {
+    Client(oneshot::Sender<HostResult>),
+    /// A contract subscribed to again after reconnecting.
+    Resubscribed,
+    /// The state of a contract fetched again after reconnecting.
+    Refetched(ContractKey),
+}
+
+enum Served {
+    /// The replica is gone.
+    Closed,
+    Lost,
+}
+
+/// One of the connections of the pool, made again whenever lost.
+struct PrimaryConnection {
+    primary: String,
+    settings: ReplicaConnection,
+    requests: mpsc::Receiver<PrimaryRequest>,
+    updated: mpsc::UnboundedSender<PrimaryUpdate>,
+    healthy: Arc<AtomicBool>,
+    /// Contracts subscribed to through the connection.
+    subscribed: HashSet<ContractKey>,
+}
+
+impl PrimaryConnection {
+    async fn run(mut self, mut conn: PrimaryStream) {
+        loop {
+            if let Served::Closed = self.serve(conn).await {
+                return;
+            }
+            self.healthy.store(false, Ordering::Release);
+            tracing::warn!(primary = %self.primary, "lost the connection to the primary");
+            let Some(reconnected) = self.reconnect().await else {
+                return;
+            };
+            conn = reconnected;
+            self.healthy.store(true, Ordering::Release);
+            tracing::info!(primary = %self.primary, "reconnected to the primary");
+        }
+    }
+
+    async fn reconnect(&mut self) -> Option<PrimaryStream> {
+        let mut backoff = self.settings.initial_backoff();
+        loop {
+            let wait = tokio::time::sleep(backoff);
+            tokio::pin!(wait);
+            loop {
+                tokio::select! {
+                    _ = &mut wait => break,
+                    req = self.requests.recv() => {
+                        // queued before the connection was known to be lost
+                        let (_, respond) = req?;
+                        let _ = respond.send(Err(ErrorKind::NodeUnavailable.into()));
+                    }
+                }
+            }
+            match tokio::time::timeout(self.settings.health_check(), connect(&self.primary)).await {
+                Ok(Ok(conn)) => return Some(conn),
+                Ok(Err(err)) => {
+                    tracing::debug!(%err, ?backoff, "failed reconnecting to the primary")
+                }
+                Err(_) => tracing::debug!(?backoff, "timed out reconnecting to the primary"),
+            }
+            backoff = (backoff * 2).min(self.settings.max_backoff());
+        }
+    }
+
+    /// Writes the requests to the primary and reads back the responses and the update
+    /// notifications in between, until the connection is lost or the replica gone.
+    async fn serve(&mut self, conn: PrimaryStream) -> Served {
+        let (mut sink, mut stream) = conn.split();
+        let mut waiting = VecDeque::new();
+        let served = 'serve: {
+            for key in &self.subscribed {
+                let resync = [
+                    (
+                        ContractRequest::Subscribe {
+                            key: *key,
+                            summary: None,
+                        },
+                        Waiting::Resubscribed,
+                    ),
+                    (
+                        ContractRequest::Get {
+                            key: *key,
+                            return_contract_code: false,
+                            subscribe: false,
+                        },
+                        Waiting::Refetched(*key),
+                    ),
+                ];
+                for (req, resynced) in resync {
+                    let Ok(msg) = encode(&ClientRequest::ContractOp(req)) else {
+                        continue;
+                    };
+                    if sink.send(msg).await.is_err() {
+                        break 'serve Served::Lost;
+                    }
+                    waiting.push_back(resynced);
+                }
+            }
+            let mut health_check = tokio::time::interval(self.settings.health_check());
+            health_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut heard = Instant::now();
+            loop {
+                tokio::select! {
+                    req = self.requests.recv() => {
+                        let Some((req, respond)) = req else {
+                            break Served::Closed;
+                        };
+                        let msg = match encode(&req) {
+                            Ok(msg) => msg,
+                            Err(err) => {
+                                let _ = respond.send(Err(err));
+                                continue;
+                            }
+                        };
+                        if let Err(err) = sink.send(msg).await {
+                            tracing::warn!(%err, "failed sending request to primary");
+                            break Served::Lost;
+                        }
+                        if let ClientRequest::ContractOp(ContractRequest::Subscribe { key, .. }) = req {
+                            self.subscribed.insert(key);
+                        }
+                        waiting.push_back(Waiting::Client(respond));
+                    }
+                    msg = stream.next() => {
+                        let msg = match msg {
+                            Some(Ok(msg)) => {
+                                heard = Instant::now();
+                                let Message::Binary(msg) = msg else {
+                                    continue;
+                                };
+                                msg
+                            }
+                            Some(Err(err)) => {
+                                tracing::warn!(%err, "failed reading from primary");
+                                break Served::Lost;
+                            }
+                            None => break Served::Lost,
+                        };
+                        let result: HostResult = match bincode::deserialize(&msg) {
+                            Ok(result) => result,
+                            Err(err) => {
+                                tracing::warn!(%err, "malformed message from primary");
+                                continue;
+                            }
+                        };
+                        if let Ok(HostResponse::ContractResponse(
+                            ContractResponse::UpdateNotification { key, update },
+                        )) = result
+                        {
+                            let _ = self.updated.send(PrimaryUpdate { key, update });
+                            continue;
+                        }
+                        match waiting.pop_front() {
+                            Some(Waiting::Client(respond)) => {
+                                let _ = respond.send(result);
+                            }
+                            Some(Waiting::Refetched(key)) => {
+                                if let Ok(HostResponse::ContractResponse(
+                                    ContractResponse::GetResponse { state, .. },
+                                )) = result
+                                {
+                                    let update =
+                                        UpdateData::State(State::from(state.as_ref().to_vec()));
+                                    let _ = self.updated.send(PrimaryUpdate { key, update });
+                                }
+                            }
+                            Some(Waiting::Resubscribed) | None => {}
+                        }
+                    }
+                    _ = health_check.tick() => {
+                        if heard.elapsed() > self.settings.health_check() * 2 {
+                            tracing::warn!("primary stopped answering health checks");
+                            break Served::Lost;
+                        }
+                        if sink.send(Message::Ping(Default::default())).await.is_err() {
+                            break Served::Lost;
+                        }
+                    }
+                }
+            }
+        };
+        // the requests written to the connection are never answered
+        for waiting in waiting {
+            if let Waiting::Client(respond) = waiting {
+                let _ = respond.send(Err(ErrorKind::NodeUnavailable.into()));
+            }
+        }
+        served
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use parking_lot::Mutex;
+    use tokio::sync::broadcast;
+
+    use super::*;
+
+    fn contract() -> ContractContainer {
+        ContractContainer::Wasm(ContractWasmAPIVersion::V1(WrappedContract::new(
+            Arc::new(ContractCode::from(vec![0, 1, 2])),
+            Parameters::from(vec![]),
+        )))
+    }
+
+    #[derive(Debug)]
+    enum Event {
+        /// The state of the contract changed, notified to the replica.
+        Updated(Vec<u8>),
+        /// All the connections are dropped.
+        Disconnect,
+    }
+
+    struct FakePrimary {
+        url: String,
+        /// Requests the primary got.
+        requests: Arc<AtomicUsize>,
+        /// Connections the primary accepted.
+        connections: Arc<AtomicUsize>,
+        state: Arc<Mutex<Vec<u8>>>,
+        events: broadcast::Sender<Arc<Event>>,
+    }
+
+    impl FakePrimary {
+        fn push(&self, new_state: Vec<u8>) {
+            self.events
+                .send(Arc::new(Event::Updated(new_state)))
+                .unwrap();
+        }
+
+        fn disconnect(&self) {
+            self.events.send(Arc::new(Event::Disconnect)).unwrap();
+        }
+    }
+
+    /// Stands in for the primary, accepting any number of connections.
+    async fn primary() -> anyhow::Result<FakePrimary> {
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        let primary = FakePrimary {
+            url: format!("ws://{addr}"),
+            requests: Arc::default(),
+            connections: Arc::default(),
+            state: Arc::new(Mutex::new(vec![1])),
+            events: broadcast::channel(16).0,
+        };
+        let (requests, connections, state, events) = (
+            primary.requests.clone(),
+            primary.connections.clone(),
+            primary.state.clone(),
+            primary.events.clone(),
+        );
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                connections.fetch_add(1, Ordering::SeqCst);
+                let conn = tokio_tungstenite::accept_async(stream).await.unwrap();
+                tokio::spawn(serve_replica(
+                    conn,
+                    requests.clone(),
+                    state.clone(),
+                    events.subscribe(),
+                ));
+            }
+        });
+        Ok(primary)
+    }
+
+    async fn serve_replica(
+        mut conn: WebSocketStream<tokio::net::TcpStream>,
+        requests: Arc<AtomicUsize>,
+        state: Arc<Mutex<Vec<u8>>>,
+        mut events: broadcast::Receiver<Arc<Event>>,
+    ) {
+        loop {
+            let msg = tokio::select! {
+                msg = conn.next() => msg,
+                Ok(event) = events.recv() => {
+                    let Event::Updated(new_state) = &*event else {
+                        return;
+                    };
+                    *state.lock() = new_state.clone();
+                    let update: HostResult = Ok(ContractResponse::UpdateNotification {
+                        key: contract().key(),
+                        update: UpdateData::State(State::from(new_state.clone())),
+                    }
+                    .into());
+                    let update = bincode::serialize(&update).unwrap();
+                    let _ = conn.send(Message::Binary(update.into())).await;
+                    continue;
+                }
+            };
+            let msg = match msg {
+                Some(Ok(Message::Binary(msg))) => msg,
+                Some(Ok(_)) => continue,
+                _ => break,
+            };
+            requests.fetch_add(1, Ordering::SeqCst);
+            let req: ClientRequest = bincode::deserialize(&msg).unwrap();
+            let ClientRequest::ContractOp(op) = req else {
+                continue;
+            };
+            let response: HostResult = match op {
+                ContractRequest::Subscribe { key, .. } => Ok(ContractResponse::SubscribeResponse {
+                    key,
+                    subscribed: true,
+                }
+                .into()),
+                ContractRequest::Get { .. } => Ok(ContractResponse::GetResponse {
+                    key: contract().key(),
+                    contract: Some(contract()),
+                    state: WrappedState::new(state.lock().clone()),
+                }
+                .into()),
+                ContractRequest::Update { key, .. } => Ok(ContractResponse::UpdateResponse {
+                    key,
+                    summary: StateSummary::from(vec![]),
+                }
+                .into()),
+                _ => Err(ErrorKind::Unhandled {
+                    cause: "unexpected request".into(),
+                }
+                .into()),
+            };
+            let response = bincode::serialize(&response).unwrap();
+            if conn.send(Message::Binary(response.into())).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    fn get() -> ContractRequest<'static> {
+        ContractRequest::Get {
+            key: contract().key(),
+            return_contract_code: false,
+            subscribe: false,
+        }
+    }
+
+    fn state(response: HostResult) -> Vec<u8> {
+        response.unwrap().unwrap_get().0.as_ref().to_vec()
+    }
+
+    #[tokio::test]
+    async fn reads_served_from_replica() -> anyhow::Result<()> {
+        let primary = primary().await?;
+        let requests = primary.requests.clone();
+        let settings = ReplicaConnection {
+            pool_size: 1,
+            ..Default::default()
+        };
+        let mut replica = Replica::connect(&primary.url, settings).await?;
+
+        // the first read replicates the contract: a subscription and a get on the primary
+        assert_eq!(state(replica.request(get(), None).await), [1]);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        for _ in 0..5 {
+            assert_eq!(state(replica.request(get(), None).await), [1]);
+        }
+        let (notifications, mut notified) = mpsc::unbounded_channel();
+        let subscribe = ContractRequest::Subscribe {
+            key: contract().key(),
+            summary: None,
+        };
+        replica.request(subscribe, Some(notifications)).await?;
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // an update made on the primary reaches the replica and its subscribers
+        primary.push(vec![2]);
+        let update = replica.next_update().await.unwrap();
+        replica.apply(update);
+        let Ok(HostResponse::ContractResponse(ContractResponse::UpdateNotification {
+            update: UpdateData::State(notified_state),
+            ..
+        })) = notified.recv().await.unwrap()
+        else {
+            panic!("expected an update notification");
+        };
+        assert_eq!(notified_state.as_ref(), [2]);
+        assert_eq!(state(replica.request(get(), None).await), [2]);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // writes go to the primary
+        let update = ContractRequest::Update {
+            key: contract().key(),
+            data: UpdateData::State(State::from(vec![3])),
+        };
+        let response = replica.request(update, None).await?;
+        assert!(matches!(
+            response,
+            HostResponse::ContractResponse(ContractResponse::UpdateResponse { .. })
+        ));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_primary_disconnects() -> anyhow::Result<()> {
+        let primary = primary().await?;
+        let settings = ReplicaConnection {
+            pool_size: 2,
+            health_check_secs: 1,
+            initial_backoff_ms: 10,
+            max_backoff_ms: 100,
+        };
+        let mut replica = Replica::connect(&primary.url, settings).await?;
+        assert_eq!(primary.connections.load(Ordering::SeqCst), 2);
+        let (notifications, mut notified) = mpsc::unbounded_channel();
+        let subscribe = ContractRequest::Subscribe {
+            key: contract().key(),
+            summary: None,
+        };
+        replica.request(subscribe, Some(notifications)).await?;
+
+        // the contract is updated while the primary is unreachable from the replica
+        *primary.state.lock() = vec![5];
+        primary.disconnect();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while primary.connections.load(Ordering::SeqCst) < 4 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        // subscribed again after reconnecting, the missed update reaches the subscribers
+        let update = tokio::time::timeout(Duration::from_secs(5), replica.next_update())
+            .await?
+            .unwrap();
+        replica.apply(update);
+        let Ok(HostResponse::ContractResponse(ContractResponse::UpdateNotification {
+            update: UpdateData::State(notified_state),
+            ..
+        })) = notified.recv().await.unwrap()
+        else {
+            panic!("expected an update notification");
+        };
+        assert_eq!(notified_state.as_ref(), [5]);
+        assert_eq!(state(replica.request(get(), None).await), [5]);
+
+        // and writes are forwarded again
+        for _ in 0..4 {
+            let update = ContractRequest::Update {
+                key: contract().key(),
+                data: UpdateData::State(State::from(vec![6])),
+            };
+            let response = replica.request(update, None).await?;
+            assert!(matches!(
+                response,
+                HostResponse::ContractResponse(ContractResponse::UpdateResponse { .. })
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+//! Access to the admin routes of the gateway, those under `/v1/admin`.
+//!
+//! With an admin token configured the routes require it as a bearer token in the
+//! `Authorization` header. Without one they are only served to clients on the loopback
+//! interface, so a gateway listening on other interfaces doesn't expose them to anyone.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use headers::{
+    authorization::{Authorization, Bearer},
+    HeaderMapExt,
+};
+
+use super::client_addr::ClientAddr;
+
+#[derive(Debug, Default)]
+pub(crate) struct AdminAuth {
+    token: Option<String>,
+}
+
+impl AdminAuth {
+    pub fn new(token: Option<String>) -> Arc<Self> {
+        Arc::new(Self { token })
+    }
+
+    fn authorizes(&self, presented: Option<&str>, client: Option<ClientAddr>) -> bool {
+        match (&self.token, presented) {
+            (Some(token), Some(presented)) => constant_time_eq(token, presented),
+            (Some(_), None) => false,
+            (None, _) => client.is_some_and(|ClientAddr(addr)| addr.is_loopback()),
+        }
+    }
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Middleware rejecting the requests to admin routes not authorized by the [`AdminAuth`].
+pub(crate) async fn require_admin(
+    State(auth): State<Arc<AdminAuth>>,
+    client: Option<Extension<ClientAddr>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let presented = req
+        .headers()
+        .typed_get::<Authorization<Bearer>>()
+        .map(|Authorization(bearer)| bearer.token().to_owned());
+    let client = client.map(|Extension(addr)| addr);
+    if !auth.authorizes(presented.as_deref(), client) {
+        tracing::warn!(
+            client = ?client.map(|ClientAddr(addr)| addr),
+            path = %req.uri().path(),
+            "unauthorized request to admin route"
+        );
+        return (StatusCode::UNAUTHORIZED, "admin token required").into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    #[test]
+    fn admin_token_required_when_configured() {
+        let local = Some(ClientAddr(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        let remote = Some(ClientAddr(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))));
+
+        let auth = AdminAuth::new(Some("secret".into()));
+        assert!(auth.authorizes(Some("secret"), remote));
+        assert!(!auth.authorizes(Some("secre"), remote));
+        assert!(!auth.authorizes(None, local));
+
+        let auth = AdminAuth::new(None);
+        assert!(auth.authorizes(None, local));
+        assert!(!auth.authorizes(Some("anything"), remote));
+        assert!(!auth.authorizes(None, None));
+    }
+}
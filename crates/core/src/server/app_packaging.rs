@@ -9,6 +9,10 @@ use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use tar::{Archive, Builder};
 use xz2::read::{XzDecoder, XzEncoder};
 
+/// Name of the file, at the root of an unpacked web app, listing the assets of the bundle
+/// which were corrupt and could not be unpacked.
+pub const UNAVAILABLE_ASSETS: &str = "freenet-unavailable-assets.json";
+
 #[derive(Debug, thiserror::Error)]
 pub enum WebContractError {
     #[error("unpacking error: {0}")]
@@ -68,14 +72,57 @@ impl WebApp {
         Ok(output)
     }
 
+    /// Unpacks every asset of the bundle which can be, returning the paths of those which
+    /// are corrupt, also listed in [`UNAVAILABLE_ASSETS`] at `dst`. Fails only when none can.
     #[instrument(level = "debug", skip(self, dst))]
-    pub fn unpack(&mut self, dst: impl AsRef<Path>) -> Result<(), WebContractError> {
-        debug!("Unpacking web content to {:?}", dst.as_ref());
+    pub fn unpack(&mut self, dst: impl AsRef<Path>) -> Result<Vec<String>, WebContractError> {
+        let dst = dst.as_ref();
+        debug!("Unpacking web content to {:?}", dst);
+        std::fs::create_dir_all(dst).map_err(WebContractError::StoringError)?;
         let mut decoded_web = self.decode_web();
-        decoded_web
-            .unpack(dst)
-            .map_err(WebContractError::StoringError)?;
-        Ok(())
+        let entries = decoded_web
+            .entries()
+            .map_err(|e| WebContractError::UnpackingError(anyhow::anyhow!(e)))?;
+        let mut unpacked = 0;
+        let mut unavailable = vec![];
+        for entry in entries {
+            let mut entry = match entry {
+                Ok(entry) => entry,
+                Err(e) if unpacked == 0 && unavailable.is_empty() => {
+                    return Err(WebContractError::UnpackingError(anyhow::anyhow!(e)));
+                }
+                Err(e) => {
+                    // nothing past a corrupt header can be told apart
+                    tracing::warn!("Web content unreadable past the unpacked assets: {e}");
+                    break;
+                }
+            };
+            let path = entry
+                .path()
+                .map(|p| p.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            match entry.unpack_in(dst) {
+                Ok(_) => unpacked += 1,
+                Err(e) => {
+                    tracing::warn!(path, "Corrupt asset in web content: {e}");
+                    // no partially written asset is left to be served
+                    let _ = std::fs::remove_file(dst.join(&path));
+                    unavailable.push(path);
+                }
+            }
+        }
+        if unpacked == 0 && !unavailable.is_empty() {
+            return Err(WebContractError::UnpackingError(anyhow::anyhow!(
+                "every asset of the web content is corrupt"
+            )));
+        }
+        if !unavailable.is_empty() {
+            let listed = serde_json::to_vec(&unavailable)
+                .map_err(|e| WebContractError::UnpackingError(e.into()))?;
+            std::fs::write(dst.join(UNAVAILABLE_ASSETS), listed)
+                .map_err(WebContractError::StoringError)?;
+        }
+        Ok(unavailable)
     }
 
     #[instrument(level = "debug", skip(self))]
@@ -0,0 +1,256 @@
+//! Resolution of web app assets kept outside of the contract state.
+//!
+//! Large apps can leave assets out of the `WebApp` bundle and instead list them in a manifest
+//! (see [`ASSET_MANIFEST`]) which maps each relative path to the blake3 hash of its content.
+//! Those assets are fetched on demand from an external content-addressed store, verified
+//! against their hash and cached locally so subsequent requests are served from disk.
+//!
+//! A fetch is given [`FETCH_TIMEOUT`] to complete and blobs over [`MAX_ASSET_BYTES`] are
+//! rejected, so a slow or misbehaving store can't hold up the gateway or fill the cache.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+
+/// Name of the manifest file, at the root of the web app, listing externally stored assets.
+pub(crate) const ASSET_MANIFEST: &str = "freenet-assets.json";
+
+/// Time allowed to fetch an asset from the store.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Size of the largest asset fetched from the store.
+const MAX_ASSET_BYTES: u64 = 32 * 1024 * 1024;
+
+enum Source {
+    Http {
+        client: reqwest::Client,
+        base: String,
+    },
+    Directory(PathBuf),
+}
+
+pub(crate) struct ExternalAssetStore {
+    source: Source,
+    cache_dir: PathBuf,
+    timeout: Duration,
+    max_bytes: u64,
+}
+
+impl ExternalAssetStore {
+    pub fn new(location: &str) -> Self {
+        Self::with_cache_dir(
+            location,
+            std::env::temp_dir().join("freenet").join("asset_store"),
+        )
+    }
+
+    pub(crate) fn with_cache_dir(location: &str, cache_dir: PathBuf) -> Self {
+        let source = if location.starts_with("http://") || location.starts_with("https://") {
+            Source::Http {
+                client: reqwest::Client::new(),
+                base: location.trim_end_matches('/').to_owned(),
+            }
+        } else {
+            Source::Directory(PathBuf::from(location))
+        };
+        Self {
+            source,
+            cache_dir,
+            timeout: FETCH_TIMEOUT,
+            max_bytes: MAX_ASSET_BYTES,
+        }
+    }
+
+    /// Returns the local path of an asset listed in the manifest of the web app unpacked at
+    /// `web_root`, fetching it from the store if not cached yet.
+    ///
+    /// Returns `None` if the asset is not listed or the store doesn't have it.
+    pub async fn resolve(
+        &self,
+        web_root: &Path,
+        relative_path: &str,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        let manifest = match tokio::fs::read(web_root.join(ASSET_MANIFEST)).await {
+            Ok(manifest) => manifest,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let manifest: HashMap<String, String> =
+            serde_json::from_slice(&manifest).context("malformed asset manifest")?;
+        let Some(hash) = manifest.get(relative_path.trim_start_matches('/')) else {
+            return Ok(None);
+        };
+        let hash = blake3::Hash::from_hex(hash).context("malformed asset hash")?;
+
+        // keep the extension so the content type can be inferred when serving the file
+        let mut cached = self.cache_dir.join(hash.to_hex().as_str());
+        if let Some(ext) = Path::new(relative_path).extension() {
+            cached.set_extension(ext);
+        }
+        if tokio::fs::try_exists(&cached).await? {
+            return Ok(Some(cached));
+        }
+
+        let blob = tokio::time::timeout(self.timeout, self.fetch(&hash))
+            .await
+            .with_context(|| format!("fetching asset `{relative_path}` timed out"))??;
+        let Some(blob) = blob else {
+            tracing::debug!(%hash, path = relative_path, "asset missing from external store");
+            return Ok(None);
+        };
+        if blake3::hash(&blob) != hash {
+            anyhow::bail!("content of asset `{relative_path}` doesn't match its hash");
+        }
+        tokio::fs::create_dir_all(&self.cache_dir).await?;
+        let partial = cached.with_extension("partial");
+        tokio::fs::write(&partial, blob).await?;
+        tokio::fs::rename(&partial, &cached).await?;
+        Ok(Some(cached))
+    }
+
+    async fn fetch(&self, hash: &blake3::Hash) -> anyhow::Result<Option<Vec<u8>>> {
+        let hash = hash.to_hex();
+        match &self.source {
+            Source::Http { client, base } => {
+                let mut response = client.get(format!("{base}/{hash}")).send().await?;
+                if response.status() == reqwest::StatusCode::NOT_FOUND {
+                    return Ok(None);
+                }
+                response = response.error_for_status()?;
+                self.check_size(response.content_length().unwrap_or(0))?;
+                let mut blob = Vec::new();
+                while let Some(chunk) = response.chunk().await? {
+                    blob.extend_from_slice(&chunk);
+                    self.check_size(blob.len() as u64)?;
+                }
+                Ok(Some(blob))
+            }
+            Source::Directory(dir) => {
+                let path = dir.join(hash.as_str());
+                match tokio::fs::metadata(&path).await {
+                    Ok(metadata) => self.check_size(metadata.len())?,
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                    Err(err) => return Err(err.into()),
+                }
+                Ok(Some(tokio::fs::read(path).await?))
+            }
+        }
+    }
+
+    fn check_size(&self, len: u64) -> anyhow::Result<()> {
+        if len > self.max_bytes {
+            anyhow::bail!(
+                "asset of {len} bytes is over the maximum of {} bytes",
+                self.max_bytes
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn serve_asset_from_external_store() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let (web_root, store_dir) = (tmp.path().join("web"), tmp.path().join("store"));
+        std::fs::create_dir_all(&web_root)?;
+        std::fs::create_dir_all(&store_dir)?;
+
+        let script = b"console.log('hello')";
+        let hash = blake3::hash(script);
+        let missing = blake3::hash(b"not in the store");
+        std::fs::write(store_dir.join(hash.to_hex().as_str()), script)?;
+        std::fs::write(
+            web_root.join(ASSET_MANIFEST),
+            serde_json::to_vec(&HashMap::from([
+                ("js/app.js", hash.to_hex().to_string()),
+                ("js/missing.js", missing.to_hex().to_string()),
+            ]))?,
+        )?;
+
+        let store = ExternalAssetStore::with_cache_dir(
+            store_dir.to_str().unwrap(),
+            tmp.path().join("cache"),
+        );
+        let cached = store.resolve(&web_root, "js/app.js").await?.unwrap();
+        assert_eq!(cached.extension().unwrap(), "js");
+        assert_eq!(std::fs::read(&cached)?, script);
+
+        // served from the cache once fetched
+        std::fs::remove_file(store_dir.join(hash.to_hex().as_str()))?;
+        assert_eq!(store.resolve(&web_root, "js/app.js").await?, Some(cached));
+
+        assert!(store.resolve(&web_root, "js/missing.js").await?.is_none());
+        assert!(store.resolve(&web_root, "js/unlisted.js").await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_blob_not_matching_hash() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let hash = blake3::hash(b"expected");
+        std::fs::write(tmp.path().join(hash.to_hex().as_str()), b"tampered")?;
+        std::fs::write(
+            tmp.path().join(ASSET_MANIFEST),
+            serde_json::to_vec(&HashMap::from([("index.css", hash.to_hex().to_string())]))?,
+        )?;
+
+        let store =
+            ExternalAssetStore::with_cache_dir(tmp.path().to_str().unwrap(), tmp.path().join("c"));
+        assert!(store.resolve(tmp.path(), "index.css").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_blob_over_maximum_size() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let blob = [b'x'; 64];
+        let hash = blake3::hash(&blob);
+        std::fs::write(tmp.path().join(hash.to_hex().as_str()), blob)?;
+        std::fs::write(
+            tmp.path().join(ASSET_MANIFEST),
+            serde_json::to_vec(&HashMap::from([("big.js", hash.to_hex().to_string())]))?,
+        )?;
+
+        let mut store =
+            ExternalAssetStore::with_cache_dir(tmp.path().to_str().unwrap(), tmp.path().join("c"));
+        store.max_bytes = 63;
+        assert!(store.resolve(tmp.path(), "big.js").await.is_err());
+        store.max_bytes = 64;
+        assert!(store.resolve(tmp.path(), "big.js").await?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_times_out() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let hash = blake3::hash(b"never sent");
+        std::fs::write(
+            tmp.path().join(ASSET_MANIFEST),
+            serde_json::to_vec(&HashMap::from([("app.js", hash.to_hex().to_string())]))?,
+        )?;
+        // accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((connection, _)) = listener.accept().await {
+                connections.push(connection);
+            }
+        });
+
+        let mut store =
+            ExternalAssetStore::with_cache_dir(&format!("http://{addr}"), tmp.path().join("cache"));
+        store.timeout = Duration::from_millis(100);
+        let err = store.resolve(tmp.path(), "app.js").await.unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{err}");
+        Ok(())
+    }
+}
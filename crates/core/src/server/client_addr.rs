@@ -0,0 +1,196 @@
+//! The address of the client behind each request.
+//!
+//! Every connection accepted by the gateway carries the address of its peer. When the gateway
+//! sits behind reverse proxies, that peer is the last proxy and the client is found in the
+//! `Forwarded` or `X-Forwarded-For` headers, which are only looked at if the peer is one of the
+//! configured trusted proxies; anyone else could claim any address in them. The address list is
+//! walked from the most recent hop backwards while the hops are trusted proxies, the first one
+//! which isn't is the client.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header::FORWARDED, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Address of the peer of the connection a request came through.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PeerAddr(pub SocketAddr);
+
+/// Address of the client which made a request, for per client limits and logging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ClientAddr(pub IpAddr);
+
+#[derive(Debug, Default)]
+pub(crate) struct TrustedProxies(Vec<IpAddr>);
+
+impl TrustedProxies {
+    pub fn new(proxies: Vec<IpAddr>) -> Self {
+        Self(proxies)
+    }
+
+    fn trusts(&self, addr: &IpAddr) -> bool {
+        self.0.contains(&canonical(*addr))
+    }
+
+    pub fn client_addr(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = canonical(peer);
+        if !self.trusts(&client) {
+            return client;
+        }
+        let hops = if headers.contains_key(FORWARDED) {
+            forwarded(headers)
+        } else {
+            x_forwarded_for(headers)
+        };
+        for hop in hops.into_iter().rev() {
+            match hop {
+                Some(hop) => {
+                    client = canonical(hop);
+                    if !self.trusts(&client) {
+                        break;
+                    }
+                }
+                // obfuscated or malformed, the last trusted proxy is as far as it is known
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+/// Resolves the [`ClientAddr`] of requests coming with a [`PeerAddr`].
+pub(crate) async fn resolve(
+    State(proxies): State<Arc<TrustedProxies>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if let Some(PeerAddr(peer)) = req.extensions().get::<PeerAddr>().copied() {
+        let client = proxies.client_addr(peer.ip(), req.headers());
+        if client != canonical(peer.ip()) {
+            tracing::trace!(%peer, %client, "request forwarded by trusted proxy");
+        }
+        req.extensions_mut().insert(ClientAddr(client));
+    }
+    next.run(req).await
+}
+
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(v6)),
+        v4 => v4,
+    }
+}
+
+/// The hops of the `Forwarded` headers, in the order they were added.
+fn forwarded(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    values(headers, FORWARDED.as_str())
+        .map(|element| {
+            let node = element.split(';').find_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| value.trim().trim_matches('"'))
+            })?;
+            parse_node(node)
+        })
+        .collect()
+}
+
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    values(headers, X_FORWARDED_FOR).map(parse_node).collect()
+}
+
+fn values<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+}
+
+/// An address, optionally with a port: `192.0.2.1`, `192.0.2.1:80`, `[2001:db8::1]:80`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(addr) = node.parse() {
+        return Some(addr);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')?.strip_suffix(']')?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    fn headers(name: &str, values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(
+                axum::http::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn client_behind_trusted_proxy() {
+        let proxies = TrustedProxies::new(vec![ip("10.0.0.1"), ip("10.0.0.2")]);
+        let proxy = ip("10.0.0.1");
+
+        let forwarded_for = headers(X_FORWARDED_FOR, &["203.0.113.7, 10.0.0.2"]);
+        assert_eq!(
+            proxies.client_addr(proxy, &forwarded_for),
+            ip("203.0.113.7")
+        );
+        // the client can't spoof its address adding hops of its own
+        let spoofed = headers(X_FORWARDED_FOR, &["198.51.100.1", "203.0.113.7:4711"]);
+        assert_eq!(proxies.client_addr(proxy, &spoofed), ip("203.0.113.7"));
+
+        let forwarded = headers(
+            "forwarded",
+            &[r#"for="[2001:db8:cafe::17]:4711";proto=https, For=10.0.0.2;by=10.0.0.1"#],
+        );
+        assert_eq!(
+            proxies.client_addr(proxy, &forwarded),
+            ip("2001:db8:cafe::17")
+        );
+        let obfuscated = headers("forwarded", &["for=_hidden, for=10.0.0.2"]);
+        assert_eq!(proxies.client_addr(proxy, &obfuscated), ip("10.0.0.2"));
+
+        // the peer is the client when it isn't a trusted proxy
+        let untrusted = ip("192.0.2.9");
+        assert_eq!(proxies.client_addr(untrusted, &forwarded_for), untrusted);
+        let mapped = IpAddr::V6(Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped());
+        assert_eq!(
+            proxies.client_addr(mapped, &forwarded_for),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            TrustedProxies::default().client_addr(proxy, &forwarded_for),
+            proxy
+        );
+    }
+}
@@ -5,7 +5,7 @@ use freenet_stdlib::prelude::ContractKey;
 use std::fmt::{Display, Formatter};
 
 #[derive(Debug)]
-pub(super) enum WebSocketApiError {
+pub(crate) enum WebSocketApiError {
     /// Something went wrong when calling the user repo.
     InvalidParam {
         error_cause: String,
@@ -19,6 +19,28 @@ pub(super) enum WebSocketApiError {
     MissingContract {
         key: ContractKey,
     },
+    MissingAsset {
+        path: String,
+    },
+    UnavailableAsset {
+        path: String,
+    },
+    MissingUpload {
+        id: String,
+    },
+    MissingScheduled {
+        id: String,
+    },
+    VersionNotRetained {
+        key: ContractKey,
+        version: String,
+    },
+    Unauthorized {
+        error_cause: String,
+    },
+    TooLarge {
+        error_cause: String,
+    },
 }
 
 impl WebSocketApiError {
@@ -28,6 +50,13 @@ impl WebSocketApiError {
             WebSocketApiError::NodeError { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             WebSocketApiError::AxumError { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             WebSocketApiError::MissingContract { .. } => StatusCode::NOT_FOUND,
+            WebSocketApiError::MissingAsset { .. } => StatusCode::NOT_FOUND,
+            WebSocketApiError::UnavailableAsset { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            WebSocketApiError::MissingUpload { .. } => StatusCode::NOT_FOUND,
+            WebSocketApiError::MissingScheduled { .. } => StatusCode::NOT_FOUND,
+            WebSocketApiError::VersionNotRetained { .. } => StatusCode::GONE,
+            WebSocketApiError::Unauthorized { .. } => StatusCode::FORBIDDEN,
+            WebSocketApiError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
         }
     }
 
@@ -39,6 +68,23 @@ impl WebSocketApiError {
             WebSocketApiError::NodeError { error_cause } => format!("Node error: {}", error_cause),
             WebSocketApiError::AxumError { error } => format!("Server error: {}", error),
             WebSocketApiError::MissingContract { key } => format!("Missing contract {key}"),
+            WebSocketApiError::MissingAsset { path } => format!("Missing asset {path}"),
+            WebSocketApiError::UnavailableAsset { path } => {
+                format!("Asset {path} is corrupt in the web app")
+            }
+            WebSocketApiError::MissingUpload { id } => format!("Missing upload {id}"),
+            WebSocketApiError::MissingScheduled { id } => {
+                format!("Missing scheduled operation {id}")
+            }
+            WebSocketApiError::VersionNotRetained { key, version } => {
+                format!("Version {version} of contract {key} is no longer retained")
+            }
+            WebSocketApiError::Unauthorized { error_cause } => {
+                format!("Unauthorized: {error_cause}")
+            }
+            WebSocketApiError::TooLarge { error_cause } => {
+                format!("Payload too large: {error_cause}")
+            }
         }
     }
 }
@@ -70,9 +116,22 @@ impl IntoResponse for WebSocketApiError {
             WebSocketApiError::NodeError { error_cause } => {
                 (StatusCode::INTERNAL_SERVER_ERROR, error_cause)
             }
-            err @ WebSocketApiError::MissingContract { .. } => {
+            err @ (WebSocketApiError::MissingContract { .. }
+            | WebSocketApiError::MissingAsset { .. }
+            | WebSocketApiError::MissingUpload { .. }
+            | WebSocketApiError::MissingScheduled { .. }) => {
                 (StatusCode::NOT_FOUND, err.error_message())
             }
+            err @ WebSocketApiError::VersionNotRetained { .. } => {
+                (StatusCode::GONE, err.error_message())
+            }
+            err @ WebSocketApiError::UnavailableAsset { .. } => {
+                (StatusCode::INTERNAL_SERVER_ERROR, err.error_message())
+            }
+            WebSocketApiError::Unauthorized { error_cause } => (StatusCode::FORBIDDEN, error_cause),
+            WebSocketApiError::TooLarge { error_cause } => {
+                (StatusCode::PAYLOAD_TOO_LARGE, error_cause)
+            }
             WebSocketApiError::AxumError { error } => {
                 (StatusCode::INTERNAL_SERVER_ERROR, format!("{error}"))
             }
@@ -0,0 +1,721 @@
+//! gRPC interface to the node for the contract operations, as described in `grpc/node.proto`.
+//!
+//! Each call is registered as a client of its own, disconnected once the call is answered or,
+//! for subscriptions, once the client hangs up. Calls are forwarded through the same queue the
+//! websocket proxy feeds the node from, so both share how requests are scheduled.
+//!
+//! A call is attested for the contract of the auth token it carries as `authorization: Bearer`
+//! metadata, as handed by the HTTP gateway to the web app of the contract; tokens the node
+//! doesn't know of or which expired are refused. With TLS configured for the gateway the
+//! interface is served over the same TLS, client certificates included: a call without a token
+//! is attested for the contract the identity of the certificate of its connection is
+//! configured with, if any.
+
+mod proto {
+    tonic::include_proto!("freenet.v1");
+}
+
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use freenet_stdlib::{
+    client_api::{
+        ClientError, ClientRequest, ContractRequest, ContractResponse, ErrorKind, HostResponse,
+    },
+    prelude::{
+        ContractCode, ContractContainer, ContractInstanceId, ContractKey, ContractWasmAPIVersion,
+        Parameters, RelatedContracts, State, StateDelta, UpdateData, WrappedContract, WrappedState,
+    },
+};
+use futures::{stream::BoxStream, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot, watch},
+};
+use tokio_rustls::server::TlsStream;
+use tonic::{transport::server::Connected, Request, Response, Status};
+
+use super::{
+    http_gateway::AttestedContractMap,
+    tls::{ClientIdentity, GatewayTls},
+    token_expiry::TokenExpiryCheck,
+    work_queue::WorkQueueSender,
+    ClientConnection, HostCallbackResult, ServerHandle, ServerState, ServerStateWatch,
+};
+use crate::{
+    client_events::{AuthToken, ClientId, HostResult},
+    config::WebsocketApiConfig,
+};
+
+use proto::{
+    node_server::{Node, NodeServer},
+    update_request, Contract, GetRequest, GetResponse, PutRequest, PutResponse, SubscribeRequest,
+    UpdateNotification, UpdateRequest, UpdateResponse,
+};
+
+/// Serves the gRPC interface on `socket`, with the TLS and token expiry of the gateway.
+pub(crate) fn serve(
+    socket: SocketAddr,
+    config: &WebsocketApiConfig,
+    requests: WorkQueueSender,
+    attested_contracts: AttestedContractMap,
+) -> anyhow::Result<ServerHandle> {
+    let tls = config.tls.as_ref().map(GatewayTls::for_grpc).transpose()?;
+    let handshake_timeout = Duration::from_secs(config.http_keep_alive_timeout_secs);
+    let node = NodeService {
+        requests,
+        attested_contracts,
+        token_expiry: TokenExpiryCheck::new(config.token_expiry),
+    };
+    let (stop, stopped) = oneshot::channel::<()>();
+    let (state, state_rx) = watch::channel(ServerState::Serving);
+    tokio::spawn(async move {
+        let listener = match TcpListener::bind(socket).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                tracing::error!(%socket, "failed to bind the gRPC interface: {e}");
+                let _ = state.send(ServerState::Stopped);
+                return;
+            }
+        };
+        tracing::info!("gRPC interface listening on {}", socket);
+        let shutdown = async {
+            let _ = stopped.await;
+            let _ = state.send(ServerState::Draining);
+        };
+        serve_listener(listener, node, tls, handshake_timeout, shutdown).await;
+        let _ = state.send(ServerState::Stopped);
+    });
+    Ok(ServerHandle {
+        stop: Some(stop),
+        state: ServerStateWatch(state_rx),
+    })
+}
+
+async fn serve_listener(
+    listener: TcpListener,
+    node: NodeService,
+    tls: Option<GatewayTls>,
+    handshake_timeout: Duration,
+    shutdown: impl Future<Output = ()>,
+) {
+    let (connected, incoming) = mpsc::channel(16);
+    tokio::spawn(accept(listener, tls, handshake_timeout, connected));
+    let incoming = futures::stream::unfold(incoming, |mut incoming| async move {
+        let connection = incoming.recv().await?;
+        Some((Ok::<_, io::Error>(connection), incoming))
+    });
+    if let Err(e) = tonic::transport::Server::builder()
+        .add_service(NodeServer::new(node))
+        .serve_with_incoming_shutdown(incoming, shutdown)
+        .await
+    {
+        tracing::error!("gRPC interface stopped: {e}");
+    }
+}
+
+/// Accepts connections until the server stops taking them, each going through the TLS
+/// handshake on a task of its own, if any.
+async fn accept(
+    listener: TcpListener,
+    tls: Option<GatewayTls>,
+    handshake_timeout: Duration,
+    connected: mpsc::Sender<Connection>,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = connected.closed() => return,
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!("Error while accepting gRPC connection: {e}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let Some(tls) = tls.clone() else {
+            if connected.send(Connection::Plain(stream)).await.is_err() {
+                return;
+            }
+            continue;
+        };
+        let connected = connected.clone();
+        tokio::spawn(async move {
+            match tls.accept(stream, handshake_timeout).await {
+                Ok((stream, identity)) => {
+                    let _ = connected
+                        .send(Connection::Tls(Box::new(stream), identity))
+                        .await;
+                }
+                Err(e) => {
+                    tracing::debug!(%peer, reason = %e, "TLS handshake with gRPC client failed");
+                }
+            }
+        });
+    }
+}
+
+enum Connection {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>, Option<ClientIdentity>),
+}
+
+impl Connected for Connection {
+    /// The identity of the client certificate, handed to every call made through it.
+    type ConnectInfo = Option<ClientIdentity>;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        match self {
+            Connection::Plain(_) => None,
+            Connection::Tls(_, identity) => identity.clone(),
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Tls(stream, _) => Pin::new(&mut **stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Tls(stream, _) => Pin::new(&mut **stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Tls(stream, _) => Pin::new(&mut **stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Tls(stream, _) => Pin::new(&mut **stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct NodeService {
+    requests: WorkQueueSender,
+    attested_contracts: AttestedContractMap,
+    token_expiry: TokenExpiryCheck,
+}
+
+/// A call registered as a client of the node, disconnected once dropped.
+struct Client {
+    id: ClientId,
+    requests: WorkQueueSender,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let requests = self.requests.clone();
+        let client_id = self.id;
+        tokio::spawn(async move {
+            let _ = requests
+                .send(ClientConnection::Request {
+                    client_id,
+                    req: Box::new(ClientRequest::Disconnect { cause: None }),
+                    auth_token: None,
+                    attested_contract: None,
+                })
+                .await;
+        });
+    }
+}
+
+/// How a call is attested for a contract.
+enum Attested {
+    Token(AuthToken, ContractInstanceId),
+    Certificate(ContractInstanceId),
+}
+
+/// What the node answered to a call.
+struct Answer {
+    response: ContractResponse,
+    /// Notifications of the contract, for subscriptions.
+    notifications: Option<mpsc::UnboundedReceiver<HostResult>>,
+    client: Client,
+}
+
+#[tonic::async_trait]
+impl Node for NodeService {
+    async fn get(&self, req: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let auth = self.attested(&req)?;
+        let req = req.into_inner();
+        let request = ContractRequest::Get {
+            key: parse_key(&req.key)?,
+            return_contract_code: req.return_contract_code,
+            subscribe: false,
+        };
+        match self.call(request, auth).await?.response {
+            ContractResponse::GetResponse {
+                key,
+                contract,
+                state,
+            } => Ok(Response::new(GetResponse {
+                key: key.encoded_contract_id(),
+                state: state.as_ref().to_vec(),
+                contract: contract.map(|contract| Contract {
+                    code: contract.data().to_vec(),
+                    parameters: contract.params().into_bytes(),
+                }),
+            })),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    async fn put(&self, req: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
+        let auth = self.attested(&req)?;
+        let req = req.into_inner();
+        let Some(Contract { code, parameters }) = req.contract else {
+            return Err(Status::invalid_argument("missing contract"));
+        };
+        let contract = WrappedContract::new(
+            Arc::new(ContractCode::from(code)),
+            Parameters::from(parameters),
+        );
+        let request = ContractRequest::Put {
+            contract: ContractContainer::Wasm(ContractWasmAPIVersion::V1(contract)),
+            state: WrappedState::new(req.state),
+            related_contracts: RelatedContracts::default(),
+            subscribe: req.subscribe,
+        };
+        match self.call(request, auth).await?.response {
+            ContractResponse::PutResponse { key } => Ok(Response::new(PutResponse {
+                key: key.encoded_contract_id(),
+            })),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    async fn update(
+        &self,
+        req: Request<UpdateRequest>,
+    ) -> Result<Response<UpdateResponse>, Status> {
+        let auth = self.attested(&req)?;
+        let req = req.into_inner();
+        let data = match req.data {
+            Some(update_request::Data::State(state)) => UpdateData::State(State::from(state)),
+            Some(update_request::Data::Delta(delta)) => UpdateData::Delta(StateDelta::from(delta)),
+            None => return Err(Status::invalid_argument("missing state or delta")),
+        };
+        let request = ContractRequest::Update {
+            key: parse_key(&req.key)?,
+            data,
+        };
+        match self.call(request, auth).await?.response {
+            ContractResponse::UpdateResponse { key, summary } => {
+                Ok(Response::new(UpdateResponse {
+                    key: key.encoded_contract_id(),
+                    summary: summary.into_bytes(),
+                }))
+            }
+            other => Err(unexpected(other)),
+        }
+    }
+
+    type SubscribeStream = BoxStream<'static, Result<UpdateNotification, Status>>;
+
+    async fn subscribe(
+        &self,
+        req: Request<SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let auth = self.attested(&req)?;
+        let request = ContractRequest::Subscribe {
+            key: parse_key(&req.get_ref().key)?,
+            summary: None,
+        };
+        let answer = self.call(request, auth).await?;
+        match answer.response {
+            ContractResponse::SubscribeResponse {
+                subscribed: true, ..
+            } => {}
+            ContractResponse::SubscribeResponse { key, .. } => {
+                return Err(Status::unavailable(format!("could not subscribe to {key}")))
+            }
+            other => return Err(unexpected(other)),
+        }
+        let Some(notifications) = answer.notifications else {
+            return Err(Status::internal("no notifications for subscription"));
+        };
+        // the client stays connected for as long as the stream is read
+        let notifications = futures::stream::unfold(
+            (notifications, answer.client),
+            |(mut rx, client)| async move {
+                let notification = rx.recv().await?;
+                Some((notification, (rx, client)))
+            },
+        );
+        let notifications = notifications.filter_map(|notification| async move {
+            match notification {
+                Ok(HostResponse::ContractResponse(ContractResponse::UpdateNotification {
+                    key,
+                    update,
+                })) => update_notification(key, update).map(Ok),
+                Ok(_) => None,
+                Err(err) => Some(Err(status(err))),
+            }
+        });
+        Ok(Response::new(notifications.boxed()))
+    }
+}
+
+impl NodeService {
+    /// The contract the call is attested for by the auth token in its metadata, or else by the
+    /// identity of its client certificate, if any.
+    // `Status` is the error type of every gRPC method
+    #[allow(clippy::result_large_err)]
+    fn attested<T>(&self, req: &Request<T>) -> Result<Option<Attested>, Status> {
+        let Some(value) = req.metadata().get("authorization") else {
+            let contract = req
+                .extensions()
+                .get::<Option<ClientIdentity>>()
+                .and_then(|identity| identity.as_ref()?.contract);
+            return Ok(contract.map(Attested::Certificate));
+        };
+        let token = value
+            .to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("malformed authorization"))?;
+        let token = AuthToken::from(token.to_owned());
+        let attested = self
+            .attested_contracts
+            .read()
+            .unwrap()
+            .get(&token)
+            .filter(|(_, _, issued)| self.token_expiry.accepts(*issued))
+            .map(|(contract, _, _)| *contract);
+        match attested {
+            Some(contract) => Ok(Some(Attested::Token(token, contract))),
+            None => Err(Status::unauthenticated("unknown or expired auth token")),
+        }
+    }
+
+    /// Sends the request to the node as a new client and waits for the response.
+    async fn call(
+        &self,
+        request: ContractRequest<'static>,
+        attested: Option<Attested>,
+    ) -> Result<Answer, Status> {
+        // the token issued for the certificate is dropped along with the client of the call
+        let (auth, assigned_token) = match attested {
+            Some(Attested::Token(token, contract)) => (Some((token, contract)), None),
+            Some(Attested::Certificate(contract)) => {
+                let assigned = (AuthToken::generate(), contract);
+                (Some(assigned.clone()), Some(assigned))
+            }
+            None => (None, None),
+        };
+        let (callbacks, mut responses) = mpsc::unbounded_channel();
+        self.requests
+            .send(ClientConnection::NewConnection {
+                callbacks,
+                assigned_token,
+            })
+            .await
+            .map_err(|_| status(ErrorKind::NodeUnavailable.into()))?;
+        let client = match responses.recv().await {
+            Some(HostCallbackResult::NewId { id }) => Client {
+                id,
+                requests: self.requests.clone(),
+            },
+            _ => return Err(status(ErrorKind::NodeUnavailable.into())),
+        };
+        let (auth_token, attested_contract) = auth.unzip();
+        self.requests
+            .send(ClientConnection::Request {
+                client_id: client.id,
+                req: Box::new(ClientRequest::ContractOp(request)),
+                auth_token,
+                attested_contract,
+            })
+            .await
+            .map_err(|_| status(ErrorKind::NodeUnavailable.into()))?;
+
+        let mut notifications = None;
+        loop {
+            match responses.recv().await {
+                Some(HostCallbackResult::Result { result, .. }) => {
+                    return match result.map_err(status)? {
+                        HostResponse::ContractResponse(response) => Ok(Answer {
+                            response,
+                            notifications,
+                            client,
+                        }),
+                        other => Err(Status::internal(format!("unexpected response: {other}"))),
+                    };
+                }
+                Some(HostCallbackResult::SubscriptionChannel { callback, .. }) => {
+                    notifications = Some(callback);
+                }
+                Some(HostCallbackResult::NewId { .. }) => {}
+                None => return Err(status(ErrorKind::NodeUnavailable.into())),
+            }
+        }
+    }
+}
+
+// `Status` is the error type of every gRPC method
+#[allow(clippy::result_large_err)]
+fn parse_key(key: &str) -> Result<ContractKey, Status> {
+    ContractKey::from_id(key)
+        .map_err(|err| Status::invalid_argument(format!("invalid contract key: {err}")))
+}
+
+fn update_notification(
+    key: ContractKey,
+    update: UpdateData<'static>,
+) -> Option<UpdateNotification> {
+    let (state, delta) = match update {
+        UpdateData::State(state) => (state.into_bytes(), vec![]),
+        UpdateData::Delta(delta) => (vec![], delta.into_bytes()),
+        UpdateData::StateAndDelta { state, delta } => (state.into_bytes(), delta.into_bytes()),
+        // updates of related contracts have no place in the notifications of this one
+        _ => return None,
+    };
+    Some(UpdateNotification {
+        key: key.encoded_contract_id(),
+        state,
+        delta,
+    })
+}
+
+fn unexpected(response: ContractResponse) -> Status {
+    tracing::error!(?response, "unexpected response to gRPC call");
+    Status::internal("unexpected response from the node")
+}
+
+fn status(err: ClientError) -> Status {
+    match err.kind() {
+        ErrorKind::RequestError(_) => Status::failed_precondition(err.to_string()),
+        ErrorKind::NodeUnavailable
+        | ErrorKind::ChannelClosed
+        | ErrorKind::Shutdown
+        | ErrorKind::TransportProtocolDisconnect => Status::unavailable(err.to_string()),
+        ErrorKind::FailedOperation => Status::deadline_exceeded(err.to_string()),
+        _ => Status::internal(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Instant};
+
+    use tonic::transport::Channel;
+
+    use super::*;
+    use crate::server::work_queue::{self, WorkQueueMetrics, WorkQueueReceiver};
+
+    use proto::node_client::NodeClient;
+
+    const STATE: &[u8] = b"state";
+    const DELTAS: u8 = 3;
+
+    /// Answers the requests the way the node does, a subscription gets `DELTAS` updates, and
+    /// tells of the clients disconnecting.
+    async fn node(mut requests: WorkQueueReceiver, disconnected: mpsc::UnboundedSender<ClientId>) {
+        let mut clients = HashMap::new();
+        while let Some(msg) = requests.recv().await {
+            match msg {
+                ClientConnection::NewConnection { callbacks, .. } => {
+                    let id = ClientId::next();
+                    callbacks.send(HostCallbackResult::NewId { id }).unwrap();
+                    clients.insert(id, callbacks);
+                }
+                ClientConnection::Unsubscribed { .. } => {}
+                ClientConnection::Request { client_id, req, .. } => {
+                    let response = match *req {
+                        ClientRequest::Disconnect { .. } => {
+                            clients.remove(&client_id).unwrap();
+                            disconnected.send(client_id).unwrap();
+                            continue;
+                        }
+                        ClientRequest::ContractOp(ContractRequest::Get { key, .. }) => {
+                            ContractResponse::GetResponse {
+                                key,
+                                contract: None,
+                                state: WrappedState::new(STATE.to_vec()),
+                            }
+                        }
+                        ClientRequest::ContractOp(ContractRequest::Subscribe { key, .. }) => {
+                            let (updates, callback) = mpsc::unbounded_channel();
+                            clients[&client_id]
+                                .send(HostCallbackResult::SubscriptionChannel {
+                                    id: client_id,
+                                    key,
+                                    callback,
+                                })
+                                .unwrap();
+                            for i in 0..DELTAS {
+                                let update = UpdateData::Delta(StateDelta::from(vec![i]));
+                                updates
+                                    .send(Ok(
+                                        ContractResponse::UpdateNotification { key, update }.into()
+                                    ))
+                                    .unwrap();
+                            }
+                            ContractResponse::SubscribeResponse {
+                                key,
+                                subscribed: true,
+                            }
+                        }
+                        other => panic!("unexpected request: {other}"),
+                    };
+                    clients[&client_id]
+                        .send(HostCallbackResult::Result {
+                            id: client_id,
+                            result: Ok(response.into()),
+                        })
+                        .unwrap();
+                }
+            }
+        }
+    }
+
+    struct Served {
+        client: NodeClient<Channel>,
+        attested_contracts: AttestedContractMap,
+        disconnected: mpsc::UnboundedReceiver<ClientId>,
+    }
+
+    async fn serve() -> Served {
+        let (requests, node_requests) =
+            work_queue::work_queue(8, Arc::new(WorkQueueMetrics::default()));
+        let (disconnected, disconnected_rx) = mpsc::unbounded_channel();
+        tokio::spawn(node(node_requests, disconnected));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let attested_contracts = AttestedContractMap::default();
+        let node = NodeService {
+            requests,
+            attested_contracts: attested_contracts.clone(),
+            token_expiry: TokenExpiryCheck::new(Default::default()),
+        };
+        tokio::spawn(serve_listener(
+            listener,
+            node,
+            None,
+            Duration::from_secs(5),
+            std::future::pending(),
+        ));
+
+        let client = NodeClient::connect(format!("http://{addr}")).await.unwrap();
+        Served {
+            client,
+            attested_contracts,
+            disconnected: disconnected_rx,
+        }
+    }
+
+    fn key() -> String {
+        ContractKey::from(ContractInstanceId::new([1; 32])).encoded_contract_id()
+    }
+
+    fn get() -> GetRequest {
+        GetRequest {
+            key: key(),
+            return_contract_code: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn get_state() {
+        let mut served = serve().await;
+        let response = served.client.get(get()).await.unwrap().into_inner();
+        assert_eq!(response.key, key());
+        assert_eq!(response.state, STATE);
+        assert_eq!(response.contract, None);
+        // the client of the call is gone along with it
+        served.disconnected.recv().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn subscribe_streams_updates() {
+        let mut served = serve().await;
+        let updates = served
+            .client
+            .subscribe(SubscribeRequest { key: key() })
+            .await
+            .unwrap()
+            .into_inner();
+        let deltas: Vec<_> = updates
+            .map(|update| {
+                let update = update.unwrap();
+                assert_eq!(update.key, key());
+                assert!(update.state.is_empty());
+                update.delta
+            })
+            .collect()
+            .await;
+        assert_eq!(deltas, (0..DELTAS).map(|i| vec![i]).collect::<Vec<_>>());
+        served.disconnected.recv().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn invalid_key_is_rejected() {
+        let mut served = serve().await;
+        let err = served
+            .client
+            .get(GetRequest {
+                key: "not a key".into(),
+                return_contract_code: false,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn only_attested_tokens_accepted() {
+        let mut served = serve().await;
+        let token = AuthToken::generate();
+        served.attested_contracts.write().unwrap().insert(
+            token.clone(),
+            (
+                ContractInstanceId::new([1; 32]),
+                ClientId::FIRST,
+                Instant::now(),
+            ),
+        );
+        let with_token = |token: &str| {
+            let mut req = Request::new(get());
+            let value = format!("Bearer {token}").parse().unwrap();
+            req.metadata_mut().insert("authorization", value);
+            req
+        };
+
+        served.client.get(with_token(token.as_str())).await.unwrap();
+        let err = served.client.get(with_token("unknown")).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unauthenticated);
+    }
+}
@@ -1,64 +1,279 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use axum::extract::Path;
+use axum::extract::{Path, Query};
 use axum::response::IntoResponse;
-use axum::routing::get;
-use axum::{Extension, Router};
-use freenet_stdlib::client_api::{ClientError, ErrorKind, HostResponse};
-use freenet_stdlib::prelude::ContractInstanceId;
+use axum::routing::{delete, get, post};
+use axum::{Extension, Json, Router};
+use freenet_stdlib::client_api::{
+    ClientError, ClientRequest, ContractRequest, ErrorKind, HostResponse,
+};
+use freenet_stdlib::prelude::{
+    ContractContainer, ContractInstanceId, ContractKey, State, StateDelta, UpdateData,
+};
 use futures::future::BoxFuture;
 use futures::FutureExt;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tracing::instrument;
 
 use crate::client_events::{ClientEventsProxy, ClientId, OpenRequest};
-use crate::server::HostCallbackResult;
+use crate::config::{ServiceTier, WebsocketApiConfig};
+use crate::contract::{
+    backup::ExportedContract, collection::RangeFrame, ContractMetadata, CostEstimate,
+    ExecutorError, Revalidation, StateDiff, TransactionOutcome,
+};
+use crate::server::asset_store::ExternalAssetStore;
+use crate::server::token_expiry::TokenExpiryCheck;
+use crate::server::work_queue::{self, WorkQueueMetrics, WorkQueueReceiver, WorkQueueSender};
+use crate::server::{HostCallbackResult, IdentityTransformer, ResponseTransformer};
+use crate::wasm_runtime::{HistoricalState, StateVersion};
 
 use super::{errors::WebSocketApiError, path_handlers, AuthToken, ClientConnection};
 
+mod backup;
+mod schedule;
+mod upload;
 mod v1;
 
 #[derive(Clone)]
-pub(super) struct HttpGatewayRequest(mpsc::Sender<ClientConnection>);
+pub(super) struct HttpGatewayRequest(WorkQueueSender);
 
 impl std::ops::Deref for HttpGatewayRequest {
-    type Target = mpsc::Sender<ClientConnection>;
+    type Target = WorkQueueSender;
 
     fn deref(&self) -> &Self::Target {
         &self.0
     }
 }
 
-pub type AttestedContractMap = Arc<RwLock<HashMap<AuthToken, (ContractInstanceId, ClientId)>>>;
+/// How long a route waits for the node to serve an executor command before giving up.
+const EXECUTOR_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Commands from routes which are answered by the node's executor directly, instead of
+/// going through a client connection.
+pub(crate) enum ExecutorCommand {
+    Revalidate {
+        key: ContractKey,
+        respond: oneshot::Sender<Result<Revalidation, ExecutorError>>,
+    },
+    Metadata {
+        key: ContractKey,
+        respond: oneshot::Sender<Result<ContractMetadata, ExecutorError>>,
+    },
+    Topics {
+        key: ContractKey,
+        respond: oneshot::Sender<Result<Vec<String>, ExecutorError>>,
+    },
+    Dependencies {
+        key: ContractKey,
+        respond: oneshot::Sender<Result<Vec<ContractKey>, ExecutorError>>,
+    },
+    Range {
+        key: ContractKey,
+        offset: usize,
+        limit: usize,
+        respond: oneshot::Sender<Result<mpsc::Receiver<RangeFrame>, ExecutorError>>,
+    },
+    Estimate {
+        key: ContractKey,
+        update: UpdateData<'static>,
+        respond: oneshot::Sender<Result<CostEstimate, ExecutorError>>,
+    },
+    History {
+        key: ContractKey,
+        version: StateVersion,
+        respond: oneshot::Sender<Result<Option<HistoricalState>, ExecutorError>>,
+    },
+    Diff {
+        key: ContractKey,
+        base: StateVersion,
+        target: Option<StateVersion>,
+        respond: oneshot::Sender<Result<Option<StateDiff>, ExecutorError>>,
+    },
+    /// Served as its updates would be one by one, as far as authorizing them, under the
+    /// token the client sent if any.
+    Transaction {
+        updates: Vec<(ContractKey, UpdateData<'static>)>,
+        auth_token: Option<AuthToken>,
+        respond: oneshot::Sender<Result<TransactionOutcome, ExecutorError>>,
+    },
+    Code {
+        key: ContractKey,
+        respond: oneshot::Sender<Result<ContractContainer, ExecutorError>>,
+    },
+    /// The contracts to export: all those stored when `contracts` is empty, otherwise only
+    /// those listed.
+    ExportedContracts {
+        contracts: HashSet<ContractInstanceId>,
+        respond: oneshot::Sender<Result<Vec<ContractKey>, ExecutorError>>,
+    },
+    ExportContract {
+        key: ContractKey,
+        respond: oneshot::Sender<Result<Option<ExportedContract>, ExecutorError>>,
+    },
+    Import {
+        contract: Box<ExportedContract>,
+        respond: oneshot::Sender<Result<bool, ExecutorError>>,
+    },
+}
+
+#[derive(Clone)]
+pub(crate) struct ExecutorCommands(mpsc::Sender<ExecutorCommand>);
+
+impl ExecutorCommands {
+    /// Sends the command built with `command`, waits for the executor to answer it.
+    pub async fn request<T>(
+        &self,
+        key: ContractKey,
+        command: impl FnOnce(oneshot::Sender<Result<T, ExecutorError>>) -> ExecutorCommand,
+    ) -> Result<T, WebSocketApiError> {
+        self.send(command).await?.map_err(|err| {
+            if err.is_missing_contract() {
+                WebSocketApiError::MissingContract { key }
+            } else {
+                WebSocketApiError::NodeError {
+                    error_cause: err.to_string(),
+                }
+            }
+        })
+    }
+
+    /// As [`Self::request`], for commands not about a contract in particular.
+    async fn send<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<Result<T, ExecutorError>>) -> ExecutorCommand,
+    ) -> Result<Result<T, ExecutorError>, WebSocketApiError> {
+        let unavailable = || WebSocketApiError::NodeError {
+            error_cause: "executor not available".into(),
+        };
+        let (respond, response) = oneshot::channel();
+        tokio::time::timeout(EXECUTOR_COMMAND_TIMEOUT, async {
+            self.0
+                .send(command(respond))
+                .await
+                .map_err(|_| unavailable())?;
+            response.await.map_err(|_| unavailable())
+        })
+        .await
+        .map_err(|_| unavailable())?
+    }
+}
+
+/// Contracts attested by the tokens handed to their web apps, along with when the tokens
+/// were issued.
+pub type AttestedContractMap =
+    Arc<RwLock<HashMap<AuthToken, (ContractInstanceId, ClientId, Instant)>>>;
 
 /// A gateway to access and interact with contracts through an HTTP interface.
 pub(crate) struct HttpGateway {
     pub attested_contracts: AttestedContractMap,
-    proxy_server_request: mpsc::Receiver<ClientConnection>,
+    proxy_server_request: WorkQueueReceiver,
     response_channels: HashMap<ClientId, mpsc::UnboundedSender<HostCallbackResult>>,
+    response_transformer: Arc<dyn ResponseTransformer>,
+    executor_commands: mpsc::Receiver<ExecutorCommand>,
+    commands: ExecutorCommands,
+    token_expiry: TokenExpiryCheck,
 }
 
 impl HttpGateway {
     /// Returns the uninitialized axum router to compose with other routing handling or websockets.
     pub fn as_router(socket: &SocketAddr) -> (Self, Router) {
         let attested_contracts = Arc::new(RwLock::new(HashMap::new()));
-        Self::as_router_with_attested_contracts(socket, attested_contracts)
+        Self::as_router_with_attested_contracts(
+            &WebsocketApiConfig::from(*socket),
+            attested_contracts,
+            Arc::new(WorkQueueMetrics::default()),
+        )
     }
 
     /// Returns the uninitialized axum router with a provided attested_contracts map.
     pub fn as_router_with_attested_contracts(
-        socket: &SocketAddr,
+        config: &WebsocketApiConfig,
         attested_contracts: AttestedContractMap,
+        work_queue: Arc<WorkQueueMetrics>,
     ) -> (Self, Router) {
-        Self::create_router_v1_with_attested_contracts(socket, attested_contracts)
+        Self::create_router_v1_with_attested_contracts(config, attested_contracts, work_queue)
+    }
+
+    /// The contract attested by the token, unless it is unknown or expired.
+    pub fn attested_contract(&self, token: &AuthToken) -> Option<ContractInstanceId> {
+        let attested = self.attested_contracts.read().ok()?;
+        let (contract, _, issued) = attested.get(token)?;
+        self.token_expiry.accepts(*issued).then_some(*contract)
+    }
+
+    pub fn with_response_transformer(mut self, transformer: Arc<dyn ResponseTransformer>) -> Self {
+        self.response_transformer = transformer;
+        self
+    }
+
+    /// Requests of both the gateway and the websocket proxy waiting for the node.
+    pub fn work_queue(&self) -> &WorkQueueMetrics {
+        self.proxy_server_request.metrics()
+    }
+
+    /// Issues commands for the executor alongside the routes of the gateway.
+    pub fn executor_commands(&self) -> ExecutorCommands {
+        self.commands.clone()
+    }
+
+    /// Hands over the commands for the executor issued through the routes, so they can be
+    /// served alongside the client requests received by the gateway.
+    pub fn take_executor_commands(&mut self) -> mpsc::Receiver<ExecutorCommand> {
+        // the sender of the replacement is dropped right away, so it is already closed
+        std::mem::replace(&mut self.executor_commands, mpsc::channel(1).1)
+    }
+}
+
+/// Executes the request on behalf of a client connected only for it.
+async fn execute(
+    request_sender: HttpGatewayRequest,
+    request: ClientRequest<'static>,
+) -> Result<HostResponse, WebSocketApiError> {
+    let unavailable = || WebSocketApiError::NodeError {
+        error_cause: "node not available".into(),
+    };
+    let (callbacks, mut responses) = mpsc::unbounded_channel();
+    request_sender
+        .send(ClientConnection::NewConnection {
+            callbacks,
+            assigned_token: None,
+        })
+        .await
+        .map_err(|_| unavailable())?;
+    let Some(HostCallbackResult::NewId { id: client_id }) = responses.recv().await else {
+        return Err(unavailable());
+    };
+    request_sender
+        .send(ClientConnection::Request {
+            client_id,
+            req: Box::new(request),
+            auth_token: None,
+            attested_contract: None,
+        })
+        .await
+        .map_err(|_| unavailable())?;
+    loop {
+        match responses.recv().await {
+            Some(HostCallbackResult::Result { result, .. }) => {
+                return result.map_err(|err| WebSocketApiError::NodeError {
+                    error_cause: err.to_string(),
+                });
+            }
+            Some(_) => {}
+            None => return Err(unavailable()),
+        }
     }
 }
 
 #[derive(Clone, Debug)]
 struct Config {
     localhost: bool,
+    token_ttl: Duration,
+    /// Service tier of the tokens issued to the web apps of the contracts, standard otherwise.
+    service_tiers: Arc<HashMap<ContractInstanceId, ServiceTier>>,
 }
 
 #[instrument(level = "debug")]
@@ -66,6 +281,504 @@ async fn home() -> axum::response::Response {
     axum::response::Response::default()
 }
 
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NotFound {
+    error: &'static str,
+    path: String,
+    request_id: String,
+}
+
+/// Answers the requests matching no route, as a page to the clients accepting HTML and as JSON
+/// to any other, along with an id to quote when reporting it, logged as well.
+async fn not_found(
+    uri: axum::http::Uri,
+    headers: axum::http::HeaderMap,
+) -> axum::response::Response {
+    let request_id = ulid::Ulid::new().to_string();
+    let path = uri.path().to_owned();
+    tracing::debug!(%request_id, %path, "no route for request");
+    let accepts_html = headers
+        .get(axum::http::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+    let status = axum::http::StatusCode::NOT_FOUND;
+    if accepts_html {
+        let page = format!(
+            "<!DOCTYPE html>\n<html><head><title>Freenet - Not found</title></head><body>\n\
+             <h1>Freenet</h1>\n<p>Nothing is served at <code>{}</code>.</p>\n\
+             <p>Request id: <code>{request_id}</code></p>\n</body></html>\n",
+            html_escape(&path)
+        );
+        return (status, axum::response::Html(page)).into_response();
+    }
+    let body = NotFound {
+        error: "not found",
+        path,
+        request_id,
+    };
+    (status, Json(body)).into_response()
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Rejects requests whose path exceeds the configured maximum length before doing any work.
+async fn limit_path_length(
+    max_path_length: usize,
+    req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let length = req
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str().len())
+        .unwrap_or_default();
+    if length > max_path_length {
+        tracing::debug!(
+            length,
+            max_path_length,
+            "rejected request with overlong path"
+        );
+        return (
+            axum::http::StatusCode::URI_TOO_LONG,
+            format!("path length exceeds the maximum of {max_path_length} bytes"),
+        )
+            .into_response();
+    }
+    next.run(req).await
+}
+
+async fn work_queue_depth(
+    Extension(work_queue): Extension<Arc<WorkQueueMetrics>>,
+) -> Json<work_queue::WorkQueueSnapshot> {
+    Json(work_queue.snapshot())
+}
+
+/// Re-runs the validation of the state stored for a contract, leaving it untouched.
+async fn revalidate_contract(
+    Path(key): Path<String>,
+    Extension(commands): Extension<ExecutorCommands>,
+) -> Result<Json<Revalidation>, WebSocketApiError> {
+    let key = parse_key(key)?;
+    let revalidation = commands
+        .request(key, |respond| ExecutorCommand::Revalidate { key, respond })
+        .await?;
+    Ok(Json(revalidation))
+}
+
+/// Describes the current state of a contract without transferring it.
+async fn contract_metadata(
+    Path(key): Path<String>,
+    Extension(commands): Extension<ExecutorCommands>,
+) -> Result<Json<ContractMetadata>, WebSocketApiError> {
+    let key = parse_key(key)?;
+    let metadata = commands
+        .request(key, |respond| ExecutorCommand::Metadata { key, respond })
+        .await?;
+    Ok(Json(metadata))
+}
+
+#[derive(serde::Serialize)]
+struct ContractTopics {
+    key: String,
+    topics: Vec<String>,
+}
+
+/// Lists the topics a contract declares for subscriptions.
+async fn contract_topics(
+    Path(key): Path<String>,
+    Extension(commands): Extension<ExecutorCommands>,
+) -> Result<Json<ContractTopics>, WebSocketApiError> {
+    let key = parse_key(key)?;
+    let topics = commands
+        .request(key, |respond| ExecutorCommand::Topics { key, respond })
+        .await?;
+    Ok(Json(ContractTopics {
+        key: key.encoded_contract_id(),
+        topics,
+    }))
+}
+
+#[derive(serde::Serialize)]
+struct ContractDependencies {
+    key: String,
+    dependencies: Vec<String>,
+}
+
+/// Lists the contracts a contract declares it depends on.
+async fn contract_dependencies(
+    Path(key): Path<String>,
+    Extension(commands): Extension<ExecutorCommands>,
+) -> Result<Json<ContractDependencies>, WebSocketApiError> {
+    let key = parse_key(key)?;
+    let dependencies = commands
+        .request(key, |respond| ExecutorCommand::Dependencies {
+            key,
+            respond,
+        })
+        .await?;
+    Ok(Json(ContractDependencies {
+        key: key.encoded_contract_id(),
+        dependencies: dependencies
+            .iter()
+            .map(ContractKey::encoded_contract_id)
+            .collect(),
+    }))
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ItemsFormat {
+    /// A JSON array, written an item at a time.
+    #[default]
+    Array,
+    /// One item per line.
+    Ndjson,
+}
+
+#[derive(serde::Deserialize)]
+struct ItemsQuery {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+    #[serde(default)]
+    format: ItemsFormat,
+}
+
+/// Streams the entries of a contract modeling a collection, each one a JSON document, in the
+/// format asked for as they are read, so clients can process them as they arrive.
+///
+/// An entry which is not JSON ends the response abruptly, leaving the body invalid.
+async fn contract_items(
+    Path(key): Path<String>,
+    Query(ItemsQuery {
+        offset,
+        limit,
+        format,
+    }): Query<ItemsQuery>,
+    Extension(commands): Extension<ExecutorCommands>,
+) -> Result<axum::response::Response, WebSocketApiError> {
+    let key = parse_key(key)?;
+    let frames = commands
+        .request(key, |respond| ExecutorCommand::Range {
+            key,
+            offset,
+            limit: limit.unwrap_or(usize::MAX),
+            respond,
+        })
+        .await?;
+    let chunks = futures::stream::unfold(Some((frames, 0)), move |streaming| async move {
+        let (mut frames, sent) = streaming?;
+        let chunk = match frames.recv().await {
+            Some(RangeFrame::Entry { index, data }) => {
+                // re-encoded so every item is on a line of its own
+                let item = match serde_json::from_slice::<serde_json::Value>(&data) {
+                    Ok(item) => item,
+                    Err(err) => {
+                        let cause = format!("entry {index} is not a JSON document: {err}");
+                        return Some((Err(std::io::Error::other(cause)), None));
+                    }
+                };
+                let mut chunk = match (format, sent) {
+                    (ItemsFormat::Array, 0) => b"[".to_vec(),
+                    (ItemsFormat::Array, _) => b",".to_vec(),
+                    (ItemsFormat::Ndjson, _) => Vec::new(),
+                };
+                serde_json::to_writer(&mut chunk, &item).expect("infallible serialization");
+                chunk.push(b'\n');
+                return Some((Ok(chunk), Some((frames, sent + 1))));
+            }
+            Some(RangeFrame::Complete { .. }) => match (format, sent) {
+                (ItemsFormat::Array, 0) => Ok(b"[]\n".to_vec()),
+                (ItemsFormat::Array, _) => Ok(b"]\n".to_vec()),
+                (ItemsFormat::Ndjson, _) => return None,
+            },
+            Some(RangeFrame::Error { cause }) => Err(std::io::Error::other(cause)),
+            None => Err(std::io::Error::other("collection stream ended early")),
+        };
+        Some((chunk, None))
+    });
+    let content_type = match format {
+        ItemsFormat::Array => "application/json",
+        ItemsFormat::Ndjson => "application/x-ndjson",
+    };
+    Ok((
+        [(axum::http::header::CONTENT_TYPE, content_type)],
+        axum::body::Body::from_stream(chunks),
+    )
+        .into_response())
+}
+
+#[derive(serde::Deserialize)]
+struct EstimateQuery {
+    /// The body is the whole new state rather than a delta.
+    #[serde(default)]
+    state: bool,
+}
+
+/// Estimates the cost of updating a contract with the delta, or state, in the body, without
+/// applying it.
+async fn estimate_update(
+    Path(key): Path<String>,
+    Query(EstimateQuery { state }): Query<EstimateQuery>,
+    Extension(commands): Extension<ExecutorCommands>,
+    body: axum::body::Bytes,
+) -> Result<Json<CostEstimate>, WebSocketApiError> {
+    let key = parse_key(key)?;
+    let update = if state {
+        UpdateData::State(State::from(body.to_vec()))
+    } else {
+        UpdateData::Delta(StateDelta::from(body.to_vec()))
+    };
+    let estimate = commands
+        .request(key, |respond| ExecutorCommand::Estimate {
+            key,
+            update,
+            respond,
+        })
+        .await?;
+    Ok(Json(estimate))
+}
+
+/// Applies the bincode encoded update requests in the body all together or none of them.
+async fn apply_transaction(
+    headers: axum::http::HeaderMap,
+    Extension(commands): Extension<ExecutorCommands>,
+    body: axum::body::Bytes,
+) -> Result<Json<TransactionOutcome>, WebSocketApiError> {
+    use headers::{
+        authorization::{Authorization, Bearer},
+        HeaderMapExt,
+    };
+
+    let requests: Vec<ContractRequest> =
+        bincode::deserialize(&body).map_err(|err| WebSocketApiError::InvalidParam {
+            error_cause: format!("malformed transaction: {err}"),
+        })?;
+    let updates = requests
+        .into_iter()
+        .map(|request| match request.into_owned() {
+            ContractRequest::Update { key, data } => Ok((key, data)),
+            _ => Err(WebSocketApiError::InvalidParam {
+                error_cause: "only updates can be part of a transaction".into(),
+            }),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if updates.is_empty() {
+        return Err(WebSocketApiError::InvalidParam {
+            error_cause: "empty transaction".into(),
+        });
+    }
+    let auth_token = headers
+        .typed_get::<Authorization<Bearer>>()
+        .map(|value| AuthToken::from(value.token().to_owned()));
+    let outcome = commands
+        .send(|respond| ExecutorCommand::Transaction {
+            updates,
+            auth_token,
+            respond,
+        })
+        .await?
+        .map_err(|err| {
+            if err.is_request() {
+                // the updates are refused before any is applied, e.g. for lacking a token
+                WebSocketApiError::Unauthorized {
+                    error_cause: err.to_string(),
+                }
+            } else {
+                WebSocketApiError::NodeError {
+                    error_cause: err.to_string(),
+                }
+            }
+        })?;
+    Ok(Json(outcome))
+}
+
+#[derive(serde::Deserialize)]
+struct CodeQuery {
+    /// Only the hash of the code is returned.
+    #[serde(default)]
+    hash: bool,
+}
+
+#[derive(serde::Serialize)]
+struct ContractCodeHash {
+    key: String,
+    code_hash: String,
+}
+
+/// Returns the wasm code of a contract, or only its hash, to the clients holding a token
+/// attested for that same contract, e.g. tooling of its web app verifying what it runs on.
+async fn contract_code(
+    Path(key): Path<String>,
+    Query(CodeQuery { hash }): Query<CodeQuery>,
+    headers: axum::http::HeaderMap,
+    Extension(attested_contracts): Extension<AttestedContractMap>,
+    Extension(token_expiry): Extension<TokenExpiryCheck>,
+    Extension(commands): Extension<ExecutorCommands>,
+) -> Result<axum::response::Response, WebSocketApiError> {
+    use headers::{
+        authorization::{Authorization, Bearer},
+        HeaderMapExt,
+    };
+
+    let key = parse_key(key)?;
+    let token = headers
+        .typed_get::<Authorization<Bearer>>()
+        .map(|value| AuthToken::from(value.token().to_owned()))
+        .ok_or_else(|| WebSocketApiError::Unauthorized {
+            error_cause: "a token attested for the contract is required".into(),
+        })?;
+    let attested = attested_contracts
+        .read()
+        .unwrap()
+        .get(&token)
+        .filter(|(_, _, issued)| token_expiry.accepts(*issued))
+        .map(|(contract, _, _)| *contract);
+    if attested.as_ref() != Some(key.id()) {
+        tracing::debug!(contract = %key, ?attested, "denied the code of the contract");
+        return Err(WebSocketApiError::Unauthorized {
+            error_cause: format!("the token is not attested for contract {key}"),
+        });
+    }
+    let contract = commands
+        .request(key, |respond| ExecutorCommand::Code { key, respond })
+        .await?;
+    let code_hash = contract
+        .key()
+        .code_hash()
+        .map(|hash| hash.encode())
+        .unwrap_or_default();
+    if hash {
+        return Ok(Json(ContractCodeHash {
+            key: key.encoded_contract_id(),
+            code_hash,
+        })
+        .into_response());
+    }
+    Ok((
+        [(axum::http::header::CONTENT_TYPE, "application/wasm")],
+        contract.data().to_vec(),
+    )
+        .into_response())
+}
+
+/// Headers of a past state of a contract with its number and hash.
+const STATE_VERSION_NUMBER_HEADER: &str = "x-state-version-number";
+const STATE_VERSION_HEADER: &str = "x-state-version";
+
+/// Returns a past state of a contract, by its number or hash, if the node still retains it.
+async fn historical_state(
+    Path((key, version)): Path<(String, String)>,
+    Extension(commands): Extension<ExecutorCommands>,
+) -> Result<axum::response::Response, WebSocketApiError> {
+    let key = parse_key(key)?;
+    let version: StateVersion = version.parse().expect("infallible");
+    let state = commands
+        .request(key, |respond| ExecutorCommand::History {
+            key,
+            version: version.clone(),
+            respond,
+        })
+        .await?
+        .ok_or_else(|| WebSocketApiError::VersionNotRetained {
+            key,
+            version: version.to_string(),
+        })?;
+    Ok((
+        [
+            (
+                axum::http::header::CONTENT_TYPE,
+                "application/octet-stream".to_owned(),
+            ),
+            (
+                axum::http::HeaderName::from_static(STATE_VERSION_NUMBER_HEADER),
+                state.number.to_string(),
+            ),
+            (
+                axum::http::HeaderName::from_static(STATE_VERSION_HEADER),
+                state.hash,
+            ),
+        ],
+        state.state.as_ref().to_vec(),
+    )
+        .into_response())
+}
+
+#[derive(serde::Deserialize)]
+struct DiffQuery {
+    /// Version to diff to, the current state if not given.
+    to: Option<String>,
+}
+
+/// Whether a diff is a delta to apply to the base version or the whole newer state.
+const STATE_DIFF_HEADER: &str = "x-state-diff";
+
+/// Size of the chunks a diff is streamed in.
+const DIFF_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Streams what brings a client from a past version of a contract to a newer one, the whole
+/// newer state if the past version is no longer retained.
+async fn state_diff(
+    Path((key, base)): Path<(String, String)>,
+    Query(DiffQuery { to }): Query<DiffQuery>,
+    Extension(commands): Extension<ExecutorCommands>,
+) -> Result<axum::response::Response, WebSocketApiError> {
+    let key = parse_key(key)?;
+    let base: StateVersion = base.parse().expect("infallible");
+    let target = to.map(|to| to.parse::<StateVersion>().expect("infallible"));
+    let diff = commands
+        .request(key, |respond| ExecutorCommand::Diff {
+            key,
+            base,
+            target: target.clone(),
+            respond,
+        })
+        .await?
+        .ok_or_else(|| WebSocketApiError::VersionNotRetained {
+            key,
+            version: target.map(|target| target.to_string()).unwrap_or_default(),
+        })?;
+    let (kind, version, bytes) = match diff {
+        StateDiff::Delta { version, delta } => ("delta", version, delta.into_bytes()),
+        StateDiff::State { version, state } => ("state", version, state.as_ref().to_vec()),
+    };
+    let bytes = axum::body::Bytes::from(bytes);
+    let chunks = (0..bytes.len()).step_by(DIFF_CHUNK_SIZE).map(move |start| {
+        let end = (start + DIFF_CHUNK_SIZE).min(bytes.len());
+        Ok::<_, std::convert::Infallible>(bytes.slice(start..end))
+    });
+    Ok((
+        [
+            (
+                axum::http::header::CONTENT_TYPE,
+                "application/octet-stream".to_owned(),
+            ),
+            (
+                axum::http::HeaderName::from_static(STATE_DIFF_HEADER),
+                kind.to_owned(),
+            ),
+            (
+                axum::http::HeaderName::from_static(STATE_VERSION_HEADER),
+                version,
+            ),
+        ],
+        axum::body::Body::from_stream(futures::stream::iter(chunks)),
+    )
+        .into_response())
+}
+
+fn parse_key(key: String) -> Result<ContractKey, WebSocketApiError> {
+    ContractKey::from_id(key).map_err(|err| WebSocketApiError::InvalidParam {
+        error_cause: format!("{err}"),
+    })
+}
+
 impl ClientEventsProxy for HttpGateway {
     #[instrument(level = "debug", skip(self))]
     fn recv(&mut self) -> BoxFuture<Result<OpenRequest<'static>, ClientError>> {
@@ -84,7 +797,10 @@ impl ClientEventsProxy for HttpGateway {
                             self.attested_contracts
                                 .write()
                                 .map_err(|_| ErrorKind::FailedOperation)?
-                                .insert(assigned_token.clone(), (contract, cli_id));
+                                .insert(
+                                    assigned_token.clone(),
+                                    (contract, cli_id, self.token_expiry.now()),
+                                );
                             tracing::debug!(
                                 ?assigned_token,
                                 ?contract,
@@ -95,6 +811,8 @@ impl ClientEventsProxy for HttpGateway {
                         self.response_channels.insert(cli_id, callbacks);
                         continue;
                     }
+                    // clients of the gateway don't subscribe
+                    ClientConnection::Unsubscribed { .. } => continue,
                     ClientConnection::Request {
                         client_id,
                         req,
@@ -120,6 +838,7 @@ impl ClientEventsProxy for HttpGateway {
         result: Result<HostResponse, ClientError>,
     ) -> BoxFuture<Result<(), ClientError>> {
         async move {
+            let result = self.response_transformer.transform(id, result);
             if let Some(ch) = self.response_channels.remove(&id) {
                 let should_rm = result
                     .as_ref()
@@ -133,10 +852,434 @@ impl ClientEventsProxy for HttpGateway {
                     tracing::info!("dropped connection to client #{id}");
                 }
             } else {
-                tracing::warn!("client: {id} not found");
+                // the client disconnected while its request was being served
+                tracing::debug!("dropped result for disconnected client #{id}");
+                self.proxy_server_request.metrics().orphaned();
             }
             Ok(())
         }
         .boxed()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::RouteTimeouts;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn overlong_asset_path_is_rejected() -> anyhow::Result<()> {
+        let config = WebsocketApiConfig {
+            max_path_length: 256,
+            ..WebsocketApiConfig::from(SocketAddr::from(([127, 0, 0, 1], 0)))
+        };
+        let (_gw, router) = HttpGateway::as_router_with_attested_contracts(
+            &config,
+            Arc::new(RwLock::new(HashMap::new())),
+            Arc::new(WorkQueueMetrics::default()),
+        );
+        let listener = tokio::net::TcpListener::bind((config.address, 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let key = ContractInstanceId::new([1; 32]);
+        let long_path = "a".repeat(config.max_path_length);
+        let response =
+            reqwest::get(format!("http://{addr}/v1/contract/web/{key}/{long_path}")).await?;
+        assert_eq!(response.status(), reqwest::StatusCode::URI_TOO_LONG);
+
+        let response =
+            reqwest::get(format!("http://{addr}/v1/contract/web/{key}/index.html")).await?;
+        assert_ne!(response.status(), reqwest::StatusCode::URI_TOO_LONG);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_route_gets_branded_not_found() -> anyhow::Result<()> {
+        let (_gw, router) = HttpGateway::as_router(&SocketAddr::from(([127, 0, 0, 1], 0)));
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        let client = reqwest::Client::new();
+        let url = format!("http://{addr}/v1/no/such/route");
+
+        let response = client.get(&url).send().await?;
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+        let body: serde_json::Value = response.json().await?;
+        assert_eq!(body["error"], "not found");
+        assert_eq!(body["path"], "/v1/no/such/route");
+        let request_id = body["requestId"].as_str().unwrap();
+        assert!(request_id.parse::<ulid::Ulid>().is_ok(), "{body}");
+
+        let response = client
+            .get(&url)
+            .header("accept", "text/html,application/xhtml+xml,*/*;q=0.8")
+            .send()
+            .await?;
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+        let page = response.text().await?;
+        assert!(page.contains("<h1>Freenet</h1>"), "{page}");
+        assert!(page.contains("/v1/no/such/route"), "{page}");
+        assert!(page.contains("Request id: <code>"), "{page}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn route_groups_have_own_timeouts() -> anyhow::Result<()> {
+        // neither the node nor the executor ever answer, the routes wait until timing out
+        async fn serve(
+            timeouts: RouteTimeouts,
+        ) -> anyhow::Result<(HttpGateway, SocketAddr, reqwest::Client)> {
+            let config = WebsocketApiConfig {
+                route_timeouts: timeouts,
+                ..WebsocketApiConfig::from(SocketAddr::from(([127, 0, 0, 1], 0)))
+            };
+            let (gw, router) = HttpGateway::as_router_with_attested_contracts(
+                &config,
+                Arc::new(RwLock::new(HashMap::new())),
+                Arc::new(WorkQueueMetrics::default()),
+            );
+            let listener = tokio::net::TcpListener::bind((config.address, 0)).await?;
+            let addr = listener.local_addr()?;
+            tokio::spawn(async move { axum::serve(listener, router).await });
+            let client = reqwest::Client::builder()
+                .timeout(Duration::from_secs(3))
+                .build()?;
+            Ok((gw, addr, client))
+        }
+        let key = ContractInstanceId::new([1; 32]);
+
+        let (_gw, addr, client) = serve(RouteTimeouts {
+            assets_secs: 1,
+            api_secs: 60,
+        })
+        .await?;
+        let asset = client
+            .get(format!("http://{addr}/v1/contract/web/{key}/"))
+            .send()
+            .await?;
+        assert_eq!(asset.status(), reqwest::StatusCode::REQUEST_TIMEOUT);
+        let api = client
+            .get(format!("http://{addr}/v1/contract/metadata/{key}"))
+            .send()
+            .await;
+        assert!(api.unwrap_err().is_timeout(), "api timed out with assets");
+
+        let (_gw, addr, client) = serve(RouteTimeouts {
+            assets_secs: 60,
+            api_secs: 1,
+        })
+        .await?;
+        let api = client
+            .get(format!("http://{addr}/v1/contract/metadata/{key}"))
+            .send()
+            .await?;
+        assert_eq!(api.status(), reqwest::StatusCode::REQUEST_TIMEOUT);
+        let asset = client
+            .get(format!("http://{addr}/v1/contract/web/{key}/"))
+            .send()
+            .await;
+        assert!(asset.unwrap_err().is_timeout(), "assets timed out with api");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn revalidation_is_served_by_node() -> anyhow::Result<()> {
+        use crate::server::client_addr::ClientAddr;
+
+        let (mut gw, router) = HttpGateway::as_router(&SocketAddr::from(([127, 0, 0, 1], 0)));
+        // without an admin token, admin routes are served to local clients only
+        let local = ClientAddr(IpAddr::from([127, 0, 0, 1]));
+        let router = router.layer(Extension(local));
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let corrupted = ContractInstanceId::new([1; 32]);
+        let missing = ContractInstanceId::new([2; 32]);
+        let mut commands = gw.take_executor_commands();
+        tokio::spawn(async move {
+            while let Some(ExecutorCommand::Revalidate { key, respond }) = commands.recv().await {
+                let result = if key.id() == &corrupted {
+                    Ok(Revalidation::Invalid)
+                } else {
+                    Err(ExecutorError::missing_contract(key))
+                };
+                let _ = respond.send(result);
+            }
+        });
+
+        let client = reqwest::Client::new();
+        let response = client
+            .post(format!(
+                "http://{addr}/v1/admin/contract/{corrupted}/validate"
+            ))
+            .send()
+            .await?;
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            response.json::<serde_json::Value>().await?,
+            serde_json::json!({ "result": "invalid" })
+        );
+
+        let response = client
+            .post(format!(
+                "http://{addr}/v1/admin/contract/{missing}/validate"
+            ))
+            .send()
+            .await?;
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn contract_code_requires_attested_token() -> anyhow::Result<()> {
+        use freenet_stdlib::prelude::{
+            ContractCode, ContractWasmAPIVersion, Parameters, WrappedContract,
+        };
+
+        let (mut gw, router) = HttpGateway::as_router(&SocketAddr::from(([127, 0, 0, 1], 0)));
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let contract = ContractContainer::Wasm(ContractWasmAPIVersion::V1(WrappedContract::new(
+            Arc::new(ContractCode::from(vec![0, 97, 115, 109])),
+            Parameters::from(vec![]),
+        )));
+        let key = contract.key();
+        let (token, other_token) = (AuthToken::generate(), AuthToken::generate());
+        {
+            let mut attested = gw.attested_contracts.write().unwrap();
+            attested.insert(token.clone(), (*key.id(), ClientId::next(), Instant::now()));
+            let other = ContractInstanceId::new([9; 32]);
+            attested.insert(
+                other_token.clone(),
+                (other, ClientId::next(), Instant::now()),
+            );
+        }
+        let mut commands = gw.take_executor_commands();
+        let served = contract.clone();
+        tokio::spawn(async move {
+            while let Some(ExecutorCommand::Code { respond, .. }) = commands.recv().await {
+                let _ = respond.send(Ok(served.clone()));
+            }
+        });
+
+        let client = reqwest::Client::new();
+        let url = format!("http://{addr}/v1/contract/code/{}", key.id());
+        let response = client.get(&url).bearer_auth(token.as_str()).send().await?;
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(&*response.bytes().await?, contract.data());
+        let response = client
+            .get(format!("{url}?hash=true"))
+            .bearer_auth(token.as_str())
+            .send()
+            .await?;
+        let hash: serde_json::Value = response.json().await?;
+        assert_eq!(
+            hash["code_hash"],
+            key.code_hash().unwrap().encode().as_str()
+        );
+
+        let response = client.get(&url).send().await?;
+        assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
+        // attested for another contract
+        let response = client
+            .get(&url)
+            .bearer_auth(other_token.as_str())
+            .send()
+            .await?;
+        assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn items_streamed_as_json_incrementally() -> anyhow::Result<()> {
+        let (mut gw, router) = HttpGateway::as_router(&SocketAddr::from(([127, 0, 0, 1], 0)));
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        // stands in for the executor, streaming the rest of the entries only once the client
+        // got the first one
+        let (first_read, mut proceed) = mpsc::channel::<()>(1);
+        let mut commands = gw.take_executor_commands();
+        tokio::spawn(async move {
+            while let Some(command) = commands.recv().await {
+                let ExecutorCommand::Range {
+                    offset, respond, ..
+                } = command
+                else {
+                    panic!("unexpected command");
+                };
+                let (frames, streamed) = mpsc::channel(1);
+                let _ = respond.send(Ok(streamed));
+                let entry = |index: usize| RangeFrame::Entry {
+                    index,
+                    data: serde_json::to_vec(&serde_json::json!({ "item": index })).unwrap(),
+                };
+                frames.send(entry(offset)).await.unwrap();
+                proceed.recv().await;
+                for index in offset + 1..offset + 3 {
+                    frames.send(entry(index)).await.unwrap();
+                }
+                let _ = frames.send(RangeFrame::Complete { entries: 3 }).await;
+            }
+        });
+
+        let key = ContractInstanceId::new([1; 32]);
+        for (format, offset) in [("array", 0), ("ndjson", 5)] {
+            let mut response = reqwest::get(format!(
+                "http://{addr}/v1/contract/items/{key}?format={format}&offset={offset}"
+            ))
+            .await?;
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            let mut body = Vec::new();
+            while !body.ends_with(b"\n") {
+                let chunk = tokio::time::timeout(Duration::from_secs(5), response.chunk())
+                    .await
+                    .expect("first item held back")?
+                    .unwrap();
+                body.extend_from_slice(&chunk);
+            }
+            first_read.send(()).await?;
+            while let Some(chunk) = response.chunk().await? {
+                body.extend_from_slice(&chunk);
+            }
+
+            let expected: Vec<_> = (offset..offset + 3)
+                .map(|index| serde_json::json!({ "item": index }))
+                .collect();
+            let items: Vec<serde_json::Value> = match format {
+                "array" => serde_json::from_slice(&body)?,
+                _ => body
+                    .split(|b| *b == b'\n')
+                    .filter(|line| !line.is_empty())
+                    .map(serde_json::from_slice)
+                    .collect::<Result<_, _>>()?,
+            };
+            assert_eq!(items, expected, "{format}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn empty_state_is_no_content() -> anyhow::Result<()> {
+        use freenet_stdlib::{
+            client_api::{ClientRequest, ContractResponse},
+            prelude::{
+                ContractCode, ContractWasmAPIVersion, Parameters, WrappedContract, WrappedState,
+            },
+        };
+
+        let (mut gw, router) = HttpGateway::as_router(&SocketAddr::from(([127, 0, 0, 1], 0)));
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let contract = ContractContainer::Wasm(ContractWasmAPIVersion::V1(WrappedContract::new(
+            Arc::new(ContractCode::from(vec![1, 2, 3])),
+            Parameters::from(vec![]),
+        )));
+        let key = contract.key();
+        tokio::spawn(async move {
+            while let Ok(req) = gw.recv().await {
+                let ClientRequest::ContractOp(ContractRequest::Get { key, .. }) = *req.request
+                else {
+                    continue;
+                };
+                let response = ContractResponse::GetResponse {
+                    key,
+                    contract: Some(contract.clone()),
+                    state: WrappedState::new(vec![]),
+                };
+                gw.send(req.client_id, Ok(response.into())).await.unwrap();
+            }
+        });
+
+        let response = reqwest::get(format!(
+            "http://{addr}/v1/contract/web/{}/",
+            key.encoded_contract_id()
+        ))
+        .await?;
+        assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+        assert!(response.bytes().await?.is_empty());
+        Ok(())
+    }
+
+    struct TagErrors;
+
+    impl ResponseTransformer for TagErrors {
+        fn transform(
+            &self,
+            client: ClientId,
+            response: Result<HostResponse, ClientError>,
+        ) -> Result<HostResponse, ClientError> {
+            response.map_err(|err| {
+                ErrorKind::OperationError {
+                    cause: format!("[client {client}] {err}").into(),
+                }
+                .into()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_go_through_transformer() -> anyhow::Result<()> {
+        let (gw, _router) = HttpGateway::as_router(&SocketAddr::from(([127, 0, 0, 1], 0)));
+        let mut gw = gw.with_response_transformer(Arc::new(TagErrors));
+        let (callbacks, mut responses) = mpsc::unbounded_channel();
+        gw.response_channels.insert(ClientId::FIRST, callbacks);
+
+        gw.send(ClientId::FIRST, Err(ErrorKind::FailedOperation.into()))
+            .await?;
+        let Some(HostCallbackResult::Result { result, .. }) = responses.recv().await else {
+            anyhow::bail!("expected a response for the client");
+        };
+        match result.unwrap_err().kind() {
+            ErrorKind::OperationError { cause } => {
+                assert!(cause.starts_with(&format!("[client {}]", ClientId::FIRST)))
+            }
+            other => anyhow::bail!("unexpected error: {other}"),
+        }
+
+        // untouched when successful
+        gw.send(ClientId::FIRST, Ok(HostResponse::Ok)).await?;
+        assert!(matches!(
+            responses.recv().await,
+            Some(HostCallbackResult::Result {
+                result: Ok(HostResponse::Ok),
+                ..
+            })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_attest_nothing() -> anyhow::Result<()> {
+        let config = WebsocketApiConfig {
+            token_expiry: crate::config::TokenExpiry {
+                ttl_secs: 0,
+                skew_margin_secs: 0,
+            },
+            ..WebsocketApiConfig::from(SocketAddr::from(([127, 0, 0, 1], 0)))
+        };
+        let (gw, _router) = HttpGateway::as_router_with_attested_contracts(
+            &config,
+            AttestedContractMap::default(),
+            Arc::new(WorkQueueMetrics::default()),
+        );
+        let token = AuthToken::generate();
+        let contract = ContractInstanceId::new([1; 32]);
+        gw.attested_contracts
+            .write()
+            .unwrap()
+            .insert(token.clone(), (contract, ClientId::FIRST, Instant::now()));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(gw.attested_contract(&token), None);
+        assert_eq!(gw.attested_contract(&AuthToken::generate()), None);
+        Ok(())
+    }
+}
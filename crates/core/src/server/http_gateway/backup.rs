@@ -0,0 +1,122 @@
+//! Admin routes exporting the contracts of the node and importing them into another one, see
+//! [`crate::contract::backup`] for the format.
+//!
+//! A `GET` of `/v1/admin/contracts/export` streams every contract stored, or only those listed
+//! as `contracts=<id>,<id>`, reading each from the executor as the stream gets to it. Posting
+//! that same stream to `/v1/admin/contracts/import` puts the contracts in it one by one as they
+//! come in. Both routes are admin routes, see [`crate::server::admin`].
+
+use axum::body::Body;
+use futures::StreamExt;
+use serde::Deserialize;
+
+use crate::contract::backup::{ExportReader, ImportOutcome};
+
+use super::*;
+
+/// Largest contract accepted in an import, as large as a contract uploaded to the gateway.
+const MAX_IMPORTED_CONTRACT_SIZE: usize = 256 * 1024 * 1024;
+const MAX_IMPORT_SIZE: u64 = 16 * 1024 * 1024 * 1024;
+
+#[derive(Deserialize)]
+pub(super) struct ExportQuery {
+    /// Ids of the contracts to export, comma separated.
+    #[serde(default)]
+    contracts: Option<String>,
+}
+
+pub(super) async fn export_contracts(
+    Query(ExportQuery { contracts }): Query<ExportQuery>,
+    Extension(commands): Extension<ExecutorCommands>,
+) -> Result<axum::response::Response, WebSocketApiError> {
+    let contracts = contracts
+        .iter()
+        .flat_map(|contracts| contracts.split(','))
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            id.parse::<ContractInstanceId>()
+                .map_err(|err| WebSocketApiError::InvalidParam {
+                    error_cause: format!("invalid contract id `{id}`: {err}"),
+                })
+        })
+        .collect::<Result<HashSet<_>, _>>()?;
+    let keys = commands
+        .send(|respond| ExecutorCommand::ExportedContracts { contracts, respond })
+        .await?
+        .map_err(|err| WebSocketApiError::NodeError {
+            error_cause: err.to_string(),
+        })?;
+    tracing::info!(contracts = keys.len(), "exporting contracts");
+    let frames = futures::stream::iter(keys).filter_map(move |key| {
+        let commands = commands.clone();
+        async move {
+            let exported = match commands
+                .send(|respond| ExecutorCommand::ExportContract { key, respond })
+                .await
+            {
+                Ok(Ok(exported)) => exported?,
+                Ok(Err(err)) => return Some(Err(std::io::Error::other(err.to_string()))),
+                Err(err) => return Some(Err(std::io::Error::other(err.to_string()))),
+            };
+            Some(Ok(exported.encode()))
+        }
+    });
+    let frames = frames.inspect(|frame| {
+        if let Err(err) = frame {
+            tracing::error!(%err, "export aborted");
+        }
+    });
+    Ok((
+        [(axum::http::header::CONTENT_TYPE, "application/octet-stream")],
+        Body::from_stream(frames),
+    )
+        .into_response())
+}
+
+pub(super) async fn import_contracts(
+    Extension(commands): Extension<ExecutorCommands>,
+    body: Body,
+) -> Result<Json<ImportOutcome>, WebSocketApiError> {
+    let mut outcome = ImportOutcome::default();
+    let malformed =
+        |err: &dyn std::fmt::Display, outcome: &ImportOutcome| WebSocketApiError::InvalidParam {
+            error_cause: format!(
+                "{err}, {} contracts were imported before",
+                outcome.imported + outcome.unchanged
+            ),
+        };
+    let mut reader = ExportReader::new(MAX_IMPORTED_CONTRACT_SIZE);
+    let mut received = 0;
+    let mut chunks = body.into_data_stream();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|err| malformed(&err, &outcome))?;
+        received += chunk.len() as u64;
+        if received > MAX_IMPORT_SIZE {
+            return Err(WebSocketApiError::TooLarge {
+                error_cause: format!(
+                    "import over {MAX_IMPORT_SIZE} bytes, {} contracts were imported before",
+                    outcome.imported + outcome.unchanged
+                ),
+            });
+        }
+        reader.extend(&chunk);
+        while let Some(contract) = reader
+            .next_contract()
+            .map_err(|err| malformed(&err, &outcome))?
+        {
+            let key = contract.contract.key();
+            let contract = Box::new(contract);
+            let changed = commands
+                .request(key, |respond| ExecutorCommand::Import { contract, respond })
+                .await?;
+            if changed {
+                outcome.imported += 1;
+            } else {
+                outcome.unchanged += 1;
+            }
+        }
+    }
+    reader.finish().map_err(|err| malformed(&err, &outcome))?;
+    tracing::info!(?outcome, "imported contracts");
+    Ok(Json(outcome))
+}
@@ -0,0 +1,226 @@
+//! Contract updates scheduled to be applied at a later time.
+//!
+//! The client posts the update to `/v1/contract/schedule/{key}` with the time it is to be
+//! applied at, as a delta unless `state` is set, and gets back the id of the scheduled
+//! operation. Until then it can cancel it with a `DELETE` of `/v1/contract/scheduled/{id}`;
+//! once the time comes the update is executed as any other, and the operation is gone
+//! whatever the outcome. Scheduled operations live in the memory of the gateway, those still
+//! pending when the node stops are never applied.
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use freenet_stdlib::client_api::ClientRequest;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::task::AbortHandle;
+
+use super::*;
+
+const MAX_SCHEDULED: usize = 1024;
+
+struct Scheduled {
+    key: ContractKey,
+    at: DateTime<Utc>,
+    task: AbortHandle,
+}
+
+#[derive(Default)]
+pub(super) struct ScheduledOperations {
+    pending: Arc<Mutex<HashMap<String, Scheduled>>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct ScheduledStatus {
+    id: String,
+    key: String,
+    at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct ScheduleQuery {
+    at: DateTime<Utc>,
+    /// The body is the whole new state instead of a delta.
+    #[serde(default)]
+    state: bool,
+}
+
+impl ScheduledOperations {
+    fn schedule(
+        &self,
+        request_sender: HttpGatewayRequest,
+        key: ContractKey,
+        update: UpdateData<'static>,
+        at: DateTime<Utc>,
+    ) -> Result<ScheduledStatus, WebSocketApiError> {
+        let mut pending = self.pending.lock();
+        if pending.len() >= MAX_SCHEDULED {
+            return Err(WebSocketApiError::NodeError {
+                error_cause: "too many scheduled operations".into(),
+            });
+        }
+        let id = bs58::encode(rand::random::<[u8; 16]>()).into_string();
+        let task = tokio::spawn(apply(
+            self.pending.clone(),
+            id.clone(),
+            request_sender,
+            ContractRequest::Update { key, data: update },
+            at,
+        ));
+        let scheduled = Scheduled {
+            key,
+            at,
+            task: task.abort_handle(),
+        };
+        let status = scheduled.status(&id);
+        pending.insert(id, scheduled);
+        Ok(status)
+    }
+
+    /// Cancels the operation unless already applied.
+    fn cancel(&self, id: &str) -> Result<ScheduledStatus, WebSocketApiError> {
+        let scheduled = self
+            .pending
+            .lock()
+            .remove(id)
+            .ok_or_else(|| WebSocketApiError::MissingScheduled { id: id.to_owned() })?;
+        scheduled.task.abort();
+        Ok(scheduled.status(id))
+    }
+}
+
+impl Scheduled {
+    fn status(&self, id: &str) -> ScheduledStatus {
+        ScheduledStatus {
+            id: id.to_owned(),
+            key: self.key.encoded_contract_id(),
+            at: self.at,
+        }
+    }
+}
+
+async fn apply(
+    pending: Arc<Mutex<HashMap<String, Scheduled>>>,
+    id: String,
+    request_sender: HttpGatewayRequest,
+    request: ContractRequest<'static>,
+    at: DateTime<Utc>,
+) {
+    tokio::time::sleep((at - Utc::now()).to_std().unwrap_or_default()).await;
+    // past this point it can't be cancelled anymore
+    if pending.lock().remove(&id).is_none() {
+        return;
+    }
+    match execute(request_sender, ClientRequest::ContractOp(request)).await {
+        Ok(response) => tracing::debug!(%id, %response, "applied scheduled operation"),
+        Err(err) => tracing::warn!(%id, %err, "failed applying scheduled operation"),
+    }
+}
+
+pub(super) async fn schedule_update(
+    Path(key): Path<String>,
+    Query(ScheduleQuery { at, state }): Query<ScheduleQuery>,
+    Extension(scheduled): Extension<Arc<ScheduledOperations>>,
+    Extension(request_sender): Extension<HttpGatewayRequest>,
+    body: axum::body::Bytes,
+) -> Result<(StatusCode, Json<ScheduledStatus>), WebSocketApiError> {
+    let key = parse_key(key)?;
+    let update = if state {
+        UpdateData::State(State::from(body.to_vec()))
+    } else {
+        UpdateData::Delta(StateDelta::from(body.to_vec()))
+    };
+    let status = scheduled.schedule(request_sender, key, update, at)?;
+    Ok((StatusCode::CREATED, Json(status)))
+}
+
+pub(super) async fn cancel_scheduled(
+    Path(id): Path<String>,
+    Extension(scheduled): Extension<Arc<ScheduledOperations>>,
+) -> Result<Json<ScheduledStatus>, WebSocketApiError> {
+    scheduled.cancel(&id).map(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use freenet_stdlib::client_api::ContractResponse;
+    use freenet_stdlib::prelude::StateSummary;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn applied_at_time_unless_cancelled() -> anyhow::Result<()> {
+        let (mut gw, router) = HttpGateway::as_router(&SocketAddr::from(([127, 0, 0, 1], 0)));
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        // stands in for the node, reporting when each update came in
+        let (applied_tx, mut applied) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok(request) = gw.recv().await {
+                let ClientRequest::ContractOp(ContractRequest::Update { key, data }) =
+                    *request.request
+                else {
+                    continue;
+                };
+                let _ = applied_tx.send((Utc::now(), data.into_owned()));
+                let response = ContractResponse::UpdateResponse {
+                    key,
+                    summary: StateSummary::from(vec![]),
+                };
+                gw.send(request.client_id, Ok(response.into()))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let client = reqwest::Client::new();
+        let key = ContractInstanceId::new([1; 32]);
+        let at = Utc::now() + Duration::from_millis(300);
+        let schedule = |delta: Vec<u8>| {
+            client
+                .post(format!("http://{addr}/v1/contract/schedule/{key}"))
+                .query(&[("at", at.to_rfc3339())])
+                .body(delta)
+                .send()
+        };
+        let response = schedule(vec![1]).await?;
+        assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+        let kept: ScheduledStatus = response.json().await?;
+        assert_eq!(kept.key, key.encode());
+        assert_eq!(kept.at, at);
+        let cancelled: ScheduledStatus = schedule(vec![2]).await?.json().await?;
+
+        let cancel = |id: String| {
+            client
+                .delete(format!("http://{addr}/v1/contract/scheduled/{id}"))
+                .send()
+        };
+        assert_eq!(
+            cancel(cancelled.id.clone()).await?.status(),
+            reqwest::StatusCode::OK
+        );
+        assert_eq!(
+            cancel(cancelled.id).await?.status(),
+            reqwest::StatusCode::NOT_FOUND
+        );
+
+        let (applied_at, update) = tokio::time::timeout(Duration::from_secs(5), applied.recv())
+            .await?
+            .unwrap();
+        assert!(
+            applied_at >= at,
+            "applied at {applied_at}, scheduled at {at}"
+        );
+        assert_eq!(update, UpdateData::Delta(StateDelta::from(vec![1])));
+        // neither the cancelled one nor anything else follows
+        let next = tokio::time::timeout(Duration::from_millis(500), applied.recv()).await;
+        assert!(next.is_err(), "{next:?}");
+        // nor can the applied one be cancelled anymore
+        assert_eq!(
+            cancel(kept.id).await?.status(),
+            reqwest::StatusCode::NOT_FOUND
+        );
+        Ok(())
+    }
+}
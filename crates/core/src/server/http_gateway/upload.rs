@@ -0,0 +1,654 @@
+//! Resumable uploads of large contract PUTs and delegate inputs.
+//!
+//! The client starts an upload announcing the size of the bincode encoded
+//! [`ContractRequest::Put`], then sends it in chunks, each one stating at which offset it
+//! goes. Every chunk received is acknowledged with the offset reached so far; after losing
+//! the connection the client asks for it and resumes from there. Once everything has been
+//! received the client completes the upload and the request is executed as a single PUT.
+//!
+//! Delegate inputs go the same way under `/v1/delegate/upload`, the upload being a bincode
+//! encoded [`DelegateRequest`] which once completed is executed, responding with the bincode
+//! encoded messages the delegate sent back.
+//!
+//! Chunks are limited by the default body size limit of the router, 2 MiB.
+//!
+//! What is received of an upload is kept in memory up to the configured spill threshold, past
+//! it the upload is moved to a temporary file which is removed once the upload is completed,
+//! fails to complete or is dropped, so large uploads in progress don't hold on to memory.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, StatusCode},
+};
+use freenet_stdlib::client_api::{
+    ClientRequest, ContractRequest, ContractResponse, DelegateRequest,
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::*;
+
+/// Header stating the offset of the uploaded chunk.
+pub(super) const UPLOAD_OFFSET: &str = "upload-offset";
+
+const MAX_UPLOAD_SIZE: usize = 256 * 1024 * 1024;
+/// Delegates run with their whole input in memory, so it is kept smaller than contracts.
+const MAX_DELEGATE_INPUT_SIZE: usize = 64 * 1024 * 1024;
+const MAX_UPLOADS: usize = 64;
+/// Uploads without any chunk received for this long are dropped when starting a new one.
+const IDLE_UPLOAD_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+pub(super) struct Uploads {
+    uploads: Mutex<HashMap<String, Upload>>,
+    /// Size over which an upload is spilled to a file in `spill_dir`.
+    spill_threshold: usize,
+    spill_dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UploadKind {
+    Put,
+    DelegateInput,
+}
+
+impl UploadKind {
+    fn max_size(self) -> usize {
+        match self {
+            UploadKind::Put => MAX_UPLOAD_SIZE,
+            UploadKind::DelegateInput => MAX_DELEGATE_INPUT_SIZE,
+        }
+    }
+}
+
+struct Upload {
+    kind: UploadKind,
+    size: usize,
+    received: Received,
+    last_chunk: Instant,
+}
+
+#[derive(Default)]
+struct Received {
+    len: usize,
+    buffered: Vec<u8>,
+    spilled: Option<Spilled>,
+}
+
+/// Temporary file an upload was spilled to, removed along with the upload.
+struct Spilled {
+    path: PathBuf,
+    file: File,
+}
+
+impl Drop for Spilled {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            tracing::warn!(path = %self.path.display(), "failed removing spilled upload: {err}");
+        }
+    }
+}
+
+impl Received {
+    fn append(
+        &mut self,
+        chunk: &[u8],
+        threshold: usize,
+        dir: &std::path::Path,
+    ) -> std::io::Result<()> {
+        if self.spilled.is_none() && self.len + chunk.len() > threshold {
+            std::fs::create_dir_all(dir)?;
+            let path = dir.join(bs58::encode(rand::random::<[u8; 16]>()).into_string());
+            let file = File::options()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)?;
+            let mut spilled = Spilled { path, file };
+            spilled.file.write_all(&self.buffered)?;
+            self.buffered = Vec::new();
+            self.spilled = Some(spilled);
+        }
+        match &mut self.spilled {
+            Some(spilled) => spilled.file.write_all(chunk)?,
+            None => self.buffered.extend_from_slice(chunk),
+        }
+        self.len += chunk.len();
+        Ok(())
+    }
+
+    fn into_bytes(mut self) -> std::io::Result<Vec<u8>> {
+        let Some(mut spilled) = self.spilled.take() else {
+            return Ok(self.buffered);
+        };
+        let mut bytes = Vec::with_capacity(self.len);
+        spilled.file.rewind()?;
+        spilled.file.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+#[derive(Deserialize)]
+pub(super) struct NewUpload {
+    size: usize,
+}
+
+#[derive(Serialize)]
+pub(super) struct UploadStatus {
+    id: String,
+    size: usize,
+    /// Bytes received so far, the next chunk starts here.
+    offset: usize,
+}
+
+impl Upload {
+    fn status(&self, id: &str) -> UploadStatus {
+        UploadStatus {
+            id: id.to_owned(),
+            size: self.size,
+            offset: self.received.len,
+        }
+    }
+}
+
+pub(super) async fn start_upload(
+    Extension(uploads): Extension<Arc<Uploads>>,
+    Json(NewUpload { size }): Json<NewUpload>,
+) -> Result<(StatusCode, Json<UploadStatus>), WebSocketApiError> {
+    uploads.start(UploadKind::Put, size)
+}
+
+pub(super) async fn start_delegate_upload(
+    Extension(uploads): Extension<Arc<Uploads>>,
+    Json(NewUpload { size }): Json<NewUpload>,
+) -> Result<(StatusCode, Json<UploadStatus>), WebSocketApiError> {
+    uploads.start(UploadKind::DelegateInput, size)
+}
+
+impl Uploads {
+    pub fn new(spill_threshold: usize) -> Self {
+        Self::spilling_to(
+            spill_threshold,
+            std::env::temp_dir().join("freenet").join("uploads"),
+        )
+    }
+
+    fn spilling_to(spill_threshold: usize, spill_dir: PathBuf) -> Self {
+        Self {
+            uploads: Mutex::default(),
+            spill_threshold,
+            spill_dir,
+        }
+    }
+
+    fn start(
+        &self,
+        kind: UploadKind,
+        size: usize,
+    ) -> Result<(StatusCode, Json<UploadStatus>), WebSocketApiError> {
+        let max_size = kind.max_size();
+        if size > max_size {
+            return Err(WebSocketApiError::InvalidParam {
+                error_cause: format!("uploads are limited to {max_size} bytes"),
+            });
+        }
+        let mut uploads = self.uploads.lock();
+        uploads.retain(|_, upload| upload.last_chunk.elapsed() < IDLE_UPLOAD_TIMEOUT);
+        if uploads.len() >= MAX_UPLOADS {
+            return Err(WebSocketApiError::NodeError {
+                error_cause: "too many uploads in progress".into(),
+            });
+        }
+        let id = bs58::encode(rand::random::<[u8; 16]>()).into_string();
+        let upload = Upload {
+            kind,
+            size,
+            received: Received::default(),
+            last_chunk: Instant::now(),
+        };
+        let status = upload.status(&id);
+        uploads.insert(id, upload);
+        Ok((StatusCode::CREATED, Json(status)))
+    }
+
+    /// Takes the completed upload of the given kind, it is gone afterwards.
+    fn complete(&self, id: &str, kind: UploadKind) -> Result<Vec<u8>, WebSocketApiError> {
+        let upload = {
+            let mut uploads = self.uploads.lock();
+            let upload = uploads
+                .get(id)
+                .filter(|upload| upload.kind == kind)
+                .ok_or_else(|| WebSocketApiError::MissingUpload { id: id.to_owned() })?;
+            if upload.received.len < upload.size {
+                return Err(WebSocketApiError::InvalidParam {
+                    error_cause: format!(
+                        "upload incomplete, {} of {} bytes received",
+                        upload.received.len, upload.size
+                    ),
+                });
+            }
+            uploads.remove(id).unwrap()
+        };
+        upload
+            .received
+            .into_bytes()
+            .map_err(|err| WebSocketApiError::NodeError {
+                error_cause: format!("failed reading spilled upload: {err}"),
+            })
+    }
+
+    /// Appends the chunk at `offset`, or gives back the status of the upload if it reached
+    /// another offset.
+    fn append(
+        &self,
+        id: &str,
+        offset: usize,
+        chunk: &[u8],
+    ) -> Result<Result<UploadStatus, UploadStatus>, WebSocketApiError> {
+        let mut uploads = self.uploads.lock();
+        let upload = uploads
+            .get_mut(id)
+            .ok_or_else(|| WebSocketApiError::MissingUpload { id: id.to_owned() })?;
+        if offset != upload.received.len {
+            tracing::debug!(
+                %id,
+                offset,
+                received = upload.received.len,
+                "upload chunk out of place"
+            );
+            return Ok(Err(upload.status(id)));
+        }
+        if offset + chunk.len() > upload.size {
+            return Err(WebSocketApiError::InvalidParam {
+                error_cause: format!("chunk goes past the upload size of {} bytes", upload.size),
+            });
+        }
+        let appended = upload
+            .received
+            .append(chunk, self.spill_threshold, &self.spill_dir);
+        if let Err(err) = appended {
+            // whatever was written of it is gone along with the upload
+            uploads.remove(id);
+            return Err(WebSocketApiError::NodeError {
+                error_cause: format!("failed spilling upload to disk: {err}"),
+            });
+        }
+        upload.last_chunk = Instant::now();
+        Ok(Ok(upload.status(id)))
+    }
+}
+
+pub(super) async fn upload_status(
+    Path(id): Path<String>,
+    Extension(uploads): Extension<Arc<Uploads>>,
+) -> Result<Json<UploadStatus>, WebSocketApiError> {
+    let uploads = uploads.uploads.lock();
+    let upload = uploads
+        .get(&id)
+        .ok_or_else(|| WebSocketApiError::MissingUpload { id: id.clone() })?;
+    Ok(Json(upload.status(&id)))
+}
+
+/// Appends a chunk to the upload, a chunk for a different offset than the one reached is
+/// rejected with a conflict carrying the current status of the upload.
+pub(super) async fn upload_chunk(
+    Path(id): Path<String>,
+    Extension(uploads): Extension<Arc<Uploads>>,
+    headers: HeaderMap,
+    chunk: Bytes,
+) -> Result<axum::response::Response, WebSocketApiError> {
+    let offset = headers
+        .get(UPLOAD_OFFSET)
+        .and_then(|offset| offset.to_str().ok()?.parse::<usize>().ok())
+        .ok_or_else(|| WebSocketApiError::InvalidParam {
+            error_cause: format!("missing or malformed {UPLOAD_OFFSET} header"),
+        })?;
+    match uploads.append(&id, offset, &chunk)? {
+        Ok(status) => Ok(Json(status).into_response()),
+        Err(status) => Ok((StatusCode::CONFLICT, Json(status)).into_response()),
+    }
+}
+
+#[derive(Serialize)]
+pub(super) struct UploadedContract {
+    key: String,
+}
+
+/// Executes the uploaded PUT, the upload is gone afterwards whatever the outcome.
+pub(super) async fn complete_upload(
+    Path(id): Path<String>,
+    Extension(uploads): Extension<Arc<Uploads>>,
+    Extension(request_sender): Extension<HttpGatewayRequest>,
+) -> Result<Json<UploadedContract>, WebSocketApiError> {
+    let received = uploads.complete(&id, UploadKind::Put)?;
+    let request = match bincode::deserialize::<ContractRequest>(&received) {
+        Ok(request @ ContractRequest::Put { .. }) => request.into_owned(),
+        Ok(_) => {
+            return Err(WebSocketApiError::InvalidParam {
+                error_cause: "uploaded request is not a put".into(),
+            })
+        }
+        Err(err) => {
+            return Err(WebSocketApiError::InvalidParam {
+                error_cause: format!("malformed uploaded request: {err}"),
+            })
+        }
+    };
+    match execute(request_sender, ClientRequest::ContractOp(request)).await? {
+        HostResponse::ContractResponse(ContractResponse::PutResponse { key }) => {
+            Ok(Json(UploadedContract {
+                key: key.encoded_contract_id(),
+            }))
+        }
+        other => Err(WebSocketApiError::NodeError {
+            error_cause: format!("unexpected response: {other}"),
+        }),
+    }
+}
+
+/// Executes the uploaded delegate request, responding with the bincode encoded messages
+/// returned by the delegate.
+pub(super) async fn complete_delegate_upload(
+    Path(id): Path<String>,
+    Extension(uploads): Extension<Arc<Uploads>>,
+    Extension(request_sender): Extension<HttpGatewayRequest>,
+) -> Result<Vec<u8>, WebSocketApiError> {
+    let received = uploads.complete(&id, UploadKind::DelegateInput)?;
+    let request = bincode::deserialize::<DelegateRequest>(&received)
+        .map_err(|err| WebSocketApiError::InvalidParam {
+            error_cause: format!("malformed uploaded request: {err}"),
+        })?
+        .into_owned();
+    match execute(request_sender, ClientRequest::DelegateOp(request)).await? {
+        HostResponse::DelegateResponse { values, .. } => {
+            bincode::serialize(&values).map_err(|err| WebSocketApiError::NodeError {
+                error_cause: err.to_string(),
+            })
+        }
+        other => Err(WebSocketApiError::NodeError {
+            error_cause: format!("unexpected response: {other}"),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use freenet_stdlib::prelude::{
+        ApplicationMessage, CodeHash, ContractCode, ContractContainer, ContractInstanceId,
+        ContractWasmAPIVersion, DelegateKey, InboundDelegateMsg, OutboundDelegateMsg, Parameters,
+        RelatedContracts, WrappedContract, WrappedState,
+    };
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    fn put_request() -> ContractRequest<'static> {
+        let contract = WrappedContract::new(
+            Arc::new(ContractCode::from(vec![1; 64 * 1024])),
+            Parameters::from(vec![]),
+        );
+        ContractRequest::Put {
+            contract: ContractContainer::Wasm(ContractWasmAPIVersion::V1(contract)),
+            state: WrappedState::new(vec![2; 512 * 1024]),
+            related_contracts: RelatedContracts::default(),
+            subscribe: false,
+        }
+    }
+
+    /// Answers puts of the expected size like the node would.
+    async fn node(mut gw: HttpGateway, expected_state: usize) {
+        while let Ok(request) = gw.recv().await {
+            let ClientRequest::ContractOp(ContractRequest::Put {
+                contract, state, ..
+            }) = *request.request
+            else {
+                panic!("unexpected request");
+            };
+            assert_eq!(state.size(), expected_state);
+            gw.send(
+                request.client_id,
+                Ok(ContractResponse::PutResponse {
+                    key: contract.key(),
+                }
+                .into()),
+            )
+            .await
+            .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn resume_interrupted_upload() -> anyhow::Result<()> {
+        let (gw, router) = HttpGateway::as_router(&SocketAddr::from(([127, 0, 0, 1], 0)));
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        tokio::spawn(node(gw, 512 * 1024));
+
+        let request = put_request();
+        let ContractRequest::Put { contract, .. } = &request else {
+            unreachable!()
+        };
+        let expected_key = contract.key().encoded_contract_id();
+        let body = bincode::serialize(&request)?;
+        let chunks: Vec<_> = body.chunks(256 * 1024).collect();
+
+        let client = reqwest::Client::new();
+        let uploads = format!("http://{addr}/v1/contract/upload");
+        let response = client
+            .post(&uploads)
+            .json(&serde_json::json!({ "size": body.len() }))
+            .send()
+            .await?;
+        assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+        let status: serde_json::Value = response.json().await?;
+        let upload = format!("{uploads}/{}", status["id"].as_str().unwrap());
+        assert_eq!(status["offset"], 0);
+
+        let response = client
+            .patch(&upload)
+            .header(UPLOAD_OFFSET, 0)
+            .body(chunks[0].to_vec())
+            .send()
+            .await?;
+        assert_eq!(
+            response.json::<serde_json::Value>().await?["offset"],
+            chunks[0].len()
+        );
+
+        // the connection drops halfway through the second chunk
+        let mut stream = tokio::net::TcpStream::connect(addr).await?;
+        let path = upload.trim_start_matches(&format!("http://{addr}"));
+        stream
+            .write_all(
+                format!(
+                    "PATCH {path} HTTP/1.1\r\nhost: {addr}\r\n{UPLOAD_OFFSET}: {}\r\ncontent-length: {}\r\n\r\n",
+                    chunks[0].len(),
+                    chunks[1].len()
+                )
+                .as_bytes(),
+            )
+            .await?;
+        stream.write_all(&chunks[1][..chunks[1].len() / 2]).await?;
+        drop(stream);
+
+        // completing before everything is there fails
+        let response = client.post(format!("{upload}/complete")).send().await?;
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        // after reconnecting, resume from the acknowledged offset
+        let status: serde_json::Value = client.get(&upload).send().await?.json().await?;
+        let mut offset = status["offset"].as_u64().unwrap() as usize;
+        assert_eq!(offset, chunks[0].len());
+        // sending a chunk for the wrong offset tells where the upload is at
+        let response = client
+            .patch(&upload)
+            .header(UPLOAD_OFFSET, 0)
+            .body(chunks[0].to_vec())
+            .send()
+            .await?;
+        assert_eq!(response.status(), reqwest::StatusCode::CONFLICT);
+        assert_eq!(
+            response.json::<serde_json::Value>().await?["offset"],
+            offset
+        );
+        for chunk in &chunks[1..] {
+            let response = client
+                .patch(&upload)
+                .header(UPLOAD_OFFSET, offset)
+                .body(chunk.to_vec())
+                .send()
+                .await?;
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            offset = response.json::<serde_json::Value>().await?["offset"]
+                .as_u64()
+                .unwrap() as usize;
+        }
+        assert_eq!(offset, body.len());
+
+        let response = client.post(format!("{upload}/complete")).send().await?;
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            response.json::<serde_json::Value>().await?["key"],
+            expected_key
+        );
+        // the upload is done with
+        let response = client.get(&upload).send().await?;
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stream_large_delegate_input() -> anyhow::Result<()> {
+        const INPUT_SIZE: usize = 5 * 1024 * 1024;
+        let (mut gw, router) = HttpGateway::as_router(&SocketAddr::from(([127, 0, 0, 1], 0)));
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        let app = ContractInstanceId::new([1; 32]);
+        tokio::spawn(async move {
+            while let Ok(request) = gw.recv().await {
+                let ClientRequest::DelegateOp(DelegateRequest::ApplicationMessages {
+                    key,
+                    inbound,
+                    ..
+                }) = *request.request
+                else {
+                    panic!("unexpected request");
+                };
+                let [InboundDelegateMsg::ApplicationMessage(msg)] = &inbound[..] else {
+                    panic!("unexpected input");
+                };
+                // the delegate gets the whole input at once
+                assert_eq!(msg.payload.len(), INPUT_SIZE);
+                let values = vec![OutboundDelegateMsg::ApplicationMessage(
+                    ApplicationMessage::new(app, b"received".to_vec()),
+                )];
+                gw.send(
+                    request.client_id,
+                    Ok(HostResponse::DelegateResponse { key, values }),
+                )
+                .await
+                .unwrap();
+            }
+        });
+
+        let request = DelegateRequest::ApplicationMessages {
+            key: DelegateKey::new([2; 32], CodeHash::new([3; 32])),
+            params: Parameters::from(vec![]),
+            inbound: vec![InboundDelegateMsg::ApplicationMessage(
+                ApplicationMessage::new(app, vec![7; INPUT_SIZE]),
+            )],
+        };
+        let body = bincode::serialize(&request)?;
+
+        let client = reqwest::Client::new();
+        let uploads = format!("http://{addr}/v1/delegate/upload");
+        let status: serde_json::Value = client
+            .post(&uploads)
+            .json(&serde_json::json!({ "size": body.len() }))
+            .send()
+            .await?
+            .json()
+            .await?;
+        let upload = format!("{uploads}/{}", status["id"].as_str().unwrap());
+        // well over what the router accepts in a single body
+        for (n, chunk) in body.chunks(1024 * 1024).enumerate() {
+            let response = client
+                .patch(&upload)
+                .header(UPLOAD_OFFSET, n * 1024 * 1024)
+                .body(chunk.to_vec())
+                .send()
+                .await?;
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+        }
+        // not a contract upload
+        let response = client
+            .post(upload.replace("/delegate/", "/contract/") + "/complete")
+            .send()
+            .await?;
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+
+        let response = client.post(format!("{upload}/complete")).send().await?;
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let values: Vec<OutboundDelegateMsg> = bincode::deserialize(&response.bytes().await?)?;
+        let [OutboundDelegateMsg::ApplicationMessage(msg)] = &values[..] else {
+            panic!("unexpected response");
+        };
+        assert_eq!(msg.payload, b"received");
+
+        // delegate inputs are capped below contract uploads
+        let response = client
+            .post(&uploads)
+            .json(&serde_json::json!({ "size": MAX_DELEGATE_INPUT_SIZE + 1 }))
+            .send()
+            .await?;
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[test]
+    fn spill_large_upload_to_disk() -> anyhow::Result<()> {
+        let spill_dir = tempfile::tempdir()?;
+        let uploads = Uploads::spilling_to(1024, spill_dir.path().to_owned());
+        let spilled = || std::fs::read_dir(spill_dir.path()).map(|files| files.count());
+        let body: Vec<u8> = (0..4096u32).map(|n| n as u8).collect();
+        let failed = |err: WebSocketApiError| anyhow::anyhow!("{err}");
+
+        for outcome in ["completed", "rejected"] {
+            let (_, Json(UploadStatus { id, .. })) =
+                uploads.start(UploadKind::Put, body.len()).map_err(failed)?;
+            let mut chunks = body.chunks(768);
+            assert!(uploads
+                .append(&id, 0, chunks.next().unwrap())
+                .map_err(failed)?
+                .is_ok());
+            // still under the threshold
+            assert_eq!(spilled()?, 0);
+            for (n, chunk) in chunks.enumerate() {
+                assert!(uploads
+                    .append(&id, (n + 1) * 768, chunk)
+                    .map_err(failed)?
+                    .is_ok());
+                assert_eq!(spilled()?, 1);
+            }
+            if outcome == "completed" {
+                assert_eq!(
+                    uploads.complete(&id, UploadKind::Put).map_err(failed)?,
+                    body
+                );
+            } else {
+                assert!(uploads.append(&id, body.len(), b"past the size").is_err());
+                uploads.uploads.lock().remove(&id);
+            }
+            assert_eq!(spilled()?, 0, "{outcome} upload left on disk");
+        }
+        Ok(())
+    }
+}
@@ -1,12 +1,19 @@
+use tower_http::timeout::TimeoutLayer;
+
+use super::backup;
+use super::schedule::{self, ScheduledOperations};
+use super::upload::{self, Uploads};
 use super::*;
+use crate::server::admin::{require_admin, AdminAuth};
 
 impl HttpGateway {
     /// Returns the uninitialized axum router with a provided attested_contracts map.
     pub fn create_router_v1_with_attested_contracts(
-        socket: &SocketAddr,
+        api_config: &WebsocketApiConfig,
         attested_contracts: AttestedContractMap,
+        work_queue: Arc<WorkQueueMetrics>,
     ) -> (Self, Router) {
-        let localhost = match socket.ip() {
+        let localhost = match api_config.address {
             IpAddr::V4(ip) if ip.is_loopback() || ip.is_unspecified() => true,
             IpAddr::V6(ip) if ip.is_loopback() || ip.is_unspecified() => true,
             _ => false,
@@ -14,16 +21,109 @@ impl HttpGateway {
         let contract_web_path = std::env::temp_dir().join("freenet").join("webs");
         std::fs::create_dir_all(contract_web_path).unwrap();
 
-        let (proxy_request_sender, request_to_server) = mpsc::channel(1);
+        let (proxy_request_sender, request_to_server) =
+            work_queue::work_queue(1, work_queue.clone());
+        let (executor_sender, executor_commands) = mpsc::channel(1);
+        let commands = ExecutorCommands(executor_sender);
 
-        let config = Config { localhost };
+        let token_expiry = TokenExpiryCheck::new(api_config.token_expiry);
+        let service_tiers = api_config
+            .service_tiers
+            .iter()
+            .filter_map(|(contract, tier)| match contract.parse::<ContractInstanceId>() {
+                Ok(contract) => Some((contract, *tier)),
+                Err(err) => {
+                    tracing::warn!(%contract, %err, "ignoring the service tier of an invalid contract id");
+                    None
+                }
+            })
+            .collect();
+        let config = Config {
+            localhost,
+            token_ttl: token_expiry.ttl(),
+            service_tiers: Arc::new(service_tiers),
+        };
+        let max_path_length = api_config.max_path_length;
+        let asset_store = api_config
+            .asset_store
+            .as_deref()
+            .map(|location| Arc::new(ExternalAssetStore::new(location)));
 
-        let router = Router::new()
-            .route("/v1", get(home))
+        let timeouts = api_config.route_timeouts;
+        let assets = Router::new()
             .route("/v1/contract/web/:key/", get(web_home))
             .with_state(config)
             .route("/v1/contract/web/:key/*path", get(web_subpages))
+            .route_layer(TimeoutLayer::new(timeouts.assets()));
+
+        let admin = Router::new()
+            .route("/v1/admin/contracts/export", get(backup::export_contracts))
+            .route("/v1/admin/contracts/import", post(backup::import_contracts))
+            .route("/v1/admin/queue", get(work_queue_depth))
+            .route(
+                "/v1/admin/contract/:key/validate",
+                post(revalidate_contract),
+            )
+            .route_layer(axum::middleware::from_fn_with_state(
+                AdminAuth::new(api_config.admin_token.clone()),
+                require_admin,
+            ));
+
+        let router = Router::new()
+            .route("/v1", get(home))
+            .route("/v1/contract/metadata/:key", get(contract_metadata))
+            .route("/v1/contract/topics/:key", get(contract_topics))
+            .route("/v1/contract/dependencies/:key", get(contract_dependencies))
+            .route("/v1/contract/items/:key", get(contract_items))
+            .route("/v1/contract/estimate/:key", post(estimate_update))
+            .route("/v1/contract/history/:key/:version", get(historical_state))
+            .route("/v1/contract/diff/:key/:base", get(state_diff))
+            .route("/v1/contract/transaction", post(apply_transaction))
+            .route("/v1/contract/code/:key", get(contract_code))
+            .route(
+                "/v1/contract/schedule/:key",
+                post(schedule::schedule_update),
+            )
+            .route(
+                "/v1/contract/scheduled/:id",
+                delete(schedule::cancel_scheduled),
+            )
+            .route("/v1/contract/upload", post(upload::start_upload))
+            .route(
+                "/v1/contract/upload/:id",
+                get(upload::upload_status).patch(upload::upload_chunk),
+            )
+            .route(
+                "/v1/contract/upload/:id/complete",
+                post(upload::complete_upload),
+            )
+            .route("/v1/delegate/upload", post(upload::start_delegate_upload))
+            .route(
+                "/v1/delegate/upload/:id",
+                get(upload::upload_status).patch(upload::upload_chunk),
+            )
+            .route(
+                "/v1/delegate/upload/:id/complete",
+                post(upload::complete_delegate_upload),
+            )
+            .route_layer(TimeoutLayer::new(timeouts.api()))
+            .merge(assets)
+            .route_layer(axum::middleware::from_fn(
+                move |req: axum::extract::Request, next: axum::middleware::Next| {
+                    limit_path_length(max_path_length, req, next)
+                },
+            ))
+            .merge(admin)
+            .fallback(not_found)
             .layer(Extension(attested_contracts.clone()))
+            .layer(Extension(token_expiry.clone()))
+            .layer(Extension(work_queue))
+            .layer(Extension(asset_store))
+            .layer(Extension(Arc::new(Uploads::new(
+                api_config.upload_spill_threshold_bytes,
+            ))))
+            .layer(Extension(Arc::new(ScheduledOperations::default())))
+            .layer(Extension(commands.clone()))
             .layer(Extension(HttpGatewayRequest(proxy_request_sender)));
 
         (
@@ -31,6 +131,10 @@ impl HttpGateway {
                 proxy_server_request: request_to_server,
                 attested_contracts: attested_contracts.clone(),
                 response_channels: HashMap::new(),
+                response_transformer: Arc::new(IdentityTransformer),
+                executor_commands,
+                commands,
+                token_expiry,
             },
             router,
         )
@@ -48,14 +152,21 @@ async fn web_home(
         .localhost
         .then_some("localhost")
         .expect("non-local connections not supported yet");
-    let token = AuthToken::generate();
+    let tier = key
+        .parse::<ContractInstanceId>()
+        .ok()
+        .and_then(|contract| config.service_tiers.get(&contract).copied())
+        .unwrap_or_default();
+    let token = AuthToken::generate_with_tier(tier);
 
     let auth_header = headers::Authorization::<headers::authorization::Bearer>::name().to_string();
     let cookie = cookie::Cookie::build((auth_header, format!("Bearer {}", token.as_str())))
         .domain(domain)
         .path(format!("/v1/contract/web/{key}"))
         .same_site(cookie::SameSite::Strict)
-        .max_age(cookie::time::Duration::days(1))
+        .max_age(cookie::time::Duration::seconds(
+            config.token_ttl.as_secs().try_into().unwrap_or(i64::MAX),
+        ))
         .secure(!config.localhost)
         .http_only(false)
         .build();
@@ -78,9 +189,10 @@ async fn web_home(
 
 async fn web_subpages(
     Path((key, last_path)): Path<(String, String)>,
+    Extension(asset_store): Extension<Option<Arc<ExternalAssetStore>>>,
 ) -> Result<axum::response::Response, WebSocketApiError> {
     let full_path: String = format!("/v1/contract/web/{}/{}", key, last_path);
-    path_handlers::variable_content(key, full_path)
+    path_handlers::variable_content(key, full_path, asset_store)
         .await
         .map_err(|e| *e)
         .map(|r| r.into_response())
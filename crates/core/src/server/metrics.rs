@@ -0,0 +1,201 @@
+//! Metrics of the gateway read in process, for embedders feeding them to their own monitoring
+//! instead of scraping the admin endpoints.
+//!
+//! [`serve_gateway_with_metrics`](super::serve_gateway_with_metrics) hands out a
+//! [`GatewayMetrics`] handle along with the clients, every [`GatewayMetrics::snapshot`] taken
+//! from it is the state of the gateway at that time. Latencies are those of the node answering
+//! websocket clients, from the moment it picks a request up until it sends the response.
+//!
+//! A [`LoadSummary`] of the same metrics is served at `/v1/load`, for clients balancing their
+//! requests across nodes to route them by the load of each.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use super::work_queue::WorkQueueMetrics;
+
+/// Weight of the requests answered before the last in the recent latency, as a power of two.
+const RECENT_LATENCY_DECAY: u32 = 3;
+
+#[derive(Debug, Default)]
+struct Counters {
+    connections: AtomicUsize,
+    answered: AtomicU64,
+    total_latency_micros: AtomicU64,
+    max_latency_micros: AtomicU64,
+    recent_latency_micros: AtomicU64,
+}
+
+/// Handle to the metrics of a running gateway, cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct GatewayMetrics {
+    work_queue: Arc<WorkQueueMetrics>,
+    counters: Arc<Counters>,
+}
+
+/// The metrics of the gateway at the time the snapshot was taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Websocket connections currently open.
+    pub connections: usize,
+    /// Requests received from clients of both the HTTP gateway and the websocket API.
+    pub requests: u64,
+    /// Requests waiting for the node to pick them up.
+    pub queued: usize,
+    /// Requests cancelled for the node being overloaded.
+    pub cancelled: u64,
+    /// Results dropped for the client they were for having disconnected in the meantime.
+    pub orphaned: u64,
+    /// Requests of websocket clients answered by the node.
+    pub answered: u64,
+    pub mean_latency: Duration,
+    pub max_latency: Duration,
+}
+
+/// How loaded the node is at the moment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoadSummary {
+    /// Requests waiting for the node to pick them up.
+    pub queued: usize,
+    /// Websocket connections currently open.
+    pub connections: usize,
+    /// Latency of the requests answered lately, weighing the most recent the most.
+    pub latency_ms: u64,
+}
+
+impl GatewayMetrics {
+    pub(crate) fn new(work_queue: Arc<WorkQueueMetrics>) -> Self {
+        Self {
+            work_queue,
+            counters: Arc::default(),
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let work_queue = self.work_queue.snapshot();
+        let counters = &*self.counters;
+        let answered = counters.answered.load(Ordering::Acquire);
+        let total_latency = counters.total_latency_micros.load(Ordering::Acquire);
+        MetricsSnapshot {
+            connections: counters.connections.load(Ordering::Acquire),
+            requests: work_queue.enqueued,
+            queued: work_queue.depth,
+            cancelled: work_queue.cancelled,
+            orphaned: work_queue.orphaned,
+            answered,
+            mean_latency: Duration::from_micros(total_latency.checked_div(answered).unwrap_or(0)),
+            max_latency: Duration::from_micros(counters.max_latency_micros.load(Ordering::Acquire)),
+        }
+    }
+
+    pub fn load(&self) -> LoadSummary {
+        let counters = &*self.counters;
+        LoadSummary {
+            queued: self.work_queue.snapshot().depth,
+            connections: counters.connections.load(Ordering::Acquire),
+            latency_ms: counters.recent_latency_micros.load(Ordering::Acquire) / 1000,
+        }
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        self.counters.connections.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        self.counters.connections.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    /// A request was answered `latency` after the node picked it up.
+    pub(crate) fn answered(&self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        let counters = &*self.counters;
+        counters.answered.fetch_add(1, Ordering::AcqRel);
+        counters
+            .total_latency_micros
+            .fetch_add(micros, Ordering::AcqRel);
+        counters
+            .max_latency_micros
+            .fetch_max(micros, Ordering::AcqRel);
+        let _ = counters.recent_latency_micros.fetch_update(
+            Ordering::AcqRel,
+            Ordering::Acquire,
+            |recent| {
+                let recent = if recent == 0 {
+                    micros
+                } else {
+                    recent - (recent >> RECENT_LATENCY_DECAY) + (micros >> RECENT_LATENCY_DECAY)
+                };
+                Some(recent)
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_of_activity() {
+        let metrics = GatewayMetrics::default();
+        assert_eq!(metrics.snapshot().mean_latency, Duration::ZERO);
+
+        metrics.connection_opened();
+        metrics.connection_opened();
+        metrics.connection_closed();
+        metrics.answered(Duration::from_millis(10));
+        metrics.answered(Duration::from_millis(30));
+
+        let snapshot = metrics.clone().snapshot();
+        assert_eq!(snapshot.connections, 1);
+        assert_eq!(snapshot.answered, 2);
+        assert_eq!(snapshot.mean_latency, Duration::from_millis(20));
+        assert_eq!(snapshot.max_latency, Duration::from_millis(30));
+    }
+
+    #[test]
+    fn load_follows_current_activity() {
+        let work_queue = Arc::new(WorkQueueMetrics::default());
+        let metrics = GatewayMetrics::new(work_queue.clone());
+        assert_eq!(
+            metrics.load(),
+            LoadSummary {
+                queued: 0,
+                connections: 0,
+                latency_ms: 0,
+            }
+        );
+
+        metrics.connection_opened();
+        work_queue.hold();
+        work_queue.hold();
+        metrics.answered(Duration::from_millis(400));
+        let load = metrics.load();
+        assert_eq!((load.queued, load.connections), (2, 1));
+        assert_eq!(load.latency_ms, 400);
+
+        // recent requests outweigh the slow one as they come in, the mean since the start
+        // doesn't cover
+        for _ in 0..32 {
+            metrics.answered(Duration::from_millis(8));
+        }
+        work_queue.release();
+        metrics.connection_closed();
+        let load = metrics.load();
+        assert_eq!((load.queued, load.connections), (1, 0));
+        assert!(load.latency_ms < 20, "{load:?}");
+        assert!(metrics.snapshot().mean_latency > Duration::from_millis(15));
+        assert_eq!(
+            serde_json::to_value(load).unwrap(),
+            serde_json::json!({ "queued": 1, "connections": 0, "latencyMs": load.latency_ms })
+        );
+    }
+}
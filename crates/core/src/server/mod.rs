@@ -6,30 +6,56 @@
 //!
 //! See [`../architecture.md`](../architecture.md) for its place in the overall architecture.
 
+pub(crate) mod admin;
 pub(crate) mod app_packaging;
+pub(crate) mod asset_store;
+pub(crate) mod client_addr;
 pub(crate) mod errors;
+#[cfg(feature = "grpc")]
+pub(crate) mod grpc;
 pub(crate) mod http_gateway;
+pub(crate) mod metrics;
 pub(crate) mod path_handlers;
+pub(crate) mod shutdown;
+pub(crate) mod tls;
+pub(crate) mod token_expiry;
+pub(crate) mod work_queue;
 
 use std::collections::HashMap;
+use std::future::Future;
 use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
+use anyhow::Context;
 use freenet_stdlib::{
     client_api::{ClientError, ClientRequest, HostResponse},
     prelude::*,
 };
 
 use http_gateway::HttpGateway;
+use hyper::server::conn::http1;
+use hyper_util::{
+    rt::{TokioIo, TokioTimer},
+    service::TowerToHyperService,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{oneshot, watch},
+    task::JoinSet,
+};
 use tower_http::trace::TraceLayer;
 
 use crate::{
     client_events::{websocket::WebSocketProxy, AuthToken, BoxedClient, ClientId, HostResult},
     config::WebsocketApiConfig,
 };
+use tls::GatewayTls;
 
 use crate::server::http_gateway::AttestedContractMap;
+use crate::server::work_queue::WorkQueueMetrics;
 pub use app_packaging::WebApp;
+pub use metrics::{GatewayMetrics, LoadSummary, MetricsSnapshot};
 
 #[derive(Debug)]
 #[allow(clippy::large_enum_variant)]
@@ -44,6 +70,12 @@ pub(crate) enum ClientConnection {
         auth_token: Option<AuthToken>,
         attested_contract: Option<ContractInstanceId>,
     },
+    /// The client dropped its subscription to the contract, closing the channel the node
+    /// notifies it through, which the node drops along with the subscription.
+    Unsubscribed {
+        client_id: ClientId,
+        key: ContractKey,
+    },
 }
 
 #[derive(Debug)]
@@ -62,23 +94,235 @@ pub(crate) enum HostCallbackResult {
     },
 }
 
-fn serve(socket: SocketAddr, router: axum::Router) {
+/// Hook to post-process the responses sent back to clients, e.g. to rewrite their content.
+///
+/// It is invoked by both the HTTP gateway and the websocket proxy for every response right
+/// before it is handed to the client connection, and for every notification of the contracts
+/// a client subscribed to before it is sent over the websocket connection.
+pub trait ResponseTransformer: Send + Sync {
+    fn transform(
+        &self,
+        client: ClientId,
+        response: Result<HostResponse, ClientError>,
+    ) -> Result<HostResponse, ClientError>;
+}
+
+/// Sends responses as produced by the node.
+pub struct IdentityTransformer;
+
+impl ResponseTransformer for IdentityTransformer {
+    fn transform(
+        &self,
+        _client: ClientId,
+        response: Result<HostResponse, ClientError>,
+    ) -> Result<HostResponse, ClientError> {
+        response
+    }
+}
+
+/// Keeps the HTTP server running, dropping it shuts the server down.
+#[derive(Debug)]
+pub(crate) struct ServerHandle {
+    stop: Option<oneshot::Sender<()>>,
+    state: ServerStateWatch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ServerState {
+    Serving,
+    /// No longer accepting connections, waiting for the in-flight requests.
+    Draining,
+    Stopped,
+}
+
+impl ServerHandle {
+    /// Stops accepting connections and drains the open ones.
+    pub fn stop_accepting(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+
+    pub fn state(&self) -> ServerStateWatch {
+        self.state.clone()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ServerStateWatch(watch::Receiver<ServerState>);
+
+impl ServerStateWatch {
+    /// Waits for the server to move on from the state it was last seen in.
+    pub async fn changed(&mut self) -> ServerState {
+        if self.0.changed().await.is_err() {
+            return ServerState::Stopped;
+        }
+        *self.0.borrow_and_update()
+    }
+}
+
+fn serve(
+    socket: SocketAddr,
+    router: axum::Router,
+    tls: Option<GatewayTls>,
+    keep_alive_timeout: Duration,
+    max_header_size: usize,
+    shutdown_deadline: Duration,
+) -> ServerHandle {
+    let (stop, stopped) = oneshot::channel::<()>();
+    let (state, state_rx) = watch::channel(ServerState::Serving);
     tokio::spawn(async move {
         tracing::info!("HTTP gateway listening on {}", socket);
         let listener = tokio::net::TcpListener::bind(socket).await.unwrap();
-        axum::serve(listener, router).await.map_err(|e| {
-            tracing::error!("Error while running HTTP gateway server: {e}");
-        })
+        let shutdown = async {
+            let _ = stopped.await;
+            let _ = state.send(ServerState::Draining);
+        };
+        serve_connections(
+            listener,
+            router,
+            tls,
+            keep_alive_timeout,
+            max_header_size,
+            shutdown,
+            shutdown_deadline,
+        )
+        .await;
+        let _ = state.send(ServerState::Stopped);
     });
+    ServerHandle {
+        stop: Some(stop),
+        state: ServerStateWatch(state_rx),
+    }
+}
+
+/// Accepts connections on the listener, a connection left idle between requests for longer
+/// than `keep_alive_timeout` is closed. With `tls` clients are given as long to complete the
+/// handshake, those failing it are dropped. Requests whose head is over `max_header_size`
+/// bytes, no less than 8 KiB, are answered with 431 and the connection closed.
+///
+/// Once `shutdown` completes no more connections are accepted and the open ones are asked to
+/// close after their in-flight requests; those still open after `shutdown_deadline` are
+/// abandoned. Returns how many were.
+async fn serve_connections(
+    listener: tokio::net::TcpListener,
+    router: axum::Router,
+    tls: Option<GatewayTls>,
+    keep_alive_timeout: Duration,
+    max_header_size: usize,
+    shutdown: impl Future<Output = ()>,
+    shutdown_deadline: Duration,
+) -> usize {
+    let mut builder = http1::Builder::new();
+    // the header read timer starts as soon as the connection waits for the next request
+    builder
+        .timer(TokioTimer::new())
+        .header_read_timeout(keep_alive_timeout)
+        // the smallest buffer hyper accepts
+        .max_buf_size(max_header_size.max(8192));
+    let (closing, _) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    let mut shutdown = std::pin::pin!(shutdown);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+            // reaps the connections already closed
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!("Error while accepting HTTP gateway connection: {e}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let router = router
+            .clone()
+            .layer(axum::Extension(client_addr::PeerAddr(peer)));
+        let builder = builder.clone();
+        let tls = tls.clone();
+        let mut closing = closing.subscribe();
+        connections.spawn(async move {
+            let result = match tls {
+                None => {
+                    let service = TowerToHyperService::new(router);
+                    serve_connection(&builder, stream, service, &mut closing).await
+                }
+                Some(tls) => match tls.accept(stream, keep_alive_timeout).await {
+                    Ok((stream, identity)) => {
+                        let router = match identity {
+                            Some(identity) => router.layer(axum::Extension(identity)),
+                            None => router,
+                        };
+                        let service = TowerToHyperService::new(router);
+                        serve_connection(&builder, stream, service, &mut closing).await
+                    }
+                    Err(e) => {
+                        tracing::debug!(%peer, reason = %e, "TLS handshake with HTTP gateway client failed");
+                        return;
+                    }
+                },
+            };
+            if let Err(e) = result {
+                tracing::debug!("HTTP gateway connection closed: {e}");
+            }
+        });
+    }
+
+    // refuses new connections instead of leaving them in the backlog while draining
+    drop(listener);
+    tracing::info!(
+        connections = connections.len(),
+        "HTTP gateway shutting down, waiting for in-flight requests"
+    );
+    let _ = closing.send(true);
+    let drained = tokio::time::timeout(shutdown_deadline, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_ok() {
+        return 0;
+    }
+    let abandoned = connections.len();
+    tracing::warn!(
+        abandoned,
+        deadline = ?shutdown_deadline,
+        "HTTP gateway shutdown deadline reached, closing the remaining connections"
+    );
+    connections.shutdown().await;
+    abandoned
+}
+
+async fn serve_connection(
+    builder: &http1::Builder,
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    service: TowerToHyperService<axum::Router>,
+    closing: &mut watch::Receiver<bool>,
+) -> hyper::Result<()> {
+    let connection = builder
+        .serve_connection(TokioIo::new(stream), service)
+        .with_upgrades();
+    let mut connection = std::pin::pin!(connection);
+    tokio::select! {
+        result = connection.as_mut() => result,
+        _ = closing.changed() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    }
 }
 
 pub mod local_node {
     use freenet_stdlib::client_api::{ClientRequest, ErrorKind};
     use std::net::{IpAddr, SocketAddr};
+    use std::time::Duration;
     use tower_http::trace::TraceLayer;
 
     use crate::{
         client_events::{websocket::WebSocketProxy, ClientEventsProxy, OpenRequest},
+        config::WebsocketApiConfig,
         contract::{Executor, ExecutorError},
     };
 
@@ -97,7 +341,17 @@ pub mod local_node {
         let (mut gw, gw_router) = HttpGateway::as_router(&socket);
         let (mut ws_proxy, ws_router) = WebSocketProxy::create_router(gw_router);
 
-        serve(socket, ws_router.layer(TraceLayer::new_for_http()));
+        let defaults = WebsocketApiConfig::default();
+        let _server = serve(
+            socket,
+            ws_router
+                .layer(axum::Extension(gw.executor_commands()))
+                .layer(TraceLayer::new_for_http()),
+            None,
+            Duration::from_secs(defaults.http_keep_alive_timeout_secs),
+            defaults.max_header_size,
+            Duration::from_secs(defaults.shutdown_deadline_secs),
+        );
 
         // TODO: use combinator instead
         // let mut all_clients =
@@ -134,13 +388,7 @@ pub mod local_node {
                         .await
                 }
                 ClientRequest::DelegateOp(op) => {
-                    let attested_contract = token.and_then(|token| {
-                        gw.attested_contracts
-                            .read()
-                            .map(|guard| guard.get(&token).cloned().map(|(t, _)| t))
-                            .ok()
-                            .flatten()
-                    });
+                    let attested_contract = token.and_then(|token| gw.attested_contract(&token));
                     executor.delegate_request(op, attested_contract.as_ref())
                 }
                 ClientRequest::Disconnect { cause } => {
@@ -151,7 +399,7 @@ pub mod local_node {
                     if let Ok(mut guard) = gw.attested_contracts.write() {
                         if let Some(rm_token) = guard
                             .iter()
-                            .find_map(|(k, (_, eid))| (eid == &id).then(|| k.clone()))
+                            .find_map(|(k, (_, eid, _))| (eid == &id).then(|| k.clone()))
                         {
                             guard.remove(&rm_token);
                         }
@@ -199,26 +447,436 @@ pub mod local_node {
     }
 }
 
-pub async fn serve_gateway(config: WebsocketApiConfig) -> [BoxedClient; 2] {
-    let (gw, ws_proxy) = serve_gateway_in(config).await;
-    [Box::new(gw), Box::new(ws_proxy)]
+pub async fn serve_gateway(config: WebsocketApiConfig) -> anyhow::Result<[BoxedClient; 2]> {
+    let (gw, ws_proxy) = serve_gateway_in(config, None).await?;
+    Ok([Box::new(gw), Box::new(ws_proxy)])
 }
 
-pub(crate) async fn serve_gateway_in(config: WebsocketApiConfig) -> (HttpGateway, WebSocketProxy) {
+/// Same as [`serve_gateway`] but every response goes through `transformer` before being sent.
+pub async fn serve_gateway_with_transformer(
+    config: WebsocketApiConfig,
+    transformer: Arc<dyn ResponseTransformer>,
+) -> anyhow::Result<[BoxedClient; 2]> {
+    let (gw, ws_proxy) = serve_gateway_in(config, Some(transformer)).await?;
+    Ok([Box::new(gw), Box::new(ws_proxy)])
+}
+
+/// Same as [`serve_gateway`], also returning the handle to read the metrics of the gateway
+/// from while it runs.
+pub async fn serve_gateway_with_metrics(
+    config: WebsocketApiConfig,
+) -> anyhow::Result<([BoxedClient; 2], GatewayMetrics)> {
+    let (gw, ws_proxy) = serve_gateway_in(config, None).await?;
+    let metrics = ws_proxy.metrics();
+    Ok(([Box::new(gw), Box::new(ws_proxy)], metrics))
+}
+
+pub(crate) async fn serve_gateway_in(
+    config: WebsocketApiConfig,
+    response_transformer: Option<Arc<dyn ResponseTransformer>>,
+) -> anyhow::Result<(HttpGateway, WebSocketProxy)> {
     let ws_socket = (config.address, config.port).into();
+    crate::tracing::redact::redact_fields(&config.redacted_log_fields);
+    let tls = config
+        .tls
+        .as_ref()
+        .map(GatewayTls::new)
+        .transpose()
+        .context("failed to load the TLS certificate of the HTTP gateway")?;
 
     // Create a shared attested_contracts map
     let attested_contracts: AttestedContractMap = Arc::new(RwLock::new(HashMap::<
         AuthToken,
-        (ContractInstanceId, ClientId),
+        (ContractInstanceId, ClientId, std::time::Instant),
     >::new()));
 
+    // Both proxies feed the same node, so their pending requests are accounted together
+    let work_queue = Arc::new(WorkQueueMetrics::new(
+        config.load_shedding.cancel_queue_depth,
+    ));
+
+    token_expiry::prune_expired(
+        &attested_contracts,
+        token_expiry::TokenExpiryCheck::new(config.token_expiry),
+    );
+
     // Pass the shared map to both HttpGateway and WebSocketProxy
-    let (gw, gw_router) =
-        HttpGateway::as_router_with_attested_contracts(&ws_socket, attested_contracts.clone());
-    let (ws_proxy, ws_router) =
-        WebSocketProxy::create_router_with_attested_contracts(gw_router, attested_contracts);
+    let (gw, gw_router) = HttpGateway::as_router_with_attested_contracts(
+        &config,
+        attested_contracts.clone(),
+        work_queue.clone(),
+    );
+    let (ws_proxy, ws_router) = WebSocketProxy::create_router_with_attested_contracts(
+        gw_router,
+        &config,
+        attested_contracts.clone(),
+        work_queue,
+    );
+
+    let response_transformer =
+        response_transformer.unwrap_or_else(|| Arc::new(IdentityTransformer));
+    let trusted_proxies = Arc::new(client_addr::TrustedProxies::new(
+        config.trusted_proxies.clone(),
+    ));
+    let server = serve(
+        ws_socket,
+        ws_router
+            .layer(axum::Extension(response_transformer.clone()))
+            .layer(axum::Extension(gw.executor_commands()))
+            .layer(axum::middleware::from_fn_with_state(
+                trusted_proxies,
+                client_addr::resolve,
+            ))
+            .layer(TraceLayer::new_for_http()),
+        tls,
+        Duration::from_secs(config.http_keep_alive_timeout_secs),
+        config.max_header_size,
+        Duration::from_secs(config.shutdown_deadline_secs),
+    );
+    let ws_proxy = ws_proxy.with_server(server);
+    #[cfg(feature = "grpc")]
+    let ws_proxy = serve_grpc(&config, ws_proxy, attested_contracts)?;
+    Ok((
+        gw.with_response_transformer(response_transformer.clone()),
+        ws_proxy.with_response_transformer(response_transformer),
+    ))
+}
+
+/// Serves the gRPC interface along with the websocket API, when configured.
+#[cfg(feature = "grpc")]
+fn serve_grpc(
+    config: &WebsocketApiConfig,
+    mut ws_proxy: WebSocketProxy,
+    attested_contracts: AttestedContractMap,
+) -> anyhow::Result<WebSocketProxy> {
+    let (Some(port), Some(requests)) = (config.grpc_port, ws_proxy.take_grpc_requests()) else {
+        return Ok(ws_proxy);
+    };
+    let server = grpc::serve(
+        (config.address, port).into(),
+        config,
+        requests,
+        attested_contracts,
+    )
+    .context("failed to load the TLS certificate of the gRPC interface")?;
+    Ok(ws_proxy.with_grpc_server(server))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    const DEFAULT_MAX_HEADER_SIZE: usize = 64 * 1024;
+
+    #[tokio::test]
+    async fn oversized_headers_rejected() -> anyhow::Result<()> {
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        let router = axum::Router::new().route("/", axum::routing::get(|| async { "ok" }));
+        tokio::spawn(serve_connections(
+            listener,
+            router,
+            None,
+            Duration::from_secs(5),
+            16 * 1024,
+            std::future::pending(),
+            Duration::ZERO,
+        ));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await?;
+        let request = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nX-Padding: {}\r\n\r\n",
+            "x".repeat(100 * 1024)
+        );
+        // the server may stop reading and answer before the whole request is written
+        let _ = stream.write_all(request.as_bytes()).await;
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response)).await??;
+        assert!(
+            response.starts_with(b"HTTP/1.1 431"),
+            "{}",
+            String::from_utf8_lossy(&response)
+        );
+
+        // requests within the limit keep being served
+        let mut stream = tokio::net::TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn idle_connection_is_closed() -> anyhow::Result<()> {
+        const KEEP_ALIVE: Duration = Duration::from_millis(200);
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        let router = axum::Router::new().route("/", axum::routing::get(|| async { "ok" }));
+        tokio::spawn(serve_connections(
+            listener,
+            router,
+            None,
+            KEEP_ALIVE,
+            DEFAULT_MAX_HEADER_SIZE,
+            std::future::pending(),
+            Duration::ZERO,
+        ));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        while !response.ends_with(b"ok") {
+            let read = stream.read(&mut buf).await?;
+            anyhow::ensure!(read > 0, "connection closed before the response");
+            response.extend_from_slice(&buf[..read]);
+        }
+        assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+
+        // kept alive after the response, until idle for long enough
+        let idle_since = Instant::now();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await??;
+        assert_eq!(read, 0);
+        assert!(idle_since.elapsed() >= KEEP_ALIVE);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stuck_request_abandoned_at_deadline() -> anyhow::Result<()> {
+        const DEADLINE: Duration = Duration::from_millis(200);
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        let (started, mut stuck) = tokio::sync::mpsc::unbounded_channel();
+        let router = axum::Router::new().route(
+            "/stuck",
+            axum::routing::get(move || {
+                let _ = started.send(());
+                std::future::pending::<&'static str>()
+            }),
+        );
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve_connections(
+            listener,
+            router,
+            None,
+            Duration::from_secs(30),
+            DEFAULT_MAX_HEADER_SIZE,
+            async {
+                let _ = stopped.await;
+            },
+            DEADLINE,
+        ));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"GET /stuck HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+        stuck.recv().await;
+        let idle = tokio::net::TcpStream::connect(addr).await?;
 
-    serve(ws_socket, ws_router.layer(TraceLayer::new_for_http()));
-    (gw, ws_proxy)
+        drop(stop);
+        let shutdown_at = Instant::now();
+        let abandoned = tokio::time::timeout(Duration::from_secs(5), server).await??;
+        assert!(shutdown_at.elapsed() >= DEADLINE);
+        // the idle connection closed right away, the stuck one had to be aborted
+        assert_eq!(abandoned, 1);
+        let mut buf = [0; 1024];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await??;
+        assert_eq!(read, 0);
+        drop(idle);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn survives_failed_tls_handshakes() -> anyhow::Result<()> {
+        use tokio_rustls::rustls::{
+            crypto::ring, pki_types::ServerName, ClientConfig, RootCertStore,
+        };
+
+        let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let config = crate::config::TlsConfig {
+            cert_file: fixtures.join("gateway-cert.pem"),
+            key_file: fixtures.join("gateway-key.pem"),
+            client_ca_file: None,
+            allowed_clients: vec![],
+            client_contracts: Default::default(),
+        };
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        let router = axum::Router::new().route("/", axum::routing::get(|| async { "ok" }));
+        tokio::spawn(serve_connections(
+            listener,
+            router,
+            Some(GatewayTls::new(&config)?),
+            Duration::from_secs(5),
+            DEFAULT_MAX_HEADER_SIZE,
+            std::future::pending(),
+            Duration::ZERO,
+        ));
+
+        // neither garbage nor plain HTTP get past the handshake, the server drops them
+        for garbage in [
+            &b"\x16\x03\x01\xff\xffnot a client hello"[..],
+            b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        ] {
+            let mut stream = tokio::net::TcpStream::connect(addr).await?;
+            stream.write_all(garbage).await?;
+            let mut buf = Vec::new();
+            let _ =
+                tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf)).await?;
+        }
+
+        let mut roots = RootCertStore::empty();
+        let certs = std::fs::read(&config.cert_file)?;
+        for cert in rustls_pemfile::certs(&mut certs.as_slice()) {
+            roots.add(cert?)?;
+        }
+        let client = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = tokio::net::TcpStream::connect(addr).await?;
+        let mut stream = tokio_rustls::TlsConnector::from(Arc::new(client))
+            .connect(ServerName::try_from("localhost")?, stream)
+            .await?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        while !response.ends_with(b"ok") {
+            let read = stream.read(&mut buf).await?;
+            anyhow::ensure!(read > 0, "connection closed before the response");
+            response.extend_from_slice(&buf[..read]);
+        }
+        assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serving_fails_without_certificate() {
+        let missing = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/missing");
+        let config = WebsocketApiConfig {
+            tls: Some(crate::config::TlsConfig {
+                cert_file: missing.join("cert.pem"),
+                key_file: missing.join("key.pem"),
+                client_ca_file: None,
+                allowed_clients: vec![],
+                client_contracts: Default::default(),
+            }),
+            ..WebsocketApiConfig::from(SocketAddr::from(([127, 0, 0, 1], 0)))
+        };
+        let Err(err) = serve_gateway_in(config, None).await else {
+            panic!("served without a certificate");
+        };
+        assert!(err.to_string().contains("TLS certificate"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn client_certificates_verified() -> anyhow::Result<()> {
+        use tokio_rustls::rustls::{
+            crypto::ring, pki_types::ServerName, ClientConfig, RootCertStore,
+        };
+
+        use super::tls::ClientIdentity;
+
+        let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let contract = freenet_stdlib::prelude::ContractInstanceId::new([1; 32]);
+        let gateway = |allowed: &[&str]| {
+            let config = crate::config::TlsConfig {
+                cert_file: fixtures.join("gateway-cert.pem"),
+                key_file: fixtures.join("gateway-key.pem"),
+                client_ca_file: Some(fixtures.join("client-ca.pem")),
+                allowed_clients: allowed.iter().map(|name| name.to_string()).collect(),
+                client_contracts: [("mail-app.clients.test".into(), contract.to_string())].into(),
+            };
+            GatewayTls::new(&config)
+        };
+        let serve = |tls| async {
+            let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+            let addr = listener.local_addr()?;
+            let router = axum::Router::new().route(
+                "/",
+                axum::routing::get(
+                    |axum::Extension(identity): axum::Extension<ClientIdentity>| async move {
+                        format!(
+                            "{} {:?}",
+                            identity.name,
+                            identity.contract.map(|c| c.to_string())
+                        )
+                    },
+                ),
+            );
+            tokio::spawn(serve_connections(
+                listener,
+                router,
+                Some(tls),
+                Duration::from_secs(5),
+                DEFAULT_MAX_HEADER_SIZE,
+                std::future::pending(),
+                Duration::ZERO,
+            ));
+            anyhow::Ok(addr)
+        };
+
+        let mut roots = RootCertStore::empty();
+        let certs = std::fs::read(fixtures.join("gateway-cert.pem"))?;
+        for cert in rustls_pemfile::certs(&mut certs.as_slice()) {
+            roots.add(cert?)?;
+        }
+        let client = |cert: Option<&str>| -> anyhow::Result<ClientConfig> {
+            let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()?
+                .with_root_certificates(roots.clone());
+            let Some(name) = cert else {
+                return Ok(config.with_no_client_auth());
+            };
+            let certs = std::fs::read(fixtures.join(format!("{name}-cert.pem")))?;
+            let certs = rustls_pemfile::certs(&mut certs.as_slice()).collect::<Result<_, _>>()?;
+            let key = std::fs::read(fixtures.join(format!("{name}-key.pem")))?;
+            let key = rustls_pemfile::private_key(&mut key.as_slice())?.expect("key");
+            Ok(config.with_client_auth_cert(certs, key)?)
+        };
+        let get = |addr: SocketAddr, client: ClientConfig| async move {
+            let stream = tokio::net::TcpStream::connect(addr).await?;
+            let mut stream = tokio_rustls::TlsConnector::from(Arc::new(client))
+                .connect(ServerName::try_from("localhost")?, stream)
+                .await?;
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .await?;
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await?;
+            anyhow::Ok(String::from_utf8(response)?)
+        };
+
+        let attested = format!("mail-app.clients.test {:?}", Some(contract.to_string()));
+        let addr = serve(gateway(&["mail-app.clients.test"])?).await?;
+        let response = get(addr, client(Some("client"))?).await?;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with(&attested), "{response}");
+        // issued by another authority, or no certificate at all
+        assert!(get(addr, client(Some("rogue-client"))?).await.is_err());
+        assert!(get(addr, client(None)?).await.is_err());
+
+        // issued by the authority but not for an allowed client
+        let addr = serve(gateway(&["other-app.clients.test"])?).await?;
+        assert!(get(addr, client(Some("client"))?).await.is_err());
+
+        // any client of the authority, identified by the name its certificate is for
+        let addr = serve(gateway(&[])?).await?;
+        let response = get(addr, client(Some("client"))?).await?;
+        assert!(response.ends_with(&attested), "{response}");
+        assert!(get(addr, client(Some("rogue-client"))?).await.is_err());
+        Ok(())
+    }
 }
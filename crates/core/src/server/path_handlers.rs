@@ -1,6 +1,9 @@
 //! Handle the `web` part of the bundles.
 
-use std::path::{Path, PathBuf};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use axum::response::{Html, IntoResponse};
 use freenet_stdlib::{
@@ -12,7 +15,8 @@ use tokio::{fs::File, io::AsyncReadExt, sync::mpsc};
 use crate::client_events::AuthToken;
 
 use super::{
-    app_packaging::{WebApp, WebContractError},
+    app_packaging::{WebApp, WebContractError, UNAVAILABLE_ASSETS},
+    asset_store::ExternalAssetStore,
     errors::WebSocketApiError,
     http_gateway::HttpGatewayRequest,
     ClientConnection, HostCallbackResult,
@@ -86,6 +90,11 @@ pub(super) async fn contract_home(
                 })),
             ..
         }) => match contract {
+            // the contract is there but holds nothing yet, there is no web app to serve
+            Some(_) if state.as_ref().is_empty() => {
+                debug!(contract = %key, "contract state is empty");
+                axum::http::StatusCode::NO_CONTENT.into_response()
+            }
             Some(contract) => {
                 let key = contract.key();
                 let path = contract_web_path(&key);
@@ -126,7 +135,14 @@ pub(super) async fn contract_home(
 
                     let mut web =
                         WebApp::try_from(state.as_ref()).map_err(|e| err(e, &contract))?;
-                    web.unpack(&path).map_err(|e| err(e, &contract))?;
+                    let unavailable = web.unpack(&path).map_err(|e| err(e, &contract))?;
+                    if !unavailable.is_empty() {
+                        tracing::warn!(
+                            contract = %key,
+                            ?unavailable,
+                            "webapp unpacked without its corrupt assets"
+                        );
+                    }
 
                     // Store new hash
                     tokio::fs::write(&hash_path, current_hash.to_be_bytes())
@@ -184,10 +200,11 @@ pub(super) async fn contract_home(
     Ok(response)
 }
 
-#[instrument(level = "debug")]
+#[instrument(level = "debug", skip(asset_store))]
 pub(super) async fn variable_content(
     key: String,
     req_path: String,
+    asset_store: Option<Arc<ExternalAssetStore>>,
 ) -> Result<impl IntoResponse, Box<WebSocketApiError>> {
     debug!(
         "variable_content: Processing request for key: {}, path: {}",
@@ -214,14 +231,46 @@ pub(super) async fn variable_content(
         "variable_content: Extracted relative path: {}",
         relative_path
     );
+    serve_asset(&base_path, relative_path, asset_store).await
+}
+
+/// Serves the asset at `relative_path` of the web app unpacked at `base_path`, resolving it
+/// from the external store if not bundled.
+async fn serve_asset(
+    base_path: &Path,
+    relative_path: String,
+    asset_store: Option<Arc<ExternalAssetStore>>,
+) -> Result<axum::response::Response, Box<WebSocketApiError>> {
+    if is_unavailable(base_path, &relative_path).await {
+        return Err(WebSocketApiError::UnavailableAsset {
+            path: relative_path,
+        }
+        .into());
+    }
 
-    let file_path = base_path.join(relative_path);
-    debug!("variable_content: Full file path to serve: {:?}", file_path);
+    let mut file_path = base_path.join(&relative_path);
+    debug!("serve_asset: Full file path to serve: {:?}", file_path);
     debug!(
-        "variable_content: Checking if file exists: {}",
+        "serve_asset: Checking if file exists: {}",
         file_path.exists()
     );
 
+    if let Some(store) = asset_store.filter(|_| !file_path.exists()) {
+        file_path = store
+            .resolve(base_path, &relative_path)
+            .await
+            .map_err(|err| {
+                tracing::error!("Failed resolving external asset {relative_path}: {err}");
+                WebSocketApiError::NodeError {
+                    error_cause: format!("{err}"),
+                }
+            })?
+            .ok_or_else(|| WebSocketApiError::MissingAsset {
+                path: relative_path.clone(),
+            })?;
+        debug!("serve_asset: Resolved from external store: {:?}", file_path);
+    }
+
     // serve the file
     let mut serve_file = tower_http::services::fs::ServeFile::new(&file_path);
     let fake_req = axum::http::Request::new(axum::body::Body::empty());
@@ -263,6 +312,15 @@ async fn get_web_body(path: &Path) -> Result<impl IntoResponse, WebSocketApiErro
     Ok(Html(body))
 }
 
+/// Whether the asset was corrupt in the bundle the web app was unpacked from.
+async fn is_unavailable(web_root: &Path, relative_path: &str) -> bool {
+    let Ok(listed) = tokio::fs::read(web_root.join(UNAVAILABLE_ASSETS)).await else {
+        return false;
+    };
+    serde_json::from_slice::<Vec<String>>(&listed)
+        .is_ok_and(|unavailable| unavailable.iter().any(|path| path == relative_path))
+}
+
 fn contract_web_path(key: &ContractKey) -> PathBuf {
     std::env::temp_dir()
         .join("freenet")
@@ -283,3 +341,102 @@ fn state_hash_path(key: &ContractKey) -> PathBuf {
         .join("webapp_cache")
         .join(format!("{}.hash", key.encoded_contract_id()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::asset_store::ASSET_MANIFEST;
+
+    #[tokio::test]
+    async fn serve_asset_resolved_from_external_store() -> anyhow::Result<()> {
+        let (web_root, store_dir, cache_dir) = (
+            tempfile::TempDir::new()?,
+            tempfile::TempDir::new()?,
+            tempfile::TempDir::new()?,
+        );
+
+        let style = b"body { color: red; }";
+        let hash = blake3::hash(style).to_hex();
+        std::fs::write(store_dir.path().join(hash.as_str()), style)?;
+        std::fs::write(
+            web_root.path().join(ASSET_MANIFEST),
+            format!(
+                r#"{{"style.css": "{hash}", "gone.css": "{}"}}"#,
+                "0".repeat(64)
+            ),
+        )?;
+        let store = Some(Arc::new(ExternalAssetStore::with_cache_dir(
+            store_dir.path().to_str().unwrap(),
+            cache_dir.path().to_path_buf(),
+        )));
+
+        let response = serve_asset(web_root.path(), "style.css".into(), store.clone())
+            .await
+            .map_err(|err| anyhow::anyhow!("{err}"))?;
+        assert_eq!(response.status(), axum::http::StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        assert_eq!(&body[..], style);
+
+        let Err(missing) = serve_asset(web_root.path(), "gone.css".into(), store).await else {
+            anyhow::bail!("expected the missing blob not to be served");
+        };
+        assert_eq!(missing.status_code(), axum::http::StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrupt_asset_unavailable_others_served() -> anyhow::Result<()> {
+        use std::io::Read;
+
+        let key = ContractKey::from(ContractInstanceId::new(
+            *blake3::hash(b"corrupt_asset_unavailable_others_served").as_bytes(),
+        ));
+        let web_root = contract_web_path(&key);
+        let _ = std::fs::remove_dir_all(&web_root);
+
+        let style = b"body { color: red; }";
+        let mut bundle = tar::Builder::new(Vec::new());
+        for (path, content) in [
+            ("index.html", &b"<html></html>"[..]),
+            ("style.css", style),
+            ("app.js", &[b'x'; 2048]),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            bundle.append_data(&mut header, path, content)?;
+        }
+        // the bundle is cut off in the middle of the last asset
+        let mut tarball = bundle.into_inner()?;
+        // the header and data blocks of the first two assets, then the header of the last one
+        let app_js_start = 5 * 512;
+        tarball.truncate(app_js_start + 1024);
+        let mut compressed = vec![];
+        xz2::read::XzEncoder::new(tarball.as_slice(), 6).read_to_end(&mut compressed)?;
+        let mut web = WebApp::from_compressed(vec![], compressed)?;
+        assert_eq!(web.unpack(&web_root)?, ["app.js"]);
+
+        let id = key.encoded_contract_id();
+        let response =
+            variable_content(id.clone(), format!("/v1/contract/web/{id}/style.css"), None)
+                .await
+                .map_err(|err| anyhow::anyhow!("{err}"))?
+                .into_response();
+        assert_eq!(response.status(), axum::http::StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        assert_eq!(&body[..], style);
+
+        let corrupt = variable_content(id.clone(), format!("/v1/contract/web/{id}/app.js"), None)
+            .await
+            .err()
+            .expect("corrupt asset");
+        assert_eq!(
+            corrupt.status_code(),
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR
+        );
+
+        std::fs::remove_dir_all(&web_root)?;
+        Ok(())
+    }
+}
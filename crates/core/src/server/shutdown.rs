@@ -0,0 +1,196 @@
+//! Shutdown of the gateway in stages, each one started once the previous one is over.
+//!
+//! The gateway first stops accepting connections, then waits for the in-flight requests to be
+//! answered, then closes the websocket connections along with their subscriptions and last
+//! stops the executor. Every stage is given its own time to complete, after which it is given
+//! up on and the next one started, and is logged as it completes so operators can follow the
+//! shutdown and tell which stage needs a longer timeout.
+
+use std::{
+    fmt::Display,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use crate::config::ShutdownTimeouts;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum ShutdownStage {
+    StopAccepting,
+    DrainRequests,
+    CloseSubscriptions,
+    StopExecutor,
+}
+
+impl ShutdownStage {
+    fn next(self) -> Option<Self> {
+        match self {
+            Self::StopAccepting => Some(Self::DrainRequests),
+            Self::DrainRequests => Some(Self::CloseSubscriptions),
+            Self::CloseSubscriptions => Some(Self::StopExecutor),
+            Self::StopExecutor => None,
+        }
+    }
+}
+
+impl Display for ShutdownStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::StopAccepting => "stop accepting",
+            Self::DrainRequests => "drain requests",
+            Self::CloseSubscriptions => "close subscriptions",
+            Self::StopExecutor => "stop executor",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StageOutcome {
+    Completed,
+    TimedOut,
+}
+
+pub(crate) struct ShutdownSequence {
+    timeouts: ShutdownTimeouts,
+    drain_deadline: Duration,
+    started: Instant,
+    /// The stage in progress and when it started, none once all are over.
+    current: Option<(ShutdownStage, Instant)>,
+    outcomes: Vec<(ShutdownStage, StageOutcome)>,
+}
+
+impl ShutdownSequence {
+    /// Starts shutting down with the first stage, draining the requests is given up to
+    /// `drain_deadline`.
+    pub fn start(timeouts: ShutdownTimeouts, drain_deadline: Duration) -> Self {
+        tracing::info!("shutting down the gateway");
+        let now = Instant::now();
+        Self {
+            timeouts,
+            drain_deadline,
+            started: now,
+            current: Some((ShutdownStage::StopAccepting, now)),
+            outcomes: Vec::new(),
+        }
+    }
+
+    pub fn stage(&self) -> Option<ShutdownStage> {
+        self.current.map(|(stage, _)| stage)
+    }
+
+    /// When the stage in progress is given up on.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.current
+            .map(|(stage, started)| started + self.timeout(stage))
+    }
+
+    fn timeout(&self, stage: ShutdownStage) -> Duration {
+        let secs = match stage {
+            ShutdownStage::StopAccepting => self.timeouts.stop_accepting_secs,
+            ShutdownStage::DrainRequests => return self.drain_deadline,
+            ShutdownStage::CloseSubscriptions => self.timeouts.subscriptions_secs,
+            ShutdownStage::StopExecutor => self.timeouts.executor_secs,
+        };
+        Duration::from_secs(secs)
+    }
+
+    /// Moves on to the next stage once `stage` is over, unless it isn't the one in progress,
+    /// e.g. a stage given up on which completed afterwards.
+    pub fn complete(&mut self, stage: ShutdownStage) -> bool {
+        if self.stage() != Some(stage) {
+            return false;
+        }
+        self.advance(StageOutcome::Completed);
+        true
+    }
+
+    /// Gives up on the stage in progress, moving on to the next one.
+    pub fn timed_out(&mut self) {
+        self.advance(StageOutcome::TimedOut);
+    }
+
+    /// Runs the stage in progress, which must be `stage`, until `work` completes or its time
+    /// is up.
+    pub async fn run(&mut self, stage: ShutdownStage, work: impl Future<Output = ()>) {
+        debug_assert_eq!(self.stage(), Some(stage), "shutdown stage out of order");
+        match tokio::time::timeout(self.timeout(stage), work).await {
+            Ok(()) => {
+                self.complete(stage);
+            }
+            Err(_) => self.timed_out(),
+        }
+    }
+
+    fn advance(&mut self, outcome: StageOutcome) {
+        let Some((stage, started)) = self.current else {
+            return;
+        };
+        let elapsed = started.elapsed();
+        match outcome {
+            StageOutcome::Completed => {
+                tracing::info!(%stage, ?elapsed, "gateway shutdown stage completed")
+            }
+            StageOutcome::TimedOut => tracing::warn!(
+                %stage,
+                timeout = ?self.timeout(stage),
+                "gateway shutdown stage timed out, moving on"
+            ),
+        }
+        self.outcomes.push((stage, outcome));
+        self.current = stage.next().map(|next| (next, Instant::now()));
+    }
+
+    /// How every stage ended, in the order they went through.
+    pub fn finish(self) -> Vec<(ShutdownStage, StageOutcome)> {
+        tracing::info!(elapsed = ?self.started.elapsed(), "gateway shut down");
+        self.outcomes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUTS: ShutdownTimeouts = ShutdownTimeouts {
+        stop_accepting_secs: 1,
+        subscriptions_secs: 1,
+        executor_secs: 1,
+    };
+
+    #[tokio::test]
+    async fn stages_complete_in_order() {
+        let mut sequence = ShutdownSequence::start(TIMEOUTS, Duration::from_secs(5));
+        assert_eq!(sequence.stage(), Some(ShutdownStage::StopAccepting));
+        // a later stage can't complete before the one in progress
+        assert!(!sequence.complete(ShutdownStage::DrainRequests));
+        assert!(sequence.complete(ShutdownStage::StopAccepting));
+        assert_eq!(sequence.stage(), Some(ShutdownStage::DrainRequests));
+
+        sequence
+            .run(
+                ShutdownStage::DrainRequests,
+                tokio::time::sleep(Duration::from_millis(10)),
+            )
+            .await;
+        // hangs past its timeout
+        sequence
+            .run(ShutdownStage::CloseSubscriptions, std::future::pending())
+            .await;
+        assert_eq!(sequence.stage(), Some(ShutdownStage::StopExecutor));
+        // given up on, completing late doesn't move the sequence back
+        assert!(!sequence.complete(ShutdownStage::CloseSubscriptions));
+        sequence.run(ShutdownStage::StopExecutor, async {}).await;
+        assert!(sequence.stage().is_none());
+        assert!(sequence.deadline().is_none());
+
+        assert_eq!(
+            sequence.finish(),
+            [
+                (ShutdownStage::StopAccepting, StageOutcome::Completed),
+                (ShutdownStage::DrainRequests, StageOutcome::Completed),
+                (ShutdownStage::CloseSubscriptions, StageOutcome::TimedOut),
+                (ShutdownStage::StopExecutor, StageOutcome::Completed),
+            ]
+        );
+    }
+}
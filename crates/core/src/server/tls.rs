@@ -0,0 +1,165 @@
+//! TLS for the connections to the HTTP gateway.
+//!
+//! The handshake happens on the task serving the connection, so a client failing it, e.g.
+//! one speaking plain HTTP or asking for a name the certificate isn't for, only ends its own
+//! connection while the gateway keeps accepting the others.
+//!
+//! With a client certificate authority configured, clients must present a certificate issued
+//! by it, and, when names of allowed clients are configured, valid for one of those names. The
+//! certificate identifies the client for the requests made through the connection: by the
+//! allowed name it is valid for, or else by the first name it is valid for, or its fingerprint
+//! if it is valid for none. A client whose identity is configured with a contract is attested
+//! for it, the same as if it had connected with a token handed to the web app of the contract.
+
+use std::{collections::HashMap, fs::File, io::BufReader, path::Path, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use freenet_stdlib::prelude::ContractInstanceId;
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{CertificateDer, ServerName},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use crate::config::TlsConfig;
+
+/// Identity of a client established by the certificate it presented.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ClientIdentity {
+    pub name: Arc<str>,
+    /// The contract the client is attested for by its identity, if any.
+    pub contract: Option<ContractInstanceId>,
+}
+
+#[derive(Clone)]
+pub(crate) struct GatewayTls {
+    acceptor: TlsAcceptor,
+    allowed_clients: Arc<[ServerName<'static>]>,
+    client_contracts: Arc<HashMap<String, ContractInstanceId>>,
+}
+
+impl GatewayTls {
+    /// TLS for the connections to the HTTP gateway, only ever served with HTTP/1.1.
+    pub fn new(config: &TlsConfig) -> anyhow::Result<Self> {
+        Self::with_protocol(config, b"http/1.1")
+    }
+
+    /// The same TLS for the connections to the gRPC interface, served with HTTP/2.
+    #[cfg(feature = "grpc")]
+    pub fn for_grpc(config: &TlsConfig) -> anyhow::Result<Self> {
+        Self::with_protocol(config, b"h2")
+    }
+
+    fn with_protocol(config: &TlsConfig, protocol: &[u8]) -> anyhow::Result<Self> {
+        let certs = rustls_pemfile::certs(&mut open(&config.cert_file)?)
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("invalid certificate in {}", config.cert_file.display()))?;
+        anyhow::ensure!(
+            !certs.is_empty(),
+            "no certificate in {}",
+            config.cert_file.display()
+        );
+        let key = rustls_pemfile::private_key(&mut open(&config.key_file)?)
+            .with_context(|| format!("invalid private key in {}", config.key_file.display()))?
+            .with_context(|| format!("no private key in {}", config.key_file.display()))?;
+        let provider = Arc::new(ring::default_provider());
+        let server = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let server = match &config.client_ca_file {
+            None => server.with_no_client_auth(),
+            Some(ca_file) => {
+                let mut roots = RootCertStore::empty();
+                for cert in rustls_pemfile::certs(&mut open(ca_file)?) {
+                    let cert = cert
+                        .with_context(|| format!("invalid certificate in {}", ca_file.display()))?;
+                    roots.add(cert)?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .build()?;
+                server.with_client_cert_verifier(verifier)
+            }
+        };
+        let mut server = server.with_single_cert(certs, key)?;
+        server.alpn_protocols = vec![protocol.to_vec()];
+        let allowed_clients = config
+            .allowed_clients
+            .iter()
+            .map(|name| {
+                ServerName::try_from(name.clone())
+                    .with_context(|| format!("invalid allowed client name: {name}"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let client_contracts = config
+            .client_contracts
+            .iter()
+            .map(|(client, contract)| {
+                let contract = contract.parse().map_err(|_| {
+                    anyhow::anyhow!("invalid contract id of client {client}: {contract}")
+                })?;
+                Ok((client.clone(), contract))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server)),
+            allowed_clients,
+            client_contracts: Arc::new(client_contracts),
+        })
+    }
+
+    /// Completes the handshake with a connected client, which has `timeout` to go through it,
+    /// along with the identity of the client if it presented a certificate.
+    pub async fn accept(
+        &self,
+        stream: TcpStream,
+        timeout: Duration,
+    ) -> std::io::Result<(TlsStream<TcpStream>, Option<ClientIdentity>)> {
+        let stream = tokio::time::timeout(timeout, self.acceptor.accept(stream))
+            .await
+            .map_err(|_| std::io::ErrorKind::TimedOut)??;
+        let cert = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first());
+        let name = match cert {
+            Some(cert) => self.identify(cert),
+            // only asked for with a client certificate authority
+            None if self.allowed_clients.is_empty() => return Ok((stream, None)),
+            None => None,
+        }
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "client certificate is not for an allowed client",
+            )
+        })?;
+        let contract = self.client_contracts.get(&*name).copied();
+        Ok((stream, Some(ClientIdentity { name, contract })))
+    }
+
+    fn identify(&self, der: &CertificateDer<'_>) -> Option<Arc<str>> {
+        let cert = webpki::EndEntityCert::try_from(der).ok()?;
+        if self.allowed_clients.is_empty() {
+            let name = cert.valid_dns_names().next().map(Arc::from);
+            return Some(name.unwrap_or_else(|| {
+                format!("cert:{}", blake3::hash(der.as_ref()).to_hex()).into()
+            }));
+        }
+        self.allowed_clients
+            .iter()
+            .find(|name| cert.verify_is_valid_for_subject_name(name).is_ok())
+            .map(|name| name.to_str().into())
+    }
+}
+
+fn open(path: &Path) -> anyhow::Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
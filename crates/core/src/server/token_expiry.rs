@@ -0,0 +1,159 @@
+//! Expiry of the tokens handed to contract web apps.
+//!
+//! A token is as old as the time elapsed on the node's monotonic clock since the node issued
+//! it, so neither the client's clock nor adjustments of the system clock play any part.
+//! Tokens are only refused once past their lifetime plus a margin, and using one shortly
+//! before that is logged, so a client about to lose its token can be told apart from one
+//! which lost it. Expired tokens are dropped from the [`AttestedContractMap`] every
+//! [`PRUNE_INTERVAL`], see [`prune_expired`].
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::TokenExpiry,
+    server::http_gateway::AttestedContractMap,
+    util::time_source::{InstantTimeSrc, TimeSource},
+};
+
+/// How often the expired tokens are dropped.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub(crate) struct TokenExpiryCheck<T: TimeSource = InstantTimeSrc> {
+    ttl: Duration,
+    skew_margin: Duration,
+    time_source: T,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum TokenAge {
+    Valid,
+    /// Accepted, though it is within the margin of its expiry, before or after it.
+    NearExpiry,
+    Expired,
+}
+
+impl TokenExpiryCheck {
+    pub fn new(expiry: TokenExpiry) -> Self {
+        Self::with_time_source(expiry, InstantTimeSrc::new())
+    }
+}
+
+impl<T: TimeSource> TokenExpiryCheck<T> {
+    fn with_time_source(expiry: TokenExpiry, time_source: T) -> Self {
+        Self {
+            ttl: Duration::from_secs(expiry.ttl_secs),
+            skew_margin: Duration::from_secs(expiry.skew_margin_secs),
+            time_source,
+        }
+    }
+
+    /// The time to record as the moment a token is issued.
+    pub fn now(&self) -> Instant {
+        self.time_source.now()
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn age(&self, issued: Instant) -> TokenAge {
+        let age = self.time_source.now().saturating_duration_since(issued);
+        if age > self.ttl + self.skew_margin {
+            TokenAge::Expired
+        } else if age + self.skew_margin >= self.ttl {
+            TokenAge::NearExpiry
+        } else {
+            TokenAge::Valid
+        }
+    }
+
+    /// Whether a token issued at `issued` is still accepted, logging when it is about to
+    /// stop being so.
+    pub fn accepts(&self, issued: Instant) -> bool {
+        match self.age(issued) {
+            TokenAge::Valid => true,
+            TokenAge::NearExpiry => {
+                let age = self.time_source.now().saturating_duration_since(issued);
+                tracing::info!(?age, ttl = ?self.ttl, "auth token near expiry");
+                true
+            }
+            TokenAge::Expired => false,
+        }
+    }
+}
+
+/// Drops the expired tokens from the map every [`PRUNE_INTERVAL`], for as long as the map is
+/// in use.
+pub(crate) fn prune_expired(attested_contracts: &AttestedContractMap, check: TokenExpiryCheck) {
+    let attested_contracts = Arc::downgrade(attested_contracts);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let Some(attested_contracts) = attested_contracts.upgrade() else {
+                break;
+            };
+            let Ok(mut attested) = attested_contracts.write() else {
+                break;
+            };
+            let before = attested.len();
+            attested.retain(|_, (_, _, issued)| check.age(*issued) != TokenAge::Expired);
+            if attested.len() < before {
+                tracing::debug!(
+                    expired = before - attested.len(),
+                    "dropped expired auth tokens"
+                );
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::time_source::MockTimeSource;
+
+    use super::*;
+
+    fn check() -> TokenExpiryCheck<MockTimeSource> {
+        TokenExpiryCheck::with_time_source(
+            TokenExpiry {
+                ttl_secs: 3600,
+                skew_margin_secs: 60,
+            },
+            MockTimeSource::new(Instant::now()),
+        )
+    }
+
+    #[test]
+    fn tokens_near_the_boundary() {
+        let mut check = check();
+        let issued = check.now();
+        assert_eq!(check.age(issued), TokenAge::Valid);
+
+        check
+            .time_source
+            .advance_time(Duration::from_secs(3600 - 61));
+        assert_eq!(check.age(issued), TokenAge::Valid);
+        check.time_source.advance_time(Duration::from_secs(1));
+        assert_eq!(check.age(issued), TokenAge::NearExpiry);
+        assert!(check.accepts(issued));
+
+        // past the lifetime but within the margin
+        check.time_source.advance_time(Duration::from_secs(90));
+        assert!(check.accepts(issued));
+        check.time_source.advance_time(Duration::from_secs(30));
+        assert!(check.accepts(issued));
+        check.time_source.advance_time(Duration::from_secs(1));
+        assert_eq!(check.age(issued), TokenAge::Expired);
+        assert!(!check.accepts(issued));
+
+        // issued after the clock reading used for the check
+        let later = check.now() + Duration::from_secs(10);
+        assert!(check.accepts(later));
+    }
+}
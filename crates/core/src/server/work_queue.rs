@@ -0,0 +1,365 @@
+//! Queue of client requests waiting to be picked up by the node.
+//!
+//! Both the HTTP gateway and the websocket proxy forward their requests through a bounded
+//! channel; the requests are accounted from the moment a connection tries to enqueue them
+//! (including while waiting for capacity) until the node receives them, so the depth reflects
+//! how backlogged the executor is.
+//!
+//! As a last resort when the node can't keep up, once more requests than the configured
+//! cancel depth are waiting those waiting for room the longest among the lowest priority ones
+//! are cancelled, one for every request arriving over the limit. Reads go before writes,
+//! while connecting clients are never cancelled. The clients of cancelled requests are told
+//! the node is unavailable, so they retry later.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use freenet_stdlib::client_api::{ClientRequest, ContractRequest};
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+
+use super::ClientConnection;
+
+/// Which of the waiting requests are cancelled first, the lowest priority goes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum RequestPriority {
+    Read,
+    Write,
+}
+
+impl RequestPriority {
+    /// None for the requests which are never cancelled.
+    fn of(msg: &ClientConnection) -> Option<Self> {
+        let ClientConnection::Request { req, .. } = msg else {
+            return None;
+        };
+        match &**req {
+            ClientRequest::ContractOp(
+                ContractRequest::Get { .. } | ContractRequest::Subscribe { .. },
+            ) => Some(Self::Read),
+            ClientRequest::ContractOp(_) | ClientRequest::DelegateOp(_) => Some(Self::Write),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Waiting {
+    id: u64,
+    priority: RequestPriority,
+    since: Instant,
+    cancel: oneshot::Sender<()>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct WorkQueueMetrics {
+    depth: AtomicUsize,
+    max_depth: AtomicUsize,
+    enqueued: AtomicU64,
+    cancelled: AtomicU64,
+    orphaned: AtomicU64,
+    cancel_depth: Option<usize>,
+    /// Requests which can be cancelled, waiting for room in the queue.
+    waiting: Mutex<Vec<Waiting>>,
+    next_waiting: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct WorkQueueSnapshot {
+    /// Requests currently waiting to be processed.
+    pub depth: usize,
+    /// Highest depth observed since the node started.
+    pub max_depth: usize,
+    /// Total number of requests enqueued since the node started.
+    pub enqueued: u64,
+    /// Requests cancelled for the node being overloaded since it started.
+    pub cancelled: u64,
+    /// Results dropped since the node started for the client they were for being gone.
+    pub orphaned: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum WorkQueueError {
+    #[error("node not receiving requests")]
+    Closed,
+    #[error("request cancelled, the node is overloaded")]
+    Cancelled,
+}
+
+impl WorkQueueMetrics {
+    /// Cancels requests while more than `cancel_depth` are waiting, if set.
+    pub fn new(cancel_depth: Option<usize>) -> Self {
+        Self {
+            cancel_depth,
+            ..Default::default()
+        }
+    }
+
+    pub fn snapshot(&self) -> WorkQueueSnapshot {
+        WorkQueueSnapshot {
+            depth: self.depth.load(Ordering::Acquire),
+            max_depth: self.max_depth.load(Ordering::Acquire),
+            enqueued: self.enqueued.load(Ordering::Acquire),
+            cancelled: self.cancelled.load(Ordering::Acquire),
+            orphaned: self.orphaned.load(Ordering::Acquire),
+        }
+    }
+
+    /// Accounts a result which came in for a client no longer connected, and was dropped.
+    pub fn orphaned(&self) {
+        self.orphaned.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Accounts a request held by the receiving end before being handed to the node.
+    pub fn hold(&self) {
+        let depth = self.depth.fetch_add(1, Ordering::AcqRel) + 1;
+        self.max_depth.fetch_max(depth, Ordering::AcqRel);
+    }
+
+    /// Releases a request previously accounted with [`Self::hold`].
+    pub fn release(&self) {
+        self.depth.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    /// Registers a request waiting for room, which is cancelled once the returned receiver
+    /// completes. Makes room cancelling another if over the cancel depth already.
+    fn wait(&self, priority: RequestPriority) -> (u64, oneshot::Receiver<()>) {
+        let id = self.next_waiting.fetch_add(1, Ordering::AcqRel);
+        let (cancel, cancelled) = oneshot::channel();
+        let mut waiting = self.waiting.lock();
+        waiting.push(Waiting {
+            id,
+            priority,
+            since: Instant::now(),
+            cancel,
+        });
+        let overloaded = self
+            .cancel_depth
+            .is_some_and(|max| self.depth.load(Ordering::Acquire) > max);
+        if overloaded {
+            let victim = waiting
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, waiting)| (waiting.priority, waiting.since))
+                .map(|(pos, _)| pos)
+                .expect("waiting request");
+            let victim = waiting.swap_remove(victim);
+            tracing::debug!(priority = ?victim.priority, waited = ?victim.since.elapsed(), "node overloaded, cancelling request");
+            self.cancelled.fetch_add(1, Ordering::AcqRel);
+            let _ = victim.cancel.send(());
+        }
+        (id, cancelled)
+    }
+
+    fn stop_waiting(&self, id: u64) {
+        self.waiting.lock().retain(|waiting| waiting.id != id);
+    }
+}
+
+pub(crate) fn work_queue(
+    capacity: usize,
+    metrics: Arc<WorkQueueMetrics>,
+) -> (WorkQueueSender, WorkQueueReceiver) {
+    let (tx, rx) = mpsc::channel(capacity);
+    (
+        WorkQueueSender {
+            inner: tx,
+            metrics: metrics.clone(),
+        },
+        WorkQueueReceiver { inner: rx, metrics },
+    )
+}
+
+#[derive(Clone)]
+pub(crate) struct WorkQueueSender {
+    inner: mpsc::Sender<ClientConnection>,
+    metrics: Arc<WorkQueueMetrics>,
+}
+
+impl WorkQueueSender {
+    pub async fn send(&self, msg: ClientConnection) -> Result<(), WorkQueueError> {
+        struct Pending<'a>(Option<&'a WorkQueueMetrics>);
+
+        impl Drop for Pending<'_> {
+            fn drop(&mut self) {
+                // the request never made it into the queue
+                if let Some(metrics) = self.0 {
+                    metrics.release();
+                }
+            }
+        }
+
+        self.metrics.hold();
+        self.metrics.enqueued.fetch_add(1, Ordering::AcqRel);
+        let mut pending = Pending(Some(&self.metrics));
+        let permit = match RequestPriority::of(&msg) {
+            None => self.inner.reserve().await,
+            Some(priority) => {
+                let (id, cancelled) = self.metrics.wait(priority);
+                let permit = tokio::select! { biased;
+                    _ = cancelled => Err(WorkQueueError::Cancelled),
+                    permit = self.inner.reserve() => Ok(permit),
+                };
+                self.metrics.stop_waiting(id);
+                permit?
+            }
+        };
+        permit.map_err(|_| WorkQueueError::Closed)?.send(msg);
+        pending.0 = None;
+        Ok(())
+    }
+}
+
+pub(crate) struct WorkQueueReceiver {
+    inner: mpsc::Receiver<ClientConnection>,
+    metrics: Arc<WorkQueueMetrics>,
+}
+
+impl WorkQueueReceiver {
+    pub async fn recv(&mut self) -> Option<ClientConnection> {
+        let msg = self.inner.recv().await?;
+        self.metrics.release();
+        Some(msg)
+    }
+
+    pub fn try_recv(&mut self) -> Option<ClientConnection> {
+        let msg = self.inner.try_recv().ok()?;
+        self.metrics.release();
+        Some(msg)
+    }
+
+    pub fn metrics(&self) -> &WorkQueueMetrics {
+        &self.metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use freenet_stdlib::prelude::{ContractInstanceId, ContractKey, StateDelta, UpdateData};
+
+    use super::*;
+    use crate::client_events::ClientId;
+
+    fn request() -> ClientConnection {
+        ClientConnection::Request {
+            client_id: ClientId::FIRST,
+            req: Box::new(ClientRequest::Disconnect { cause: None }),
+            auth_token: None,
+            attested_contract: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn depth_reflects_burst() {
+        let metrics = Arc::new(WorkQueueMetrics::default());
+        let (tx, mut rx) = work_queue(2, metrics.clone());
+
+        // a burst exceeding the channel capacity, the excess waits for room
+        let mut senders = Vec::new();
+        for _ in 0..5 {
+            let tx = tx.clone();
+            senders.push(tokio::spawn(async move { tx.send(request()).await }));
+        }
+        while metrics.snapshot().depth < 5 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            metrics.snapshot(),
+            WorkQueueSnapshot {
+                depth: 5,
+                max_depth: 5,
+                enqueued: 5,
+                cancelled: 0,
+                orphaned: 0,
+            }
+        );
+
+        for expected in (0..5).rev() {
+            rx.recv().await.unwrap();
+            assert_eq!(metrics.snapshot().depth, expected);
+        }
+        for sender in senders {
+            sender.await.unwrap().unwrap();
+        }
+        assert_eq!(metrics.snapshot().max_depth, 5);
+    }
+
+    #[tokio::test]
+    async fn cancelled_send_is_not_accounted() {
+        let metrics = Arc::new(WorkQueueMetrics::default());
+        let (tx, _rx) = work_queue(1, metrics.clone());
+        tx.send(request()).await.unwrap();
+
+        let blocked =
+            tokio::time::timeout(std::time::Duration::from_millis(10), tx.send(request()));
+        assert!(blocked.await.is_err());
+        assert_eq!(metrics.snapshot().depth, 1);
+    }
+
+    #[tokio::test]
+    async fn reads_cancelled_on_overload() {
+        let metrics = Arc::new(WorkQueueMetrics::new(Some(3)));
+        let (tx, mut rx) = work_queue(1, metrics.clone());
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let op = |req: ContractRequest<'static>| ClientConnection::Request {
+            client_id: ClientId::FIRST,
+            req: Box::new(req.into()),
+            auth_token: None,
+            attested_contract: None,
+        };
+        let read = || op(ContractRequest::Subscribe { key, summary: None });
+        let write = || {
+            op(ContractRequest::Update {
+                key,
+                data: UpdateData::Delta(StateDelta::from(vec![1])),
+            })
+        };
+        tx.send(write()).await.unwrap();
+
+        let send = |msg| {
+            let tx = tx.clone();
+            tokio::spawn(async move { tx.send(msg).await })
+        };
+        let settled = |enqueued, depth| {
+            let metrics = metrics.clone();
+            async move {
+                while metrics.snapshot().enqueued < enqueued || metrics.snapshot().depth != depth {
+                    tokio::task::yield_now().await;
+                }
+            }
+        };
+        // the reads wait for room, then each write over the cancel depth cancels one of them
+        let reads = [send(read()), send(read())];
+        settled(3, 3).await;
+        let writes = [send(write()), send(write())];
+        settled(5, 3).await;
+        for read in reads {
+            assert!(matches!(
+                read.await.unwrap(),
+                Err(WorkQueueError::Cancelled)
+            ));
+        }
+        assert_eq!(metrics.snapshot().cancelled, 2);
+
+        for _ in 0..3 {
+            let Some(ClientConnection::Request { req, .. }) = rx.recv().await else {
+                panic!("expected a request");
+            };
+            assert!(matches!(
+                *req,
+                ClientRequest::ContractOp(ContractRequest::Update { .. })
+            ));
+        }
+        for write in writes {
+            write.await.unwrap().unwrap();
+        }
+        assert_eq!(metrics.snapshot().depth, 0);
+    }
+}
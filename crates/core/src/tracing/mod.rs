@@ -27,6 +27,9 @@ use crate::node::OpManager;
 
 /// An append-only log for network events.
 mod aof;
+#[cfg(feature = "http-gateway")]
+pub(crate) mod otlp;
+pub(crate) mod redact;
 
 #[derive(Debug, Clone, Copy)]
 #[allow(dead_code)]
@@ -858,12 +861,11 @@ mod opentelemetry_tracer {
             use trace::{Tracer, TracerProvider};
 
             let tracer = {
-                let tracer_provider = global::tracer_provider();
-                tracer_provider
-                    .tracer_builder("freenet")
+                let scope = opentelemetry::InstrumentationScope::builder("freenet")
                     .with_version(env!("CARGO_PKG_VERSION"))
                     .with_schema_url("https://opentelemetry.io/schemas/1.21.0")
-                    .build()
+                    .build();
+                global::tracer_provider().tracer_with_scope(scope)
             };
             let tx_bytes = transaction.as_bytes();
             let mut span_id = [0; 8];
@@ -1263,7 +1265,7 @@ enum UpdateEvent {
 #[cfg(feature = "trace")]
 pub(crate) mod tracer {
     use tracing::level_filters::LevelFilter;
-    use tracing_subscriber::{Layer, Registry};
+    use tracing_subscriber::{fmt::format::PrettyFields, Layer, Registry};
 
     pub fn init_tracer(level: Option<LevelFilter>, endpoint: Option<String>) -> anyhow::Result<()> {
         let default_filter = if cfg!(any(test, debug_assertions)) {
@@ -1284,7 +1286,10 @@ pub(crate) mod tracer {
         let disabled_logs = std::env::var("FREENET_DISABLE_LOGS").is_ok();
         let to_stderr = std::env::var("FREENET_LOG_TO_STDERR").is_ok();
         let layers = {
-            let fmt_layer = tracing_subscriber::fmt::layer().with_level(true).pretty();
+            let fmt_layer = tracing_subscriber::fmt::layer()
+                .with_level(true)
+                .pretty()
+                .fmt_fields(super::redact::Redacted::new(PrettyFields::new()));
             let fmt_layer = if cfg!(any(test, debug_assertions)) {
                 fmt_layer.with_file(true).with_line_number(true)
             } else {
@@ -1311,21 +1316,25 @@ pub(crate) mod tracer {
                 println!("setting OT collector with identifier: {identifier}");
                 let tracing_ot_layer = {
                     // Connect the Jaeger OT tracer with the tracing middleware
-                    let ot_jaeger_tracer = opentelemetry_otlp::new_pipeline()
-                        .tracing()
-                        .with_exporter(
-                            opentelemetry_otlp::new_exporter()
-                                .tonic()
-                                .with_endpoint(endpoint.unwrap_or_default()),
+                    use opentelemetry::trace::TracerProvider as _;
+                    use opentelemetry_otlp::WithExportConfig;
+
+                    let exporter = opentelemetry_otlp::SpanExporter::builder()
+                        .with_tonic()
+                        .with_endpoint(endpoint.unwrap_or_default())
+                        .build()?;
+                    let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+                        .with_simple_exporter(exporter)
+                        .with_resource(
+                            opentelemetry_sdk::Resource::builder_empty()
+                                .with_attribute(opentelemetry::KeyValue::new(
+                                    identifier,
+                                    "tracing-jaeger",
+                                ))
+                                .build(),
                         )
-                        .with_trace_config(
-                            opentelemetry_sdk::trace::Config::default().with_resource(
-                                opentelemetry_sdk::Resource::new(vec![
-                                    opentelemetry::KeyValue::new(identifier, "tracing-jaeger"),
-                                ]),
-                            ),
-                        )
-                        .install_simple()?;
+                        .build();
+                    let ot_jaeger_tracer = provider.tracer("freenet-core");
                     // Get a tracer which will route OT spans to a Jaeger agent
                     tracing_opentelemetry::layer().with_tracer(ot_jaeger_tracer)
                 };
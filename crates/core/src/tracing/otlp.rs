@@ -0,0 +1,162 @@
+//! Export of the spans of client requests to an OTLP collector over gRPC, through
+//! `opentelemetry-otlp`; it requires the `trace-ot` feature.
+//!
+//! Spans are batched by the SDK and exported in the background. Recording a span never waits
+//! on the collector, if the exporter falls behind the spans which don't fit in its queue are
+//! dropped.
+
+use std::time::SystemTime;
+
+#[cfg(feature = "trace-ot")]
+use opentelemetry::{
+    trace::{Span, SpanKind, Status, Tracer, TracerProvider as _},
+    KeyValue,
+};
+#[cfg(feature = "trace-ot")]
+use opentelemetry_sdk::{
+    trace::{SdkTracer, SdkTracerProvider, SpanExporter as Export},
+    Resource,
+};
+
+#[cfg(feature = "trace-ot")]
+const SERVICE_NAME: &str = "freenet";
+#[cfg(feature = "trace-ot")]
+const SCOPE_NAME: &str = "freenet.client-requests";
+
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "trace-ot"), allow(dead_code))]
+pub(crate) struct RequestSpan {
+    pub name: &'static str,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(&'static str, String)>,
+    /// Why the request failed.
+    pub error: Option<String>,
+}
+
+#[derive(Clone)]
+pub(crate) struct SpanExporter {
+    /// Exports the spans left once the last clone is dropped.
+    #[cfg(feature = "trace-ot")]
+    tracer: SdkTracer,
+}
+
+impl SpanExporter {
+    /// Starts exporting to the collector at `endpoint`, e.g. `http://localhost:4317`.
+    #[cfg(feature = "trace-ot")]
+    pub fn start(endpoint: &str) -> anyhow::Result<Self> {
+        use opentelemetry_otlp::WithExportConfig;
+
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()?;
+        Ok(Self::with_exporter(exporter))
+    }
+
+    #[cfg(not(feature = "trace-ot"))]
+    pub fn start(_endpoint: &str) -> anyhow::Result<Self> {
+        anyhow::bail!("exporting spans to an OTLP collector requires the `trace-ot` feature")
+    }
+
+    #[cfg(feature = "trace-ot")]
+    fn with_exporter(exporter: impl Export + 'static) -> Self {
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+            .build();
+        Self {
+            tracer: provider.tracer(SCOPE_NAME),
+        }
+    }
+
+    #[cfg(feature = "trace-ot")]
+    pub fn record(&self, span: RequestSpan) {
+        // every request is a trace of its own
+        let mut exported = self
+            .tracer
+            .span_builder(span.name)
+            .with_kind(SpanKind::Server)
+            .with_start_time(span.start)
+            .with_attributes(
+                span.attributes
+                    .into_iter()
+                    .map(|(key, value)| KeyValue::new(key, value)),
+            )
+            .start(&self.tracer);
+        exported.set_status(match span.error {
+            None => Status::Ok,
+            Some(error) => Status::error(error),
+        });
+        exported.end_with_timestamp(span.end);
+    }
+
+    #[cfg(not(feature = "trace-ot"))]
+    pub fn record(&self, _span: RequestSpan) {}
+}
+
+#[cfg(all(test, feature = "trace-ot"))]
+mod tests {
+    use std::time::Duration;
+
+    use opentelemetry_sdk::{error::OTelSdkResult, trace::SpanData};
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    /// Stands in for the collector.
+    #[derive(Debug)]
+    struct Collector(mpsc::UnboundedSender<SpanData>);
+
+    impl Export for Collector {
+        fn export(
+            &self,
+            batch: Vec<SpanData>,
+        ) -> impl std::future::Future<Output = OTelSdkResult> + Send {
+            for span in batch {
+                let _ = self.0.send(span);
+            }
+            std::future::ready(Ok(()))
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spans_are_exported() -> anyhow::Result<()> {
+        let (collector, mut received) = mpsc::unbounded_channel();
+        let exporter = SpanExporter::with_exporter(Collector(collector));
+        let start = SystemTime::now();
+        for (name, error) in [
+            ("contract.get", None),
+            ("contract.put", Some("invalid state")),
+        ] {
+            exporter.record(RequestSpan {
+                name,
+                start,
+                end: start + Duration::from_millis(3),
+                attributes: vec![("client.id", "1".into())],
+                error: error.map(String::from),
+            });
+        }
+        drop(exporter);
+
+        let get = received.recv().await.unwrap();
+        assert_eq!(get.name, "contract.get");
+        assert_eq!(get.span_kind, SpanKind::Server);
+        assert_eq!(get.status, Status::Ok);
+        assert_eq!(get.attributes, [KeyValue::new("client.id", "1")]);
+        assert_eq!(get.instrumentation_scope.name(), SCOPE_NAME);
+        assert_eq!(
+            get.end_time.duration_since(get.start_time)?,
+            Duration::from_millis(3)
+        );
+        let put = received.recv().await.unwrap();
+        assert_eq!(put.name, "contract.put");
+        assert_eq!(put.status, Status::error("invalid state"));
+        assert_ne!(
+            put.span_context.trace_id(),
+            get.span_context.trace_id(),
+            "each request is a trace of its own"
+        );
+        Ok(())
+    }
+}
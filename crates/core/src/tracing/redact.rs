@@ -0,0 +1,181 @@
+//! Redaction of sensitive fields from the logs.
+//!
+//! The fields to redact are configured once for the whole node, and every event and span the
+//! logs are formatted from has the values of those fields replaced before being written, so
+//! the places emitting them don't have to care. A field is redacted if its name matches any of
+//! the configured patterns: either a name as is, or one starting or ending with `*` matching
+//! any name ending or starting with the rest, e.g. `*token` or `delegate_*`.
+
+use parking_lot::RwLock;
+
+#[cfg(feature = "trace")]
+const REDACTED: &str = "<redacted>";
+
+static REDACTED_FIELDS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+/// Redacts the fields matching the patterns from the logs from then on.
+pub(crate) fn redact_fields(patterns: &[String]) {
+    if !patterns.is_empty() {
+        tracing::info!(?patterns, "redacting fields from the logs");
+    }
+    *REDACTED_FIELDS.write() = patterns.to_vec();
+}
+
+#[cfg(feature = "trace")]
+fn matches(pattern: &str, field: &str) -> bool {
+    if let Some(prefix) = pattern.strip_suffix('*') {
+        field.starts_with(prefix)
+    } else if let Some(suffix) = pattern.strip_prefix('*') {
+        field.ends_with(suffix)
+    } else {
+        pattern == field
+    }
+}
+
+#[cfg(feature = "trace")]
+pub(crate) use self::format::Redacted;
+
+#[cfg(feature = "trace")]
+mod format {
+    use std::fmt;
+
+    use parking_lot::RwLock;
+    use tracing::field::{Field, Visit};
+    use tracing_subscriber::field::{MakeVisitor, VisitFmt, VisitOutput};
+
+    use super::{matches, REDACTED, REDACTED_FIELDS};
+
+    /// Formats the fields of events and spans as the wrapped formatter does, but for the
+    /// values of the redacted fields.
+    pub(crate) struct Redacted<M> {
+        inner: M,
+        fields: &'static RwLock<Vec<String>>,
+    }
+
+    impl<M> Redacted<M> {
+        pub fn new(inner: M) -> Self {
+            Self::with_fields(inner, &REDACTED_FIELDS)
+        }
+
+        pub(super) fn with_fields(inner: M, fields: &'static RwLock<Vec<String>>) -> Self {
+            Self { inner, fields }
+        }
+    }
+
+    impl<T, M: MakeVisitor<T>> MakeVisitor<T> for Redacted<M> {
+        type Visitor = RedactedVisitor<M::Visitor>;
+
+        fn make_visitor(&self, target: T) -> Self::Visitor {
+            RedactedVisitor {
+                inner: self.inner.make_visitor(target),
+                fields: self.fields,
+            }
+        }
+    }
+
+    pub(crate) struct RedactedVisitor<V> {
+        inner: V,
+        fields: &'static RwLock<Vec<String>>,
+    }
+
+    impl<V> RedactedVisitor<V> {
+        fn redacted(&self, field: &Field) -> bool {
+            let fields = self.fields.read();
+            fields.iter().any(|pattern| matches(pattern, field.name()))
+        }
+    }
+
+    macro_rules! record {
+        ($($method:ident: $ty:ty),*) => {
+            $(
+                fn $method(&mut self, field: &Field, value: $ty) {
+                    if self.redacted(field) {
+                        self.inner.record_str(field, REDACTED);
+                    } else {
+                        self.inner.$method(field, value);
+                    }
+                }
+            )*
+        };
+    }
+
+    impl<V: Visit> Visit for RedactedVisitor<V> {
+        record!(
+            record_debug: &dyn fmt::Debug,
+            record_str: &str,
+            record_i64: i64,
+            record_u64: u64,
+            record_i128: i128,
+            record_u128: u128,
+            record_f64: f64,
+            record_bool: bool,
+            record_error: &(dyn std::error::Error + 'static)
+        );
+    }
+
+    impl<V: VisitOutput<O>, O> VisitOutput<O> for RedactedVisitor<V> {
+        fn finish(self) -> O {
+            self.inner.finish()
+        }
+    }
+
+    impl<V: VisitFmt> VisitFmt for RedactedVisitor<V> {
+        fn writer(&mut self) -> &mut dyn fmt::Write {
+            self.inner.writer()
+        }
+    }
+}
+
+#[cfg(all(test, feature = "trace"))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tracing_subscriber::fmt::{format::DefaultFields, MakeWriter};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Output {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn configured_fields_redacted() {
+        static FIELDS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+        *FIELDS.write() = vec!["secret".into(), "delegate_*".into()];
+        let output = Output::default();
+        let subscriber = tracing_subscriber::fmt()
+            .fmt_fields(Redacted::with_fields(DefaultFields::new(), &FIELDS))
+            .with_writer(output.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request", delegate_params = "p4r4ms");
+            let _entered = span.enter();
+            tracing::info!(secret = "hunter2", contract = "visible", "handled request");
+        });
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("handled request"), "{output}");
+        assert!(output.contains("contract=\"visible\""), "{output}");
+        assert!(output.contains(r#"secret="<redacted>""#), "{output}");
+        assert!(!output.contains("hunter2"), "{output}");
+        assert!(!output.contains("p4r4ms"), "{output}");
+    }
+}
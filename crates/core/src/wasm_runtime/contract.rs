@@ -120,7 +120,7 @@ impl ContractRuntimeInterface for super::Runtime {
         let is_valid = unsafe {
             ContractInterfaceResult::from_raw(result, &linear_mem)
                 .unwrap_validate_state_res(linear_mem)
-                .map_err(Into::<ContractExecError>::into)?
+                .map_err(ContractExecError::from_contract)?
         };
         Ok(is_valid)
     }
@@ -188,7 +188,7 @@ impl ContractRuntimeInterface for super::Runtime {
         let update_res = unsafe {
             ContractInterfaceResult::from_raw(result, &linear_mem)
                 .unwrap_update_state(linear_mem)
-                .map_err(Into::<ContractExecError>::into)?
+                .map_err(ContractExecError::from_contract)?
         };
 
         Ok(update_res)
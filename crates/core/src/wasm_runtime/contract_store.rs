@@ -122,9 +122,39 @@ impl ContractStore {
             tracing::warn!("trying to store partially unspecified contract `{}`", key);
             RuntimeInnerError::UnwrapContract
         })?;
-        if self.contract_cache.get(code_hash).is_some() {
-            return Ok(());
+        if self.contract_cache.get(code_hash).is_none() {
+            self.store_code(code_hash, code, APIVersion::from(contract))?;
         }
+
+        // Update index, the instance may be moving to new code
+        let keys = self.key_to_code_part.entry(*key.id());
+        match keys {
+            dashmap::mapref::entry::Entry::Occupied(v) if v.get().1 == *code_hash => {}
+            dashmap::mapref::entry::Entry::Occupied(mut v) => {
+                let current_version_offset = v.get().0;
+                let prev_val = &mut v.get_mut().1;
+                tracing::info!(contract = %key, previous = %prev_val, "contract code upgraded");
+                // first mark the old entry (if it exists) as removed
+                Self::remove(&self.key_file, current_version_offset)?;
+                let new_offset = Self::insert(&mut self.index_file, *key.id(), code_hash)?;
+                *prev_val = *code_hash;
+                v.get_mut().0 = new_offset;
+            }
+            dashmap::mapref::entry::Entry::Vacant(v) => {
+                let offset = Self::insert(&mut self.index_file, *key.id(), code_hash)?;
+                v.insert((offset, *code_hash));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn store_code(
+        &mut self,
+        code_hash: &CodeHash,
+        code: Arc<ContractCode<'static>>,
+        version: APIVersion,
+    ) -> RuntimeResult<()> {
         let key_path = code_hash.encode();
         let key_path = self.contracts_dir.join(key_path).with_extension("wasm");
         if let Ok((code, _ver)) = ContractCode::load_versioned_from_path(&key_path) {
@@ -140,31 +170,11 @@ impl ContractStore {
             .insert(*code_hash, Arc::new(ContractCode::from(data)), size);
 
         // save on disc
-        let version = APIVersion::from(contract);
         let output: Vec<u8> = code
             .to_bytes_versioned(version)
             .map_err(|e| anyhow::anyhow!(e))?;
         let mut file = File::create(key_path)?;
         file.write_all(output.as_slice())?;
-
-        // Update index
-        let keys = self.key_to_code_part.entry(*key.id());
-        match keys {
-            dashmap::mapref::entry::Entry::Occupied(mut v) => {
-                let current_version_offset = v.get().0;
-                let prev_val = &mut v.get_mut().1;
-                // first mark the old entry (if it exists) as removed
-                Self::remove(&self.key_file, current_version_offset)?;
-                let new_offset = Self::insert(&mut self.index_file, *key.id(), code_hash)?;
-                *prev_val = *code_hash;
-                v.get_mut().0 = new_offset;
-            }
-            dashmap::mapref::entry::Entry::Vacant(v) => {
-                let offset = Self::insert(&mut self.index_file, *key.id(), code_hash)?;
-                v.insert((offset, *code_hash));
-            }
-        }
-
         Ok(())
     }
 
@@ -223,4 +233,36 @@ mod test {
         assert!(f.is_some());
         Ok(())
     }
+
+    #[test]
+    fn code_upgraded_in_place() -> Result<(), Box<dyn std::error::Error>> {
+        let contract_dir = crate::util::tests::get_temp_dir();
+        std::fs::create_dir_all(contract_dir.path())?;
+        let mut store = ContractStore::new(contract_dir.path().into(), 10_000)?;
+        let params = Parameters::from(vec![0, 1]);
+        let original =
+            WrappedContract::new(Arc::new(ContractCode::from(vec![0, 1, 2])), params.clone());
+        store.store_contract(ContractContainer::Wasm(ContractWasmAPIVersion::V1(
+            original.clone(),
+        )))?;
+
+        // the same instance, now with other code
+        let code = Arc::new(ContractCode::from(vec![3, 4, 5]));
+        let mut key = serde_json::to_value(original.key())?;
+        key["code"] = serde_json::to_value(code.hash())?;
+        let mut upgraded = WrappedContract::new(code.clone(), params.clone());
+        upgraded.key = serde_json::from_value(key)?;
+        store.store_contract(ContractContainer::Wasm(ContractWasmAPIVersion::V1(
+            upgraded,
+        )))?;
+
+        assert_eq!(store.code_hash_from_key(original.key()), Some(*code.hash()));
+        let Some(ContractContainer::Wasm(ContractWasmAPIVersion::V1(fetched))) =
+            store.fetch_contract(&ContractKey::from(*original.key().id()), &params)
+        else {
+            panic!("upgraded contract not found");
+        };
+        assert_eq!(fetched.code().data(), code.data());
+        Ok(())
+    }
 }
@@ -0,0 +1,61 @@
+//! Contracts a contract depends on, e.g. the other contracts of an app.
+//!
+//! A contract lists the encoded ids of the contracts it depends on, one per line, in a custom
+//! section of its wasm module named [`DEPENDENCIES_SECTION`], read the same way as its
+//! [topics](super::topics). Clients resolving the graph of an app get them all along to fetch
+//! and subscribe to the dependencies ahead of using them.
+
+use anyhow::Context;
+use freenet_stdlib::prelude::ContractKey;
+
+use super::topics::section_lines;
+
+pub(crate) const DEPENDENCIES_SECTION: &str = "freenet:dependencies";
+
+/// The contracts the `code` of a contract declares it depends on.
+pub(crate) fn declared_dependencies(code: &[u8]) -> anyhow::Result<Vec<ContractKey>> {
+    section_lines(code, DEPENDENCIES_SECTION)?
+        .into_iter()
+        .map(|id| {
+            ContractKey::from_id(id.as_str()).with_context(|| format!("malformed dependency {id}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use freenet_stdlib::prelude::ContractInstanceId;
+
+    use super::super::topics::{CUSTOM_SECTION_ID, MAGIC};
+    use super::*;
+
+    fn module(dependencies: &str) -> Vec<u8> {
+        let mut content = vec![DEPENDENCIES_SECTION.len() as u8];
+        content.extend(DEPENDENCIES_SECTION.as_bytes());
+        content.extend(dependencies.as_bytes());
+        let mut module = MAGIC.to_vec();
+        module.extend([1, 0, 0, 0, CUSTOM_SECTION_ID, content.len() as u8]);
+        module.extend(content);
+        module
+    }
+
+    #[test]
+    fn lists_declared_dependencies() {
+        let (first, second) = (
+            ContractKey::from(ContractInstanceId::new([1; 32])),
+            ContractKey::from(ContractInstanceId::new([2; 32])),
+        );
+        let code = module(&format!(
+            "{}\n{}\n",
+            first.encoded_contract_id(),
+            second.encoded_contract_id()
+        ));
+        let declared = declared_dependencies(&code).unwrap();
+        assert_eq!(
+            declared.iter().map(|key| *key.id()).collect::<Vec<_>>(),
+            [*first.id(), *second.id()]
+        );
+
+        assert!(declared_dependencies(&module("not-a-key")).is_err());
+    }
+}
@@ -2,22 +2,34 @@ mod contract;
 mod contract_store;
 mod delegate;
 mod delegate_store;
+mod dependencies;
 mod error;
 mod native_api;
 mod runtime;
 mod secrets_store;
+mod state_history;
 mod state_store;
 mod store;
 #[cfg(test)]
-mod tests;
+pub(crate) mod tests;
+mod topics;
 
 pub(crate) use contract::ContractRuntimeInterface;
 pub use contract_store::ContractStore;
 pub(crate) use delegate::DelegateRuntimeInterface;
 pub use delegate_store::DelegateStore;
+pub(crate) use dependencies::declared_dependencies;
 pub(crate) use error::{ContractError, RuntimeInnerError, RuntimeResult};
 pub use runtime::{ContractExecError, Runtime};
 pub(crate) use secrets_store::SecretStoreError;
 pub use secrets_store::SecretsStore;
+pub(crate) use state_history::state_version;
+#[cfg(feature = "http-gateway")]
+pub(crate) use state_history::{HistoricalState, StateVersion};
+#[cfg(test)]
+pub(crate) use state_store::seal;
+#[cfg(feature = "http-gateway")]
+pub(crate) use state_store::StateReader;
 pub use state_store::StateStore;
 pub(crate) use state_store::{StateStorage, StateStoreError};
+pub(crate) use topics::declared_topics;
@@ -2,6 +2,7 @@ use super::{
     contract_store::ContractStore, delegate_store::DelegateStore, error::RuntimeInnerError,
     native_api, secrets_store::SecretsStore, RuntimeResult,
 };
+use crate::contract::ValidationError;
 use freenet_stdlib::{
     memory::{
         buf::{BufferBuilder, BufferMut},
@@ -9,7 +10,11 @@ use freenet_stdlib::{
     },
     prelude::*,
 };
-use std::{collections::HashMap, sync::atomic::AtomicI64};
+use std::{
+    collections::HashMap,
+    sync::{atomic::AtomicI64, mpsc},
+    time::{Duration, Instant},
+};
 use wasmer::{
     imports, Bytes, CompilerConfig, Imports, Instance, Memory, MemoryType, Module, Store,
     TypedFunction,
@@ -79,6 +84,10 @@ pub enum ContractExecError {
     #[error(transparent)]
     ContractError(#[from] ContractError),
 
+    /// A state or delta rejected by the contract with a code, see [`ValidationError`].
+    #[error(transparent)]
+    Rejected(ValidationError),
+
     #[error("Attempted to perform a put for an already put contract ({0}), use update instead")]
     DoublePut(ContractKey),
 
@@ -96,6 +105,20 @@ pub enum ContractExecError {
 
     #[error("The operation exceeded the maximum allowed compute time")]
     MaxComputeTimeExceeded,
+
+    #[error("Compiling the code exceeded the maximum allowed compilation time")]
+    MaxCompileTimeExceeded,
+}
+
+impl ContractExecError {
+    /// Wraps an error returned by the contract, keeping the code it rejected the state or
+    /// delta with if it reported one.
+    pub(crate) fn from_contract(err: ContractError) -> Self {
+        match ValidationError::coded(&err) {
+            Some(rejected) => Self::Rejected(rejected),
+            None => Self::ContractError(err),
+        }
+    }
 }
 
 pub struct RuntimeConfig {
@@ -106,6 +129,8 @@ pub struct RuntimeConfig {
     /// Safety margin for CPU speed variations (0.0 to 1.0)
     pub safety_margin: f64,
     pub enable_metering: bool,
+    /// Maximum allowed time compiling the WASM code of a contract or delegate in seconds
+    pub max_compilation_seconds: f64,
 }
 
 impl Default for RuntimeConfig {
@@ -115,6 +140,7 @@ impl Default for RuntimeConfig {
             cpu_cycles_per_second: None,
             safety_margin: 0.2,
             enable_metering: false,
+            max_compilation_seconds: 30.0,
         }
     }
 }
@@ -135,8 +161,99 @@ pub struct Runtime {
     /// Local contract storage.
     pub(crate) contract_store: ContractStore,
     /// loaded contract modules
-    pub(super) contract_modules: HashMap<ContractKey, Module>,
+    pub(super) contract_modules: ContractModules,
     pub(crate) enabled_metering: bool,
+    max_compilation_time: Duration,
+    pub(crate) compilation: CompilationMetrics,
+}
+
+/// Time spent compiling the modules loaded by a runtime.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CompilationMetrics {
+    pub compiled: u64,
+    /// Compilations given up on for taking longer than allowed.
+    pub timed_out: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl CompilationMetrics {
+    fn compiled(&mut self, took: Duration) {
+        self.compiled += 1;
+        self.total += took;
+        self.max = self.max.max(took);
+    }
+}
+
+/// Compiled modules of the contracts, each along with the code it was compiled from.
+pub(super) struct ContractModules<M = Module> {
+    modules: HashMap<ContractKey, (CodeHash, M)>,
+    /// Modules dropped for the code of their contract having changed since compiled.
+    pub upgraded: u64,
+}
+
+impl<M> Default for ContractModules<M> {
+    fn default() -> Self {
+        Self {
+            modules: HashMap::new(),
+            upgraded: 0,
+        }
+    }
+}
+
+impl<M: Clone> ContractModules<M> {
+    /// The module compiled for the contract, unless compiled from other than its `current`
+    /// code, in which case it is dropped so it is compiled anew.
+    fn get(&mut self, key: &ContractKey, current: Option<&CodeHash>) -> Option<M> {
+        let (code, module) = self.modules.get(key)?;
+        match current {
+            Some(current) if current != code => {
+                tracing::info!(contract = %key, previous = %code, %current, "contract code changed, dropping its module");
+                self.modules.remove(key);
+                self.upgraded += 1;
+                None
+            }
+            _ => Some(module.clone()),
+        }
+    }
+
+    fn insert(&mut self, key: ContractKey, code: CodeHash, module: M) {
+        self.modules.insert(key, (code, module));
+    }
+}
+
+/// Compiles on a thread of its own, so a module taking too long is given up on; the thread is
+/// left to finish in the background and what it compiled is dropped.
+fn compile_within<T, F>(
+    timeout: Duration,
+    metrics: &mut CompilationMetrics,
+    compile: F,
+) -> RuntimeResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, wasmer::CompileError> + Send + 'static,
+{
+    let started = Instant::now();
+    let (tx, rx) = mpsc::sync_channel(1);
+    std::thread::spawn(move || {
+        let _ = tx.send(compile());
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(compiled) => {
+            let took = started.elapsed();
+            tracing::debug!(?took, "compiled wasm module");
+            metrics.compiled(took);
+            Ok(compiled?)
+        }
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            tracing::warn!(?timeout, "gave up compiling wasm module");
+            metrics.timed_out += 1;
+            Err(ContractExecError::MaxCompileTimeExceeded.into())
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            Err(anyhow::anyhow!("wasm compilation thread panicked").into())
+        }
+    }
 }
 
 impl Runtime {
@@ -170,11 +287,13 @@ impl Runtime {
 
             secret_store,
             delegate_store,
-            contract_modules: HashMap::new(),
+            contract_modules: ContractModules::default(),
 
             contract_store,
             delegate_modules: HashMap::new(),
             enabled_metering: config.enable_metering,
+            max_compilation_time: Duration::from_secs_f64(config.max_compilation_seconds),
+            compilation: CompilationMetrics::default(),
         })
     }
 
@@ -228,23 +347,26 @@ impl Runtime {
         parameters: &Parameters,
         req_bytes: usize,
     ) -> RuntimeResult<RunningInstance> {
-        let module = if let Some(module) = self.contract_modules.get(key) {
+        // the store knows of upgrades the key of the caller may predate
+        let current = self.contract_store.code_hash_from_key(key);
+        let current = current.as_ref().or(key.code_hash());
+        let module = if let Some(module) = self.contract_modules.get(key, current) {
             module
         } else {
             let contract = self
                 .contract_store
                 .fetch_contract(key, parameters)
                 .ok_or_else(|| RuntimeInnerError::ContractNotFound(*key))?;
-            let module = match contract {
-                ContractContainer::Wasm(ContractWasmAPIVersion::V1(contract_v1)) => {
-                    Module::new(self.wasm_store.as_ref().unwrap(), contract_v1.code().data())?
-                }
+            let (code, module) = match contract {
+                ContractContainer::Wasm(ContractWasmAPIVersion::V1(contract_v1)) => (
+                    *contract_v1.code().hash(),
+                    self.compile(contract_v1.code().data())?,
+                ),
                 _ => unimplemented!(),
             };
-            self.contract_modules.insert(*key, module);
-            self.contract_modules.get(key).unwrap()
-        }
-        .clone();
+            self.contract_modules.insert(*key, code, module.clone());
+            module
+        };
         let instance = self.prepare_instance(&module)?;
         self.set_instance_mem(req_bytes, &instance)?;
         RunningInstance::new(self, instance, Key::Contract(*key.id()))
@@ -263,7 +385,7 @@ impl Runtime {
                 .delegate_store
                 .fetch_delegate(key, params)
                 .ok_or_else(|| RuntimeInnerError::DelegateNotFound(key.clone()))?;
-            let module = Module::new(self.wasm_store.as_ref().unwrap(), delegate.code().as_ref())?;
+            let module = self.compile(delegate.code().as_ref())?;
             self.delegate_modules.insert(key.clone(), module);
             self.delegate_modules.get(key).unwrap()
         }
@@ -273,6 +395,16 @@ impl Runtime {
         RunningInstance::new(self, instance, Key::Delegate(key.clone()))
     }
 
+    fn compile(&mut self, code: &[u8]) -> RuntimeResult<Module> {
+        let engine = self.wasm_store.as_ref().unwrap().engine().clone();
+        let code = code.to_vec();
+        compile_within(
+            self.max_compilation_time,
+            &mut self.compilation,
+            move || Module::new(&engine, code),
+        )
+    }
+
     fn set_instance_mem(&mut self, req_bytes: usize, instance: &Instance) -> RuntimeResult<()> {
         let wasm_store = self.wasm_store.as_mut().unwrap();
         let memory = self
@@ -468,3 +600,50 @@ fn get_cpu_cycles_per_second_runtime() -> Option<u64> {
 
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasm_runtime::RuntimeInnerError;
+
+    #[test]
+    fn slow_compilation_times_out() {
+        let mut metrics = CompilationMetrics::default();
+        let slow = || {
+            std::thread::sleep(Duration::from_millis(500));
+            Ok(())
+        };
+        let err = compile_within(Duration::from_millis(50), &mut metrics, slow).unwrap_err();
+        assert!(matches!(
+            err.deref(),
+            RuntimeInnerError::ContractExecError(ContractExecError::MaxCompileTimeExceeded)
+        ));
+        assert_eq!((metrics.compiled, metrics.timed_out), (0, 1));
+
+        compile_within(Duration::from_secs(5), &mut metrics, || Ok(())).unwrap();
+        assert_eq!((metrics.compiled, metrics.timed_out), (1, 1));
+        assert!(metrics.max < Duration::from_millis(500), "{metrics:?}");
+    }
+
+    #[test]
+    fn module_dropped_once_code_changes() {
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let original = *ContractCode::from(vec![1]).hash();
+        let upgraded = *ContractCode::from(vec![2]).hash();
+        let mut modules = ContractModules::default();
+        modules.insert(key, original, "original");
+
+        // keys without code and those of the same code get the cached module
+        assert_eq!(modules.get(&key, None), Some("original"));
+        assert_eq!(modules.get(&key, Some(&original)), Some("original"));
+        assert_eq!(modules.upgraded, 0);
+
+        assert_eq!(modules.get(&key, Some(&upgraded)), None);
+        assert_eq!(modules.upgraded, 1);
+        // gone for good, even for callers still on the old code
+        assert_eq!(modules.get(&key, Some(&original)), None);
+        modules.insert(key, upgraded, "upgraded");
+        assert_eq!(modules.get(&key, Some(&upgraded)), Some("upgraded"));
+        assert_eq!(modules.upgraded, 1);
+    }
+}
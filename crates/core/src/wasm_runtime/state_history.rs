@@ -0,0 +1,152 @@
+//! Past states of contracts, retained in memory for apps undoing changes or auditing them.
+//!
+//! The last states stored for every contract are kept up to the configured depth, each one
+//! numbered from the first state stored since the node started and identified as well by
+//! its hash, the same version the metadata of a contract shows. Once more states are stored
+//! the oldest ones are evicted, as are all of them when the node restarts.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    str::FromStr,
+};
+
+use freenet_stdlib::prelude::{ContractInstanceId, WrappedState};
+
+/// Identifies the state stored for a contract at some point, by the hash of its bytes.
+pub(crate) fn state_version(state: &WrappedState) -> String {
+    bs58::encode(blake3::hash(state.as_ref()).as_bytes()).into_string()
+}
+
+/// A version of a contract state asked for, by number or by hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateVersion {
+    Number(u64),
+    Hash(String),
+}
+
+impl FromStr for StateVersion {
+    type Err = std::convert::Infallible;
+
+    fn from_str(version: &str) -> Result<Self, Self::Err> {
+        // a hash is never made of digits alone, it is way longer than any number
+        Ok(match version.parse() {
+            Ok(number) => Self::Number(number),
+            Err(_) => Self::Hash(version.to_owned()),
+        })
+    }
+}
+
+impl Display for StateVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Number(number) => number.fmt(f),
+            Self::Hash(hash) => hash.fmt(f),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoricalState {
+    pub number: u64,
+    pub hash: String,
+    pub state: WrappedState,
+}
+
+#[derive(Default)]
+struct Versions {
+    /// Number of the latest state stored.
+    latest: u64,
+    retained: VecDeque<HistoricalState>,
+}
+
+#[derive(Default)]
+pub(crate) struct StateHistory {
+    depth: usize,
+    contracts: HashMap<ContractInstanceId, Versions>,
+}
+
+impl StateHistory {
+    /// Retains the last `depth` states of every contract, none when zero.
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            contracts: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, contract: &ContractInstanceId, state: &WrappedState) {
+        if self.depth == 0 {
+            return;
+        }
+        let versions = self.contracts.entry(*contract).or_default();
+        versions.latest += 1;
+        if versions.retained.len() == self.depth {
+            versions.retained.pop_front();
+        }
+        versions.retained.push_back(HistoricalState {
+            number: versions.latest,
+            hash: state_version(state),
+            state: state.clone(),
+        });
+    }
+
+    /// The version of the state of the contract, if still retained.
+    pub fn get(
+        &self,
+        contract: &ContractInstanceId,
+        version: &StateVersion,
+    ) -> Option<&HistoricalState> {
+        let versions = self.contracts.get(contract)?;
+        match version {
+            StateVersion::Number(number) => versions
+                .retained
+                .iter()
+                .find(|retained| retained.number == *number),
+            // the same state may have been stored more than once, the latest time wins
+            StateVersion::Hash(hash) => versions
+                .retained
+                .iter()
+                .rev()
+                .find(|retained| &retained.hash == hash),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_versions_evicted() {
+        let mut history = StateHistory::new(3);
+        let contract = ContractInstanceId::new([1; 32]);
+        let states: Vec<_> = (1..=5).map(|n| WrappedState::new(vec![n])).collect();
+        for state in &states {
+            history.record(&contract, state);
+        }
+
+        let third = history.get(&contract, &StateVersion::Number(3)).unwrap();
+        assert_eq!(third.state.as_ref(), &[3]);
+        let by_hash = history
+            .get(&contract, &StateVersion::Hash(state_version(&states[3])))
+            .unwrap();
+        assert_eq!(by_hash.number, 4);
+        assert!(history.get(&contract, &StateVersion::Number(2)).is_none());
+        assert!(history
+            .get(&contract, &StateVersion::Hash(state_version(&states[0])))
+            .is_none());
+        assert!(history.get(&contract, &StateVersion::Number(6)).is_none());
+
+        let mut disabled = StateHistory::new(0);
+        disabled.record(&contract, &states[0]);
+        assert!(disabled.get(&contract, &StateVersion::Number(1)).is_none());
+    }
+
+    #[test]
+    fn parse_version() {
+        assert_eq!("12".parse(), Ok(StateVersion::Number(12)));
+        let hash = state_version(&WrappedState::new(vec![1]));
+        assert_eq!(hash.parse(), Ok(StateVersion::Hash(hash.clone())));
+    }
+}
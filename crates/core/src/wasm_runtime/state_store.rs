@@ -1,13 +1,34 @@
 use core::future::Future;
+use dashmap::DashMap;
 use freenet_stdlib::prelude::*;
 use stretto::AsyncCache;
 
+use super::state_history::{state_version, HistoricalState, StateHistory, StateVersion};
+
+/// Prefix of the stored states, followed by the version of the format they are stored in,
+/// states stored before lack it and are read as they are.
+///
+/// In the current format the prefix is followed by the length of the state and its checksum,
+/// so a state stored before which happens to start with the prefix is only taken for one in
+/// the current format if its length and checksum match as well.
+const FORMAT_PREFIX: &[u8] = b"\0fcs";
+const FORMAT_VERSION: u8 = 1;
+const LENGTH_LEN: usize = 8;
+const CHECKSUM_LEN: usize = 8;
+const HEADER_LEN: usize = FORMAT_PREFIX.len() + 1 + LENGTH_LEN + CHECKSUM_LEN;
+
 #[derive(thiserror::Error, Debug)]
 pub enum StateStoreError {
     #[error(transparent)]
     Any(#[from] anyhow::Error),
     #[error("missing contract: {0}")]
     MissingContract(ContractKey),
+    /// The state read back doesn't match the checksum it was stored with, e.g. truncated.
+    #[error("stored state of contract {0} is corrupt")]
+    DataCorruption(ContractKey),
+    /// The state was stored in a format newer than the ones known.
+    #[error("stored state of contract {0} is in the unknown format version {1}")]
+    UnknownFormat(ContractKey, u8),
 }
 
 impl From<StateStoreError> for crate::wasm_runtime::ContractError {
@@ -16,7 +37,9 @@ impl From<StateStoreError> for crate::wasm_runtime::ContractError {
             StateStoreError::Any(err) => {
                 crate::wasm_runtime::ContractError::from(anyhow::format_err!(err))
             }
-            err @ StateStoreError::MissingContract(_) => {
+            err @ (StateStoreError::MissingContract(_)
+            | StateStoreError::DataCorruption(_)
+            | StateStoreError::UnknownFormat(..)) => {
                 crate::wasm_runtime::ContractError::from(anyhow::format_err!(err))
             }
         }
@@ -30,6 +53,11 @@ pub trait StateStorage {
         key: ContractKey,
         state: WrappedState,
     ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    /// Stores the states of several contracts in one go, either all of them or none.
+    fn store_all(
+        &mut self,
+        states: Vec<(ContractKey, WrappedState)>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
     fn store_params(
         &mut self,
         key: ContractKey,
@@ -43,12 +71,152 @@ pub trait StateStorage {
         &'a self,
         key: &'a ContractKey,
     ) -> impl Future<Output = Result<Option<Parameters<'static>>, Self::Error>> + Send + 'a;
+    /// `len` bytes of the state stored for the contract from `offset`, fewer past its end.
+    fn read_at(
+        &self,
+        key: &ContractKey,
+        offset: usize,
+        len: usize,
+    ) -> impl Future<Output = Result<Option<Vec<u8>>, Self::Error>> + Send;
+    /// The contracts a state is stored for.
+    fn keys(&self) -> impl Future<Output = Result<Vec<ContractKey>, Self::Error>> + Send;
+}
+
+fn checksum(state: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let mut checksum = [0; CHECKSUM_LEN];
+    checksum.copy_from_slice(&blake3::hash(state).as_bytes()[..CHECKSUM_LEN]);
+    checksum
+}
+
+/// The state as stored in the current format.
+pub(crate) fn seal(state: &WrappedState) -> WrappedState {
+    let mut sealed = Vec::with_capacity(HEADER_LEN + state.size());
+    sealed.extend(FORMAT_PREFIX);
+    sealed.push(FORMAT_VERSION);
+    sealed.extend((state.size() as u64).to_le_bytes());
+    sealed.extend(checksum(state.as_ref()));
+    sealed.extend(state.as_ref());
+    WrappedState::new(sealed)
+}
+
+/// The state read back from the storage, checked against its length and checksum.
+fn unseal(key: &ContractKey, stored: WrappedState) -> Result<WrappedState, StateStoreError> {
+    let Some((&version, sealed)) = stored
+        .as_ref()
+        .strip_prefix(FORMAT_PREFIX)
+        .and_then(<[u8]>::split_first)
+    else {
+        return Ok(stored);
+    };
+    if version != FORMAT_VERSION {
+        tracing::error!(contract = %key, version, "stored state in an unknown format");
+        return Err(StateStoreError::UnknownFormat(*key, version));
+    }
+    let intact = sealed.len() >= LENGTH_LEN + CHECKSUM_LEN && {
+        let (length, rest) = sealed.split_at(LENGTH_LEN);
+        let (stored_checksum, state) = rest.split_at(CHECKSUM_LEN);
+        u64::from_le_bytes(length.try_into().expect("length of the length")) == state.len() as u64
+            && stored_checksum == checksum(state)
+    };
+    if !intact {
+        tracing::error!(contract = %key, "stored state doesn't match its length or checksum");
+        return Err(StateStoreError::DataCorruption(*key));
+    }
+    Ok(WrappedState::new(
+        sealed[LENGTH_LEN + CHECKSUM_LEN..].to_vec(),
+    ))
+}
+
+/// Reads the state of a contract a chunk at a time, see [`StateStore::reader`].
+#[cfg(feature = "http-gateway")]
+pub(crate) enum StateReader<S> {
+    Cached(WrappedState),
+    Stored {
+        store: S,
+        key: ContractKey,
+        /// Where the state starts, past the header of the format it is stored in.
+        start: usize,
+        /// Size of the state, unknown for those stored before the format was marked.
+        size: Option<usize>,
+    },
+}
+
+#[cfg(feature = "http-gateway")]
+impl<S> From<WrappedState> for StateReader<S> {
+    fn from(state: WrappedState) -> Self {
+        Self::Cached(state)
+    }
+}
+
+#[cfg(feature = "http-gateway")]
+impl<S> StateReader<S>
+where
+    S: StateStorage,
+    <S as StateStorage>::Error: Into<anyhow::Error>,
+{
+    /// Size of the whole state, if known before reading it.
+    pub fn size(&self) -> Option<usize> {
+        match self {
+            Self::Cached(state) => Some(state.size()),
+            Self::Stored { size, .. } => *size,
+        }
+    }
+
+    /// `len` bytes of the state from `offset`, fewer past its end.
+    pub async fn read_at(&self, offset: usize, len: usize) -> Result<Vec<u8>, StateStoreError> {
+        let (store, key, start, size) = match self {
+            Self::Cached(state) => {
+                let rest = state.as_ref().get(offset..).unwrap_or_default();
+                return Ok(rest[..len.min(rest.len())].to_vec());
+            }
+            Self::Stored {
+                store,
+                key,
+                start,
+                size,
+            } => (store, key, start, size),
+        };
+        let len = size.map_or(len, |size| len.min(size.saturating_sub(offset)));
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let read = store
+            .read_at(key, start + offset, len)
+            .await
+            .map_err(Into::into)?
+            .ok_or(StateStoreError::MissingContract(*key))?;
+        if size.is_some() && read.len() < len {
+            tracing::error!(contract = %key, "stored state shorter than its length");
+            return Err(StateStoreError::DataCorruption(*key));
+        }
+        Ok(read)
+    }
+}
+
+/// Size and version of a stored state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateMetadata {
+    pub size: usize,
+    pub version: String,
+}
+
+impl StateMetadata {
+    fn of(state: &WrappedState) -> Self {
+        Self {
+            size: state.size(),
+            version: state_version(state),
+        }
+    }
 }
 
 pub struct StateStore<S: StateStorage> {
     state_mem_cache: AsyncCache<ContractKey, WrappedState>,
     // params_mem_cache: AsyncCache<ContractKey, Parameters<'static>>,
     store: S,
+    history: StateHistory,
+    /// Of the states stored, taken as they are written, or read the first time for those
+    /// stored before the node started.
+    metadata: DashMap<ContractKey, StateMetadata>,
 }
 
 impl<S> StateStore<S>
@@ -68,9 +236,17 @@ where
             // params_mem_cache: AsyncCache::new(counters, max_size as i64)
             //     .map_err(|err| StateStoreError::Any(Box::new(err)))?,
             store,
+            history: StateHistory::default(),
+            metadata: DashMap::new(),
         })
     }
 
+    /// Retains the last `depth` states stored for every contract, see [`Self::version`].
+    pub fn with_history(mut self, depth: usize) -> Self {
+        self.history = StateHistory::new(depth);
+        self
+    }
+
     pub async fn update(
         &mut self,
         key: &ContractKey,
@@ -85,14 +261,44 @@ where
                 .ok_or_else(|| StateStoreError::MissingContract(*key))?;
         }
         self.store
-            .store(*key, state.clone())
+            .store(*key, seal(&state))
             .await
             .map_err(Into::into)?;
+        self.history.record(key.id(), &state);
+        self.metadata.insert(*key, StateMetadata::of(&state));
         let cost = state.size() as i64;
         self.state_mem_cache.insert(*key, state, cost).await;
         Ok(())
     }
 
+    /// Updates the states of several existing contracts, either all of them or none.
+    pub async fn update_all(
+        &mut self,
+        states: Vec<(ContractKey, WrappedState)>,
+    ) -> Result<(), StateStoreError> {
+        for (key, _) in &states {
+            if self.state_mem_cache.get(key).await.is_none() {
+                self.store
+                    .get(key)
+                    .await
+                    .map_err(Into::into)?
+                    .ok_or_else(|| StateStoreError::MissingContract(*key))?;
+            }
+        }
+        let sealed = states
+            .iter()
+            .map(|(key, state)| (*key, seal(state)))
+            .collect();
+        self.store.store_all(sealed).await.map_err(Into::into)?;
+        for (key, state) in states {
+            self.history.record(key.id(), &state);
+            self.metadata.insert(key, StateMetadata::of(&state));
+            let cost = state.size() as i64;
+            self.state_mem_cache.insert(key, state, cost).await;
+        }
+        Ok(())
+    }
+
     pub async fn store(
         &mut self,
         key: ContractKey,
@@ -100,9 +306,11 @@ where
         params: Parameters<'static>,
     ) -> Result<(), StateStoreError> {
         self.store
-            .store(key, state.clone())
+            .store(key, seal(&state))
             .await
             .map_err(Into::into)?;
+        self.history.record(key.id(), &state);
+        self.metadata.insert(key, StateMetadata::of(&state));
         let cost = state.size() as i64;
         self.state_mem_cache.insert(key, state, cost).await;
         self.store
@@ -119,7 +327,82 @@ where
             return Ok(v.value().clone());
         }
         let r = self.store.get(key).await.map_err(Into::into)?;
-        r.ok_or_else(|| StateStoreError::MissingContract(*key))
+        unseal(
+            key,
+            r.ok_or_else(|| StateStoreError::MissingContract(*key))?,
+        )
+    }
+
+    /// Reads the state of the contract a chunk at a time, from memory if cached, otherwise from
+    /// the storage without reading the rest of it.
+    ///
+    /// Unlike [`Self::get`] the checksum of a stored state is not verified, only its length,
+    /// and chunks read while the state is updated may come from either state.
+    #[cfg(feature = "http-gateway")]
+    pub(crate) async fn reader(&self, key: &ContractKey) -> Result<StateReader<S>, StateStoreError>
+    where
+        S: Clone,
+    {
+        if let Some(state) = self.state_mem_cache.get(key).await {
+            return Ok(StateReader::Cached(state.value().clone()));
+        }
+        let header = self
+            .store
+            .read_at(key, 0, HEADER_LEN)
+            .await
+            .map_err(Into::into)?
+            .ok_or(StateStoreError::MissingContract(*key))?;
+        let Some((&version, sealed)) = header
+            .strip_prefix(FORMAT_PREFIX)
+            .and_then(<[u8]>::split_first)
+        else {
+            return Ok(StateReader::Stored {
+                store: self.store.clone(),
+                key: *key,
+                start: 0,
+                size: None,
+            });
+        };
+        if version != FORMAT_VERSION {
+            tracing::error!(contract = %key, version, "stored state in an unknown format");
+            return Err(StateStoreError::UnknownFormat(*key, version));
+        }
+        let size = sealed
+            .first_chunk::<LENGTH_LEN>()
+            .and_then(|length| usize::try_from(u64::from_le_bytes(*length)).ok())
+            .ok_or(StateStoreError::DataCorruption(*key))?;
+        Ok(StateReader::Stored {
+            store: self.store.clone(),
+            key: *key,
+            start: HEADER_LEN,
+            size: Some(size),
+        })
+    }
+
+    /// Metadata of the state stored for the contract, without reading the state unless it
+    /// wasn't written nor read since the node started.
+    pub async fn metadata(&self, key: &ContractKey) -> Result<StateMetadata, StateStoreError> {
+        if let Some(metadata) = self.metadata.get(key) {
+            return Ok(metadata.clone());
+        }
+        let metadata = StateMetadata::of(&self.get(key).await?);
+        self.metadata.insert(*key, metadata.clone());
+        Ok(metadata)
+    }
+
+    /// The contracts `included` a state is stored for.
+    pub async fn keys(
+        &self,
+        included: impl Fn(&ContractInstanceId) -> bool,
+    ) -> Result<Vec<ContractKey>, StateStoreError> {
+        let mut keys = self.store.keys().await.map_err(Into::into)?;
+        keys.retain(|key| included(key.id()));
+        Ok(keys)
+    }
+
+    /// A past state of the contract, if still retained.
+    pub fn version(&self, key: &ContractKey, version: &StateVersion) -> Option<HistoricalState> {
+        self.history.get(key.id(), version).cloned()
     }
 
     pub async fn get_params<'a>(
@@ -133,3 +416,196 @@ where
         Ok(r)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use parking_lot::Mutex;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct MemoryStorage(
+        Arc<Mutex<HashMap<ContractKey, WrappedState>>>,
+        /// Bytes read a chunk at a time.
+        Arc<AtomicUsize>,
+    );
+
+    impl StateStorage for MemoryStorage {
+        type Error = anyhow::Error;
+
+        async fn store(&mut self, key: ContractKey, state: WrappedState) -> anyhow::Result<()> {
+            self.0.lock().insert(key, state);
+            Ok(())
+        }
+
+        async fn store_all(
+            &mut self,
+            states: Vec<(ContractKey, WrappedState)>,
+        ) -> anyhow::Result<()> {
+            self.0.lock().extend(states);
+            Ok(())
+        }
+
+        async fn store_params(
+            &mut self,
+            _key: ContractKey,
+            _params: Parameters<'static>,
+        ) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn get(&self, key: &ContractKey) -> anyhow::Result<Option<WrappedState>> {
+            Ok(self.0.lock().get(key).cloned())
+        }
+
+        async fn get_params<'a>(
+            &'a self,
+            _key: &'a ContractKey,
+        ) -> anyhow::Result<Option<Parameters<'static>>> {
+            Ok(None)
+        }
+
+        async fn read_at(
+            &self,
+            key: &ContractKey,
+            offset: usize,
+            len: usize,
+        ) -> anyhow::Result<Option<Vec<u8>>> {
+            let Some(state) = self.0.lock().get(key).cloned() else {
+                return Ok(None);
+            };
+            let rest = state.as_ref().get(offset..).unwrap_or_default();
+            let chunk = rest[..len.min(rest.len())].to_vec();
+            self.1.fetch_add(chunk.len(), Ordering::Relaxed);
+            Ok(Some(chunk))
+        }
+
+        async fn keys(&self) -> anyhow::Result<Vec<ContractKey>> {
+            Ok(self.0.lock().keys().copied().collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn corrupt_state_detected() -> anyhow::Result<()> {
+        let storage = MemoryStorage::default();
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let mut store = StateStore::new(storage.clone(), 10_000)?;
+        store
+            .store(
+                key,
+                WrappedState::new(vec![1, 2, 3, 4]),
+                Parameters::from(vec![]),
+            )
+            .await?;
+        // read through a store with nothing cached
+        let fresh = StateStore::new(storage.clone(), 10_000)?;
+        assert_eq!(fresh.get(&key).await?.as_ref(), [1, 2, 3, 4]);
+
+        let truncated = {
+            let stored = &storage.0.lock()[&key];
+            WrappedState::new(stored.as_ref()[..stored.size() - 1].to_vec())
+        };
+        storage.0.lock().insert(key, truncated);
+        let fresh = StateStore::new(storage.clone(), 10_000)?;
+        assert!(matches!(
+            fresh.get(&key).await,
+            Err(StateStoreError::DataCorruption(corrupt)) if corrupt == key
+        ));
+
+        // stored without a checksum
+        storage.0.lock().insert(key, WrappedState::new(vec![5]));
+        assert_eq!(fresh.get(&key).await?.as_ref(), [5]);
+
+        // stored in a later format
+        let mut later = seal(&WrappedState::new(vec![1])).as_ref().to_vec();
+        later[FORMAT_PREFIX.len()] = FORMAT_VERSION + 1;
+        storage.0.lock().insert(key, WrappedState::new(later));
+        assert!(matches!(
+            fresh.get(&key).await,
+            Err(StateStoreError::UnknownFormat(_, version)) if version == FORMAT_VERSION + 1
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "http-gateway")]
+    #[tokio::test]
+    async fn state_read_in_chunks() -> anyhow::Result<()> {
+        let storage = MemoryStorage::default();
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let mut store = StateStore::new(storage.clone(), 10_000)?;
+        let state: Vec<u8> = (0..=255).collect();
+        store
+            .store(
+                key,
+                WrappedState::new(state.clone()),
+                Parameters::from(vec![]),
+            )
+            .await?;
+        let cached = store.reader(&key).await?;
+        assert_eq!(cached.read_at(250, 10).await?, state[250..]);
+        assert_eq!(storage.1.load(Ordering::Relaxed), 0);
+
+        // only the header and the chunks asked for are read from the storage
+        let fresh = StateStore::new(storage.clone(), 10_000)?;
+        let reader = fresh.reader(&key).await?;
+        assert_eq!(reader.size(), Some(256));
+        assert_eq!(reader.read_at(10, 4).await?, state[10..14]);
+        assert_eq!(reader.read_at(250, 10).await?, state[250..]);
+        assert!(reader.read_at(300, 4).await?.is_empty());
+        assert_eq!(storage.1.load(Ordering::Relaxed), HEADER_LEN + 4 + 6);
+
+        let truncated = {
+            let stored = &storage.0.lock()[&key];
+            WrappedState::new(stored.as_ref()[..stored.size() - 1].to_vec())
+        };
+        storage.0.lock().insert(key, truncated);
+        let reader = fresh.reader(&key).await?;
+        assert!(matches!(
+            reader.read_at(250, 10).await,
+            Err(StateStoreError::DataCorruption(corrupt)) if corrupt == key
+        ));
+
+        // stored without a header
+        storage.0.lock().insert(key, WrappedState::new(vec![5, 6]));
+        let reader = fresh.reader(&key).await?;
+        assert_eq!(reader.size(), None);
+        assert_eq!(reader.read_at(1, 10).await?, [6]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn metadata_kept_when_written() -> anyhow::Result<()> {
+        let storage = MemoryStorage::default();
+        let key = ContractKey::from(ContractInstanceId::new([1; 32]));
+        let mut store = StateStore::new(storage.clone(), 10_000)?;
+        let state = WrappedState::new(vec![1, 2, 3]);
+        store
+            .store(key, state.clone(), Parameters::from(vec![]))
+            .await?;
+        // not read back from the storage
+        storage.0.lock().clear();
+        assert_eq!(store.metadata(&key).await?, StateMetadata::of(&state));
+
+        let updated = WrappedState::new(vec![4, 5]);
+        storage.0.lock().insert(key, seal(&state));
+        store.update(&key, updated.clone()).await?;
+        storage.0.lock().clear();
+        let metadata = store.metadata(&key).await?;
+        assert_eq!(metadata.size, 2);
+        assert_eq!(metadata.version, state_version(&updated));
+
+        // stored before the node started
+        storage.0.lock().insert(key, seal(&state));
+        let fresh = StateStore::new(storage.clone(), 10_000)?;
+        assert_eq!(fresh.metadata(&key).await?, StateMetadata::of(&state));
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+//! Subscription topics declared by a contract.
+//!
+//! A contract lists the topics it publishes updates under, one per line, in a custom section
+//! of its wasm module named [`TOPICS_SECTION`]. Reading them doesn't require compiling the
+//! module, so they can be listed for any stored contract.
+
+use anyhow::{bail, Context};
+
+pub(crate) const TOPICS_SECTION: &str = "freenet:topics";
+
+pub(super) const MAGIC: &[u8] = b"\0asm";
+pub(super) const CUSTOM_SECTION_ID: u8 = 0;
+
+/// The topics declared in the `code` of a contract, none if it doesn't declare any.
+pub(crate) fn declared_topics(code: &[u8]) -> anyhow::Result<Vec<String>> {
+    section_lines(code, TOPICS_SECTION)
+}
+
+/// The non-empty lines of the custom sections of the module named `name`.
+pub(super) fn section_lines(code: &[u8], name: &str) -> anyhow::Result<Vec<String>> {
+    let Some(mut sections) = code.strip_prefix(MAGIC) else {
+        bail!("contract code is not a wasm module");
+    };
+    // version of the binary format
+    sections = sections.get(4..).context("truncated wasm header")?;
+    let mut lines = Vec::new();
+    while let Some((&id, rest)) = sections.split_first() {
+        let (size, rest) = read_leb128(rest)?;
+        let (section, rest) = split(rest, size)?;
+        sections = rest;
+        if id != CUSTOM_SECTION_ID {
+            continue;
+        }
+        let (name_len, section) = read_leb128(section)?;
+        let (section_name, data) = split(section, name_len)?;
+        if section_name != name.as_bytes() {
+            continue;
+        }
+        let data = std::str::from_utf8(data)
+            .with_context(|| format!("{name} section is not valid utf-8"))?;
+        lines.extend(
+            data.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(String::from),
+        );
+    }
+    Ok(lines)
+}
+
+fn read_leb128(bytes: &[u8]) -> anyhow::Result<(usize, &[u8])> {
+    let mut value = 0usize;
+    for (i, byte) in bytes.iter().enumerate().take(5) {
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, &bytes[i + 1..]));
+        }
+    }
+    bail!("malformed section size")
+}
+
+fn split(bytes: &[u8], at: usize) -> anyhow::Result<(&[u8], &[u8])> {
+    if at > bytes.len() {
+        bail!("truncated wasm section");
+    }
+    Ok(bytes.split_at(at))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(id: u8, content: &[u8]) -> Vec<u8> {
+        assert!(content.len() < 0x80);
+        let mut section = vec![id, content.len() as u8];
+        section.extend(content);
+        section
+    }
+
+    fn custom_section(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut content = vec![name.len() as u8];
+        content.extend(name.as_bytes());
+        content.extend(data);
+        section(CUSTOM_SECTION_ID, &content)
+    }
+
+    fn module(sections: &[Vec<u8>]) -> Vec<u8> {
+        let mut module = MAGIC.to_vec();
+        module.extend([1, 0, 0, 0]);
+        for section in sections {
+            module.extend(section);
+        }
+        module
+    }
+
+    #[test]
+    fn lists_declared_topics() {
+        let code = module(&[
+            // an empty type section
+            section(1, &[0]),
+            custom_section("name", b"\0\x03foo"),
+            custom_section(TOPICS_SECTION, b"posts\ncomments\n\n"),
+        ]);
+        assert_eq!(declared_topics(&code).unwrap(), ["posts", "comments"]);
+    }
+
+    #[test]
+    fn no_topics_declared() {
+        let code = module(&[section(1, &[0])]);
+        assert!(declared_topics(&code).unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_module() {
+        assert!(declared_topics(b"not wasm").is_err());
+        let mut code = module(&[custom_section(TOPICS_SECTION, b"posts")]);
+        code.truncate(code.len() - 2);
+        assert!(declared_topics(&code).is_err());
+    }
+}
@@ -0,0 +1,51 @@
+//! The executor driven in-process, which has to keep working when building without the
+//! HTTP gateway:
+//!
+//! ```text
+//! cargo test -p freenet --no-default-features --features redb,trace --test in_process
+//! ```
+
+use std::sync::Arc;
+
+use freenet::{
+    config::{ConfigArgs, ConfigPathsArgs, NetworkArgs},
+    dev_tool::ClientId,
+    local_node::{Executor, OperationMode},
+};
+use freenet_stdlib::{
+    client_api::ContractRequest,
+    prelude::{ContractInstanceId, ContractKey},
+};
+
+#[tokio::test]
+async fn executor_without_gateway() -> anyhow::Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let config = ConfigArgs {
+        mode: Some(OperationMode::Local),
+        network_api: NetworkArgs {
+            skip_load_from_network: true,
+            ..Default::default()
+        },
+        config_paths: ConfigPathsArgs {
+            config_dir: Some(temp_dir.path().to_path_buf()),
+            data_dir: Some(temp_dir.path().to_path_buf()),
+        },
+        ..Default::default()
+    }
+    .build()
+    .await?;
+    let mut executor = Executor::from_config(Arc::new(config), None).await?;
+
+    let missing = ContractKey::from(ContractInstanceId::new([1; 32]));
+    let get = ContractRequest::Get {
+        key: missing,
+        return_contract_code: false,
+        subscribe: false,
+    };
+    let err = executor
+        .contract_requests(get, ClientId::FIRST, None)
+        .await
+        .unwrap_err();
+    assert!(err.is_missing_contract(), "{err}");
+    Ok(())
+}